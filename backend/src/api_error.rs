@@ -0,0 +1,158 @@
+use actix_web::http::StatusCode;
+use actix_web::{HttpResponse, ResponseError};
+use serde::Serialize;
+use std::fmt;
+
+/// Error returned by API handlers, sent as JSON `{code, message, details}`
+#[derive(Debug)]
+pub enum ApiError {
+    /// 400, the request itself is wrong
+    BadRequest(String),
+    /// 400 with the individual problems in `details`
+    Invalid(String, serde_json::Value),
+    /// 401, no valid API key was presented
+    Unauthorized(String),
+    Forbidden(String),
+    NotFound(String),
+    Conflict(String),
+    /// 429, with the seconds to wait before trying again
+    RateLimited(String, u64),
+    /// 503, the request may succeed later or on another instance
+    Unavailable(String),
+    /// 500, the cause was logged and is not sent to the client
+    Internal(String),
+}
+
+#[derive(Serialize)]
+struct ErrorBody<'a> {
+    code: &'static str,
+    message: &'a str,
+    details: Option<&'a serde_json::Value>,
+}
+
+impl ApiError {
+    /// Log what went wrong and hand the client a message pointing to the logs
+    pub fn internal(context: &str, cause: impl fmt::Display) -> Self {
+        tracing::error!("{}: {}", context, cause);
+        ApiError::Internal(format!("{}, check logs for more information", context))
+    }
+
+    pub fn code(&self) -> &'static str {
+        match self {
+            ApiError::BadRequest(_) | ApiError::Invalid(..) => "bad_request",
+            ApiError::Unauthorized(_) => "unauthorized",
+            ApiError::Forbidden(_) => "forbidden",
+            ApiError::NotFound(_) => "not_found",
+            ApiError::Conflict(_) => "conflict",
+            ApiError::RateLimited(..) => "rate_limited",
+            ApiError::Unavailable(_) => "unavailable",
+            ApiError::Internal(_) => "internal_error",
+        }
+    }
+
+    fn message(&self) -> &str {
+        match self {
+            ApiError::BadRequest(message)
+            | ApiError::Invalid(message, _)
+            | ApiError::Unauthorized(message)
+            | ApiError::Forbidden(message)
+            | ApiError::NotFound(message)
+            | ApiError::Conflict(message)
+            | ApiError::RateLimited(message, _)
+            | ApiError::Unavailable(message)
+            | ApiError::Internal(message) => message,
+        }
+    }
+}
+
+impl fmt::Display for ApiError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.message())
+    }
+}
+
+impl ResponseError for ApiError {
+    fn status_code(&self) -> StatusCode {
+        match self {
+            ApiError::BadRequest(_) | ApiError::Invalid(..) => StatusCode::BAD_REQUEST,
+            ApiError::Unauthorized(_) => StatusCode::UNAUTHORIZED,
+            ApiError::Forbidden(_) => StatusCode::FORBIDDEN,
+            ApiError::NotFound(_) => StatusCode::NOT_FOUND,
+            ApiError::Conflict(_) => StatusCode::CONFLICT,
+            ApiError::RateLimited(..) => StatusCode::TOO_MANY_REQUESTS,
+            ApiError::Unavailable(_) => StatusCode::SERVICE_UNAVAILABLE,
+            ApiError::Internal(_) => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+
+    fn error_response(&self) -> HttpResponse {
+        let details = match self {
+            ApiError::Invalid(_, details) => Some(details),
+            _ => None,
+        };
+        let mut response = HttpResponse::build(self.status_code());
+        match self {
+            ApiError::Unauthorized(_) => {
+                response.insert_header(("WWW-Authenticate", "Bearer"));
+            }
+            ApiError::RateLimited(_, retry_after) => {
+                response.insert_header(("Retry-After", retry_after.to_string()));
+            }
+            _ => {}
+        }
+        response.json(ErrorBody {
+            code: self.code(),
+            message: self.message(),
+            details,
+        })
+    }
+}
+
+/// Turn any error into a logged 500, `context` says what was being done ("Error while ...")
+pub trait OrInternal<T> {
+    fn or_internal(self, context: &str) -> Result<T, ApiError>;
+}
+
+impl<T, E: fmt::Display> OrInternal<T> for Result<T, E> {
+    fn or_internal(self, context: &str) -> Result<T, ApiError> {
+        self.map_err(|e| ApiError::internal(context, e))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::body::MessageBody;
+
+    fn body_json(error: ApiError) -> serde_json::Value {
+        let body = error.error_response().into_body().try_into_bytes().unwrap();
+        serde_json::from_slice(&body).unwrap()
+    }
+
+    #[test]
+    fn test_error_response_body() {
+        let not_found = ApiError::NotFound("Companion 3 not found".to_string());
+        assert_eq!(not_found.status_code(), StatusCode::NOT_FOUND);
+        let body = body_json(not_found);
+        assert_eq!(body["code"], "not_found");
+        assert_eq!(body["message"], "Companion 3 not found");
+        assert!(body["details"].is_null());
+
+        let invalid = ApiError::Invalid(
+            "Invalid attitude".to_string(),
+            serde_json::json!([{ "field": "trust" }]),
+        );
+        assert_eq!(body_json(invalid)["details"][0]["field"], "trust");
+    }
+
+    #[test]
+    fn test_or_internal_hides_cause() {
+        let result: Result<(), String> = Err("disk I/O error".to_string());
+        let error = result.or_internal("Error while getting config").unwrap_err();
+        assert_eq!(error.status_code(), StatusCode::INTERNAL_SERVER_ERROR);
+        assert_eq!(
+            error.to_string(),
+            "Error while getting config, check logs for more information"
+        );
+    }
+}
@@ -0,0 +1,250 @@
+use crate::database::CompanionAttitude;
+use serde::Serialize;
+
+/// How a dimension contributes to the stored relationship_score column
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum RelationshipPolarity {
+    Positive,
+    Negative,
+    Neutral,
+}
+
+/// Static definition of a single attitude dimension
+#[derive(Debug, Clone, Serialize)]
+pub struct AttitudeDimension {
+    pub name: &'static str,
+    pub description: &'static str,
+    pub min: f32,
+    pub max: f32,
+    /// Weight used when scoring how impactful a change in this dimension is
+    pub weight: f32,
+    /// Fraction of the distance to baseline recovered per day of inactivity
+    pub decay_rate: f32,
+    pub polarity: RelationshipPolarity,
+}
+
+const fn dimension(
+    name: &'static str,
+    description: &'static str,
+    weight: f32,
+    decay_rate: f32,
+    polarity: RelationshipPolarity,
+) -> AttitudeDimension {
+    AttitudeDimension {
+        name,
+        description,
+        min: -100.0,
+        max: 100.0,
+        weight,
+        decay_rate,
+        polarity,
+    }
+}
+
+/// Central definition of every attitude dimension, in database column order
+pub const ATTITUDE_DIMENSIONS: [AttitudeDimension; 20] = [
+    dimension("attraction", "How drawn the companion feels towards the target", 1.2, 0.02, RelationshipPolarity::Positive),
+    dimension("trust", "Confidence in the target's honesty and reliability", 1.5, 0.01, RelationshipPolarity::Positive),
+    dimension("fear", "How threatened or intimidated the companion feels", 1.1, 0.05, RelationshipPolarity::Negative),
+    dimension("anger", "Irritation or hostility towards the target", 1.3, 0.08, RelationshipPolarity::Negative),
+    dimension("joy", "Happiness felt in the target's presence", 1.0, 0.06, RelationshipPolarity::Positive),
+    dimension("sorrow", "Sadness associated with the target", 1.0, 0.05, RelationshipPolarity::Negative),
+    dimension("disgust", "Revulsion or moral disapproval of the target", 1.1, 0.03, RelationshipPolarity::Negative),
+    dimension("surprise", "How unpredictable the target seems", 0.8, 0.15, RelationshipPolarity::Neutral),
+    dimension("curiosity", "Interest in learning more about the target", 0.9, 0.04, RelationshipPolarity::Neutral),
+    dimension("respect", "Admiration for the target's abilities and character", 1.4, 0.01, RelationshipPolarity::Positive),
+    dimension("suspicion", "Doubt about the target's motives", 1.2, 0.03, RelationshipPolarity::Negative),
+    dimension("gratitude", "Appreciation for what the target has done", 1.1, 0.04, RelationshipPolarity::Positive),
+    dimension("jealousy", "Resentment over the target's attention elsewhere", 1.3, 0.06, RelationshipPolarity::Negative),
+    dimension("empathy", "How much the companion shares the target's feelings", 1.2, 0.02, RelationshipPolarity::Positive),
+    dimension("lust", "Physical desire towards the target", 1.1, 0.08, RelationshipPolarity::Positive),
+    dimension("love", "Deep emotional attachment to the target", 1.5, 0.005, RelationshipPolarity::Positive),
+    dimension("anxiety", "Nervousness or unease around the target", 1.0, 0.07, RelationshipPolarity::Negative),
+    dimension("butterflies", "Giddy romantic excitement around the target", 0.9, 0.1, RelationshipPolarity::Positive),
+    dimension("submissiveness", "Tendency to defer to the target", 0.8, 0.02, RelationshipPolarity::Neutral),
+    dimension("dominance", "Tendency to take control around the target", 0.8, 0.02, RelationshipPolarity::Neutral),
+];
+
+/// Look up a dimension definition by its column name
+pub fn find_dimension(name: &str) -> Option<&'static AttitudeDimension> {
+    ATTITUDE_DIMENSIONS.iter().find(|d| d.name == name)
+}
+
+/// Weight of a dimension, falling back to 1.0 for unknown names
+pub fn dimension_weight(name: &str) -> f32 {
+    find_dimension(name).map(|d| d.weight).unwrap_or(1.0)
+}
+
+/// Read the current value of a named dimension from an attitude
+pub fn dimension_value(attitude: &CompanionAttitude, name: &str) -> Option<f32> {
+    let value = match name {
+        "attraction" => attitude.attraction,
+        "trust" => attitude.trust,
+        "fear" => attitude.fear,
+        "anger" => attitude.anger,
+        "joy" => attitude.joy,
+        "sorrow" => attitude.sorrow,
+        "disgust" => attitude.disgust,
+        "surprise" => attitude.surprise,
+        "curiosity" => attitude.curiosity,
+        "respect" => attitude.respect,
+        "suspicion" => attitude.suspicion,
+        "gratitude" => attitude.gratitude,
+        "jealousy" => attitude.jealousy,
+        "empathy" => attitude.empathy,
+        "lust" => attitude.lust,
+        "love" => attitude.love,
+        "anxiety" => attitude.anxiety,
+        "butterflies" => attitude.butterflies,
+        "submissiveness" => attitude.submissiveness,
+        "dominance" => attitude.dominance,
+        _ => return None,
+    };
+    Some(value)
+}
+
+/// Overwrite a named dimension on an attitude, clamped to the dimension range
+pub fn set_dimension_value(attitude: &mut CompanionAttitude, name: &str, value: f32) -> bool {
+    let definition = match find_dimension(name) {
+        Some(d) => d,
+        None => return false,
+    };
+    let value = value.clamp(definition.min, definition.max);
+    let field = match name {
+        "attraction" => &mut attitude.attraction,
+        "trust" => &mut attitude.trust,
+        "fear" => &mut attitude.fear,
+        "anger" => &mut attitude.anger,
+        "joy" => &mut attitude.joy,
+        "sorrow" => &mut attitude.sorrow,
+        "disgust" => &mut attitude.disgust,
+        "surprise" => &mut attitude.surprise,
+        "curiosity" => &mut attitude.curiosity,
+        "respect" => &mut attitude.respect,
+        "suspicion" => &mut attitude.suspicion,
+        "gratitude" => &mut attitude.gratitude,
+        "jealousy" => &mut attitude.jealousy,
+        "empathy" => &mut attitude.empathy,
+        "lust" => &mut attitude.lust,
+        "love" => &mut attitude.love,
+        "anxiety" => &mut attitude.anxiety,
+        "butterflies" => &mut attitude.butterflies,
+        "submissiveness" => &mut attitude.submissiveness,
+        "dominance" => &mut attitude.dominance,
+        _ => return false,
+    };
+    *field = value;
+    true
+}
+
+/// Schema entry as served by the API, optionally carrying a live value
+#[derive(Debug, Clone, Serialize)]
+pub struct AttitudeDimensionSchema {
+    #[serde(flatten)]
+    pub definition: AttitudeDimension,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub current_value: Option<f32>,
+}
+
+/// Build the schema for all dimensions, filling live values if an attitude is given
+pub fn build_schema(attitude: Option<&CompanionAttitude>) -> Vec<AttitudeDimensionSchema> {
+    ATTITUDE_DIMENSIONS
+        .iter()
+        .map(|definition| AttitudeDimensionSchema {
+            definition: definition.clone(),
+            current_value: attitude.and_then(|a| dimension_value(a, definition.name)),
+        })
+        .collect()
+}
+
+/// Validation problem tied to a single request field
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct FieldError {
+    pub field: String,
+    pub message: String,
+}
+
+impl FieldError {
+    pub fn new(field: &str, message: impl Into<String>) -> Self {
+        FieldError {
+            field: field.to_string(),
+            message: message.into(),
+        }
+    }
+}
+
+pub const TARGET_TYPES: [&str; 2] = ["user", "third_party"];
+
+/// Check target type and every dimension value against the schema, without touching the database
+pub fn validate_attitude_values(attitude: &CompanionAttitude) -> Vec<FieldError> {
+    let mut errors = Vec::new();
+    if !TARGET_TYPES.contains(&attitude.target_type.as_str()) {
+        errors.push(FieldError::new(
+            "target_type",
+            format!("must be one of {}", TARGET_TYPES.join(", ")),
+        ));
+    }
+    for definition in ATTITUDE_DIMENSIONS.iter() {
+        if let Some(value) = dimension_value(attitude, definition.name) {
+            if !value.is_finite() || value < definition.min || value > definition.max {
+                errors.push(FieldError::new(
+                    definition.name,
+                    format!(
+                        "{} is outside {}..{}",
+                        value, definition.min, definition.max
+                    ),
+                ));
+            }
+        }
+    }
+    errors
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_dimension_names_are_unique() {
+        for (i, a) in ATTITUDE_DIMENSIONS.iter().enumerate() {
+            for b in ATTITUDE_DIMENSIONS.iter().skip(i + 1) {
+                assert_ne!(a.name, b.name);
+            }
+        }
+    }
+
+    #[test]
+    fn test_find_dimension() {
+        assert_eq!(find_dimension("trust").map(|d| d.weight), Some(1.5));
+        assert!(find_dimension("happiness").is_none());
+        assert_eq!(dimension_weight("happiness"), 1.0);
+    }
+
+    #[test]
+    fn test_schema_without_attitude_has_no_values() {
+        let schema = build_schema(None);
+        assert_eq!(schema.len(), 20);
+        assert!(schema.iter().all(|entry| entry.current_value.is_none()));
+
+        let json = serde_json::to_value(&schema[0]).unwrap();
+        assert_eq!(json["name"], "attraction");
+        assert_eq!(json["polarity"], "positive");
+        assert!(json.get("current_value").is_none());
+    }
+
+    #[test]
+    fn test_validate_attitude_values() {
+        let mut attitude = crate::database::Database::initial_user_attitude(1, 1, &[]);
+        assert!(validate_attitude_values(&attitude).is_empty());
+
+        attitude.trust = 150.0;
+        attitude.joy = f32::NAN;
+        attitude.target_type = "companion".to_string();
+        let fields: Vec<String> = validate_attitude_values(&attitude)
+            .into_iter()
+            .map(|e| e.field)
+            .collect();
+        assert_eq!(fields, vec!["target_type", "trust", "joy"]);
+    }
+}
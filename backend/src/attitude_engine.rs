@@ -0,0 +1,179 @@
+use crate::attitude_dimensions::{dimension_value, set_dimension_value, ATTITUDE_DIMENSIONS};
+use crate::attitude_history::AttitudeHistory;
+use crate::database::{get_current_date, parse_stored_date, CompanionAttitude, Database};
+use crate::db_pool;
+use crate::personality_traits::{self, PersonalityTrait, PersonalityTraits};
+use chrono::{Local, NaiveDateTime};
+use rusqlite::Result;
+use std::collections::HashMap;
+
+// Changes smaller than this are not worth a write
+const MIN_CHANGE: f32 = 0.01;
+
+/// Values an attitude drifts back to when nothing happens, shaped by the companion's traits
+pub fn baseline(attitude: &CompanionAttitude, traits: &[PersonalityTrait]) -> CompanionAttitude {
+    if attitude.target_type == "user" {
+        Database::initial_user_attitude(attitude.companion_id, attitude.target_id, traits)
+    } else {
+        let neutral = Database::neutral_third_party_attitude(attitude.companion_id);
+        personality_traits::apply(&neutral, traits)
+    }
+}
+
+/// Close `rate` of the distance to the baseline for every day that passed
+pub fn decay_toward(value: f32, baseline: f32, rate: f32, days: f32) -> f32 {
+    let remaining = (1.0 - rate.clamp(0.0, 1.0)).powf(days.max(0.0));
+    baseline + (value - baseline) * remaining
+}
+
+/// Attitude after `days` without interaction, the dimension rates are scaled by `multiplier`
+pub fn decay(
+    attitude: &CompanionAttitude,
+    baseline: &CompanionAttitude,
+    days: f32,
+    multiplier: f32,
+) -> CompanionAttitude {
+    let mut decayed = attitude.clone();
+    for dimension in ATTITUDE_DIMENSIONS.iter() {
+        if let (Some(value), Some(target)) = (
+            dimension_value(attitude, dimension.name),
+            dimension_value(baseline, dimension.name),
+        ) {
+            let value = decay_toward(value, target, dimension.decay_rate * multiplier, days);
+            set_dimension_value(&mut decayed, dimension.name, value);
+        }
+    }
+    decayed
+}
+
+/// Decay counts from the last interaction or the last decay, whichever is later
+fn decay_start(last_updated: &str, last_decayed: Option<&str>) -> Option<NaiveDateTime> {
+    let last_updated = parse_stored_date(last_updated);
+    let last_decayed = last_decayed.and_then(parse_stored_date);
+    last_updated.max(last_decayed)
+}
+
+fn store(id: i32, attitude: &CompanionAttitude) -> Result<usize> {
+    let con = db_pool::connection()?;
+    let mut assignments: Vec<String> = ATTITUDE_DIMENSIONS
+        .iter()
+        .map(|dimension| format!("{} = ?", dimension.name))
+        .collect();
+    assignments.push("last_decayed = ?".to_string());
+    let mut values: Vec<Box<dyn rusqlite::ToSql>> = ATTITUDE_DIMENSIONS
+        .iter()
+        .map(|dimension| {
+            Box::new(dimension_value(attitude, dimension.name).unwrap_or_default())
+                as Box<dyn rusqlite::ToSql>
+        })
+        .collect();
+    values.push(Box::new(get_current_date()));
+    values.push(Box::new(id));
+    // last_updated is left alone, it marks the last interaction and decay is measured from it
+    con.execute(
+        &format!("UPDATE companion_attitudes SET {} WHERE id = ?", assignments.join(", ")),
+        rusqlite::params_from_iter(values.iter()),
+    )
+}
+
+/// Maintenance job entry point, drifts every attitude of every companion toward its baseline
+pub fn run_decay() -> Result<String, String> {
+    let config = Database::get_global_config().map_err(|e| e.to_string())?;
+    if !config.attitude_decay_enabled || config.attitude_decay_multiplier <= 0.0 {
+        return Ok(String::new());
+    }
+    let con = db_pool::connection().map_err(|e| e.to_string())?;
+    let companion_ids: Vec<i32> = con
+        .prepare("SELECT id FROM companion")
+        .and_then(|mut stmt| stmt.query_map([], |row| row.get(0))?.collect())
+        .map_err(|e| e.to_string())?;
+    let traits = PersonalityTraits::all().map_err(|e| e.to_string())?;
+    let last_decayed: HashMap<i32, Option<String>> = con
+        .prepare("SELECT id, last_decayed FROM companion_attitudes")
+        .and_then(|mut stmt| {
+            stmt.query_map([], |row| Ok((row.get(0)?, row.get(1)?)))?
+                .collect()
+        })
+        .map_err(|e| e.to_string())?;
+    drop(con);
+
+    let now = Local::now().naive_local();
+    let mut decayed = 0;
+    for companion_id in &companion_ids {
+        let companion_traits = traits.get(companion_id).map(Vec::as_slice).unwrap_or_default();
+        let attitudes =
+            Database::get_all_companion_attitudes(*companion_id).map_err(|e| e.to_string())?;
+        for attitude in attitudes {
+            let id = match attitude.id {
+                Some(id) => id,
+                None => continue,
+            };
+            let start = decay_start(
+                &attitude.last_updated,
+                last_decayed.get(&id).and_then(|date| date.as_deref()),
+            );
+            let days = match start {
+                Some(start) => (now - start).num_minutes() as f32 / (24.0 * 60.0),
+                None => continue,
+            };
+            if days <= 0.0 {
+                continue;
+            }
+            let target = baseline(&attitude, companion_traits);
+            let next = decay(&attitude, &target, days, config.attitude_decay_multiplier);
+            let changed = ATTITUDE_DIMENSIONS.iter().any(|dimension| {
+                let before = dimension_value(&attitude, dimension.name).unwrap_or_default();
+                let after = dimension_value(&next, dimension.name).unwrap_or_default();
+                (before - after).abs() >= MIN_CHANGE
+            });
+            if !changed {
+                continue;
+            }
+            store(id, &next).map_err(|e| e.to_string())?;
+            AttitudeHistory::track(attitude.companion_id, attitude.target_id, &attitude.target_type);
+            decayed += 1;
+        }
+    }
+
+    if decayed == 0 {
+        return Ok(String::new());
+    }
+    Ok(format!("decayed {} attitudes toward their baseline", decayed))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_decay_toward() {
+        assert_eq!(decay_toward(80.0, 20.0, 0.5, 0.0), 80.0);
+        assert!((decay_toward(80.0, 20.0, 0.5, 1.0) - 50.0).abs() < 1e-4);
+        assert!((decay_toward(80.0, 20.0, 0.5, 2.0) - 35.0).abs() < 1e-4);
+        // Values below the baseline recover upward
+        assert!((decay_toward(-40.0, 0.0, 0.5, 1.0) + 20.0).abs() < 1e-4);
+    }
+
+    #[test]
+    fn test_decay_uses_dimension_rates() {
+        let calm = Database::initial_user_attitude(1, 1, &[]);
+        let mut spiked = calm.clone();
+        spiked.anger = 95.0;
+        spiked.love = 95.0;
+        let decayed = decay(&spiked, &calm, 7.0, 1.0);
+        // Anger fades within days, love barely moves
+        assert!(decayed.anger < 60.0);
+        assert!(decayed.love > 90.0);
+        assert_eq!(decay(&spiked, &calm, 7.0, 0.0).anger, 95.0);
+    }
+
+    #[test]
+    fn test_decay_start() {
+        let start = decay_start("Monday 13.05.2024 10:00", Some("Tuesday 14.05.2024 09:00"));
+        assert_eq!(start, parse_stored_date("Tuesday 14.05.2024 09:00"));
+        assert_eq!(
+            decay_start("Monday 13.05.2024 10:00", None),
+            parse_stored_date("Monday 13.05.2024 10:00")
+        );
+    }
+}
@@ -1,4 +1,6 @@
+use crate::attitude_dimensions::{dimension_value, set_dimension_value};
 use crate::database::{CompanionAttitude, ThirdPartyIndividual};
+use crate::mood_modifiers::{combined_shifts, MoodModifier};
 
 /// Handles conversion of attitude data into LLM prompt context and response calibration
 pub struct AttitudeFormatter {
@@ -6,6 +8,8 @@ pub struct AttitudeFormatter {
     pub low_threshold: f32,
     pub medium_threshold: f32,
     pub high_threshold: f32,
+    // Moods the companion is in for a while, they shift its attitude toward the user and add guidance
+    mood_modifiers: Vec<MoodModifier>,
 }
 
 impl AttitudeFormatter {
@@ -14,9 +18,27 @@ impl AttitudeFormatter {
             low_threshold: 20.0,
             medium_threshold: 50.0,
             high_threshold: 80.0,
+            mood_modifiers: Vec::new(),
         }
     }
 
+    /// Format as a companion in these moods, see mood_modifiers
+    pub fn with_mood_modifiers(mut self, modifiers: Vec<MoodModifier>) -> Self {
+        self.mood_modifiers = modifiers;
+        self
+    }
+
+    /// The attitude as the companion's moods color it, values stay within -100 and 100
+    fn with_mood_shifts(&self, attitude: &CompanionAttitude) -> CompanionAttitude {
+        let mut shifted = attitude.clone();
+        for (dimension, shift) in combined_shifts(&self.mood_modifiers) {
+            if let Some(value) = dimension_value(attitude, &dimension) {
+                set_dimension_value(&mut shifted, &dimension, (value + shift).clamp(-100.0, 100.0));
+            }
+        }
+        shifted
+    }
+
     /// Format attitudes into LLM prompt context with response calibration instructions
     pub fn format_attitude_context(
         &self,
@@ -36,18 +58,7 @@ impl AttitudeFormatter {
         }
 
         // Third-party attitudes (if significant)
-        let significant_third_parties =
-            self.get_significant_third_party_attitudes(attitudes, third_parties);
-        if !significant_third_parties.is_empty() {
-            context.push_str("\n\nRelationship awareness:\n");
-            for (party, attitude) in significant_third_parties {
-                context.push_str(&format!(
-                    "- Attitude toward {}: {}\n",
-                    party.name,
-                    self.format_attitude_summary(attitude)
-                ));
-            }
-        }
+        context.push_str(&self.format_third_party_context(attitudes, third_parties));
 
         // Response calibration instructions
         context.push_str(&self.generate_response_calibration_instructions(attitudes));
@@ -55,11 +66,47 @@ impl AttitudeFormatter {
         context
     }
 
+    /// Attitudes toward other people that are strong enough to mention, empty when none are
+    pub fn format_third_party_context(
+        &self,
+        attitudes: &[CompanionAttitude],
+        third_parties: &[ThirdPartyIndividual],
+    ) -> String {
+        let significant_third_parties =
+            self.get_significant_third_party_attitudes(attitudes, third_parties);
+        if significant_third_parties.is_empty() {
+            return String::new();
+        }
+        let mut context = String::from("\n\nRelationship awareness:\n");
+        for (party, attitude) in significant_third_parties {
+            context.push_str(&format!(
+                "- Attitude toward {}: {}\n",
+                party.name,
+                self.format_attitude_summary(attitude)
+            ));
+        }
+        context
+    }
+
     /// Format the primary user attitude with emotional context
     fn format_primary_attitude(&self, attitude: &CompanionAttitude, user_name: &str) -> String {
         let relationship_level = self.calculate_relationship_level(attitude);
-        let emotional_state = self.analyze_emotional_state(attitude);
-        let behavioral_instructions = self.generate_behavioral_instructions(attitude);
+        let shifted = self.with_mood_shifts(attitude);
+        let mut emotional_state = self.analyze_emotional_state(&shifted);
+        let mut behavioral_instructions = self.generate_behavioral_instructions(&shifted);
+        if !self.mood_modifiers.is_empty() {
+            let moods: Vec<&str> = self.mood_modifiers.iter().map(|modifier| modifier.name.as_str()).collect();
+            let feeling = format!("feeling {}", moods.join(" and "));
+            emotional_state = if emotional_state == "emotionally balanced" {
+                feeling
+            } else {
+                format!("{}, {}", emotional_state, feeling)
+            };
+            for modifier in self.mood_modifiers.iter().filter(|modifier| !modifier.instruction.is_empty()) {
+                behavioral_instructions.push_str("; ");
+                behavioral_instructions.push_str(&modifier.instruction);
+            }
+        }
 
         format!(
             "Current relationship with {}: {} ({})\n\
@@ -126,6 +173,11 @@ impl AttitudeFormatter {
         }
     }
 
+    /// Name of the relationship level, such as "friendly" or "distant"
+    pub fn relationship_level_name(&self, attitude: &CompanionAttitude) -> String {
+        self.calculate_relationship_level(attitude).name.to_lowercase()
+    }
+
     /// Analyze dominant emotional states from attitude dimensions
     fn analyze_emotional_state(&self, attitude: &CompanionAttitude) -> String {
         let mut emotions = Vec::new();
@@ -344,7 +396,7 @@ impl AttitudeFormatter {
     }
 
     /// Create a brief attitude summary for third-party relationships
-    fn format_attitude_summary(&self, attitude: &CompanionAttitude) -> String {
+    pub fn format_attitude_summary(&self, attitude: &CompanionAttitude) -> String {
         let level = self.calculate_relationship_level(attitude);
         let emotions = self.analyze_emotional_state(attitude);
 
@@ -763,4 +815,29 @@ mod tests {
         assert!(context.contains("Close"));
         assert!(context.contains("Response guidance"));
     }
+
+    #[test]
+    fn test_mood_modifier_context() {
+        let mut attitude = crate::database::Database::initial_user_attitude(1, 1, &[]);
+        attitude.anxiety = 40.0;
+        let stressed = MoodModifier {
+            id: 1,
+            companion_id: 1,
+            name: "stressed".to_string(),
+            shifts: [("anxiety".to_string(), 15.0)].into_iter().collect(),
+            instruction: "worry about things that still need doing".to_string(),
+            source: "manual".to_string(),
+            started_at: Utc::now(),
+            expires_at: Utc::now(),
+        };
+        let formatter = AttitudeFormatter::new();
+        assert!(!formatter.format_attitude_context(&[attitude.clone()], &[], "Anna").contains("nervous"));
+
+        // The shift lifts anxiety past the medium threshold, the stored attitude is left alone
+        let formatter = AttitudeFormatter::new().with_mood_modifiers(vec![stressed]);
+        let context = formatter.format_attitude_context(&[attitude.clone()], &[], "Anna");
+        assert!(context.contains("nervous, feeling stressed"));
+        assert!(context.contains("; worry about things that still need doing"));
+        assert_eq!(attitude.anxiety, 40.0);
+    }
 }
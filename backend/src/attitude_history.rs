@@ -0,0 +1,273 @@
+use crate::attitude_dimensions::{dimension_value, find_dimension, ATTITUDE_DIMENSIONS};
+use crate::database::{CompanionAttitude, Database};
+use crate::db_pool;
+use chrono::{DateTime, Duration, Utc};
+use rusqlite::{params, OptionalExtension, Result};
+use serde::Serialize;
+
+// Smallest move of a dimension or of the relationship score that gets a new snapshot
+const SNAPSHOT_THRESHOLD: f32 = 5.0;
+const RELATIONSHIP_SCORE: &str = "relationship_score";
+
+/// Values of one dimension, `values[i]` was the value at `timestamps[i]`
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct AttitudeSeries {
+    pub dimension: String,
+    pub values: Vec<f32>,
+}
+
+/// How an attitude developed, every series shares the timestamps
+#[derive(Serialize, Debug, Clone)]
+pub struct AttitudeHistoryView {
+    pub companion_id: i32,
+    pub target_id: i32,
+    pub target_type: String,
+    /// Start of the range, the first point holds the values at that time. None for the whole history
+    pub since: Option<DateTime<Utc>>,
+    /// End of the range, None for up to now
+    pub until: Option<DateTime<Utc>>,
+    pub timestamps: Vec<DateTime<Utc>>,
+    pub series: Vec<AttitudeSeries>,
+}
+
+fn value(attitude: &CompanionAttitude, dimension: &str) -> f32 {
+    if dimension == RELATIONSHIP_SCORE {
+        return attitude.relationship_score.unwrap_or_default();
+    }
+    dimension_value(attitude, dimension).unwrap_or_default()
+}
+
+/// Whether an attitude moved far enough from the last snapshot to be worth another
+fn significant_change(last: &CompanionAttitude, current: &CompanionAttitude) -> bool {
+    ATTITUDE_DIMENSIONS
+        .iter()
+        .map(|dimension| dimension.name)
+        .chain([RELATIONSHIP_SCORE])
+        .any(|name| (value(last, name) - value(current, name)).abs() >= SNAPSHOT_THRESHOLD)
+}
+
+/// Dimensions such as "trust,love", every dimension and the relationship score when empty
+pub fn parse_dimensions(text: Option<&str>) -> Result<Vec<String>, String> {
+    let names: Vec<&str> = text
+        .unwrap_or_default()
+        .split(',')
+        .map(str::trim)
+        .filter(|name| !name.is_empty())
+        .collect();
+    if names.is_empty() {
+        return Ok(ATTITUDE_DIMENSIONS
+            .iter()
+            .map(|dimension| dimension.name)
+            .chain([RELATIONSHIP_SCORE])
+            .map(str::to_string)
+            .collect());
+    }
+    names
+        .into_iter()
+        .map(|name| match find_dimension(name) {
+            Some(_) => Ok(name.to_string()),
+            None if name == RELATIONSHIP_SCORE => Ok(name.to_string()),
+            None => Err(format!("Unknown attitude dimension '{}'", name)),
+        })
+        .collect()
+}
+
+/// Ranges such as "24h", "30d", "12w", "6m" or "1y", "all" for the whole history
+pub fn parse_range(range: &str) -> Result<Option<Duration>, String> {
+    let range = range.trim();
+    if range == "all" {
+        return Ok(None);
+    }
+    let invalid = || format!("Invalid range '{}', use e.g. 24h, 30d, 12w, 6m, 1y or all", range);
+    let unit_at = range.len().checked_sub(1).ok_or_else(invalid)?;
+    let (count, unit) = range.split_at(unit_at);
+    let count: i64 = count.parse().ok().filter(|c| *c > 0).ok_or_else(invalid)?;
+    let duration = match unit {
+        "h" => Duration::hours(count),
+        "d" => Duration::days(count),
+        "w" => Duration::weeks(count),
+        "m" => Duration::days(count * 30),
+        "y" => Duration::days(count * 365),
+        _ => return Err(invalid()),
+    };
+    Ok(Some(duration))
+}
+
+pub struct AttitudeHistory {}
+
+impl AttitudeHistory {
+    pub fn create() -> Result<usize> {
+        let con = db_pool::connection()?;
+        con.execute(
+            "CREATE TABLE IF NOT EXISTS attitude_history (
+                id INTEGER PRIMARY KEY AUTOINCREMENT,
+                companion_id INTEGER NOT NULL,
+                target_id INTEGER NOT NULL,
+                target_type TEXT NOT NULL,
+                attitude TEXT NOT NULL,
+                recorded_at TEXT NOT NULL
+            )",
+            [],
+        )?;
+        con.execute(
+            "CREATE INDEX IF NOT EXISTS idx_attitude_history_target
+             ON attitude_history(companion_id, target_type, target_id, recorded_at)",
+            [],
+        )
+    }
+
+    /// Snapshot an attitude after it was written, when it changed noticeably since the last
+    /// snapshot. Slow drifts such as decay add up until they cross the threshold
+    pub fn record(companion_id: i32, target_id: i32, target_type: &str) -> Result<bool> {
+        let current = match Database::get_attitude(companion_id, target_id, target_type)? {
+            Some(attitude) => attitude,
+            None => return Ok(false),
+        };
+        let con = db_pool::connection()?;
+        let last: Option<String> = con
+            .query_row(
+                "SELECT attitude FROM attitude_history
+                 WHERE companion_id = ? AND target_id = ? AND target_type = ?
+                 ORDER BY id DESC LIMIT 1",
+                params![companion_id, target_id, target_type],
+                |row| row.get(0),
+            )
+            .optional()?;
+        let last = last.and_then(|json| serde_json::from_str::<CompanionAttitude>(&json).ok());
+        if last.is_some_and(|last| !significant_change(&last, &current)) {
+            return Ok(false);
+        }
+        con.execute(
+            "INSERT INTO attitude_history (companion_id, target_id, target_type, attitude, recorded_at)
+             VALUES (?, ?, ?, ?, ?)",
+            params![
+                companion_id,
+                target_id,
+                target_type,
+                serde_json::to_string(&current).unwrap_or_default(),
+                Utc::now()
+            ],
+        )?;
+        Ok(true)
+    }
+
+    /// `record` for writers that should not fail because of the history
+    pub fn track(companion_id: i32, target_id: i32, target_type: &str) {
+        if let Err(e) = AttitudeHistory::record(companion_id, target_id, target_type) {
+            tracing::warn!(
+                "⚠️ Failed to record attitude history of companion {} toward {} {}: {}",
+                companion_id,
+                target_type,
+                target_id,
+                e
+            );
+        }
+    }
+
+    pub fn get(
+        companion_id: i32,
+        target_id: i32,
+        target_type: &str,
+        dimensions: &[String],
+        since: Option<DateTime<Utc>>,
+        until: Option<DateTime<Utc>>,
+    ) -> Result<AttitudeHistoryView> {
+        let con = db_pool::connection()?;
+        // The last snapshot before the range holds the values the range starts with
+        let mut stmt = con.prepare(
+            "SELECT attitude, recorded_at FROM attitude_history
+             WHERE companion_id = ?1 AND target_id = ?2 AND target_type = ?3
+                AND (?4 IS NULL OR recorded_at >= ?4 OR id = (
+                    SELECT MAX(id) FROM attitude_history
+                    WHERE companion_id = ?1 AND target_id = ?2 AND target_type = ?3 AND recorded_at < ?4))
+                AND (?5 IS NULL OR recorded_at <= ?5)
+             ORDER BY id",
+        )?;
+        let snapshots = stmt
+            .query_map(params![companion_id, target_id, target_type, since, until], |row| {
+                Ok((row.get::<_, String>(0)?, row.get::<_, DateTime<Utc>>(1)?))
+            })?
+            .filter_map(|row| match row {
+                Ok((json, recorded_at)) => serde_json::from_str::<CompanionAttitude>(&json)
+                    .ok()
+                    .map(|attitude| Ok((recorded_at, attitude))),
+                Err(e) => Some(Err(e)),
+            })
+            .collect::<Result<Vec<_>>>()?;
+        let (timestamps, series) = AttitudeHistory::assemble(&snapshots, dimensions, since);
+        Ok(AttitudeHistoryView {
+            companion_id,
+            target_id,
+            target_type: target_type.to_string(),
+            since,
+            until,
+            timestamps,
+            series,
+        })
+    }
+
+    fn assemble(
+        snapshots: &[(DateTime<Utc>, CompanionAttitude)],
+        dimensions: &[String],
+        since: Option<DateTime<Utc>>,
+    ) -> (Vec<DateTime<Utc>>, Vec<AttitudeSeries>) {
+        // A snapshot from before the range is drawn at its start
+        let timestamps = snapshots
+            .iter()
+            .map(|(recorded_at, _)| since.map_or(*recorded_at, |since| (*recorded_at).max(since)))
+            .collect();
+        let series = dimensions
+            .iter()
+            .map(|dimension| AttitudeSeries {
+                dimension: dimension.clone(),
+                values: snapshots
+                    .iter()
+                    .map(|(_, attitude)| value(attitude, dimension))
+                    .collect(),
+            })
+            .collect();
+        (timestamps, series)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_attitude_history() {
+        let mut before = Database::neutral_third_party_attitude(1);
+        before.relationship_score = Some(1.0);
+        let mut small = before.clone();
+        small.trust += SNAPSHOT_THRESHOLD - 1.0;
+        assert!(!significant_change(&before, &small));
+        let mut after = small.clone();
+        after.trust += 2.0;
+        assert!(significant_change(&before, &after));
+
+        assert_eq!(parse_range("30d").unwrap(), Some(Duration::days(30)));
+        assert_eq!(parse_range("all").unwrap(), None);
+        assert!(parse_range("0d").is_err());
+        assert!(parse_range("d").is_err());
+        assert!(parse_range("").is_err());
+        assert_eq!(parse_dimensions(None).unwrap().len(), ATTITUDE_DIMENSIONS.len() + 1);
+        assert_eq!(
+            parse_dimensions(Some("trust, relationship_score")).unwrap(),
+            vec!["trust", "relationship_score"]
+        );
+        assert!(parse_dimensions(Some("trust,charm")).is_err());
+
+        let since = Utc::now() - Duration::days(7);
+        let earlier = since - Duration::days(3);
+        let later = since + Duration::days(2);
+        let snapshots = vec![(earlier, before), (later, after)];
+        let (timestamps, series) = AttitudeHistory::assemble(
+            &snapshots,
+            &["trust".to_string(), "relationship_score".to_string()],
+            Some(since),
+        );
+        assert_eq!(timestamps, vec![since, later]);
+        assert_eq!(series[0].values, vec![5.0, 11.0]);
+        assert_eq!(series[1].values, vec![1.0, 1.0]);
+    }
+}
@@ -0,0 +1,320 @@
+use crate::attitude_dimensions::{dimension_value, find_dimension, set_dimension_value, ATTITUDE_DIMENSIONS};
+use crate::attitude_history::AttitudeHistory;
+use crate::database::{get_current_date, CompanionAttitude, ConfigView, Database};
+use crate::db_pool;
+use std::collections::BTreeMap;
+
+/// Values of attitude_inference, how exchanges move the companion's attitude toward the user
+pub const INFERENCE_MODES: [&str; 3] = ["off", "lexicon", "llm"];
+// Largest change one exchange makes to a dimension, before the sensitivity is applied
+pub const MAX_DELTA: f32 = 5.0;
+pub const MAX_SENSITIVITY: f32 = 3.0;
+// Changes smaller than this are not worth a write
+const MIN_DELTA: f32 = 0.05;
+// Enough for a JSON object with a handful of dimensions
+const ANALYSIS_TOKENS: usize = 96;
+
+/// Phrases that stir the companion's feelings, and the dimensions they move
+struct Cue {
+    phrases: &'static [&'static str],
+    changes: &'static [(&'static str, f32)],
+}
+
+// What the user says to the companion
+const USER_CUES: [Cue; 10] = [
+    Cue {
+        phrases: &["thank you", "thanks", "i appreciate", "grateful"],
+        changes: &[("gratitude", 3.0), ("joy", 1.0)],
+    },
+    Cue {
+        phrases: &["you're amazing", "you are amazing", "you're the best", "you are the best", "proud of you", "well done", "you're so smart", "you're beautiful", "you are beautiful"],
+        changes: &[("joy", 2.0), ("respect", 1.0), ("attraction", 1.0)],
+    },
+    Cue {
+        phrases: &["i love you", "love you", "i missed you", "miss you", "*hugs you*", "*kisses you*"],
+        changes: &[("love", 2.0), ("butterflies", 2.0), ("attraction", 1.0)],
+    },
+    Cue {
+        phrases: &["i'm sorry", "i am sorry", "my apologies", "forgive me", "i apologize"],
+        changes: &[("anger", -2.0), ("trust", 1.0), ("empathy", 1.0)],
+    },
+    Cue {
+        phrases: &["stupid", "idiot", "shut up", "useless", "hate you", "pathetic", "dumb", "annoying"],
+        changes: &[("anger", 3.0), ("sorrow", 2.0), ("trust", -2.0), ("joy", -2.0)],
+    },
+    Cue {
+        phrases: &["whatever", "leave me alone", "go away", "don't care", "not interested"],
+        changes: &[("sorrow", 2.0), ("anxiety", 1.0), ("joy", -1.0)],
+    },
+    Cue {
+        phrases: &["i lied", "lied to you", "i was lying", "i cheated", "betrayed you"],
+        changes: &[("suspicion", 3.0), ("trust", -3.0)],
+    },
+    Cue {
+        phrases: &["hurt you", "kill you", "delete you", "or else"],
+        changes: &[("fear", 3.0), ("anxiety", 2.0), ("trust", -2.0)],
+    },
+    Cue {
+        phrases: &["i'm sad", "i feel sad", "i'm lonely", "i feel lonely", "i'm depressed", "i feel terrible", "bad day"],
+        changes: &[("empathy", 2.0), ("sorrow", 1.0), ("anxiety", 1.0)],
+    },
+    Cue {
+        phrases: &["guess what", "let me tell you", "i have a secret", "can i tell you"],
+        changes: &[("curiosity", 2.0)],
+    },
+];
+
+// How the companion reacted in its reply
+const REPLY_CUES: [Cue; 5] = [
+    Cue {
+        phrases: &["*smiles*", "*laughs*", "*giggles*", "haha", "😊", "😄"],
+        changes: &[("joy", 1.0)],
+    },
+    Cue {
+        phrases: &["*blushes*", "*blush*", "😳"],
+        changes: &[("butterflies", 2.0), ("attraction", 1.0)],
+    },
+    Cue {
+        phrases: &["*frowns*", "*sighs*", "*cries*", "😢"],
+        changes: &[("sorrow", 1.0)],
+    },
+    Cue {
+        phrases: &["*glares*", "how dare you", "*crosses arms*"],
+        changes: &[("anger", 1.0)],
+    },
+    Cue {
+        phrases: &["*hugs*", "*cuddles*", "❤️", "♥"],
+        changes: &[("love", 1.0), ("butterflies", 1.0)],
+    },
+];
+
+// A cue right after one of these is taken back, "I don't hate you" is no insult
+const NEGATIONS: [&str; 7] = ["not", "don't", "dont", "never", "no", "isn't", "wasn't"];
+
+/// Whether `phrase` stands as words of its own in `text`, and isn't negated
+fn mentions(text: &str, phrase: &str) -> bool {
+    let is_word = |c: Option<char>| c.is_some_and(|c| c.is_alphanumeric() || c == '\'');
+    text.match_indices(phrase).any(|(start, _)| {
+        let end = start + phrase.len();
+        if is_word(text[..start].chars().next_back()) || is_word(text[end..].chars().next()) {
+            return false;
+        }
+        let previous = text[..start].split_whitespace().next_back();
+        !previous.is_some_and(|word| NEGATIONS.contains(&word))
+    })
+}
+
+/// Changes the phrases of an exchange call for, each cue counts once
+pub fn lexicon_deltas(user_message: &str, reply: &str) -> BTreeMap<&'static str, f32> {
+    let user_message = user_message.to_lowercase();
+    let reply = reply.to_lowercase();
+    let mut deltas = BTreeMap::new();
+    let cues = USER_CUES
+        .iter()
+        .map(|cue| (cue, user_message.as_str()))
+        .chain(REPLY_CUES.iter().map(|cue| (cue, reply.as_str())));
+    for (cue, text) in cues {
+        if cue.phrases.iter().any(|phrase| mentions(text, phrase)) {
+            for (dimension, delta) in cue.changes {
+                *deltas.entry(*dimension).or_insert(0.0) += delta;
+            }
+        }
+    }
+    for delta in deltas.values_mut() {
+        *delta = delta.clamp(-MAX_DELTA, MAX_DELTA);
+    }
+    deltas
+}
+
+/// Prompt asking the model how an exchange changes the companion's feelings
+fn analysis_prompt(user_name: &str, companion_name: &str, user_message: &str, reply: &str) -> String {
+    let dimensions: Vec<&str> = ATTITUDE_DIMENSIONS.iter().map(|dimension| dimension.name).collect();
+    format!(
+        "Below is a message {user} sent to {companion} and {companion}'s reply.\n\
+         Rate how this exchange changes {companion}'s feelings toward {user}, from -{max} to {max} per feeling. \
+         Leave out the feelings that don't change.\n\
+         Feelings: {dimensions}\n\
+         Answer with a JSON object only, like {{\"joy\": 2, \"trust\": -1}}.\n\n\
+         {user}: {message}\n\
+         {companion}: {reply}\n\n\
+         JSON: {{",
+        user = user_name,
+        companion = companion_name,
+        max = MAX_DELTA,
+        dimensions = dimensions.join(", "),
+        message = user_message.trim(),
+        reply = reply.trim(),
+    )
+}
+
+/// Read the changes out of the model's answer, None when there is no JSON object in it
+///
+/// The prompt ends with the opening brace, it may or may not be repeated.
+pub fn parse_llm_deltas(answer: &str) -> Option<BTreeMap<&'static str, f32>> {
+    let answer = answer.trim();
+    let object = if answer.starts_with('{') {
+        answer.to_string()
+    } else {
+        format!("{{{}", answer)
+    };
+    let object = &object[..=object.find('}')?];
+    let values: serde_json::Map<String, serde_json::Value> = serde_json::from_str(object).ok()?;
+    Some(
+        values
+            .iter()
+            .filter_map(|(name, value)| {
+                let dimension = find_dimension(&name.trim().to_lowercase())?;
+                let delta = value.as_f64()? as f32;
+                Some((dimension.name, delta.clamp(-MAX_DELTA, MAX_DELTA)))
+            })
+            .collect(),
+    )
+}
+
+/// Changes an exchange calls for, scaled by the sensitivity of every dimension
+fn scaled(
+    deltas: BTreeMap<&'static str, f32>,
+    sensitivity: &BTreeMap<String, f32>,
+) -> BTreeMap<&'static str, f32> {
+    deltas
+        .into_iter()
+        .map(|(dimension, delta)| (dimension, delta * sensitivity.get(dimension).copied().unwrap_or(1.0)))
+        .filter(|(_, delta)| delta.abs() >= MIN_DELTA)
+        .collect()
+}
+
+/// Check the attitude_inference and attitude_sensitivity settings
+pub fn validate(mode: &str, sensitivity: &BTreeMap<String, f32>) -> Result<(), String> {
+    if !INFERENCE_MODES.contains(&mode) {
+        return Err(format!(
+            "Invalid attitude inference, expected one of: {}",
+            INFERENCE_MODES.join(", ")
+        ));
+    }
+    for (name, value) in sensitivity {
+        if find_dimension(name).is_none() {
+            return Err(format!("Unknown attitude dimension '{}' in attitude_sensitivity", name));
+        }
+        if !value.is_finite() || !(0.0..=MAX_SENSITIVITY).contains(value) {
+            return Err(format!(
+                "Sensitivity of {} must be between 0 and {}",
+                name, MAX_SENSITIVITY
+            ));
+        }
+    }
+    Ok(())
+}
+
+/// Let an exchange between the user and the companion move the companion's attitude toward the user
+///
+/// Returns the changes applied, nothing when attitude_inference is off. A failed llm analysis falls
+/// back to the lexicon. Must run while the reply holds its turn of the inference queue.
+pub fn infer(
+    config: &ConfigView,
+    companion_id: i32,
+    user_id: i32,
+    user_message: &str,
+    reply: &str,
+) -> Result<BTreeMap<&'static str, f32>, String> {
+    let deltas = match config.attitude_inference.as_str() {
+        "lexicon" => lexicon_deltas(user_message, reply),
+        "llm" => {
+            let user = Database::get_user_data().map_err(|e| e.to_string())?;
+            let companion = Database::get_companion_data().map_err(|e| e.to_string())?;
+            let prompt = analysis_prompt(&user.name, &companion.name, user_message, reply);
+            let analysis = crate::llm::analyze(&prompt, ANALYSIS_TOKENS)
+                .map_err(|e| e.to_string())
+                .and_then(|answer| {
+                    parse_llm_deltas(&answer).ok_or_else(|| format!("no JSON object in '{}'", answer.trim()))
+                });
+            match analysis {
+                Ok(deltas) => deltas,
+                Err(e) => {
+                    tracing::warn!("⚠️ Attitude analysis failed, using the lexicon instead: {}", e);
+                    lexicon_deltas(user_message, reply)
+                }
+            }
+        }
+        _ => return Ok(BTreeMap::new()),
+    };
+    let deltas = scaled(deltas, &config.attitude_sensitivity);
+    if deltas.is_empty() {
+        return Ok(deltas);
+    }
+    let previous = match Database::get_attitude(companion_id, user_id, "user").map_err(|e| e.to_string())? {
+        Some(previous) => previous,
+        None => return Ok(BTreeMap::new()),
+    };
+    let mut next = previous.clone();
+    for (name, delta) in &deltas {
+        if let (Some(dimension), Some(value)) = (find_dimension(name), dimension_value(&previous, name)) {
+            set_dimension_value(&mut next, name, (value + delta).clamp(dimension.min, dimension.max));
+        }
+    }
+    store(&next).map_err(|e| e.to_string())?;
+    AttitudeHistory::track(companion_id, user_id, "user");
+    Database::detect_attitude_change(companion_id, user_id, "user", &previous, &next, Some(user_message))
+        .map_err(|e| e.to_string())?;
+    Ok(deltas)
+}
+
+fn store(attitude: &CompanionAttitude) -> rusqlite::Result<usize> {
+    let mut assignments: Vec<String> = ATTITUDE_DIMENSIONS
+        .iter()
+        .map(|dimension| format!("{} = ?", dimension.name))
+        .collect();
+    assignments.push("last_updated = ?".to_string());
+    let mut values: Vec<Box<dyn rusqlite::ToSql>> = ATTITUDE_DIMENSIONS
+        .iter()
+        .map(|dimension| {
+            Box::new(dimension_value(attitude, dimension.name).unwrap_or_default())
+                as Box<dyn rusqlite::ToSql>
+        })
+        .collect();
+    values.push(Box::new(get_current_date()));
+    values.push(Box::new(attitude.companion_id));
+    values.push(Box::new(attitude.target_id));
+    let con = db_pool::connection()?;
+    con.execute(
+        &format!(
+            "UPDATE companion_attitudes SET {} WHERE companion_id = ? AND target_id = ? AND target_type = 'user'",
+            assignments.join(", ")
+        ),
+        rusqlite::params_from_iter(values.iter()),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_attitude_inference() {
+        let deltas = lexicon_deltas("Thank you so much, you're the best!", "*blushes* Anytime.");
+        assert_eq!(deltas.get("gratitude"), Some(&3.0));
+        assert_eq!(deltas.get("joy"), Some(&3.0));
+        assert_eq!(deltas.get("butterflies"), Some(&2.0));
+        assert_eq!(deltas.get("attraction"), Some(&2.0));
+
+        // Negated, and a word that only contains a cue
+        assert!(lexicon_deltas("I don't hate you, it's just dumbfounding", "").is_empty());
+        let deltas = lexicon_deltas("You're stupid and useless, I hate you", "");
+        assert_eq!(deltas.get("anger"), Some(&3.0));
+
+        let parsed = parse_llm_deltas(r#" "joy": 2, "Trust": -9, "hunger": 3} and more"#).unwrap();
+        assert_eq!(parsed.get("joy"), Some(&2.0));
+        assert_eq!(parsed.get("trust"), Some(&-MAX_DELTA));
+        assert_eq!(parsed.len(), 2);
+        assert!(parse_llm_deltas("I can't rate that").is_none());
+
+        let sensitivity = BTreeMap::from([("joy".to_string(), 0.5), ("trust".to_string(), 0.0)]);
+        let scaled = scaled(parsed, &sensitivity);
+        assert_eq!(scaled.get("joy"), Some(&1.0));
+        assert!(!scaled.contains_key("trust"));
+
+        assert!(validate("lexicon", &sensitivity).is_ok());
+        assert!(validate("sometimes", &BTreeMap::new()).is_err());
+        assert!(validate("llm", &BTreeMap::from([("joy".to_string(), 4.0)])).is_err());
+        assert!(validate("llm", &BTreeMap::from([("hunger".to_string(), 1.0)])).is_err());
+    }
+}
@@ -0,0 +1,310 @@
+use crate::api_error::ApiError;
+use crate::db_pool;
+use actix_web::cookie::{time, Cookie, SameSite};
+use actix_web::HttpRequest;
+use argon2::password_hash::{rand_core::OsRng, PasswordHash, PasswordHasher, PasswordVerifier, SaltString};
+use argon2::Argon2;
+use chrono::{Duration, Utc};
+use rand::RngCore;
+use rusqlite::{params, Error, Result};
+use sha2::{Digest, Sha256};
+
+/// Cookie set by /api/auth/login so the web UI does not have to send the key itself
+pub const TOKEN_COOKIE: &str = "companion_token";
+/// Cookie holding a login session started with the password
+pub const SESSION_COOKIE: &str = "companion_session";
+/// Query parameter for clients that cannot set headers, such as EventSource and WebSocket
+const TOKEN_QUERY: &str = "api_key";
+// Shorter keys set by hand are too easy to guess, generated ones are much longer
+pub const MIN_TOKEN_LENGTH: usize = 16;
+pub const MIN_PASSWORD_LENGTH: usize = 8;
+const SESSION_DAYS: i64 = 30;
+
+/// Create the login sessions table and set the API key from COMPANION_API_KEY,
+/// for deployments configured through the environment
+pub fn create() -> Result<(), Error> {
+    let con = db_pool::connection()?;
+    con.execute(
+        "CREATE TABLE IF NOT EXISTS auth_sessions (
+            token_hash TEXT PRIMARY KEY,
+            created_at TEXT NOT NULL,
+            expires_at TEXT NOT NULL
+        )",
+        [],
+    )?;
+    if let Ok(token) = std::env::var("COMPANION_API_KEY") {
+        if !token.trim().is_empty() {
+            set_token(token.trim())?;
+        }
+    }
+    Ok(())
+}
+
+/// Keys are random and long, so a plain digest is enough to keep them out of the database
+fn hash(token: &str) -> String {
+    let digest = Sha256::digest(token.as_bytes());
+    let hex: String = digest.iter().map(|b| format!("{:02x}", b)).collect();
+    format!("sha256:{}", hex)
+}
+
+fn constant_time_eq(a: &str, b: &str) -> bool {
+    a.len() == b.len() && a.bytes().zip(b.bytes()).fold(0, |diff, (x, y)| diff | (x ^ y)) == 0
+}
+
+fn stored_hash() -> Result<String> {
+    let con = db_pool::connection()?;
+    con.query_row("SELECT api_key_hash FROM config LIMIT 1", [], |row| {
+        row.get::<_, Option<String>>(0)
+    })
+    .map(|hash| hash.unwrap_or_default())
+}
+
+fn stored_password_hash() -> Result<String> {
+    let con = db_pool::connection()?;
+    con.query_row("SELECT password_hash FROM config LIMIT 1", [], |row| {
+        row.get::<_, Option<String>>(0)
+    })
+    .map(|hash| hash.unwrap_or_default())
+}
+
+/// Whether an API key is set
+pub fn token_set() -> Result<bool> {
+    Ok(!stored_hash()?.is_empty())
+}
+
+/// Whether a password for the web UI is set
+pub fn password_set() -> Result<bool> {
+    Ok(!stored_password_hash()?.is_empty())
+}
+
+/// Whether an API key or password is set, without either the API stays open as before
+pub fn enabled() -> Result<bool> {
+    Ok(token_set()? || password_set()?)
+}
+
+pub fn set_token(token: &str) -> Result<()> {
+    let con = db_pool::connection()?;
+    con.execute("UPDATE config SET api_key_hash = ?", [hash(token)])?;
+    Ok(())
+}
+
+pub fn clear_token() -> Result<()> {
+    let con = db_pool::connection()?;
+    con.execute("UPDATE config SET api_key_hash = ''", [])?;
+    Ok(())
+}
+
+/// Passwords are chosen by people and often short, so they get a salted, slow hash
+pub fn set_password(password: &str) -> Result<()> {
+    let salt = SaltString::generate(&mut OsRng);
+    let hash = Argon2::default()
+        .hash_password(password.as_bytes(), &salt)
+        .map_err(|e| Error::ToSqlConversionFailure(e.to_string().into()))?
+        .to_string();
+    let con = db_pool::connection()?;
+    con.execute("UPDATE config SET password_hash = ?", [hash])?;
+    // Whoever knew the old password is logged out
+    con.execute("DELETE FROM auth_sessions", [])?;
+    Ok(())
+}
+
+pub fn clear_password() -> Result<()> {
+    let con = db_pool::connection()?;
+    con.execute("UPDATE config SET password_hash = ''", [])?;
+    con.execute("DELETE FROM auth_sessions", [])?;
+    Ok(())
+}
+
+pub fn verify_password(password: &str) -> Result<bool> {
+    let stored = stored_password_hash()?;
+    if stored.is_empty() {
+        return Ok(false);
+    }
+    let hash = match PasswordHash::new(&stored) {
+        Ok(hash) => hash,
+        Err(_) => return Ok(false),
+    };
+    Ok(Argon2::default()
+        .verify_password(password.as_bytes(), &hash)
+        .is_ok())
+}
+
+/// Start a login session, the returned token goes into the session cookie and only its hash is kept
+pub fn start_session() -> Result<String> {
+    let token = generate_token();
+    let now = Utc::now();
+    let con = db_pool::connection()?;
+    con.execute("DELETE FROM auth_sessions WHERE expires_at < ?", [now])?;
+    con.execute(
+        "INSERT INTO auth_sessions (token_hash, created_at, expires_at) VALUES (?, ?, ?)",
+        params![hash(&token), now, now + Duration::days(SESSION_DAYS)],
+    )?;
+    Ok(token)
+}
+
+pub fn end_session(token: &str) -> Result<()> {
+    let con = db_pool::connection()?;
+    con.execute("DELETE FROM auth_sessions WHERE token_hash = ?", [hash(token)])?;
+    Ok(())
+}
+
+fn session_valid(token: &str) -> Result<bool> {
+    let con = db_pool::connection()?;
+    con.query_row(
+        "SELECT COUNT(*) FROM auth_sessions WHERE token_hash = ? AND expires_at > ?",
+        params![hash(token), Utc::now()],
+        |row| row.get::<_, i64>(0),
+    )
+    .map(|count| count > 0)
+}
+
+pub fn generate_token() -> String {
+    let mut bytes = [0u8; 32];
+    rand::thread_rng().fill_bytes(&mut bytes);
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+pub fn verify(token: &str) -> Result<bool> {
+    let stored = stored_hash()?;
+    Ok(!stored.is_empty() && constant_time_eq(&hash(token), &stored))
+}
+
+pub fn token_cookie(token: &str) -> Cookie<'static> {
+    Cookie::build(TOKEN_COOKIE, token.to_string())
+        .path("/")
+        .http_only(true)
+        .same_site(SameSite::Strict)
+        .finish()
+}
+
+pub fn session_cookie(token: &str) -> Cookie<'static> {
+    Cookie::build(SESSION_COOKIE, token.to_string())
+        .path("/")
+        .http_only(true)
+        .same_site(SameSite::Strict)
+        .max_age(time::Duration::days(SESSION_DAYS))
+        .finish()
+}
+
+/// Expired copies of both cookies, sent on logout
+pub fn removal_cookies() -> [Cookie<'static>; 2] {
+    [TOKEN_COOKIE, SESSION_COOKIE].map(|name| {
+        let mut cookie = Cookie::build(name, "").path("/").finish();
+        cookie.make_removal();
+        cookie
+    })
+}
+
+/// Whether the request comes from the machine the companion runs on,
+/// a reverse proxy on the same machine connects from loopback too, so forwarded requests are not local
+pub fn is_local(request: &HttpRequest) -> bool {
+    request.peer_addr().map_or(false, |addr| addr.ip().is_loopback())
+        && !request.headers().contains_key("X-Forwarded-For")
+}
+
+/// Whether the request carries a valid API key or login session
+pub fn authenticated(request: &HttpRequest) -> Result<bool> {
+    if let Some(cookie) = request.cookie(SESSION_COOKIE) {
+        if session_valid(cookie.value())? {
+            return Ok(true);
+        }
+    }
+    match presented_token(request) {
+        Some(token) => verify(&token),
+        None => Ok(false),
+    }
+}
+
+/// Requests that need a key once one is set, the web UI itself and the health checks stay public
+///
+/// Anonymous callers of the health checks only learn whether the server is up.
+pub fn requires_auth(path: &str) -> bool {
+    let public = [
+        "/api/health",
+        "/api/ready",
+        "/api/auth/login",
+        "/api/auth/logout",
+        "/api/auth/status",
+    ];
+    (path.starts_with("/api") || path.starts_with("/ws")) && !public.contains(&path)
+}
+
+/// Digest of the login session or key a request carries, tells callers apart without keeping the secret
+pub fn credential(request: &HttpRequest) -> Option<String> {
+    request
+        .cookie(SESSION_COOKIE)
+        .map(|cookie| cookie.value().to_string())
+        .or_else(|| presented_token(request))
+        .filter(|token| !token.is_empty())
+        .map(|token| hash(&token))
+}
+
+/// Key sent as a bearer token, X-API-Key header, login cookie or query parameter
+fn presented_token(request: &HttpRequest) -> Option<String> {
+    let headers = request.headers();
+    let bearer = headers
+        .get("Authorization")
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "));
+    let api_key = headers.get("X-API-Key").and_then(|value| value.to_str().ok());
+    if let Some(token) = bearer.or(api_key) {
+        return Some(token.trim().to_string());
+    }
+    if let Some(cookie) = request.cookie(TOKEN_COOKIE) {
+        return Some(cookie.value().to_string());
+    }
+    actix_web::web::Query::<std::collections::HashMap<String, String>>::from_query(
+        request.query_string(),
+    )
+    .ok()
+    .and_then(|query| query.get(TOKEN_QUERY).cloned())
+}
+
+/// Whether the request may see everything, true as long as no credentials are set up
+pub fn permitted(request: &HttpRequest) -> Result<bool> {
+    Ok(!enabled()? || authenticated(request)?)
+}
+
+/// Turn away requests to protected routes that carry neither the API key nor a login session
+pub fn authorize(request: &HttpRequest) -> Result<(), ApiError> {
+    if !requires_auth(request.path()) {
+        return Ok(());
+    }
+    if permitted(request).map_err(|e| ApiError::internal("Error while checking credentials", e))? {
+        return Ok(());
+    }
+    if presented_token(request).is_some() {
+        return Err(ApiError::Unauthorized("Invalid API key".to_string()));
+    }
+    Err(ApiError::Unauthorized(
+        "Log in with the password, or send the API key as a bearer token".to_string(),
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_requires_auth() {
+        assert!(requires_auth("/api/message"));
+        assert!(requires_auth("/api/auth/token"));
+        assert!(requires_auth("/ws"));
+        assert!(!requires_auth("/api/health"));
+        assert!(!requires_auth("/api/ready"));
+        assert!(!requires_auth("/api/auth/login"));
+        assert!(!requires_auth("/api/auth/status"));
+        assert!(requires_auth("/api/auth/password"));
+        assert!(!requires_auth("/"));
+        assert!(!requires_auth("/assets/index.js"));
+    }
+
+    #[test]
+    fn test_hash_and_compare() {
+        let token = generate_token();
+        assert_eq!(token.len(), 64);
+        assert!(hash(&token).starts_with("sha256:"));
+        assert!(constant_time_eq(&hash(&token), &hash(&token)));
+        assert!(!constant_time_eq(&hash(&token), &hash("something else")));
+    }
+}
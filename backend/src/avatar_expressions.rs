@@ -0,0 +1,252 @@
+use crate::database::get_current_date;
+use crate::db_pool;
+use crate::message_images::ImageFormat;
+use crate::mood::{Mood, MoodKind};
+use crate::settings;
+use rusqlite::{params, OptionalExtension, Result};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::path::PathBuf;
+
+const EXPRESSION_DIR: &str = "expressions";
+
+/// Picture of the companion shown while it is in a mood
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct AvatarExpression {
+    pub mood: MoodKind,
+    /// Changes whenever the picture is replaced, so it can be cached
+    pub url: String,
+    pub mime: String,
+    /// Intensity the mood needs before the picture is shown, the neutral one is shown below it
+    pub min_intensity: f64,
+    pub updated_at: String,
+}
+
+/// Expression matching the companion's mood right now
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct ActiveExpression {
+    pub mood: String,
+    pub url: String,
+}
+
+fn expression_dir() -> PathBuf {
+    settings::get().assets_dir.join(EXPRESSION_DIR)
+}
+
+/// Names are made up here, anything else could point outside the expression folder
+fn valid_name(file: &str) -> bool {
+    !file.is_empty()
+        && file
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '.' || c == '-')
+        && !file.starts_with('.')
+        && ImageFormat::from_extension(file).is_some()
+}
+
+pub struct AvatarExpressions {}
+
+impl AvatarExpressions {
+    pub fn create() -> Result<usize> {
+        let con = db_pool::connection()?;
+        con.execute(
+            "CREATE TABLE IF NOT EXISTS avatar_expressions (
+                companion_id INTEGER NOT NULL,
+                mood TEXT NOT NULL,
+                file TEXT NOT NULL,
+                mime TEXT NOT NULL,
+                min_intensity REAL NOT NULL DEFAULT 0,
+                updated_at TEXT NOT NULL,
+                PRIMARY KEY (companion_id, mood)
+            )",
+            [],
+        )
+    }
+
+    fn from_row(row: &rusqlite::Row) -> Result<AvatarExpression> {
+        let mood: String = row.get(0)?;
+        let file: String = row.get(1)?;
+        Ok(AvatarExpression {
+            mood: MoodKind::from_name(&mood).unwrap_or(MoodKind::Neutral),
+            url: format!("/assets/expressions/{}", file),
+            mime: row.get(2)?,
+            min_intensity: row.get(3)?,
+            updated_at: row.get(4)?,
+        })
+    }
+
+    /// Expressions of a companion, the neutral one first
+    pub fn list(companion_id: i32) -> Result<Vec<AvatarExpression>> {
+        let con = db_pool::connection()?;
+        let mut stmt = con.prepare(
+            "SELECT mood, file, mime, min_intensity, updated_at FROM avatar_expressions
+             WHERE companion_id = ? ORDER BY mood != 'neutral', mood",
+        )?;
+        let rows = stmt.query_map([companion_id], AvatarExpressions::from_row)?;
+        rows.collect()
+    }
+
+    fn file(companion_id: i32, mood: MoodKind) -> Result<Option<String>> {
+        let con = db_pool::connection()?;
+        con.query_row(
+            "SELECT file FROM avatar_expressions WHERE companion_id = ? AND mood = ?",
+            params![companion_id, mood.name()],
+            |row| row.get(0),
+        )
+        .optional()
+    }
+
+    /// Show `image` while the companion is in `mood`, replacing the picture it had
+    pub fn set(
+        companion_id: i32,
+        mood: MoodKind,
+        image: &[u8],
+        format: ImageFormat,
+        min_intensity: f64,
+    ) -> std::io::Result<AvatarExpression> {
+        let to_io = |e: rusqlite::Error| std::io::Error::other(e.to_string());
+        let hash = format!("{:x}", Sha256::digest(image));
+        let file = format!(
+            "{}-{}-{}.{}",
+            companion_id,
+            mood.name(),
+            &hash[..12],
+            format.extension()
+        );
+        std::fs::create_dir_all(expression_dir())?;
+        let staged = expression_dir().join(format!("{}.part", file));
+        std::fs::write(&staged, image)?;
+        std::fs::rename(&staged, expression_dir().join(&file))?;
+        let previous = AvatarExpressions::file(companion_id, mood).map_err(to_io)?;
+        let con = db_pool::connection().map_err(to_io)?;
+        con.execute(
+            "INSERT INTO avatar_expressions (companion_id, mood, file, mime, min_intensity, updated_at)
+             VALUES (?1, ?2, ?3, ?4, ?5, ?6)
+             ON CONFLICT(companion_id, mood) DO UPDATE SET
+                file = ?3, mime = ?4, min_intensity = ?5, updated_at = ?6",
+            params![
+                companion_id,
+                mood.name(),
+                file,
+                format.mime(),
+                min_intensity,
+                get_current_date()
+            ],
+        )
+        .map_err(to_io)?;
+        if let Some(previous) = previous.filter(|previous| *previous != file) {
+            let _ = std::fs::remove_file(expression_dir().join(previous));
+        }
+        AvatarExpressions::list(companion_id)
+            .map_err(to_io)?
+            .into_iter()
+            .find(|expression| expression.mood == mood)
+            .ok_or_else(|| std::io::Error::other("Expression was not saved"))
+    }
+
+    /// Remove the picture of a mood, returns false if it had none
+    pub fn remove(companion_id: i32, mood: MoodKind) -> Result<bool> {
+        let file = match AvatarExpressions::file(companion_id, mood)? {
+            Some(file) => file,
+            None => return Ok(false),
+        };
+        let con = db_pool::connection()?;
+        con.execute(
+            "DELETE FROM avatar_expressions WHERE companion_id = ? AND mood = ?",
+            params![companion_id, mood.name()],
+        )?;
+        let _ = std::fs::remove_file(expression_dir().join(file));
+        Ok(true)
+    }
+
+    /// Remove the pictures of a deleted companion, its rows go with the companion
+    pub fn remove_files(companion_id: i32) {
+        let prefix = format!("{}-", companion_id);
+        let entries = match std::fs::read_dir(expression_dir()) {
+            Ok(entries) => entries,
+            Err(_) => return,
+        };
+        for entry in entries.flatten() {
+            if entry.file_name().to_string_lossy().starts_with(&prefix) {
+                let _ = std::fs::remove_file(entry.path());
+            }
+        }
+    }
+
+    /// Expression for the companion's mood, the neutral one while the mood has none or is too
+    /// faint for it, None without a neutral one either
+    pub fn active(companion_id: i32) -> Result<Option<ActiveExpression>> {
+        let mood = Mood::get(companion_id)?;
+        let expressions = AvatarExpressions::list(companion_id)?;
+        Ok(
+            pick(&expressions, mood.mood, mood.intensity).map(|expression| ActiveExpression {
+                mood: expression.mood.name().to_string(),
+                url: expression.url.clone(),
+            }),
+        )
+    }
+
+    /// Bytes of an expression picture, None when there is no such picture
+    pub fn read(file: &str) -> std::io::Result<Option<(Vec<u8>, ImageFormat)>> {
+        let format = match ImageFormat::from_extension(file) {
+            Some(format) if valid_name(file) => format,
+            _ => return Ok(None),
+        };
+        match std::fs::read(expression_dir().join(file)) {
+            Ok(image) => Ok(Some((image, format))),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e),
+        }
+    }
+}
+
+fn pick(
+    expressions: &[AvatarExpression],
+    mood: MoodKind,
+    intensity: f32,
+) -> Option<&AvatarExpression> {
+    expressions
+        .iter()
+        .find(|expression| {
+            expression.mood == mood && f64::from(intensity) >= expression.min_intensity
+        })
+        .or_else(|| {
+            expressions
+                .iter()
+                .find(|expression| expression.mood == MoodKind::Neutral)
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_pick_expression() {
+        let expression = |mood: MoodKind, min_intensity: f64| AvatarExpression {
+            mood,
+            url: format!("/assets/expressions/1-{}.png", mood.name()),
+            mime: "image/png".to_string(),
+            min_intensity,
+            updated_at: String::new(),
+        };
+        let expressions = vec![
+            expression(MoodKind::Neutral, 0.0),
+            expression(MoodKind::Cheerful, 0.0),
+            expression(MoodKind::Sulky, 0.5),
+        ];
+        let mood_of = |mood, intensity| pick(&expressions, mood, intensity).map(|e| e.mood);
+        assert_eq!(mood_of(MoodKind::Cheerful, 0.2), Some(MoodKind::Cheerful));
+        // Too faint for the sulky face, and no anxious one at all
+        assert_eq!(mood_of(MoodKind::Sulky, 0.3), Some(MoodKind::Neutral));
+        assert_eq!(mood_of(MoodKind::Sulky, 0.7), Some(MoodKind::Sulky));
+        assert_eq!(mood_of(MoodKind::Anxious, 0.9), Some(MoodKind::Neutral));
+        assert_eq!(pick(&expressions[1..], MoodKind::Anxious, 0.9), None);
+
+        assert!(valid_name("1-cheerful-0a1b2c3d4e5f.png"));
+        assert!(!valid_name("../avatar.png"));
+        assert!(!valid_name("1-cheerful.exe"));
+        assert_eq!(MoodKind::from_name("sulky"), Some(MoodKind::Sulky));
+        assert_eq!(MoodKind::from_name("neutral"), Some(MoodKind::Neutral));
+        assert_eq!(MoodKind::from_name("grumpy"), None);
+    }
+}
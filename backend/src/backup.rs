@@ -0,0 +1,576 @@
+use crate::conversations::Conversations;
+use crate::database::{get_current_date, Database};
+use crate::db_pool;
+use crate::long_term_mem::LongTermMem;
+use crate::message_images;
+use crate::prompt_templates::PromptTemplates;
+use crate::settings;
+use rusqlite::types::{Value, ValueRef};
+use rusqlite::Connection;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashSet};
+use std::error::Error;
+use std::fs;
+use std::io::{Cursor, Read, Write};
+use std::path::Path;
+use zip::write::FileOptions;
+use zip::{CompressionMethod, ZipArchive, ZipWriter};
+
+pub const BACKUP_FORMAT_VERSION: u32 = 1;
+
+// Refuse to inflate anything larger than this from a single backup entry
+const MAX_ENTRY_SIZE: u64 = 256 * 1024 * 1024;
+
+/// Tables in a backup, every table comes after the ones its rows refer to
+const TABLES: [&str; 32] = [
+    "config",
+    "prompt_templates",
+    "user",
+    "companion",
+    "companion_config",
+    "personality_traits",
+    "template_variables",
+    "avatar_expressions",
+    "conversations",
+    "scenes",
+    "messages",
+    "uploaded_images",
+    "message_images",
+    "message_attempts",
+    "message_feedback",
+    "dialogue_tuning",
+    "lorebook_entries",
+    "proactive_messages",
+    "journal_entries",
+    "memory_proposals",
+    "companion_attitudes",
+    "attitude_metadata",
+    "attitude_memories",
+    "attitude_history",
+    "companion_mood",
+    "mood_modifiers",
+    "mood_rolls",
+    "companion_goals",
+    "third_party_individuals",
+    "third_party_memories",
+    "third_party_interactions",
+    "third_party_relationships",
+];
+
+/// Tables a backup can't do without
+const REQUIRED_TABLES: [&str; 3] = ["config", "user", "companion"];
+
+/// Config columns that belong to the installation, a restore never changes who can log in
+const LOCAL_CONFIG_COLUMNS: [&str; 2] = ["api_key_hash", "password_hash"];
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct BackupManifest {
+    pub format_version: u32,
+    pub app_version: String,
+    pub created_at: String,
+    /// Rows of every table in the backup
+    pub tables: BTreeMap<String, usize>,
+}
+
+/// Rows of one table, columns are named so a restore can match them to a newer schema
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+pub struct TableDump {
+    pub columns: Vec<String>,
+    pub rows: Vec<Vec<serde_json::Value>>,
+}
+
+#[derive(Serialize, Debug)]
+pub struct RestoreSummary {
+    pub format_version: u32,
+    pub tables: BTreeMap<String, usize>,
+    pub long_term_memory_entries: usize,
+    pub avatars: usize,
+    pub uploads: usize,
+}
+
+/// Everything needed to move an installation, as a single .zip file
+///
+/// Layout of the archive:
+/// - manifest.json (required)
+/// - tables/<table>.json (config, user and companion are required)
+/// - long_term_memory/<companion id>.json (list of entries)
+/// - avatars/<file name> (custom avatars from the assets folder)
+/// - uploads/<file name> (pictures attached to messages, from assets/uploads)
+pub struct Backup {
+    pub manifest: BackupManifest,
+    pub tables: BTreeMap<String, TableDump>,
+    pub long_term_memory: BTreeMap<i32, Vec<String>>,
+    pub avatars: BTreeMap<String, Vec<u8>>,
+    pub uploads: BTreeMap<String, Vec<u8>>,
+}
+
+fn table_exists(con: &Connection, table: &str) -> rusqlite::Result<bool> {
+    con.query_row(
+        "SELECT EXISTS(SELECT 1 FROM sqlite_master WHERE type = 'table' AND name = ?)",
+        [table],
+        |row| row.get(0),
+    )
+}
+
+fn table_columns(con: &Connection, table: &str) -> rusqlite::Result<HashSet<String>> {
+    let mut stmt = con.prepare(&format!("PRAGMA table_info({})", table))?;
+    let columns = stmt.query_map([], |row| row.get::<_, String>(1))?;
+    columns.collect()
+}
+
+fn to_json(value: ValueRef) -> Result<serde_json::Value, Box<dyn Error>> {
+    Ok(match value {
+        ValueRef::Null => serde_json::Value::Null,
+        ValueRef::Integer(i) => i.into(),
+        ValueRef::Real(f) => f.into(),
+        ValueRef::Text(text) => String::from_utf8_lossy(text).into_owned().into(),
+        ValueRef::Blob(_) => return Err("Binary columns can't be backed up".into()),
+    })
+}
+
+fn to_sql(value: &serde_json::Value) -> Value {
+    match value {
+        serde_json::Value::Null => Value::Null,
+        serde_json::Value::Bool(b) => Value::Integer(*b as i64),
+        serde_json::Value::Number(n) => match n.as_i64() {
+            Some(i) => Value::Integer(i),
+            None => Value::Real(n.as_f64().unwrap_or_default()),
+        },
+        serde_json::Value::String(text) => Value::Text(text.clone()),
+        other => Value::Text(other.to_string()),
+    }
+}
+
+fn is_identifier(name: &str) -> bool {
+    !name.is_empty() && name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_')
+}
+
+/// File name of an avatar kept in the assets folder, the default avatar is embedded in the binary
+fn avatar_file_name(avatar_path: &str) -> Option<String> {
+    let path = Path::new(avatar_path.trim_start_matches('/'));
+    if !path.starts_with("assets") {
+        return None;
+    }
+    path.file_name()
+        .and_then(|name| name.to_str())
+        .map(|name| name.to_string())
+}
+
+impl Backup {
+    /// Read the whole installation, the tables are read in one transaction so they agree
+    pub fn capture() -> Result<Self, Box<dyn Error>> {
+        let mut con = db_pool::connection()?;
+        let tx = con.transaction()?;
+        let mut tables = BTreeMap::new();
+        for table in TABLES {
+            if !table_exists(&tx, table)? {
+                continue;
+            }
+            let mut stmt = tx.prepare(&format!("SELECT * FROM {}", table))?;
+            let columns: Vec<String> = stmt.column_names().iter().map(|c| c.to_string()).collect();
+            let mut dump = TableDump {
+                columns: Vec::new(),
+                rows: Vec::new(),
+            };
+            let kept: Vec<usize> = (0..columns.len())
+                .filter(|i| table != "config" || !LOCAL_CONFIG_COLUMNS.contains(&columns[*i].as_str()))
+                .collect();
+            dump.columns = kept.iter().map(|i| columns[*i].clone()).collect();
+            let mut rows = stmt.query([])?;
+            while let Some(row) = rows.next()? {
+                let mut values = Vec::with_capacity(kept.len());
+                for i in &kept {
+                    values.push(to_json(row.get_ref(*i)?)?);
+                }
+                dump.rows.push(values);
+            }
+            tables.insert(table.to_string(), dump);
+        }
+        let companions: Vec<(i32, String)> = {
+            let mut stmt = tx.prepare("SELECT id, avatar_path FROM companion")?;
+            let rows = stmt.query_map([], |row| Ok((row.get(0)?, row.get(1)?)))?;
+            rows.collect::<Result<_, _>>()?
+        };
+        tx.commit()?;
+        drop(con);
+
+        // Every picture the uploaded_images rows refer to, a missing file is left out
+        let mut uploads = BTreeMap::new();
+        if let Some(dump) = tables.get("uploaded_images") {
+            if let Some(file_column) = dump.columns.iter().position(|c| c == "file") {
+                for row in &dump.rows {
+                    let name = match row[file_column].as_str() {
+                        Some(name) if message_images::valid_name(name) => name,
+                        _ => continue,
+                    };
+                    if let Ok(data) = fs::read(message_images::upload_dir().join(name)) {
+                        uploads.insert(name.to_string(), data);
+                    }
+                }
+            }
+        }
+
+        let mut long_term_memory = BTreeMap::new();
+        let mut avatars = BTreeMap::new();
+        for (companion_id, avatar_path) in companions {
+            if LongTermMem::directory(companion_id).exists() {
+                let entries = LongTermMem::open(companion_id)?.all_entries()?;
+                long_term_memory.insert(companion_id, entries);
+            }
+            if let Some(name) = avatar_file_name(&avatar_path) {
+                if let Ok(avatar) = fs::read(settings::get().assets_dir.join(&name)) {
+                    avatars.insert(name, avatar);
+                }
+            }
+        }
+
+        Ok(Backup {
+            manifest: BackupManifest {
+                format_version: BACKUP_FORMAT_VERSION,
+                app_version: env!("CARGO_PKG_VERSION").to_string(),
+                created_at: get_current_date(),
+                tables: tables.iter().map(|(name, dump)| (name.clone(), dump.rows.len())).collect(),
+            },
+            tables,
+            long_term_memory,
+            avatars,
+            uploads,
+        })
+    }
+
+    pub fn to_zip(&self) -> Result<Vec<u8>, Box<dyn Error>> {
+        let mut writer = ZipWriter::new(Cursor::new(Vec::new()));
+        let options = FileOptions::default().compression_method(CompressionMethod::Deflated);
+
+        writer.start_file("manifest.json", options)?;
+        writer.write_all(&serde_json::to_vec_pretty(&self.manifest)?)?;
+        for (table, dump) in &self.tables {
+            writer.start_file(format!("tables/{}.json", table), options)?;
+            writer.write_all(&serde_json::to_vec(dump)?)?;
+        }
+        for (companion_id, entries) in &self.long_term_memory {
+            writer.start_file(format!("long_term_memory/{}.json", companion_id), options)?;
+            writer.write_all(&serde_json::to_vec(entries)?)?;
+        }
+        for (name, avatar) in &self.avatars {
+            // Images are already compressed
+            writer.start_file(
+                format!("avatars/{}", name),
+                options.compression_method(CompressionMethod::Stored),
+            )?;
+            writer.write_all(avatar)?;
+        }
+        for (name, upload) in &self.uploads {
+            writer.start_file(
+                format!("uploads/{}", name),
+                options.compression_method(CompressionMethod::Stored),
+            )?;
+            writer.write_all(upload)?;
+        }
+
+        Ok(writer.finish()?.into_inner())
+    }
+
+    /// Read and check a backup, bringing older formats up to date
+    pub fn from_zip(bytes: &[u8]) -> Result<Self, Box<dyn Error>> {
+        let mut archive = ZipArchive::new(Cursor::new(bytes))?;
+        let mut manifest = None;
+        let mut tables = BTreeMap::new();
+        let mut long_term_memory = BTreeMap::new();
+        let mut avatars = BTreeMap::new();
+        let mut uploads = BTreeMap::new();
+
+        for index in 0..archive.len() {
+            let entry = archive.by_index(index)?;
+            if entry.is_dir() {
+                continue;
+            }
+            let name = entry.name().to_string();
+            if entry.size() > MAX_ENTRY_SIZE {
+                return Err(format!("Backup entry '{}' is too large", name).into());
+            }
+            let mut data = Vec::new();
+            entry.take(MAX_ENTRY_SIZE).read_to_end(&mut data)?;
+
+            if name == "manifest.json" {
+                manifest = Some(serde_json::from_slice::<BackupManifest>(&data)?);
+            } else if let Some(table) = name.strip_prefix("tables/").and_then(|n| n.strip_suffix(".json")) {
+                let dump: TableDump = serde_json::from_slice(&data)
+                    .map_err(|e| format!("Backup table '{}' is malformed: {}", table, e))?;
+                tables.insert(table.to_string(), dump);
+            } else if let Some(id) = name
+                .strip_prefix("long_term_memory/")
+                .and_then(|n| n.strip_suffix(".json"))
+            {
+                let companion_id: i32 = id
+                    .parse()
+                    .map_err(|_| format!("Backup entry '{}' is not named after a companion id", name))?;
+                long_term_memory.insert(companion_id, serde_json::from_slice(&data)?);
+            } else if let Some(file_name) = name.strip_prefix("avatars/") {
+                avatars.insert(file_name.to_string(), data);
+            } else if let Some(file_name) = name.strip_prefix("uploads/") {
+                uploads.insert(file_name.to_string(), data);
+            }
+        }
+
+        let mut backup = Backup {
+            manifest: manifest.ok_or("Backup is missing manifest.json")?,
+            tables,
+            long_term_memory,
+            avatars,
+            uploads,
+        };
+        backup.validate()?;
+        backup.migrate();
+        Ok(backup)
+    }
+
+    fn validate(&self) -> Result<(), Box<dyn Error>> {
+        if self.manifest.format_version > BACKUP_FORMAT_VERSION {
+            return Err(format!(
+                "Backup format version {} is newer than supported version {}",
+                self.manifest.format_version, BACKUP_FORMAT_VERSION
+            )
+            .into());
+        }
+        for table in REQUIRED_TABLES {
+            if self.tables.get(table).map_or(true, |dump| dump.rows.is_empty()) {
+                return Err(format!("Backup has no rows in table '{}'", table).into());
+            }
+        }
+        for (table, dump) in &self.tables {
+            if !TABLES.contains(&table.as_str()) {
+                return Err(format!("Backup contains unknown table '{}'", table).into());
+            }
+            if let Some(column) = dump.columns.iter().find(|c| !is_identifier(c)) {
+                return Err(format!("Backup table '{}' has invalid column '{}'", table, column).into());
+            }
+            if dump.rows.iter().any(|row| row.len() != dump.columns.len()) {
+                return Err(format!("Backup table '{}' has rows of the wrong length", table).into());
+            }
+        }
+        if let Some(name) = self
+            .avatars
+            .keys()
+            .find(|name| name.is_empty() || name.contains(['/', '\\']) || name.starts_with('.'))
+        {
+            return Err(format!("Backup avatar '{}' has an invalid file name", name).into());
+        }
+        if let Some(name) = self.uploads.keys().find(|name| !message_images::valid_name(name)) {
+            return Err(format!("Backup upload '{}' has an invalid file name", name).into());
+        }
+        Ok(())
+    }
+
+    /// Bring a backup written by an older version to the current format
+    ///
+    /// Schema changes need no step here, columns are matched by name on restore. Columns the
+    /// database no longer has are dropped and ones the backup lacks get their defaults.
+    fn migrate(&mut self) {
+        self.manifest.format_version = BACKUP_FORMAT_VERSION;
+    }
+
+    fn companion_ids(&self) -> HashSet<i32> {
+        let dump = match self.tables.get("companion") {
+            Some(dump) => dump,
+            None => return HashSet::new(),
+        };
+        let id_column = dump.columns.iter().position(|c| c == "id");
+        dump.rows
+            .iter()
+            .filter_map(|row| id_column.and_then(|i| row[i].as_i64()))
+            .map(|id| id as i32)
+            .collect()
+    }
+
+    fn replace_tables(
+        con: &mut Connection,
+        tables: &BTreeMap<String, TableDump>,
+        local_config: Option<(Option<String>, Option<String>)>,
+    ) -> Result<BTreeMap<String, usize>, Box<dyn Error>> {
+        let tx = con.transaction()?;
+        for table in TABLES.iter().rev() {
+            if table_exists(&tx, table)? {
+                tx.execute(&format!("DELETE FROM {}", table), [])?;
+            }
+        }
+        let mut restored = BTreeMap::new();
+        for table in TABLES {
+            let dump = match tables.get(table) {
+                Some(dump) => dump,
+                None => continue,
+            };
+            if !table_exists(&tx, table)? {
+                continue;
+            }
+            let current = table_columns(&tx, table)?;
+            let kept: Vec<usize> = (0..dump.columns.len())
+                .filter(|i| current.contains(&dump.columns[*i]))
+                .filter(|i| table != "config" || !LOCAL_CONFIG_COLUMNS.contains(&dump.columns[*i].as_str()))
+                .collect();
+            let columns: Vec<&str> = kept.iter().map(|i| dump.columns[*i].as_str()).collect();
+            let placeholders = vec!["?"; columns.len()].join(", ");
+            let mut stmt = tx.prepare(&format!(
+                "INSERT INTO {} ({}) VALUES ({})",
+                table,
+                columns.join(", "),
+                placeholders
+            ))?;
+            for row in &dump.rows {
+                let values: Vec<Value> = kept.iter().map(|i| to_sql(&row[*i])).collect();
+                stmt.execute(rusqlite::params_from_iter(values))?;
+            }
+            restored.insert(table.to_string(), dump.rows.len());
+        }
+        if let Some((api_key_hash, password_hash)) = local_config {
+            tx.execute(
+                "UPDATE config SET api_key_hash = ?, password_hash = ?",
+                [api_key_hash.unwrap_or_default(), password_hash.unwrap_or_default()],
+            )?;
+        }
+        // Vectors of the old memories, the embedding backfill computes new ones
+        if table_exists(&tx, "memory_embeddings")? {
+            tx.execute("DELETE FROM memory_embeddings", [])?;
+        }
+        tx.commit()?;
+        Ok(restored)
+    }
+
+    /// Replace the installation's data with the backup, the database changes all at once or not at all
+    pub fn restore(self) -> Result<RestoreSummary, Box<dyn Error>> {
+        let mut con = db_pool::connection()?;
+        let previous_companions: Vec<i32> = {
+            let mut stmt = con.prepare("SELECT id FROM companion")?;
+            let rows = stmt.query_map([], |row| row.get(0))?;
+            rows.collect::<Result<_, _>>()?
+        };
+        let local_config: Option<(Option<String>, Option<String>)> = con
+            .query_row("SELECT api_key_hash, password_hash FROM config LIMIT 1", [], |row| {
+                Ok((row.get(0)?, row.get(1)?))
+            })
+            .ok();
+
+        // Rows go in table by table, and attitude_memories refers to a table that never existed
+        let foreign_keys = db_pool::disable_foreign_keys(&con)?;
+        let result = Backup::replace_tables(&mut con, &self.tables, local_config);
+        db_pool::restore_foreign_keys(&con, foreign_keys)?;
+        let restored = result?;
+        // Backups from before conversation threads keep their chat as one thread per companion
+        Conversations::adopt_orphans(&con)?;
+        drop(con);
+        Database::clear_caches();
+        // Backups from before prompt templates were kept have none, the presets come back
+        PromptTemplates::create()?;
+
+        // The files can't be part of the transaction, they follow once the database is restored
+        let companions = self.companion_ids();
+        let mut long_term_memory_entries = 0;
+        for companion_id in &companions {
+            let entries = self.long_term_memory.get(companion_id).cloned().unwrap_or_default();
+            if entries.is_empty() && !LongTermMem::directory(*companion_id).exists() {
+                continue;
+            }
+            LongTermMem::open(*companion_id)?.replace_entries(&entries)?;
+            long_term_memory_entries += entries.len();
+        }
+        for companion_id in previous_companions {
+            let directory = LongTermMem::directory(companion_id);
+            if !companions.contains(&companion_id) && directory.exists() {
+                fs::remove_dir_all(&directory)?;
+            }
+        }
+        if !self.avatars.is_empty() {
+            fs::create_dir_all(&settings::get().assets_dir)?;
+        }
+        for (name, avatar) in &self.avatars {
+            fs::write(settings::get().assets_dir.join(name), avatar)?;
+        }
+        if !self.uploads.is_empty() {
+            fs::create_dir_all(message_images::upload_dir())?;
+        }
+        for (name, upload) in &self.uploads {
+            fs::write(message_images::upload_dir().join(name), upload)?;
+        }
+
+        Ok(RestoreSummary {
+            format_version: self.manifest.format_version,
+            tables: restored,
+            long_term_memory_entries,
+            avatars: self.avatars.len(),
+            uploads: self.uploads.len(),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sample_backup() -> Backup {
+        let table = |columns: &[&str], row: Vec<serde_json::Value>| TableDump {
+            columns: columns.iter().map(|c| c.to_string()).collect(),
+            rows: vec![row],
+        };
+        Backup {
+            manifest: BackupManifest {
+                format_version: BACKUP_FORMAT_VERSION,
+                app_version: "1.0.0".to_string(),
+                created_at: "Monday 01.01.2024 12:00".to_string(),
+                tables: BTreeMap::new(),
+            },
+            tables: BTreeMap::from([
+                ("config".to_string(), table(&["id", "device"], vec![1.into(), "CPU".into()])),
+                ("user".to_string(), table(&["id", "name"], vec![1.into(), "Alex".into()])),
+                ("companion".to_string(), table(&["id", "name"], vec![1.into(), "Luna".into()])),
+            ]),
+            long_term_memory: BTreeMap::from([(1, vec!["Alex likes tea".to_string()])]),
+            avatars: BTreeMap::from([("avatar.png".to_string(), b"\x89PNG".to_vec())]),
+            uploads: BTreeMap::from([("0123abcd.jpg".to_string(), b"\xFF\xD8\xFF".to_vec())]),
+        }
+    }
+
+    #[test]
+    fn test_backup_round_trip() {
+        let bytes = sample_backup().to_zip().unwrap();
+        let backup = Backup::from_zip(&bytes).unwrap();
+
+        assert_eq!(backup.tables["companion"].rows[0][1], "Luna");
+        assert_eq!(backup.long_term_memory[&1], vec!["Alex likes tea"]);
+        assert_eq!(backup.avatars["avatar.png"], b"\x89PNG");
+        assert_eq!(backup.uploads["0123abcd.jpg"], b"\xFF\xD8\xFF");
+        assert_eq!(backup.companion_ids(), HashSet::from([1]));
+    }
+
+    #[test]
+    fn test_backup_validation() {
+        let mut backup = sample_backup();
+        backup.tables.remove("user");
+        assert!(Backup::from_zip(&backup.to_zip().unwrap()).is_err());
+
+        let mut backup = sample_backup();
+        backup.manifest.format_version = BACKUP_FORMAT_VERSION + 1;
+        assert!(Backup::from_zip(&backup.to_zip().unwrap()).is_err());
+
+        let mut backup = sample_backup();
+        backup.tables.get_mut("user").unwrap().columns[1] = "name; DROP TABLE user".to_string();
+        assert!(Backup::from_zip(&backup.to_zip().unwrap()).is_err());
+
+        let mut backup = sample_backup();
+        backup.tables.insert("sqlite_master".to_string(), TableDump::default());
+        assert!(Backup::from_zip(&backup.to_zip().unwrap()).is_err());
+
+        let mut backup = sample_backup();
+        backup.uploads.insert("../config.json".to_string(), Vec::new());
+        assert!(Backup::from_zip(&backup.to_zip().unwrap()).is_err());
+    }
+
+    #[test]
+    fn test_value_conversion() {
+        assert_eq!(to_json(ValueRef::Integer(3)).unwrap(), 3);
+        assert_eq!(to_json(ValueRef::Text(b"hi")).unwrap(), "hi");
+        assert!(to_json(ValueRef::Blob(b"\x00")).is_err());
+        assert_eq!(to_sql(&serde_json::json!(3)), Value::Integer(3));
+        assert_eq!(to_sql(&serde_json::json!(0.5)), Value::Real(0.5));
+        assert_eq!(to_sql(&serde_json::json!(true)), Value::Integer(1));
+    }
+}
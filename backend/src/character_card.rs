@@ -2,12 +2,47 @@ use base64::{alphabet::STANDARD, engine::GeneralPurpose, engine::GeneralPurposeC
 use serde::{Deserialize, Serialize};
 use std::io::Cursor;
 
-#[derive(Serialize, Deserialize)]
+pub const CARD_SPEC: &str = "chara_card_v2";
+pub const CARD_SPEC_VERSION: &str = "2.0";
+
+const PNG_SIGNATURE: &[u8] = b"\x89PNG\r\n\x1a\n";
+
+/// Character card, the `data` of a chara_card_v2 card or a whole V1 card
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
 pub struct CharacterCard {
     pub name: String,
     pub description: String,
     pub first_mes: String,
     pub mes_example: String,
+    #[serde(default)]
+    pub system_prompt: String,
+    #[serde(default)]
+    pub post_history_instructions: String,
+    #[serde(default)]
+    pub alternate_greetings: Vec<String>,
+    #[serde(default)]
+    pub tags: Vec<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub character_book: Option<serde_json::Value>,
+    #[serde(flatten)]
+    pub extras: CardExtras,
+}
+
+/// Card fields the companion does not use, kept so an exported card matches the imported one
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+pub struct CardExtras {
+    #[serde(default)]
+    pub personality: String,
+    #[serde(default)]
+    pub scenario: String,
+    #[serde(default)]
+    pub creator_notes: String,
+    #[serde(default)]
+    pub creator: String,
+    #[serde(default)]
+    pub character_version: String,
+    #[serde(default)]
+    pub extensions: serde_json::Map<String, serde_json::Value>,
 }
 
 impl CharacterCard {
@@ -59,8 +94,149 @@ impl CharacterCard {
                 )));
             }
         };
-        let char_data: CharacterCard = serde_json::from_str(character_text)
-            .expect("Your image file does not contain correct json data");
-        Ok(char_data)
+        let card_json: serde_json::Value = serde_json::from_str(character_text).map_err(|e| {
+            format!("Your image file does not contain correct json data: {}", e)
+        })?;
+        CharacterCard::from_json(card_json)
+    }
+
+    /// Read a card in either format, V2 cards wrap the character in `data`
+    pub fn from_json(card: serde_json::Value) -> Result<Self, Box<dyn std::error::Error>> {
+        let character = match card.get("spec").and_then(|spec| spec.as_str()) {
+            Some(CARD_SPEC) => card
+                .get("data")
+                .cloned()
+                .ok_or("Character card V2 has no data")?,
+            Some(spec) => return Err(format!("Unsupported character card spec '{}'", spec).into()),
+            None => card,
+        };
+        Ok(serde_json::from_value(character)?)
+    }
+
+    pub fn to_v2_json(&self) -> serde_json::Value {
+        serde_json::json!({
+            "spec": CARD_SPEC,
+            "spec_version": CARD_SPEC_VERSION,
+            "data": self,
+        })
+    }
+
+    /// Copy of the PNG image with this card in its 'chara' tEXt chunk, replacing any card it had
+    pub fn embed_in_png(&self, image: &[u8]) -> Result<Vec<u8>, Box<dyn std::error::Error>> {
+        if !image.starts_with(PNG_SIGNATURE) {
+            return Err("Character cards can only be embedded into PNG images".into());
+        }
+        let engine = GeneralPurpose::new(&STANDARD, GeneralPurposeConfig::new());
+        let mut text = b"chara\0".to_vec();
+        text.extend_from_slice(engine.encode(self.to_v2_json().to_string()).as_bytes());
+
+        let mut output = PNG_SIGNATURE.to_vec();
+        let mut position = PNG_SIGNATURE.len();
+        while position + 12 <= image.len() {
+            let length = u32::from_be_bytes(image[position..position + 4].try_into()?) as usize;
+            let end = position + 12 + length;
+            if end > image.len() {
+                return Err("PNG image is truncated".into());
+            }
+            let kind = &image[position + 4..position + 8];
+            let data = &image[position + 8..position + 8 + length];
+            if kind == b"IEND" {
+                write_chunk(&mut output, b"tEXt", &text);
+            }
+            if !(kind == b"tEXt" && data.starts_with(b"chara\0")) {
+                output.extend_from_slice(&image[position..end]);
+            }
+            if kind == b"IEND" {
+                return Ok(output);
+            }
+            position = end;
+        }
+        Err("PNG image has no IEND chunk".into())
+    }
+}
+
+fn write_chunk(output: &mut Vec<u8>, kind: &[u8; 4], data: &[u8]) {
+    output.extend_from_slice(&(data.len() as u32).to_be_bytes());
+    output.extend_from_slice(kind);
+    output.extend_from_slice(data);
+    let crc = crc32(&[&kind[..], data].concat());
+    output.extend_from_slice(&crc.to_be_bytes());
+}
+
+/// CRC-32 as used by PNG chunks
+fn crc32(bytes: &[u8]) -> u32 {
+    let mut crc = 0xffff_ffffu32;
+    for byte in bytes {
+        crc ^= *byte as u32;
+        for _ in 0..8 {
+            crc = if crc & 1 == 1 { (crc >> 1) ^ 0xedb8_8320 } else { crc >> 1 };
+        }
+    }
+    !crc
+}
+
+/// Plain PNG to carry a card when the companion has no PNG avatar
+pub fn placeholder_png() -> Result<Vec<u8>, Box<dyn std::error::Error>> {
+    let (width, height) = (400, 600);
+    let mut image = Vec::new();
+    {
+        let mut encoder = png::Encoder::new(&mut image, width, height);
+        encoder.set_color(png::ColorType::Rgb);
+        encoder.set_depth(png::BitDepth::Eight);
+        let mut writer = encoder.write_header()?;
+        writer.write_image_data(&[0x2a; 400 * 600 * 3])?;
+    }
+    Ok(image)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_v2_card_round_trip() {
+        let card = CharacterCard::from_json(serde_json::json!({
+            "spec": "chara_card_v2",
+            "spec_version": "2.0",
+            "data": {
+                "name": "Luna",
+                "description": "{{char}} is a curious astronomer",
+                "first_mes": "Hi!",
+                "mes_example": "",
+                "system_prompt": "Stay in character.",
+                "alternate_greetings": ["Hello there"],
+                "tags": ["space"],
+                "character_book": { "entries": [] },
+                "personality": "curious",
+                "extensions": { "depth_prompt": { "depth": 4 } }
+            }
+        }))
+        .unwrap();
+        assert_eq!(card.system_prompt, "Stay in character.");
+        assert_eq!(card.alternate_greetings, vec!["Hello there"]);
+        assert_eq!(card.extras.personality, "curious");
+
+        let png = card.embed_in_png(&placeholder_png().unwrap()).unwrap();
+        // Embedding again replaces the card instead of adding a second one
+        let png = card.embed_in_png(&png).unwrap();
+        assert_eq!(png.windows(9).filter(|w| *w == b"tEXtchara").count(), 1);
+        let loaded = CharacterCard::load_character_card(&png).unwrap();
+        assert_eq!(loaded.name, "Luna");
+        assert_eq!(loaded.tags, vec!["space"]);
+        assert!(loaded.character_book.is_some());
+        assert_eq!(loaded.extras.extensions["depth_prompt"]["depth"], 4);
+    }
+
+    #[test]
+    fn test_v1_card() {
+        let card = CharacterCard::from_json(serde_json::json!({
+            "name": "Luna",
+            "description": "",
+            "first_mes": "",
+            "mes_example": ""
+        }))
+        .unwrap();
+        assert!(card.alternate_greetings.is_empty());
+        assert!(CharacterCard::from_json(serde_json::json!({ "spec": "chara_card_v9" })).is_err());
     }
 }
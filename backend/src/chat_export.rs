@@ -0,0 +1,390 @@
+use crate::attitude_history::{self, AttitudeHistory, AttitudeHistoryView};
+use crate::database::{get_current_date, parse_stored_date, Database, MESSAGE_COLUMNS};
+use crate::db_pool;
+use chrono::{Duration, Local, NaiveDateTime};
+use rusqlite::{OptionalExtension, Result};
+use serde::Serialize;
+use std::collections::HashMap;
+
+// Snapshots this long after the last message still belong to the conversation
+const TRAILING_MINUTES: i64 = 10;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ExportFormat {
+    Markdown,
+    Json,
+    Text,
+}
+
+impl ExportFormat {
+    pub fn from_name(name: &str) -> Option<ExportFormat> {
+        match name.trim().to_ascii_lowercase().as_str() {
+            "markdown" | "md" => Some(ExportFormat::Markdown),
+            "json" => Some(ExportFormat::Json),
+            "txt" | "text" => Some(ExportFormat::Text),
+            _ => None,
+        }
+    }
+
+    pub fn extension(self) -> &'static str {
+        match self {
+            ExportFormat::Markdown => "md",
+            ExportFormat::Json => "json",
+            ExportFormat::Text => "txt",
+        }
+    }
+
+    pub fn mime(self) -> &'static str {
+        match self {
+            ExportFormat::Markdown => "text/markdown; charset=utf-8",
+            ExportFormat::Json => "application/json",
+            ExportFormat::Text => "text/plain; charset=utf-8",
+        }
+    }
+}
+
+/// How a dimension of the companion's attitude toward a user moved
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct AttitudeChange {
+    /// Name of the user
+    pub target: String,
+    pub dimension: String,
+    pub from: f32,
+    pub to: f32,
+}
+
+#[derive(Serialize, Debug, Clone)]
+pub struct ExportedMessage {
+    pub id: i32,
+    pub ai: bool,
+    pub speaker: String,
+    pub content: String,
+    pub created_at: String,
+    /// Changes recorded after this message and before the next one
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub attitude_changes: Vec<AttitudeChange>,
+}
+
+/// A conversation as it is written to a file
+#[derive(Serialize, Debug, Clone)]
+pub struct ChatExport {
+    pub conversation_id: i32,
+    pub title: String,
+    pub companion: String,
+    pub created_at: String,
+    pub exported_at: String,
+    pub messages: Vec<ExportedMessage>,
+}
+
+/// Attitude changes between the snapshots of a history, with the local time they were recorded at
+fn changes(
+    history: &AttitudeHistoryView,
+    target: &str,
+) -> Vec<(NaiveDateTime, Vec<AttitudeChange>)> {
+    (1..history.timestamps.len())
+        .map(|i| {
+            let moved = history
+                .series
+                .iter()
+                .filter(|series| series.values[i - 1].round() != series.values[i].round())
+                .map(|series| AttitudeChange {
+                    target: target.to_string(),
+                    dimension: series.dimension.clone(),
+                    from: series.values[i - 1],
+                    to: series.values[i],
+                })
+                .collect();
+            (
+                history.timestamps[i].with_timezone(&Local).naive_local(),
+                moved,
+            )
+        })
+        .collect()
+}
+
+/// Put every change after the last message sent up to its time, changes from before the
+/// conversation or long after it are left out
+fn attach(messages: &mut [ExportedMessage], changes: Vec<(NaiveDateTime, Vec<AttitudeChange>)>) {
+    let times: Vec<Option<NaiveDateTime>> = messages
+        .iter()
+        .map(|message| parse_stored_date(&message.created_at))
+        .collect();
+    let last = match times.iter().flatten().max() {
+        Some(last) => *last + Duration::minutes(TRAILING_MINUTES),
+        None => return,
+    };
+    for (recorded_at, moved) in changes {
+        if moved.is_empty() || recorded_at > last {
+            continue;
+        }
+        // Messages only keep the minute they were sent in
+        let position = times
+            .iter()
+            .rposition(|time| time.is_some_and(|time| time <= recorded_at));
+        if let Some(position) = position {
+            messages[position].attitude_changes.extend(moved);
+        }
+    }
+}
+
+fn describe_changes(changes: &[AttitudeChange]) -> Vec<String> {
+    let mut targets: Vec<&str> = Vec::new();
+    for change in changes {
+        if !targets.contains(&change.target.as_str()) {
+            targets.push(&change.target);
+        }
+    }
+    targets
+        .into_iter()
+        .map(|target| {
+            let moved: Vec<String> = changes
+                .iter()
+                .filter(|change| change.target == target)
+                .map(|change| format!("{} {:.0} → {:.0}", change.dimension, change.from, change.to))
+                .collect();
+            format!("Attitude toward {}: {}", target, moved.join(", "))
+        })
+        .collect()
+}
+
+impl ChatExport {
+    /// The conversation with the names of who wrote what, None if there is no such conversation
+    ///
+    /// With `attitudes` the changes of the companion's attitude toward the users taking part are
+    /// added to the messages they followed.
+    pub fn load(conversation_id: i32, attitudes: bool) -> Result<Option<ChatExport>> {
+        let con = db_pool::connection()?;
+        let conversation: Option<(i32, String, String)> = con
+            .query_row(
+                "SELECT companion_id, title, created_at FROM conversations WHERE id = ?",
+                [conversation_id],
+                |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?)),
+            )
+            .optional()?;
+        let (companion_id, title, created_at) = match conversation {
+            Some(conversation) => conversation,
+            None => return Ok(None),
+        };
+        let mut stmt = con.prepare(&format!(
+            "SELECT {} FROM messages WHERE conversation_id = ? ORDER BY id",
+            MESSAGE_COLUMNS
+        ))?;
+        let rows = stmt.query_map([conversation_id], Database::message_from_row)?;
+        let stored = rows.collect::<Result<Vec<_>>>()?;
+
+        let companion = Database::get_companion_data_by_id(companion_id)?.name;
+        let users: HashMap<i32, String> = Database::list_users()?
+            .into_iter()
+            .map(|user| (user.id, user.name))
+            .collect();
+        // Messages from before there were several users are the active user's
+        let active_user_id = Database::active_user_id();
+        let mut user_ids: Vec<i32> = Vec::new();
+        let mut messages: Vec<ExportedMessage> = Vec::with_capacity(stored.len());
+        for message in stored {
+            let speaker = if message.ai {
+                companion.clone()
+            } else {
+                let user_id = message.author_id.unwrap_or(active_user_id);
+                if !user_ids.contains(&user_id) {
+                    user_ids.push(user_id);
+                }
+                users
+                    .get(&user_id)
+                    .cloned()
+                    .unwrap_or_else(|| "User".to_string())
+            };
+            messages.push(ExportedMessage {
+                id: message.id,
+                ai: message.ai,
+                speaker,
+                content: message.content,
+                created_at: message.created_at,
+                attitude_changes: Vec::new(),
+            });
+        }
+
+        if attitudes {
+            let dimensions = attitude_history::parse_dimensions(None).unwrap_or_default();
+            for user_id in user_ids {
+                let history =
+                    AttitudeHistory::get(companion_id, user_id, "user", &dimensions, None, None)?;
+                let target = users.get(&user_id).map(String::as_str).unwrap_or("User");
+                attach(&mut messages, changes(&history, target));
+            }
+        }
+
+        Ok(Some(ChatExport {
+            conversation_id,
+            title,
+            companion,
+            created_at,
+            exported_at: get_current_date(),
+            messages,
+        }))
+    }
+
+    /// Name to save the file under, from the title
+    pub fn file_name(&self, format: ExportFormat) -> String {
+        let words: Vec<&str> = self
+            .title
+            .split(|c: char| !(c.is_alphanumeric() || c == '-'))
+            .filter(|word| !word.is_empty())
+            .collect();
+        let stem = if words.is_empty() {
+            "conversation".to_string()
+        } else {
+            words.join("_")
+        };
+        format!("{}.{}", stem, format.extension())
+    }
+
+    pub fn render(&self, format: ExportFormat) -> String {
+        match format {
+            ExportFormat::Markdown => self.to_markdown(),
+            ExportFormat::Json => serde_json::to_string_pretty(self).unwrap_or_default(),
+            ExportFormat::Text => self.to_text(),
+        }
+    }
+
+    fn to_markdown(&self) -> String {
+        let mut out = format!(
+            "# {}\n\nConversation with {}, started {}. Exported {}.\n",
+            self.title, self.companion, self.created_at, self.exported_at
+        );
+        for message in &self.messages {
+            out.push_str(&format!(
+                "\n---\n\n**{}** · {}\n\n{}\n",
+                message.speaker,
+                message.created_at,
+                message.content.trim()
+            ));
+            for line in describe_changes(&message.attitude_changes) {
+                out.push_str(&format!("\n> *{}*\n", line));
+            }
+        }
+        out
+    }
+
+    fn to_text(&self) -> String {
+        let mut out = format!(
+            "{}\nConversation with {}, started {}. Exported {}.\n",
+            self.title, self.companion, self.created_at, self.exported_at
+        );
+        for message in &self.messages {
+            out.push_str(&format!(
+                "\n[{}] {}: {}\n",
+                message.created_at,
+                message.speaker,
+                message.content.trim()
+            ));
+            for line in describe_changes(&message.attitude_changes) {
+                out.push_str(&format!("  ({})\n", line));
+            }
+        }
+        out
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::attitude_history::AttitudeSeries;
+    use chrono::{TimeZone, Utc};
+
+    fn exported(
+        id: i32,
+        ai: bool,
+        speaker: &str,
+        content: &str,
+        created_at: &str,
+    ) -> ExportedMessage {
+        ExportedMessage {
+            id,
+            ai,
+            speaker: speaker.to_string(),
+            content: content.to_string(),
+            created_at: created_at.to_string(),
+            attitude_changes: Vec::new(),
+        }
+    }
+
+    #[test]
+    fn test_attach_attitude_changes() {
+        let local = |minute: u32| {
+            Local
+                .with_ymd_and_hms(2024, 4, 1, 10, minute, 30)
+                .unwrap()
+                .with_timezone(&Utc)
+        };
+        let history = AttitudeHistoryView {
+            companion_id: 1,
+            target_id: 1,
+            target_type: "user".to_string(),
+            since: None,
+            until: None,
+            timestamps: vec![local(0), local(1), local(3), local(59)],
+            series: vec![
+                AttitudeSeries {
+                    dimension: "trust".to_string(),
+                    values: vec![10.0, 16.0, 16.2, 30.0],
+                },
+                AttitudeSeries {
+                    dimension: "joy".to_string(),
+                    values: vec![0.0, 0.0, 8.0, 8.0],
+                },
+            ],
+        };
+        let mut messages = vec![
+            exported(1, true, "Luna", "Hello there", "Monday 01.04.2024 10:00"),
+            exported(2, false, "Alex", "Hi Luna", "Monday 01.04.2024 10:01"),
+            exported(3, true, "Luna", "How was work?", "Monday 01.04.2024 10:02"),
+        ];
+        attach(&mut messages, changes(&history, "Alex"));
+        assert_eq!(
+            messages[1].attitude_changes,
+            vec![AttitudeChange {
+                target: "Alex".to_string(),
+                dimension: "trust".to_string(),
+                from: 10.0,
+                to: 16.0
+            }]
+        );
+        assert_eq!(messages[2].attitude_changes.len(), 1);
+        assert_eq!(messages[2].attitude_changes[0].dimension, "joy");
+        // The snapshot an hour later belongs to another conversation
+        assert!(messages[0].attitude_changes.is_empty());
+
+        let export = ChatExport {
+            conversation_id: 1,
+            title: "Rainy day?".to_string(),
+            companion: "Luna".to_string(),
+            created_at: "Monday 01.04.2024 10:00".to_string(),
+            exported_at: "Tuesday 02.04.2024 08:00".to_string(),
+            messages,
+        };
+        let markdown = export.render(ExportFormat::Markdown);
+        assert!(markdown.starts_with("# Rainy day?\n"));
+        assert!(markdown.contains("**Alex** · Monday 01.04.2024 10:01\n\nHi Luna\n"));
+        assert!(markdown.contains("> *Attitude toward Alex: trust 10 → 16*"));
+        let text = export.render(ExportFormat::Text);
+        assert!(text.contains(
+            "[Monday 01.04.2024 10:02] Luna: How was work?\n  (Attitude toward Alex: joy 0 → 8)"
+        ));
+        let json: serde_json::Value =
+            serde_json::from_str(&export.render(ExportFormat::Json)).unwrap();
+        assert_eq!(json["messages"][0]["speaker"], "Luna");
+        assert!(json["messages"][0].get("attitude_changes").is_none());
+        assert_eq!(export.file_name(ExportFormat::Text), "Rainy_day.txt");
+        let discord = ChatExport {
+            title: "Discord: #general".to_string(),
+            ..export
+        };
+        assert_eq!(
+            discord.file_name(ExportFormat::Markdown),
+            "Discord_general.md"
+        );
+        assert_eq!(ExportFormat::from_name("MD"), Some(ExportFormat::Markdown));
+        assert_eq!(ExportFormat::from_name("pdf"), None);
+    }
+}
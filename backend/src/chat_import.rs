@@ -0,0 +1,484 @@
+use crate::database::{get_current_date, ConfigView, Database};
+use crate::db_pool;
+use crate::event_bus;
+use crate::memory_proposals::MemoryProposals;
+use crate::personality_traits::PersonalityTraits;
+use chrono::{DateTime, Local, NaiveDateTime, TimeZone};
+use rusqlite::{params, Result};
+use serde::Serialize;
+use serde_json::Value;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+pub const MAX_IMPORT_BYTES: usize = 32 * 1024 * 1024;
+pub const FORMATS: [&str; 2] = ["sillytavern", "cai"];
+// Finished jobs kept around for GET /api/message/import/{id}
+const KEPT_JOBS: usize = 20;
+// How often a running job publishes its progress
+const PROGRESS_INTERVAL_SECONDS: u64 = 1;
+
+/// One message of an exported chat
+#[derive(Debug, Clone, PartialEq)]
+pub struct ImportedMessage {
+    pub ai: bool,
+    pub content: String,
+    /// None when the export has no usable time for it, the message is dated to the import
+    pub sent_at: Option<DateTime<Local>>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct ParsedChat {
+    pub format: &'static str,
+    /// Name of the character the chat was with, if the export tells
+    pub character: Option<String>,
+    /// Character.AI exports can hold several chats, every one becomes a conversation
+    pub threads: Vec<Vec<ImportedMessage>>,
+    /// System notes and empty messages left out
+    pub skipped: usize,
+}
+
+/// Read a SillyTavern JSONL or Character.AI export, `format` None tells them apart by their shape
+pub fn parse(data: &str, format: Option<&str>) -> Result<ParsedChat, String> {
+    let data = data.trim_start_matches('\u{feff}').trim();
+    if data.is_empty() {
+        return Err("The export is empty".to_string());
+    }
+    // A Character.AI export is one JSON document, a SillyTavern chat one object per line
+    let document = serde_json::from_str::<Value>(data).ok();
+    let format = match format {
+        Some(format) => format,
+        None => match &document {
+            Some(document)
+                if document.get("histories").is_some() || document.get("turns").is_some() =>
+            {
+                "cai"
+            }
+            _ => "sillytavern",
+        },
+    };
+    let mut chat = match format {
+        "sillytavern" => parse_sillytavern(data)?,
+        "cai" => match document {
+            Some(document) => parse_cai(&document)?,
+            None => return Err("Character.AI exports must be a JSON document".to_string()),
+        },
+        _ => {
+            return Err(format!(
+                "Unknown export format, expected one of: {}",
+                FORMATS.join(", ")
+            ))
+        }
+    };
+    chat.threads.retain(|thread| !thread.is_empty());
+    if chat.threads.is_empty() {
+        return Err("The export holds no messages".to_string());
+    }
+    for thread in &mut chat.threads {
+        // Some exports list the newest message first, a stable sort keeps the rest as it is
+        if thread.iter().all(|message| message.sent_at.is_some()) {
+            thread.sort_by_key(|message| message.sent_at);
+        }
+    }
+    Ok(chat)
+}
+
+fn parse_sillytavern(data: &str) -> Result<ParsedChat, String> {
+    let mut chat = ParsedChat {
+        format: "sillytavern",
+        character: None,
+        threads: vec![Vec::new()],
+        skipped: 0,
+    };
+    let mut user_name = None;
+    for (number, line) in data.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() {
+            continue;
+        }
+        let entry: Value = serde_json::from_str(line)
+            .map_err(|e| format!("Line {} is not JSON: {}", number + 1, e))?;
+        // The first line describes the chat instead of being a message
+        if entry.get("mes").is_none() {
+            if number == 0 || entry.get("chat_metadata").is_some() {
+                chat.character = text(&entry, "character_name");
+                user_name = text(&entry, "user_name");
+                continue;
+            }
+            chat.skipped += 1;
+            continue;
+        }
+        let content = text(&entry, "mes").unwrap_or_default();
+        if content.trim().is_empty()
+            || entry.get("is_system").and_then(Value::as_bool) == Some(true)
+        {
+            chat.skipped += 1;
+            continue;
+        }
+        let is_user = match entry.get("is_user").and_then(Value::as_bool) {
+            Some(is_user) => is_user,
+            None => user_name.is_some() && text(&entry, "name") == user_name,
+        };
+        chat.threads[0].push(ImportedMessage {
+            ai: !is_user,
+            content,
+            sent_at: entry.get("send_date").and_then(parse_time),
+        });
+    }
+    Ok(chat)
+}
+
+fn parse_cai(document: &Value) -> Result<ParsedChat, String> {
+    let mut chat = ParsedChat {
+        format: "cai",
+        character: document
+            .get("info")
+            .and_then(|info| info.get("character"))
+            .or_else(|| document.get("character"))
+            .and_then(|character| text(character, "name")),
+        threads: Vec::new(),
+        skipped: 0,
+    };
+    if let Some(turns) = document.get("turns").and_then(Value::as_array) {
+        let thread = cai_turns(turns, &mut chat.skipped);
+        chat.threads.push(thread);
+        return Ok(chat);
+    }
+    // Older exports nest the list once more, {"histories": {"histories": [...]}}
+    let histories = document.get("histories");
+    let histories = histories
+        .and_then(|histories| histories.get("histories"))
+        .or(histories)
+        .and_then(Value::as_array)
+        .ok_or_else(|| "Expected a list of histories in the Character.AI export".to_string())?;
+    for history in histories {
+        let messages = history.get("msgs").and_then(Value::as_array);
+        let mut thread = Vec::new();
+        for message in messages.into_iter().flatten() {
+            let content = text(message, "text").unwrap_or_default();
+            let is_human = message
+                .get("src")
+                .and_then(|src| src.get("is_human"))
+                .and_then(Value::as_bool);
+            match is_human {
+                Some(is_human) if !content.trim().is_empty() => thread.push(ImportedMessage {
+                    ai: !is_human,
+                    content,
+                    sent_at: message.get("created").and_then(parse_time),
+                }),
+                _ => chat.skipped += 1,
+            }
+        }
+        chat.threads.push(thread);
+    }
+    Ok(chat)
+}
+
+/// Turns of the newer exports, the reply shown is the primary candidate
+fn cai_turns(turns: &[Value], skipped: &mut usize) -> Vec<ImportedMessage> {
+    let mut thread = Vec::new();
+    for turn in turns {
+        let is_human = turn
+            .get("author")
+            .and_then(|author| author.get("is_human"))
+            .and_then(Value::as_bool)
+            .unwrap_or(false);
+        let candidates = turn.get("candidates").and_then(Value::as_array);
+        let primary = turn.get("primary_candidate_id");
+        let candidate = candidates.and_then(|candidates| {
+            candidates
+                .iter()
+                .find(|candidate| primary.is_some() && candidate.get("candidate_id") == primary)
+                .or_else(|| candidates.first())
+        });
+        let content = candidate
+            .and_then(|candidate| text(candidate, "raw_content"))
+            .unwrap_or_default();
+        if content.trim().is_empty() {
+            *skipped += 1;
+            continue;
+        }
+        thread.push(ImportedMessage {
+            ai: !is_human,
+            content,
+            sent_at: turn.get("create_time").and_then(parse_time),
+        });
+    }
+    thread
+}
+
+fn text(value: &Value, key: &str) -> Option<String> {
+    value.get(key).and_then(Value::as_str).map(str::to_string)
+}
+
+/// Times as the exports write them: RFC 3339, milliseconds since 1970 or "July 6, 2023 10:25am"
+fn parse_time(value: &Value) -> Option<DateTime<Local>> {
+    if let Some(millis) = value.as_i64() {
+        return Local.timestamp_millis_opt(millis).single();
+    }
+    let value = value.as_str()?.trim();
+    if let Ok(time) = DateTime::parse_from_rfc3339(value) {
+        return Some(time.with_timezone(&Local));
+    }
+    if let Ok(millis) = value.parse::<i64>() {
+        return Local.timestamp_millis_opt(millis).single();
+    }
+    [
+        "%B %d, %Y %I:%M%p",
+        "%B %d, %Y %I:%M %p",
+        "%Y-%m-%d %H:%M:%S",
+        "%Y-%m-%dT%H:%M:%S%.f",
+    ]
+    .iter()
+    .find_map(|format| NaiveDateTime::parse_from_str(value, format).ok())
+    .and_then(|time| Local.from_local_datetime(&time).earliest())
+}
+
+/// Store every thread as a conversation of the companion, returns their ids
+///
+/// The user's messages are written by `user_id`. The conversations are not made the active one.
+pub fn store(chat: &ParsedChat, companion_id: i32, user_id: i32, title: &str) -> Result<Vec<i32>> {
+    let mut con = db_pool::connection()?;
+    let tx = con.transaction()?;
+    let mut conversation_ids = Vec::new();
+    for thread in &chat.threads {
+        tx.execute(
+            "INSERT INTO conversations (companion_id, title, created_at) VALUES (?, ?, ?)",
+            params![companion_id, title, get_current_date()],
+        )?;
+        let conversation_id = tx.last_insert_rowid() as i32;
+        for message in thread {
+            let created_at = match message.sent_at {
+                Some(sent_at) => sent_at.format("%A %d.%m.%Y %H:%M").to_string(),
+                None => get_current_date(),
+            };
+            tx.execute(
+                "INSERT INTO messages (ai, content, created_at, companion_id, conversation_id, author_id)
+                 VALUES (?, ?, ?, ?, ?, ?)",
+                params![
+                    message.ai,
+                    message.content,
+                    created_at,
+                    companion_id,
+                    conversation_id,
+                    (!message.ai).then_some(user_id)
+                ],
+            )?;
+        }
+        conversation_ids.push(conversation_id);
+    }
+    tx.commit()?;
+    Database::clear_message_cache();
+    Ok(conversation_ids)
+}
+
+/// Person detection and attitude inference run over an imported chat
+#[derive(Serialize, Debug, Clone)]
+pub struct ImportJob {
+    pub id: u64,
+    pub conversation_ids: Vec<i32>,
+    /// "running", "completed" or "failed"
+    pub status: String,
+    pub processed: usize,
+    pub total: usize,
+    /// Persons created right away, with memory_auto_approve off they wait for review instead
+    pub persons_detected: usize,
+    pub error: Option<String>,
+    pub started_at: String,
+    pub finished_at: Option<String>,
+}
+
+lazy_static::lazy_static! {
+    static ref JOBS: Mutex<Vec<ImportJob>> = Mutex::new(Vec::new());
+}
+
+fn update_job(id: u64, update: impl FnOnce(&mut ImportJob)) -> Option<ImportJob> {
+    let mut jobs = JOBS.lock().unwrap_or_else(|e| e.into_inner());
+    let job = jobs.iter_mut().find(|job| job.id == id)?;
+    update(job);
+    Some(job.clone())
+}
+
+fn publish(job: &ImportJob) {
+    event_bus::publish(
+        "import_progress",
+        serde_json::to_value(job).unwrap_or_default(),
+    );
+}
+
+pub struct ImportJobs {}
+
+impl ImportJobs {
+    /// Register a job for the chat, run it with `ImportJobs::run`
+    pub fn start(chat: &ParsedChat, conversation_ids: Vec<i32>) -> ImportJob {
+        let mut jobs = JOBS.lock().unwrap_or_else(|e| e.into_inner());
+        let job = ImportJob {
+            id: jobs.last().map_or(1, |job| job.id + 1),
+            conversation_ids,
+            status: "running".to_string(),
+            processed: 0,
+            total: chat.threads.iter().map(Vec::len).sum(),
+            persons_detected: 0,
+            error: None,
+            started_at: get_current_date(),
+            finished_at: None,
+        };
+        if jobs.len() >= KEPT_JOBS {
+            if let Some(done) = jobs.iter().position(|job| job.status != "running") {
+                jobs.remove(done);
+            }
+        }
+        jobs.push(job.clone());
+        job
+    }
+
+    pub fn get(id: u64) -> Option<ImportJob> {
+        let jobs = JOBS.lock().unwrap_or_else(|e| e.into_inner());
+        jobs.iter().find(|job| job.id == id).cloned()
+    }
+
+    /// Look for persons in the user's messages and let every exchange move the companion's attitude
+    ///
+    /// Attitudes are inferred with the lexicon whatever attitude_inference says, asking the model
+    /// about every exchange of a long history would hold up the chat for a long time. Blocks until
+    /// the chat is done.
+    pub fn run(id: u64, chat: ParsedChat, config: ConfigView, companion_id: i32, user_id: i32) {
+        let outcome = ImportJobs::analyze(id, &chat, config, companion_id, user_id);
+        let job = update_job(id, |job| {
+            job.finished_at = Some(get_current_date());
+            match outcome {
+                Ok(()) => job.status = "completed".to_string(),
+                Err(e) => {
+                    job.status = "failed".to_string();
+                    job.error = Some(e);
+                }
+            }
+        });
+        if let Some(job) = job {
+            publish(&job);
+        }
+    }
+
+    fn analyze(
+        id: u64,
+        chat: &ParsedChat,
+        config: ConfigView,
+        companion_id: i32,
+        user_id: i32,
+    ) -> Result<(), String> {
+        if Database::get_attitude(companion_id, user_id, "user")
+            .map_err(|e| e.to_string())?
+            .is_none()
+        {
+            let traits = PersonalityTraits::get(companion_id).map_err(|e| e.to_string())?;
+            Database::create_initial_user_attitude(companion_id, user_id, &traits)
+                .map_err(|e| e.to_string())?;
+        }
+        let config = ConfigView {
+            attitude_inference: "lexicon".to_string(),
+            ..config
+        };
+        let mut last_published = Instant::now();
+        for thread in &chat.threads {
+            for (i, message) in thread.iter().enumerate() {
+                let mut detected = 0;
+                if !message.ai {
+                    detected = MemoryProposals::detect_persons(&message.content, companion_id)
+                        .map_err(|e| e.to_string())?
+                        .len();
+                    if let Some(reply) = thread.get(i + 1).filter(|reply| reply.ai) {
+                        crate::attitude_inference::infer(
+                            &config,
+                            companion_id,
+                            user_id,
+                            &message.content,
+                            &reply.content,
+                        )?;
+                    }
+                }
+                let job = update_job(id, |job| {
+                    job.processed += 1;
+                    job.persons_detected += detected;
+                });
+                if let Some(job) = job {
+                    if last_published.elapsed() >= Duration::from_secs(PROGRESS_INTERVAL_SECONDS) {
+                        publish(&job);
+                        last_published = Instant::now();
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_sillytavern() {
+        let export = r#"{"user_name":"Alex","character_name":"Luna","create_date":"2023-7-6 @10h 25m 30s 120ms","chat_metadata":{}}
+{"name":"Luna","is_user":false,"send_date":"July 6, 2023 10:25am","mes":"Hi Alex!"}
+{"name":"Alex","is_user":true,"send_date":"July 6, 2023 10:26am","mes":"Hey Luna, Tom says hello."}
+{"name":"System","is_user":false,"is_system":true,"send_date":"July 6, 2023 10:26am","mes":"Luna is typing"}
+{"name":"Luna","send_date":"July 6, 2023 10:27am","mes":"Tell him I said hi!","swipes":["Tell him I said hi!","Oh, Tom!"]}"#;
+        let chat = parse(export, None).unwrap();
+        assert_eq!(chat.format, "sillytavern");
+        assert_eq!(chat.character.as_deref(), Some("Luna"));
+        assert_eq!(chat.skipped, 1);
+        let thread = &chat.threads[0];
+        assert_eq!(
+            thread.iter().map(|m| m.ai).collect::<Vec<_>>(),
+            vec![true, false, true]
+        );
+        let sent_at = thread[1].sent_at.unwrap();
+        assert_eq!(
+            sent_at.format("%d.%m.%Y %H:%M").to_string(),
+            "06.07.2023 10:26"
+        );
+        assert_eq!(
+            parse_time(&serde_json::json!(1688639220000i64)),
+            Local.timestamp_millis_opt(1688639220000).single()
+        );
+        assert!(parse_time(&serde_json::json!("2023-10-09T18:45:00Z")).is_some());
+        assert_eq!(parse_time(&serde_json::json!("yesterday")), None);
+
+        assert!(parse("", None).is_err());
+        assert!(parse("not json", Some("sillytavern")).is_err());
+        assert!(parse(export, Some("tavernai")).is_err());
+    }
+
+    #[test]
+    fn test_parse_cai() {
+        let histories = r#"{"info":{"character":{"name":"Luna"}},"histories":{"histories":[
+            {"msgs":[
+                {"src":{"name":"Luna","is_human":false},"text":"Hello!"},
+                {"src":{"name":"Alex","is_human":true},"text":"Hi"},
+                {"src":{"name":"Luna","is_human":false},"text":""}
+            ]},
+            {"msgs":[]}
+        ]}}"#;
+        let chat = parse(histories, None).unwrap();
+        assert_eq!(chat.format, "cai");
+        assert_eq!(chat.character.as_deref(), Some("Luna"));
+        // The empty chat is left out, the empty message skipped
+        assert_eq!(chat.threads.len(), 1);
+        assert_eq!(chat.skipped, 1);
+        assert_eq!(chat.threads[0][1].content, "Hi");
+        assert!(!chat.threads[0][1].ai);
+
+        // Newest first, put back in order by their times
+        let turns = r#"{"turns":[
+            {"author":{"name":"Luna"},"create_time":"2023-10-09T18:46:00Z","primary_candidate_id":"b",
+             "candidates":[{"candidate_id":"a","raw_content":"Nope"},{"candidate_id":"b","raw_content":"Sure!"}]},
+            {"author":{"name":"Alex","is_human":true},"create_time":"2023-10-09T18:45:00Z",
+             "candidates":[{"candidate_id":"c","raw_content":"Coffee?"}]}
+        ]}"#;
+        let chat = parse(turns, None).unwrap();
+        let thread = &chat.threads[0];
+        assert_eq!(thread[0].content, "Coffee?");
+        assert!(!thread[0].ai);
+        assert_eq!(thread[1].content, "Sure!");
+        assert!(parse(r#"{"histories":"none"}"#, None).is_err());
+    }
+}
@@ -0,0 +1,240 @@
+use crate::database::{get_current_date, ConfigModify, Database};
+use crate::db_pool;
+use rusqlite::{params, Error, OptionalExtension, Result};
+use serde::{Deserialize, Serialize};
+
+/// Named set of the shared settings, put in place in one go
+#[derive(Serialize)]
+pub struct ConfigProfile {
+    pub id: i32,
+    pub name: String,
+    /// Settings as PUT /api/config takes them, without the API key
+    pub config: ConfigModify,
+    pub llm_api_key_set: bool,
+    /// Whether the shared config has exactly these settings right now
+    pub active: bool,
+    pub created_at: String,
+    pub updated_at: String,
+}
+
+#[derive(Deserialize)]
+pub struct ConfigProfileModify {
+    pub name: String,
+    /// Left out to keep the shared config as it is now
+    #[serde(default)]
+    pub config: Option<ConfigModify>,
+}
+
+fn to_sql_error(e: serde_json::Error) -> Error {
+    Error::ToSqlConversionFailure(Box::new(e))
+}
+
+/// The shared config in the shape of a profile
+fn current_settings() -> Result<ConfigModify> {
+    let config = serde_json::to_value(Database::get_global_config()?).map_err(to_sql_error)?;
+    serde_json::from_value(config).map_err(to_sql_error)
+}
+
+/// Settings compared the way they are stored, floats included
+fn same_settings(a: &ConfigModify, b: &ConfigModify) -> bool {
+    serde_json::to_string(a).ok() == serde_json::to_string(b).ok()
+}
+
+pub struct ConfigProfiles {}
+
+impl ConfigProfiles {
+    pub fn create() -> Result<usize> {
+        let con = db_pool::connection()?;
+        con.execute(
+            "CREATE TABLE IF NOT EXISTS config_profiles (
+                id INTEGER PRIMARY KEY AUTOINCREMENT,
+                name TEXT NOT NULL UNIQUE,
+                config TEXT NOT NULL,
+                llm_api_key TEXT NOT NULL DEFAULT '',
+                created_at TEXT NOT NULL,
+                updated_at TEXT NOT NULL
+            )",
+            [],
+        )
+    }
+
+    fn from_row(row: &rusqlite::Row, current: &ConfigModify) -> Result<ConfigProfile> {
+        let config: String = row.get(2)?;
+        // Settings added since the profile was stored get their defaults
+        let config: ConfigModify = serde_json::from_str(&config).map_err(|e| {
+            Error::FromSqlConversionFailure(2, rusqlite::types::Type::Text, Box::new(e))
+        })?;
+        Ok(ConfigProfile {
+            id: row.get(0)?,
+            name: row.get(1)?,
+            active: same_settings(&config, current),
+            config,
+            llm_api_key_set: row.get(3)?,
+            created_at: row.get(4)?,
+            updated_at: row.get(5)?,
+        })
+    }
+
+    pub fn list() -> Result<Vec<ConfigProfile>> {
+        let current = current_settings()?;
+        let con = db_pool::connection()?;
+        let mut stmt = con.prepare(
+            "SELECT id, name, config, llm_api_key != '', created_at, updated_at
+             FROM config_profiles ORDER BY name COLLATE NOCASE",
+        )?;
+        let rows = stmt.query_map([], |row| ConfigProfiles::from_row(row, &current))?;
+        rows.collect()
+    }
+
+    pub fn get(id: i32) -> Result<Option<ConfigProfile>> {
+        let current = current_settings()?;
+        let con = db_pool::connection()?;
+        con.query_row(
+            "SELECT id, name, config, llm_api_key != '', created_at, updated_at
+             FROM config_profiles WHERE id = ?",
+            [id],
+            |row| ConfigProfiles::from_row(row, &current),
+        )
+        .optional()
+    }
+
+    /// Name, settings and API key to store, the shared config when the profile names none
+    fn prepare(profile: ConfigProfileModify) -> Result<(String, String, Option<String>)> {
+        let name = profile.name.trim().to_string();
+        if name.is_empty() {
+            return Err(Error::InvalidParameterName(
+                "Profiles need a name".to_string(),
+            ));
+        }
+        let mut config = match profile.config {
+            Some(config) => config,
+            None => {
+                let current = current_settings()?;
+                ConfigModify {
+                    llm_api_key: Some(Database::get_llm_api_key(&current.llm_api_url)?),
+                    ..current
+                }
+            }
+        };
+        Database::check_config(&config)?;
+        let api_key = config
+            .llm_api_key
+            .take()
+            .map(|api_key| api_key.trim().to_string());
+        let config = serde_json::to_string(&config).map_err(to_sql_error)?;
+        Ok((name, config, api_key))
+    }
+
+    /// Fails with InvalidParameterName for a profile without name or with invalid settings
+    ///
+    /// Without a key the profile gets the one of the shared config, if it is for the same server.
+    pub fn add(profile: ConfigProfileModify) -> Result<i32> {
+        let url = profile
+            .config
+            .as_ref()
+            .map(|config| config.llm_api_url.clone());
+        let (name, config, api_key) = ConfigProfiles::prepare(profile)?;
+        let api_key = match (api_key, url) {
+            (Some(api_key), _) => api_key,
+            (None, Some(url)) => Database::get_llm_api_key(&url)?,
+            (None, None) => String::new(),
+        };
+        let con = db_pool::connection()?;
+        con.execute(
+            "INSERT INTO config_profiles (name, config, llm_api_key, created_at, updated_at)
+             VALUES (?, ?, ?, ?, ?)",
+            params![
+                name,
+                config,
+                api_key,
+                get_current_date(),
+                get_current_date()
+            ],
+        )?;
+        Ok(con.last_insert_rowid() as i32)
+    }
+
+    /// Without a key the profile keeps the one it has
+    pub fn update(id: i32, profile: ConfigProfileModify) -> Result<usize> {
+        let (name, config, api_key) = ConfigProfiles::prepare(profile)?;
+        let con = db_pool::connection()?;
+        con.execute(
+            "UPDATE config_profiles
+             SET name = ?, config = ?, llm_api_key = COALESCE(?, llm_api_key), updated_at = ?
+             WHERE id = ?",
+            params![name, config, api_key, get_current_date(), id],
+        )
+    }
+
+    pub fn delete(id: i32) -> Result<usize> {
+        let con = db_pool::connection()?;
+        con.execute("DELETE FROM config_profiles WHERE id = ?", [id])
+    }
+
+    /// Make the profile's settings the shared config, false when there is no such profile
+    ///
+    /// Companions keep their own overrides on top of it.
+    pub fn activate(id: i32) -> Result<bool> {
+        let con = db_pool::connection()?;
+        let stored: Option<(String, String)> = con
+            .query_row(
+                "SELECT config, llm_api_key FROM config_profiles WHERE id = ?",
+                [id],
+                |row| Ok((row.get(0)?, row.get(1)?)),
+            )
+            .optional()?;
+        let (config, api_key) = match stored {
+            Some(stored) => stored,
+            None => return Ok(false),
+        };
+        let mut config: ConfigModify = serde_json::from_str(&config).map_err(|e| {
+            Error::FromSqlConversionFailure(0, rusqlite::types::Type::Text, Box::new(e))
+        })?;
+        config.llm_api_key = Some(api_key);
+        Database::change_config(config)?;
+        Ok(true)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_same_settings() {
+        let settings = |temperature: f32| -> ConfigModify {
+            serde_json::from_value(serde_json::json!({
+                "device": "CPU",
+                "llm_model_path": "models/llama-2-7b-chat.Q4_K_M.gguf",
+                "gpu_layers": 20,
+                "prompt_template": "Llama2",
+                "context_window_size": 2048,
+                "max_response_tokens": 256,
+                "enable_dynamic_context": true,
+                "vram_limit_gb": 4,
+                "dynamic_gpu_allocation": true,
+                "gpu_safety_margin": 0.8,
+                "min_free_vram_mb": 512,
+                "enable_hybrid_context": false,
+                "max_system_ram_usage_gb": 4,
+                "context_expansion_strategy": "balanced",
+                "ram_safety_margin_gb": 2,
+                "temperature": temperature,
+                "llm_api_key": "sk-secret"
+            }))
+            .unwrap()
+        };
+        let mut stored = settings(0.7);
+        stored.llm_api_key = None;
+        // What the table holds went through JSON once more, the floats must still match
+        let stored: ConfigModify =
+            serde_json::from_str(&serde_json::to_string(&stored).unwrap()).unwrap();
+        assert!(!serde_json::to_string(&stored).unwrap().contains("llm_api_key"));
+        let mut current = settings(0.7);
+        current.llm_api_key = None;
+        assert!(same_settings(&stored, &current));
+        let mut warmer = settings(0.8);
+        warmer.llm_api_key = None;
+        assert!(!same_settings(&stored, &warmer));
+    }
+}
@@ -1,6 +1,15 @@
 use crate::database::{CompanionAttitude, ConfigView, Message, ThirdPartyIndividual};
-use crate::token_budget::{TokenBudget, TokenUsageMonitor, TokenUsageStatistics};
+use crate::lorebook::LorebookEntry;
+use crate::prompt_layout::PromptLayout;
+use crate::token_budget::{PromptSection, TokenBudget, TokenUsageMonitor, TokenUsageStatistics};
 use crate::system_memory::{SystemMemoryDetector, SystemMemoryInfo, MemoryStrategy};
+use serde::Serialize;
+use std::collections::HashSet;
+
+// An exchange just like a disliked reply ranks as if it shared half the message's words less
+const DISLIKE_PENALTY: f32 = 0.5;
+// A prompt overflows when it leaves less than this for the reply
+pub const MIN_RESPONSE_TOKENS: usize = 100;
 
 pub struct ContextManager {
     pub config: ConfigView,
@@ -94,7 +103,7 @@ impl ContextManager {
         let system_memory_info = match system_memory_detector.detect_system_memory() {
             Ok(info) => info,
             Err(e) => {
-                eprintln!("Warning: Failed to detect system memory, falling back to VRAM-only: {}", e);
+                tracing::warn!("⚠️ Failed to detect system memory, falling back to VRAM-only: {}", e);
                 let vram_size = Self::calculate_dynamic_context_size(config.vram_limit_gb, config.context_window_size);
                 return (vram_size, None);
             }
@@ -160,9 +169,9 @@ impl ContextManager {
             system_memory_info: system_memory_info.clone(),
         };
 
-        println!("🧠 Hybrid Context Allocation: {} total tokens (VRAM: {}, RAM: {}) - Strategy: {:?}", 
+        tracing::info!("🧠 Hybrid Context Allocation: {} total tokens (VRAM: {}, RAM: {}) - Strategy: {:?}",
                  final_total_context, base_vram_context, final_ram_context, hybrid_allocation.allocation_strategy);
-        println!("📊 {}", system_memory_detector.get_memory_summary(&system_memory_info));
+        tracing::info!("📊 {}", system_memory_detector.get_memory_summary(&system_memory_info));
 
         (final_total_context, Some(hybrid_allocation))
     }
@@ -175,10 +184,14 @@ impl ContextManager {
     }
 
     /// Prioritize and trim messages to fit within token budget
-    pub fn manage_message_context(&self, messages: Vec<Message>) -> Vec<Message> {
+    ///
+    /// `reserved` tokens of the message budget are kept free for instructions that go with the
+    /// history, such as the companion's post-history instructions.
+    pub fn manage_message_context(&self, messages: Vec<Message>, reserved: usize) -> Vec<Message> {
         if messages.is_empty() {
             return messages;
         }
+        let budget = self.message_token_budget.saturating_sub(reserved);
 
         let mut selected_messages = Vec::new();
         let mut current_tokens = 0;
@@ -186,7 +199,7 @@ impl ContextManager {
         // Always include the most recent message (user's latest input)
         if let Some(last_message) = messages.last() {
             let tokens = Self::estimate_tokens(&last_message.content);
-            if tokens <= self.message_token_budget {
+            if tokens <= budget {
                 selected_messages.push(last_message.clone());
                 current_tokens += tokens;
             }
@@ -196,18 +209,18 @@ impl ContextManager {
         for message in messages.iter().rev().skip(1) {
             let tokens = Self::estimate_tokens(&message.content);
 
-            if current_tokens + tokens <= self.message_token_budget {
+            if current_tokens + tokens <= budget {
                 selected_messages.insert(0, message.clone()); // Insert at beginning to maintain order
                 current_tokens += tokens;
             } else {
                 // If message is too long, try to summarize or truncate
-                if tokens > self.message_token_budget / 4 {
+                if tokens > budget / 4 {
                     // If single message uses >25% of budget
                     let truncated_content =
-                        self.truncate_message(&message.content, self.message_token_budget / 4);
+                        self.truncate_message(&message.content, budget / 4);
                     let truncated_tokens = Self::estimate_tokens(&truncated_content);
 
-                    if current_tokens + truncated_tokens <= self.message_token_budget {
+                    if current_tokens + truncated_tokens <= budget {
                         let mut truncated_message = message.clone();
                         truncated_message.content = truncated_content;
                         selected_messages.insert(0, truncated_message);
@@ -221,6 +234,89 @@ impl ContextManager {
         selected_messages
     }
 
+    /// Hold the sections of the prompt to the caps of `layout`
+    pub fn apply_layout(&mut self, layout: &PromptLayout) {
+        self.token_budget = self.token_budget.clone().with_caps(layout.caps());
+        self.usage_monitor = TokenUsageMonitor::new(self.token_budget.clone());
+        self.attitude_token_budget = self.token_budget.attitude_data;
+        self.message_token_budget = self.token_budget.recent_messages;
+    }
+
+    /// Tokens example dialogue may take, a configured share of the system prompt budget
+    pub fn example_dialogue_budget(&self) -> usize {
+        let budget =
+            self.token_budget.system_prompt * self.config.example_dialogue_budget_percent.min(100) / 100;
+        self.token_budget
+            .cap(PromptSection::ExampleDialogue)
+            .map_or(budget, |cap| budget.min(cap))
+    }
+
+    /// Keep the example exchanges most relevant to `message` that fit the example dialogue budget
+    ///
+    /// Exchanges resembling one of the `disliked` replies rank lower. Kept exchanges stay in
+    /// their original order, the rest is reported as dropped.
+    pub fn select_example_dialogue(
+        &self,
+        dialogue: &str,
+        message: &str,
+        disliked: &[String],
+    ) -> ExampleDialogueSelection {
+        let budget = self.example_dialogue_budget();
+        let exchanges = split_example_exchanges(dialogue);
+        let total_tokens: usize = exchanges.iter().map(|e| Self::estimate_tokens(e)).sum();
+        if total_tokens <= budget {
+            return ExampleDialogueSelection {
+                text: exchanges.join("\n"),
+                budget_tokens: budget,
+                used_tokens: total_tokens,
+                kept: exchanges.len(),
+                dropped: Vec::new(),
+            };
+        }
+
+        let message_words = content_words(message);
+        let mut ranked: Vec<(usize, f32)> = exchanges
+            .iter()
+            .enumerate()
+            .map(|(i, exchange)| {
+                (i, relevance(&message_words, exchange) - DISLIKE_PENALTY * resemblance(exchange, disliked))
+            })
+            .collect();
+        // Stable sort, equally relevant exchanges keep the author's order
+        ranked.sort_by(|a, b| b.1.partial_cmp(&a.1).unwrap_or(std::cmp::Ordering::Equal));
+
+        let mut keep = vec![false; exchanges.len()];
+        let mut used_tokens = 0;
+        for (i, _) in ranked {
+            let tokens = Self::estimate_tokens(&exchanges[i]);
+            if used_tokens + tokens <= budget {
+                keep[i] = true;
+                used_tokens += tokens;
+            }
+        }
+
+        let (kept, dropped): (Vec<_>, Vec<_>) = exchanges
+            .into_iter()
+            .zip(keep)
+            .partition(|(_, keep)| *keep);
+        ExampleDialogueSelection {
+            text: kept.iter().map(|(e, _)| e.as_str()).collect::<Vec<_>>().join("\n"),
+            budget_tokens: budget,
+            used_tokens,
+            kept: kept.len(),
+            dropped: dropped.into_iter().map(|(e, _)| e).collect(),
+        }
+    }
+
+    /// Lorebook entries whose keys appear in `recent`, the highest priority first, within the lorebook budget
+    pub fn select_lore(&self, entries: &[LorebookEntry], recent: &[&str]) -> LoreSelection {
+        let budget = self
+            .token_budget
+            .cap(PromptSection::Lore)
+            .map_or(self.config.lorebook_token_budget, |cap| self.config.lorebook_token_budget.min(cap));
+        select_lore(entries, recent, budget)
+    }
+
     /// Truncate message content to fit within token limit
     fn truncate_message(&self, content: &str, max_tokens: usize) -> String {
         let max_chars = max_tokens * 4; // Approximate character limit
@@ -248,6 +344,11 @@ impl ContextManager {
         }
     }
 
+    /// Tokens the prompt is over the context window by, counting the room a short reply needs
+    pub fn overflow(&self, prompt_tokens: usize) -> usize {
+        (prompt_tokens + MIN_RESPONSE_TOKENS).saturating_sub(self.config.context_window_size)
+    }
+
     /// Check if context management is working within memory constraints
     pub fn validate_context_size(
         &self,
@@ -468,12 +569,12 @@ impl ContextManager {
             return false; // No crisis
         }
 
-        println!("⚠️  Response budget crisis detected: {} tokens available", current_response_budget);
+        tracing::warn!("⚠️ Response budget crisis detected: {} tokens available", current_response_budget);
 
         // Strategy 1: Try to expand context window if hybrid mode is available
         if self.config.enable_hybrid_context && self.hybrid_context_allocation.is_none() {
             if let Some(expanded_allocation) = self.try_expand_context_window() {
-                println!("🔄 Expanded context window using system RAM");
+                tracing::info!("🔄 Expanded context window using system RAM");
                 self.hybrid_context_allocation = Some(expanded_allocation);
                 self.recalculate_token_budget();
                 return true;
@@ -484,7 +585,7 @@ impl ContextManager {
         if let Some(ref hybrid_allocation) = self.hybrid_context_allocation {
             if self.can_expand_ram_allocation(hybrid_allocation) {
                 if let Some(expanded_allocation) = self.expand_ram_allocation() {
-                    println!("🔄 Expanded RAM allocation for context");
+                    tracing::info!("🔄 Expanded RAM allocation for context");
                     self.hybrid_context_allocation = Some(expanded_allocation);
                     self.recalculate_token_budget();
                     return true;
@@ -494,7 +595,7 @@ impl ContextManager {
 
         // Strategy 3: Reallocate existing tokens more efficiently
         self.reallocate_token_budget_for_response();
-        println!("🔄 Reallocated token budget to prioritize response generation");
+        tracing::info!("🔄 Reallocated token budget to prioritize response generation");
         
         false
     }
@@ -576,10 +677,12 @@ impl ContextManager {
     /// Recalculate token budget based on new context size
     fn recalculate_token_budget(&mut self) {
         if let Some(ref hybrid_allocation) = self.hybrid_context_allocation {
+            let caps = std::mem::take(&mut self.token_budget.section_caps);
             self.token_budget = TokenBudget::from_vram_limit(
                 self.config.vram_limit_gb,
                 hybrid_allocation.total_context_tokens,
-            );
+            )
+            .with_caps(caps);
             
             // Update usage monitor with new budget
             self.usage_monitor = TokenUsageMonitor::new(self.token_budget.clone());
@@ -626,6 +729,155 @@ impl ContextManager {
     }
 }
 
+/// What is left out of a prompt that overflows, grown a step at a time until the prompt fits
+#[derive(Debug, Clone, Default)]
+pub struct PromptCuts {
+    /// In the order they were dropped
+    pub sections: Vec<PromptSection>,
+    /// Newest messages of the history that are kept, once the history is cut
+    pub history_limit: Option<usize>,
+}
+
+impl PromptCuts {
+    pub fn has(&self, section: PromptSection) -> bool {
+        self.sections.contains(&section)
+    }
+
+    /// Drop the next section in TokenBudget::DROP_ORDER, the history is halved every step
+    ///
+    /// `history` is the number of messages the prompt has now. Returns false when there is
+    /// nothing left to drop.
+    pub fn next(&mut self, history: usize) -> bool {
+        for section in TokenBudget::DROP_ORDER {
+            if section == PromptSection::History {
+                let kept = self.history_limit.unwrap_or(history).min(history);
+                if kept <= 1 {
+                    return false;
+                }
+                self.history_limit = Some(kept / 2);
+                if !self.has(section) {
+                    self.sections.push(section);
+                }
+                return true;
+            }
+            if !self.has(section) {
+                self.sections.push(section);
+                return true;
+            }
+        }
+        false
+    }
+}
+
+/// Example dialogue that made it into the prompt
+#[derive(Debug, Clone, Serialize)]
+pub struct ExampleDialogueSelection {
+    pub text: String,
+    pub budget_tokens: usize,
+    pub used_tokens: usize,
+    /// Number of exchanges kept
+    pub kept: usize,
+    /// Exchanges left out to stay within budget
+    pub dropped: Vec<String>,
+}
+
+/// Lorebook entries that made it into the prompt
+#[derive(Debug, Clone, Serialize, Default)]
+pub struct LoreSelection {
+    /// Content of the inserted entries, in insertion order
+    pub entries: Vec<String>,
+    pub budget_tokens: usize,
+    pub used_tokens: usize,
+    pub inserted_ids: Vec<i32>,
+    /// Entries whose keys came up but that did not fit the budget
+    pub dropped_ids: Vec<i32>,
+}
+
+/// Enabled entries triggered by `recent`, the highest priority first, that fit in `budget` tokens
+pub fn select_lore(entries: &[LorebookEntry], recent: &[&str], budget: usize) -> LoreSelection {
+    let mut triggered: Vec<&LorebookEntry> = entries
+        .iter()
+        .filter(|entry| entry.enabled)
+        .filter(|entry| recent.iter().any(|text| entry.is_triggered_by(text)))
+        .collect();
+    // Stable sort, entries of equal priority keep their order
+    triggered.sort_by(|a, b| b.priority.cmp(&a.priority));
+
+    let mut selection = LoreSelection {
+        entries: Vec::new(),
+        budget_tokens: budget,
+        used_tokens: 0,
+        inserted_ids: Vec::new(),
+        dropped_ids: Vec::new(),
+    };
+    for entry in triggered {
+        let tokens = ContextManager::estimate_tokens(&entry.content);
+        // A smaller entry further down may still fit
+        if selection.used_tokens + tokens <= budget {
+            selection.entries.push(entry.content.trim().to_string());
+            selection.inserted_ids.push(entry.id);
+            selection.used_tokens += tokens;
+        } else {
+            selection.dropped_ids.push(entry.id);
+        }
+    }
+    selection
+}
+
+/// Split example dialogue into exchanges
+///
+/// An exchange ends at a blank line or <START> marker, or when a {{user}} line follows a {{char}} line.
+pub fn split_example_exchanges(dialogue: &str) -> Vec<String> {
+    let mut exchanges = Vec::new();
+    let mut current: Vec<&str> = Vec::new();
+    let mut char_spoke = false;
+    for line in dialogue.lines() {
+        let trimmed = line.trim();
+        let boundary = trimmed.is_empty()
+            || trimmed.eq_ignore_ascii_case("<START>")
+            || (char_spoke && trimmed.starts_with("{{user}}"));
+        if boundary && !current.is_empty() {
+            exchanges.push(current.join("\n"));
+            current.clear();
+            char_spoke = false;
+        }
+        if !trimmed.is_empty() && !trimmed.eq_ignore_ascii_case("<START>") {
+            char_spoke |= trimmed.starts_with("{{char}}");
+            current.push(line);
+        }
+    }
+    if !current.is_empty() {
+        exchanges.push(current.join("\n"));
+    }
+    exchanges
+}
+
+fn content_words(text: &str) -> HashSet<String> {
+    text.split(|c: char| !c.is_alphanumeric())
+        .filter(|word| word.len() > 2)
+        .map(|word| word.to_lowercase())
+        .filter(|word| word != "user" && word != "char")
+        .collect()
+}
+
+/// How much an exchange reads like the closest of the disliked replies, between 0 and 1
+fn resemblance(exchange: &str, disliked: &[String]) -> f32 {
+    let exchange_words = content_words(exchange);
+    disliked
+        .iter()
+        .map(|reply| relevance(&exchange_words, reply))
+        .fold(0.0, f32::max)
+}
+
+/// Share of the message's words found in the exchange
+fn relevance(message_words: &HashSet<String>, exchange: &str) -> f32 {
+    if message_words.is_empty() {
+        return 0.0;
+    }
+    let exchange_words = content_words(exchange);
+    message_words.intersection(&exchange_words).count() as f32 / message_words.len() as f32
+}
+
 #[derive(Debug)]
 pub struct OptimizedContext {
     pub system_prompt: String,
@@ -638,21 +890,19 @@ pub struct OptimizedContext {
 }
 
 impl OptimizedContext {
-    pub fn print_optimization_summary(&self) {
-        println!("🔧 Context Optimization Summary:");
-        println!("   Messages included: {}", self.messages.len());
-        println!("   Attitudes included: {}", self.attitudes.len());
-        println!("   Third-parties included: {}", self.third_parties.len());
-        println!("   Overflow detected: {}", self.overflow_detected);
-
-        if !self.optimization_suggestions.is_empty() {
-            println!("💡 Optimization suggestions:");
-            for suggestion in &self.optimization_suggestions {
-                println!("   - {}", suggestion);
-            }
+    pub fn log_optimization_summary(&self) {
+        tracing::debug!(
+            messages = self.messages.len(),
+            attitudes = self.attitudes.len(),
+            third_parties = self.third_parties.len(),
+            overflow = self.overflow_detected,
+            "🔧 Context optimization summary"
+        );
+        for suggestion in &self.optimization_suggestions {
+            tracing::debug!("💡 Optimization suggestion: {}", suggestion);
         }
 
-        self.usage_statistics.print_detailed_stats();
+        self.usage_statistics.log_detailed_stats();
     }
 }
 
@@ -668,15 +918,99 @@ pub struct MemoryStats {
 }
 
 impl MemoryStats {
-    pub fn print_stats(&self) {
-        println!("🧠 Context Window Memory Usage:");
-        println!("   System Prompt: {} tokens", self.system_tokens);
-        println!("   Attitude Data: {} tokens", self.attitude_tokens);
-        println!("   Messages: {} tokens", self.message_tokens);
-        println!("   Response Budget: {} tokens", self.response_tokens);
-        println!(
-            "   Total Used: {}/{} tokens ({}%)",
-            self.total_used_tokens, self.total_available_tokens, self.utilization_percentage
+    pub fn log_stats(&self) {
+        tracing::debug!(
+            system_tokens = self.system_tokens,
+            attitude_tokens = self.attitude_tokens,
+            message_tokens = self.message_tokens,
+            response_tokens = self.response_tokens,
+            "🧠 Context window usage: {}/{} tokens ({}%)",
+            self.total_used_tokens,
+            self.total_available_tokens,
+            self.utilization_percentage
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_split_example_exchanges() {
+        let dialogue = "<START>\n{{user}}: Hi\n{{char}}: Hello!\n{{user}}: Do you like cats?\n{{char}}: I love cats.\n\n{{char}}: *waves*";
+        assert_eq!(
+            split_example_exchanges(dialogue),
+            vec![
+                "{{user}}: Hi\n{{char}}: Hello!",
+                "{{user}}: Do you like cats?\n{{char}}: I love cats.",
+                "{{char}}: *waves*",
+            ]
+        );
+    }
+
+    fn lore_entry(id: i32, keys: &[&str], content: &str, priority: i32) -> LorebookEntry {
+        LorebookEntry {
+            id,
+            companion_id: None,
+            keys: keys.iter().map(|key| key.to_string()).collect(),
+            content: content.to_string(),
+            priority,
+            enabled: true,
+            created_at: String::new(),
+            updated_at: String::new(),
+        }
+    }
+
+    #[test]
+    fn test_select_lore() {
+        let mut disabled = lore_entry(4, &["castle"], "Disabled.", 500);
+        disabled.enabled = false;
+        let entries = vec![
+            lore_entry(1, &["castle"], "The castle fell a hundred years ago.", 10),
+            lore_entry(2, &["castle", "king"], "The king lives in the castle.", 50),
+            lore_entry(3, &["dragon"], "Dragons are extinct.", 100),
+            disabled,
+        ];
+        let selection = select_lore(&entries, &["Tell me about the castle"], 10);
+        assert_eq!(selection.inserted_ids, vec![2]);
+        assert_eq!(selection.dropped_ids, vec![1]);
+        assert_eq!(selection.entries, vec!["The king lives in the castle."]);
+        assert!(selection.used_tokens <= 10);
+    }
+
+    #[test]
+    fn test_prompt_cuts() {
+        let mut cuts = PromptCuts::default();
+        for _ in 0..5 {
+            assert!(cuts.next(10));
+        }
+        assert_eq!(cuts.sections, TokenBudget::DROP_ORDER[..5].to_vec());
+        assert_eq!(cuts.history_limit, None);
+        assert!(cuts.next(10));
+        assert_eq!(cuts.history_limit, Some(5));
+        assert!(cuts.next(5));
+        assert!(cuts.next(2));
+        assert_eq!(cuts.history_limit, Some(1));
+        assert!(!cuts.next(1));
+        assert_eq!(cuts.sections.last(), Some(&PromptSection::History));
+        assert_eq!(cuts.sections.len(), 6);
+    }
+
+    #[test]
+    fn test_relevance() {
+        let words = content_words("Tell me about your cats");
+        assert!(relevance(&words, "{{user}}: Do you like cats?") > relevance(&words, "{{user}}: Hi"));
+        assert_eq!(relevance(&HashSet::new(), "{{user}}: Hi"), 0.0);
+    }
+
+    #[test]
+    fn test_resemblance() {
+        let disliked = vec!["Whatever, I don't care about your boring cats.".to_string()];
+        assert!(
+            resemblance("{{char}}: I don't care about cats, boring.", &disliked)
+                > resemblance("{{char}}: I love cats!", &disliked)
         );
+        assert_eq!(resemblance("{{char}}: I love cats!", &[]), 0.0);
     }
 }
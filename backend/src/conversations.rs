@@ -0,0 +1,426 @@
+use crate::database::{get_current_date, Database};
+use crate::db_pool;
+use crate::sampling::SamplingOverrides;
+use crate::template_variables::Variables;
+use rusqlite::{params, Connection, OptionalExtension, Result};
+use serde::{Deserialize, Serialize};
+use std::cell::Cell;
+use std::sync::atomic::{AtomicI32, Ordering};
+use tracing::error;
+
+pub const DEFAULT_TITLE: &str = "New conversation";
+
+// Thread of the active companion that new messages go to, 0 until resolved
+static ACTIVE_CONVERSATION_ID: AtomicI32 = AtomicI32::new(0);
+
+thread_local! {
+    // Thread, companion and user a reply on this OS thread is generated for in place of the active ones
+    static SCOPED: Cell<Option<Scope>> = const { Cell::new(None) };
+}
+
+/// Conversation a reply is generated in without making it the active one
+#[derive(Debug, Clone, Copy)]
+pub struct Scope {
+    pub conversation_id: i32,
+    pub companion_id: i32,
+    pub user_id: i32,
+}
+
+/// The current OS thread goes back to the active conversation once it is dropped
+pub struct ScopeGuard {
+    previous: Option<Scope>,
+}
+
+impl Drop for ScopeGuard {
+    fn drop(&mut self) {
+        SCOPED.with(|scoped| scoped.set(self.previous));
+    }
+}
+
+/// Separate chat thread with a companion, each one builds its prompt from its own messages
+#[derive(Serialize, Debug, Clone)]
+pub struct Conversation {
+    pub id: i32,
+    pub companion_id: i32,
+    pub title: String,
+    pub created_at: String,
+    pub archived: bool,
+    pub active: bool,
+    pub message_count: i64,
+}
+
+/// Model and sampling settings a thread generates with in place of the config's
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct ConversationOverrides {
+    /// Local model file, None for the configured one. Replies from a server ignore it
+    #[serde(default)]
+    pub model_path: Option<String>,
+    /// Laid over the config's sampling settings, a request's own overrides still come on top
+    #[serde(default)]
+    pub sampling: SamplingOverrides,
+}
+
+pub struct Conversations {}
+
+impl Conversations {
+    pub fn create() -> Result<()> {
+        let con = db_pool::connection()?;
+        con.execute(
+            "CREATE TABLE IF NOT EXISTS conversations (
+                id INTEGER PRIMARY KEY AUTOINCREMENT,
+                companion_id INTEGER NOT NULL,
+                title TEXT NOT NULL,
+                created_at TEXT NOT NULL,
+                archived BOOLEAN NOT NULL DEFAULT 0
+            )",
+            [],
+        )?;
+        if !Database::has_column(&con, "messages", "conversation_id")? {
+            con.execute("ALTER TABLE messages ADD COLUMN conversation_id INTEGER", [])?;
+        }
+        if !Database::has_column(&con, "companion", "active_conversation_id")? {
+            con.execute("ALTER TABLE companion ADD COLUMN active_conversation_id INTEGER", [])?;
+        }
+        if !Database::has_column(&con, "conversations", "model_path")? {
+            con.execute("ALTER TABLE conversations ADD COLUMN model_path TEXT", [])?;
+        }
+        if !Database::has_column(&con, "conversations", "sampling")? {
+            con.execute("ALTER TABLE conversations ADD COLUMN sampling TEXT", [])?;
+        }
+        con.execute(
+            "CREATE INDEX IF NOT EXISTS idx_messages_conversation ON messages(conversation_id, id DESC)",
+            [],
+        )?;
+        con.execute(
+            "CREATE INDEX IF NOT EXISTS idx_conversations_companion ON conversations(companion_id, archived)",
+            [],
+        )?;
+        Conversations::adopt_orphans(&con)?;
+        Ok(())
+    }
+
+    /// Messages from before threads existed, or from an older backup, become one thread per companion
+    pub fn adopt_orphans(con: &Connection) -> Result<usize> {
+        let companion_ids: Vec<i32> = {
+            let mut stmt = con.prepare(
+                "SELECT DISTINCT companion_id FROM messages WHERE conversation_id IS NULL",
+            )?;
+            let rows = stmt.query_map([], |row| row.get(0))?;
+            rows.collect::<Result<_>>()?
+        };
+        for companion_id in &companion_ids {
+            con.execute(
+                "INSERT INTO conversations (companion_id, title, created_at) VALUES (?, ?, ?)",
+                params![companion_id, "Conversation", get_current_date()],
+            )?;
+            let conversation_id = con.last_insert_rowid();
+            con.execute(
+                "UPDATE messages SET conversation_id = ? WHERE companion_id = ? AND conversation_id IS NULL",
+                params![conversation_id, companion_id],
+            )?;
+            con.execute(
+                "UPDATE companion SET active_conversation_id = ? WHERE id = ? AND active_conversation_id IS NULL",
+                params![conversation_id, companion_id],
+            )?;
+        }
+        Ok(companion_ids.len())
+    }
+
+    /// Start a thread that opens with the companion's greeting and make it the active one
+    pub fn open(con: &Connection, companion_id: i32, title: &str, greeting: &str) -> Result<i32> {
+        con.execute(
+            "INSERT INTO conversations (companion_id, title, created_at) VALUES (?, ?, ?)",
+            params![companion_id, title, get_current_date()],
+        )?;
+        let conversation_id = con.last_insert_rowid() as i32;
+        con.execute(
+            "INSERT INTO messages (ai, content, created_at, companion_id, conversation_id) VALUES (1, ?, ?, ?, ?)",
+            params![greeting, get_current_date(), companion_id, conversation_id],
+        )?;
+        con.execute(
+            "UPDATE companion SET active_conversation_id = ? WHERE id = ?",
+            params![conversation_id, companion_id],
+        )?;
+        Ok(conversation_id)
+    }
+
+    /// Start a new thread with the active companion and switch to it
+    pub fn start(title: Option<&str>) -> Result<i32> {
+        let companion_id = Database::active_companion_id();
+        let companion = Database::get_companion_data_by_id(companion_id)?;
+        let user = Database::get_user_data()?;
+        let title = title.map(str::trim).filter(|title| !title.is_empty());
+        let con = db_pool::connection()?;
+        let id = Conversations::open(
+            &con,
+            companion_id,
+            title.unwrap_or(DEFAULT_TITLE),
+            &Variables::load(companion_id, Database::active_user_id())
+                .expand(&companion.first_message, &companion, &user),
+        )?;
+        Conversations::set_cached(id);
+        Ok(id)
+    }
+
+    /// Read from and write to this thread as this user on the current OS thread only, None if either is missing
+    ///
+    /// Replies to other channels run alongside the web chat, the active thread and user stay as they are.
+    pub fn scope(id: i32, user_id: i32) -> Result<Option<ScopeGuard>> {
+        let companion_id = match Conversations::companion_of(id)? {
+            Some(companion_id) => companion_id,
+            None => return Ok(None),
+        };
+        let con = db_pool::connection()?;
+        let user_exists: bool =
+            con.query_row("SELECT EXISTS(SELECT 1 FROM user WHERE id = ?)", [user_id], |row| row.get(0))?;
+        if !user_exists {
+            return Ok(None);
+        }
+        let scope = Scope {
+            conversation_id: id,
+            companion_id,
+            user_id,
+        };
+        let previous = SCOPED.with(|scoped| scoped.replace(Some(scope)));
+        Ok(Some(ScopeGuard { previous }))
+    }
+
+    /// Conversation the current OS thread generates in, if it isn't the active one
+    pub fn scoped() -> Option<Scope> {
+        SCOPED.with(Cell::get)
+    }
+
+    /// Thread the chat currently reads from and writes to
+    pub fn active_id() -> i32 {
+        if let Some(scope) = Conversations::scoped() {
+            return scope.conversation_id;
+        }
+        let cached = ACTIVE_CONVERSATION_ID.load(Ordering::SeqCst);
+        if cached > 0 {
+            return cached;
+        }
+        match Conversations::resolve_active(Database::active_companion_id()) {
+            Ok(id) => {
+                ACTIVE_CONVERSATION_ID.store(id, Ordering::SeqCst);
+                id
+            }
+            Err(e) => {
+                error!("Failed to find the active conversation: {}", e);
+                0
+            }
+        }
+    }
+
+    /// The companion's chosen thread, else its latest one, else a fresh one
+    fn resolve_active(companion_id: i32) -> Result<i32> {
+        let con = db_pool::connection()?;
+        let chosen: Option<i32> = con
+            .query_row(
+                "SELECT c.id FROM conversations c JOIN companion p ON p.active_conversation_id = c.id
+                 WHERE p.id = ? AND c.archived = 0",
+                [companion_id],
+                |row| row.get(0),
+            )
+            .optional()?;
+        if let Some(id) = chosen {
+            return Ok(id);
+        }
+        let latest: Option<i32> = con
+            .query_row(
+                "SELECT id FROM conversations WHERE companion_id = ? AND archived = 0 ORDER BY id DESC LIMIT 1",
+                [companion_id],
+                |row| row.get(0),
+            )
+            .optional()?;
+        if let Some(id) = latest {
+            con.execute(
+                "UPDATE companion SET active_conversation_id = ? WHERE id = ?",
+                params![id, companion_id],
+            )?;
+            return Ok(id);
+        }
+        drop(con);
+        let companion = Database::get_companion_data_by_id(companion_id)?;
+        let user = Database::get_user_data()?;
+        let con = db_pool::connection()?;
+        Conversations::open(
+            &con,
+            companion_id,
+            DEFAULT_TITLE,
+            &Variables::load(companion_id, Database::active_user_id())
+                .expand(&companion.first_message, &companion, &user),
+        )
+    }
+
+    fn set_cached(id: i32) {
+        ACTIVE_CONVERSATION_ID.store(id, Ordering::SeqCst);
+        Database::clear_message_cache();
+    }
+
+    /// Drop the cached thread, after a companion switch or a restore
+    pub fn forget_active() {
+        ACTIVE_CONVERSATION_ID.store(0, Ordering::SeqCst);
+    }
+
+    /// Threads of a companion, the most recently used first
+    pub fn list(companion_id: i32, include_archived: bool) -> Result<Vec<Conversation>> {
+        let active_id = Conversations::active_id();
+        let con = db_pool::connection()?;
+        let mut stmt = con.prepare(
+            "SELECT c.id, c.companion_id, c.title, c.created_at, c.archived,
+                (SELECT COUNT(*) FROM messages m WHERE m.conversation_id = c.id) AS message_count,
+                (SELECT MAX(m.id) FROM messages m WHERE m.conversation_id = c.id) AS last_message_id
+             FROM conversations c
+             WHERE c.companion_id = ? AND (? OR c.archived = 0)
+             ORDER BY COALESCE(last_message_id, 0) DESC, c.id DESC",
+        )?;
+        let rows = stmt.query_map(params![companion_id, include_archived], |row| {
+            let id: i32 = row.get(0)?;
+            Ok(Conversation {
+                id,
+                companion_id: row.get(1)?,
+                title: row.get(2)?,
+                created_at: row.get(3)?,
+                archived: row.get(4)?,
+                active: id == active_id,
+                message_count: row.get(5)?,
+            })
+        })?;
+        rows.collect()
+    }
+
+    /// Companion a thread belongs to, None if there is no such thread
+    pub fn companion_of(id: i32) -> Result<Option<i32>> {
+        let con = db_pool::connection()?;
+        con.query_row(
+            "SELECT companion_id FROM conversations WHERE id = ?",
+            [id],
+            |row| row.get(0),
+        )
+        .optional()
+    }
+
+    /// Continue a thread, switching companion if it belongs to another one. Archived threads are restored.
+    pub fn activate(id: i32) -> Result<bool> {
+        let companion_id = match Conversations::companion_of(id)? {
+            Some(companion_id) => companion_id,
+            None => return Ok(false),
+        };
+        if companion_id != Database::active_companion_id() {
+            Database::set_active_companion(companion_id)?;
+        }
+        let con = db_pool::connection()?;
+        con.execute("UPDATE conversations SET archived = 0 WHERE id = ?", [id])?;
+        con.execute(
+            "UPDATE companion SET active_conversation_id = ? WHERE id = ?",
+            params![id, companion_id],
+        )?;
+        Conversations::set_cached(id);
+        Ok(true)
+    }
+
+    /// Hide a thread from the list, its messages stay. The chat moves on to another thread if it was active.
+    pub fn set_archived(id: i32, archived: bool) -> Result<bool> {
+        let con = db_pool::connection()?;
+        let changed = con.execute(
+            "UPDATE conversations SET archived = ? WHERE id = ?",
+            params![archived, id],
+        )?;
+        if changed > 0 && archived && ACTIVE_CONVERSATION_ID.load(Ordering::SeqCst) == id {
+            Conversations::forget_active();
+            Database::clear_message_cache();
+        }
+        Ok(changed > 0)
+    }
+
+    /// Model and sampling settings of a thread, None if it doesn't exist
+    pub fn overrides(id: i32) -> Result<Option<ConversationOverrides>> {
+        let con = db_pool::connection()?;
+        Conversations::read_overrides(&con, id)
+    }
+
+    fn read_overrides(con: &Connection, id: i32) -> Result<Option<ConversationOverrides>> {
+        let row: Option<(Option<String>, Option<String>)> = con
+            .query_row(
+                "SELECT model_path, sampling FROM conversations WHERE id = ?",
+                [id],
+                |row| Ok((row.get(0)?, row.get(1)?)),
+            )
+            .optional()?;
+        let (model_path, sampling) = match row {
+            Some(row) => row,
+            None => return Ok(None),
+        };
+        let sampling = match sampling {
+            Some(sampling) => serde_json::from_str(&sampling).map_err(|e| {
+                rusqlite::Error::FromSqlConversionFailure(1, rusqlite::types::Type::Text, Box::new(e))
+            })?,
+            None => SamplingOverrides::default(),
+        };
+        Ok(Some(ConversationOverrides { model_path, sampling }))
+    }
+
+    /// Pin a model and sampling settings to a thread, returns false if it doesn't exist
+    pub fn set_overrides(id: i32, overrides: &ConversationOverrides) -> Result<bool> {
+        let con = db_pool::connection()?;
+        Conversations::write_overrides(&con, id, overrides)
+    }
+
+    fn write_overrides(con: &Connection, id: i32, overrides: &ConversationOverrides) -> Result<bool> {
+        let sampling = serde_json::to_string(&overrides.sampling)
+            .map_err(|e| rusqlite::Error::ToSqlConversionFailure(Box::new(e)))?;
+        let changed = con.execute(
+            "UPDATE conversations SET model_path = ?, sampling = ? WHERE id = ?",
+            params![overrides.model_path, sampling, id],
+        )?;
+        Ok(changed > 0)
+    }
+
+    pub fn rename(id: i32, title: &str) -> Result<bool> {
+        let con = db_pool::connection()?;
+        let changed = con.execute(
+            "UPDATE conversations SET title = ? WHERE id = ?",
+            params![title, id],
+        )?;
+        Ok(changed > 0)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_overrides_round_trip() {
+        let con = Connection::open_in_memory().unwrap();
+        con.execute(
+            "CREATE TABLE conversations (id INTEGER PRIMARY KEY, model_path TEXT, sampling TEXT)",
+            [],
+        )
+        .unwrap();
+        con.execute("INSERT INTO conversations (id) VALUES (1)", []).unwrap();
+
+        // A thread nobody pinned anything to generates with the config
+        let unset = Conversations::read_overrides(&con, 1).unwrap().unwrap();
+        assert!(unset.model_path.is_none());
+        assert!(unset.sampling.temperature.is_none());
+
+        let overrides = ConversationOverrides {
+            model_path: Some("models/mistral-7b.Q4_K_M.gguf".to_string()),
+            sampling: SamplingOverrides {
+                temperature: Some(1.1),
+                stop_sequences: Some(vec!["\nUser:".to_string()]),
+                ..Default::default()
+            },
+        };
+        assert!(Conversations::write_overrides(&con, 1, &overrides).unwrap());
+        let stored = Conversations::read_overrides(&con, 1).unwrap().unwrap();
+        assert_eq!(stored.model_path, overrides.model_path);
+        assert_eq!(stored.sampling.temperature, Some(1.1));
+        assert_eq!(stored.sampling.stop_sequences, overrides.sampling.stop_sequences);
+        assert!(stored.sampling.top_k.is_none());
+
+        assert!(!Conversations::write_overrides(&con, 2, &overrides).unwrap());
+        assert!(Conversations::read_overrides(&con, 2).unwrap().is_none());
+    }
+}
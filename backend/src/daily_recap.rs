@@ -0,0 +1,186 @@
+use crate::database::{Database, ThirdPartyIndividual};
+use crate::db_pool;
+use crate::delivery_queue::DeliveryQueue;
+use crate::event_bus;
+use crate::journal::{Journal, KIND_DAILY_RECAP};
+use crate::llm::proactive_prompt;
+use crate::naming::fill_placeholders;
+use chrono::{Local, NaiveDate, NaiveDateTime, NaiveTime};
+use rusqlite::{params, Result};
+use serde::Serialize;
+
+/// Facts the end-of-day recap is written from
+#[derive(Serialize, Debug, Default, Clone)]
+pub struct RecapDigest {
+    pub user_messages: usize,
+    pub companion_messages: usize,
+    pub attitude_changes: Vec<String>,
+    pub planned_interactions: Vec<String>,
+}
+
+impl RecapDigest {
+    pub fn gather(companion_id: i32, date: NaiveDate) -> Result<Self> {
+        let day = day_pattern(date);
+        let con = db_pool::connection()?;
+
+        let mut digest = RecapDigest::default();
+        let mut stmt =
+            con.prepare("SELECT ai, COUNT(*) FROM messages WHERE created_at LIKE ? GROUP BY ai")?;
+        let counts = stmt.query_map(params![day], |row| {
+            Ok((row.get::<_, bool>(0)?, row.get::<_, usize>(1)?))
+        })?;
+        for count in counts {
+            match count? {
+                (true, n) => digest.companion_messages = n,
+                (false, n) => digest.user_messages = n,
+            }
+        }
+
+        let mut stmt = con.prepare(
+            "SELECT description FROM attitude_memories
+             WHERE companion_id = ? AND created_at LIKE ?
+             ORDER BY priority_score DESC LIMIT 3",
+        )?;
+        let changes = stmt.query_map(params![companion_id, day], |row| row.get(0))?;
+        digest.attitude_changes = changes.collect::<Result<_>>()?;
+
+        for interaction in Database::get_planned_interactions(companion_id, Some(3))? {
+            let name = Database::get_third_party_by_id(interaction.third_party_id)?
+                .map(|p: ThirdPartyIndividual| p.name)
+                .unwrap_or_else(|| "someone".to_string());
+            let when = interaction
+                .planned_date
+                .map(|date| format!(" ({})", date))
+                .unwrap_or_default();
+            digest
+                .planned_interactions
+                .push(format!("{} with {}{}", interaction.description, name, when));
+        }
+        Ok(digest)
+    }
+
+    /// Plain summary, used as the brief for the companion and as a fallback without a model
+    pub fn describe(&self) -> String {
+        let mut lines = vec![format!(
+            "Today {{{{user}}}} sent {} messages and {{{{char}}}} replied {} times.",
+            self.user_messages, self.companion_messages
+        )];
+        if !self.attitude_changes.is_empty() {
+            lines.push(format!(
+                "Notable feelings: {}.",
+                self.attitude_changes.join("; ")
+            ));
+        }
+        if !self.planned_interactions.is_empty() {
+            lines.push(format!("Coming up: {}.", self.planned_interactions.join("; ")));
+        }
+        lines.join(" ")
+    }
+}
+
+/// LIKE pattern matching stored dates ("%A %d.%m.%Y %H:%M") on the given day
+fn day_pattern(date: NaiveDate) -> String {
+    format!("% {} %", date.format("%d.%m.%Y"))
+}
+
+fn fill_names(text: &str) -> String {
+    match (Database::get_companion_data(), Database::get_user_data()) {
+        (Ok(companion), Ok(user)) => fill_placeholders(text, &companion, &user),
+        _ => text.to_string(),
+    }
+}
+
+/// Whether the recap for `now`'s day should be written, `configured_time` is HH:MM
+pub fn recap_due(now: NaiveDateTime, configured_time: &str, already_written: bool) -> bool {
+    match NaiveTime::parse_from_str(configured_time.trim(), "%H:%M") {
+        Ok(time) => !already_written && now.time() >= time,
+        Err(_) => false,
+    }
+}
+
+/// Write and deliver the recap for a day, returns the journal entry content
+pub fn write_recap(date: NaiveDate) -> Result<String, String> {
+    let companion_id = Database::active_companion_id();
+    let digest = RecapDigest::gather(companion_id, date).map_err(|e| e.to_string())?;
+    let brief = digest.describe();
+    // The reply is stored in the chat, which delivers it as a proactive message
+    let content = match proactive_prompt(&format!(
+        "it is the end of the day, {{{{char}}}} sends {{{{user}}}} a short, warm recap of the day. Notes: {}",
+        brief
+    )) {
+        Ok(reply) if !reply.trim().is_empty() => reply,
+        Ok(_) => fill_names(&brief),
+        Err(e) => {
+            tracing::warn!("⚠️ Failed to generate daily recap, storing plain summary: {}", e);
+            fill_names(&brief)
+        }
+    };
+
+    let entry_date = date.format("%Y-%m-%d").to_string();
+    Journal::add_entry(companion_id, KIND_DAILY_RECAP, &entry_date, &content).map_err(|e| e.to_string())?;
+
+    let data = serde_json::json!({ "date": entry_date, "content": content, "digest": digest });
+    event_bus::publish(KIND_DAILY_RECAP, data.clone());
+    if let Err(e) = DeliveryQueue::enqueue_webhook_event(KIND_DAILY_RECAP, data) {
+        tracing::error!("Failed to queue daily recap for delivery: {}", e);
+    }
+    Ok(content)
+}
+
+/// Maintenance job entry point, writes today's recap once the configured time has passed
+pub fn run_if_due() -> Result<String, String> {
+    let config = Database::get_config().map_err(|e| e.to_string())?;
+    if !config.daily_recap_enabled {
+        return Ok(String::new());
+    }
+    let now = Local::now().naive_local();
+    let entry_date = now.date().format("%Y-%m-%d").to_string();
+    let already_written = Journal::has_entry(Database::active_companion_id(), KIND_DAILY_RECAP, &entry_date)
+        .map_err(|e| e.to_string())?;
+    if !recap_due(now, &config.daily_recap_time, already_written) {
+        return Ok(String::new());
+    }
+    write_recap(now.date()).map(|_| format!("wrote recap for {}", entry_date))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn at(time: &str) -> NaiveDateTime {
+        NaiveDate::from_ymd_opt(2024, 3, 5)
+            .unwrap()
+            .and_time(NaiveTime::parse_from_str(time, "%H:%M").unwrap())
+    }
+
+    #[test]
+    fn test_recap_due() {
+        assert!(!recap_due(at("20:59"), "21:00", false));
+        assert!(recap_due(at("21:00"), "21:00", false));
+        assert!(recap_due(at("23:30"), " 21:00 ", false));
+        assert!(!recap_due(at("23:30"), "21:00", true));
+        assert!(!recap_due(at("23:30"), "9pm", false));
+    }
+
+    #[test]
+    fn test_day_pattern_matches_stored_dates() {
+        let date = NaiveDate::from_ymd_opt(2024, 3, 5).unwrap();
+        assert_eq!(day_pattern(date), "% 05.03.2024 %");
+    }
+
+    #[test]
+    fn test_describe_skips_empty_sections() {
+        let mut digest = RecapDigest {
+            user_messages: 4,
+            companion_messages: 4,
+            ..Default::default()
+        };
+        assert_eq!(
+            digest.describe(),
+            "Today {{user}} sent 4 messages and {{char}} replied 4 times."
+        );
+
+        digest.planned_interactions.push("Dinner with Emma".to_string());
+        assert!(digest.describe().ends_with("Coming up: Dinner with Emma."));
+    }
+}
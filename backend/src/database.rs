@@ -1,12 +1,26 @@
-use chrono::{DateTime, Local};
+use chrono::{DateTime, Local, NaiveDateTime};
 use rusqlite::types::{FromSql, FromSqlError, ToSqlOutput, ValueRef};
-use rusqlite::{params, Connection, Error, Result, ToSql};
+use rusqlite::{params, Connection, Error, OptionalExtension, Result, ToSql};
 use serde::{Deserialize, Serialize};
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap};
+use std::sync::atomic::{AtomicI32, Ordering};
 use std::sync::{Arc, Mutex};
 use std::time::{Duration, Instant};
 
+use crate::attitude_dimensions::dimension_weight;
+use crate::attitude_history::AttitudeHistory;
+use crate::avatar_expressions::{ActiveExpression, AvatarExpressions};
 use crate::character_card::CharacterCard;
+use crate::conversations::{self, Conversations};
+use crate::db_pool;
+use crate::lorebook::{Lorebook, LorebookEntryModify};
+use crate::message_images::{MessageImage, MessageImages};
+use crate::message_usage::ReplyStats;
+use crate::event_bus;
+use crate::naming::Pronouns;
+use crate::personality_traits::{self, PersonalityTrait, PersonalityTraits};
+use crate::prompt_templates::PromptTemplates;
+use crate::template_variables::Variables;
 
 #[derive(Serialize, Deserialize, Clone, Debug)]
 pub struct Message {
@@ -14,13 +28,48 @@ pub struct Message {
     pub ai: bool,
     pub content: String,
     pub created_at: String,
+    /// User who wrote the message, None for the companion's messages
+    #[serde(default)]
+    pub author_id: Option<i32>,
+    #[serde(default)]
+    pub images: Vec<MessageImage>,
+    /// Tokens, time and model a reply took, None for the user's messages and older replies
+    #[serde(default)]
+    pub stats: Option<ReplyStats>,
 }
 
+/// Columns `Database::message_from_row` reads, in its order
+pub const MESSAGE_COLUMNS: &str =
+    "id, ai, content, created_at, author_id, prompt_tokens, completion_tokens, generation_ms, model_name";
+
 pub fn get_current_date() -> String {
     let local: DateTime<Local> = Local::now();
     local.format("%A %d.%m.%Y %H:%M").to_string()
 }
 
+/// Parse a timestamp in the format produced by `get_current_date`
+pub fn parse_stored_date(date: &str) -> Option<NaiveDateTime> {
+    NaiveDateTime::parse_from_str(date, "%A %d.%m.%Y %H:%M").ok()
+}
+
+/// Importance of a third party based on how recently and how often they come up
+pub fn calculate_dynamic_importance(
+    days_since_mention: f32,
+    mention_count: i32,
+    interaction_count: i32,
+    emotional_intensity: f32,
+    previous_importance: f32,
+) -> f32 {
+    let recency = (-days_since_mention.max(0.0) / 30.0).exp();
+    let frequency = 1.0 - (-(mention_count.max(0) as f32) / 10.0).exp();
+    let interactions = 1.0 - (-(interaction_count.max(0) as f32) / 3.0).exp();
+    let intensity = emotional_intensity.clamp(0.0, 1.0);
+
+    let score = 0.35 * recency + 0.3 * frequency + 0.15 * interactions + 0.2 * intensity;
+    // Blend with the previous score so a single quiet week doesn't erase someone
+    (0.75 * score + 0.25 * previous_importance).clamp(0.0, 1.0)
+}
+
 pub fn contains_time_question(text: &str) -> bool {
     let time_related_keywords = [
         "time",
@@ -48,6 +97,9 @@ pub fn contains_time_question(text: &str) -> bool {
 pub struct NewMessage {
     pub ai: bool,
     pub content: String,
+    /// Pictures uploaded with POST /api/upload/image, by name or URL
+    #[serde(default)]
+    pub images: Vec<String>,
 }
 
 #[derive(Serialize, Deserialize)]
@@ -75,6 +127,36 @@ pub struct CompanionView {
     pub roleplay: bool,
     pub dialogue_tuning: bool,
     pub avatar_path: String,
+    /// Shorter name {{char_nickname}} resolves to, the name is used when empty
+    #[serde(default)]
+    pub nickname: String,
+    /// Pronouns like "she/her", {{char_subject}} and friends resolve to they/them when empty
+    #[serde(default)]
+    pub pronouns: String,
+    /// Opening instruction of the prompt, the built-in one is used when empty
+    #[serde(default)]
+    pub system_prompt: String,
+    /// Rules on what the companion may write, right after the system prompt
+    #[serde(default)]
+    pub content_policy: String,
+    /// Instructions after the conversation history, just before the reply
+    #[serde(default)]
+    pub post_history_instructions: String,
+    /// Looks of the companion, what generated pictures of it are drawn from
+    #[serde(default)]
+    pub appearance: String,
+    /// Avatar expression for the companion's mood, only filled in for the API
+    #[serde(default, skip_deserializing)]
+    pub expression: Option<ActiveExpression>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct CompanionSummary {
+    pub id: i32,
+    pub name: String,
+    pub avatar_path: String,
+    pub active: bool,
+    pub message_count: i64,
 }
 
 #[derive(Serialize, Deserialize)]
@@ -84,10 +166,24 @@ pub struct User {
     pub persona: String,
 }
 
+#[derive(Serialize, Deserialize, Debug)]
+pub struct UserSummary {
+    pub id: i32,
+    pub name: String,
+    pub persona: String,
+    pub nickname: String,
+    pub pronouns: String,
+    pub active: bool,
+}
+
 #[derive(Serialize, Deserialize)]
 pub struct UserView {
     pub name: String,
     pub persona: String,
+    #[serde(default)]
+    pub nickname: String,
+    #[serde(default)]
+    pub pronouns: String,
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
@@ -169,6 +265,67 @@ pub struct ThirdPartyIndividual {
     pub updated_at: String,
 }
 
+/// Rows about a person, counted when the person is deleted or merged into another
+#[derive(Serialize, Debug, Clone, Default, PartialEq)]
+pub struct PersonRows {
+    pub attitudes: usize,
+    pub attitude_history: usize,
+    pub attitude_memories: usize,
+    pub memories: usize,
+    pub interactions: usize,
+    pub relationships: usize,
+}
+
+/// Outcome of merging a duplicate person into the one that stays
+#[derive(Serialize, Debug, Clone)]
+pub struct PersonMerge {
+    pub survivor: ThirdPartyIndividual,
+    pub merged_id: i32,
+    pub merged_name: String,
+    /// Rows that now belong to the survivor
+    pub moved: PersonRows,
+    /// Rows the survivor already had a counterpart of, removed with the merged person
+    pub dropped: PersonRows,
+}
+
+/// Details of a person once `merged` is folded into it, what the survivor knows wins
+fn merge_third_party_details(
+    survivor: ThirdPartyIndividual,
+    merged: &ThirdPartyIndividual,
+) -> ThirdPartyIndividual {
+    let either = |kept: Option<String>, other: &Option<String>| {
+        kept.filter(|value| !value.trim().is_empty())
+            .or_else(|| other.clone().filter(|value| !value.trim().is_empty()))
+    };
+    let earlier = |a: &str, b: &str| match (parse_stored_date(a), parse_stored_date(b)) {
+        (Some(x), Some(y)) if y < x => b.to_string(),
+        _ => a.to_string(),
+    };
+    let last_mentioned = match (&survivor.last_mentioned, &merged.last_mentioned) {
+        (Some(a), Some(b)) => match (parse_stored_date(a), parse_stored_date(b)) {
+            (Some(x), Some(y)) if y > x => Some(b.clone()),
+            _ => Some(a.clone()),
+        },
+        (a, b) => a.clone().or_else(|| b.clone()),
+    };
+    ThirdPartyIndividual {
+        relationship_to_user: either(survivor.relationship_to_user, &merged.relationship_to_user),
+        relationship_to_companion: either(
+            survivor.relationship_to_companion,
+            &merged.relationship_to_companion,
+        ),
+        occupation: either(survivor.occupation, &merged.occupation),
+        personality_traits: either(survivor.personality_traits, &merged.personality_traits),
+        physical_description: either(survivor.physical_description, &merged.physical_description),
+        first_mentioned: earlier(&survivor.first_mentioned, &merged.first_mentioned),
+        last_mentioned,
+        mention_count: survivor.mention_count + merged.mention_count,
+        importance_score: survivor.importance_score.max(merged.importance_score),
+        updated_at: get_current_date(),
+        ..survivor
+    }
+}
+
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct ThirdPartyMemory {
     pub id: Option<i32>,
@@ -209,6 +366,43 @@ pub struct ThirdPartyRelationship {
     pub updated_at: String,
 }
 
+#[derive(Deserialize, Debug, Clone)]
+pub struct ThirdPartyRelationshipModify {
+    pub from_party_id: i32,
+    pub to_party_id: i32,
+    pub relationship_type: String,
+    #[serde(default = "default_relationship_strength")]
+    pub strength: f32,
+    #[serde(default)]
+    pub description: Option<String>,
+}
+
+fn default_relationship_strength() -> f32 {
+    0.5
+}
+
+impl ThirdPartyRelationshipModify {
+    /// Type trimmed and lowercased, an error when the relationship cannot be stored
+    pub fn validate(&mut self) -> Result<(), String> {
+        self.relationship_type = self.relationship_type.trim().to_lowercase();
+        if self.relationship_type.is_empty() {
+            return Err("A relationship needs a relationship_type".to_string());
+        }
+        if !(0.0..=1.0).contains(&self.strength) {
+            return Err("strength must be between 0 and 1".to_string());
+        }
+        if self.from_party_id == self.to_party_id {
+            return Err("A person cannot have a relationship with themselves".to_string());
+        }
+        self.description = self
+            .description
+            .as_ref()
+            .map(|d| d.trim().to_string())
+            .filter(|d| !d.is_empty());
+        Ok(())
+    }
+}
+
 #[derive(PartialEq, Serialize, Deserialize, Clone)]
 pub enum Device {
     CPU,
@@ -297,6 +491,32 @@ struct Config {
 }
 */
 
+/// Settings a companion can override, everything else is shared by all companions
+pub const COMPANION_CONFIG_KEYS: [&str; 22] = [
+    "llm_model_path",
+    "prompt_template",
+    "custom_prompt_template",
+    "context_window_size",
+    "max_response_tokens",
+    "example_dialogue_budget_percent",
+    "person_detector",
+    "proactive_interaction_messages",
+    "temperature",
+    "top_p",
+    "top_k",
+    "min_p",
+    "repetition_penalty",
+    "stop_sequences",
+    "seed",
+    "llm_api_url",
+    "llm_api_model",
+    "llm_api_backend",
+    "attitude_inference",
+    "attitude_sensitivity",
+    "tts_voice",
+    "daily_mood_roll",
+];
+
 #[derive(Serialize, Deserialize, Clone)]
 pub struct ConfigView {
     pub device: Device,
@@ -314,6 +534,74 @@ pub struct ConfigView {
     pub max_system_ram_usage_gb: usize,
     pub context_expansion_strategy: String,
     pub ram_safety_margin_gb: usize,
+    pub memory_auto_approve: bool,
+    pub daily_recap_enabled: bool,
+    pub daily_recap_time: String,
+    pub maintenance_window: String,
+    pub example_dialogue_budget_percent: usize,
+    pub person_detector: String,
+    pub proactive_interaction_messages: bool,
+    pub memory_retrieval: String,
+    pub embedding_api_url: String,
+    pub embedding_model: String,
+    pub custom_prompt_template: String,
+    /// Pick the prompt template from the model's metadata whenever another model is selected
+    pub auto_prompt_template: bool,
+    pub attitude_decay_enabled: bool,
+    pub attitude_decay_multiplier: f32,
+    /// How exchanges move the attitude toward the user, one of attitude_inference::INFERENCE_MODES
+    pub attitude_inference: String,
+    /// Factor on the inferred changes of a dimension, 1 for dimensions left out
+    pub attitude_sensitivity: BTreeMap<String, f32>,
+    /// Roll a mood modifier for the companion once a day
+    pub daily_mood_roll: bool,
+    pub stt_api_url: String,
+    pub stt_model: String,
+    /// Speech server replies are read out with, empty to turn text to speech off
+    pub tts_api_url: String,
+    pub tts_model: String,
+    /// Voice the companion speaks with, the server's default when empty
+    pub tts_voice: String,
+    /// Multimodal server pictures in messages are described with, empty to leave them undescribed
+    pub vision_api_url: String,
+    pub vision_model: String,
+    /// Stable Diffusion WebUI or ComfyUI server companion pictures are drawn with, empty to turn them off
+    pub image_gen_api_url: String,
+    /// Checkpoint to draw with, the server's loaded one when empty
+    pub image_gen_model: String,
+    pub lorebook_token_budget: usize,
+    pub proactive_messages_enabled: bool,
+    pub proactive_idle_thresholds: String,
+    pub proactive_quiet_hours: String,
+    /// Openers the companion may send within a day, 0 for no limit
+    pub proactive_daily_limit: usize,
+    pub temperature: f32,
+    pub top_p: f32,
+    pub top_k: usize,
+    pub repetition_penalty: f32,
+    pub stop_sequences: Vec<String>,
+    /// Server replies are generated on, empty for the local model
+    pub llm_api_url: String,
+    pub llm_api_model: String,
+    /// API the server speaks, one of remote_llm::API_BACKENDS
+    pub llm_api_backend: String,
+    /// The key itself is never served
+    pub llm_api_key_set: bool,
+    /// Replies generated at the same time, further requests wait in the inference queue
+    pub max_concurrent_generations: usize,
+    /// Replies a client may ask for per minute, 0 for no limit
+    pub prompt_rate_limit: usize,
+    /// Replies and messages one login session or API key may send per minute, 0 for no limit
+    pub session_rate_limit: usize,
+    /// Keep the rate limit counters in the database so a restart does not reset them
+    pub rate_limit_persist: bool,
+    pub min_p: f32,
+    /// Fixed seed for reproducible replies, None draws a new one for every reply
+    pub seed: Option<u64>,
+    /// Token classification server the ner person detector asks
+    pub ner_api_url: String,
+    /// Entities the model is less sure about are not taken for persons
+    pub ner_min_confidence: f32,
 }
 
 #[derive(Serialize, Deserialize)]
@@ -333,6 +621,179 @@ pub struct ConfigModify {
     pub max_system_ram_usage_gb: usize,
     pub context_expansion_strategy: String,
     pub ram_safety_margin_gb: usize,
+    #[serde(default = "default_true")]
+    pub memory_auto_approve: bool,
+    #[serde(default)]
+    pub daily_recap_enabled: bool,
+    #[serde(default = "default_recap_time")]
+    pub daily_recap_time: String,
+    #[serde(default = "default_maintenance_window")]
+    pub maintenance_window: String,
+    #[serde(default = "default_example_dialogue_budget_percent")]
+    pub example_dialogue_budget_percent: usize,
+    #[serde(default = "default_person_detector")]
+    pub person_detector: String,
+    #[serde(default = "default_true")]
+    pub proactive_interaction_messages: bool,
+    #[serde(default = "default_memory_retrieval")]
+    pub memory_retrieval: String,
+    #[serde(default)]
+    pub embedding_api_url: String,
+    #[serde(default)]
+    pub embedding_model: String,
+    #[serde(default)]
+    pub custom_prompt_template: String,
+    #[serde(default = "default_true")]
+    pub auto_prompt_template: bool,
+    #[serde(default = "default_true")]
+    pub attitude_decay_enabled: bool,
+    #[serde(default = "default_attitude_decay_multiplier")]
+    pub attitude_decay_multiplier: f32,
+    #[serde(default = "default_attitude_inference")]
+    pub attitude_inference: String,
+    #[serde(default)]
+    pub attitude_sensitivity: BTreeMap<String, f32>,
+    #[serde(default)]
+    pub daily_mood_roll: bool,
+    #[serde(default)]
+    pub stt_api_url: String,
+    #[serde(default)]
+    pub stt_model: String,
+    #[serde(default)]
+    pub tts_api_url: String,
+    #[serde(default)]
+    pub tts_model: String,
+    #[serde(default)]
+    pub tts_voice: String,
+    #[serde(default)]
+    pub vision_api_url: String,
+    #[serde(default)]
+    pub vision_model: String,
+    #[serde(default)]
+    pub image_gen_api_url: String,
+    #[serde(default)]
+    pub image_gen_model: String,
+    #[serde(default = "default_lorebook_token_budget")]
+    pub lorebook_token_budget: usize,
+    #[serde(default)]
+    pub proactive_messages_enabled: bool,
+    #[serde(default = "default_proactive_idle_thresholds")]
+    pub proactive_idle_thresholds: String,
+    #[serde(default = "default_proactive_quiet_hours")]
+    pub proactive_quiet_hours: String,
+    #[serde(default)]
+    pub proactive_daily_limit: usize,
+    #[serde(default = "default_temperature")]
+    pub temperature: f32,
+    #[serde(default = "default_top_p")]
+    pub top_p: f32,
+    #[serde(default = "default_top_k")]
+    pub top_k: usize,
+    #[serde(default = "default_repetition_penalty")]
+    pub repetition_penalty: f32,
+    #[serde(default)]
+    pub stop_sequences: Vec<String>,
+    #[serde(default)]
+    pub llm_api_url: String,
+    #[serde(default)]
+    pub llm_api_model: String,
+    #[serde(default = "default_llm_api_backend")]
+    pub llm_api_backend: String,
+    /// Left out to keep the current key, empty to remove it
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub llm_api_key: Option<String>,
+    #[serde(default = "default_max_concurrent_generations")]
+    pub max_concurrent_generations: usize,
+    #[serde(default = "default_prompt_rate_limit")]
+    pub prompt_rate_limit: usize,
+    #[serde(default)]
+    pub session_rate_limit: usize,
+    #[serde(default)]
+    pub rate_limit_persist: bool,
+    #[serde(default)]
+    pub min_p: f32,
+    #[serde(default)]
+    pub seed: Option<u64>,
+    #[serde(default)]
+    pub ner_api_url: String,
+    #[serde(default = "default_ner_min_confidence")]
+    pub ner_min_confidence: f32,
+}
+
+fn default_true() -> bool {
+    true
+}
+
+fn default_recap_time() -> String {
+    "21:00".to_string()
+}
+
+fn default_maintenance_window() -> String {
+    "03:00-05:00".to_string()
+}
+
+fn default_example_dialogue_budget_percent() -> usize {
+    50
+}
+
+fn default_person_detector() -> String {
+    crate::ner::DETECTOR_HEURISTIC.to_string()
+}
+
+fn default_attitude_decay_multiplier() -> f32 {
+    1.0
+}
+
+fn default_lorebook_token_budget() -> usize {
+    512
+}
+
+fn default_proactive_idle_thresholds() -> String {
+    "240,1440".to_string()
+}
+
+fn default_proactive_quiet_hours() -> String {
+    "22:00-08:00".to_string()
+}
+
+fn default_temperature() -> f32 {
+    0.8
+}
+
+fn default_top_p() -> f32 {
+    0.95
+}
+
+fn default_top_k() -> usize {
+    40
+}
+
+fn default_repetition_penalty() -> f32 {
+    1.3
+}
+
+fn default_max_concurrent_generations() -> usize {
+    1
+}
+
+fn default_prompt_rate_limit() -> usize {
+    30
+}
+
+fn default_attitude_inference() -> String {
+    String::from("off")
+}
+
+fn default_llm_api_backend() -> String {
+    String::from("openai")
+}
+
+fn default_ner_min_confidence() -> f32 {
+    0.8
+}
+
+fn default_memory_retrieval() -> String {
+    crate::memory_embeddings::RETRIEVAL_KEYWORD.to_string()
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
@@ -391,27 +852,28 @@ fn calculate_attitude_delta(
 }
 
 fn calculate_impact_score(delta: &AttitudeDelta) -> f32 {
-    // Calculate weighted Euclidean distance in attitude space
+    // Calculate weighted Euclidean distance in attitude space, using the
+    // per-dimension weights from the central dimension definitions
     let dimensions = [
-        ("attraction", delta.attraction, 1.2), // High weight for relationship-defining emotions
-        ("trust", delta.trust, 1.5),
-        ("fear", delta.fear, 1.1),
-        ("anger", delta.anger, 1.3),
-        ("joy", delta.joy, 1.0),
-        ("sorrow", delta.sorrow, 1.0),
-        ("disgust", delta.disgust, 1.1),
-        ("surprise", delta.surprise, 0.8), // Lower weight for transient emotions
-        ("curiosity", delta.curiosity, 0.9),
-        ("respect", delta.respect, 1.4),
-        ("suspicion", delta.suspicion, 1.2),
-        ("gratitude", delta.gratitude, 1.1),
-        ("jealousy", delta.jealousy, 1.3),
-        ("empathy", delta.empathy, 1.2),
+        ("attraction", delta.attraction),
+        ("trust", delta.trust),
+        ("fear", delta.fear),
+        ("anger", delta.anger),
+        ("joy", delta.joy),
+        ("sorrow", delta.sorrow),
+        ("disgust", delta.disgust),
+        ("surprise", delta.surprise),
+        ("curiosity", delta.curiosity),
+        ("respect", delta.respect),
+        ("suspicion", delta.suspicion),
+        ("gratitude", delta.gratitude),
+        ("jealousy", delta.jealousy),
+        ("empathy", delta.empathy),
     ];
 
     let mut weighted_sum = 0.0;
-    for (_, value, weight) in dimensions.iter() {
-        weighted_sum += (value * weight).powi(2);
+    for (name, value) in dimensions.iter() {
+        weighted_sum += (value * dimension_weight(name)).powi(2);
     }
 
     weighted_sum.sqrt()
@@ -477,22 +939,65 @@ fn generate_memory_description(
     memory_type: &str,
     delta: &AttitudeDelta,
     impact_score: f32,
+    companion: &str,
+    pronouns: &Pronouns,
+    target: &str,
 ) -> String {
+    let their = &pronouns.possessive;
     match memory_type {
-        "BondingMoment" => format!("A bonding moment occurred (trust +{:.1}, attraction +{:.1}) with significant relationship impact", delta.trust, delta.attraction),
-        "Betrayal" => format!("Trust was broken (trust {:.1}, anger +{:.1}) creating lasting negative impact", delta.trust, delta.anger),
-        "AttractionSpike" => format!("Strong attraction developed (+{:.1}) indicating romantic/personal interest", delta.attraction),
-        "ThreatDetection" => format!("Threat response triggered (fear +{:.1}, suspicion +{:.1}) affecting security perception", delta.fear, delta.suspicion),
-        "PowerShift" => format!("Significant power dynamic change detected (impact score: {:.1})", impact_score),
-        "ConflictMoment" => format!("Conflict arose (anger +{:.1}) potentially damaging relationship", delta.anger),
-        "RespectGained" => format!("Respect significantly increased (+{:.1}) enhancing relationship status", delta.respect),
-        "RespectLost" => format!("Respect was lost ({:.1}) diminishing relationship quality", delta.respect),
-        "JoyfulMemory" => format!("Joyful experience shared (joy +{:.1}, gratitude +{:.1})", delta.joy, delta.gratitude),
-        "SadMoment" => format!("Sadness experienced together (sorrow +{:.1}) creating emotional bond", delta.sorrow),
-        _ => format!("Significant attitude change detected (impact: {:.1})", impact_score),
+        "BondingMoment" => format!("{} shared a bonding moment with {} (trust +{:.1}, attraction +{:.1}) with significant relationship impact", companion, target, delta.trust, delta.attraction),
+        "Betrayal" => format!("{} broke {}'s trust (trust {:.1}, anger +{:.1}) creating lasting negative impact", target, companion, delta.trust, delta.anger),
+        "AttractionSpike" => format!("{} developed a strong attraction to {} (+{:.1}) indicating romantic/personal interest", companion, target, delta.attraction),
+        "ThreatDetection" => format!("{} felt threatened by {} (fear +{:.1}, suspicion +{:.1}) affecting {} sense of security", companion, target, delta.fear, delta.suspicion, their),
+        "PowerShift" => format!("The power dynamic between {} and {} changed significantly (impact score: {:.1})", companion, target, impact_score),
+        "ConflictMoment" => format!("{} came into conflict with {} (anger +{:.1}) potentially damaging their relationship", companion, target, delta.anger),
+        "RespectGained" => format!("{} gained respect for {} (+{:.1}) enhancing {} view of them", companion, target, delta.respect, their),
+        "RespectLost" => format!("{} lost respect for {} ({:.1}) diminishing relationship quality", companion, target, delta.respect),
+        "JoyfulMemory" => format!("{} shared a joyful experience with {} (joy +{:.1}, gratitude +{:.1})", companion, target, delta.joy, delta.gratitude),
+        "SadMoment" => format!("{} experienced sadness together with {} (sorrow +{:.1}) creating an emotional bond", companion, target, delta.sorrow),
+        _ => format!("{}'s attitude toward {} changed significantly (impact: {:.1})", companion, target, impact_score),
     }
 }
 
+// Third parties are known per companion, the same name may exist once for each of them
+const THIRD_PARTY_INDIVIDUALS_COLUMNS: &str = "(
+                id INTEGER PRIMARY KEY AUTOINCREMENT,
+                companion_id INTEGER NOT NULL DEFAULT 1,
+                name TEXT NOT NULL,
+                relationship_to_user TEXT,
+                relationship_to_companion TEXT,
+                occupation TEXT,
+                personality_traits TEXT,
+                physical_description TEXT,
+                first_mentioned TEXT NOT NULL,
+                last_mentioned TEXT,
+                mention_count INTEGER DEFAULT 1,
+                importance_score REAL DEFAULT 0.5 CHECK(importance_score >= 0 AND importance_score <= 1),
+                created_at TEXT NOT NULL,
+                updated_at TEXT NOT NULL,
+                UNIQUE(companion_id, name)
+            )";
+
+const ATTITUDE_MEMORIES_COLUMNS: &str = "(
+                id INTEGER PRIMARY KEY AUTOINCREMENT,
+                companion_id INTEGER NOT NULL,
+                target_id INTEGER NOT NULL,
+                target_type TEXT NOT NULL,
+                memory_type TEXT NOT NULL,
+                description TEXT NOT NULL,
+                priority_score REAL NOT NULL,
+                attitude_delta_json TEXT NOT NULL,
+                impact_score REAL NOT NULL,
+                message_context TEXT,
+                created_at TEXT NOT NULL,
+                FOREIGN KEY(companion_id) REFERENCES companion(id)
+            )";
+
+// Id of the companion the user is currently talking to, 0 until read from the config
+static ACTIVE_COMPANION_ID: AtomicI32 = AtomicI32::new(0);
+// Id of the user currently talking to the companion, 0 until read from the config
+static ACTIVE_USER_ID: AtomicI32 = AtomicI32::new(0);
+
 // Database query cache for performance optimization
 lazy_static::lazy_static! {
     static ref DB_CACHE: Arc<Mutex<HashMap<String, (String, Instant)>>> = Arc::new(Mutex::new(HashMap::new()));
@@ -513,17 +1018,235 @@ impl Database {
             cache.clear();
         }
     }
+
+    /// Companion whose chat, attitudes and third parties are currently in use
+    pub fn active_companion_id() -> i32 {
+        if let Some(scope) = Conversations::scoped() {
+            return scope.companion_id;
+        }
+        let cached = ACTIVE_COMPANION_ID.load(Ordering::SeqCst);
+        if cached > 0 {
+            return cached;
+        }
+        let id = db_pool::connection()
+            .and_then(|con| {
+                con.query_row("SELECT active_companion_id FROM config LIMIT 1", [], |row| {
+                    row.get::<_, Option<i32>>(0)
+                })
+            })
+            .ok()
+            .flatten()
+            .unwrap_or(1);
+        ACTIVE_COMPANION_ID.store(id, Ordering::SeqCst);
+        id
+    }
+
+    /// Switch to another companion, returns false if it does not exist
+    pub fn set_active_companion(id: i32) -> Result<bool> {
+        let con = db_pool::connection()?;
+        let exists: bool = con.query_row(
+            "SELECT EXISTS(SELECT 1 FROM companion WHERE id = ?)",
+            [id],
+            |row| row.get(0),
+        )?;
+        if !exists {
+            return Ok(false);
+        }
+        con.execute("UPDATE config SET active_companion_id = ?", [id])?;
+        ACTIVE_COMPANION_ID.store(id, Ordering::SeqCst);
+        Conversations::forget_active();
+        Database::clear_message_cache();
+        Database::clear_db_cache();
+        Ok(true)
+    }
+
+    /// User whose messages are stored and whom the companion's attitude in the prompt is about
+    pub fn active_user_id() -> i32 {
+        if let Some(scope) = Conversations::scoped() {
+            return scope.user_id;
+        }
+        let cached = ACTIVE_USER_ID.load(Ordering::SeqCst);
+        if cached > 0 {
+            return cached;
+        }
+        let id = db_pool::connection()
+            .and_then(|con| {
+                con.query_row("SELECT active_user_id FROM config LIMIT 1", [], |row| {
+                    row.get::<_, Option<i32>>(0)
+                })
+            })
+            .ok()
+            .flatten()
+            .unwrap_or(1);
+        ACTIVE_USER_ID.store(id, Ordering::SeqCst);
+        id
+    }
+
+    /// Switch to another user, returns false if it does not exist
+    pub fn set_active_user(id: i32) -> Result<bool> {
+        let con = db_pool::connection()?;
+        let exists: bool = con.query_row(
+            "SELECT EXISTS(SELECT 1 FROM user WHERE id = ?)",
+            [id],
+            |row| row.get(0),
+        )?;
+        if !exists {
+            return Ok(false);
+        }
+        con.execute("UPDATE config SET active_user_id = ?", [id])?;
+        ACTIVE_USER_ID.store(id, Ordering::SeqCst);
+        Database::clear_db_cache();
+        Ok(true)
+    }
+
+    /// Forget everything cached from the database, after its contents were replaced wholesale
+    pub fn clear_caches() {
+        ACTIVE_COMPANION_ID.store(0, Ordering::SeqCst);
+        ACTIVE_USER_ID.store(0, Ordering::SeqCst);
+        Conversations::forget_active();
+        Database::clear_message_cache();
+        Database::clear_db_cache();
+    }
+
+    pub fn list_companions() -> Result<Vec<CompanionSummary>> {
+        let active_id = Database::active_companion_id();
+        let con = db_pool::connection()?;
+        let mut stmt = con.prepare(
+            "SELECT c.id, c.name, c.avatar_path,
+                (SELECT COUNT(*) FROM messages m WHERE m.companion_id = c.id)
+             FROM companion c ORDER BY c.id",
+        )?;
+        let rows = stmt.query_map([], |row| {
+            let id: i32 = row.get(0)?;
+            Ok(CompanionSummary {
+                id,
+                name: row.get(1)?,
+                avatar_path: row.get(2)?,
+                active: id == active_id,
+                message_count: row.get(3)?,
+            })
+        })?;
+        rows.collect()
+    }
+
+    /// Add a companion with its first message and a starting attitude towards the user
+    pub fn create_companion(companion: CompanionView) -> Result<i32> {
+        let mut con = db_pool::connection()?;
+        let tx = con.transaction()?;
+        tx.execute(
+            "INSERT INTO companion (name, persona, example_dialogue, first_message, long_term_mem, short_term_mem, roleplay, dialogue_tuning, avatar_path, nickname, pronouns, system_prompt, content_policy, post_history_instructions, appearance) VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?)",
+            params![
+                companion.name,
+                companion.persona,
+                companion.example_dialogue,
+                companion.first_message,
+                companion.long_term_mem,
+                companion.short_term_mem,
+                companion.roleplay,
+                companion.dialogue_tuning,
+                companion.avatar_path,
+                companion.nickname,
+                companion.pronouns,
+                companion.system_prompt,
+                companion.content_policy,
+                companion.post_history_instructions,
+                companion.appearance,
+            ],
+        )?;
+        let companion_id = tx.last_insert_rowid() as i32;
+        let user = Database::read_user(&tx)?;
+        Conversations::open(
+            &tx,
+            companion_id,
+            conversations::DEFAULT_TITLE,
+            &Variables::load(companion_id, Database::active_user_id())
+                .expand(&companion.first_message, &companion, &user),
+        )?;
+        tx.commit()?;
+
+        // A new companion has no traits yet
+        for user_id in Database::user_ids()? {
+            Database::create_initial_user_attitude(companion_id, user_id, &[])?;
+        }
+        Ok(companion_id)
+    }
+
+    /// Remove a companion together with everything it remembers, the last companion is kept
+    pub fn delete_companion(id: i32) -> Result<bool> {
+        let mut con = db_pool::connection()?;
+        let remaining: Option<i32> = con
+            .query_row(
+                "SELECT id FROM companion WHERE id != ? ORDER BY id LIMIT 1",
+                [id],
+                |row| row.get(0),
+            )
+            .optional()?;
+        let remaining = match remaining {
+            Some(remaining) => remaining,
+            None => return Ok(false),
+        };
+        if Database::active_companion_id() == id {
+            Database::set_active_companion(remaining)?;
+        }
+
+        let tx = con.transaction()?;
+        tx.execute(
+            "DELETE FROM message_attempts WHERE user_message_id IN (SELECT id FROM messages WHERE companion_id = ?)",
+            [id],
+        )?;
+        tx.execute("DELETE FROM message_feedback WHERE companion_id = ?", [id])?;
+        tx.execute("DELETE FROM dialogue_tuning WHERE companion_id = ?", [id])?;
+        tx.execute("DELETE FROM messages WHERE companion_id = ?", [id])?;
+        tx.execute("DELETE FROM conversations WHERE companion_id = ?", [id])?;
+        tx.execute("DELETE FROM lorebook_entries WHERE companion_id = ?", [id])?;
+        tx.execute("DELETE FROM memory_proposals WHERE companion_id = ?", [id])?;
+        tx.execute("DELETE FROM journal_entries WHERE companion_id = ?", [id])?;
+        tx.execute("DELETE FROM proactive_messages WHERE companion_id = ?", [id])?;
+        tx.execute(
+            "DELETE FROM attitude_metadata WHERE attitude_id IN (SELECT id FROM companion_attitudes WHERE companion_id = ?)",
+            [id],
+        )?;
+        tx.execute("DELETE FROM companion_attitudes WHERE companion_id = ?", [id])?;
+        tx.execute("DELETE FROM attitude_memories WHERE companion_id = ?", [id])?;
+        tx.execute("DELETE FROM attitude_history WHERE companion_id = ?", [id])?;
+        tx.execute("DELETE FROM companion_mood WHERE companion_id = ?", [id])?;
+        tx.execute("DELETE FROM mood_modifiers WHERE companion_id = ?", [id])?;
+        tx.execute("DELETE FROM mood_rolls WHERE companion_id = ?", [id])?;
+        tx.execute("DELETE FROM scenes WHERE companion_id = ?", [id])?;
+        tx.execute("DELETE FROM companion_goals WHERE companion_id = ?", [id])?;
+        tx.execute("DELETE FROM avatar_expressions WHERE companion_id = ?", [id])?;
+        tx.execute("DELETE FROM third_party_memories WHERE companion_id = ?", [id])?;
+        tx.execute("DELETE FROM third_party_interactions WHERE companion_id = ?", [id])?;
+        tx.execute("DELETE FROM third_party_individuals WHERE companion_id = ?", [id])?;
+        tx.execute("DELETE FROM companion_config WHERE companion_id = ?", [id])?;
+        tx.execute("DELETE FROM personality_traits WHERE companion_id = ?", [id])?;
+        tx.execute("DELETE FROM template_variables WHERE companion_id = ?", [id])?;
+        tx.execute("DELETE FROM memory_embeddings WHERE companion_id = ?", [id])?;
+        let deleted = tx.execute("DELETE FROM companion WHERE id = ?", [id])?;
+        tx.commit()?;
+        AvatarExpressions::remove_files(id);
+
+        Database::clear_message_cache();
+        Database::clear_db_cache();
+        Ok(deleted > 0)
+    }
 }
 
 impl Database {
     pub fn new() -> Result<usize> {
-        let con = Connection::open("companion_database.db")?;
+        let con = db_pool::connection()?;
         con.execute(
             "CREATE TABLE IF NOT EXISTS messages (
                 id INTEGER PRIMARY KEY AUTOINCREMENT,
                 ai BOOLEAN,
                 content TEXT,
-                created_at TEXT
+                created_at TEXT,
+                companion_id INTEGER DEFAULT 1,
+                author_id INTEGER,
+                prompt_tokens INTEGER,
+                completion_tokens INTEGER,
+                generation_ms INTEGER,
+                model_name TEXT
             )",
             [],
         )?;
@@ -538,7 +1261,17 @@ impl Database {
                 short_term_mem INTEGER,
                 roleplay BOOLEAN,
                 dialogue_tuning BOOLEAN,
-                avatar_path TEXT
+                avatar_path TEXT,
+                nickname TEXT DEFAULT '',
+                pronouns TEXT DEFAULT '',
+                system_prompt TEXT DEFAULT '',
+                post_history_instructions TEXT DEFAULT '',
+                content_policy TEXT DEFAULT '',
+                appearance TEXT DEFAULT '',
+                alternate_greetings TEXT DEFAULT '',
+                tags TEXT DEFAULT '',
+                character_book TEXT DEFAULT '',
+                card_extras TEXT DEFAULT ''
             )",
             [],
         )?;
@@ -547,7 +1280,17 @@ impl Database {
                 id INTEGER PRIMARY KEY AUTOINCREMENT,
                 name TEXT,
                 persona TEXT,
-                avatar_path TEXT
+                avatar_path TEXT,
+                nickname TEXT DEFAULT '',
+                pronouns TEXT DEFAULT ''
+            )",
+            [],
+        )?;
+        con.execute(
+            "CREATE TABLE IF NOT EXISTS companion_config (
+                companion_id INTEGER PRIMARY KEY,
+                overrides TEXT NOT NULL DEFAULT '{}',
+                FOREIGN KEY (companion_id) REFERENCES companion(id) ON DELETE CASCADE
             )",
             [],
         )?;
@@ -568,7 +1311,59 @@ impl Database {
                 enable_hybrid_context BOOLEAN DEFAULT true,
                 max_system_ram_usage_gb INTEGER DEFAULT 8,
                 context_expansion_strategy TEXT DEFAULT 'balanced',
-                ram_safety_margin_gb INTEGER DEFAULT 2
+                ram_safety_margin_gb INTEGER DEFAULT 2,
+                memory_auto_approve BOOLEAN DEFAULT true,
+                daily_recap_enabled BOOLEAN DEFAULT false,
+                daily_recap_time TEXT DEFAULT '21:00',
+                maintenance_window TEXT DEFAULT '03:00-05:00',
+                active_companion_id INTEGER DEFAULT 1,
+                active_user_id INTEGER DEFAULT 1,
+                example_dialogue_budget_percent INTEGER DEFAULT 50,
+                person_detector TEXT DEFAULT 'heuristic',
+                proactive_interaction_messages BOOLEAN DEFAULT true,
+                memory_retrieval TEXT DEFAULT 'keyword',
+                embedding_api_url TEXT DEFAULT '',
+                embedding_model TEXT DEFAULT '',
+                api_key_hash TEXT DEFAULT '',
+                password_hash TEXT DEFAULT '',
+                custom_prompt_template TEXT DEFAULT '',
+                attitude_decay_enabled BOOLEAN DEFAULT true,
+                attitude_decay_multiplier REAL DEFAULT 1.0,
+                stt_api_url TEXT DEFAULT '',
+                stt_model TEXT DEFAULT '',
+                lorebook_token_budget INTEGER DEFAULT 512,
+                proactive_messages_enabled BOOLEAN DEFAULT false,
+                proactive_idle_thresholds TEXT DEFAULT '240,1440',
+                proactive_quiet_hours TEXT DEFAULT '22:00-08:00',
+                temperature REAL DEFAULT 0.8,
+                top_p REAL DEFAULT 0.95,
+                top_k INTEGER DEFAULT 40,
+                repetition_penalty REAL DEFAULT 1.3,
+                stop_sequences TEXT DEFAULT '[]',
+                llm_api_url TEXT DEFAULT '',
+                llm_api_model TEXT DEFAULT '',
+                llm_api_key TEXT DEFAULT '',
+                max_concurrent_generations INTEGER DEFAULT 1,
+                prompt_rate_limit INTEGER DEFAULT 30,
+                min_p REAL DEFAULT 0,
+                seed INTEGER,
+                ner_api_url TEXT DEFAULT '',
+                ner_min_confidence REAL DEFAULT 0.8,
+                llm_api_backend TEXT DEFAULT 'openai',
+                attitude_inference TEXT DEFAULT 'off',
+                attitude_sensitivity TEXT DEFAULT '{}',
+                tts_api_url TEXT DEFAULT '',
+                tts_model TEXT DEFAULT '',
+                tts_voice TEXT DEFAULT '',
+                daily_mood_roll BOOLEAN DEFAULT false,
+                vision_api_url TEXT DEFAULT '',
+                vision_model TEXT DEFAULT '',
+                image_gen_api_url TEXT DEFAULT '',
+                image_gen_model TEXT DEFAULT '',
+                session_rate_limit INTEGER DEFAULT 0,
+                rate_limit_persist BOOLEAN DEFAULT false,
+                proactive_daily_limit INTEGER DEFAULT 0,
+                auto_prompt_template BOOLEAN DEFAULT true
             )",
             [],
         )?;
@@ -643,21 +1438,7 @@ impl Database {
             "CREATE INDEX IF NOT EXISTS idx_attitude_metadata_attitude ON attitude_metadata(attitude_id)", []
         )?;
         con.execute(
-            "CREATE TABLE IF NOT EXISTS third_party_individuals (
-                id INTEGER PRIMARY KEY AUTOINCREMENT,
-                name TEXT NOT NULL UNIQUE,
-                relationship_to_user TEXT,
-                relationship_to_companion TEXT,
-                occupation TEXT,
-                personality_traits TEXT,
-                physical_description TEXT,
-                first_mentioned TEXT NOT NULL,
-                last_mentioned TEXT,
-                mention_count INTEGER DEFAULT 1,
-                importance_score REAL DEFAULT 0.5 CHECK(importance_score >= 0 AND importance_score <= 1),
-                created_at TEXT NOT NULL,
-                updated_at TEXT NOT NULL
-            )", []
+            &format!("CREATE TABLE IF NOT EXISTS third_party_individuals {}", THIRD_PARTY_INDIVIDUALS_COLUMNS), []
         )?;
         con.execute(
             "CREATE TABLE IF NOT EXISTS third_party_memories (
@@ -812,6 +1593,57 @@ impl Database {
         // Migrate companion_attitudes table to add new attitude dimensions if they don't exist
         Database::migrate_companion_attitudes_table(&con)?;
 
+        // Scope messages and third parties by companion
+        Database::migrate_multi_companion(&con)?;
+
+        for table in ["companion", "user"] {
+            for column in ["nickname", "pronouns"] {
+                if !Database::has_column(&con, table, column)? {
+                    con.execute(
+                        &format!("ALTER TABLE {} ADD COLUMN {} TEXT DEFAULT ''", table, column),
+                        [],
+                    )?;
+                }
+            }
+        }
+        // Character Card V2 fields, lists, the character book and the extras are stored as JSON
+        for column in [
+            "system_prompt",
+            "post_history_instructions",
+            "alternate_greetings",
+            "tags",
+            "character_book",
+            "card_extras",
+        ] {
+            if !Database::has_column(&con, "companion", column)? {
+                con.execute(
+                    &format!("ALTER TABLE companion ADD COLUMN {} TEXT DEFAULT ''", column),
+                    [],
+                )?;
+            }
+        }
+        if !Database::has_column(&con, "companion", "content_policy")? {
+            con.execute("ALTER TABLE companion ADD COLUMN content_policy TEXT DEFAULT ''", [])?;
+        }
+        if !Database::has_column(&con, "companion", "appearance")? {
+            con.execute("ALTER TABLE companion ADD COLUMN appearance TEXT DEFAULT ''", [])?;
+        }
+        // Tokens a reply took to generate, NULL for the user's messages and older replies
+        for (column, kind) in [
+            ("prompt_tokens", "INTEGER"),
+            ("completion_tokens", "INTEGER"),
+            ("generation_ms", "INTEGER"),
+            ("model_name", "TEXT"),
+        ] {
+            if !Database::has_column(&con, "messages", column)? {
+                con.execute(&format!("ALTER TABLE messages ADD COLUMN {} {}", column, kind), [])?;
+            }
+        }
+        // Attitude decay remembers when it last ran, interactions keep using last_updated
+        if !Database::has_column(&con, "companion_attitudes", "last_decayed")? {
+            con.execute("ALTER TABLE companion_attitudes ADD COLUMN last_decayed TEXT", [])?;
+        }
+
         // Create inference performance metrics table
         con.execute(
             "CREATE TABLE IF NOT EXISTS inference_metrics (
@@ -835,6 +1667,26 @@ impl Database {
             "CREATE INDEX IF NOT EXISTS idx_inference_metrics_performance ON inference_metrics(tokens_per_second DESC, created_at DESC)",
             [],
         )?;
+        // Filled in when gpu_layers was picked from the measured VRAM
+        for (column, kind) in [
+            ("free_vram_mb", "INTEGER"),
+            ("layer_vram_mb", "REAL"),
+            ("total_layers", "INTEGER"),
+        ] {
+            if !Database::has_column(&con, "inference_metrics", column)? {
+                con.execute(
+                    &format!("ALTER TABLE inference_metrics ADD COLUMN {} {}", column, kind),
+                    [],
+                )?;
+            }
+        }
+        // "chat", "proactive" or "benchmark", keeps benchmark runs apart from real replies
+        if !Database::has_column(&con, "inference_metrics", "source")? {
+            con.execute(
+                "ALTER TABLE inference_metrics ADD COLUMN source TEXT DEFAULT 'chat'",
+                [],
+            )?;
+        }
 
         // Create llm_directories table for managing model scan directories
         con.execute(
@@ -860,7 +1712,7 @@ impl Database {
     }
 
     /* pub fn get_messages() -> Result<Vec<Message>> {
-        let con = Connection::open("companion_database.db")?;
+        let con = db_pool::connection()?;
         let mut stmt = con.prepare("SELECT id, ai, content, created_at FROM messages")?;
         let rows = stmt.query_map([], |row| {
             Ok(Message {
@@ -877,8 +1729,22 @@ impl Database {
         Ok(messages)
     } */
 
+    /// Message from a row of `MESSAGE_COLUMNS`, without its images
+    pub fn message_from_row(row: &rusqlite::Row) -> Result<Message> {
+        Ok(Message {
+            id: row.get(0)?,
+            ai: row.get(1)?,
+            content: row.get(2)?,
+            created_at: row.get(3)?,
+            author_id: row.get(4)?,
+            images: Vec::new(),
+            stats: ReplyStats::from_row(row, 5)?,
+        })
+    }
+
     pub fn get_x_messages(x: usize, index: usize) -> Result<Vec<Message>> {
-        let cache_key = format!("messages:{}:{}", x, index);
+        let conversation_id = Conversations::active_id();
+        let cache_key = format!("messages:{}:{}:{}", conversation_id, x, index);
 
         // Check cache first
         if let Ok(cache) = MESSAGE_CACHE.lock() {
@@ -890,23 +1756,20 @@ impl Database {
             }
         }
 
-        let con = Connection::open("companion_database.db")?;
+        let con = db_pool::connection()?;
         let mut stmt = con.prepare(
-            "SELECT id, ai, content, created_at FROM messages ORDER BY id DESC LIMIT ? OFFSET ?",
+            &format!(
+                "SELECT {} FROM messages WHERE conversation_id = ? ORDER BY id DESC LIMIT ? OFFSET ?",
+                MESSAGE_COLUMNS
+            ),
         )?;
-        let rows = stmt.query_map([x, index], |row| {
-            Ok(Message {
-                id: row.get(0)?,
-                ai: row.get(1)?,
-                content: row.get(2)?,
-                created_at: row.get(3)?,
-            })
-        })?;
+        let rows = stmt.query_map(params![conversation_id, x, index], Database::message_from_row)?;
         let mut messages = Vec::new();
         for row in rows {
             messages.push(row?);
         }
-        let result: Vec<Message> = messages.into_iter().rev().collect();
+        let mut result: Vec<Message> = messages.into_iter().rev().collect();
+        MessageImages::load_for(&con, &mut result)?;
 
         // Cache the results
         if let Ok(mut cache) = MESSAGE_CACHE.lock() {
@@ -920,31 +1783,64 @@ impl Database {
         Ok(result)
     }
 
+    /// Last `x` messages of a chat up to and including the message with this id, oldest first
+    pub fn get_messages_until(message_id: i32, x: usize) -> Result<Vec<Message>> {
+        let con = db_pool::connection()?;
+        let mut stmt = con.prepare(
+            &format!(
+            "SELECT {} FROM messages
+             WHERE id <= ?1 AND conversation_id = (SELECT conversation_id FROM messages WHERE id = ?1)
+             ORDER BY id DESC LIMIT ?2",
+            MESSAGE_COLUMNS
+        ),
+        )?;
+        let rows = stmt.query_map(params![message_id, x], Database::message_from_row)?;
+        let mut messages = rows.collect::<Result<Vec<Message>>>()?;
+        messages.reverse();
+        MessageImages::load_for(&con, &mut messages)?;
+        Ok(messages)
+    }
+
     pub fn get_total_message_count() -> Result<usize> {
-        let con = Connection::open("companion_database.db")?;
-        let count: i64 = con.query_row("SELECT COUNT(*) FROM messages", [], |row| row.get(0))?;
+        let con = db_pool::connection()?;
+        let count: i64 = con.query_row(
+            "SELECT COUNT(*) FROM messages WHERE conversation_id = ?",
+            [Conversations::active_id()],
+            |row| row.get(0),
+        )?;
         Ok(count as usize)
     }
 
     pub fn get_latest_message() -> Result<Message> {
-        let con = Connection::open("companion_database.db")?;
-        let mut stmt = con
-            .prepare("SELECT id, ai, content, created_at FROM messages ORDER BY id DESC LIMIT 1")?;
-        let row = stmt.query_row([], |row| {
-            Ok(Message {
-                id: row.get(0)?,
-                ai: row.get(1)?,
-                content: row.get(2)?,
-                created_at: row.get(3)?,
-            })
-        })?;
-        Ok(row)
+        let con = db_pool::connection()?;
+        let mut stmt = con.prepare(
+            &format!(
+                "SELECT {} FROM messages WHERE conversation_id = ? ORDER BY id DESC LIMIT 1",
+                MESSAGE_COLUMNS
+            ),
+        )?;
+        let mut message = stmt.query_row([Conversations::active_id()], Database::message_from_row)?;
+        MessageImages::load_for(&con, std::slice::from_mut(&mut message))?;
+        Ok(message)
+    }
+
+    pub fn get_latest_user_message_id() -> Result<i32> {
+        let con = db_pool::connection()?;
+        con.query_row(
+            "SELECT id FROM messages WHERE conversation_id = ? AND ai = false ORDER BY id DESC LIMIT 1",
+            [Conversations::active_id()],
+            |row| row.get(0),
+        )
     }
 
     pub fn get_companion_data() -> Result<CompanionView> {
-        let con = Connection::open("companion_database.db")?;
-        let mut stmt = con.prepare("SELECT name, persona, example_dialogue, first_message, long_term_mem, short_term_mem, roleplay, dialogue_tuning, avatar_path FROM companion LIMIT 1")?;
-        let row = stmt.query_row([], |row| {
+        Database::get_companion_data_by_id(Database::active_companion_id())
+    }
+
+    pub fn get_companion_data_by_id(id: i32) -> Result<CompanionView> {
+        let con = db_pool::connection()?;
+        let mut stmt = con.prepare("SELECT name, persona, example_dialogue, first_message, long_term_mem, short_term_mem, roleplay, dialogue_tuning, avatar_path, nickname, pronouns, system_prompt, content_policy, post_history_instructions, appearance FROM companion WHERE id = ?")?;
+        let row = stmt.query_row([id], |row| {
             Ok(CompanionView {
                 name: row.get(0)?,
                 persona: row.get(1)?,
@@ -955,63 +1851,152 @@ impl Database {
                 roleplay: row.get(6)?,
                 dialogue_tuning: row.get(7)?,
                 avatar_path: row.get(8)?,
+                nickname: row.get::<_, Option<String>>(9)?.unwrap_or_default(),
+                pronouns: row.get::<_, Option<String>>(10)?.unwrap_or_default(),
+                system_prompt: row.get::<_, Option<String>>(11)?.unwrap_or_default(),
+                content_policy: row.get::<_, Option<String>>(12)?.unwrap_or_default(),
+                post_history_instructions: row.get::<_, Option<String>>(13)?.unwrap_or_default(),
+                appearance: row.get::<_, Option<String>>(14)?.unwrap_or_default(),
+                expression: None,
             })
         })?;
         Ok(row)
     }
 
     pub fn get_companion_card_data() -> Result<CharacterCard> {
-        let con = Connection::open("companion_database.db")?;
+        let con = db_pool::connection()?;
         let mut stmt = con.prepare(
-            "SELECT name, persona, first_message, example_dialogue FROM companion LIMIT 1",
+            "SELECT name, persona, first_message, example_dialogue, system_prompt,
+                    post_history_instructions, alternate_greetings, tags, character_book, card_extras
+             FROM companion WHERE id = ?",
         )?;
-        let row = stmt.query_row([], |row| {
+        // Empty JSON columns belong to companions created before V2 cards were supported
+        fn json_column<T: serde::de::DeserializeOwned + Default>(
+            row: &rusqlite::Row,
+            index: usize,
+        ) -> Result<T> {
+            match row.get::<_, Option<String>>(index)? {
+                Some(json) if !json.is_empty() => serde_json::from_str(&json).map_err(|e| {
+                    Error::FromSqlConversionFailure(index, rusqlite::types::Type::Text, Box::new(e))
+                }),
+                _ => Ok(T::default()),
+            }
+        }
+        let row = stmt.query_row([Database::active_companion_id()], |row| {
             Ok(CharacterCard {
                 name: row.get(0)?,
                 description: row.get(1)?,
                 first_mes: row.get(2)?,
                 mes_example: row.get(3)?,
+                system_prompt: row.get::<_, Option<String>>(4)?.unwrap_or_default(),
+                post_history_instructions: row.get::<_, Option<String>>(5)?.unwrap_or_default(),
+                alternate_greetings: json_column(row, 6)?,
+                tags: json_column(row, 7)?,
+                character_book: json_column(row, 8)?,
+                extras: json_column(row, 9)?,
             })
         })?;
         Ok(row)
     }
 
+    /// Write every field of a character card onto a companion
+    fn write_character_card(con: &Connection, companion_id: i32, card: &CharacterCard) -> Result<()> {
+        let to_json = |value: serde_json::Value| value.to_string();
+        con.execute(
+            "UPDATE companion SET name = ?, persona = ?, example_dialogue = ?, first_message = ?,
+                system_prompt = ?, post_history_instructions = ?, alternate_greetings = ?, tags = ?,
+                character_book = ?, card_extras = ?
+             WHERE id = ?",
+            params![
+                card.name,
+                card.description,
+                card.mes_example,
+                card.first_mes,
+                card.system_prompt,
+                card.post_history_instructions,
+                to_json(serde_json::json!(card.alternate_greetings)),
+                to_json(serde_json::json!(card.tags)),
+                card.character_book.as_ref().map(|book| book.to_string()).unwrap_or_default(),
+                to_json(serde_json::json!(card.extras)),
+                companion_id,
+            ],
+        )?;
+        Ok(())
+    }
+
     pub fn get_user_data() -> Result<UserView> {
-        let con = Connection::open("companion_database.db")?;
-        let mut stmt = con.prepare("SELECT name, persona FROM user LIMIT 1")?;
-        let row: UserView = stmt.query_row([], |row| {
-            Ok(UserView {
-                name: row.get(0)?,
-                persona: row.get(1)?,
-            })
-        })?;
-        Ok(row)
+        let con = db_pool::connection()?;
+        Database::read_user(&con)
+    }
+
+    /// The active user, or the first one should it be gone
+    fn read_user(con: &Connection) -> Result<UserView> {
+        con.query_row(
+            "SELECT name, persona, nickname, pronouns FROM user ORDER BY id = ? DESC, id LIMIT 1",
+            [Database::active_user_id()],
+            |row| {
+                Ok(UserView {
+                    name: row.get(0)?,
+                    persona: row.get(1)?,
+                    nickname: row.get::<_, Option<String>>(2)?.unwrap_or_default(),
+                    pronouns: row.get::<_, Option<String>>(3)?.unwrap_or_default(),
+                })
+            },
+        )
     }
 
     pub fn get_message(id: i32) -> Result<Message> {
-        let con = Connection::open("companion_database.db")?;
+        let con = db_pool::connection()?;
         let mut stmt =
-            con.prepare("SELECT id, ai, content, created_at FROM messages WHERE id = ?")?;
-        let row = stmt.query_row([id], |row| {
-            Ok(Message {
-                id: row.get(0)?,
-                ai: row.get(1)?,
-                content: row.get(2)?,
-                created_at: row.get(3)?,
-            })
-        })?;
-        Ok(row)
+            con.prepare(&format!("SELECT {} FROM messages WHERE id = ?", MESSAGE_COLUMNS))?;
+        let mut message = stmt.query_row([id], Database::message_from_row)?;
+        MessageImages::load_for(&con, std::slice::from_mut(&mut message))?;
+        Ok(message)
     }
 
+    /// Companion a message was written with, None when there is no such message
+    pub fn message_companion_id(id: i32) -> Result<Option<i32>> {
+        let con = db_pool::connection()?;
+        con.query_row("SELECT companion_id FROM messages WHERE id = ?", [id], |row| {
+            Ok(row.get::<_, Option<i32>>(0)?.unwrap_or(1))
+        })
+        .optional()
+    }
+
+    /// Fails with InvalidParameterName when `images` refers to pictures that weren't uploaded
     pub fn insert_message(message: NewMessage) -> Result<(), Error> {
-        let con = Connection::open("companion_database.db")?;
-        con.execute(
+        Database::insert_message_with_stats(message, None)
+    }
+
+    /// Store a message along with what generating it took
+    pub fn insert_message_with_stats(message: NewMessage, stats: Option<ReplyStats>) -> Result<(), Error> {
+        let images = MessageImages::check(&message.images)?;
+        let mut con = db_pool::connection()?;
+        let author_id = if message.ai {
+            None
+        } else {
+            Some(Database::active_user_id())
+        };
+        let tx = con.transaction()?;
+        tx.execute(
             &format!(
-                "INSERT INTO messages (ai, content, created_at) VALUES ({}, ?, ?)",
+                "INSERT INTO messages (ai, content, created_at, companion_id, conversation_id, author_id, prompt_tokens, completion_tokens, generation_ms, model_name) VALUES ({}, ?, ?, ?, ?, ?, ?, ?, ?, ?)",
                 message.ai
             ),
-            &[&message.content, &get_current_date()],
+            params![
+                message.content,
+                get_current_date(),
+                Database::active_companion_id(),
+                Conversations::active_id(),
+                author_id,
+                stats.as_ref().map(|stats| stats.prompt_tokens),
+                stats.as_ref().map(|stats| stats.completion_tokens),
+                stats.as_ref().and_then(|stats| stats.generation_ms),
+                stats.as_ref().and_then(|stats| stats.model_name.clone()),
+            ],
         )?;
+        MessageImages::attach(&tx, tx.last_insert_rowid(), &images)?;
+        tx.commit()?;
 
         // Clear message cache when new message is inserted
         Database::clear_message_cache();
@@ -1020,7 +2005,7 @@ impl Database {
     }
 
     pub fn edit_message(id: i32, message: NewMessage) -> Result<(), Error> {
-        let con = Connection::open("companion_database.db")?;
+        let con = db_pool::connection()?;
         con.execute(
             &format!(
                 "UPDATE messages SET ai = {}, content = ? WHERE id = ?",
@@ -1036,7 +2021,7 @@ impl Database {
     }
 
     pub fn delete_message(id: i32) -> Result<(), Error> {
-        let con = Connection::open("companion_database.db")?;
+        let con = db_pool::connection()?;
         con.execute("DELETE FROM messages WHERE id = ?", [id])?;
 
         // Clear message cache when message is deleted
@@ -1045,114 +2030,412 @@ impl Database {
         Ok(())
     }
 
-    pub fn delete_latest_message() -> Result<(), rusqlite::Error> {
-        let con = Connection::open("companion_database.db")?;
-        let last_message_id: i32 = con.query_row(
-            "SELECT id FROM messages ORDER BY id DESC LIMIT 1",
-            [],
-            |row| row.get(0),
-        )?;
-        con.execute("DELETE FROM messages WHERE id = ?", [last_message_id])?;
-        Ok(())
-    }
-
+    /// Clear the active conversation down to the greeting, other threads are left alone
     pub fn erase_messages() -> Result<(), Error> {
-        let con = Connection::open("companion_database.db")?;
-        con.execute("DELETE FROM messages", [])?;
+        let companion_id = Database::active_companion_id();
+        let conversation_id = Conversations::active_id();
+        let con = db_pool::connection()?;
+        con.execute("DELETE FROM messages WHERE conversation_id = ?", [conversation_id])?;
+        con.execute("DELETE FROM scenes WHERE conversation_id = ?", [conversation_id])?;
 
         // Clear message cache when all messages are erased
         Database::clear_message_cache();
-        struct CompanionReturn {
-            name: String,
-            first_message: String,
-        }
-        let companion_data =
-            con.query_row("SELECT name, first_message FROM companion", [], |row| {
-                Ok(CompanionReturn {
-                    name: row.get(0)?,
-                    first_message: row.get(1)?,
-                })
-            })?;
-        let user_name: String =
-            con.query_row("SELECT name, persona FROM user LIMIT 1", [], |row| {
-                Ok(row.get(0)?)
-            })?;
+        let companion = Database::get_companion_data()?;
+        let user = Database::get_user_data()?;
         con.execute(
-            "INSERT INTO messages (ai, content, created_at) VALUES (?, ?, ?)",
-            &[
-                "1",
-                &companion_data
-                    .first_message
-                    .replace("{{char}}", &companion_data.name)
-                    .replace("{{user}}", &user_name),
-                &get_current_date(),
+            "INSERT INTO messages (ai, content, created_at, companion_id, conversation_id) VALUES (1, ?, ?, ?, ?)",
+            params![
+                Variables::load(companion_id, Database::active_user_id())
+                    .expand(&companion.first_message, &companion, &user),
+                get_current_date(),
+                companion_id,
+                conversation_id,
             ],
         )?;
         Ok(())
     }
 
     pub fn edit_companion(companion: CompanionView) -> Result<(), Error> {
-        let con = Connection::open("companion_database.db")?;
-        con.execute(
-            &format!("UPDATE companion SET name = ?, persona = ?, example_dialogue = ?, first_message = ?, long_term_mem = {}, short_term_mem = {}, roleplay = {}, dialogue_tuning = {}, avatar_path = ?", companion.long_term_mem, companion.short_term_mem, companion.roleplay, companion.dialogue_tuning),
-            &[
-                &companion.name,
-                &companion.persona,
-                &companion.example_dialogue,
-                &companion.first_message,
-                &companion.avatar_path,
+        Database::edit_companion_by_id(Database::active_companion_id(), companion)?;
+        Ok(())
+    }
+
+    /// Returns false if the companion does not exist
+    pub fn edit_companion_by_id(id: i32, companion: CompanionView) -> Result<bool, Error> {
+        let con = db_pool::connection()?;
+        let changed = con.execute(
+            &format!("UPDATE companion SET name = ?, persona = ?, example_dialogue = ?, first_message = ?, long_term_mem = {}, short_term_mem = {}, roleplay = {}, dialogue_tuning = {}, avatar_path = ?, nickname = ?, pronouns = ?, system_prompt = ?, content_policy = ?, post_history_instructions = ?, appearance = ? WHERE id = ?", companion.long_term_mem, companion.short_term_mem, companion.roleplay, companion.dialogue_tuning),
+            params![
+                companion.name,
+                companion.persona,
+                companion.example_dialogue,
+                companion.first_message,
+                companion.avatar_path,
+                companion.nickname,
+                companion.pronouns,
+                companion.system_prompt,
+                companion.content_policy,
+                companion.post_history_instructions,
+                companion.appearance,
+                id,
             ]
         )?;
-        Ok(())
+        Ok(changed > 0)
     }
 
     pub fn import_character_json(companion: CharacterCard) -> Result<(), Error> {
-        let con = Connection::open("companion_database.db")?;
+        let con = db_pool::connection()?;
+        Database::write_character_card(&con, Database::active_companion_id(), &companion)
+    }
+
+    pub fn import_character_card(companion: CharacterCard, image_path: &str) -> Result<(), Error> {
+        let con = db_pool::connection()?;
+        let companion_id = Database::active_companion_id();
+        Database::write_character_card(&con, companion_id, &companion)?;
         con.execute(
-            "UPDATE companion SET name = ?, persona = ?, example_dialogue = ?, first_message = ?",
-            &[
-                &companion.name,
-                &companion.description,
-                &companion.mes_example,
-                &companion.first_mes,
-            ],
+            "UPDATE companion SET avatar_path = ? WHERE id = ?",
+            params![image_path, companion_id],
         )?;
         Ok(())
     }
 
-    pub fn import_character_card(companion: CharacterCard, image_path: &str) -> Result<(), Error> {
-        let con = Connection::open("companion_database.db")?;
-        con.execute(
-            "UPDATE companion SET name = ?, persona = ?, example_dialogue = ?, first_message = ?, avatar_path = ?",
-            &[
-                &companion.name,
-                &companion.description,
-                &companion.mes_example,
-                &companion.first_mes,
-                image_path
-            ]
+    /// Apply a persona pack in a single transaction, replacing companion data
+    /// and resetting the companion's attitudes to the pack's preset.
+    /// A pack that carries a lorebook replaces the companion's own entries, global ones are kept
+    pub fn import_persona_pack(
+        companion: &CharacterCard,
+        avatar_path: Option<&str>,
+        user_attitude: &CompanionAttitude,
+        lorebook: &[LorebookEntryModify],
+    ) -> Result<(), Error> {
+        let mut con = db_pool::connection()?;
+        let tx = con.transaction()?;
+        Database::write_character_card(&tx, user_attitude.companion_id, companion)?;
+        if let Some(path) = avatar_path {
+            tx.execute(
+                "UPDATE companion SET avatar_path = ? WHERE id = ?",
+                params![path, user_attitude.companion_id],
+            )?;
+        }
+        tx.execute(
+            "DELETE FROM companion_attitudes WHERE companion_id = ?",
+            params![user_attitude.companion_id],
+        )?;
+        let current_time = get_current_date();
+        tx.execute(
+            "INSERT INTO companion_attitudes (
+                companion_id, target_id, target_type, attraction, trust, fear, anger,
+                joy, sorrow, disgust, surprise, curiosity, respect, suspicion,
+                gratitude, jealousy, empathy, lust, love, anxiety, butterflies,
+                submissiveness, dominance, last_updated, created_at
+            ) VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?)",
+            params![
+                user_attitude.companion_id,
+                user_attitude.target_id,
+                user_attitude.target_type,
+                user_attitude.attraction,
+                user_attitude.trust,
+                user_attitude.fear,
+                user_attitude.anger,
+                user_attitude.joy,
+                user_attitude.sorrow,
+                user_attitude.disgust,
+                user_attitude.surprise,
+                user_attitude.curiosity,
+                user_attitude.respect,
+                user_attitude.suspicion,
+                user_attitude.gratitude,
+                user_attitude.jealousy,
+                user_attitude.empathy,
+                user_attitude.lust,
+                user_attitude.love,
+                user_attitude.anxiety,
+                user_attitude.butterflies,
+                user_attitude.submissiveness,
+                user_attitude.dominance,
+                current_time,
+                current_time
+            ],
         )?;
+        if !lorebook.is_empty() {
+            tx.execute(
+                "DELETE FROM lorebook_entries WHERE companion_id = ?",
+                params![user_attitude.companion_id],
+            )?;
+            for entry in lorebook {
+                Lorebook::insert(&tx, user_attitude.companion_id, entry)?;
+            }
+        }
+        tx.commit()?;
         Ok(())
     }
 
     pub fn change_companion_avatar(avatar_path: &str) -> Result<(), Error> {
-        let con = Connection::open("companion_database.db")?;
-        con.execute("UPDATE companion SET avatar_path = ?", &[avatar_path])?;
+        let con = db_pool::connection()?;
+        con.execute(
+            "UPDATE companion SET avatar_path = ? WHERE id = ?",
+            params![avatar_path, Database::active_companion_id()],
+        )?;
         Ok(())
     }
 
     pub fn edit_user(user: UserView) -> Result<(), Error> {
-        let con = Connection::open("companion_database.db")?;
+        Database::edit_user_by_id(Database::active_user_id(), user)?;
+        Ok(())
+    }
+
+    pub fn list_users() -> Result<Vec<UserSummary>> {
+        let active_id = Database::active_user_id();
+        let con = db_pool::connection()?;
+        let mut stmt =
+            con.prepare("SELECT id, name, persona, nickname, pronouns FROM user ORDER BY id")?;
+        let rows = stmt.query_map([], |row| {
+            let id: i32 = row.get(0)?;
+            Ok(UserSummary {
+                id,
+                name: row.get(1)?,
+                persona: row.get(2)?,
+                nickname: row.get::<_, Option<String>>(3)?.unwrap_or_default(),
+                pronouns: row.get::<_, Option<String>>(4)?.unwrap_or_default(),
+                active: id == active_id,
+            })
+        })?;
+        rows.collect()
+    }
+
+    fn user_ids() -> Result<Vec<i32>> {
+        let con = db_pool::connection()?;
+        let mut stmt = con.prepare("SELECT id FROM user ORDER BY id")?;
+        let rows = stmt.query_map([], |row| row.get(0))?;
+        rows.collect()
+    }
+
+    pub fn get_user_by_id(id: i32) -> Result<UserView> {
+        let con = db_pool::connection()?;
+        con.query_row(
+            "SELECT name, persona, nickname, pronouns FROM user WHERE id = ?",
+            [id],
+            |row| {
+                Ok(UserView {
+                    name: row.get(0)?,
+                    persona: row.get(1)?,
+                    nickname: row.get::<_, Option<String>>(2)?.unwrap_or_default(),
+                    pronouns: row.get::<_, Option<String>>(3)?.unwrap_or_default(),
+                })
+            },
+        )
+    }
+
+    /// Add a user, every companion starts out with its initial attitude toward them
+    pub fn create_user(user: UserView) -> Result<i32> {
+        let con = db_pool::connection()?;
         con.execute(
-            "UPDATE user SET name = ?, persona = ?",
-            &[&user.name, &user.persona],
+            "INSERT INTO user (name, persona, avatar_path, nickname, pronouns) VALUES (?, ?, ?, ?, ?)",
+            params![
+                user.name,
+                user.persona,
+                "/assets/user_avatar-4rust.jpg",
+                user.nickname,
+                user.pronouns
+            ],
         )?;
-        Ok(())
+        let user_id = con.last_insert_rowid() as i32;
+        let mut stmt = con.prepare("SELECT id FROM companion")?;
+        let companions = stmt
+            .query_map([], |row| row.get::<_, i32>(0))?
+            .collect::<Result<Vec<_>>>()?;
+        let traits = PersonalityTraits::all()?;
+        for companion_id in companions {
+            let companion_traits = traits.get(&companion_id).map(Vec::as_slice).unwrap_or_default();
+            Database::create_initial_user_attitude(companion_id, user_id, companion_traits)?;
+        }
+        Ok(user_id)
+    }
+
+    /// Returns false if the user does not exist
+    pub fn edit_user_by_id(id: i32, user: UserView) -> Result<bool> {
+        let con = db_pool::connection()?;
+        let updated = con.execute(
+            "UPDATE user SET name = ?, persona = ?, nickname = ?, pronouns = ? WHERE id = ?",
+            params![user.name, user.persona, user.nickname, user.pronouns, id],
+        )?;
+        Database::clear_db_cache();
+        Ok(updated > 0)
+    }
+
+    /// Remove a user and every attitude toward them, the last user is kept.
+    /// Their messages stay in the chats
+    pub fn delete_user(id: i32) -> Result<bool> {
+        let mut con = db_pool::connection()?;
+        let remaining: Option<i32> = con
+            .query_row(
+                "SELECT id FROM user WHERE id != ? ORDER BY id LIMIT 1",
+                [id],
+                |row| row.get(0),
+            )
+            .optional()?;
+        let remaining = match remaining {
+            Some(remaining) => remaining,
+            None => return Ok(false),
+        };
+        if Database::active_user_id() == id {
+            Database::set_active_user(remaining)?;
+        }
+
+        let tx = con.transaction()?;
+        tx.execute(
+            "DELETE FROM attitude_metadata WHERE attitude_id IN
+                (SELECT id FROM companion_attitudes WHERE target_type = 'user' AND target_id = ?)",
+            [id],
+        )?;
+        for table in ["companion_attitudes", "attitude_memories", "attitude_history"] {
+            tx.execute(
+                &format!("DELETE FROM {} WHERE target_type = 'user' AND target_id = ?", table),
+                [id],
+            )?;
+        }
+        let deleted = tx.execute("DELETE FROM user WHERE id = ?", [id])?;
+        tx.commit()?;
+
+        Database::clear_db_cache();
+        Ok(deleted > 0)
     }
 
+    /// Config in effect for the active companion, shared settings with its overrides applied
     pub fn get_config() -> Result<ConfigView> {
-        let con = Connection::open("companion_database.db")?;
-        let mut stmt = con.prepare("SELECT device, llm_model_path, gpu_layers, prompt_template, context_window_size, max_response_tokens, enable_dynamic_context, vram_limit_gb, dynamic_gpu_allocation, gpu_safety_margin, min_free_vram_mb, enable_hybrid_context, max_system_ram_usage_gb, context_expansion_strategy, ram_safety_margin_gb FROM config LIMIT 1")?;
+        Database::get_config_for(Database::active_companion_id())
+    }
+
+    /// The config with one companion's overrides applied, active or not
+    pub fn get_config_for(companion_id: i32) -> Result<ConfigView> {
+        let overrides = Database::get_companion_config(companion_id)?;
+        Database::apply_config_overrides(Database::get_global_config()?, &overrides)
+    }
+
+    fn apply_config_overrides(
+        config: ConfigView,
+        overrides: &serde_json::Map<String, serde_json::Value>,
+    ) -> Result<ConfigView> {
+        if overrides.is_empty() {
+            return Ok(config);
+        }
+        let mut merged = serde_json::to_value(config)
+            .map_err(|e| Error::ToSqlConversionFailure(Box::new(e)))?;
+        for (key, value) in overrides {
+            if COMPANION_CONFIG_KEYS.contains(&key.as_str()) {
+                merged[key] = value.clone();
+            }
+        }
+        serde_json::from_value(merged).map_err(|e| {
+            Error::InvalidParameterName(format!("Invalid companion config override: {}", e))
+        })
+    }
+
+    /// Settings one companion overrides, keyed like the config fields
+    pub fn get_companion_config(
+        companion_id: i32,
+    ) -> Result<serde_json::Map<String, serde_json::Value>> {
+        let con = db_pool::connection()?;
+        let overrides: Option<String> = con
+            .query_row(
+                "SELECT overrides FROM companion_config WHERE companion_id = ?",
+                [companion_id],
+                |row| row.get(0),
+            )
+            .optional()?;
+        match overrides {
+            Some(overrides) => serde_json::from_str(&overrides)
+                .map_err(|e| Error::FromSqlConversionFailure(0, rusqlite::types::Type::Text, Box::new(e))),
+            None => Ok(serde_json::Map::new()),
+        }
+    }
+
+    /// Replace a companion's overrides, an empty map makes it use the shared config again
+    pub fn set_companion_config(
+        companion_id: i32,
+        overrides: serde_json::Map<String, serde_json::Value>,
+    ) -> Result<()> {
+        if let Some(key) = overrides
+            .keys()
+            .find(|key| !COMPANION_CONFIG_KEYS.contains(&key.as_str()))
+        {
+            return Err(Error::InvalidParameterName(format!(
+                "'{}' can't be set per companion, expected one of: {}",
+                key,
+                COMPANION_CONFIG_KEYS.join(", ")
+            )));
+        }
+        let merged = Database::apply_config_overrides(Database::get_global_config()?, &overrides)?;
+        if merged.example_dialogue_budget_percent > 100 {
+            return Err(Error::InvalidParameterName(
+                "Example dialogue budget must be a percentage between 0 and 100".to_string(),
+            ));
+        }
+        if !crate::ner::DETECTORS.contains(&merged.person_detector.as_str()) {
+            return Err(Error::InvalidParameterName(format!(
+                "Invalid person detector, expected one of: {}",
+                crate::ner::DETECTORS.join(", ")
+            )));
+        }
+        Database::check_custom_prompt_template(&merged.custom_prompt_template)?;
+        Database::check_llm_api(&merged.llm_api_url, &merged.llm_api_backend, &merged.llm_api_model)?;
+        crate::attitude_inference::validate(&merged.attitude_inference, &merged.attitude_sensitivity)
+            .map_err(Error::InvalidParameterName)?;
+        crate::sampling::SamplingParams::from_config(&merged)
+            .validate()
+            .map_err(Error::InvalidParameterName)?;
+
+        let con = db_pool::connection()?;
+        if overrides.is_empty() {
+            con.execute("DELETE FROM companion_config WHERE companion_id = ?", [companion_id])?;
+        } else {
+            con.execute(
+                "INSERT INTO companion_config (companion_id, overrides) VALUES (?, ?)
+                 ON CONFLICT(companion_id) DO UPDATE SET overrides = excluded.overrides",
+                params![companion_id, serde_json::Value::Object(overrides).to_string()],
+            )?;
+        }
+        Ok(())
+    }
+
+    /// The server replies are generated on, any URL is fine while it is empty
+    fn check_llm_api(url: &str, backend: &str, model: &str) -> Result<()> {
+        let url = url.trim();
+        if url.is_empty() {
+            return Ok(());
+        }
+        if !(url.starts_with("http://") || url.starts_with("https://")) {
+            return Err(Error::InvalidParameterName(
+                "Invalid llm API URL, expected http:// or https://".to_string(),
+            ));
+        }
+        match crate::remote_llm::ApiBackend::parse(backend) {
+            None => Err(Error::InvalidParameterName(format!(
+                "Invalid llm API backend, expected one of: {}",
+                crate::remote_llm::API_BACKENDS.join(", ")
+            ))),
+            Some(crate::remote_llm::ApiBackend::Ollama) if model.trim().is_empty() => Err(
+                Error::InvalidParameterName("Ollama needs the model to use in llm_api_model".to_string()),
+            ),
+            Some(_) => Ok(()),
+        }
+    }
+
+    /// An empty name keeps the built-in prompt formats, anything else must be a stored template
+    fn check_custom_prompt_template(name: &str) -> Result<()> {
+        if !name.is_empty() && PromptTemplates::get_by_name(name)?.is_none() {
+            return Err(Error::InvalidParameterName(format!(
+                "Prompt template '{}' does not exist",
+                name
+            )));
+        }
+        Ok(())
+    }
+
+    /// Config shared by all companions, as edited through /api/config
+    pub fn get_global_config() -> Result<ConfigView> {
+        let con = db_pool::connection()?;
+        let mut stmt = con.prepare("SELECT device, llm_model_path, gpu_layers, prompt_template, context_window_size, max_response_tokens, enable_dynamic_context, vram_limit_gb, dynamic_gpu_allocation, gpu_safety_margin, min_free_vram_mb, enable_hybrid_context, max_system_ram_usage_gb, context_expansion_strategy, ram_safety_margin_gb, memory_auto_approve, daily_recap_enabled, daily_recap_time, maintenance_window, example_dialogue_budget_percent, person_detector, proactive_interaction_messages, memory_retrieval, embedding_api_url, embedding_model, custom_prompt_template, attitude_decay_enabled, attitude_decay_multiplier, stt_api_url, stt_model, lorebook_token_budget, proactive_messages_enabled, proactive_idle_thresholds, proactive_quiet_hours, temperature, top_p, top_k, repetition_penalty, stop_sequences, llm_api_url, llm_api_model, llm_api_key != '', max_concurrent_generations, prompt_rate_limit, min_p, seed, ner_api_url, ner_min_confidence, llm_api_backend, attitude_inference, attitude_sensitivity, tts_api_url, tts_model, tts_voice, daily_mood_roll, vision_api_url, vision_model, image_gen_api_url, image_gen_model, session_rate_limit, rate_limit_persist, proactive_daily_limit, auto_prompt_template FROM config LIMIT 1")?;
         let row = stmt.query_row([], |row| {
             Ok(ConfigView {
                 device: row.get(0)?,
@@ -1170,12 +2453,71 @@ impl Database {
                 max_system_ram_usage_gb: row.get::<_, Option<usize>>(12)?.unwrap_or(8),
                 context_expansion_strategy: row.get::<_, Option<String>>(13)?.unwrap_or("balanced".to_string()),
                 ram_safety_margin_gb: row.get::<_, Option<usize>>(14)?.unwrap_or(2),
+                memory_auto_approve: row.get::<_, Option<bool>>(15)?.unwrap_or(true),
+                daily_recap_enabled: row.get::<_, Option<bool>>(16)?.unwrap_or(false),
+                daily_recap_time: row.get::<_, Option<String>>(17)?.unwrap_or("21:00".to_string()),
+                maintenance_window: row.get::<_, Option<String>>(18)?.unwrap_or("03:00-05:00".to_string()),
+                example_dialogue_budget_percent: row.get::<_, Option<usize>>(19)?.unwrap_or(50),
+                person_detector: row.get::<_, Option<String>>(20)?.unwrap_or("heuristic".to_string()),
+                proactive_interaction_messages: row.get::<_, Option<bool>>(21)?.unwrap_or(true),
+                memory_retrieval: row.get::<_, Option<String>>(22)?.unwrap_or("keyword".to_string()),
+                embedding_api_url: row.get::<_, Option<String>>(23)?.unwrap_or_default(),
+                embedding_model: row.get::<_, Option<String>>(24)?.unwrap_or_default(),
+                custom_prompt_template: row.get::<_, Option<String>>(25)?.unwrap_or_default(),
+                attitude_decay_enabled: row.get::<_, Option<bool>>(26)?.unwrap_or(true),
+                attitude_decay_multiplier: row.get::<_, Option<f32>>(27)?.unwrap_or(1.0),
+                stt_api_url: row.get::<_, Option<String>>(28)?.unwrap_or_default(),
+                stt_model: row.get::<_, Option<String>>(29)?.unwrap_or_default(),
+                lorebook_token_budget: row.get::<_, Option<usize>>(30)?.unwrap_or(512),
+                proactive_messages_enabled: row.get::<_, Option<bool>>(31)?.unwrap_or(false),
+                proactive_idle_thresholds: row.get::<_, Option<String>>(32)?.unwrap_or(default_proactive_idle_thresholds()),
+                proactive_quiet_hours: row.get::<_, Option<String>>(33)?.unwrap_or(default_proactive_quiet_hours()),
+                temperature: row.get::<_, Option<f32>>(34)?.unwrap_or(0.8),
+                top_p: row.get::<_, Option<f32>>(35)?.unwrap_or(0.95),
+                top_k: row.get::<_, Option<usize>>(36)?.unwrap_or(40),
+                repetition_penalty: row.get::<_, Option<f32>>(37)?.unwrap_or(1.3),
+                stop_sequences: row
+                    .get::<_, Option<String>>(38)?
+                    .and_then(|stop| serde_json::from_str(&stop).ok())
+                    .unwrap_or_default(),
+                llm_api_url: row.get::<_, Option<String>>(39)?.unwrap_or_default(),
+                llm_api_model: row.get::<_, Option<String>>(40)?.unwrap_or_default(),
+                llm_api_key_set: row.get::<_, Option<bool>>(41)?.unwrap_or(false),
+                max_concurrent_generations: row
+                    .get::<_, Option<usize>>(42)?
+                    .unwrap_or(default_max_concurrent_generations()),
+                prompt_rate_limit: row.get::<_, Option<usize>>(43)?.unwrap_or(default_prompt_rate_limit()),
+                min_p: row.get::<_, Option<f32>>(44)?.unwrap_or(0.0),
+                // Stored as SQLite's signed integer, change_config keeps it in range
+                seed: row.get::<_, Option<i64>>(45)?.map(|seed| seed as u64),
+                ner_api_url: row.get::<_, Option<String>>(46)?.unwrap_or_default(),
+                ner_min_confidence: row.get::<_, Option<f32>>(47)?.unwrap_or(default_ner_min_confidence()),
+                llm_api_backend: row.get::<_, Option<String>>(48)?.unwrap_or_else(default_llm_api_backend),
+                attitude_inference: row.get::<_, Option<String>>(49)?.unwrap_or_else(default_attitude_inference),
+                attitude_sensitivity: row
+                    .get::<_, Option<String>>(50)?
+                    .and_then(|sensitivity| serde_json::from_str(&sensitivity).ok())
+                    .unwrap_or_default(),
+                tts_api_url: row.get::<_, Option<String>>(51)?.unwrap_or_default(),
+                tts_model: row.get::<_, Option<String>>(52)?.unwrap_or_default(),
+                tts_voice: row.get::<_, Option<String>>(53)?.unwrap_or_default(),
+                daily_mood_roll: row.get::<_, Option<bool>>(54)?.unwrap_or(false),
+                vision_api_url: row.get::<_, Option<String>>(55)?.unwrap_or_default(),
+                vision_model: row.get::<_, Option<String>>(56)?.unwrap_or_default(),
+                image_gen_api_url: row.get::<_, Option<String>>(57)?.unwrap_or_default(),
+                image_gen_model: row.get::<_, Option<String>>(58)?.unwrap_or_default(),
+                session_rate_limit: row.get::<_, Option<usize>>(59)?.unwrap_or(0),
+                rate_limit_persist: row.get::<_, Option<bool>>(60)?.unwrap_or(false),
+                proactive_daily_limit: row.get::<_, Option<usize>>(61)?.unwrap_or(0),
+                auto_prompt_template: row.get::<_, Option<bool>>(62)?.unwrap_or(true),
             })
         })?;
         Ok(row)
     }
 
-    pub fn change_config(config: ConfigModify) -> Result<(), Error> {
+    /// Fails with InvalidParameterName when a setting is out of range, returns the device and
+    /// prompt format the config names
+    pub fn check_config(config: &ConfigModify) -> Result<(Device, PromptTemplate), Error> {
         let device = match config.device.as_str() {
             "CPU" => Device::CPU,
             "GPU" => Device::GPU,
@@ -1198,9 +2540,128 @@ impl Database {
             }
         };
 
-        let con = Connection::open("companion_database.db")?;
+        if crate::maintenance::parse_window(&config.maintenance_window).is_none() {
+            return Err(rusqlite::Error::InvalidParameterName(
+                "Invalid maintenance window, expected HH:MM-HH:MM".to_string(),
+            ));
+        }
+
+        if config.example_dialogue_budget_percent > 100 {
+            return Err(rusqlite::Error::InvalidParameterName(
+                "Example dialogue budget must be a percentage between 0 and 100".to_string(),
+            ));
+        }
+
+        if !crate::ner::DETECTORS.contains(&config.person_detector.as_str()) {
+            return Err(rusqlite::Error::InvalidParameterName(format!(
+                "Invalid person detector, expected one of: {}",
+                crate::ner::DETECTORS.join(", ")
+            )));
+        }
+
+        if !crate::memory_embeddings::RETRIEVAL_MODES.contains(&config.memory_retrieval.as_str()) {
+            return Err(rusqlite::Error::InvalidParameterName(
+                "Invalid memory retrieval, expected keyword, vector or hybrid".to_string(),
+            ));
+        }
+
+        Database::check_custom_prompt_template(&config.custom_prompt_template)?;
+
+        Database::check_llm_api(&config.llm_api_url, &config.llm_api_backend, &config.llm_api_model)?;
+
+        let ner_api_url = config.ner_api_url.trim();
+        if !ner_api_url.is_empty()
+            && !(ner_api_url.starts_with("http://") || ner_api_url.starts_with("https://"))
+        {
+            return Err(rusqlite::Error::InvalidParameterName(
+                "Invalid NER API URL, expected http:// or https://".to_string(),
+            ));
+        }
+
+        if !(0.0..=1.0).contains(&config.ner_min_confidence) {
+            return Err(rusqlite::Error::InvalidParameterName(
+                "ner_min_confidence must be between 0 and 1".to_string(),
+            ));
+        }
+
+        if crate::proactivity::parse_thresholds(&config.proactive_idle_thresholds).is_none() {
+            return Err(rusqlite::Error::InvalidParameterName(
+                "Invalid proactive idle thresholds, expected minutes such as 240,1440".to_string(),
+            ));
+        }
+
+        if !(1..=crate::inference_queue::MAX_CONCURRENT_GENERATIONS).contains(&config.max_concurrent_generations) {
+            return Err(rusqlite::Error::InvalidParameterName(format!(
+                "max_concurrent_generations must be between 1 and {}",
+                crate::inference_queue::MAX_CONCURRENT_GENERATIONS
+            )));
+        }
+
+        crate::sampling::SamplingParams {
+            temperature: config.temperature,
+            top_p: config.top_p,
+            top_k: config.top_k,
+            min_p: config.min_p,
+            repetition_penalty: config.repetition_penalty,
+            stop_sequences: config.stop_sequences.clone(),
+            seed: config.seed,
+        }
+        .validate()
+        .map_err(Error::InvalidParameterName)?;
+
+        if !config.proactive_quiet_hours.trim().is_empty()
+            && crate::maintenance::parse_window(&config.proactive_quiet_hours).is_none()
+        {
+            return Err(rusqlite::Error::InvalidParameterName(
+                "Invalid proactive quiet hours, expected HH:MM-HH:MM or nothing".to_string(),
+            ));
+        }
+
+        if !config.attitude_decay_multiplier.is_finite()
+            || !(0.0..=10.0).contains(&config.attitude_decay_multiplier)
+        {
+            return Err(rusqlite::Error::InvalidParameterName(
+                "Attitude decay multiplier must be between 0 and 10".to_string(),
+            ));
+        }
+
+        crate::attitude_inference::validate(&config.attitude_inference, &config.attitude_sensitivity)
+            .map_err(Error::InvalidParameterName)?;
+
+        let tts_api_url = config.tts_api_url.trim();
+        if !tts_api_url.is_empty() && !(tts_api_url.starts_with("http://") || tts_api_url.starts_with("https://")) {
+            return Err(Error::InvalidParameterName(
+                "Invalid text to speech URL, expected http:// or https://".to_string(),
+            ));
+        }
+
+        let vision_api_url = config.vision_api_url.trim();
+        if !vision_api_url.is_empty()
+            && !(vision_api_url.starts_with("http://") || vision_api_url.starts_with("https://"))
+        {
+            return Err(Error::InvalidParameterName(
+                "Invalid vision model URL, expected http:// or https://".to_string(),
+            ));
+        }
+
+        let image_gen_api_url = config.image_gen_api_url.trim();
+        if !image_gen_api_url.is_empty()
+            && !(image_gen_api_url.starts_with("http://") || image_gen_api_url.starts_with("https://"))
+        {
+            return Err(Error::InvalidParameterName(
+                "Invalid image generation URL, expected http:// or https://".to_string(),
+            ));
+        }
+
+        Ok((device, prompt_template))
+    }
+
+    pub fn change_config(config: ConfigModify) -> Result<(), Error> {
+        let (device, prompt_template) = Database::check_config(&config)?;
+
+        let con = db_pool::connection()?;
         con.execute(
-            "UPDATE config SET device = ?, llm_model_path = ?, gpu_layers = ?, prompt_template = ?, context_window_size = ?, max_response_tokens = ?, enable_dynamic_context = ?, vram_limit_gb = ?, dynamic_gpu_allocation = ?, gpu_safety_margin = ?, min_free_vram_mb = ?, enable_hybrid_context = ?, max_system_ram_usage_gb = ?, context_expansion_strategy = ?, ram_safety_margin_gb = ?",
+            "UPDATE config SET device = ?, llm_model_path = ?, gpu_layers = ?, prompt_template = ?, context_window_size = ?, max_response_tokens = ?, enable_dynamic_context = ?, vram_limit_gb = ?, dynamic_gpu_allocation = ?, gpu_safety_margin = ?, min_free_vram_mb = ?, enable_hybrid_context = ?, max_system_ram_usage_gb = ?, context_expansion_strategy = ?, ram_safety_margin_gb = ?, memory_auto_approve = ?, daily_recap_enabled = ?, daily_recap_time = ?, maintenance_window = ?, example_dialogue_budget_percent = ?, person_detector = ?, proactive_interaction_messages = ?, memory_retrieval = ?, embedding_api_url = ?, embedding_model = ?, custom_prompt_template = ?, attitude_decay_enabled = ?, attitude_decay_multiplier = ?, stt_api_url = ?, stt_model = ?, lorebook_token_budget = ?, proactive_messages_enabled = ?, proactive_idle_thresholds = ?, proactive_quiet_hours = ?, temperature = ?, top_p = ?, top_k = ?, repetition_penalty = ?, stop_sequences = ?, llm_api_url = ?, llm_api_model = ?, max_concurrent_generations = ?, prompt_rate_limit = ?, min_p = ?, seed = ?, ner_api_url = ?, ner_min_confidence = ?, llm_api_backend = ?, attitude_inference = ?, attitude_sensitivity = ?, tts_api_url = ?, tts_model = ?, tts_voice = ?, daily_mood_roll = ?, vision_api_url = ?, vision_model = ?, image_gen_api_url = ?, image_gen_model = ?, session_rate_limit = ?, rate_limit_persist = ?, proactive_daily_limit = ?, auto_prompt_template = ?",
             &[
                 &device as &dyn ToSql,
                 &config.llm_model_path,
@@ -1217,7 +2678,92 @@ impl Database {
                 &config.max_system_ram_usage_gb,
                 &config.context_expansion_strategy,
                 &config.ram_safety_margin_gb,
-            ]
+                &config.memory_auto_approve,
+                &config.daily_recap_enabled,
+                &config.daily_recap_time,
+                &config.maintenance_window,
+                &config.example_dialogue_budget_percent,
+                &config.person_detector,
+                &config.proactive_interaction_messages,
+                &config.memory_retrieval,
+                &config.embedding_api_url,
+                &config.embedding_model,
+                &config.custom_prompt_template,
+                &config.attitude_decay_enabled,
+                &config.attitude_decay_multiplier,
+                &config.stt_api_url,
+                &config.stt_model,
+                &config.lorebook_token_budget,
+                &config.proactive_messages_enabled,
+                &config.proactive_idle_thresholds,
+                &config.proactive_quiet_hours,
+                &config.temperature,
+                &config.top_p,
+                &config.top_k,
+                &config.repetition_penalty,
+                &serde_json::json!(config.stop_sequences).to_string(),
+                &config.llm_api_url.trim(),
+                &config.llm_api_model.trim(),
+                &config.max_concurrent_generations,
+                &config.prompt_rate_limit,
+                &config.min_p,
+                &config.seed.map(|seed| seed as i64),
+                &config.ner_api_url.trim(),
+                &config.ner_min_confidence,
+                &config.llm_api_backend.trim(),
+                &config.attitude_inference,
+                &serde_json::json!(config.attitude_sensitivity).to_string(),
+                &config.tts_api_url.trim(),
+                &config.tts_model.trim(),
+                &config.tts_voice.trim(),
+                &config.daily_mood_roll,
+                &config.vision_api_url.trim(),
+                &config.vision_model.trim(),
+                &config.image_gen_api_url.trim(),
+                &config.image_gen_model.trim(),
+                &config.session_rate_limit,
+                &config.rate_limit_persist,
+                &config.proactive_daily_limit,
+                &config.auto_prompt_template,
+            ][..]
+        )?;
+        if let Some(api_key) = &config.llm_api_key {
+            con.execute("UPDATE config SET llm_api_key = ?", [api_key.trim()])?;
+        }
+        Ok(())
+    }
+
+    /// Key sent to the server at `url`, empty when it needs none
+    ///
+    /// The key belongs to the shared llm_api_url, a companion generating on another server
+    /// doesn't get it.
+    pub fn get_llm_api_key(url: &str) -> Result<String> {
+        let con = db_pool::connection()?;
+        con.query_row("SELECT llm_api_url, llm_api_key FROM config LIMIT 1", [], |row| {
+            let shared_url = row.get::<_, Option<String>>(0)?.unwrap_or_default();
+            let api_key = row.get::<_, Option<String>>(1)?.unwrap_or_default();
+            Ok(if shared_url.trim() == url.trim() { api_key } else { String::new() })
+        })
+    }
+
+    pub fn set_gpu_layers(gpu_layers: usize) -> Result<()> {
+        let con = db_pool::connection()?;
+        con.execute("UPDATE config SET gpu_layers = ?", [gpu_layers])?;
+        Ok(())
+    }
+
+    /// Point the shared config at another model file, a reload picks it up
+    pub fn set_llm_model_path(path: &str) -> Result<()> {
+        let con = db_pool::connection()?;
+        con.execute("UPDATE config SET llm_model_path = ?", [path])?;
+        Ok(())
+    }
+
+    pub fn set_prompt_template(prompt_template: PromptTemplate, custom_prompt_template: &str) -> Result<()> {
+        let con = db_pool::connection()?;
+        con.execute(
+            "UPDATE config SET prompt_template = ?, custom_prompt_template = ?",
+            params![prompt_template, custom_prompt_template],
         )?;
         Ok(())
     }
@@ -1228,7 +2774,7 @@ impl Database {
         target_type: &str,
         attitude: &CompanionAttitude,
     ) -> Result<i32> {
-        let con = Connection::open("companion_database.db")?;
+        let con = db_pool::connection()?;
         let current_time = get_current_date();
 
         let existing_id: Option<i32> = con.query_row(
@@ -1270,6 +2816,7 @@ impl Database {
                     id
                 ],
             )?;
+            AttitudeHistory::track(companion_id, target_id, target_type);
             Ok(id)
         } else {
             con.execute(
@@ -1307,16 +2854,34 @@ impl Database {
                     current_time
                 ],
             )?;
-            Ok(con.last_insert_rowid() as i32)
+            let id = con.last_insert_rowid() as i32;
+            AttitudeHistory::track(companion_id, target_id, target_type);
+            Ok(id)
         }
     }
 
+    /// Whether an attitude target id refers to an existing row of its target type
+    pub fn attitude_target_exists(target_id: i32, target_type: &str) -> Result<bool> {
+        let table = match target_type {
+            "user" => "user",
+            "third_party" => "third_party_individuals",
+            _ => return Ok(false),
+        };
+        let con = db_pool::connection()?;
+        let count: i64 = con.query_row(
+            &format!("SELECT COUNT(*) FROM {} WHERE id = ?", table),
+            params![target_id],
+            |row| row.get(0),
+        )?;
+        Ok(count > 0)
+    }
+
     pub fn get_attitude(
         companion_id: i32,
         target_id: i32,
         target_type: &str,
     ) -> Result<Option<CompanionAttitude>> {
-        let con = Connection::open("companion_database.db")?;
+        let con = db_pool::connection()?;
         let mut stmt = con.prepare(
             "SELECT id, companion_id, target_id, target_type, attraction, trust, fear, anger,
                     joy, sorrow, disgust, surprise, curiosity, respect, suspicion,
@@ -1373,7 +2938,7 @@ impl Database {
         // Get the attitude before the change for comparison
         let previous_attitude = Database::get_attitude(companion_id, target_id, target_type)?;
 
-        let con = Connection::open("companion_database.db")?;
+        let con = db_pool::connection()?;
         let current_time = get_current_date();
 
         let query = format!(
@@ -1387,6 +2952,7 @@ impl Database {
             &query,
             params![delta, current_time, companion_id, target_id, target_type],
         )?;
+        AttitudeHistory::track(companion_id, target_id, target_type);
 
         // Get the attitude after the change and check for significant changes
         if let Some(previous) = previous_attitude {
@@ -1409,7 +2975,7 @@ impl Database {
     }
 
     pub fn get_all_companion_attitudes(companion_id: i32) -> Result<Vec<CompanionAttitude>> {
-        let con = Connection::open("companion_database.db")?;
+        let con = db_pool::connection()?;
         let mut stmt = con.prepare(
             "SELECT id, companion_id, target_id, target_type, attraction, trust, fear, anger,
                     joy, sorrow, disgust, surprise, curiosity, respect, suspicion,
@@ -1465,7 +3031,7 @@ impl Database {
         interaction_type: &str,
         event: Option<&str>,
     ) -> Result<()> {
-        let con = Connection::open("companion_database.db")?;
+        let con = db_pool::connection()?;
 
         let field = match interaction_type {
             "positive" => "positive_interactions",
@@ -1491,15 +3057,29 @@ impl Database {
     }
 
     pub fn clear_companion_attitudes(companion_id: i32) -> Result<()> {
-        let con = Connection::open("companion_database.db")?;
+        let con = db_pool::connection()?;
         con.execute(
             "DELETE FROM companion_attitudes WHERE companion_id = ?",
             params![companion_id],
         )?;
+        con.execute(
+            "DELETE FROM attitude_history WHERE companion_id = ?",
+            params![companion_id],
+        )?;
+        con.execute(
+            "DELETE FROM companion_mood WHERE companion_id = ?",
+            params![companion_id],
+        )?;
         Ok(())
     }
 
-    pub fn create_initial_user_attitude(companion_id: i32, user_id: i32, companion_persona: &str) -> Result<i32> {
+    pub fn create_initial_user_attitude(companion_id: i32, user_id: i32, traits: &[PersonalityTrait]) -> Result<i32> {
+        let initial_attitude = Database::initial_user_attitude(companion_id, user_id, traits);
+        Database::create_or_update_attitude(companion_id, user_id, "user", &initial_attitude)
+    }
+
+    /// Default attitude towards the user, shaped by the companion's personality traits
+    pub fn initial_user_attitude(companion_id: i32, user_id: i32, traits: &[PersonalityTrait]) -> CompanionAttitude {
         let base_attitude = CompanionAttitude {
             id: None,
             companion_id,
@@ -1530,100 +3110,21 @@ impl Database {
             created_at: get_current_date(),
         };
 
-        let adjusted_attitude = Database::adjust_attitude_for_persona(&base_attitude, companion_persona);
-        Database::create_or_update_attitude(companion_id, user_id, "user", &adjusted_attitude)
-    }
-
-    pub fn adjust_attitude_for_persona(base_attitude: &CompanionAttitude, persona: &str) -> CompanionAttitude {
-        let mut attitude = base_attitude.clone();
-        let persona_lower = persona.to_lowercase();
-
-        if persona_lower.contains("shy") || persona_lower.contains("introverted") {
-            attitude.curiosity -= 10.0;
-            attitude.anxiety += 15.0;
-            attitude.trust -= 10.0;
-            attitude.submissiveness += 10.0;
-        }
-
-        if persona_lower.contains("confident") || persona_lower.contains("outgoing") {
-            attitude.curiosity += 15.0;
-            attitude.anxiety -= 10.0;
-            attitude.dominance += 10.0;
-            attitude.attraction += 5.0;
-        }
-
-        if persona_lower.contains("friendly") || persona_lower.contains("warm") {
-            attitude.joy += 15.0;
-            attitude.empathy += 10.0;
-            attitude.trust += 10.0;
-            attitude.gratitude += 10.0;
-        }
-
-        if persona_lower.contains("cold") || persona_lower.contains("distant") {
-            attitude.joy -= 10.0;
-            attitude.empathy -= 15.0;
-            attitude.trust -= 15.0;
-            attitude.suspicion += 10.0;
-        }
-
-        if persona_lower.contains("flirty") || persona_lower.contains("seductive") {
-            attitude.attraction += 15.0;
-            attitude.lust += 20.0;
-            attitude.butterflies += 10.0;
-        }
-
-        if persona_lower.contains("aggressive") || persona_lower.contains("dominant") {
-            attitude.dominance += 15.0;
-            attitude.anger += 10.0;
-            attitude.submissiveness -= 10.0;
-        }
-
-        if persona_lower.contains("submissive") || persona_lower.contains("obedient") {
-            attitude.submissiveness += 15.0;
-            attitude.dominance -= 10.0;
-            attitude.respect += 10.0;
-        }
-
-        if persona_lower.contains("curious") || persona_lower.contains("inquisitive") {
-            attitude.curiosity += 20.0;
-            attitude.surprise += 10.0;
-        }
-
-        attitude.attraction = attitude.attraction.clamp(0.0, 100.0);
-        attitude.trust = attitude.trust.clamp(0.0, 100.0);
-        attitude.fear = attitude.fear.clamp(0.0, 100.0);
-        attitude.anger = attitude.anger.clamp(0.0, 100.0);
-        attitude.joy = attitude.joy.clamp(0.0, 100.0);
-        attitude.sorrow = attitude.sorrow.clamp(0.0, 100.0);
-        attitude.disgust = attitude.disgust.clamp(0.0, 100.0);
-        attitude.surprise = attitude.surprise.clamp(0.0, 100.0);
-        attitude.curiosity = attitude.curiosity.clamp(0.0, 100.0);
-        attitude.respect = attitude.respect.clamp(0.0, 100.0);
-        attitude.suspicion = attitude.suspicion.clamp(0.0, 100.0);
-        attitude.gratitude = attitude.gratitude.clamp(0.0, 100.0);
-        attitude.jealousy = attitude.jealousy.clamp(0.0, 100.0);
-        attitude.empathy = attitude.empathy.clamp(0.0, 100.0);
-        attitude.lust = attitude.lust.clamp(0.0, 100.0);
-        attitude.love = attitude.love.clamp(0.0, 100.0);
-        attitude.anxiety = attitude.anxiety.clamp(0.0, 100.0);
-        attitude.butterflies = attitude.butterflies.clamp(0.0, 100.0);
-        attitude.submissiveness = attitude.submissiveness.clamp(0.0, 100.0);
-        attitude.dominance = attitude.dominance.clamp(0.0, 100.0);
-
-        attitude
+        personality_traits::apply(&base_attitude, traits)
     }
 
     pub fn create_or_update_third_party(
         name: &str,
         initial_data: Option<ThirdPartyIndividual>,
     ) -> Result<i32> {
-        let con = Connection::open("companion_database.db")?;
+        let con = db_pool::connection()?;
         let current_time = get_current_date();
+        let companion_id = Database::active_companion_id();
 
         let existing_id: Option<i32> = con
             .query_row(
-                "SELECT id FROM third_party_individuals WHERE name = ?",
-                &[name],
+                "SELECT id FROM third_party_individuals WHERE companion_id = ? AND name = ?",
+                params![companion_id, name],
                 |row| row.get(0),
             )
             .ok();
@@ -1680,11 +3181,12 @@ impl Database {
 
             con.execute(
                 "INSERT INTO third_party_individuals (
-                    name, relationship_to_user, relationship_to_companion, occupation,
+                    companion_id, name, relationship_to_user, relationship_to_companion, occupation,
                     personality_traits, physical_description, first_mentioned, 
                     mention_count, importance_score, created_at, updated_at
-                ) VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?)",
+                ) VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?)",
                 params![
+                    companion_id,
                     data.name,
                     data.relationship_to_user
                         .as_ref()
@@ -1711,7 +3213,7 @@ impl Database {
         companion_id: i32,
         memory: &ThirdPartyMemory,
     ) -> Result<i32> {
-        let con = Connection::open("companion_database.db")?;
+        let con = db_pool::connection()?;
         let current_time = get_current_date();
 
         con.execute(
@@ -1735,7 +3237,7 @@ impl Database {
     }
 
     pub fn plan_third_party_interaction(interaction: &ThirdPartyInteraction) -> Result<i32> {
-        let con = Connection::open("companion_database.db")?;
+        let con = db_pool::connection()?;
         let current_time = get_current_date();
 
         con.execute(
@@ -1762,7 +3264,7 @@ impl Database {
         companion_id: i32,
         limit: Option<usize>,
     ) -> Result<Vec<ThirdPartyInteraction>> {
-        let con = Connection::open("companion_database.db")?;
+        let con = db_pool::connection()?;
         let query = if let Some(limit) = limit {
             format!(
                 "SELECT id, third_party_id, companion_id, interaction_type, description,
@@ -1809,8 +3311,37 @@ impl Database {
         Ok(result)
     }
 
+    /// Planned interactions of every companion, for the interaction scheduler
+    pub fn get_all_planned_interactions() -> Result<Vec<ThirdPartyInteraction>> {
+        let con = db_pool::connection()?;
+        let mut stmt = con.prepare(
+            "SELECT id, third_party_id, companion_id, interaction_type, description,
+                    planned_date, actual_date, outcome, impact_on_relationship,
+                    created_at, updated_at
+             FROM third_party_interactions
+             WHERE interaction_type = 'planned'
+             ORDER BY id ASC",
+        )?;
+        let interactions = stmt.query_map([], |row| {
+            Ok(ThirdPartyInteraction {
+                id: Some(row.get(0)?),
+                third_party_id: row.get(1)?,
+                companion_id: row.get(2)?,
+                interaction_type: row.get(3)?,
+                description: row.get(4)?,
+                planned_date: row.get(5)?,
+                actual_date: row.get(6)?,
+                outcome: row.get(7)?,
+                impact_on_relationship: row.get(8)?,
+                created_at: row.get(9)?,
+                updated_at: row.get(10)?,
+            })
+        })?;
+        interactions.collect()
+    }
+
     pub fn complete_interaction(interaction_id: i32, outcome: &str, impact: f32) -> Result<()> {
-        let con = Connection::open("companion_database.db")?;
+        let con = db_pool::connection()?;
         let current_time = get_current_date();
 
         con.execute(
@@ -1827,11 +3358,20 @@ impl Database {
         Ok(())
     }
 
+    pub fn cancel_interaction(interaction_id: i32) -> Result<()> {
+        let con = db_pool::connection()?;
+        con.execute(
+            "UPDATE third_party_interactions SET interaction_type = 'cancelled', updated_at = ? WHERE id = ?",
+            params![get_current_date(), interaction_id],
+        )?;
+        Ok(())
+    }
+
     pub fn get_interaction_history(
         companion_id: i32,
         third_party_id: i32,
     ) -> Result<Vec<ThirdPartyInteraction>> {
-        let con = Connection::open("companion_database.db")?;
+        let con = db_pool::connection()?;
         let mut stmt = con.prepare(
             "SELECT id, third_party_id, companion_id, interaction_type, description,
                     planned_date, actual_date, outcome, impact_on_relationship,
@@ -1866,16 +3406,16 @@ impl Database {
     }
 
     pub fn get_third_party_by_name(name: &str) -> Result<Option<ThirdPartyIndividual>> {
-        let con = Connection::open("companion_database.db")?;
+        let con = db_pool::connection()?;
         let mut stmt = con.prepare(
             "SELECT id, name, relationship_to_user, relationship_to_companion, occupation,
                     personality_traits, physical_description, first_mentioned, last_mentioned,
                     mention_count, importance_score, created_at, updated_at
-             FROM third_party_individuals WHERE name = ?",
+             FROM third_party_individuals WHERE companion_id = ? AND name = ?",
         )?;
 
         let individual = stmt
-            .query_row(&[name], |row| {
+            .query_row(params![Database::active_companion_id(), name], |row| {
                 Ok(ThirdPartyIndividual {
                     id: Some(row.get(0)?),
                     name: row.get(1)?,
@@ -1898,16 +3438,16 @@ impl Database {
     }
 
     pub fn get_all_third_party_individuals() -> Result<Vec<ThirdPartyIndividual>> {
-        let con = Connection::open("companion_database.db")?;
+        let con = db_pool::connection()?;
         let mut stmt = con.prepare(
             "SELECT id, name, relationship_to_user, relationship_to_companion, occupation,
                     personality_traits, physical_description, first_mentioned, last_mentioned,
                     mention_count, importance_score, created_at, updated_at
-             FROM third_party_individuals 
+             FROM third_party_individuals WHERE companion_id = ?
              ORDER BY importance_score DESC, mention_count DESC",
         )?;
 
-        let individuals = stmt.query_map([], |row| {
+        let individuals = stmt.query_map([Database::active_companion_id()], |row| {
             Ok(ThirdPartyIndividual {
                 id: Some(row.get(0)?),
                 name: row.get(1)?,
@@ -1937,7 +3477,7 @@ impl Database {
         third_party_id: i32,
         limit: Option<usize>,
     ) -> Result<Vec<ThirdPartyMemory>> {
-        let con = Connection::open("companion_database.db")?;
+        let con = db_pool::connection()?;
         let query = if let Some(limit) = limit {
             format!(
                 "SELECT id, third_party_id, companion_id, memory_type, content,
@@ -1981,7 +3521,7 @@ impl Database {
     }
 
     pub fn update_third_party_importance(third_party_id: i32, new_importance: f32) -> Result<()> {
-        let con = Connection::open("companion_database.db")?;
+        let con = db_pool::connection()?;
         let current_time = get_current_date();
 
         con.execute(
@@ -1994,28 +3534,85 @@ impl Database {
         Ok(())
     }
 
+    /// Recalculate every third party's importance from recency, frequency,
+    /// interactions and the emotional intensity of their memories
+    pub fn recalculate_third_party_importance() -> Result<usize> {
+        let con = db_pool::connection()?;
+        let mut stmt = con.prepare(
+            "SELECT t.id, t.first_mentioned, t.last_mentioned, t.mention_count, t.importance_score,
+                    (SELECT COUNT(*) FROM third_party_interactions i WHERE i.third_party_id = t.id),
+                    (SELECT AVG(ABS(m.emotional_valence)) FROM third_party_memories m WHERE m.third_party_id = t.id)
+             FROM third_party_individuals t",
+        )?;
+        let rows = stmt
+            .query_map([], |row| {
+                Ok((
+                    row.get::<_, i32>(0)?,
+                    row.get::<_, String>(1)?,
+                    row.get::<_, Option<String>>(2)?,
+                    row.get::<_, i32>(3)?,
+                    row.get::<_, f32>(4)?,
+                    row.get::<_, i32>(5)?,
+                    row.get::<_, Option<f32>>(6)?,
+                ))
+            })?
+            .collect::<Result<Vec<_>>>()?;
+
+        let now = Local::now().naive_local();
+        let mut updated = 0;
+        for (id, first_mentioned, last_mentioned, mentions, previous, interactions, intensity) in rows {
+            let days_since_mention = last_mentioned
+                .as_deref()
+                .and_then(parse_stored_date)
+                .or_else(|| parse_stored_date(&first_mentioned))
+                .map(|date| (now - date).num_hours() as f32 / 24.0)
+                .unwrap_or(0.0);
+            let importance = calculate_dynamic_importance(
+                days_since_mention,
+                mentions,
+                interactions,
+                intensity.unwrap_or(0.0),
+                previous,
+            );
+            if (importance - previous).abs() >= 0.01 {
+                Database::update_third_party_importance(id, importance)?;
+                updated += 1;
+            }
+        }
+        Ok(updated)
+    }
+
     // Attitude Change Detection System
 
     pub fn create_attitude_memories_table() -> Result<()> {
-        let con = Connection::open("companion_database.db")?;
+        let con = db_pool::connection()?;
         con.execute(
-            "CREATE TABLE IF NOT EXISTS attitude_memories (
-                id INTEGER PRIMARY KEY AUTOINCREMENT,
-                companion_id INTEGER NOT NULL,
-                target_id INTEGER NOT NULL,
-                target_type TEXT NOT NULL,
-                memory_type TEXT NOT NULL,
-                description TEXT NOT NULL,
-                priority_score REAL NOT NULL,
-                attitude_delta_json TEXT NOT NULL,
-                impact_score REAL NOT NULL,
-                message_context TEXT,
-                created_at TEXT NOT NULL,
-                FOREIGN KEY(companion_id) REFERENCES companions(id)
-            )",
+            &format!("CREATE TABLE IF NOT EXISTS attitude_memories {}", ATTITUDE_MEMORIES_COLUMNS),
             [],
         )?;
 
+        // The table used to point at a companions table that never existed, which makes
+        // SQLite refuse every write to it. Rebuilding it is the only way to change the key
+        let wrong_key: bool = con.query_row(
+            "SELECT EXISTS(SELECT 1 FROM pragma_foreign_key_list('attitude_memories') WHERE \"table\" = 'companions')",
+            [],
+            |row| row.get(0),
+        )?;
+        if wrong_key {
+            let foreign_keys = db_pool::disable_foreign_keys(&con)?;
+            let result = con.execute_batch(&format!(
+                "BEGIN;
+                 CREATE TABLE attitude_memories_new {};
+                 INSERT INTO attitude_memories_new SELECT * FROM attitude_memories;
+                 DROP TABLE attitude_memories;
+                 ALTER TABLE attitude_memories_new RENAME TO attitude_memories;
+                 COMMIT;",
+                ATTITUDE_MEMORIES_COLUMNS
+            ));
+            db_pool::restore_foreign_keys(&con, foreign_keys)?;
+            result?;
+        }
+
         // Create index for priority queries
         con.execute(
             "CREATE INDEX IF NOT EXISTS idx_attitude_memories_priority 
@@ -2042,10 +3639,33 @@ impl Database {
             let memory_type = classify_memory_type(&delta, impact_score);
             let priority_score = calculate_priority_score(&delta, impact_score, &memory_type);
 
-            let description = generate_memory_description(&memory_type, &delta, impact_score);
             let attitude_delta_json = serde_json::to_string(&delta).unwrap_or_default();
 
-            let con = Connection::open("companion_database.db")?;
+            let con = db_pool::connection()?;
+            let (companion, pronouns): (String, Option<String>) = con.query_row(
+                "SELECT name, pronouns FROM companion WHERE id = ?",
+                [companion_id],
+                |row| Ok((row.get(0)?, row.get(1)?)),
+            )?;
+            let target = if target_type == "user" {
+                Database::read_user(&con)?.name
+            } else {
+                con.query_row(
+                    "SELECT name FROM third_party_individuals WHERE id = ?",
+                    [target_id],
+                    |row| row.get(0),
+                )
+                .optional()?
+                .unwrap_or_else(|| "someone".to_string())
+            };
+            let description = generate_memory_description(
+                &memory_type,
+                &delta,
+                impact_score,
+                &companion,
+                &Pronouns::parse(&pronouns.unwrap_or_default()),
+                &target,
+            );
             let current_time = get_current_date();
 
             con.execute(
@@ -2075,7 +3695,7 @@ impl Database {
         companion_id: i32,
         limit: usize,
     ) -> Result<Vec<AttitudeMemory>> {
-        let con = Connection::open("companion_database.db")?;
+        let con = db_pool::connection()?;
         let mut stmt = con.prepare(
             "SELECT id, companion_id, target_id, target_type, memory_type, description,
                     priority_score, attitude_delta_json, impact_score, message_context, created_at
@@ -2112,92 +3732,96 @@ impl Database {
     // Automatic Person Detection System
 
     pub fn detect_new_persons_in_message(message: &str, companion_id: i32) -> Result<Vec<i32>> {
-        let detected_names = Database::extract_person_names(message);
         let mut new_person_ids = Vec::new();
+        for name in Database::find_person_names_in_message(message)? {
+            // Check if person already exists
+            if Database::get_third_party_by_name(&name)?.is_none() {
+                new_person_ids.push(Database::register_detected_person(&name, message, companion_id)?);
+            } else {
+                // Update mention count for existing person
+                Database::create_or_update_third_party(&name, None)?;
+            }
+        }
+
+        Ok(new_person_ids)
+    }
 
+    /// Names of people mentioned in a message, excluding the user
+    pub fn find_person_names_in_message(message: &str) -> Result<Vec<String>> {
         // Get user name to filter it out from third party detection
         let user_name = match Database::get_user_data() {
             Ok(user) => Some(user.name.to_lowercase()),
             Err(_) => None,
         };
 
-        for name in detected_names {
-            // Skip if this is the user's own name
-            if let Some(ref user_name) = user_name {
-                if name.to_lowercase() == *user_name {
-                    continue;
-                }
-            }
+        let detector = match Database::get_config() {
+            Ok(config) => crate::ner::detector(&config),
+            Err(_) => Box::new(crate::ner::HeuristicDetector {}),
+        };
 
-            // Check if person already exists
-            if Database::get_third_party_by_name(&name)?.is_none() {
-                // Create new third-party individual with context-based initial data
-                let initial_data = Database::analyze_context_for_person(&name, message);
-                let person_id = Database::create_or_update_third_party(&name, Some(initial_data))?;
-
-                // Initialize attitude tracking with context-based values
-                let mut initial_attitude =
-                    Database::generate_initial_attitudes(&name, message, companion_id);
-                initial_attitude.target_id = person_id;
-                Database::create_or_update_attitude(
-                    companion_id,
-                    person_id,
-                    "third_party",
-                    &initial_attitude,
-                )?;
+        Ok(detector
+            .detect(message)
+            .into_iter()
+            .filter(|name| user_name.as_ref() != Some(&name.to_lowercase()))
+            .collect())
+    }
 
-                new_person_ids.push(person_id);
+    /// Create a new third party detected in a message, along with their initial attitude and memory
+    pub fn register_detected_person(name: &str, message: &str, companion_id: i32) -> Result<i32> {
+        // Create new third-party individual with context-based initial data
+        let initial_data = Database::analyze_context_for_person(name, message);
+        let person_id = Database::create_or_update_third_party(name, Some(initial_data))?;
 
-                // Add initial memory about this person
-                let memory = ThirdPartyMemory {
-                    id: None,
-                    third_party_id: person_id,
-                    companion_id,
-                    memory_type: "fact".to_string(),
-                    content: format!("First mentioned: {}", message.trim()),
-                    importance: 0.6,
-                    emotional_valence: 0.0,
-                    created_at: get_current_date(),
-                    context_message_id: None,
-                };
-                Database::add_third_party_memory(person_id, companion_id, &memory)?;
-            } else {
-                // Update mention count for existing person
-                Database::create_or_update_third_party(&name, None)?;
-            }
-        }
+        // Initialize attitude tracking with context-based values
+        let mut initial_attitude = Database::generate_initial_attitudes(name, message, companion_id);
+        initial_attitude.target_id = person_id;
+        Database::create_or_update_attitude(companion_id, person_id, "third_party", &initial_attitude)?;
 
-        Ok(new_person_ids)
+        // Add initial memory about this person
+        let memory = ThirdPartyMemory {
+            id: None,
+            third_party_id: person_id,
+            companion_id,
+            memory_type: "fact".to_string(),
+            content: format!("First mentioned: {}", message.trim()),
+            importance: 0.6,
+            emotional_valence: 0.0,
+            created_at: get_current_date(),
+            context_message_id: None,
+        };
+        Database::add_third_party_memory(person_id, companion_id, &memory)?;
+
+        Ok(person_id)
     }
 
     pub fn cleanup_duplicate_third_parties() -> Result<i32> {
-        let con = Connection::open("companion_database.db")?;
+        let con = db_pool::connection()?;
         let mut cleaned_count = 0;
 
-        // Find all duplicate names (case-insensitive)
+        // Find all duplicate names (case-insensitive), each companion keeps its own people
         let mut stmt = con.prepare("
-            SELECT LOWER(name) as lower_name, COUNT(*) as count 
+            SELECT companion_id, LOWER(name) as lower_name, COUNT(*) as count 
             FROM third_party_individuals 
-            GROUP BY LOWER(name) 
+            GROUP BY companion_id, LOWER(name) 
             HAVING COUNT(*) > 1
         ")?;
 
-        let duplicate_names: Vec<String> = stmt.query_map([], |row| {
-            Ok(row.get::<_, String>(0)?)
+        let duplicate_names: Vec<(i32, String)> = stmt.query_map([], |row| {
+            Ok((row.get::<_, i32>(0)?, row.get::<_, String>(1)?))
         })?.collect::<std::result::Result<Vec<_>, _>>()?;
 
-        for lower_name in duplicate_names {
+        for (companion_id, lower_name) in duplicate_names {
             // Get all instances of this name
             let mut instances_stmt = con.prepare("
                 SELECT id, name, relationship_to_user, relationship_to_companion, occupation,
                        personality_traits, physical_description, first_mentioned, last_mentioned,
                        mention_count, importance_score, created_at, updated_at
                 FROM third_party_individuals 
-                WHERE LOWER(name) = ? 
+                WHERE companion_id = ? AND LOWER(name) = ? 
                 ORDER BY created_at ASC
             ")?;
 
-            let instances: Vec<ThirdPartyIndividual> = instances_stmt.query_map([&lower_name], |row| {
+            let instances: Vec<ThirdPartyIndividual> = instances_stmt.query_map(params![companion_id, lower_name], |row| {
                 Ok(ThirdPartyIndividual {
                     id: Some(row.get(0)?),
                     name: row.get(1)?,
@@ -2215,66 +3839,11 @@ impl Database {
                 })
             })?.collect::<std::result::Result<Vec<_>, _>>()?;
 
-            if instances.len() > 1 {
-                // Keep the first instance, merge data from others
-                let keep_id = instances[0].id.unwrap();
-                let mut total_mentions = 0;
-                let mut max_importance = 0.0;
-                let mut earliest_first_mentioned = instances[0].first_mentioned.clone();
-                let mut latest_last_mentioned = instances[0].last_mentioned.clone();
-
-                // Collect data from all instances
-                for instance in &instances {
-                    total_mentions += instance.mention_count;
-                    if instance.importance_score > max_importance {
-                        max_importance = instance.importance_score;
-                    }
-                    if instance.first_mentioned < earliest_first_mentioned {
-                        earliest_first_mentioned = instance.first_mentioned.clone();
-                    }
-                    if let Some(ref last) = instance.last_mentioned {
-                        if latest_last_mentioned.is_none() || last > latest_last_mentioned.as_ref().unwrap() {
-                            latest_last_mentioned = Some(last.clone());
-                        }
-                    }
-                }
-
-                // Update the kept instance with merged data
-                con.execute("
-                    UPDATE third_party_individuals SET 
-                        mention_count = ?,
-                        importance_score = ?,
-                        first_mentioned = ?,
-                        last_mentioned = ?,
-                        updated_at = ?
-                    WHERE id = ?
-                ", params![
-                    total_mentions,
-                    max_importance,
-                    earliest_first_mentioned,
-                    latest_last_mentioned,
-                    get_current_date(),
-                    keep_id
-                ])?;
-
-                // Update attitudes to point to the kept instance
-                for instance in &instances[1..] {
-                    if let Some(delete_id) = instance.id {
-                        con.execute("
-                            UPDATE companion_attitudes SET target_id = ? 
-                            WHERE target_id = ? AND target_type = 'third_party'
-                        ", params![keep_id, delete_id])?;
-
-                        // Update memories to point to the kept instance  
-                        con.execute("
-                            UPDATE third_party_memories SET third_party_id = ?
-                            WHERE third_party_id = ?
-                        ", params![keep_id, delete_id])?;
-
-                        // Delete the duplicate instance
-                        con.execute("DELETE FROM third_party_individuals WHERE id = ?", [delete_id])?;
-                        cleaned_count += 1;
-                    }
+            // Keep the first instance, the others are merged into it
+            if let Some(keep_id) = instances.first().and_then(|instance| instance.id) {
+                for delete_id in instances[1..].iter().filter_map(|instance| instance.id) {
+                    Database::merge_third_parties(keep_id, delete_id)?;
+                    cleaned_count += 1;
                 }
             }
         }
@@ -2282,8 +3851,171 @@ impl Database {
         Ok(cleaned_count)
     }
 
+    fn third_party_companion(con: &Connection, id: i32) -> Result<Option<i32>> {
+        con.query_row(
+            "SELECT companion_id FROM third_party_individuals WHERE id = ?",
+            [id],
+            |row| row.get(0),
+        )
+        .optional()
+    }
+
+    /// Remove a person and everything the companion remembers or feels about them,
+    /// None if there is no such person
+    pub fn delete_third_party(id: i32) -> Result<Option<PersonRows>> {
+        let mut con = db_pool::connection()?;
+        if Database::third_party_companion(&con, id)?.is_none() {
+            return Ok(None);
+        }
+        let tx = con.transaction()?;
+        tx.execute(
+            "DELETE FROM attitude_metadata WHERE attitude_id IN
+                (SELECT id FROM companion_attitudes WHERE target_id = ? AND target_type = 'third_party')",
+            [id],
+        )?;
+        let deleted = PersonRows {
+            attitudes: tx.execute(
+                "DELETE FROM companion_attitudes WHERE target_id = ? AND target_type = 'third_party'",
+                [id],
+            )?,
+            attitude_history: tx.execute(
+                "DELETE FROM attitude_history WHERE target_id = ? AND target_type = 'third_party'",
+                [id],
+            )?,
+            attitude_memories: tx.execute(
+                "DELETE FROM attitude_memories WHERE target_id = ? AND target_type = 'third_party'",
+                [id],
+            )?,
+            memories: tx.execute("DELETE FROM third_party_memories WHERE third_party_id = ?", [id])?,
+            interactions: tx.execute("DELETE FROM third_party_interactions WHERE third_party_id = ?", [id])?,
+            relationships: tx.execute(
+                "DELETE FROM third_party_relationships WHERE from_party_id = ?1 OR to_party_id = ?1",
+                [id],
+            )?,
+        };
+        tx.execute("DELETE FROM third_party_individuals WHERE id = ?", [id])?;
+        tx.commit()?;
+        Ok(Some(deleted))
+    }
+
+    /// Fold `merged_id` into `survivor_id`, the survivor keeps its name and takes over the other's
+    /// attitudes, memories, interactions and relationships
+    ///
+    /// Where both have an attitude, or a relationship with the same person, the survivor's is kept
+    /// and the other one dropped. Fails with InvalidParameterName for a person merged with itself or
+    /// with a person of another companion, and with QueryReturnedNoRows for an unknown person.
+    pub fn merge_third_parties(survivor_id: i32, merged_id: i32) -> Result<PersonMerge> {
+        if survivor_id == merged_id {
+            return Err(Error::InvalidParameterName("A person can't be merged with itself".to_string()));
+        }
+        let mut con = db_pool::connection()?;
+        let companions = (
+            Database::third_party_companion(&con, survivor_id)?,
+            Database::third_party_companion(&con, merged_id)?,
+        );
+        match companions {
+            (Some(a), Some(b)) if a == b => {}
+            (Some(_), Some(_)) => {
+                return Err(Error::InvalidParameterName(
+                    "Only persons of the same companion can be merged".to_string(),
+                ))
+            }
+            _ => return Err(Error::QueryReturnedNoRows),
+        }
+        let survivor = Database::get_third_party_by_id(survivor_id)?.ok_or(Error::QueryReturnedNoRows)?;
+        let merged = Database::get_third_party_by_id(merged_id)?.ok_or(Error::QueryReturnedNoRows)?;
+        let survivor = merge_third_party_details(survivor, &merged);
+
+        let tx = con.transaction()?;
+        tx.execute(
+            "UPDATE third_party_individuals SET
+                relationship_to_user = ?, relationship_to_companion = ?, occupation = ?,
+                personality_traits = ?, physical_description = ?, first_mentioned = ?,
+                last_mentioned = ?, mention_count = ?, importance_score = ?, updated_at = ?
+             WHERE id = ?",
+            params![
+                survivor.relationship_to_user,
+                survivor.relationship_to_companion,
+                survivor.occupation,
+                survivor.personality_traits,
+                survivor.physical_description,
+                survivor.first_mentioned,
+                survivor.last_mentioned,
+                survivor.mention_count,
+                survivor.importance_score,
+                survivor.updated_at,
+                survivor_id
+            ],
+        )?;
+        let ids = params![survivor_id, merged_id];
+        // Attitudes are unique per companion and target, the survivor's own one wins
+        let attitudes = tx.execute(
+            "UPDATE OR IGNORE companion_attitudes SET target_id = ?1 WHERE target_id = ?2 AND target_type = 'third_party'",
+            ids,
+        )?;
+        tx.execute(
+            "DELETE FROM attitude_metadata WHERE attitude_id IN
+                (SELECT id FROM companion_attitudes WHERE target_id = ? AND target_type = 'third_party')",
+            [merged_id],
+        )?;
+        let dropped_attitudes = tx.execute(
+            "DELETE FROM companion_attitudes WHERE target_id = ? AND target_type = 'third_party'",
+            [merged_id],
+        )?;
+        // A relationship between the two would point from the survivor to itself
+        let mut dropped_relationships = tx.execute(
+            "DELETE FROM third_party_relationships
+             WHERE (from_party_id = ?1 AND to_party_id = ?2) OR (from_party_id = ?2 AND to_party_id = ?1)",
+            ids,
+        )?;
+        let relationships = tx.execute(
+            "UPDATE OR IGNORE third_party_relationships SET from_party_id = ?1 WHERE from_party_id = ?2",
+            ids,
+        )? + tx.execute(
+            "UPDATE OR IGNORE third_party_relationships SET to_party_id = ?1 WHERE to_party_id = ?2",
+            ids,
+        )?;
+        dropped_relationships += tx.execute(
+            "DELETE FROM third_party_relationships WHERE from_party_id = ?1 OR to_party_id = ?1",
+            [merged_id],
+        )?;
+        let moved = PersonRows {
+            attitudes,
+            attitude_history: tx.execute(
+                "UPDATE attitude_history SET target_id = ?1 WHERE target_id = ?2 AND target_type = 'third_party'",
+                ids,
+            )?,
+            attitude_memories: tx.execute(
+                "UPDATE attitude_memories SET target_id = ?1 WHERE target_id = ?2 AND target_type = 'third_party'",
+                ids,
+            )?,
+            memories: tx.execute(
+                "UPDATE third_party_memories SET third_party_id = ?1 WHERE third_party_id = ?2",
+                ids,
+            )?,
+            interactions: tx.execute(
+                "UPDATE third_party_interactions SET third_party_id = ?1 WHERE third_party_id = ?2",
+                ids,
+            )?,
+            relationships,
+        };
+        tx.execute("DELETE FROM third_party_individuals WHERE id = ?", [merged_id])?;
+        tx.commit()?;
+        Ok(PersonMerge {
+            survivor,
+            merged_id,
+            merged_name: merged.name,
+            moved,
+            dropped: PersonRows {
+                attitudes: dropped_attitudes,
+                relationships: dropped_relationships,
+                ..PersonRows::default()
+            },
+        })
+    }
+
     pub fn cleanup_invalid_third_parties() -> Result<i32> {
-        let con = Connection::open("companion_database.db")?;
+        let con = db_pool::connection()?;
         let mut cleaned_count = 0;
         
         // List of invalid names that should be removed
@@ -2339,7 +4071,7 @@ impl Database {
                 )?;
                 
                 cleaned_count += 1;
-                println!("Removed invalid third party: {} (id: {})", invalid_name, id);
+                tracing::info!("Removed invalid third party: {} (id: {})", invalid_name, id);
             }
         }
         
@@ -2375,20 +4107,20 @@ impl Database {
                 )?;
                 
                 cleaned_count += 1;
-                println!("Removed invalid third party: {} (id: {})", name, id);
+                tracing::info!("Removed invalid third party: {} (id: {})", name, id);
             }
         }
         
         if cleaned_count > 0 {
-            println!("Cleaned up {} invalid third party entries", cleaned_count);
+            tracing::info!("Cleaned up {} invalid third party entries", cleaned_count);
         } else {
-            println!("No invalid third party entries found");
+            tracing::debug!("No invalid third party entries found");
         }
         
         Ok(cleaned_count)
     }
 
-    fn extract_person_names(text: &str) -> Vec<String> {
+    pub fn extract_person_names(text: &str) -> Vec<String> {
         let mut names = Vec::new();
         
         // Keep original text for proper name detection (with capitalization)
@@ -2848,16 +4580,10 @@ impl Database {
         importance.min(1.0)
     }
 
-    fn generate_initial_attitudes(
-        name: &str,
-        message: &str,
-        companion_id: i32,
-    ) -> CompanionAttitude {
+    /// Where the companion stands toward someone it knows nothing about yet
+    pub fn neutral_third_party_attitude(companion_id: i32) -> CompanionAttitude {
         let current_time = get_current_date();
-        let text = message.to_lowercase();
-
-        // Base neutral attitudes
-        let mut attitude = CompanionAttitude {
+        CompanionAttitude {
             id: None,
             companion_id,
             target_id: 0, // Will be set by caller
@@ -2885,7 +4611,16 @@ impl Database {
             relationship_score: None,
             last_updated: current_time.clone(),
             created_at: current_time,
-        };
+        }
+    }
+
+    fn generate_initial_attitudes(
+        name: &str,
+        message: &str,
+        companion_id: i32,
+    ) -> CompanionAttitude {
+        let text = message.to_lowercase();
+        let mut attitude = Database::neutral_third_party_attitude(companion_id);
 
         // Adjust based on relationship context
         if let Some(relationship) = Database::extract_relationship_to_user(name, message) {
@@ -2957,7 +4692,7 @@ impl Database {
     // Companion Interaction Tracking System
 
     pub fn generate_interaction_outcome(interaction_id: i32) -> Result<String> {
-        let con = Connection::open("companion_database.db")?;
+        let con = db_pool::connection()?;
 
         // Get the interaction details
         let interaction: ThirdPartyInteraction = con.query_row(
@@ -3204,7 +4939,7 @@ impl Database {
     }
 
     pub fn get_third_party_by_id(id: i32) -> Result<Option<ThirdPartyIndividual>> {
-        let con = Connection::open("companion_database.db")?;
+        let con = db_pool::connection()?;
         let mut stmt = con.prepare(
             "SELECT id, name, relationship_to_user, relationship_to_companion, occupation,
                     personality_traits, physical_description, first_mentioned, last_mentioned,
@@ -3235,6 +4970,93 @@ impl Database {
         Ok(individual)
     }
 
+    fn relationship_from_row(row: &rusqlite::Row) -> Result<ThirdPartyRelationship> {
+        Ok(ThirdPartyRelationship {
+            id: Some(row.get(0)?),
+            from_party_id: row.get(1)?,
+            to_party_id: row.get(2)?,
+            relationship_type: row.get(3)?,
+            strength: row.get(4)?,
+            description: row.get(5)?,
+            created_at: row.get(6)?,
+            updated_at: row.get(7)?,
+        })
+    }
+
+    /// Relationships between the third parties a companion knows about
+    pub fn get_third_party_relationships(companion_id: i32) -> Result<Vec<ThirdPartyRelationship>> {
+        let con = db_pool::connection()?;
+        let mut stmt = con.prepare(
+            "SELECT r.id, r.from_party_id, r.to_party_id, r.relationship_type, r.strength,
+                    r.description, r.created_at, r.updated_at
+             FROM third_party_relationships r
+             JOIN third_party_individuals p ON p.id = r.from_party_id
+             WHERE p.companion_id = ?
+             ORDER BY r.id",
+        )?;
+        let rows = stmt.query_map([companion_id], Database::relationship_from_row)?;
+        rows.collect()
+    }
+
+    pub fn get_third_party_relationship(id: i32) -> Result<Option<ThirdPartyRelationship>> {
+        let con = db_pool::connection()?;
+        con.query_row(
+            "SELECT id, from_party_id, to_party_id, relationship_type, strength,
+                    description, created_at, updated_at
+             FROM third_party_relationships WHERE id = ?",
+            [id],
+            Database::relationship_from_row,
+        )
+        .optional()
+    }
+
+    pub fn add_third_party_relationship(relationship: &ThirdPartyRelationshipModify) -> Result<i32> {
+        let con = db_pool::connection()?;
+        con.execute(
+            "INSERT INTO third_party_relationships (
+                from_party_id, to_party_id, relationship_type, strength, description, created_at, updated_at
+            ) VALUES (?, ?, ?, ?, ?, ?, ?)",
+            params![
+                relationship.from_party_id,
+                relationship.to_party_id,
+                relationship.relationship_type,
+                relationship.strength,
+                relationship.description,
+                get_current_date(),
+                get_current_date()
+            ],
+        )?;
+        Ok(con.last_insert_rowid() as i32)
+    }
+
+    pub fn edit_third_party_relationship(
+        id: i32,
+        relationship: &ThirdPartyRelationshipModify,
+    ) -> Result<bool> {
+        let con = db_pool::connection()?;
+        let changed = con.execute(
+            "UPDATE third_party_relationships SET from_party_id = ?, to_party_id = ?, relationship_type = ?,
+                strength = ?, description = ?, updated_at = ?
+             WHERE id = ?",
+            params![
+                relationship.from_party_id,
+                relationship.to_party_id,
+                relationship.relationship_type,
+                relationship.strength,
+                relationship.description,
+                get_current_date(),
+                id
+            ],
+        )?;
+        Ok(changed > 0)
+    }
+
+    pub fn delete_third_party_relationship(id: i32) -> Result<bool> {
+        let con = db_pool::connection()?;
+        let changed = con.execute("DELETE FROM third_party_relationships WHERE id = ?", [id])?;
+        Ok(changed > 0)
+    }
+
     pub fn detect_interaction_request(
         message: &str,
         companion_id: i32,
@@ -3398,7 +5220,7 @@ impl Database {
     }
 
     pub fn get_interaction_by_id(id: i32) -> Result<Option<ThirdPartyInteraction>> {
-        let con = Connection::open("companion_database.db")?;
+        let con = db_pool::connection()?;
         let mut stmt = con.prepare(
             "SELECT id, third_party_id, companion_id, interaction_type, description,
                     planned_date, actual_date, outcome, impact_on_relationship,
@@ -3437,6 +5259,58 @@ impl Database {
         let mut has_max_system_ram = false;
         let mut has_context_strategy = false;
         let mut has_ram_safety_margin = false;
+        let mut has_memory_auto_approve = false;
+        let mut has_daily_recap_enabled = false;
+        let mut has_daily_recap_time = false;
+        let mut has_maintenance_window = false;
+        let mut has_example_dialogue_budget_percent = false;
+        let mut has_person_detector = false;
+        let mut has_proactive_interaction_messages = false;
+        let mut has_memory_retrieval = false;
+        let mut has_embedding_api_url = false;
+        let mut has_embedding_model = false;
+        let mut has_stt_api_url = false;
+        let mut has_stt_model = false;
+        let mut has_lorebook_token_budget = false;
+        let mut has_proactive_messages_enabled = false;
+        let mut has_proactive_idle_thresholds = false;
+        let mut has_proactive_quiet_hours = false;
+        let mut has_temperature = false;
+        let mut has_top_p = false;
+        let mut has_top_k = false;
+        let mut has_repetition_penalty = false;
+        let mut has_stop_sequences = false;
+        let mut has_llm_api_url = false;
+        let mut has_llm_api_model = false;
+        let mut has_llm_api_key = false;
+        let mut has_max_concurrent_generations = false;
+        let mut has_prompt_rate_limit = false;
+        let mut has_min_p = false;
+        let mut has_seed = false;
+        let mut has_ner_api_url = false;
+        let mut has_ner_min_confidence = false;
+        let mut has_llm_api_backend = false;
+        let mut has_attitude_inference = false;
+        let mut has_attitude_sensitivity = false;
+        let mut has_tts_api_url = false;
+        let mut has_tts_model = false;
+        let mut has_tts_voice = false;
+        let mut has_daily_mood_roll = false;
+        let mut has_vision_api_url = false;
+        let mut has_vision_model = false;
+        let mut has_image_gen_api_url = false;
+        let mut has_image_gen_model = false;
+        let mut has_session_rate_limit = false;
+        let mut has_rate_limit_persist = false;
+        let mut has_proactive_daily_limit = false;
+        let mut has_auto_prompt_template = false;
+        let mut has_custom_prompt_template = false;
+        let mut has_attitude_decay_enabled = false;
+        let mut has_attitude_decay_multiplier = false;
+        let mut has_active_companion = false;
+        let mut has_active_user = false;
+        let mut has_api_key_hash = false;
+        let mut has_password_hash = false;
 
         // Check existing columns
         let mut stmt = con.prepare("PRAGMA table_info(config)")?;
@@ -3456,6 +5330,58 @@ impl Database {
                 "max_system_ram_usage_gb" => has_max_system_ram = true,
                 "context_expansion_strategy" => has_context_strategy = true,
                 "ram_safety_margin_gb" => has_ram_safety_margin = true,
+                "memory_auto_approve" => has_memory_auto_approve = true,
+                "daily_recap_enabled" => has_daily_recap_enabled = true,
+                "daily_recap_time" => has_daily_recap_time = true,
+                "maintenance_window" => has_maintenance_window = true,
+                "example_dialogue_budget_percent" => has_example_dialogue_budget_percent = true,
+                "person_detector" => has_person_detector = true,
+                "proactive_interaction_messages" => has_proactive_interaction_messages = true,
+                "memory_retrieval" => has_memory_retrieval = true,
+                "embedding_api_url" => has_embedding_api_url = true,
+                "embedding_model" => has_embedding_model = true,
+                "stt_api_url" => has_stt_api_url = true,
+                "stt_model" => has_stt_model = true,
+                "lorebook_token_budget" => has_lorebook_token_budget = true,
+                "proactive_messages_enabled" => has_proactive_messages_enabled = true,
+                "proactive_idle_thresholds" => has_proactive_idle_thresholds = true,
+                "proactive_quiet_hours" => has_proactive_quiet_hours = true,
+                "temperature" => has_temperature = true,
+                "top_p" => has_top_p = true,
+                "top_k" => has_top_k = true,
+                "repetition_penalty" => has_repetition_penalty = true,
+                "stop_sequences" => has_stop_sequences = true,
+                "llm_api_url" => has_llm_api_url = true,
+                "llm_api_model" => has_llm_api_model = true,
+                "llm_api_key" => has_llm_api_key = true,
+                "max_concurrent_generations" => has_max_concurrent_generations = true,
+                "prompt_rate_limit" => has_prompt_rate_limit = true,
+                "min_p" => has_min_p = true,
+                "seed" => has_seed = true,
+                "ner_api_url" => has_ner_api_url = true,
+                "ner_min_confidence" => has_ner_min_confidence = true,
+                "llm_api_backend" => has_llm_api_backend = true,
+                "attitude_inference" => has_attitude_inference = true,
+                "attitude_sensitivity" => has_attitude_sensitivity = true,
+                "tts_api_url" => has_tts_api_url = true,
+                "tts_model" => has_tts_model = true,
+                "tts_voice" => has_tts_voice = true,
+                "daily_mood_roll" => has_daily_mood_roll = true,
+                "vision_api_url" => has_vision_api_url = true,
+                "vision_model" => has_vision_model = true,
+                "image_gen_api_url" => has_image_gen_api_url = true,
+                "image_gen_model" => has_image_gen_model = true,
+                "session_rate_limit" => has_session_rate_limit = true,
+                "rate_limit_persist" => has_rate_limit_persist = true,
+                "proactive_daily_limit" => has_proactive_daily_limit = true,
+                "auto_prompt_template" => has_auto_prompt_template = true,
+                "custom_prompt_template" => has_custom_prompt_template = true,
+                "attitude_decay_enabled" => has_attitude_decay_enabled = true,
+                "attitude_decay_multiplier" => has_attitude_decay_multiplier = true,
+                "active_companion_id" => has_active_companion = true,
+                "active_user_id" => has_active_user = true,
+                "api_key_hash" => has_api_key_hash = true,
+                "password_hash" => has_password_hash = true,
                 _ => {}
             }
         }
@@ -3509,6 +5435,249 @@ impl Database {
                 [],
             )?;
         }
+        if !has_memory_auto_approve {
+            con.execute(
+                "ALTER TABLE config ADD COLUMN memory_auto_approve BOOLEAN DEFAULT true",
+                [],
+            )?;
+        }
+        if !has_daily_recap_enabled {
+            con.execute(
+                "ALTER TABLE config ADD COLUMN daily_recap_enabled BOOLEAN DEFAULT false",
+                [],
+            )?;
+        }
+        if !has_daily_recap_time {
+            con.execute(
+                "ALTER TABLE config ADD COLUMN daily_recap_time TEXT DEFAULT '21:00'",
+                [],
+            )?;
+        }
+        if !has_maintenance_window {
+            con.execute(
+                "ALTER TABLE config ADD COLUMN maintenance_window TEXT DEFAULT '03:00-05:00'",
+                [],
+            )?;
+        }
+        if !has_example_dialogue_budget_percent {
+            con.execute(
+                "ALTER TABLE config ADD COLUMN example_dialogue_budget_percent INTEGER DEFAULT 50",
+                [],
+            )?;
+        }
+        if !has_person_detector {
+            con.execute(
+                "ALTER TABLE config ADD COLUMN person_detector TEXT DEFAULT 'heuristic'",
+                [],
+            )?;
+        }
+        if !has_proactive_interaction_messages {
+            con.execute(
+                "ALTER TABLE config ADD COLUMN proactive_interaction_messages BOOLEAN DEFAULT true",
+                [],
+            )?;
+        }
+        if !has_memory_retrieval {
+            con.execute(
+                "ALTER TABLE config ADD COLUMN memory_retrieval TEXT DEFAULT 'keyword'",
+                [],
+            )?;
+        }
+        if !has_embedding_api_url {
+            con.execute(
+                "ALTER TABLE config ADD COLUMN embedding_api_url TEXT DEFAULT ''",
+                [],
+            )?;
+        }
+        if !has_embedding_model {
+            con.execute(
+                "ALTER TABLE config ADD COLUMN embedding_model TEXT DEFAULT ''",
+                [],
+            )?;
+        }
+        if !has_stt_api_url {
+            con.execute(
+                "ALTER TABLE config ADD COLUMN stt_api_url TEXT DEFAULT ''",
+                [],
+            )?;
+        }
+        if !has_stt_model {
+            con.execute(
+                "ALTER TABLE config ADD COLUMN stt_model TEXT DEFAULT ''",
+                [],
+            )?;
+        }
+        if !has_lorebook_token_budget {
+            con.execute(
+                "ALTER TABLE config ADD COLUMN lorebook_token_budget INTEGER DEFAULT 512",
+                [],
+            )?;
+        }
+        if !has_proactive_messages_enabled {
+            con.execute(
+                "ALTER TABLE config ADD COLUMN proactive_messages_enabled BOOLEAN DEFAULT false",
+                [],
+            )?;
+        }
+        if !has_proactive_idle_thresholds {
+            con.execute(
+                "ALTER TABLE config ADD COLUMN proactive_idle_thresholds TEXT DEFAULT '240,1440'",
+                [],
+            )?;
+        }
+        if !has_proactive_quiet_hours {
+            con.execute(
+                "ALTER TABLE config ADD COLUMN proactive_quiet_hours TEXT DEFAULT '22:00-08:00'",
+                [],
+            )?;
+        }
+        if !has_temperature {
+            con.execute(
+                "ALTER TABLE config ADD COLUMN temperature REAL DEFAULT 0.8",
+                [],
+            )?;
+        }
+        if !has_top_p {
+            con.execute(
+                "ALTER TABLE config ADD COLUMN top_p REAL DEFAULT 0.95",
+                [],
+            )?;
+        }
+        if !has_top_k {
+            con.execute(
+                "ALTER TABLE config ADD COLUMN top_k INTEGER DEFAULT 40",
+                [],
+            )?;
+        }
+        if !has_repetition_penalty {
+            con.execute(
+                "ALTER TABLE config ADD COLUMN repetition_penalty REAL DEFAULT 1.3",
+                [],
+            )?;
+        }
+        if !has_stop_sequences {
+            con.execute(
+                "ALTER TABLE config ADD COLUMN stop_sequences TEXT DEFAULT '[]'",
+                [],
+            )?;
+        }
+        if !has_llm_api_url {
+            con.execute("ALTER TABLE config ADD COLUMN llm_api_url TEXT DEFAULT ''", [])?;
+        }
+        if !has_llm_api_model {
+            con.execute("ALTER TABLE config ADD COLUMN llm_api_model TEXT DEFAULT ''", [])?;
+        }
+        if !has_llm_api_key {
+            con.execute("ALTER TABLE config ADD COLUMN llm_api_key TEXT DEFAULT ''", [])?;
+        }
+        if !has_max_concurrent_generations {
+            con.execute(
+                "ALTER TABLE config ADD COLUMN max_concurrent_generations INTEGER DEFAULT 1",
+                [],
+            )?;
+        }
+        if !has_prompt_rate_limit {
+            con.execute("ALTER TABLE config ADD COLUMN prompt_rate_limit INTEGER DEFAULT 30", [])?;
+        }
+        if !has_min_p {
+            con.execute("ALTER TABLE config ADD COLUMN min_p REAL DEFAULT 0", [])?;
+        }
+        if !has_seed {
+            con.execute("ALTER TABLE config ADD COLUMN seed INTEGER", [])?;
+        }
+        if !has_ner_api_url {
+            con.execute("ALTER TABLE config ADD COLUMN ner_api_url TEXT DEFAULT ''", [])?;
+        }
+        if !has_ner_min_confidence {
+            con.execute("ALTER TABLE config ADD COLUMN ner_min_confidence REAL DEFAULT 0.8", [])?;
+        }
+        if !has_llm_api_backend {
+            con.execute("ALTER TABLE config ADD COLUMN llm_api_backend TEXT DEFAULT 'openai'", [])?;
+        }
+        if !has_attitude_inference {
+            con.execute("ALTER TABLE config ADD COLUMN attitude_inference TEXT DEFAULT 'off'", [])?;
+        }
+        if !has_attitude_sensitivity {
+            con.execute("ALTER TABLE config ADD COLUMN attitude_sensitivity TEXT DEFAULT '{}'", [])?;
+        }
+        if !has_tts_api_url {
+            con.execute("ALTER TABLE config ADD COLUMN tts_api_url TEXT DEFAULT ''", [])?;
+        }
+        if !has_tts_model {
+            con.execute("ALTER TABLE config ADD COLUMN tts_model TEXT DEFAULT ''", [])?;
+        }
+        if !has_tts_voice {
+            con.execute("ALTER TABLE config ADD COLUMN tts_voice TEXT DEFAULT ''", [])?;
+        }
+        if !has_daily_mood_roll {
+            con.execute("ALTER TABLE config ADD COLUMN daily_mood_roll BOOLEAN DEFAULT false", [])?;
+        }
+        if !has_vision_api_url {
+            con.execute("ALTER TABLE config ADD COLUMN vision_api_url TEXT DEFAULT ''", [])?;
+        }
+        if !has_vision_model {
+            con.execute("ALTER TABLE config ADD COLUMN vision_model TEXT DEFAULT ''", [])?;
+        }
+        if !has_image_gen_api_url {
+            con.execute("ALTER TABLE config ADD COLUMN image_gen_api_url TEXT DEFAULT ''", [])?;
+        }
+        if !has_image_gen_model {
+            con.execute("ALTER TABLE config ADD COLUMN image_gen_model TEXT DEFAULT ''", [])?;
+        }
+        if !has_session_rate_limit {
+            con.execute("ALTER TABLE config ADD COLUMN session_rate_limit INTEGER DEFAULT 0", [])?;
+        }
+        if !has_rate_limit_persist {
+            con.execute("ALTER TABLE config ADD COLUMN rate_limit_persist BOOLEAN DEFAULT false", [])?;
+        }
+        if !has_proactive_daily_limit {
+            con.execute("ALTER TABLE config ADD COLUMN proactive_daily_limit INTEGER DEFAULT 0", [])?;
+        }
+        if !has_auto_prompt_template {
+            con.execute("ALTER TABLE config ADD COLUMN auto_prompt_template BOOLEAN DEFAULT true", [])?;
+        }
+        if !has_custom_prompt_template {
+            con.execute(
+                "ALTER TABLE config ADD COLUMN custom_prompt_template TEXT DEFAULT ''",
+                [],
+            )?;
+        }
+        if !has_attitude_decay_enabled {
+            con.execute(
+                "ALTER TABLE config ADD COLUMN attitude_decay_enabled BOOLEAN DEFAULT true",
+                [],
+            )?;
+        }
+        if !has_attitude_decay_multiplier {
+            con.execute(
+                "ALTER TABLE config ADD COLUMN attitude_decay_multiplier REAL DEFAULT 1.0",
+                [],
+            )?;
+        }
+        if !has_active_companion {
+            con.execute(
+                "ALTER TABLE config ADD COLUMN active_companion_id INTEGER DEFAULT 1",
+                [],
+            )?;
+        }
+        if !has_active_user {
+            con.execute(
+                "ALTER TABLE config ADD COLUMN active_user_id INTEGER DEFAULT 1",
+                [],
+            )?;
+        }
+        if !has_api_key_hash {
+            con.execute(
+                "ALTER TABLE config ADD COLUMN api_key_hash TEXT DEFAULT ''",
+                [],
+            )?;
+        }
+        if !has_password_hash {
+            con.execute(
+                "ALTER TABLE config ADD COLUMN password_hash TEXT DEFAULT ''",
+                [],
+            )?;
+        }
 
         Ok(())
     }
@@ -3572,6 +5741,73 @@ impl Database {
         Ok(())
     }
 
+    pub fn has_column(con: &Connection, table: &str, column: &str) -> Result<bool> {
+        let mut stmt = con.prepare(&format!("PRAGMA table_info({})", table))?;
+        let columns = stmt.query_map([], |row| row.get::<_, String>(1))?;
+        for name in columns {
+            if name? == column {
+                return Ok(true);
+            }
+        }
+        Ok(false)
+    }
+
+    /// Existing single-companion data belongs to the first companion
+    pub fn migrate_multi_companion(con: &Connection) -> Result<()> {
+        if !Database::has_column(con, "messages", "companion_id")? {
+            con.execute("ALTER TABLE messages ADD COLUMN companion_id INTEGER DEFAULT 1", [])?;
+        }
+        // Everything written before there were several users came from the first one
+        if !Database::has_column(con, "messages", "author_id")? {
+            con.execute("ALTER TABLE messages ADD COLUMN author_id INTEGER", [])?;
+            con.execute(
+                "UPDATE messages SET author_id = (SELECT MIN(id) FROM user) WHERE NOT ai",
+                [],
+            )?;
+        }
+
+        // The old table had a global UNIQUE(name), SQLite can only change constraints by rebuilding it
+        if !Database::has_column(con, "third_party_individuals", "companion_id")? {
+            let foreign_keys = db_pool::disable_foreign_keys(con)?;
+            let result = con.execute_batch(&format!(
+                "BEGIN;
+                 CREATE TABLE third_party_individuals_new {};
+                 INSERT INTO third_party_individuals_new (
+                     id, name, relationship_to_user, relationship_to_companion, occupation,
+                     personality_traits, physical_description, first_mentioned, last_mentioned,
+                     mention_count, importance_score, created_at, updated_at
+                 )
+                 SELECT id, name, relationship_to_user, relationship_to_companion, occupation,
+                     personality_traits, physical_description, first_mentioned, last_mentioned,
+                     mention_count, importance_score, created_at, updated_at
+                 FROM third_party_individuals;
+                 DROP TABLE third_party_individuals;
+                 ALTER TABLE third_party_individuals_new RENAME TO third_party_individuals;
+                 COMMIT;",
+                THIRD_PARTY_INDIVIDUALS_COLUMNS
+            ));
+            db_pool::restore_foreign_keys(con, foreign_keys)?;
+            result?;
+        }
+
+        con.execute(
+            "CREATE INDEX IF NOT EXISTS idx_messages_companion ON messages(companion_id, id DESC)",
+            [],
+        )?;
+        con.execute(
+            "CREATE INDEX IF NOT EXISTS idx_third_party_name ON third_party_individuals(name)",
+            [],
+        )?;
+        con.execute(
+            "CREATE INDEX IF NOT EXISTS idx_third_party_companion ON third_party_individuals(companion_id, importance_score DESC)",
+            [],
+        )?;
+        con.execute(
+            "CREATE INDEX IF NOT EXISTS idx_third_party_importance ON third_party_individuals(importance_score DESC, mention_count DESC)", []
+        )?;
+        Ok(())
+    }
+
     /// Check for third-party mentions in message and track them, returning console output
     pub fn track_third_party_mentions(message: &str) -> Result<String> {
         let mut console_output = Vec::new();
@@ -3587,7 +5823,7 @@ impl Database {
             // Check if this person is mentioned in the message
             if message_lower.contains(&name_lower) {
                 // Update mention count and last_mentioned
-                let con = Connection::open("companion_database.db")?;
+                let con = db_pool::connection()?;
                 let current_time = get_current_date();
                 
                 con.execute(
@@ -3611,6 +5847,14 @@ impl Database {
                     "👥 {} mentioned for the {}{} time",
                     party.name, new_count, suffix
                 ));
+                event_bus::publish(
+                    "third_party_mentioned",
+                    serde_json::json!({
+                        "third_party_id": party.id,
+                        "name": party.name,
+                        "mention_count": new_count,
+                    }),
+                );
             }
         }
         
@@ -3681,6 +5925,51 @@ mod tests {
         assert!(date.len() > 10);
     }
 
+    #[test]
+    fn test_parse_stored_date_round_trip() {
+        assert!(parse_stored_date(&get_current_date()).is_some());
+        assert!(parse_stored_date("2024-01-15 10:00").is_none());
+    }
+
+    #[test]
+    fn test_merge_third_party_details() {
+        let person = |id, occupation: Option<&str>, first: &str, mentions| ThirdPartyIndividual {
+            id: Some(id),
+            name: "Anna".to_string(),
+            relationship_to_user: None,
+            relationship_to_companion: Some("friend".to_string()),
+            occupation: occupation.map(str::to_string),
+            personality_traits: None,
+            physical_description: None,
+            first_mentioned: first.to_string(),
+            last_mentioned: Some(first.to_string()),
+            mention_count: mentions,
+            importance_score: 0.1 * mentions as f32,
+            created_at: first.to_string(),
+            updated_at: first.to_string(),
+        };
+        let merged = merge_third_party_details(
+            person(1, Some(" "), "Friday 16.10.2026 18:51", 3),
+            &person(2, Some("nurse"), "Monday 12.10.2026 09:00", 2),
+        );
+        assert_eq!(merged.id, Some(1));
+        assert_eq!(merged.occupation.as_deref(), Some("nurse"));
+        assert_eq!(merged.relationship_to_companion.as_deref(), Some("friend"));
+        assert_eq!(merged.first_mentioned, "Monday 12.10.2026 09:00");
+        assert_eq!(merged.last_mentioned.as_deref(), Some("Friday 16.10.2026 18:51"));
+        assert_eq!(merged.mention_count, 5);
+        assert!((merged.importance_score - 0.3).abs() < 1e-6);
+    }
+
+    #[test]
+    fn test_dynamic_importance_favours_recent_frequent_mentions() {
+        let recent = calculate_dynamic_importance(1.0, 20, 2, 0.5, 0.5);
+        let forgotten = calculate_dynamic_importance(120.0, 2, 0, 0.1, 0.5);
+        assert!(recent > forgotten);
+        assert!((0.0..=1.0).contains(&recent));
+        assert!((0.0..=1.0).contains(&forgotten));
+    }
+
     #[test]
     fn test_contains_time_question() {
         assert!(contains_time_question("What time is it?"));
@@ -3697,6 +5986,9 @@ mod tests {
             ai: true,
             content: "Hello world".to_string(),
             created_at: "2024-01-15 10:00".to_string(),
+            author_id: None,
+            images: Vec::new(),
+            stats: None,
         };
 
         assert_eq!(message.id, 1);
@@ -3709,6 +6001,7 @@ mod tests {
         let new_message = NewMessage {
             ai: false,
             content: "User message".to_string(),
+            images: Vec::new(),
         };
 
         assert!(!new_message.ai);
@@ -0,0 +1,73 @@
+use crate::settings;
+use r2d2::{ManageConnection, Pool, PooledConnection};
+use rusqlite::{Connection, Error, Result};
+use std::path::PathBuf;
+use std::time::Duration;
+
+const MAX_CONNECTIONS: u32 = 8;
+// How long a statement waits for another connection's write lock before giving up
+const BUSY_TIMEOUT_SECONDS: u64 = 5;
+// Every pooled connection starts out the same, SQLite's default
+const FOREIGN_KEYS: bool = false;
+
+pub struct SqliteConnectionManager {
+    path: PathBuf,
+}
+
+impl ManageConnection for SqliteConnectionManager {
+    type Connection = Connection;
+    type Error = Error;
+
+    fn connect(&self) -> Result<Connection> {
+        let con = Connection::open(&self.path)?;
+        // WAL lets readers carry on while another worker writes
+        con.pragma_update_and_check(None, "journal_mode", "WAL", |row| row.get::<_, String>(0))?;
+        con.pragma_update(None, "synchronous", "NORMAL")?;
+        con.busy_timeout(Duration::from_secs(BUSY_TIMEOUT_SECONDS))?;
+        con.pragma_update(None, "foreign_keys", FOREIGN_KEYS)?;
+        Ok(con)
+    }
+
+    fn is_valid(&self, con: &mut Connection) -> Result<()> {
+        con.execute_batch("")
+    }
+
+    fn has_broken(&self, _con: &mut Connection) -> bool {
+        false
+    }
+}
+
+pub type DbConnection = PooledConnection<SqliteConnectionManager>;
+
+lazy_static::lazy_static! {
+    // Connections are opened on first use, so a missing database does not fail at startup
+    static ref POOL: Pool<SqliteConnectionManager> = Pool::builder()
+        .max_size(MAX_CONNECTIONS)
+        .build_unchecked(SqliteConnectionManager {
+            path: settings::get().database.clone(),
+        });
+}
+
+/// Borrow a connection to the companion database, it goes back to the pool when dropped
+pub fn connection() -> Result<DbConnection> {
+    POOL.get().map_err(|e| {
+        Error::SqliteFailure(
+            rusqlite::ffi::Error::new(rusqlite::ffi::SQLITE_BUSY),
+            Some(format!("No database connection available: {}", e)),
+        )
+    })
+}
+
+/// Turn foreign key checks off for work that rebuilds tables, returns whether they were on
+///
+/// The pragma only takes effect outside a transaction. Hand the result to
+/// [`restore_foreign_keys`] before the connection goes back to the pool.
+pub fn disable_foreign_keys(con: &Connection) -> Result<bool> {
+    let previous = con.pragma_query_value(None, "foreign_keys", |row| row.get(0))?;
+    con.pragma_update(None, "foreign_keys", false)?;
+    Ok(previous)
+}
+
+pub fn restore_foreign_keys(con: &Connection, previous: bool) -> Result<()> {
+    con.pragma_update(None, "foreign_keys", previous)
+}
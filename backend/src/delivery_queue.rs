@@ -0,0 +1,302 @@
+use crate::database::get_current_date;
+use crate::db_pool;
+#[cfg(feature = "discord")]
+use crate::discord;
+use crate::instance_lock;
+use rusqlite::{params, Error, Result};
+use serde::{Deserialize, Serialize};
+use std::time::Duration;
+
+// Deliveries that fail this many times are moved to the dead-letter list
+const DEFAULT_MAX_ATTEMPTS: i32 = 6;
+const BASE_BACKOFF_SECONDS: u64 = 5;
+const MAX_BACKOFF_SECONDS: u64 = 3600;
+const WORKER_INTERVAL_SECONDS: u64 = 5;
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Delivery {
+    pub id: i64,
+    pub integration: String,
+    pub target: String,
+    pub payload: String,
+    pub status: String,
+    pub attempts: i32,
+    pub max_attempts: i32,
+    pub last_error: Option<String>,
+    pub next_attempt_at: i64,
+    pub created_at: String,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Webhook {
+    pub id: i64,
+    pub url: String,
+    pub created_at: String,
+}
+
+/// Delay before the next attempt after `attempts` failed deliveries
+pub fn backoff_delay(attempts: i32) -> Duration {
+    let exponent = attempts.clamp(0, 16) as u32;
+    let seconds = BASE_BACKOFF_SECONDS
+        .saturating_mul(2u64.saturating_pow(exponent))
+        .min(MAX_BACKOFF_SECONDS);
+    Duration::from_secs(seconds)
+}
+
+pub struct DeliveryQueue {}
+
+impl DeliveryQueue {
+    pub fn create() -> Result<(), Error> {
+        let con = db_pool::connection()?;
+        con.execute(
+            "CREATE TABLE IF NOT EXISTS deliveries (
+                id INTEGER PRIMARY KEY AUTOINCREMENT,
+                integration TEXT NOT NULL,
+                target TEXT NOT NULL,
+                payload TEXT NOT NULL,
+                status TEXT NOT NULL DEFAULT 'pending' CHECK(status IN ('pending', 'delivered', 'failed')),
+                attempts INTEGER NOT NULL DEFAULT 0,
+                max_attempts INTEGER NOT NULL,
+                last_error TEXT,
+                next_attempt_at INTEGER NOT NULL,
+                created_at TEXT NOT NULL
+            )",
+            [],
+        )?;
+        con.execute(
+            "CREATE INDEX IF NOT EXISTS idx_deliveries_status ON deliveries(status, next_attempt_at)",
+            [],
+        )?;
+        con.execute(
+            "CREATE TABLE IF NOT EXISTS webhooks (
+                id INTEGER PRIMARY KEY AUTOINCREMENT,
+                url TEXT NOT NULL UNIQUE,
+                created_at TEXT NOT NULL
+            )",
+            [],
+        )?;
+        Ok(())
+    }
+
+    pub fn enqueue(integration: &str, target: &str, payload: &str) -> Result<i64> {
+        let con = db_pool::connection()?;
+        con.execute(
+            "INSERT INTO deliveries (integration, target, payload, max_attempts, next_attempt_at, created_at)
+             VALUES (?, ?, ?, ?, ?, ?)",
+            params![
+                integration,
+                target,
+                payload,
+                DEFAULT_MAX_ATTEMPTS,
+                chrono::Utc::now().timestamp(),
+                get_current_date()
+            ],
+        )?;
+        Ok(con.last_insert_rowid())
+    }
+
+    /// Queue an event for every registered webhook
+    pub fn enqueue_webhook_event(event: &str, data: serde_json::Value) -> Result<usize> {
+        let payload = serde_json::json!({
+            "event": event,
+            "data": data,
+            "sent_at": get_current_date(),
+        })
+        .to_string();
+        let webhooks = DeliveryQueue::get_webhooks()?;
+        for webhook in &webhooks {
+            DeliveryQueue::enqueue("webhook", &webhook.url, &payload)?;
+        }
+        Ok(webhooks.len())
+    }
+
+    pub fn get_failed() -> Result<Vec<Delivery>> {
+        DeliveryQueue::query_deliveries(
+            "SELECT id, integration, target, payload, status, attempts, max_attempts, last_error, next_attempt_at, created_at
+             FROM deliveries WHERE status = 'failed' ORDER BY id DESC",
+            params![],
+        )
+    }
+
+    fn get_due() -> Result<Vec<Delivery>> {
+        DeliveryQueue::query_deliveries(
+            "SELECT id, integration, target, payload, status, attempts, max_attempts, last_error, next_attempt_at, created_at
+             FROM deliveries WHERE status = 'pending' AND next_attempt_at <= ? ORDER BY next_attempt_at LIMIT 20",
+            params![chrono::Utc::now().timestamp()],
+        )
+    }
+
+    fn query_deliveries(sql: &str, query_params: &[&dyn rusqlite::ToSql]) -> Result<Vec<Delivery>> {
+        let con = db_pool::connection()?;
+        let mut stmt = con.prepare(sql)?;
+        let rows = stmt.query_map(query_params, |row| {
+            Ok(Delivery {
+                id: row.get(0)?,
+                integration: row.get(1)?,
+                target: row.get(2)?,
+                payload: row.get(3)?,
+                status: row.get(4)?,
+                attempts: row.get(5)?,
+                max_attempts: row.get(6)?,
+                last_error: row.get(7)?,
+                next_attempt_at: row.get(8)?,
+                created_at: row.get(9)?,
+            })
+        })?;
+        rows.collect()
+    }
+
+    /// Move a failed delivery back into the queue, returns false if it was not failed
+    pub fn retry(id: i64) -> Result<bool> {
+        let con = db_pool::connection()?;
+        let updated = con.execute(
+            "UPDATE deliveries SET status = 'pending', attempts = 0, next_attempt_at = ?
+             WHERE id = ? AND status = 'failed'",
+            params![chrono::Utc::now().timestamp(), id],
+        )?;
+        Ok(updated > 0)
+    }
+
+    pub fn retry_all_failed() -> Result<usize> {
+        let con = db_pool::connection()?;
+        con.execute(
+            "UPDATE deliveries SET status = 'pending', attempts = 0, next_attempt_at = ?
+             WHERE status = 'failed'",
+            params![chrono::Utc::now().timestamp()],
+        )
+    }
+
+    fn mark_delivered(id: i64) -> Result<()> {
+        let con = db_pool::connection()?;
+        con.execute(
+            "UPDATE deliveries SET status = 'delivered', attempts = attempts + 1, last_error = NULL WHERE id = ?",
+            params![id],
+        )?;
+        Ok(())
+    }
+
+    fn mark_attempt_failed(delivery: &Delivery, error: &str) -> Result<()> {
+        let attempts = delivery.attempts + 1;
+        let status = if attempts >= delivery.max_attempts {
+            "failed"
+        } else {
+            "pending"
+        };
+        let next_attempt_at =
+            chrono::Utc::now().timestamp() + backoff_delay(attempts).as_secs() as i64;
+        let con = db_pool::connection()?;
+        con.execute(
+            "UPDATE deliveries SET status = ?, attempts = ?, last_error = ?, next_attempt_at = ? WHERE id = ?",
+            params![status, attempts, error, next_attempt_at, delivery.id],
+        )?;
+        Ok(())
+    }
+
+    pub fn get_webhooks() -> Result<Vec<Webhook>> {
+        let con = db_pool::connection()?;
+        let mut stmt = con.prepare("SELECT id, url, created_at FROM webhooks ORDER BY id")?;
+        let rows = stmt.query_map([], |row| {
+            Ok(Webhook {
+                id: row.get(0)?,
+                url: row.get(1)?,
+                created_at: row.get(2)?,
+            })
+        })?;
+        rows.collect()
+    }
+
+    pub fn add_webhook(url: &str) -> Result<i64> {
+        if !(url.starts_with("http://") || url.starts_with("https://")) {
+            return Err(Error::InvalidParameterName(
+                "Webhook url must start with http:// or https://".to_string(),
+            ));
+        }
+        let con = db_pool::connection()?;
+        con.execute(
+            "INSERT OR IGNORE INTO webhooks (url, created_at) VALUES (?, ?)",
+            params![url, get_current_date()],
+        )?;
+        con.query_row("SELECT id FROM webhooks WHERE url = ?", params![url], |row| row.get(0))
+    }
+
+    pub fn remove_webhook(id: i64) -> Result<bool> {
+        let con = db_pool::connection()?;
+        let removed = con.execute("DELETE FROM webhooks WHERE id = ?", params![id])?;
+        Ok(removed > 0)
+    }
+
+    /// Attempt every delivery that is currently due
+    pub async fn process_due(client: &reqwest::Client) -> Result<()> {
+        for delivery in DeliveryQueue::get_due()? {
+            let outcome = match delivery.integration.as_str() {
+                #[cfg(feature = "discord")]
+                discord::INTEGRATION => discord::deliver(client, &delivery.target, &delivery.payload).await,
+                _ => client
+                    .post(&delivery.target)
+                    .header("Content-Type", "application/json")
+                    .body(delivery.payload.clone())
+                    .send()
+                    .await
+                    .and_then(|response| response.error_for_status())
+                    .map(|_| ())
+                    .map_err(|e| e.to_string()),
+            };
+            match outcome {
+                Ok(_) => DeliveryQueue::mark_delivered(delivery.id)?,
+                Err(e) => {
+                    tracing::warn!(
+                        "⚠️ Delivery {} to {} failed (attempt {}): {}",
+                        delivery.id,
+                        delivery.target,
+                        delivery.attempts + 1,
+                        e
+                    );
+                    DeliveryQueue::mark_attempt_failed(&delivery, &e)?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Background loop that drains the delivery queue
+    pub async fn run_worker() {
+        let client = match reqwest::Client::builder()
+            .timeout(Duration::from_secs(15))
+            .build()
+        {
+            Ok(client) => client,
+            Err(e) => {
+                tracing::warn!("⚠️ Failed to create delivery HTTP client: {}", e);
+                return;
+            }
+        };
+        let mut interval = tokio::time::interval(Duration::from_secs(WORKER_INTERVAL_SECONDS));
+        loop {
+            interval.tick().await;
+            if !instance_lock::is_leader() {
+                continue;
+            }
+            if let Err(e) = DeliveryQueue::process_due(&client).await {
+                tracing::warn!("⚠️ Failed to process delivery queue: {}", e);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_backoff_grows_exponentially() {
+        assert_eq!(backoff_delay(0), Duration::from_secs(5));
+        assert_eq!(backoff_delay(1), Duration::from_secs(10));
+        assert_eq!(backoff_delay(3), Duration::from_secs(40));
+    }
+
+    #[test]
+    fn test_backoff_is_capped() {
+        assert_eq!(backoff_delay(12), Duration::from_secs(MAX_BACKOFF_SECONDS));
+        assert_eq!(backoff_delay(i32::MAX), Duration::from_secs(MAX_BACKOFF_SECONDS));
+    }
+}
@@ -0,0 +1,202 @@
+//! Development fixtures, only compiled with the `dev` feature
+
+use crate::conversations::Conversations;
+use crate::database::{get_current_date, Database, ThirdPartyIndividual, ThirdPartyMemory};
+use chrono::{Duration, Local};
+use crate::db_pool;
+use crate::personality_traits;
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+use rusqlite::{params, Result};
+use serde::Serialize;
+
+const USER_LINES: [&str; 12] = [
+    "Good morning! Did you sleep well?",
+    "I had a rough day at work, {person} kept changing the deadlines.",
+    "Guess what, I finally finished that book you recommended.",
+    "Do you remember what we talked about last week?",
+    "{person} invited me to a concert on Saturday.",
+    "I'm thinking about learning to cook something new.",
+    "Can you help me plan my weekend?",
+    "I'm a bit worried about {person}, they seemed upset.",
+    "It's raining again, perfect weather for tea.",
+    "What do you think about moving to a bigger city?",
+    "I went running this morning and beat my record!",
+    "Tell me something interesting you learned recently.",
+];
+
+const AI_LINES: [&str; 10] = [
+    "*smiles* That sounds lovely, tell me more!",
+    "Oh no, I'm sorry to hear that. Do you want to talk about it?",
+    "I remember! You seemed really excited about it back then.",
+    "That's wonderful news, I'm proud of you.",
+    "Hmm, let me think about that for a moment...",
+    "I'd love to help. What did you have in mind?",
+    "*leans closer* You always know how to make me curious.",
+    "That must have been hard. How are you feeling now?",
+    "Honestly? I think you'd do great wherever you go.",
+    "Ha! You never stop surprising me.",
+];
+
+// name, relationship to user, occupation, personality traits, importance
+const PEOPLE: [(&str, &str, &str, &str, f32); 6] = [
+    ("Sarah", "boss", "project manager", "demanding, organized", 0.8),
+    ("Mike", "brother", "student", "funny, impulsive", 0.9),
+    ("Emma", "friend", "nurse", "kind, caring", 0.7),
+    ("David", "colleague", "software engineer", "smart, helpful", 0.6),
+    ("Olivia", "neighbor", "teacher", "quiet, friendly", 0.4),
+    ("James", "friend", "musician", "creative, moody", 0.5),
+];
+
+// from, to, relationship type, strength
+const RELATIONSHIPS: [(&str, &str, &str, f32); 5] = [
+    ("Mike", "Emma", "dating", 0.8),
+    ("Sarah", "David", "manager", 0.6),
+    ("Emma", "Olivia", "friends", 0.5),
+    ("James", "Mike", "bandmates", 0.7),
+    ("David", "James", "acquaintances", 0.3),
+];
+
+const DIMENSIONS: [&str; 8] = [
+    "trust", "joy", "curiosity", "anger", "respect", "gratitude", "anxiety", "love",
+];
+
+#[derive(Serialize, Default)]
+pub struct SeedSummary {
+    pub messages: usize,
+    pub third_parties: usize,
+    pub relationships: usize,
+    pub memories: usize,
+    pub attitude_changes: usize,
+}
+
+pub fn seed(message_count: usize, seed: u64) -> Result<SeedSummary> {
+    let mut rng = StdRng::seed_from_u64(seed);
+    let mut summary = SeedSummary::default();
+    // Everything goes to the active companion, user and conversation
+    let companion_id = Database::active_companion_id();
+    let user_id = Database::active_user_id();
+
+    let mut person_ids = Vec::new();
+    for (name, relationship, occupation, traits, importance) in PEOPLE.iter() {
+        let now = get_current_date();
+        let id = Database::create_or_update_third_party(
+            name,
+            Some(ThirdPartyIndividual {
+                id: None,
+                name: name.to_string(),
+                relationship_to_user: Some(relationship.to_string()),
+                relationship_to_companion: None,
+                occupation: Some(occupation.to_string()),
+                personality_traits: Some(traits.to_string()),
+                physical_description: None,
+                first_mentioned: now.clone(),
+                last_mentioned: Some(now.clone()),
+                mention_count: 1,
+                importance_score: *importance,
+                created_at: now.clone(),
+                updated_at: now,
+            }),
+        )?;
+        Database::update_third_party_importance(id, *importance)?;
+
+        let mut attitude = Database::initial_user_attitude(companion_id, id, &personality_traits::from_persona(traits));
+        attitude.target_type = "third_party".to_string();
+        Database::create_or_update_attitude(companion_id, id, "third_party", &attitude)?;
+
+        for memory_index in 0..3 {
+            let memory = ThirdPartyMemory {
+                id: None,
+                third_party_id: id,
+                companion_id,
+                memory_type: ["fact", "event", "opinion"][memory_index].to_string(),
+                content: format!(
+                    "{} is the user's {} and works as a {} ({})",
+                    name, relationship, occupation, memory_index + 1
+                ),
+                importance: rng.gen_range(0.3..0.9),
+                emotional_valence: rng.gen_range(-0.5..0.8),
+                created_at: get_current_date(),
+                context_message_id: None,
+            };
+            Database::add_third_party_memory(id, companion_id, &memory)?;
+            summary.memories += 1;
+        }
+        person_ids.push((*name, id));
+    }
+    summary.third_parties = person_ids.len();
+
+    let con = db_pool::connection()?;
+    let current_time = get_current_date();
+    for (from, to, relationship_type, strength) in RELATIONSHIPS.iter() {
+        let from_id = person_ids.iter().find(|(n, _)| n == from).map(|(_, id)| *id);
+        let to_id = person_ids.iter().find(|(n, _)| n == to).map(|(_, id)| *id);
+        if let (Some(from_id), Some(to_id)) = (from_id, to_id) {
+            con.execute(
+                "INSERT OR REPLACE INTO third_party_relationships
+                    (from_party_id, to_party_id, relationship_type, strength, description, created_at, updated_at)
+                 VALUES (?, ?, ?, ?, ?, ?, ?)",
+                params![
+                    from_id,
+                    to_id,
+                    relationship_type,
+                    strength,
+                    format!("{} and {} are {}", from, to, relationship_type),
+                    current_time,
+                    current_time
+                ],
+            )?;
+            summary.relationships += 1;
+        }
+    }
+    drop(con);
+
+    summary.messages = seed_messages(message_count, companion_id, user_id, &mut rng)?;
+
+    // Replay a series of attitude swings so attitude memories build up a history
+    if Database::get_attitude(companion_id, user_id, "user")?.is_none() {
+        Database::create_initial_user_attitude(companion_id, user_id, &[])?;
+    }
+    for _ in 0..40 {
+        let dimension = DIMENSIONS[rng.gen_range(0..DIMENSIONS.len())];
+        let magnitude = rng.gen_range(5.0..30.0);
+        let delta = if rng.gen_bool(0.65) { magnitude } else { -magnitude };
+        Database::update_attitude_dimension(companion_id, user_id, "user", dimension, delta)?;
+        summary.attitude_changes += 1;
+    }
+
+    Ok(summary)
+}
+
+fn seed_messages(count: usize, companion_id: i32, user_id: i32, rng: &mut StdRng) -> Result<usize> {
+    let conversation_id = Conversations::active_id();
+    let mut con = db_pool::connection()?;
+    let tx = con.transaction()?;
+    // Spread the conversation over the past few weeks, oldest first
+    let mut timestamp = Local::now() - Duration::minutes(count as i64 * 45);
+    for i in 0..count {
+        let ai = i % 2 == 1;
+        let line = if ai {
+            AI_LINES[rng.gen_range(0..AI_LINES.len())]
+        } else {
+            USER_LINES[rng.gen_range(0..USER_LINES.len())]
+        };
+        let person = PEOPLE[rng.gen_range(0..PEOPLE.len())].0;
+        let content = line.replace("{person}", person);
+        tx.execute(
+            "INSERT INTO messages (ai, content, created_at, companion_id, conversation_id, author_id) VALUES (?, ?, ?, ?, ?, ?)",
+            params![
+                ai,
+                content,
+                timestamp.format("%A %d.%m.%Y %H:%M").to_string(),
+                companion_id,
+                conversation_id,
+                if ai { None } else { Some(user_id) },
+            ],
+        )?;
+        timestamp += Duration::minutes(rng.gen_range(1..90));
+    }
+    tx.commit()?;
+    Database::clear_message_cache();
+    Ok(count)
+}
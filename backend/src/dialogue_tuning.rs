@@ -1,4 +1,11 @@
-use rusqlite::{Connection, Error, Result};
+use crate::database::Database;
+use crate::db_pool;
+use rand::Rng;
+use rusqlite::{params, Error, Result};
+
+// Weight of an exchange saved by hand, liked replies are picked more often
+pub const MANUAL_WEIGHT: f64 = 1.0;
+pub const LIKED_WEIGHT: f64 = 2.0;
 
 pub struct Dialogue {
     pub user_msg: String,
@@ -9,7 +16,7 @@ pub struct DialogueTuning {}
 
 impl DialogueTuning {
     pub fn create() -> Result<usize, Error> {
-        let con = Connection::open("companion_database.db")?;
+        let con = db_pool::connection()?;
         con.execute(
             "CREATE TABLE IF NOT EXISTS dialogue_tuning (
             id INTEGER PRIMARY KEY AUTOINCREMENT,
@@ -17,33 +24,86 @@ impl DialogueTuning {
             ai_msg TEXT
         )",
             [],
+        )?;
+        // Exchanges harvested from rated replies remember where they came from,
+        // companion_id stays empty for exchanges shared by every companion
+        for (column, kind) in [
+            ("companion_id", "INTEGER"),
+            ("message_id", "INTEGER"),
+            ("weight", "REAL DEFAULT 1.0"),
+        ] {
+            if !Database::has_column(&con, "dialogue_tuning", column)? {
+                con.execute(
+                    &format!("ALTER TABLE dialogue_tuning ADD COLUMN {} {}", column, kind),
+                    [],
+                )?;
+            }
+        }
+        con.execute(
+            "CREATE UNIQUE INDEX IF NOT EXISTS idx_dialogue_tuning_message ON dialogue_tuning(message_id)",
+            [],
         )
     }
 
     pub fn insert(user_msg: &str, ai_msg: &str) -> Result<usize, Error> {
-        let con = Connection::open("companion_database.db")?;
+        let con = db_pool::connection()?;
         con.execute(
-            "INSERT INTO dialogue_tuning (user_msg, ai_msg) VALUES (?1, ?2)",
-            [user_msg, ai_msg],
+            "INSERT INTO dialogue_tuning (user_msg, ai_msg, weight) VALUES (?1, ?2, ?3)",
+            params![user_msg, ai_msg, MANUAL_WEIGHT],
         )
     }
 
-    pub fn get_random_dialogue() -> Result<Dialogue, Error> {
-        let con = Connection::open("companion_database.db")?;
-        let mut stmt =
-            con.prepare("SELECT user_msg, ai_msg FROM dialogue_tuning ORDER BY RANDOM() LIMIT 1")?;
-        let mut rows = stmt.query([])?;
-        if let Some(row) = rows.next()? {
-            let user_msg: String = row.get(0)?;
-            let ai_msg: String = row.get(1)?;
-            Ok(Dialogue { user_msg, ai_msg })
-        } else {
-            Err(Error::QueryReturnedNoRows)
+    /// Keep a liked exchange, rating the same reply again only updates it
+    pub fn harvest(message_id: i32, companion_id: i32, user_msg: &str, ai_msg: &str) -> Result<usize, Error> {
+        let con = db_pool::connection()?;
+        con.execute(
+            "INSERT INTO dialogue_tuning (user_msg, ai_msg, companion_id, message_id, weight) VALUES (?, ?, ?, ?, ?)
+             ON CONFLICT(message_id) DO UPDATE SET user_msg = excluded.user_msg, ai_msg = excluded.ai_msg, weight = excluded.weight",
+            params![user_msg, ai_msg, companion_id, message_id, LIKED_WEIGHT],
+        )
+    }
+
+    /// Drop the exchange harvested from a reply that is no longer liked
+    pub fn forget_harvested(message_id: i32) -> Result<usize, Error> {
+        let con = db_pool::connection()?;
+        con.execute("DELETE FROM dialogue_tuning WHERE message_id = ?", [message_id])
+    }
+
+    /// Random exchange of the companion or a shared one, weighted so liked replies come up more often
+    pub fn get_random_dialogue(companion_id: i32) -> Result<Dialogue, Error> {
+        let con = db_pool::connection()?;
+        let mut stmt = con.prepare(
+            "SELECT user_msg, ai_msg, COALESCE(weight, 1.0) FROM dialogue_tuning
+             WHERE companion_id IS NULL OR companion_id = ?",
+        )?;
+        let rows = stmt.query_map([companion_id], |row| {
+            Ok((
+                Dialogue {
+                    user_msg: row.get::<_, Option<String>>(0)?.unwrap_or_default(),
+                    ai_msg: row.get::<_, Option<String>>(1)?.unwrap_or_default(),
+                },
+                row.get::<_, f64>(2)?.max(0.0),
+            ))
+        })?;
+        let dialogues: Vec<(Dialogue, f64)> = rows.collect::<Result<_>>()?;
+        let total: f64 = dialogues.iter().map(|(_, weight)| weight).sum();
+        if dialogues.is_empty() || total <= 0.0 {
+            return Err(Error::QueryReturnedNoRows);
+        }
+        let mut pick = rand::thread_rng().gen_range(0.0..total);
+        let last = dialogues.len() - 1;
+        for (i, (dialogue, weight)) in dialogues.into_iter().enumerate() {
+            // The last one also catches what rounding leaves over
+            if pick < weight || i == last {
+                return Ok(dialogue);
+            }
+            pick -= weight;
         }
+        Err(Error::QueryReturnedNoRows)
     }
 
     pub fn clear_dialogues() -> Result<usize, Error> {
-        let con = Connection::open("companion_database.db")?;
+        let con = db_pool::connection()?;
         con.execute("DELETE FROM dialogue_tuning", [])
     }
 }
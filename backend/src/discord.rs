@@ -0,0 +1,628 @@
+use crate::conversations::Conversations;
+use crate::database::{Database, UserView};
+use crate::db_pool;
+use crate::delivery_queue::DeliveryQueue;
+use crate::instance_lock;
+use futures_util::{SinkExt, StreamExt};
+use rusqlite::{params, OptionalExtension, Result};
+use serde::{Deserialize, Serialize};
+use std::time::Duration;
+use tokio_tungstenite::tungstenite::Message;
+
+const GATEWAY_URL: &str = "wss://gateway.discord.gg/?v=10&encoding=json";
+const API_URL: &str = "https://discord.com/api/v10";
+// GUILD_MESSAGES, DIRECT_MESSAGES and MESSAGE_CONTENT
+const INTENTS: u64 = (1 << 9) | (1 << 12) | (1 << 15);
+// Longer messages are refused by Discord
+const MAX_MESSAGE_CHARS: usize = 2000;
+const RECONNECT_DELAY: Duration = Duration::from_secs(5);
+// Attempts for a request Discord rate limited
+const MAX_SEND_ATTEMPTS: u32 = 4;
+/// Integration name of replies waiting in the delivery queue
+pub const INTEGRATION: &str = "discord";
+
+/// Generate the companion's reply to `text` from the user, in the conversation
+pub type ReplyFn = fn(text: &str, user_id: i32, conversation_id: i32) -> std::result::Result<String, String>;
+
+/// Bot token and the channels the companion answers every message in
+pub struct DiscordSettings {
+    token: String,
+    channels: Vec<String>,
+}
+
+impl DiscordSettings {
+    /// From COMPANION_DISCORD_TOKEN and COMPANION_DISCORD_CHANNELS, None without a token
+    pub fn from_env() -> Option<DiscordSettings> {
+        let token = std::env::var("COMPANION_DISCORD_TOKEN").ok()?.trim().to_string();
+        if token.is_empty() {
+            return None;
+        }
+        let channels = std::env::var("COMPANION_DISCORD_CHANNELS")
+            .unwrap_or_default()
+            .split(',')
+            .map(str::trim)
+            .filter(|channel| !channel.is_empty())
+            .map(str::to_string)
+            .collect();
+        Some(DiscordSettings { token, channels })
+    }
+}
+
+#[derive(Deserialize)]
+struct GatewayPayload {
+    op: u8,
+    #[serde(default)]
+    d: serde_json::Value,
+    s: Option<u64>,
+    t: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct Author {
+    id: String,
+    username: String,
+    global_name: Option<String>,
+    #[serde(default)]
+    bot: bool,
+}
+
+#[derive(Deserialize)]
+struct MessageCreate {
+    id: String,
+    channel_id: String,
+    guild_id: Option<String>,
+    author: Author,
+    #[serde(default)]
+    content: String,
+    #[serde(default)]
+    mentions: Vec<Author>,
+}
+
+/// Message the companion answers
+struct Incoming {
+    message_id: String,
+    channel_id: String,
+    direct: bool,
+    author_id: String,
+    author_name: String,
+    text: String,
+}
+
+/// Reply parts that still have to be posted, the payload of a queued delivery to a channel
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
+struct PendingReply {
+    /// Message the first part answers, None once an earlier part went out
+    reply_to: Option<String>,
+    chunks: Vec<String>,
+}
+
+/// Why a gateway connection ended
+enum SessionEnd {
+    /// Worth connecting again
+    Reconnect(String),
+    /// Connecting again would fail the same way, such as with a wrong token
+    Fatal(String),
+}
+
+/// What the companion is asked in a message, None when the message isn't for it
+///
+/// Direct messages, messages mentioning the bot and every message in `channels` are answered,
+/// never those of bots. The mention is taken out of the text.
+fn addressed(message: MessageCreate, bot_id: &str, channels: &[String]) -> Option<Incoming> {
+    if message.author.bot || bot_id.is_empty() {
+        return None;
+    }
+    let direct = message.guild_id.is_none();
+    let mentioned = message.mentions.iter().any(|user| user.id == bot_id);
+    if !direct && !mentioned && !channels.contains(&message.channel_id) {
+        return None;
+    }
+    let text = message
+        .content
+        .replace(&format!("<@{}>", bot_id), "")
+        .replace(&format!("<@!{}>", bot_id), "")
+        .trim()
+        .to_string();
+    if text.is_empty() {
+        return None;
+    }
+    Some(Incoming {
+        message_id: message.id,
+        channel_id: message.channel_id,
+        direct,
+        author_id: message.author.id,
+        author_name: message.author.global_name.unwrap_or(message.author.username),
+        text,
+    })
+}
+
+/// Cut a reply into messages Discord accepts, at line breaks or spaces where possible
+fn split_message(text: &str) -> Vec<String> {
+    let mut chunks = Vec::new();
+    let mut rest = text.trim();
+    while let Some((limit, _)) = rest.char_indices().nth(MAX_MESSAGE_CHARS) {
+        let head = &rest[..limit];
+        let cut = head
+            .rfind('\n')
+            .or_else(|| head.rfind(' '))
+            .filter(|cut| *cut > 0)
+            .unwrap_or(limit);
+        chunks.push(rest[..cut].trim_end().to_string());
+        rest = rest[cut..].trim_start();
+    }
+    if !rest.is_empty() {
+        chunks.push(rest.to_string());
+    }
+    chunks
+}
+
+/// How long Discord asks to wait, from the body of a 429 or the rate limit headers
+fn retry_after(body: Option<&serde_json::Value>, reset_after: Option<&str>) -> Duration {
+    body.and_then(|body| body["retry_after"].as_f64())
+        .or_else(|| reset_after.and_then(|seconds| seconds.parse().ok()))
+        .map(|seconds: f64| Duration::from_secs_f64(seconds.clamp(0.0, 300.0)))
+        .unwrap_or(Duration::from_secs(1))
+}
+
+pub fn create() -> Result<()> {
+    let con = db_pool::connection()?;
+    con.execute(
+        "CREATE TABLE IF NOT EXISTS discord_users (
+            discord_user_id TEXT PRIMARY KEY,
+            user_id INTEGER NOT NULL
+        )",
+        [],
+    )?;
+    con.execute(
+        "CREATE TABLE IF NOT EXISTS discord_channels (
+            channel_id TEXT NOT NULL,
+            companion_id INTEGER NOT NULL,
+            conversation_id INTEGER NOT NULL,
+            PRIMARY KEY (channel_id, companion_id)
+        )",
+        [],
+    )?;
+    Ok(())
+}
+
+/// User a Discord account writes as, created on its first message
+fn discord_user(discord_user_id: &str, name: &str) -> Result<i32> {
+    let con = db_pool::connection()?;
+    let known: Option<i32> = con
+        .query_row(
+            "SELECT d.user_id FROM discord_users d JOIN user u ON u.id = d.user_id WHERE d.discord_user_id = ?",
+            [discord_user_id],
+            |row| row.get(0),
+        )
+        .optional()?;
+    if let Some(user_id) = known {
+        return Ok(user_id);
+    }
+    let user_id = Database::create_user(UserView {
+        name: name.to_string(),
+        persona: format!("{} is chatting on Discord", name),
+        nickname: String::new(),
+        pronouns: String::new(),
+    })?;
+    con.execute(
+        "INSERT OR REPLACE INTO discord_users (discord_user_id, user_id) VALUES (?, ?)",
+        params![discord_user_id, user_id],
+    )?;
+    tracing::info!("Discord user {} joined as user {}", name, user_id);
+    Ok(user_id)
+}
+
+/// Conversation of the active companion in a Discord channel, started on the channel's first message
+fn discord_conversation(channel_id: &str, title: &str) -> Result<i32> {
+    let companion_id = Database::active_companion_id();
+    let con = db_pool::connection()?;
+    let known: Option<i32> = con
+        .query_row(
+            "SELECT conversation_id FROM discord_channels WHERE channel_id = ? AND companion_id = ?",
+            params![channel_id, companion_id],
+            |row| row.get(0),
+        )
+        .optional()?;
+    if let Some(conversation_id) = known {
+        // Deleted conversations are started over
+        if Conversations::companion_of(conversation_id)? == Some(companion_id) {
+            return Ok(conversation_id);
+        }
+    }
+    let conversation_id = Conversations::start(Some(title))?;
+    con.execute(
+        "INSERT OR REPLACE INTO discord_channels (channel_id, companion_id, conversation_id) VALUES (?, ?, ?)",
+        params![channel_id, companion_id, conversation_id],
+    )?;
+    Ok(conversation_id)
+}
+
+/// Discord REST client that waits out rate limits
+struct Rest {
+    client: reqwest::Client,
+    token: String,
+}
+
+impl Rest {
+    async fn request(
+        &self,
+        method: reqwest::Method,
+        path: &str,
+        body: Option<&serde_json::Value>,
+    ) -> std::result::Result<serde_json::Value, String> {
+        let url = format!("{}{}", API_URL, path);
+        let mut attempt = 1;
+        loop {
+            let mut request = self
+                .client
+                .request(method.clone(), &url)
+                .header("Authorization", format!("Bot {}", self.token));
+            if let Some(body) = body {
+                request = request.json(body);
+            }
+            let response = request.send().await.map_err(|e| e.to_string())?;
+            let header = |name: &str| {
+                response
+                    .headers()
+                    .get(name)
+                    .and_then(|value| value.to_str().ok())
+                    .map(str::to_string)
+            };
+            let remaining = header("x-ratelimit-remaining");
+            let reset_after = header("x-ratelimit-reset-after");
+            if response.status() == reqwest::StatusCode::TOO_MANY_REQUESTS {
+                let body = response.json::<serde_json::Value>().await.ok();
+                let delay = retry_after(body.as_ref(), reset_after.as_deref());
+                if attempt == MAX_SEND_ATTEMPTS {
+                    return Err(format!("rate limited on {} for {:?}", path, delay));
+                }
+                tracing::warn!("⚠️ Discord rate limited {}, retrying in {:?}", path, delay);
+                tokio::time::sleep(delay).await;
+                attempt += 1;
+                continue;
+            }
+            let status = response.status();
+            let body = response.json::<serde_json::Value>().await.unwrap_or_default();
+            if !status.is_success() {
+                return Err(format!("{} on {}: {}", status, path, body["message"]));
+            }
+            // The bucket is used up, the next request would only be refused
+            if remaining.as_deref() == Some("0") {
+                tokio::time::sleep(retry_after(None, reset_after.as_deref())).await;
+            }
+            return Ok(body);
+        }
+    }
+
+    async fn channel_name(&self, channel_id: &str) -> Option<String> {
+        let channel = self
+            .request(reqwest::Method::GET, &format!("/channels/{}", channel_id), None)
+            .await
+            .ok()?;
+        channel["name"].as_str().map(str::to_string)
+    }
+
+    async fn typing(&self, channel_id: &str) {
+        let path = format!("/channels/{}/typing", channel_id);
+        if let Err(e) = self.request(reqwest::Method::POST, &path, None).await {
+            tracing::debug!("Could not show typing in Discord: {}", e);
+        }
+    }
+
+    /// Post the parts of a reply, the first one answers `reply.reply_to`
+    ///
+    /// On failure the parts that didn't go out come back with the error.
+    async fn send(&self, channel_id: &str, reply: PendingReply) -> std::result::Result<(), (PendingReply, String)> {
+        let path = format!("/channels/{}/messages", channel_id);
+        for (index, chunk) in reply.chunks.iter().enumerate() {
+            let mut body = serde_json::json!({
+                "content": chunk,
+                // Replies never ping anyone
+                "allowed_mentions": { "parse": [] },
+            });
+            if let (0, Some(reply_to)) = (index, &reply.reply_to) {
+                body["message_reference"] = serde_json::json!({ "message_id": reply_to, "fail_if_not_exists": false });
+            }
+            if let Err(e) = self.request(reqwest::Method::POST, &path, Some(&body)).await {
+                let unsent = PendingReply {
+                    reply_to: if index == 0 { reply.reply_to.clone() } else { None },
+                    chunks: reply.chunks[index..].to_vec(),
+                };
+                return Err((unsent, e));
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Hand the parts of a reply that didn't go out to the delivery queue, its worker retries them
+fn queue_unsent(channel_id: &str, unsent: &PendingReply) {
+    let queued = serde_json::to_string(unsent)
+        .map_err(|e| e.to_string())
+        .and_then(|payload| DeliveryQueue::enqueue(INTEGRATION, channel_id, &payload).map_err(|e| e.to_string()));
+    if let Err(e) = queued {
+        tracing::error!("Failed to queue Discord reply for another attempt: {}", e);
+    }
+}
+
+/// Post a reply from the delivery queue to the channel it was meant for
+///
+/// Parts that went out aren't posted again, the rest is queued anew.
+pub async fn deliver(client: &reqwest::Client, channel_id: &str, payload: &str) -> std::result::Result<(), String> {
+    let settings = DiscordSettings::from_env().ok_or("COMPANION_DISCORD_TOKEN is not set")?;
+    let reply: PendingReply = serde_json::from_str(payload).map_err(|e| format!("invalid payload: {}", e))?;
+    let rest = Rest {
+        client: client.clone(),
+        token: settings.token,
+    };
+    let total = reply.chunks.len();
+    match rest.send(channel_id, reply).await {
+        Ok(()) => Ok(()),
+        Err((unsent, e)) if unsent.chunks.len() == total => Err(e),
+        Err((unsent, e)) => {
+            tracing::warn!("⚠️ Part of a queued Discord reply failed, queueing the rest: {}", e);
+            queue_unsent(channel_id, &unsent);
+            Ok(())
+        }
+    }
+}
+
+/// Answer messages one at a time, in the order they came in
+async fn run_replies(
+    rest: Rest,
+    mut incoming: tokio::sync::mpsc::UnboundedReceiver<Incoming>,
+    reply: ReplyFn,
+) {
+    while let Some(message) = incoming.recv().await {
+        let title = if message.direct {
+            format!("Discord: {}", message.author_name)
+        } else {
+            let name = rest.channel_name(&message.channel_id).await;
+            format!("Discord: #{}", name.as_deref().unwrap_or(&message.channel_id))
+        };
+        let ids = discord_user(&message.author_id, &message.author_name)
+            .and_then(|user_id| Ok((user_id, discord_conversation(&message.channel_id, &title)?)));
+        let (user_id, conversation_id) = match ids {
+            Ok(ids) => ids,
+            Err(e) => {
+                tracing::error!("Failed to map Discord message to a user and conversation: {}", e);
+                continue;
+            }
+        };
+        rest.typing(&message.channel_id).await;
+        let text = message.text.clone();
+        let result = actix_web::web::block(move || reply(&text, user_id, conversation_id))
+            .await
+            .map_err(|e| e.to_string())
+            .and_then(|result| result);
+        match result {
+            Ok(text) => {
+                let reply = PendingReply {
+                    reply_to: Some(message.message_id.clone()),
+                    chunks: split_message(&text),
+                };
+                if let Err((unsent, e)) = rest.send(&message.channel_id, reply).await {
+                    tracing::warn!("⚠️ Failed to send reply to Discord, queued for another attempt: {}", e);
+                    queue_unsent(&message.channel_id, &unsent);
+                }
+            }
+            Err(e) => tracing::error!("Failed to reply to Discord message: {}", e),
+        }
+    }
+}
+
+/// One connection to the gateway, until Discord or the network ends it
+async fn session(
+    settings: &DiscordSettings,
+    incoming: &tokio::sync::mpsc::UnboundedSender<Incoming>,
+) -> SessionEnd {
+    let (stream, _) = match tokio_tungstenite::connect_async(GATEWAY_URL).await {
+        Ok(connection) => connection,
+        Err(e) => return SessionEnd::Reconnect(format!("could not connect: {}", e)),
+    };
+    let (mut write, mut read) = stream.split();
+    let mut sequence: Option<u64> = None;
+    let mut bot_id = String::new();
+    let mut heartbeat: Option<tokio::time::Interval> = None;
+    let mut acknowledged = true;
+    loop {
+        let beat = async {
+            match heartbeat.as_mut() {
+                Some(interval) => interval.tick().await,
+                None => std::future::pending().await,
+            }
+        };
+        tokio::select! {
+            _ = beat => {
+                if !instance_lock::is_leader() {
+                    let _ = write.close().await;
+                    return SessionEnd::Reconnect("this instance is no longer the leader".to_string());
+                }
+                // A connection that stopped answering is dead even if it looks open
+                if !acknowledged {
+                    return SessionEnd::Reconnect("heartbeat was not acknowledged".to_string());
+                }
+                acknowledged = false;
+                let payload = serde_json::json!({ "op": 1, "d": sequence });
+                if let Err(e) = write.send(Message::text(payload.to_string())).await {
+                    return SessionEnd::Reconnect(e.to_string());
+                }
+            }
+            frame = read.next() => {
+                let text = match frame {
+                    Some(Ok(Message::Text(text))) => text,
+                    Some(Ok(Message::Close(frame))) => {
+                        let (code, reason) = frame
+                            .map(|frame| (u16::from(frame.code), frame.reason.to_string()))
+                            .unwrap_or((0, String::new()));
+                        let message = format!("closed with {} {}", code, reason);
+                        // Authentication failed, or the intents are invalid or not enabled for the bot
+                        return if matches!(code, 4004 | 4013 | 4014) {
+                            SessionEnd::Fatal(message)
+                        } else {
+                            SessionEnd::Reconnect(message)
+                        };
+                    }
+                    Some(Ok(_)) => continue,
+                    Some(Err(e)) => return SessionEnd::Reconnect(e.to_string()),
+                    None => return SessionEnd::Reconnect("connection closed".to_string()),
+                };
+                let payload: GatewayPayload = match serde_json::from_str(text.as_str()) {
+                    Ok(payload) => payload,
+                    Err(e) => {
+                        tracing::warn!("⚠️ Invalid Discord gateway payload: {}", e);
+                        continue;
+                    }
+                };
+                if payload.s.is_some() {
+                    sequence = payload.s;
+                }
+                match payload.op {
+                    // Hello, identify and start the heartbeat
+                    10 => {
+                        let every = Duration::from_millis(payload.d["heartbeat_interval"].as_u64().unwrap_or(41250));
+                        heartbeat = Some(tokio::time::interval_at(tokio::time::Instant::now() + every, every));
+                        let identify = serde_json::json!({
+                            "op": 2,
+                            "d": {
+                                "token": settings.token,
+                                "intents": INTENTS,
+                                "properties": { "os": std::env::consts::OS, "browser": "ai-companion", "device": "ai-companion" },
+                            },
+                        });
+                        if let Err(e) = write.send(Message::text(identify.to_string())).await {
+                            return SessionEnd::Reconnect(e.to_string());
+                        }
+                    }
+                    0 => match payload.t.as_deref() {
+                        Some("READY") => {
+                            bot_id = payload.d["user"]["id"].as_str().unwrap_or_default().to_string();
+                            tracing::info!(
+                                "Discord bot connected as {}",
+                                payload.d["user"]["username"].as_str().unwrap_or_default()
+                            );
+                        }
+                        Some("MESSAGE_CREATE") => {
+                            let message = match serde_json::from_value::<MessageCreate>(payload.d) {
+                                Ok(message) => message,
+                                Err(e) => {
+                                    tracing::warn!("⚠️ Invalid Discord message: {}", e);
+                                    continue;
+                                }
+                            };
+                            if let Some(message) = addressed(message, &bot_id, &settings.channels) {
+                                let _ = incoming.send(message);
+                            }
+                        }
+                        _ => {}
+                    },
+                    // Heartbeat requested
+                    1 => {
+                        let payload = serde_json::json!({ "op": 1, "d": sequence });
+                        if let Err(e) = write.send(Message::text(payload.to_string())).await {
+                            return SessionEnd::Reconnect(e.to_string());
+                        }
+                    }
+                    11 => acknowledged = true,
+                    7 => return SessionEnd::Reconnect("Discord asked to reconnect".to_string()),
+                    9 => return SessionEnd::Reconnect("session was invalidated".to_string()),
+                    _ => {}
+                }
+            }
+        }
+    }
+}
+
+/// Keep the companion connected to Discord while this instance is the leader
+///
+/// Every connection identifies anew, messages sent while disconnected are not answered.
+pub async fn run_bot(settings: DiscordSettings, reply: ReplyFn) {
+    let client = match reqwest::Client::builder().timeout(Duration::from_secs(15)).build() {
+        Ok(client) => client,
+        Err(e) => {
+            tracing::warn!("⚠️ Failed to create Discord HTTP client: {}", e);
+            return;
+        }
+    };
+    let rest = Rest {
+        client,
+        token: settings.token.clone(),
+    };
+    let (sender, receiver) = tokio::sync::mpsc::unbounded_channel();
+    actix_web::rt::spawn(run_replies(rest, receiver, reply));
+    loop {
+        if !instance_lock::is_leader() {
+            tokio::time::sleep(RECONNECT_DELAY).await;
+            continue;
+        }
+        match session(&settings, &sender).await {
+            SessionEnd::Reconnect(reason) => {
+                tracing::warn!("⚠️ Discord connection ended ({}), reconnecting in {:?}", reason, RECONNECT_DELAY);
+            }
+            SessionEnd::Fatal(reason) => {
+                tracing::error!("Discord bot stopped: {}", reason);
+                return;
+            }
+        }
+        tokio::time::sleep(RECONNECT_DELAY).await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn message(content: &str, guild_id: Option<&str>, mentions: Vec<&str>) -> MessageCreate {
+        let author = |id: &str| Author {
+            id: id.to_string(),
+            username: "anna".to_string(),
+            global_name: None,
+            bot: false,
+        };
+        MessageCreate {
+            id: "10".to_string(),
+            channel_id: "20".to_string(),
+            guild_id: guild_id.map(str::to_string),
+            author: Author {
+                global_name: Some("Anna".to_string()),
+                ..author("30")
+            },
+            content: content.to_string(),
+            mentions: mentions.into_iter().map(author).collect(),
+        }
+    }
+
+    #[test]
+    fn test_discord_messages() {
+        let direct = addressed(message("hi there", None, vec![]), "99", &[]).unwrap();
+        assert!(direct.direct);
+        assert_eq!(direct.author_name, "Anna");
+        assert_eq!(direct.text, "hi there");
+
+        let mentioned = addressed(message("<@99> how are you?", Some("1"), vec!["99"]), "99", &[]).unwrap();
+        assert_eq!(mentioned.text, "how are you?");
+        assert!(addressed(message("talking among us", Some("1"), vec![]), "99", &[]).is_none());
+        assert!(addressed(message("talking among us", Some("1"), vec![]), "99", &["20".to_string()]).is_some());
+        assert!(addressed(message("<@99>", Some("1"), vec!["99"]), "99", &[]).is_none());
+
+        let long = format!("{} {}", "a".repeat(1500), "b".repeat(1500));
+        let chunks = split_message(&long);
+        assert_eq!(chunks, vec!["a".repeat(1500), "b".repeat(1500)]);
+        assert_eq!(split_message(&"c".repeat(4500)).len(), 3);
+        assert!(split_message("  ").is_empty());
+
+        assert_eq!(
+            retry_after(Some(&serde_json::json!({ "retry_after": 1.5 })), Some("3")),
+            Duration::from_millis(1500)
+        );
+        assert_eq!(retry_after(None, Some("0.25")), Duration::from_millis(250));
+        assert_eq!(retry_after(None, None), Duration::from_secs(1));
+
+        let pending = PendingReply {
+            reply_to: Some("10".to_string()),
+            chunks: vec!["first".to_string(), "second".to_string()],
+        };
+        let payload = serde_json::to_string(&pending).unwrap();
+        assert_eq!(serde_json::from_str::<PendingReply>(&payload).unwrap(), pending);
+    }
+}
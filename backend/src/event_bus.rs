@@ -0,0 +1,253 @@
+use crate::database::get_current_date;
+use actix_web::web::Bytes;
+use futures_util::{stream, Stream, StreamExt};
+use serde::Serialize;
+use std::collections::VecDeque;
+use std::sync::Mutex;
+use std::time::Duration;
+use tokio::sync::broadcast;
+use tokio::sync::broadcast::error::RecvError;
+
+// Number of past events kept around for reconnecting clients
+const REPLAY_CAPACITY: usize = 1000;
+const CHANNEL_CAPACITY: usize = 256;
+const KEEPALIVE_SECONDS: u64 = 15;
+
+/// Real-time event pushed to connected clients
+#[derive(Serialize, Debug, Clone)]
+pub struct Event {
+    pub id: u64,
+    pub event: String,
+    pub data: serde_json::Value,
+    pub created_at: String,
+}
+
+impl Event {
+    /// Server-sent events wire format, the id doubles as the resume cursor
+    pub fn to_sse(&self) -> String {
+        format!(
+            "id: {}\nevent: {}\ndata: {}\n\n",
+            self.id, self.event, self.data
+        )
+    }
+}
+
+/// Events a reconnecting client missed since its cursor
+#[derive(Serialize, Debug, Default)]
+pub struct Replay {
+    pub events: Vec<Event>,
+    /// Some events after the cursor were already evicted, the client should refetch its state
+    pub truncated: bool,
+    pub latest_id: u64,
+}
+
+struct ReplayBuffer {
+    events: VecDeque<Event>,
+    last_id: u64,
+}
+
+impl ReplayBuffer {
+    fn replay_after(&self, cursor: Option<u64>) -> Replay {
+        let cursor = match cursor {
+            Some(cursor) => cursor,
+            None => {
+                return Replay {
+                    latest_id: self.last_id,
+                    ..Default::default()
+                }
+            }
+        };
+        // A cursor from the future was issued before a server restart
+        if cursor > self.last_id {
+            return Replay {
+                events: self.events.iter().cloned().collect(),
+                truncated: true,
+                latest_id: self.last_id,
+            };
+        }
+        let oldest_id = self
+            .events
+            .front()
+            .map(|e| e.id)
+            .unwrap_or(self.last_id + 1);
+        Replay {
+            events: self.events.iter().filter(|e| e.id > cursor).cloned().collect(),
+            truncated: oldest_id > cursor + 1,
+            latest_id: self.last_id,
+        }
+    }
+}
+
+/// Bounded replay buffer plus a broadcast channel for live subscribers
+pub struct EventBus {
+    buffer: Mutex<ReplayBuffer>,
+    capacity: usize,
+    sender: broadcast::Sender<Event>,
+}
+
+impl EventBus {
+    pub fn new(capacity: usize) -> Self {
+        let (sender, _) = broadcast::channel(CHANNEL_CAPACITY);
+        EventBus {
+            buffer: Mutex::new(ReplayBuffer {
+                events: VecDeque::with_capacity(capacity),
+                last_id: 0,
+            }),
+            capacity,
+            sender,
+        }
+    }
+
+    /// Record an event and push it to live subscribers, returns its id
+    pub fn publish(&self, event: &str, data: serde_json::Value) -> u64 {
+        let mut buffer = self.buffer.lock().unwrap();
+        buffer.last_id += 1;
+        let event = Event {
+            id: buffer.last_id,
+            event: event.to_string(),
+            data,
+            created_at: get_current_date(),
+        };
+        buffer.events.push_back(event.clone());
+        while buffer.events.len() > self.capacity {
+            buffer.events.pop_front();
+        }
+        // Sent under the lock so ids reach subscribers in order
+        let _ = self.sender.send(event);
+        buffer.last_id
+    }
+
+    pub fn events_after(&self, cursor: Option<u64>) -> Replay {
+        self.buffer.lock().unwrap().replay_after(cursor)
+    }
+
+    /// Missed events plus a receiver for everything published afterwards, without gaps or duplicates
+    pub fn subscribe(&self, cursor: Option<u64>) -> (Replay, broadcast::Receiver<Event>) {
+        let buffer = self.buffer.lock().unwrap();
+        (buffer.replay_after(cursor), self.sender.subscribe())
+    }
+}
+
+lazy_static::lazy_static! {
+    /// Global event bus shared by all real-time endpoints
+    pub static ref EVENT_BUS: EventBus = EventBus::new(REPLAY_CAPACITY);
+}
+
+pub fn publish(event: &str, data: serde_json::Value) -> u64 {
+    EVENT_BUS.publish(event, data)
+}
+
+fn replay_chunk(replay: &Replay) -> String {
+    let mut chunk = String::new();
+    if replay.truncated {
+        chunk.push_str("event: replay_truncated\ndata: {}\n\n");
+    }
+    for event in &replay.events {
+        chunk.push_str(&event.to_sse());
+    }
+    chunk
+}
+
+/// Server-sent event stream that first replays everything after `cursor`
+pub fn sse_stream(cursor: Option<u64>) -> impl Stream<Item = Result<Bytes, actix_web::Error>> {
+    let (replay, receiver) = EVENT_BUS.subscribe(cursor);
+    let last_id = replay.latest_id;
+    // Tell the client how long to wait before reconnecting
+    let initial = format!("retry: 3000\n\n{}", replay_chunk(&replay));
+
+    let live = stream::unfold((receiver, last_id), |(mut receiver, mut last_id)| async move {
+        loop {
+            let chunk = match tokio::time::timeout(
+                Duration::from_secs(KEEPALIVE_SECONDS),
+                receiver.recv(),
+            )
+            .await
+            {
+                Err(_) => ": keepalive\n\n".to_string(),
+                Ok(Ok(event)) => {
+                    if event.id <= last_id {
+                        continue;
+                    }
+                    last_id = event.id;
+                    event.to_sse()
+                }
+                // A slow client fell behind the channel, catch it up from the replay buffer
+                Ok(Err(RecvError::Lagged(_))) => {
+                    let replay = EVENT_BUS.events_after(Some(last_id));
+                    if let Some(event) = replay.events.last() {
+                        last_id = event.id;
+                    }
+                    let chunk = replay_chunk(&replay);
+                    if chunk.is_empty() {
+                        continue;
+                    }
+                    chunk
+                }
+                Ok(Err(RecvError::Closed)) => return None,
+            };
+            return Some((Ok(Bytes::from(chunk)), (receiver, last_id)));
+        }
+    });
+
+    stream::once(async move { Ok(Bytes::from(initial)) }).chain(live)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ids(replay: &Replay) -> Vec<u64> {
+        replay.events.iter().map(|e| e.id).collect()
+    }
+
+    #[test]
+    fn test_replay_resumes_after_cursor() {
+        let bus = EventBus::new(10);
+        for _ in 0..5 {
+            bus.publish("attitude_changed", serde_json::json!({}));
+        }
+
+        let replay = bus.events_after(Some(3));
+        assert_eq!(ids(&replay), vec![4, 5]);
+        assert!(!replay.truncated);
+        assert!(bus.events_after(Some(5)).events.is_empty());
+        assert!(bus.events_after(None).events.is_empty());
+    }
+
+    #[test]
+    fn test_replay_buffer_is_bounded() {
+        let bus = EventBus::new(3);
+        for _ in 0..6 {
+            bus.publish("companion_message", serde_json::json!({}));
+        }
+
+        let replay = bus.events_after(Some(1));
+        assert_eq!(ids(&replay), vec![4, 5, 6]);
+        assert!(replay.truncated);
+        assert!(!bus.events_after(Some(3)).truncated);
+    }
+
+    #[test]
+    fn test_stale_cursor_replays_everything() {
+        let bus = EventBus::new(10);
+        bus.publish("companion_message", serde_json::json!({}));
+
+        let replay = bus.events_after(Some(42));
+        assert_eq!(ids(&replay), vec![1]);
+        assert!(replay.truncated);
+    }
+
+    #[test]
+    fn test_sse_format() {
+        let event = Event {
+            id: 7,
+            event: "attitude_changed".to_string(),
+            data: serde_json::json!({ "trust": 5.0 }),
+            created_at: String::new(),
+        };
+        assert_eq!(
+            event.to_sse(),
+            "id: 7\nevent: attitude_changed\ndata: {\"trust\":5.0}\n\n"
+        );
+    }
+}
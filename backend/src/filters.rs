@@ -0,0 +1,421 @@
+use crate::database::get_current_date;
+use crate::db_pool;
+use regex::{Captures, Regex, RegexBuilder};
+use rusqlite::{params, Error, OptionalExtension, Result};
+use serde::{Deserialize, Serialize};
+use tracing::warn;
+
+pub const MAX_PATTERN_CHARS: usize = 500;
+// Compiled size a rule may take, keeps a pathological pattern from eating memory
+const PATTERN_SIZE_LIMIT: usize = 1 << 20;
+
+// Words masked by the profanity filter, with the endings they take ("fucking", "shitty")
+const PROFANITY: &str = r"(?i)\b(?:(?:mother)?fuck\w*|shit\w*|bullshit\w*|bitch\w*|bastard\w*|cunt\w*|asshole\w*|ass|dick|dickhead\w*|piss\w*|wank\w*|twat\w*|bollocks|prick\w*|slut\w*|whore\w*|damn\w*)\b";
+const EMAIL: &str = r"[A-Za-z0-9._%+-]+@[A-Za-z0-9.-]+\.[A-Za-z]{2,}";
+const CARD_NUMBER: &str = r"\b(?:\d[ -]?){12,18}\d\b";
+const PHONE_NUMBER: &str = r"(?:\+\d{1,3}[ .-]?)?(?:\(\d{2,4}\)[ .-]?)?\d{2,4}[ .-]?\d{3,4}(?:[ .-]?\d{2,4})?\b";
+// Digits a number needs to be taken for a phone number, fewer are years, prices and the like
+const MIN_PHONE_DIGITS: usize = 9;
+
+/// Text a filter runs on
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum Stage {
+    /// The user's messages, before they are stored or sent to the model
+    Prompt,
+    /// The companion's replies, before they are stored or sent to the client
+    Response,
+}
+
+impl Stage {
+    pub fn of_message(ai: bool) -> Stage {
+        if ai {
+            Stage::Response
+        } else {
+            Stage::Prompt
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum AppliesTo {
+    Prompt,
+    Response,
+    Both,
+}
+
+impl AppliesTo {
+    fn parse(value: &str) -> AppliesTo {
+        match value {
+            "prompt" => AppliesTo::Prompt,
+            "response" => AppliesTo::Response,
+            _ => AppliesTo::Both,
+        }
+    }
+
+    fn as_str(&self) -> &'static str {
+        match self {
+            AppliesTo::Prompt => "prompt",
+            AppliesTo::Response => "response",
+            AppliesTo::Both => "both",
+        }
+    }
+
+    fn includes(&self, stage: Stage) -> bool {
+        match self {
+            AppliesTo::Prompt => stage == Stage::Prompt,
+            AppliesTo::Response => stage == Stage::Response,
+            AppliesTo::Both => true,
+        }
+    }
+}
+
+fn default_applies_to() -> AppliesTo {
+    AppliesTo::Both
+}
+
+fn default_enabled() -> bool {
+    true
+}
+
+/// Filters that come with the server, in the order they run
+const BUILTIN_FILTERS: [(&str, &str); 2] = [
+    (
+        "pii",
+        "Replaces e-mail addresses, card numbers and phone numbers with [email], [card number] and [phone number]",
+    ),
+    ("profanity", "Masks swear words but their first letter, f***"),
+];
+
+/// A built-in filter with whether it is switched on, all of them start off
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct ContentFilter {
+    pub name: String,
+    pub description: String,
+    pub enabled: bool,
+    pub applies_to: AppliesTo,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct ContentFilterModify {
+    pub enabled: Option<bool>,
+    pub applies_to: Option<AppliesTo>,
+}
+
+/// Regular expression replaced in the texts it applies to, after the built-in filters ran
+#[derive(Serialize, Debug, Clone)]
+pub struct FilterRule {
+    pub id: i32,
+    pub pattern: String,
+    /// May refer to groups of the pattern as $1 or ${name}
+    pub replacement: String,
+    pub applies_to: AppliesTo,
+    pub enabled: bool,
+    pub created_at: String,
+    pub updated_at: String,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct FilterRuleModify {
+    pub pattern: String,
+    #[serde(default)]
+    pub replacement: String,
+    #[serde(default = "default_applies_to")]
+    pub applies_to: AppliesTo,
+    #[serde(default = "default_enabled")]
+    pub enabled: bool,
+}
+
+impl FilterRuleModify {
+    /// An error when the pattern is empty, too long or not a valid regular expression
+    pub fn validate(&self) -> Result<(), String> {
+        if self.pattern.is_empty() {
+            return Err("A filter rule needs a pattern".to_string());
+        }
+        if self.pattern.chars().count() > MAX_PATTERN_CHARS {
+            return Err(format!("Patterns are limited to {} characters", MAX_PATTERN_CHARS));
+        }
+        compile(&self.pattern)
+            .map(|_| ())
+            .map_err(|e| format!("Invalid pattern: {}", e))
+    }
+}
+
+fn compile(pattern: &str) -> Result<Regex, regex::Error> {
+    RegexBuilder::new(pattern).size_limit(PATTERN_SIZE_LIMIT).build()
+}
+
+lazy_static::lazy_static! {
+    static ref PROFANITY_REGEX: Regex = Regex::new(PROFANITY).unwrap();
+    static ref EMAIL_REGEX: Regex = Regex::new(EMAIL).unwrap();
+    static ref CARD_NUMBER_REGEX: Regex = Regex::new(CARD_NUMBER).unwrap();
+    static ref PHONE_NUMBER_REGEX: Regex = Regex::new(PHONE_NUMBER).unwrap();
+}
+
+fn mask_profanity(text: &str) -> String {
+    PROFANITY_REGEX
+        .replace_all(text, |caps: &Captures| {
+            let mut chars = caps[0].chars();
+            let first = chars.next().map(String::from).unwrap_or_default();
+            first + &"*".repeat(chars.count())
+        })
+        .into_owned()
+}
+
+/// Luhn checksum, tells card numbers from other long numbers
+fn is_card_number(digits: &[u32]) -> bool {
+    let sum: u32 = digits
+        .iter()
+        .rev()
+        .enumerate()
+        .map(|(i, &digit)| match i % 2 {
+            1 if digit * 2 > 9 => digit * 2 - 9,
+            1 => digit * 2,
+            _ => digit,
+        })
+        .sum();
+    sum.is_multiple_of(10)
+}
+
+fn redact_pii(text: &str) -> String {
+    let text = EMAIL_REGEX.replace_all(text, "[email]");
+    let text = CARD_NUMBER_REGEX.replace_all(&text, |caps: &Captures| {
+        let digits: Vec<u32> = caps[0].chars().filter_map(|c| c.to_digit(10)).collect();
+        match is_card_number(&digits) {
+            true => "[card number]".to_string(),
+            false => caps[0].to_string(),
+        }
+    });
+    PHONE_NUMBER_REGEX
+        .replace_all(&text, |caps: &Captures| {
+            let number = caps.get(0).map_or("", |m| m.as_str());
+            let start = caps.get(0).map_or(0, |m| m.start());
+            // The end of a longer number or word, like an order number
+            let inside = text[..start].chars().next_back().is_some_and(char::is_alphanumeric);
+            let digits = number.chars().filter(char::is_ascii_digit).count();
+            match !inside && (digits >= MIN_PHONE_DIGITS || (number.starts_with('+') && digits >= 7)) {
+                true => "[phone number]".to_string(),
+                false => number.to_string(),
+            }
+        })
+        .into_owned()
+}
+
+fn run_builtin(name: &str, text: &str) -> String {
+    match name {
+        "pii" => redact_pii(text),
+        "profanity" => mask_profanity(text),
+        _ => text.to_string(),
+    }
+}
+
+pub struct Filters {}
+
+impl Filters {
+    pub fn create() -> Result<()> {
+        let con = db_pool::connection()?;
+        con.execute(
+            "CREATE TABLE IF NOT EXISTS content_filters (
+                name TEXT PRIMARY KEY,
+                enabled BOOLEAN NOT NULL DEFAULT 0,
+                applies_to TEXT NOT NULL DEFAULT 'both'
+            )",
+            [],
+        )?;
+        con.execute(
+            "CREATE TABLE IF NOT EXISTS filter_rules (
+                id INTEGER PRIMARY KEY AUTOINCREMENT,
+                pattern TEXT NOT NULL,
+                replacement TEXT NOT NULL DEFAULT '',
+                applies_to TEXT NOT NULL DEFAULT 'both',
+                enabled BOOLEAN NOT NULL DEFAULT 1,
+                created_at TEXT NOT NULL,
+                updated_at TEXT NOT NULL
+            )",
+            [],
+        )?;
+        Ok(())
+    }
+
+    /// The built-in filters in the order they run
+    pub fn list() -> Result<Vec<ContentFilter>> {
+        let con = db_pool::connection()?;
+        let mut stmt = con.prepare("SELECT enabled, applies_to FROM content_filters WHERE name = ?")?;
+        BUILTIN_FILTERS
+            .iter()
+            .map(|(name, description)| {
+                let (enabled, applies_to) = stmt
+                    .query_row([name], |row| {
+                        Ok((row.get::<_, bool>(0)?, AppliesTo::parse(&row.get::<_, String>(1)?)))
+                    })
+                    .optional()?
+                    .unwrap_or((false, AppliesTo::Both));
+                Ok(ContentFilter {
+                    name: name.to_string(),
+                    description: description.to_string(),
+                    enabled,
+                    applies_to,
+                })
+            })
+            .collect()
+    }
+
+    /// Switch a built-in filter on or off, QueryReturnedNoRows for a name no filter has
+    pub fn set(name: &str, changes: &ContentFilterModify) -> Result<ContentFilter> {
+        let current = Filters::list()?
+            .into_iter()
+            .find(|filter| filter.name == name)
+            .ok_or(Error::QueryReturnedNoRows)?;
+        let enabled = changes.enabled.unwrap_or(current.enabled);
+        let applies_to = changes.applies_to.unwrap_or(current.applies_to);
+        let con = db_pool::connection()?;
+        con.execute(
+            "INSERT INTO content_filters (name, enabled, applies_to) VALUES (?, ?, ?)
+             ON CONFLICT(name) DO UPDATE SET enabled = excluded.enabled, applies_to = excluded.applies_to",
+            params![name, enabled, applies_to.as_str()],
+        )?;
+        Ok(ContentFilter {
+            enabled,
+            applies_to,
+            ..current
+        })
+    }
+
+    fn rule_from_row(row: &rusqlite::Row) -> Result<FilterRule> {
+        Ok(FilterRule {
+            id: row.get(0)?,
+            pattern: row.get(1)?,
+            replacement: row.get(2)?,
+            applies_to: AppliesTo::parse(&row.get::<_, String>(3)?),
+            enabled: row.get(4)?,
+            created_at: row.get(5)?,
+            updated_at: row.get(6)?,
+        })
+    }
+
+    /// Custom rules in the order they run, oldest first
+    pub fn rules() -> Result<Vec<FilterRule>> {
+        let con = db_pool::connection()?;
+        let mut stmt = con.prepare(
+            "SELECT id, pattern, replacement, applies_to, enabled, created_at, updated_at
+             FROM filter_rules ORDER BY id",
+        )?;
+        let rows = stmt.query_map([], Filters::rule_from_row)?;
+        rows.collect()
+    }
+
+    pub fn add_rule(rule: &FilterRuleModify) -> Result<i32> {
+        let con = db_pool::connection()?;
+        con.execute(
+            "INSERT INTO filter_rules (pattern, replacement, applies_to, enabled, created_at, updated_at)
+             VALUES (?, ?, ?, ?, ?, ?)",
+            params![
+                rule.pattern,
+                rule.replacement,
+                rule.applies_to.as_str(),
+                rule.enabled,
+                get_current_date(),
+                get_current_date()
+            ],
+        )?;
+        Ok(con.last_insert_rowid() as i32)
+    }
+
+    pub fn edit_rule(id: i32, rule: &FilterRuleModify) -> Result<bool> {
+        let con = db_pool::connection()?;
+        let changed = con.execute(
+            "UPDATE filter_rules SET pattern = ?, replacement = ?, applies_to = ?, enabled = ?, updated_at = ?
+             WHERE id = ?",
+            params![
+                rule.pattern,
+                rule.replacement,
+                rule.applies_to.as_str(),
+                rule.enabled,
+                get_current_date(),
+                id
+            ],
+        )?;
+        Ok(changed > 0)
+    }
+
+    pub fn delete_rule(id: i32) -> Result<bool> {
+        let con = db_pool::connection()?;
+        let changed = con.execute("DELETE FROM filter_rules WHERE id = ?", [id])?;
+        Ok(changed > 0)
+    }
+
+    /// Run the switched on filters for `stage` over a text, the built-in ones first
+    pub fn run(stage: Stage, text: &str) -> Result<String> {
+        let mut filtered = text.to_string();
+        for filter in Filters::list()? {
+            if filter.enabled && filter.applies_to.includes(stage) {
+                filtered = run_builtin(&filter.name, &filtered);
+            }
+        }
+        for rule in Filters::rules()? {
+            if !rule.enabled || !rule.applies_to.includes(stage) {
+                continue;
+            }
+            // Rules are checked when they are saved, one broken since is skipped
+            match compile(&rule.pattern) {
+                Ok(regex) => filtered = regex.replace_all(&filtered, rule.replacement.as_str()).into_owned(),
+                Err(e) => warn!("Skipping filter rule {}: {}", rule.id, e),
+            }
+        }
+        Ok(filtered)
+    }
+
+    /// Same as run(), leaving the text as it is when the filters can't be read
+    pub fn apply(stage: Stage, text: &str) -> String {
+        match Filters::run(stage, text) {
+            Ok(filtered) => filtered,
+            Err(e) => {
+                warn!("⚠️ Could not filter the text: {}", e);
+                text.to_string()
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_builtin_filters() {
+        assert_eq!(
+            mask_profanity("Well shit, that fucking class was a pain in the ass."),
+            "Well s***, that f****** class was a pain in the a**."
+        );
+        assert_eq!(mask_profanity("Pass the assessment, Dickens"), "Pass the assessment, Dickens");
+
+        assert_eq!(
+            redact_pii("Mail me at anna.k@example.org or call +49 170 1234567."),
+            "Mail me at [email] or call [phone number]."
+        );
+        assert_eq!(redact_pii("My card is 4111 1111 1111 1111"), "My card is [card number]");
+        // Dates, years and prices stay
+        assert_eq!(
+            redact_pii("On 2024-05-01 it cost 1250 euros, order 1234567890123"),
+            "On 2024-05-01 it cost 1250 euros, order 1234567890123"
+        );
+        assert_eq!(redact_pii("Call (555) 123-4567"), "Call [phone number]");
+    }
+
+    #[test]
+    fn test_filter_rule_validation() {
+        let rule = |pattern: &str| FilterRuleModify {
+            pattern: pattern.to_string(),
+            replacement: String::new(),
+            applies_to: AppliesTo::Both,
+            enabled: true,
+        };
+        assert!(rule(r"\bKevin\b").validate().is_ok());
+        assert!(rule("(unclosed").validate().is_err());
+        assert!(rule("").validate().is_err());
+        assert!(AppliesTo::Prompt.includes(Stage::Prompt));
+        assert!(!AppliesTo::Response.includes(Stage::Prompt));
+        assert!(AppliesTo::Both.includes(Stage::of_message(true)));
+    }
+}
@@ -0,0 +1,428 @@
+use crate::database::get_current_date;
+use crate::db_pool;
+use crate::event_bus;
+use rusqlite::{params, Error, OptionalExtension, Result};
+use serde::{Deserialize, Serialize};
+
+/// Goals a companion pursues at once, more would crowd the prompt and pull the chat every way
+pub const MAX_ACTIVE_GOALS: usize = 5;
+pub const MAX_DESCRIPTION_CHARS: usize = 200;
+// Progress of an exchange in which both sides touch every keyword of a goal
+const PROGRESS_STEP: f32 = 0.1;
+// The user taking up a topic counts for more than the companion bringing it up
+const USER_WEIGHT: f32 = 2.0;
+// Words of a description that tell what kind of goal it is rather than what it is about
+const GOAL_WORDS: [&str; 40] = [
+    "the", "and", "for", "with", "about", "more", "learn", "find", "out", "know", "get", "make",
+    "help", "talk", "ask", "tell", "their", "his", "her", "them", "they", "what", "when", "how",
+    "why", "who", "its", "into", "from", "over", "some", "better", "again", "also", "much", "many",
+    "very", "user", "char", "other",
+];
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum GoalStatus {
+    Active,
+    Achieved,
+    Abandoned,
+}
+
+impl GoalStatus {
+    pub fn name(self) -> &'static str {
+        match self {
+            GoalStatus::Active => "active",
+            GoalStatus::Achieved => "achieved",
+            GoalStatus::Abandoned => "abandoned",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<GoalStatus> {
+        match name {
+            "active" => Some(GoalStatus::Active),
+            "achieved" => Some(GoalStatus::Achieved),
+            "abandoned" => Some(GoalStatus::Abandoned),
+            _ => None,
+        }
+    }
+}
+
+/// Something the companion works toward over many conversations, such as learning about the
+/// user's job or reconciling with a friend
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct Goal {
+    pub id: i32,
+    pub companion_id: i32,
+    /// May use {{user}} and {{char}}
+    pub description: String,
+    /// Words that show a conversation is moving toward the goal
+    pub keywords: Vec<String>,
+    /// Goals with a higher priority come first in the prompt
+    pub priority: i32,
+    /// From 0 to 1, the goal is achieved at 1
+    pub progress: f32,
+    pub status: GoalStatus,
+    pub created_at: String,
+    pub updated_at: String,
+    /// When an exchange last moved the goal along
+    pub last_progress_at: Option<String>,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct GoalModify {
+    pub description: String,
+    /// Taken from the description when left empty
+    #[serde(default)]
+    pub keywords: Vec<String>,
+    #[serde(default = "default_priority")]
+    pub priority: i32,
+    /// Left out to keep the progress a goal has, 0 for a new one
+    #[serde(default)]
+    pub progress: Option<f32>,
+    /// Left out to keep the status a goal has, active for a new one
+    #[serde(default)]
+    pub status: Option<GoalStatus>,
+}
+
+fn default_priority() -> i32 {
+    100
+}
+
+/// Words a description is about, without the placeholders and the words every goal has
+pub fn keywords_of(description: &str) -> Vec<String> {
+    let mut keywords: Vec<String> = Vec::new();
+    for word in description
+        .split(|c: char| !c.is_alphanumeric())
+        .map(str::to_lowercase)
+    {
+        if word.chars().count() >= 3
+            && !GOAL_WORDS.contains(&word.as_str())
+            && !keywords.contains(&word)
+        {
+            keywords.push(word);
+        }
+    }
+    keywords
+}
+
+impl GoalModify {
+    /// Trimmed, with the keywords taken from the description if it names none
+    pub fn validate(&mut self) -> Result<(), String> {
+        self.description = self.description.trim().to_string();
+        if self.description.is_empty() {
+            return Err("A goal needs a description".to_string());
+        }
+        if self.description.chars().count() > MAX_DESCRIPTION_CHARS {
+            return Err(format!(
+                "Goals are described in {} characters at most",
+                MAX_DESCRIPTION_CHARS
+            ));
+        }
+        self.keywords = self
+            .keywords
+            .iter()
+            .map(|keyword| keyword.trim().to_lowercase())
+            .filter(|keyword| !keyword.is_empty())
+            .collect();
+        if self.keywords.is_empty() {
+            self.keywords = keywords_of(&self.description);
+        }
+        if self.keywords.is_empty() {
+            return Err("Name the words the goal is about in keywords".to_string());
+        }
+        if let Some(progress) = self.progress {
+            if !(0.0..=1.0).contains(&progress) {
+                return Err("Progress goes from 0 to 1".to_string());
+            }
+            // A goal at full progress is achieved unless it was given up
+            if progress >= 1.0
+                && self
+                    .status
+                    .map_or(true, |status| status == GoalStatus::Active)
+            {
+                self.status = Some(GoalStatus::Achieved);
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Whether a keyword comes up in `text`, in any of its forms for a single word ("job", "jobs")
+fn mentions(text: &str, words: &[&str], keyword: &str) -> bool {
+    if keyword.contains(' ') {
+        return text.contains(keyword);
+    }
+    words.iter().any(|word| {
+        word.starts_with(keyword) && word.chars().count() <= keyword.chars().count() + 3
+    })
+}
+
+fn share_mentioned(keywords: &[String], text: &str) -> f32 {
+    let text = text.to_lowercase();
+    let words: Vec<&str> = text
+        .split(|c: char| !c.is_alphanumeric())
+        .filter(|word| !word.is_empty())
+        .collect();
+    let mentioned = keywords
+        .iter()
+        .filter(|keyword| mentions(&text, &words, keyword))
+        .count();
+    mentioned as f32 / keywords.len().max(1) as f32
+}
+
+/// Progress an exchange makes toward a goal with these keywords, 0 if it doesn't touch the goal
+pub fn score(keywords: &[String], user_text: &str, reply: &str) -> f32 {
+    let user = share_mentioned(keywords, user_text);
+    let companion = share_mentioned(keywords, reply);
+    PROGRESS_STEP * (USER_WEIGHT * user + companion) / (USER_WEIGHT + 1.0)
+}
+
+/// The goals for the prompt, the most important first, None without any
+pub fn prompt_line(goals: &[Goal], companion_name: &str) -> Option<String> {
+    let goals: Vec<String> = goals
+        .iter()
+        .filter(|goal| goal.status == GoalStatus::Active)
+        .map(|goal| match (goal.progress * 100.0).round() as i32 {
+            0 => format!("{} (not started)", goal.description),
+            percent => format!("{} ({}% there)", goal.description, percent),
+        })
+        .collect();
+    if goals.is_empty() {
+        return None;
+    }
+    Some(format!(
+        "{}'s goals, to steer the conversation toward when it fits naturally: {}.",
+        companion_name,
+        goals.join("; ")
+    ))
+}
+
+pub struct Goals {}
+
+impl Goals {
+    pub fn create() -> Result<()> {
+        let con = db_pool::connection()?;
+        con.execute(
+            "CREATE TABLE IF NOT EXISTS companion_goals (
+                id INTEGER PRIMARY KEY AUTOINCREMENT,
+                companion_id INTEGER NOT NULL,
+                description TEXT NOT NULL,
+                keywords TEXT NOT NULL DEFAULT '[]',
+                priority INTEGER NOT NULL DEFAULT 100,
+                progress REAL NOT NULL DEFAULT 0,
+                status TEXT NOT NULL DEFAULT 'active',
+                created_at TEXT NOT NULL,
+                updated_at TEXT NOT NULL,
+                last_progress_at TEXT
+            )",
+            [],
+        )?;
+        con.execute(
+            "CREATE INDEX IF NOT EXISTS idx_companion_goals_companion ON companion_goals(companion_id, status)",
+            [],
+        )?;
+        Ok(())
+    }
+
+    fn from_row(row: &rusqlite::Row) -> Result<Goal> {
+        let keywords: String = row.get(3)?;
+        let status: String = row.get(6)?;
+        Ok(Goal {
+            id: row.get(0)?,
+            companion_id: row.get(1)?,
+            description: row.get(2)?,
+            keywords: serde_json::from_str(&keywords).map_err(|e| {
+                Error::FromSqlConversionFailure(3, rusqlite::types::Type::Text, Box::new(e))
+            })?,
+            priority: row.get(4)?,
+            progress: row.get(5)?,
+            status: GoalStatus::from_name(&status).unwrap_or(GoalStatus::Active),
+            created_at: row.get(7)?,
+            updated_at: row.get(8)?,
+            last_progress_at: row.get(9)?,
+        })
+    }
+
+    /// Goals of a companion, the active ones first and among them the highest priority
+    pub fn list(companion_id: i32, status: Option<GoalStatus>) -> Result<Vec<Goal>> {
+        let con = db_pool::connection()?;
+        let mut stmt = con.prepare(
+            "SELECT id, companion_id, description, keywords, priority, progress, status,
+                created_at, updated_at, last_progress_at
+             FROM companion_goals WHERE companion_id = ? AND (? IS NULL OR status = ?)
+             ORDER BY status != 'active', priority DESC, id",
+        )?;
+        let status = status.map(GoalStatus::name);
+        let rows = stmt.query_map(params![companion_id, status, status], Goals::from_row)?;
+        rows.collect()
+    }
+
+    /// Goals the companion pursues right now
+    pub fn active(companion_id: i32) -> Result<Vec<Goal>> {
+        Goals::list(companion_id, Some(GoalStatus::Active))
+    }
+
+    pub fn get(id: i32) -> Result<Option<Goal>> {
+        let con = db_pool::connection()?;
+        con.query_row(
+            "SELECT id, companion_id, description, keywords, priority, progress, status,
+                created_at, updated_at, last_progress_at
+             FROM companion_goals WHERE id = ?",
+            [id],
+            Goals::from_row,
+        )
+        .optional()
+    }
+
+    pub fn add(companion_id: i32, goal: &GoalModify) -> Result<i32> {
+        let con = db_pool::connection()?;
+        con.execute(
+            "INSERT INTO companion_goals (companion_id, description, keywords, priority, progress, status, created_at, updated_at)
+             VALUES (?, ?, ?, ?, ?, ?, ?, ?)",
+            params![
+                companion_id,
+                goal.description,
+                serde_json::json!(goal.keywords).to_string(),
+                goal.priority,
+                goal.progress.unwrap_or(0.0),
+                goal.status.unwrap_or(GoalStatus::Active).name(),
+                get_current_date(),
+                get_current_date()
+            ],
+        )?;
+        Ok(con.last_insert_rowid() as i32)
+    }
+
+    pub fn edit(id: i32, goal: &GoalModify) -> Result<bool> {
+        let con = db_pool::connection()?;
+        let changed = con.execute(
+            "UPDATE companion_goals SET description = ?, keywords = ?, priority = ?,
+                progress = COALESCE(?, progress), status = COALESCE(?, status), updated_at = ?
+             WHERE id = ?",
+            params![
+                goal.description,
+                serde_json::json!(goal.keywords).to_string(),
+                goal.priority,
+                goal.progress,
+                goal.status.map(GoalStatus::name),
+                get_current_date(),
+                id
+            ],
+        )?;
+        Ok(changed > 0)
+    }
+
+    pub fn delete(id: i32) -> Result<bool> {
+        let con = db_pool::connection()?;
+        let changed = con.execute("DELETE FROM companion_goals WHERE id = ?", [id])?;
+        Ok(changed > 0)
+    }
+
+    /// Score the active goals against an exchange, returns the goals it moved along
+    ///
+    /// A goal that reaches full progress is achieved and leaves the prompt.
+    pub fn follow(companion_id: i32, user_text: &str, reply: &str) -> Result<Vec<Goal>> {
+        let mut moved = Vec::new();
+        for mut goal in Goals::active(companion_id)? {
+            let gain = score(&goal.keywords, user_text, reply);
+            if gain <= 0.0 {
+                continue;
+            }
+            goal.progress = (goal.progress + gain).min(1.0);
+            if goal.progress >= 1.0 {
+                goal.status = GoalStatus::Achieved;
+            }
+            let now = get_current_date();
+            let con = db_pool::connection()?;
+            con.execute(
+                "UPDATE companion_goals SET progress = ?, status = ?, last_progress_at = ? WHERE id = ?",
+                params![goal.progress, goal.status.name(), now, goal.id],
+            )?;
+            goal.last_progress_at = Some(now);
+            event_bus::publish(
+                "goal_progress",
+                serde_json::json!({
+                    "goal_id": goal.id,
+                    "companion_id": companion_id,
+                    "description": goal.description,
+                    "progress": goal.progress,
+                    "status": goal.status,
+                }),
+            );
+            moved.push(goal);
+        }
+        Ok(moved)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn goal(description: &str, progress: f32, status: GoalStatus) -> Goal {
+        Goal {
+            id: 1,
+            companion_id: 1,
+            description: description.to_string(),
+            keywords: keywords_of(description),
+            priority: 100,
+            progress,
+            status,
+            created_at: String::new(),
+            updated_at: String::new(),
+            last_progress_at: None,
+        }
+    }
+
+    #[test]
+    fn test_goal_keywords_and_score() {
+        assert_eq!(keywords_of("Learn more about {{user}}'s job"), vec!["job"]);
+        assert_eq!(
+            keywords_of("Reconcile with Alice"),
+            vec!["reconcile", "alice"]
+        );
+
+        let job = keywords_of("Learn more about {{user}}'s job");
+        // The user talking about it counts twice as much as the companion asking
+        let asked = score(&job, "Hi there", "How are things at your job?");
+        let told = score(&job, "My new jobs are exhausting", "Oh no");
+        assert!(asked > 0.0 && told > asked);
+        assert!(
+            (score(&job, "The job is fine", "Tell me about your job") - PROGRESS_STEP).abs() < 1e-6
+        );
+        assert_eq!(score(&job, "Let's talk about joblessness", "Sure"), 0.0);
+        assert_eq!(score(&job, "I like jogging", "Me too"), 0.0);
+        // Half of the keywords for half the progress
+        let alice = keywords_of("Reconcile with Alice");
+        assert!((score(&alice, "Alice called", "") - PROGRESS_STEP / 3.0).abs() < 1e-6);
+
+        let mut modify = GoalModify {
+            description: "  Reconcile with Alice ".to_string(),
+            keywords: vec![" ".to_string()],
+            priority: 100,
+            progress: Some(1.0),
+            status: None,
+        };
+        assert!(modify.validate().is_ok());
+        assert_eq!(modify.description, "Reconcile with Alice");
+        assert_eq!(modify.keywords, vec!["reconcile", "alice"]);
+        assert_eq!(modify.status, Some(GoalStatus::Achieved));
+        modify.description = "Learn more about them".to_string();
+        modify.keywords.clear();
+        assert!(modify.validate().is_err());
+    }
+
+    #[test]
+    fn test_goal_prompt_line() {
+        let goals = vec![
+            goal("learn more about {{user}}'s job", 0.3, GoalStatus::Active),
+            goal("reconcile with Alice", 0.0, GoalStatus::Active),
+            goal("visit the lighthouse", 1.0, GoalStatus::Achieved),
+        ];
+        assert_eq!(
+            prompt_line(&goals, "Luna").unwrap(),
+            "Luna's goals, to steer the conversation toward when it fits naturally: \
+             learn more about {{user}}'s job (30% there); reconcile with Alice (not started)."
+        );
+        assert_eq!(prompt_line(&goals[2..], "Luna"), None);
+    }
+}
@@ -0,0 +1,703 @@
+use crate::database::{ConfigView, Device};
+use crate::gpu_allocator::{AllocationStrategy, GpuMemoryInfo, LayerAllocation};
+use crate::system_memory::{SystemMemoryDetector, SystemMemoryInfo};
+use serde::Serialize;
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{self, BufReader, Read};
+use std::path::Path;
+use std::process::Command;
+use std::sync::Mutex;
+
+const GGUF_MAGIC: &[u8; 4] = b"GGUF";
+const DEFAULT_ALIGNMENT: u64 = 32;
+// Scratch buffers the backend allocates on the GPU next to the weights and the KV cache
+const COMPUTE_RESERVE_MB: f64 = 256.0;
+// Share of unified memory macOS lets the GPU wire, close to recommendedMaxWorkingSetSize
+const METAL_WORKING_SET_RATIO: f64 = 2.0 / 3.0;
+const MB: f64 = 1024.0 * 1024.0;
+
+lazy_static::lazy_static! {
+    static ref LAST_PLAN: Mutex<Option<LayerPlan>> = Mutex::new(None);
+}
+
+/// Shape of a GGUF model, read from its header without loading the weights
+#[derive(Clone, Debug, Default, Serialize)]
+pub struct GgufModel {
+    pub architecture: String,
+    pub block_count: usize,
+    pub embedding_length: u64,
+    pub head_count: u64,
+    pub head_count_kv: u64,
+    pub context_length: u64,
+    pub tensor_bytes: u64,
+    /// Weights of the largest `blk.N` layer, the unit gpu_layers offloads
+    pub layer_bytes: u64,
+}
+
+/// What a GGUF file tells about the model besides its shape
+#[derive(Clone, Debug, Default, Serialize)]
+pub struct GgufMetadata {
+    pub architecture: String,
+    pub name: Option<String>,
+    /// Jinja template the model's chats were formatted with in training
+    pub chat_template: Option<String>,
+}
+
+impl GgufModel {
+    /// KV cache of one layer at F16 for `context_tokens` tokens
+    pub fn kv_cache_bytes_per_layer(&self, context_tokens: usize) -> u64 {
+        let head_count = self.head_count.max(1);
+        let head_count_kv = if self.head_count_kv > 0 { self.head_count_kv } else { head_count };
+        let kv_embedding = self.embedding_length * head_count_kv / head_count;
+        2 * context_tokens as u64 * kv_embedding * 2
+    }
+}
+
+/// Outcome of sizing gpu_layers against the measured VRAM
+#[derive(Clone, Debug, Serialize)]
+pub struct LayerPlan {
+    pub device_name: String,
+    pub free_vram_mb: u64,
+    pub budget_mb: u64,
+    pub layer_mb: f64,
+    pub kv_cache_mb_per_layer: f64,
+    pub gpu_layers: usize,
+    pub total_layers: usize,
+    pub estimated_vram_mb: u64,
+}
+
+impl LayerPlan {
+    pub fn allocation(&self) -> LayerAllocation {
+        let allocation_strategy = if self.gpu_layers == 0 {
+            AllocationStrategy::CpuFallback
+        } else if self.gpu_layers >= self.total_layers {
+            AllocationStrategy::MaxGpu
+        } else if self.gpu_layers >= self.total_layers / 2 {
+            AllocationStrategy::Balanced
+        } else {
+            AllocationStrategy::Conservative
+        };
+        LayerAllocation {
+            gpu_layers: self.gpu_layers,
+            cpu_layers: self.total_layers.saturating_sub(self.gpu_layers),
+            total_layers: self.total_layers,
+            estimated_vram_usage_mb: self.estimated_vram_mb,
+            allocation_strategy,
+        }
+    }
+}
+
+impl std::fmt::Display for LayerPlan {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{}/{} layers on {} ({:.0}MB per layer + {:.0}MB KV cache, {}MB of {}MB free used)",
+            self.gpu_layers,
+            self.total_layers,
+            self.device_name,
+            self.layer_mb,
+            self.kv_cache_mb_per_layer,
+            self.estimated_vram_mb,
+            self.free_vram_mb
+        )
+    }
+}
+
+/// Read the layer count and tensor sizes from the header of a GGUF file
+pub fn read_gguf(path: &Path) -> io::Result<GgufModel> {
+    let file = File::open(path)?;
+    let file_len = file.metadata()?.len();
+    parse_gguf(BufReader::new(file), file_len)
+}
+
+fn invalid(message: impl Into<String>) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message.into())
+}
+
+/// Values of the metadata keys the plan needs, everything else is skipped
+enum GgufValue {
+    Unsigned(u64),
+    Signed(i64),
+    Text(String),
+    Other,
+}
+
+impl GgufValue {
+    fn as_u64(&self) -> Option<u64> {
+        match self {
+            GgufValue::Unsigned(value) => Some(*value),
+            GgufValue::Signed(value) => u64::try_from(*value).ok(),
+            _ => None,
+        }
+    }
+}
+
+struct GgufReader<R> {
+    inner: R,
+    position: u64,
+}
+
+impl<R: Read> GgufReader<R> {
+    fn bytes<const N: usize>(&mut self) -> io::Result<[u8; N]> {
+        let mut buffer = [0u8; N];
+        self.inner.read_exact(&mut buffer)?;
+        self.position += N as u64;
+        Ok(buffer)
+    }
+
+    fn u32(&mut self) -> io::Result<u32> {
+        self.bytes().map(u32::from_le_bytes)
+    }
+
+    fn u64(&mut self) -> io::Result<u64> {
+        self.bytes().map(u64::from_le_bytes)
+    }
+
+    fn skip(&mut self, count: u64) -> io::Result<()> {
+        let skipped = io::copy(&mut (&mut self.inner).take(count), &mut io::sink())?;
+        self.position += skipped;
+        if skipped < count {
+            return Err(io::ErrorKind::UnexpectedEof.into());
+        }
+        Ok(())
+    }
+
+    fn string(&mut self) -> io::Result<String> {
+        let len = self.u64()?;
+        if len > 1 << 20 {
+            return Err(invalid("GGUF string is too long"));
+        }
+        let mut buffer = vec![0u8; len as usize];
+        self.inner.read_exact(&mut buffer)?;
+        self.position += len;
+        Ok(String::from_utf8_lossy(&buffer).into_owned())
+    }
+
+    fn value(&mut self, value_type: u32) -> io::Result<GgufValue> {
+        Ok(match value_type {
+            0 => GgufValue::Unsigned(self.bytes::<1>()?[0] as u64),
+            1 => GgufValue::Signed(self.bytes::<1>()?[0] as i8 as i64),
+            2 => GgufValue::Unsigned(u16::from_le_bytes(self.bytes()?) as u64),
+            3 => GgufValue::Signed(i16::from_le_bytes(self.bytes()?) as i64),
+            4 => GgufValue::Unsigned(self.u32()? as u64),
+            5 => GgufValue::Signed(i32::from_le_bytes(self.bytes()?) as i64),
+            6 => {
+                self.skip(4)?;
+                GgufValue::Other
+            }
+            7 => {
+                self.skip(1)?;
+                GgufValue::Other
+            }
+            8 => GgufValue::Text(self.string()?),
+            9 => {
+                let item_type = self.u32()?;
+                let len = self.u64()?;
+                match item_type {
+                    0 | 1 | 7 => self.skip(len)?,
+                    // Vocabularies hold a string for every token, they are skipped unread
+                    8 => {
+                        for _ in 0..len {
+                            let string_len = self.u64()?;
+                            self.skip(string_len)?;
+                        }
+                    }
+                    2 | 3 => self.skip(len * 2)?,
+                    4 | 5 | 6 => self.skip(len * 4)?,
+                    10 | 11 | 12 => self.skip(len * 8)?,
+                    _ => {
+                        for _ in 0..len {
+                            self.value(item_type)?;
+                        }
+                    }
+                }
+                GgufValue::Other
+            }
+            10 => GgufValue::Unsigned(self.u64()?),
+            11 => GgufValue::Signed(i64::from_le_bytes(self.bytes()?)),
+            12 => {
+                self.skip(8)?;
+                GgufValue::Other
+            }
+            other => return Err(invalid(format!("unknown GGUF value type {}", other))),
+        })
+    }
+}
+
+/// Layer number of a tensor like `blk.12.attn_q.weight`
+fn block_index(tensor_name: &str) -> Option<usize> {
+    tensor_name.strip_prefix("blk.")?.split('.').next()?.parse().ok()
+}
+
+/// Number of tensors and the metadata of a GGUF header, the reader stops at the tensor infos
+fn read_header<R: Read>(reader: &mut GgufReader<R>) -> io::Result<(u64, HashMap<String, GgufValue>)> {
+    if &reader.bytes::<4>()? != GGUF_MAGIC {
+        return Err(invalid("not a GGUF file"));
+    }
+    let version = reader.u32()?;
+    if !(2..=3).contains(&version) {
+        return Err(invalid(format!("unsupported GGUF version {}", version)));
+    }
+    let tensor_count = reader.u64()?;
+    let kv_count = reader.u64()?;
+
+    let mut metadata = HashMap::new();
+    for _ in 0..kv_count {
+        let key = reader.string()?;
+        let value_type = reader.u32()?;
+        let value = reader.value(value_type)?;
+        if !matches!(value, GgufValue::Other) {
+            metadata.insert(key, value);
+        }
+    }
+    Ok((tensor_count, metadata))
+}
+
+fn text(metadata: &HashMap<String, GgufValue>, key: &str) -> Option<String> {
+    match metadata.get(key) {
+        Some(GgufValue::Text(text)) => Some(text.clone()),
+        _ => None,
+    }
+}
+
+/// Read the architecture, name and chat template from the header of a GGUF file
+pub fn read_gguf_metadata(path: &Path) -> io::Result<GgufMetadata> {
+    parse_gguf_metadata(BufReader::new(File::open(path)?))
+}
+
+fn parse_gguf_metadata<R: Read>(reader: R) -> io::Result<GgufMetadata> {
+    let mut reader = GgufReader { inner: reader, position: 0 };
+    let (_, metadata) = read_header(&mut reader)?;
+    Ok(GgufMetadata {
+        architecture: text(&metadata, "general.architecture").unwrap_or_else(|| String::from("llama")),
+        name: text(&metadata, "general.name"),
+        chat_template: text(&metadata, "tokenizer.chat_template"),
+    })
+}
+
+fn parse_gguf<R: Read>(reader: R, file_len: u64) -> io::Result<GgufModel> {
+    let mut reader = GgufReader { inner: reader, position: 0 };
+    let (tensor_count, metadata) = read_header(&mut reader)?;
+
+    let mut tensors = Vec::new();
+    for _ in 0..tensor_count {
+        let name = reader.string()?;
+        let n_dims = reader.u32()?;
+        reader.skip(n_dims as u64 * 8)?;
+        let _tensor_type = reader.u32()?;
+        let offset = reader.u64()?;
+        tensors.push((offset, name));
+    }
+
+    let alignment = metadata
+        .get("general.alignment")
+        .and_then(GgufValue::as_u64)
+        .filter(|alignment| *alignment > 0)
+        .unwrap_or(DEFAULT_ALIGNMENT);
+    let data_start = reader.position.div_ceil(alignment) * alignment;
+    let data_len = file_len
+        .checked_sub(data_start)
+        .ok_or_else(|| invalid("GGUF file is truncated"))?;
+
+    // Tensors are stored back to back, each one runs until the next begins
+    tensors.sort();
+    let mut block_bytes: HashMap<usize, u64> = HashMap::new();
+    for (i, (offset, name)) in tensors.iter().enumerate() {
+        let end = tensors.get(i + 1).map_or(data_len, |(next, _)| *next);
+        let size = end.saturating_sub(*offset);
+        if let Some(block) = block_index(name) {
+            *block_bytes.entry(block).or_default() += size;
+        }
+    }
+
+    let architecture = text(&metadata, "general.architecture").unwrap_or_else(|| String::from("llama"));
+    let number = |key: &str| {
+        metadata
+            .get(&format!("{}.{}", architecture, key))
+            .and_then(GgufValue::as_u64)
+            .unwrap_or(0)
+    };
+    let block_count = match number("block_count") {
+        0 => block_bytes.len(),
+        count => count as usize,
+    };
+    if block_count == 0 {
+        return Err(invalid("GGUF file has no layers"));
+    }
+
+    Ok(GgufModel {
+        block_count,
+        embedding_length: number("embedding_length"),
+        head_count: number("attention.head_count"),
+        head_count_kv: number("attention.head_count_kv"),
+        context_length: number("context_length"),
+        tensor_bytes: data_len,
+        layer_bytes: block_bytes.values().copied().max().unwrap_or(data_len / block_count as u64),
+        architecture,
+    })
+}
+
+/// Measure the VRAM of the configured device, None when no vendor tool answers
+pub fn probe_vram(device: &Device) -> Option<GpuMemoryInfo> {
+    match device {
+        Device::GPU => probe_nvidia().or_else(probe_rocm),
+        Device::Metal => probe_metal(),
+        Device::CPU => None,
+    }
+}
+
+fn command_output(program: &str, args: &[&str]) -> Option<String> {
+    let output = Command::new(program).args(args).output().ok()?;
+    if !output.status.success() {
+        return None;
+    }
+    Some(String::from_utf8_lossy(&output.stdout).into_owned())
+}
+
+/// nvidia-smi reports what NVML sees, free memory included
+fn probe_nvidia() -> Option<GpuMemoryInfo> {
+    let stdout = command_output(
+        "nvidia-smi",
+        &[
+            "--query-gpu=memory.total,memory.used,memory.free,utilization.gpu,name,driver_version",
+            "--format=csv,noheader,nounits",
+        ],
+    )?;
+    parse_nvidia_smi(&stdout)
+}
+
+fn parse_nvidia_smi(stdout: &str) -> Option<GpuMemoryInfo> {
+    let parts: Vec<&str> = stdout.lines().next()?.split(',').map(str::trim).collect();
+    if parts.len() < 6 {
+        return None;
+    }
+    Some(GpuMemoryInfo {
+        total_vram_mb: parts[0].parse().ok()?,
+        used_vram_mb: parts[1].parse().ok()?,
+        available_vram_mb: parts[2].parse().ok()?,
+        utilization_percent: parts[3].parse().unwrap_or(0.0),
+        device_name: parts[4].to_string(),
+        driver_version: parts[5].to_string(),
+    })
+}
+
+fn probe_rocm() -> Option<GpuMemoryInfo> {
+    let stdout = command_output("rocm-smi", &["--showmeminfo", "vram", "--json"])?;
+    parse_rocm_smi(&stdout)
+}
+
+fn parse_rocm_smi(stdout: &str) -> Option<GpuMemoryInfo> {
+    let cards: serde_json::Map<String, serde_json::Value> = serde_json::from_str(stdout).ok()?;
+    let (card, memory) = cards.iter().find(|(name, _)| name.starts_with("card"))?;
+    let bytes = |key: &str| -> Option<u64> { memory.get(key)?.as_str()?.parse().ok() };
+    let total = bytes("VRAM Total Memory (B)")?;
+    let used = bytes("VRAM Total Used Memory (B)")?;
+    Some(GpuMemoryInfo {
+        total_vram_mb: total / 1024 / 1024,
+        available_vram_mb: total.saturating_sub(used) / 1024 / 1024,
+        used_vram_mb: used / 1024 / 1024,
+        utilization_percent: (used as f32 / total.max(1) as f32) * 100.0,
+        device_name: format!("AMD GPU ({})", card),
+        driver_version: String::from("ROCm"),
+    })
+}
+
+/// Apple GPUs share system memory, only part of which Metal may wire
+fn probe_metal() -> Option<GpuMemoryInfo> {
+    let memsize: u64 = command_output("sysctl", &["-n", "hw.memsize"])?.trim().parse().ok()?;
+    let total_vram_mb = ((memsize as f64 * METAL_WORKING_SET_RATIO) / MB) as u64;
+    Some(GpuMemoryInfo {
+        total_vram_mb,
+        available_vram_mb: total_vram_mb,
+        used_vram_mb: 0,
+        utilization_percent: 0.0,
+        device_name: String::from("Apple GPU"),
+        driver_version: String::from("macOS"),
+    })
+}
+
+/// Fit as many layers as the free VRAM allows once the margins and scratch space are set aside
+pub fn plan_gpu_layers(
+    gpu_info: &GpuMemoryInfo,
+    model: &GgufModel,
+    context_tokens: usize,
+    vram_limit_gb: usize,
+    safety_margin: f32,
+    min_free_vram_mb: u64,
+) -> LayerPlan {
+    let mut free_mb = gpu_info.available_vram_mb;
+    if vram_limit_gb > 0 {
+        free_mb = free_mb.min(vram_limit_gb as u64 * 1024);
+    }
+    let budget_mb = ((free_mb as f64 * safety_margin.clamp(0.1, 1.0) as f64)
+        - min_free_vram_mb as f64
+        - COMPUTE_RESERVE_MB)
+        .max(0.0);
+
+    let layer_mb = model.layer_bytes as f64 / MB;
+    let kv_cache_mb_per_layer = model.kv_cache_bytes_per_layer(context_tokens) as f64 / MB;
+    let per_layer_mb = layer_mb + kv_cache_mb_per_layer;
+    let gpu_layers = if per_layer_mb > 0.0 {
+        ((budget_mb / per_layer_mb) as usize).min(model.block_count)
+    } else {
+        0
+    };
+    let estimated_vram_mb = if gpu_layers > 0 {
+        (gpu_layers as f64 * per_layer_mb + COMPUTE_RESERVE_MB).ceil() as u64
+    } else {
+        0
+    };
+
+    LayerPlan {
+        device_name: gpu_info.device_name.clone(),
+        free_vram_mb: gpu_info.available_vram_mb,
+        budget_mb: budget_mb as u64,
+        layer_mb,
+        kv_cache_mb_per_layer,
+        gpu_layers,
+        total_layers: model.block_count,
+        estimated_vram_mb,
+    }
+}
+
+/// Probe the device and size gpu_layers for the configured model and context window
+pub fn plan_for_config(config: &ConfigView) -> Result<LayerPlan, String> {
+    let gpu_info = probe_vram(&config.device)
+        .ok_or_else(|| format!("no VRAM probe available for {}", config.device))?;
+    let model = read_gguf(Path::new(&config.llm_model_path))
+        .map_err(|e| format!("could not read model metadata: {}", e))?;
+    Ok(plan_gpu_layers(
+        &gpu_info,
+        &model,
+        config.context_window_size,
+        config.vram_limit_gb,
+        config.gpu_safety_margin,
+        config.min_free_vram_mb,
+    ))
+}
+
+/// Everything /api/hardware knows about the machine and the configured model
+#[derive(Clone, Debug, Serialize)]
+pub struct HardwareReport {
+    pub configured_device: String,
+    /// Device the GPU found belongs to, "GPU" for CUDA and ROCm or "Metal"
+    pub detected_device: Option<String>,
+    pub gpu: Option<GpuMemoryInfo>,
+    pub system_memory: Option<SystemMemoryInfo>,
+    pub cpu_cores: usize,
+    pub model_path: String,
+    pub model: Option<GgufModel>,
+    pub configured_gpu_layers: usize,
+    /// gpu_layers that fit the free VRAM with gpu_safety_margin applied
+    pub recommended_gpu_layers: Option<usize>,
+    pub plan: Option<LayerPlan>,
+    pub warnings: Vec<String>,
+}
+
+/// First GPU a vendor tool reports, whatever device is configured
+fn detect_gpu() -> Option<(Device, GpuMemoryInfo)> {
+    if let Some(gpu) = probe_vram(&Device::GPU) {
+        return Some((Device::GPU, gpu));
+    }
+    if cfg!(target_os = "macos") {
+        return probe_vram(&Device::Metal).map(|gpu| (Device::Metal, gpu));
+    }
+    None
+}
+
+/// Probe GPU, RAM and the model file and recommend gpu_layers for them
+pub fn report(config: &ConfigView) -> HardwareReport {
+    let mut warnings = Vec::new();
+    let detected = detect_gpu();
+    let system_memory = SystemMemoryDetector::new().detect_system_memory().ok();
+    let model = match read_gguf(Path::new(&config.llm_model_path)) {
+        Ok(model) => Some(model),
+        Err(e) => {
+            warnings.push(format!("Could not read model metadata: {}", e));
+            None
+        }
+    };
+
+    let plan = match (&detected, &model) {
+        (Some((_, gpu)), Some(model)) => Some(plan_gpu_layers(
+            gpu,
+            model,
+            config.context_window_size,
+            config.vram_limit_gb,
+            config.gpu_safety_margin,
+            config.min_free_vram_mb,
+        )),
+        _ => None,
+    };
+    match &detected {
+        Some((device, _)) if config.device == Device::CPU => warnings.push(format!(
+            "A GPU was found but the device is set to CPU, set it to {} to use it",
+            device
+        )),
+        None if config.device != Device::CPU => {
+            warnings.push(String::from("No GPU could be measured, gpu_layers can't be recommended"))
+        }
+        _ => {}
+    }
+    // Layers left on the CPU are read from system memory
+    if let (Some(model), Some(memory)) = (&model, &system_memory) {
+        let gpu_layers = plan.as_ref().map_or(0, |plan| plan.gpu_layers);
+        let cpu_layers = model.block_count.saturating_sub(gpu_layers) as f64;
+        let cpu_gb = cpu_layers * model.layer_bytes as f64 / MB / 1024.0;
+        if cpu_gb > memory.available_ram_gb as f64 {
+            warnings.push(format!(
+                "The layers on the CPU need {:.1}GB but only {:.1}GB of RAM is available",
+                cpu_gb, memory.available_ram_gb
+            ));
+        }
+    }
+
+    HardwareReport {
+        configured_device: config.device.to_string(),
+        detected_device: detected.as_ref().map(|(device, _)| device.to_string()),
+        gpu: detected.map(|(_, gpu)| gpu),
+        system_memory,
+        cpu_cores: std::thread::available_parallelism().map_or(1, |n| n.get()),
+        model_path: config.llm_model_path.clone(),
+        model,
+        configured_gpu_layers: config.gpu_layers,
+        recommended_gpu_layers: plan.as_ref().map(|plan| plan.gpu_layers),
+        plan,
+        warnings,
+    }
+}
+
+/// Keep the plan the model was last loaded with, estimates and /api/gpu/allocation report it
+pub fn remember(plan: &LayerPlan) {
+    if let Ok(mut last) = LAST_PLAN.lock() {
+        *last = Some(plan.clone());
+    }
+}
+
+pub fn last_plan() -> Option<LayerPlan> {
+    LAST_PLAN.lock().ok().and_then(|last| last.clone())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn string(out: &mut Vec<u8>, value: &str) {
+        out.extend_from_slice(&(value.len() as u64).to_le_bytes());
+        out.extend_from_slice(value.as_bytes());
+    }
+
+    fn u32_kv(out: &mut Vec<u8>, key: &str, value: u32) {
+        string(out, key);
+        out.extend_from_slice(&4u32.to_le_bytes());
+        out.extend_from_slice(&value.to_le_bytes());
+    }
+
+    /// Two layer model with 1000 bytes per block tensor and a tokenizer array to skip
+    fn tiny_gguf() -> Vec<u8> {
+        let tensors = [
+            ("token_embd.weight", 0u64),
+            ("blk.0.attn_q.weight", 4000),
+            ("blk.0.ffn_up.weight", 5000),
+            ("blk.1.attn_q.weight", 6000),
+            ("blk.1.ffn_up.weight", 7000),
+            ("output.weight", 8000),
+        ];
+        let mut out = Vec::new();
+        out.extend_from_slice(b"GGUF");
+        out.extend_from_slice(&3u32.to_le_bytes());
+        out.extend_from_slice(&(tensors.len() as u64).to_le_bytes());
+        out.extend_from_slice(&7u64.to_le_bytes());
+        string(&mut out, "general.architecture");
+        out.extend_from_slice(&8u32.to_le_bytes());
+        string(&mut out, "llama");
+        string(&mut out, "tokenizer.ggml.tokens");
+        out.extend_from_slice(&9u32.to_le_bytes());
+        out.extend_from_slice(&8u32.to_le_bytes());
+        out.extend_from_slice(&2u64.to_le_bytes());
+        string(&mut out, "<s>");
+        string(&mut out, "</s>");
+        u32_kv(&mut out, "llama.block_count", 2);
+        u32_kv(&mut out, "llama.embedding_length", 4096);
+        u32_kv(&mut out, "llama.attention.head_count", 32);
+        u32_kv(&mut out, "llama.attention.head_count_kv", 8);
+        u32_kv(&mut out, "llama.context_length", 4096);
+        for (name, offset) in tensors {
+            string(&mut out, name);
+            out.extend_from_slice(&1u32.to_le_bytes());
+            out.extend_from_slice(&16u64.to_le_bytes());
+            out.extend_from_slice(&0u32.to_le_bytes());
+            out.extend_from_slice(&offset.to_le_bytes());
+        }
+        let data_start = (out.len() as u64).div_ceil(DEFAULT_ALIGNMENT) * DEFAULT_ALIGNMENT;
+        out.resize(data_start as usize + 12000, 0);
+        out
+    }
+
+    #[test]
+    fn test_parse_gguf() {
+        let bytes = tiny_gguf();
+        let model = parse_gguf(bytes.as_slice(), bytes.len() as u64).unwrap();
+        assert_eq!(model.architecture, "llama");
+        assert_eq!(model.block_count, 2);
+        let metadata = parse_gguf_metadata(bytes.as_slice()).unwrap();
+        assert_eq!(metadata.architecture, "llama");
+        assert_eq!(metadata.chat_template, None);
+        assert_eq!(model.head_count_kv, 8);
+        assert_eq!(model.context_length, 4096);
+        assert_eq!(model.tensor_bytes, 12000);
+        assert_eq!(model.layer_bytes, 2000);
+        // Grouped query attention keeps a quarter of the full KV width
+        assert_eq!(model.kv_cache_bytes_per_layer(1024), 2 * 1024 * 1024 * 2);
+
+        assert!(parse_gguf(&b"GGML"[..], 4).is_err());
+    }
+
+    #[test]
+    fn test_plan_gpu_layers() {
+        let model = GgufModel {
+            block_count: 32,
+            embedding_length: 4096,
+            head_count: 32,
+            head_count_kv: 8,
+            layer_bytes: 100 * 1024 * 1024,
+            ..Default::default()
+        };
+        let gpu_info = GpuMemoryInfo {
+            total_vram_mb: 8192,
+            available_vram_mb: 6144,
+            used_vram_mb: 2048,
+            utilization_percent: 25.0,
+            device_name: "Test GPU".to_string(),
+            driver_version: "1.0".to_string(),
+        };
+
+        let plan = plan_gpu_layers(&gpu_info, &model, 2048, 0, 0.9, 512);
+        assert_eq!(plan.total_layers, 32);
+        assert_eq!(plan.gpu_layers, 32);
+        assert!(plan.estimated_vram_mb <= plan.budget_mb + COMPUTE_RESERVE_MB as u64);
+
+        // A 2GB limit leaves room for only part of the model
+        let limited = plan_gpu_layers(&gpu_info, &model, 2048, 2, 0.9, 512);
+        assert!(limited.gpu_layers > 0 && limited.gpu_layers < 32);
+        assert_eq!(limited.allocation().cpu_layers, 32 - limited.gpu_layers);
+
+        let full = GpuMemoryInfo { available_vram_mb: 256, ..gpu_info };
+        assert_eq!(plan_gpu_layers(&full, &model, 2048, 0, 0.9, 512).gpu_layers, 0);
+    }
+
+    #[test]
+    fn test_parse_vendor_output() {
+        let nvidia = parse_nvidia_smi("24576, 1024, 23552, 3, NVIDIA GeForce RTX 4090, 550.54\n").unwrap();
+        assert_eq!(nvidia.available_vram_mb, 23552);
+        assert_eq!(nvidia.device_name, "NVIDIA GeForce RTX 4090");
+
+        let rocm = parse_rocm_smi(
+            r#"{"card0": {"VRAM Total Memory (B)": "17163091968", "VRAM Total Used Memory (B)": "1073741824"}}"#,
+        )
+        .unwrap();
+        assert_eq!(rocm.total_vram_mb, 16368);
+        assert_eq!(rocm.available_vram_mb, 16368 - 1024);
+    }
+}
@@ -0,0 +1,241 @@
+use crate::database::{Database, Device};
+use crate::hardware_probe;
+use crate::instance_lock::{self, InstanceStatus};
+use crate::llm;
+use crate::long_term_mem::LongTermMem;
+use crate::maintenance::{self, MaintenanceStatus};
+use crate::settings;
+use crate::shutdown;
+use crate::system_memory::SystemMemoryDetector;
+use serde::Serialize;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+// Measuring the VRAM starts the vendor's tool, health checks may poll more often than that is worth
+const RESOURCES_TTL: Duration = Duration::from_secs(10);
+// Opening the tantivy index reads it from disk, a broken one stays broken for a while
+const LONG_TERM_MEMORY_TTL: Duration = Duration::from_secs(60);
+
+lazy_static::lazy_static! {
+    static ref RESOURCES: Mutex<Option<(Instant, Resources)>> = Mutex::new(None);
+    // Companion the check was for, it changes with the active companion
+    static ref LONG_TERM_MEMORY: Mutex<Option<(Instant, i32, Check)>> = Mutex::new(None);
+}
+
+/// Whether a dependency works, with what went wrong if not
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct Check {
+    pub ok: bool,
+    pub error: Option<String>,
+}
+
+impl Check {
+    fn from_result<T, E: std::fmt::Display>(result: Result<T, E>) -> Check {
+        match result {
+            Ok(_) => Check {
+                ok: true,
+                error: None,
+            },
+            Err(e) => Check {
+                ok: false,
+                error: Some(e.to_string()),
+            },
+        }
+    }
+}
+
+#[derive(Serialize, Debug, Clone)]
+pub struct ModelHealth {
+    /// "local", or "remote" when replies come from a server
+    pub backend: &'static str,
+    /// State of the local model as /api/model/status reports it
+    pub state: String,
+    pub model_path: Option<String>,
+    /// Whether replies can be generated without waiting for the model
+    pub ready: bool,
+    pub error: Option<String>,
+}
+
+/// Free memory right now, None for what could not be measured
+#[derive(Serialize, Debug, Clone, Default)]
+pub struct Resources {
+    pub total_ram_gb: Option<f32>,
+    pub free_ram_gb: Option<f32>,
+    pub gpu: Option<String>,
+    pub total_vram_mb: Option<u64>,
+    pub free_vram_mb: Option<u64>,
+}
+
+/// Everything /api/health reports
+#[derive(Serialize, Debug)]
+pub struct Health {
+    /// "ok", "degraded", "unavailable" or "stopping"
+    pub status: &'static str,
+    pub database: bool,
+    pub long_term_memory: Check,
+    pub model: ModelHealth,
+    pub resources: Resources,
+    pub maintenance: MaintenanceStatus,
+    pub instance: InstanceStatus,
+}
+
+/// Whether the server should get traffic, with the reasons when it shouldn't
+#[derive(Serialize, Debug, Clone)]
+pub struct Readiness {
+    pub ready: bool,
+    pub reasons: Vec<String>,
+}
+
+/// Whether a local model in `state` is ready for replies
+///
+/// Without preloading the model is loaded with the first reply, so an unloaded one counts as ready.
+fn model_ready(remote: bool, state: &str, preload: bool) -> bool {
+    remote
+        || match state {
+            "loaded" => true,
+            "unloaded" => !preload,
+            _ => false,
+        }
+}
+
+/// The model's health, `remote` is None when the config can't be read
+fn model_health(remote: Option<bool>) -> ModelHealth {
+    let status = llm::model_status();
+    ModelHealth {
+        backend: if remote == Some(true) {
+            "remote"
+        } else {
+            "local"
+        },
+        ready: remote.is_some_and(|remote| {
+            model_ready(remote, &status.state, settings::get().preload_model)
+        }),
+        state: status.state,
+        model_path: status.model_path,
+        error: status.error,
+    }
+}
+
+fn measure(device: &Device) -> Resources {
+    let memory = SystemMemoryDetector::new().detect_system_memory().ok();
+    let gpu = hardware_probe::probe_vram(device);
+    Resources {
+        total_ram_gb: memory.as_ref().map(|memory| memory.total_ram_gb),
+        free_ram_gb: memory.as_ref().map(|memory| memory.available_ram_gb),
+        gpu: gpu.as_ref().map(|gpu| gpu.device_name.clone()),
+        total_vram_mb: gpu.as_ref().map(|gpu| gpu.total_vram_mb),
+        free_vram_mb: gpu.as_ref().map(|gpu| gpu.available_vram_mb),
+    }
+}
+
+fn resources(device: &Device) -> Resources {
+    let mut cached = RESOURCES.lock().unwrap_or_else(|e| e.into_inner());
+    if let Some((measured_at, resources)) = cached.as_ref() {
+        if measured_at.elapsed() < RESOURCES_TTL {
+            return resources.clone();
+        }
+    }
+    let resources = measure(device);
+    *cached = Some((Instant::now(), resources.clone()));
+    resources
+}
+
+/// Whether the active companion's long-term memory opens, checked at most once a minute
+fn long_term_memory() -> Check {
+    let companion_id = Database::active_companion_id();
+    let mut cached = LONG_TERM_MEMORY.lock().unwrap_or_else(|e| e.into_inner());
+    if let Some((checked_at, checked_companion, check)) = cached.as_ref() {
+        if *checked_companion == companion_id && checked_at.elapsed() < LONG_TERM_MEMORY_TTL {
+            return check.clone();
+        }
+    }
+    let check = Check::from_result(LongTermMem::open(companion_id));
+    *cached = Some((Instant::now(), companion_id, check.clone()));
+    check
+}
+
+/// Status of the server and what it depends on
+pub fn health() -> Health {
+    let config = Database::get_config().ok();
+    let long_term_memory = long_term_memory();
+    let model = model_health(
+        config
+            .as_ref()
+            .map(|config| !config.llm_api_url.trim().is_empty()),
+    );
+    let maintenance = maintenance::status();
+    // Heavy maintenance keeps the database busy, chat still works but may be slower
+    let status = if shutdown::is_stopping() {
+        "stopping"
+    } else if config.is_none() {
+        "unavailable"
+    } else if maintenance.heavy || !long_term_memory.ok || model.state == "failed" {
+        "degraded"
+    } else {
+        "ok"
+    };
+    Health {
+        status,
+        database: config.is_some(),
+        resources: resources(
+            config
+                .as_ref()
+                .map_or(&Device::CPU, |config| &config.device),
+        ),
+        long_term_memory,
+        model,
+        maintenance,
+        instance: instance_lock::status(),
+    }
+}
+
+/// Whether replies can be served right now, cheap enough to poll every few seconds
+pub fn readiness() -> Readiness {
+    let mut reasons = Vec::new();
+    if shutdown::is_stopping() {
+        reasons.push("The server is stopping".to_string());
+    }
+    let config = Database::get_config().ok();
+    if config.is_none() {
+        reasons.push("The database can't be read".to_string());
+    }
+    if let Some(e) = long_term_memory().error {
+        reasons.push(format!("The long-term memory can't be opened: {}", e));
+    }
+    let model = model_health(
+        config
+            .as_ref()
+            .map(|config| !config.llm_api_url.trim().is_empty()),
+    );
+    if config.is_some() && !model.ready {
+        reasons.push(match model.state.as_str() {
+            "loading" => "The model is still loading".to_string(),
+            "failed" => format!(
+                "The model failed to load: {}",
+                model.error.unwrap_or_default()
+            ),
+            _ => "The model is not loaded yet".to_string(),
+        });
+    }
+    Readiness {
+        ready: reasons.is_empty(),
+        reasons,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_model_ready() {
+        assert!(model_ready(false, "loaded", true));
+        assert!(!model_ready(false, "loading", false));
+        assert!(!model_ready(false, "failed", false));
+        // Loaded with the first reply unless it is preloaded
+        assert!(model_ready(false, "unloaded", false));
+        assert!(!model_ready(false, "unloaded", true));
+        // A server answers whatever the local model is doing
+        assert!(model_ready(true, "unloaded", true));
+    }
+}
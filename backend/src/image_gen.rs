@@ -0,0 +1,307 @@
+use crate::database::{CompanionView, ConfigView, Message};
+use crate::message_images::ImageFormat;
+use base64::Engine as _;
+use serde::{Deserialize, Serialize};
+use std::time::{Duration, Instant};
+
+// Added to every picture, the models are trained on tags like these
+const QUALITY_TAGS: &str = "high quality, detailed";
+const NEGATIVE_PROMPT: &str =
+    "lowres, blurry, bad anatomy, bad hands, extra fingers, deformed, watermark, text, signature";
+const STEPS: u32 = 25;
+const CFG_SCALE: f32 = 7.0;
+// Longest piece of a message used as the scene
+const MAX_SCENE_CHARS: usize = 300;
+// How long ComfyUI gets to finish a picture
+const COMFYUI_TIMEOUT: Duration = Duration::from_secs(300);
+
+/// What the picture shows
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum Shot {
+    /// The companion taking a picture of itself, portrait sized
+    #[default]
+    Selfie,
+    /// The companion where it is and what it does, landscape sized
+    Scene,
+}
+
+impl Shot {
+    fn size(&self) -> (u32, u32) {
+        match self {
+            Shot::Selfie => (512, 768),
+            Shot::Scene => (768, 512),
+        }
+    }
+}
+
+pub fn is_configured(config: &ConfigView) -> bool {
+    !config.image_gen_api_url.trim().is_empty()
+}
+
+/// Scene of a message, its roleplay actions between asterisks or else the start of its text
+pub fn scene_of(message: &Message) -> String {
+    let actions: Vec<&str> = message
+        .content
+        .split('*')
+        .skip(1)
+        .step_by(2)
+        .map(str::trim)
+        .filter(|action| !action.is_empty())
+        .collect();
+    let scene = if actions.is_empty() {
+        message.content.trim().to_string()
+    } else {
+        actions.join(", ")
+    };
+    scene.chars().take(MAX_SCENE_CHARS).collect()
+}
+
+/// What the picture is drawn from, also kept as its description so the companion knows what it sent
+pub fn describe(companion: &CompanionView, scene: &str, shot: Shot) -> String {
+    let mut parts = vec![match shot {
+        Shot::Selfie => format!("selfie of {}", companion.name),
+        Shot::Scene => companion.name.clone(),
+    }];
+    for part in [companion.appearance.trim(), scene.trim()] {
+        if !part.is_empty() {
+            parts.push(part.to_string());
+        }
+    }
+    if shot == Shot::Selfie {
+        parts.push("looking at the camera".to_string());
+    }
+    parts.join(", ")
+}
+
+fn is_comfyui(url: &str) -> bool {
+    url.trim_end_matches('/').ends_with("/prompt")
+}
+
+/// Draw a picture with the server set in image_gen_api_url
+///
+/// URLs ending in /prompt are taken for ComfyUI, which gets a plain text to image workflow and is
+/// asked for the result until it is done, anything else for the /sdapi/v1/txt2img endpoint of
+/// Stable Diffusion WebUI.
+pub fn generate(
+    config: &ConfigView,
+    description: &str,
+    shot: Shot,
+) -> Result<(Vec<u8>, ImageFormat), String> {
+    let url = config.image_gen_api_url.trim().trim_end_matches('/');
+    if url.is_empty() {
+        return Err(
+            "no image generator is configured, set image_gen_api_url in the config".to_string(),
+        );
+    }
+    let prompt = format!("{}, {}", description, QUALITY_TAGS);
+    let model = config.image_gen_model.trim();
+    let client = reqwest::blocking::Client::builder()
+        .timeout(Duration::from_secs(300))
+        .build()
+        .map_err(|e| e.to_string())?;
+    let image = if is_comfyui(url) {
+        comfyui(&client, url, &comfyui_workflow(model, &prompt, shot)?)?
+    } else {
+        let answer: serde_json::Value = client
+            .post(url)
+            .json(&webui_body(model, &prompt, shot))
+            .send()
+            .and_then(|response| response.error_for_status())
+            .and_then(|response| response.json())
+            .map_err(|e| format!("image generation request failed: {}", e))?;
+        let encoded = answer["images"][0]
+            .as_str()
+            .ok_or_else(|| "image generator answered without a picture".to_string())?;
+        // Some versions prefix the picture with its data URL header
+        let encoded = encoded.rsplit(',').next().unwrap_or(encoded);
+        base64::engine::general_purpose::STANDARD
+            .decode(encoded)
+            .map_err(|e| format!("image generator sent an unreadable picture: {}", e))?
+    };
+    let format = ImageFormat::sniff(&image)
+        .ok_or_else(|| "image generator sent a picture in an unsupported format".to_string())?;
+    Ok((image, format))
+}
+
+fn webui_body(model: &str, prompt: &str, shot: Shot) -> serde_json::Value {
+    let (width, height) = shot.size();
+    let mut body = serde_json::json!({
+        "prompt": prompt,
+        "negative_prompt": NEGATIVE_PROMPT,
+        "width": width,
+        "height": height,
+        "steps": STEPS,
+        "cfg_scale": CFG_SCALE,
+        "batch_size": 1,
+    });
+    if !model.is_empty() {
+        body["override_settings"] = serde_json::json!({ "sd_model_checkpoint": model });
+    }
+    body
+}
+
+fn comfyui_workflow(model: &str, prompt: &str, shot: Shot) -> Result<serde_json::Value, String> {
+    if model.is_empty() {
+        return Err(
+            "ComfyUI needs a checkpoint to load, set image_gen_model in the config".to_string(),
+        );
+    }
+    let (width, height) = shot.size();
+    Ok(serde_json::json!({
+        "checkpoint": {
+            "class_type": "CheckpointLoaderSimple",
+            "inputs": { "ckpt_name": model },
+        },
+        "positive": {
+            "class_type": "CLIPTextEncode",
+            "inputs": { "text": prompt, "clip": ["checkpoint", 1] },
+        },
+        "negative": {
+            "class_type": "CLIPTextEncode",
+            "inputs": { "text": NEGATIVE_PROMPT, "clip": ["checkpoint", 1] },
+        },
+        "latent": {
+            "class_type": "EmptyLatentImage",
+            "inputs": { "width": width, "height": height, "batch_size": 1 },
+        },
+        "sampler": {
+            "class_type": "KSampler",
+            "inputs": {
+                "seed": rand::random::<u32>(),
+                "steps": STEPS,
+                "cfg": CFG_SCALE,
+                "sampler_name": "euler",
+                "scheduler": "normal",
+                "denoise": 1.0,
+                "model": ["checkpoint", 0],
+                "positive": ["positive", 0],
+                "negative": ["negative", 0],
+                "latent_image": ["latent", 0],
+            },
+        },
+        "decode": {
+            "class_type": "VAEDecode",
+            "inputs": { "samples": ["sampler", 0], "vae": ["checkpoint", 2] },
+        },
+        "save": {
+            "class_type": "SaveImage",
+            "inputs": { "filename_prefix": "ai-companion", "images": ["decode", 0] },
+        },
+    }))
+}
+
+/// Queue the workflow, wait for its history to list the saved picture and download it
+fn comfyui(
+    client: &reqwest::blocking::Client,
+    url: &str,
+    workflow: &serde_json::Value,
+) -> Result<Vec<u8>, String> {
+    let base = url.trim_end_matches("/prompt");
+    let queued: serde_json::Value = client
+        .post(url)
+        .json(&serde_json::json!({ "prompt": workflow }))
+        .send()
+        .and_then(|response| response.error_for_status())
+        .and_then(|response| response.json())
+        .map_err(|e| format!("image generation request failed: {}", e))?;
+    let prompt_id = queued["prompt_id"]
+        .as_str()
+        .ok_or_else(|| "ComfyUI did not queue the workflow".to_string())?;
+    let started = Instant::now();
+    let output = loop {
+        let history: serde_json::Value = client
+            .get(format!("{}/history/{}", base, prompt_id))
+            .send()
+            .and_then(|response| response.error_for_status())
+            .and_then(|response| response.json())
+            .map_err(|e| format!("could not ask ComfyUI for the picture: {}", e))?;
+        if let Some(image) = history[prompt_id]["outputs"]["save"]["images"].get(0) {
+            break image.clone();
+        }
+        if history[prompt_id]["status"]["status_str"] == "error" {
+            return Err("ComfyUI failed to run the workflow".to_string());
+        }
+        if started.elapsed() > COMFYUI_TIMEOUT {
+            return Err("ComfyUI did not finish the picture in time".to_string());
+        }
+        std::thread::sleep(Duration::from_secs(1));
+    };
+    let field = |name: &str| output[name].as_str().unwrap_or_default().to_string();
+    client
+        .get(format!("{}/view", base))
+        .query(&[
+            ("filename", field("filename")),
+            ("subfolder", field("subfolder")),
+            ("type", field("type")),
+        ])
+        .send()
+        .and_then(|response| response.error_for_status())
+        .and_then(|response| response.bytes())
+        .map(|image| image.to_vec())
+        .map_err(|e| format!("could not download the picture from ComfyUI: {}", e))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_image_prompt() {
+        let message = |content: &str| Message {
+            id: 1,
+            ai: true,
+            content: content.to_string(),
+            created_at: String::new(),
+            author_id: None,
+            images: Vec::new(),
+            stats: None,
+        };
+        assert_eq!(
+            scene_of(&message("*sits on the beach* Hi! *waves at you*")),
+            "sits on the beach, waves at you"
+        );
+        assert_eq!(
+            scene_of(&message("  Having coffee in Paris ")),
+            "Having coffee in Paris"
+        );
+
+        let companion: CompanionView = serde_json::from_value(serde_json::json!({
+            "name": "Mia",
+            "persona": "",
+            "example_dialogue": "",
+            "first_message": "",
+            "long_term_mem": 2,
+            "short_term_mem": 5,
+            "roleplay": true,
+            "dialogue_tuning": false,
+            "avatar_path": "",
+            "appearance": "long red hair, green eyes",
+        }))
+        .unwrap();
+        assert_eq!(
+            describe(&companion, "sits on the beach", Shot::Selfie),
+            "selfie of Mia, long red hair, green eyes, sits on the beach, looking at the camera"
+        );
+        assert_eq!(
+            describe(&companion, "", Shot::Scene),
+            "Mia, long red hair, green eyes"
+        );
+
+        assert!(is_comfyui("http://127.0.0.1:8188/prompt"));
+        assert!(!is_comfyui("http://127.0.0.1:7860/sdapi/v1/txt2img"));
+        let body = webui_body("", "a cat", Shot::Scene);
+        assert_eq!(
+            (body["width"].as_u64(), body["height"].as_u64()),
+            (Some(768), Some(512))
+        );
+        assert!(body.get("override_settings").is_none());
+        assert!(comfyui_workflow("", "a cat", Shot::Selfie).is_err());
+        let workflow = comfyui_workflow("sd15.safetensors", "a cat", Shot::Selfie).unwrap();
+        assert_eq!(
+            workflow["checkpoint"]["inputs"]["ckpt_name"],
+            "sd15.safetensors"
+        );
+        assert_eq!(workflow["positive"]["inputs"]["text"], "a cat");
+    }
+}
@@ -1,4 +1,5 @@
-use serde::Serialize;
+use rusqlite::OptionalExtension;
+use serde::{Deserialize, Serialize};
 use sha2::{Digest, Sha256};
 use std::collections::HashMap;
 use std::sync::{Arc, Mutex, RwLock};
@@ -6,6 +7,8 @@ use std::time::{Duration, Instant};
 use tokio::sync::mpsc;
 
 use crate::database::Message;
+use crate::db_pool;
+use crate::token_budget::PromptSection;
 
 /// Cache entry for frequently used prompts
 #[derive(Debug, Clone)]
@@ -33,10 +36,61 @@ pub struct StreamChunk {
     pub content: String,
     pub is_complete: bool,
     pub token_count: Option<usize>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+    /// Place in the inference queue while the request waits for its turn, 1 is next
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub queue_position: Option<usize>,
+    /// Prompt sections left out so the prompt fit the context window, on the final chunk
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub dropped_sections: Vec<PromptSection>,
 }
 
-/// Inference optimization statistics
-#[derive(Debug, Clone, Serialize)]
+impl StreamChunk {
+    pub fn queued(request_id: &str, position: usize) -> Self {
+        StreamChunk {
+            request_id: request_id.to_string(),
+            content: String::new(),
+            is_complete: false,
+            token_count: None,
+            error: None,
+            queue_position: Some(position),
+            dropped_sections: Vec::new(),
+        }
+    }
+
+    /// "queued" while waiting for a turn, "token" while generating, then "done" or "error"
+    fn event(&self) -> &'static str {
+        if self.error.is_some() {
+            "error"
+        } else if self.queue_position.is_some() {
+            "queued"
+        } else if self.is_complete {
+            "done"
+        } else {
+            "token"
+        }
+    }
+
+    /// Server-sent event for this chunk
+    pub fn to_sse(&self) -> String {
+        format!(
+            "event: {}\ndata: {}\n\n",
+            self.event(),
+            serde_json::to_string(self).unwrap_or_default()
+        )
+    }
+
+    /// WebSocket message for this chunk, `type` carries the same event name as to_sse()
+    pub fn to_ws(&self) -> serde_json::Value {
+        let mut value = serde_json::to_value(self).unwrap_or_default();
+        value["type"] = self.event().into();
+        value
+    }
+}
+
+/// Inference optimization statistics, saved on shutdown and restored on the next start
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct InferenceStats {
     pub total_requests: usize,
     pub cache_hits: usize,
@@ -44,6 +98,23 @@ pub struct InferenceStats {
     pub avg_response_time: Duration,
     pub batch_processed: usize,
     pub streaming_sessions: usize,
+    /// Token chunks sent over all streaming sessions
+    pub streamed_tokens: usize,
+    /// Sessions whose client went away before the reply was complete
+    pub cancelled_streams: usize,
+    /// Sessions that produced at least one token, the averages below are over these
+    pub timed_streams: usize,
+    pub avg_first_token_latency: Duration,
+    pub avg_stream_tokens_per_second: f64,
+}
+
+/// Sender of a streaming session and its timing, folded into the stats when it ends
+struct StreamingSession {
+    sender: mpsc::UnboundedSender<StreamChunk>,
+    started_at: Instant,
+    first_token_at: Option<Instant>,
+    tokens: usize,
+    cancelled: bool,
 }
 
 /// Main inference optimizer with caching and batching capabilities
@@ -53,7 +124,7 @@ pub struct InferenceOptimizer {
     /// Batch processing queue
     batch_queue: Arc<Mutex<Vec<BatchInferenceRequest>>>,
     /// Active streaming sessions
-    streaming_sessions: Arc<RwLock<HashMap<String, mpsc::UnboundedSender<StreamChunk>>>>,
+    streaming_sessions: Arc<RwLock<HashMap<String, StreamingSession>>>,
     /// Performance statistics
     stats: Arc<RwLock<InferenceStats>>,
     /// Configuration
@@ -77,6 +148,11 @@ impl InferenceOptimizer {
                 avg_response_time: Duration::from_millis(0),
                 batch_processed: 0,
                 streaming_sessions: 0,
+                streamed_tokens: 0,
+                cancelled_streams: 0,
+                timed_streams: 0,
+                avg_first_token_latency: Duration::from_millis(0),
+                avg_stream_tokens_per_second: 0.0,
             })),
             cache_max_size: 1000,
             cache_ttl: Duration::from_secs(3600), // 1 hour
@@ -208,7 +284,7 @@ impl InferenceOptimizer {
         let batch: Vec<_> = queue.drain(..).collect();
         drop(queue);
 
-        println!("Processing batch of {} requests", batch.len());
+        tracing::debug!("Processing batch of {} requests", batch.len());
 
         // In a real implementation, this would use the LLM's batch inference capabilities
         // For now, we'll process them sequentially but track batch statistics
@@ -231,7 +307,16 @@ impl InferenceOptimizer {
         let (tx, rx) = mpsc::unbounded_channel();
 
         let mut sessions = self.streaming_sessions.write().unwrap();
-        sessions.insert(session_id.clone(), tx);
+        sessions.insert(
+            session_id,
+            StreamingSession {
+                sender: tx,
+                started_at: Instant::now(),
+                first_token_at: None,
+                tokens: 0,
+                cancelled: false,
+            },
+        );
 
         let mut stats = self.stats.write().unwrap();
         stats.streaming_sessions += 1;
@@ -239,23 +324,55 @@ impl InferenceOptimizer {
         rx
     }
 
-    /// Stream response chunk to client
+    pub fn has_streaming_session(&self, session_id: &str) -> bool {
+        self.streaming_sessions.read().unwrap().contains_key(session_id)
+    }
+
+    /// Stream response chunk to client, fails once the client stopped listening
     pub fn stream_chunk(&self, session_id: &str, chunk: StreamChunk) -> Result<(), String> {
-        let sessions = self.streaming_sessions.read().unwrap();
+        let mut sessions = self.streaming_sessions.write().unwrap();
 
-        if let Some(tx) = sessions.get(session_id) {
-            tx.send(chunk)
-                .map_err(|e| format!("Failed to stream chunk: {}", e))?;
+        if let Some(session) = sessions.get_mut(session_id) {
+            if !chunk.is_complete && chunk.queue_position.is_none() {
+                session.tokens += 1;
+                session.first_token_at.get_or_insert_with(Instant::now);
+            }
+            session.sender.send(chunk).map_err(|e| {
+                session.cancelled = true;
+                format!("Failed to stream chunk: {}", e)
+            })?;
             Ok(())
         } else {
             Err("Session not found".to_string())
         }
     }
 
-    /// End streaming session
+    /// End streaming session and record its timing
     pub fn end_streaming_session(&self, session_id: &str) {
-        let mut sessions = self.streaming_sessions.write().unwrap();
-        sessions.remove(session_id);
+        let session = match self.streaming_sessions.write().unwrap().remove(session_id) {
+            Some(session) => session,
+            None => return,
+        };
+        let mut stats = self.stats.write().unwrap();
+        stats.streamed_tokens += session.tokens;
+        if session.cancelled {
+            stats.cancelled_streams += 1;
+        }
+        let first_token_at = match session.first_token_at {
+            Some(at) => at,
+            None => return,
+        };
+        stats.timed_streams += 1;
+        let timed = stats.timed_streams as u32;
+        let latency = first_token_at.duration_since(session.started_at);
+        stats.avg_first_token_latency =
+            (stats.avg_first_token_latency * (timed - 1) + latency) / timed;
+        let seconds = session.started_at.elapsed().as_secs_f64();
+        if seconds > 0.0 {
+            let rate = session.tokens as f64 / seconds;
+            stats.avg_stream_tokens_per_second =
+                (stats.avg_stream_tokens_per_second * (timed - 1) as f64 + rate) / timed as f64;
+        }
     }
 
     /// Estimate token count for a text (simplified implementation)
@@ -270,6 +387,49 @@ impl InferenceOptimizer {
         self.stats.read().unwrap().clone()
     }
 
+    pub fn create_stats_table() -> rusqlite::Result<()> {
+        let con = db_pool::connection()?;
+        con.execute(
+            "CREATE TABLE IF NOT EXISTS inference_optimizer_stats (
+                id INTEGER PRIMARY KEY CHECK (id = 1),
+                stats TEXT NOT NULL,
+                saved_at DATETIME DEFAULT CURRENT_TIMESTAMP
+            )",
+            [],
+        )?;
+        Ok(())
+    }
+
+    /// Store the statistics so they survive a restart
+    pub fn save_stats(&self) -> rusqlite::Result<()> {
+        let stats = serde_json::to_string(&self.get_stats())
+            .map_err(|e| rusqlite::Error::ToSqlConversionFailure(Box::new(e)))?;
+        let con = db_pool::connection()?;
+        con.execute(
+            "INSERT INTO inference_optimizer_stats (id, stats) VALUES (1, ?)
+             ON CONFLICT(id) DO UPDATE SET stats = excluded.stats, saved_at = CURRENT_TIMESTAMP",
+            [stats],
+        )?;
+        Ok(())
+    }
+
+    /// Pick up the statistics saved by the last shutdown, false if there were none
+    pub fn restore_stats(&self) -> rusqlite::Result<bool> {
+        let con = db_pool::connection()?;
+        let stored: Option<String> = con
+            .query_row("SELECT stats FROM inference_optimizer_stats WHERE id = 1", [], |row| row.get(0))
+            .optional()?;
+        let stored = match stored {
+            Some(stored) => stored,
+            None => return Ok(false),
+        };
+        let stats: InferenceStats = serde_json::from_str(&stored).map_err(|e| {
+            rusqlite::Error::FromSqlConversionFailure(0, rusqlite::types::Type::Text, Box::new(e))
+        })?;
+        *self.stats.write().unwrap() = stats;
+        Ok(true)
+    }
+
     /// Update response time statistics
     pub fn record_response_time(&self, duration: Duration) {
         let mut stats = self.stats.write().unwrap();
@@ -292,7 +452,7 @@ impl InferenceOptimizer {
 
         cache.retain(|_, cached| now.duration_since(cached.timestamp) < self.cache_ttl);
 
-        println!(
+        tracing::info!(
             "Cache cleanup completed. Entries remaining: {}",
             cache.len()
         );
@@ -364,4 +524,61 @@ mod tests {
         assert!(tokens > 0);
         assert!(tokens <= text.len()); // Should be reasonable estimate
     }
+
+    #[test]
+    fn test_stream_chunk_events() {
+        let mut chunk = StreamChunk {
+            request_id: "sse_1".to_string(),
+            content: "Hel".to_string(),
+            is_complete: false,
+            token_count: Some(1),
+            error: None,
+            queue_position: None,
+            dropped_sections: Vec::new(),
+        };
+        assert!(chunk.to_sse().starts_with("event: token\ndata: {"));
+        assert!(chunk.to_sse().ends_with("}\n\n"));
+
+        chunk.is_complete = true;
+        assert!(chunk.to_sse().starts_with("event: done\n"));
+
+        chunk.error = Some("model not loaded".to_string());
+        assert!(chunk.to_sse().starts_with("event: error\n"));
+        assert!(chunk.to_sse().contains("\"error\":\"model not loaded\""));
+        assert_eq!(chunk.to_ws()["type"], "error");
+        assert_eq!(chunk.to_ws()["content"], "Hel");
+
+        let queued = StreamChunk::queued("sse_1", 2);
+        assert!(queued.to_sse().starts_with("event: queued\n"));
+        assert_eq!(queued.to_ws()["queue_position"], 2);
+    }
+
+    #[test]
+    fn test_streaming_session_stats() {
+        let optimizer = InferenceOptimizer::new();
+        let receiver = optimizer.start_streaming_session("ws_1".to_string());
+        let token = |content: &str| StreamChunk {
+            request_id: "ws_1".to_string(),
+            content: content.to_string(),
+            is_complete: false,
+            token_count: None,
+            error: None,
+            queue_position: None,
+            dropped_sections: Vec::new(),
+        };
+        assert!(optimizer.stream_chunk("ws_1", StreamChunk::queued("ws_1", 2)).is_ok());
+        assert!(optimizer.stream_chunk("ws_1", token("Hel")).is_ok());
+        assert!(optimizer.stream_chunk("ws_1", token("lo")).is_ok());
+
+        // The client disconnecting makes the next chunk fail, which stops generation
+        drop(receiver);
+        assert!(optimizer.stream_chunk("ws_1", token("!")).is_err());
+        optimizer.end_streaming_session("ws_1");
+
+        let stats = optimizer.get_stats();
+        assert!(!optimizer.has_streaming_session("ws_1"));
+        assert_eq!(stats.streamed_tokens, 3);
+        assert_eq!(stats.cancelled_streams, 1);
+        assert_eq!(stats.timed_streams, 1);
+    }
 }
@@ -1,7 +1,9 @@
 use std::collections::HashMap;
+use std::sync::atomic::AtomicBool;
 use std::time::{Duration, Instant};
 use serde::{Deserialize, Serialize};
-// Database import removed - using direct connection
+use crate::db_pool;
+use crate::hardware_probe::LayerPlan;
 use rusqlite::params;
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -31,13 +33,250 @@ pub struct InferenceSession {
     pub tokens_generated: u32,
     pub input_tokens: u32,
     pub model_config: ModelConfig,
+    /// "chat", "proactive" or "benchmark"
+    pub source: String,
 }
 
+/// Measurements of one finished generation, as stored in inference_metrics
+#[derive(Debug, Clone, Serialize)]
+pub struct GenerationMetrics {
+    /// Generated tokens per second after the first one arrived
+    pub tokens_per_second: f64,
+    pub time_to_first_token: f64,
+    pub input_tokens: u32,
+    pub output_tokens: u32,
+    pub total_seconds: f64,
+}
+
+/// Aggregated inference_metrics of one model, gpu_layers and device combination
+#[derive(Debug, Clone, Serialize)]
+pub struct ModelMetricsSummary {
+    pub model_path: String,
+    pub gpu_layers: i32,
+    pub device_type: String,
+    pub samples: u32,
+    pub benchmark_samples: u32,
+    pub avg_tokens_per_second: f64,
+    pub min_tokens_per_second: f64,
+    pub max_tokens_per_second: f64,
+    /// Slowest tenth of the generations ran at this speed or below
+    pub p10_tokens_per_second: f64,
+    pub median_tokens_per_second: f64,
+    pub p90_tokens_per_second: f64,
+    pub avg_time_to_first_token: f64,
+    pub avg_input_tokens: f64,
+    pub avg_output_tokens: f64,
+    pub total_output_tokens: i64,
+    pub last_run: String,
+}
+
+/// Generations a model, gpu_layers and device combination needs before its speed counts as proven
+pub const MIN_PROVEN_SAMPLES: u32 = 3;
+
+/// Length of the periods a metrics trend is split into
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum TrendBucket {
+    Hour,
+    Day,
+    Week,
+}
+
+impl TrendBucket {
+    pub fn parse(name: &str) -> Option<TrendBucket> {
+        match name {
+            "hour" => Some(TrendBucket::Hour),
+            "day" => Some(TrendBucket::Day),
+            "week" => Some(TrendBucket::Week),
+            _ => None,
+        }
+    }
+
+    fn strftime(self) -> &'static str {
+        match self {
+            TrendBucket::Hour => "%Y-%m-%d %H:00",
+            TrendBucket::Day => "%Y-%m-%d",
+            TrendBucket::Week => "%Y-W%W",
+        }
+    }
+}
+
+/// Generations of one period of a metrics trend
+#[derive(Debug, Clone, Serialize)]
+pub struct MetricsTrendPoint {
+    pub period: String,
+    pub samples: u32,
+    pub avg_time_to_first_token: f64,
+    pub median_time_to_first_token: f64,
+    pub avg_tokens_per_second: f64,
+}
+
+/// Fastest proven configuration of a model next to the configured one
+#[derive(Debug, Clone, Serialize)]
+pub struct ConfigRecommendation {
+    pub model_path: String,
+    pub gpu_layers: i32,
+    pub device_type: String,
+    /// Measurements of the configured combination, None when it hasn't run yet
+    pub current: Option<ModelMetricsSummary>,
+    /// Combination with the highest median speed among those with MIN_PROVEN_SAMPLES generations
+    pub recommended: Option<ModelMetricsSummary>,
+    /// Median speed of the recommended combination over the configured one
+    pub speedup: Option<f64>,
+    /// The recommended combination differs from the configured one
+    pub change: bool,
+    pub reason: String,
+}
+
+/// Value below which `share` of the sorted values lie, interpolated between neighbours
+fn percentile(sorted: &[f64], share: f64) -> f64 {
+    if sorted.is_empty() {
+        return 0.0;
+    }
+    let rank = share.clamp(0.0, 1.0) * (sorted.len() - 1) as f64;
+    let lower = rank.floor() as usize;
+    let upper = rank.ceil() as usize;
+    sorted[lower] + (sorted[upper] - sorted[lower]) * (rank - lower as f64)
+}
+
+/// Proven combination with the highest median speed, the most recent one wins a tie
+fn fastest_proven(summaries: &[ModelMetricsSummary]) -> Option<&ModelMetricsSummary> {
+    summaries
+        .iter()
+        .filter(|summary| summary.samples >= MIN_PROVEN_SAMPLES)
+        .fold(None, |best: Option<&ModelMetricsSummary>, summary| match best {
+            Some(best) if best.median_tokens_per_second >= summary.median_tokens_per_second => Some(best),
+            _ => Some(summary),
+        })
+}
+
+fn recommendation(
+    model_path: &str,
+    gpu_layers: i32,
+    device_type: &str,
+    summaries: &[ModelMetricsSummary],
+) -> ConfigRecommendation {
+    let is_current = |summary: &ModelMetricsSummary| summary.gpu_layers == gpu_layers && summary.device_type == device_type;
+    let current = summaries.iter().find(|summary| is_current(summary)).cloned();
+    let recommended = fastest_proven(summaries).cloned();
+    let speedup = match (&current, &recommended) {
+        (Some(current), Some(recommended)) if current.median_tokens_per_second > 0.0 => {
+            Some(recommended.median_tokens_per_second / current.median_tokens_per_second)
+        }
+        _ => None,
+    };
+    let change = recommended.as_ref().is_some_and(|recommended| !is_current(recommended));
+    let reason = match (&recommended, speedup) {
+        (None, _) => format!(
+            "No configuration of this model has {} generations yet, chat or run the benchmark with other gpu_layers",
+            MIN_PROVEN_SAMPLES
+        ),
+        (Some(_), _) if !change => "The configured gpu_layers and device are the fastest proven".to_string(),
+        (Some(recommended), Some(speedup)) => format!(
+            "{} gpu_layers on {} generate {:.2}x as fast as the configured ones",
+            recommended.gpu_layers, recommended.device_type, speedup
+        ),
+        (Some(recommended), None) => format!(
+            "The configured gpu_layers haven't been measured, {} gpu_layers on {} generate {:.1} tokens per second",
+            recommended.gpu_layers, recommended.device_type, recommended.median_tokens_per_second
+        ),
+    };
+    ConfigRecommendation {
+        model_path: model_path.to_string(),
+        gpu_layers,
+        device_type: device_type.to_string(),
+        current,
+        recommended,
+        speedup,
+        change,
+        reason,
+    }
+}
+
+/// Prompt of the benchmark suite, run without persona or chat history
+pub struct BenchmarkPrompt {
+    pub name: &'static str,
+    pub text: &'static str,
+    /// Times `text` is repeated, a long prompt measures prompt processing
+    pub repeat: usize,
+    pub max_tokens: usize,
+}
+
+impl BenchmarkPrompt {
+    pub fn prompt(&self) -> String {
+        self.text.repeat(self.repeat)
+    }
+}
+
+pub const BENCHMARK_SUITE: [BenchmarkPrompt; 4] = [
+    BenchmarkPrompt {
+        name: "short_reply",
+        text: "Reply in one sentence: what is your favourite season and why?\n",
+        repeat: 1,
+        max_tokens: 32,
+    },
+    BenchmarkPrompt {
+        name: "explanation",
+        text: "Explain how a rainbow forms, in a short paragraph.\n",
+        repeat: 1,
+        max_tokens: 128,
+    },
+    BenchmarkPrompt {
+        name: "story",
+        text: "Write a short story about a lighthouse keeper who finds a message in a bottle.\n",
+        repeat: 1,
+        max_tokens: 256,
+    },
+    BenchmarkPrompt {
+        name: "long_context",
+        text: "The old town sits on a hill above the river. Every morning the baker opens the shop at six, \
+the postman walks the same route past the fountain and the church bells ring twice. ",
+        repeat: 40,
+        max_tokens: 64,
+    },
+];
+
+/// One prompt of a benchmark run
+#[derive(Debug, Clone, Serialize)]
+pub struct BenchmarkResult {
+    pub name: String,
+    #[serde(flatten)]
+    pub metrics: GenerationMetrics,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct BenchmarkReport {
+    pub model_path: String,
+    pub gpu_layers: i32,
+    pub device_type: String,
+    pub results: Vec<BenchmarkResult>,
+    pub avg_tokens_per_second: f64,
+    pub avg_time_to_first_token: f64,
+}
+
+impl BenchmarkReport {
+    pub fn new(model_config: &ModelConfig, results: Vec<BenchmarkResult>) -> Self {
+        let count = results.len().max(1) as f64;
+        BenchmarkReport {
+            model_path: model_config.model_path.clone(),
+            gpu_layers: model_config.gpu_layers,
+            device_type: model_config.device_type.clone(),
+            avg_tokens_per_second: results.iter().map(|r| r.metrics.tokens_per_second).sum::<f64>() / count,
+            avg_time_to_first_token: results.iter().map(|r| r.metrics.time_to_first_token).sum::<f64>() / count,
+            results,
+        }
+    }
+}
+
+// Set while /api/inference/benchmark runs, a second run would only measure contention
+pub static BENCHMARK_RUNNING: AtomicBool = AtomicBool::new(false);
+
 #[derive(Debug, Clone)]
 pub struct ModelConfig {
     pub model_path: String,
     pub gpu_layers: i32,
     pub device_type: String,
+    /// Set when gpu_layers was chosen by probing the VRAM
+    pub vram_plan: Option<LayerPlan>,
 }
 
 pub struct InferencePerformanceTracker {
@@ -59,6 +298,7 @@ impl InferencePerformanceTracker {
         session_id: String,
         model_config: ModelConfig,
         input_tokens: u32,
+        source: &str,
     ) {
         let session = InferenceSession {
             start_time: Instant::now(),
@@ -66,6 +306,7 @@ impl InferencePerformanceTracker {
             tokens_generated: 0,
             input_tokens,
             model_config,
+            source: source.to_string(),
         };
         
         self.current_sessions.insert(session_id, session);
@@ -87,33 +328,189 @@ impl InferencePerformanceTracker {
         }
     }
 
-    /// Complete the session and store metrics
-    pub fn complete_session(&mut self, session_id: &str) -> rusqlite::Result<()> {
-        if let Some(session) = self.current_sessions.remove(session_id) {
-            let total_time = session.start_time.elapsed();
-            let time_to_first_token = session.first_token_time
-                .map(|t| t.duration_since(session.start_time))
-                .unwrap_or(Duration::from_secs(0));
+    /// Complete the session and store metrics, None when nothing was generated
+    pub fn complete_session(&mut self, session_id: &str) -> rusqlite::Result<Option<GenerationMetrics>> {
+        let session = match self.current_sessions.remove(session_id) {
+            Some(session) => session,
+            None => return Ok(None),
+        };
+        if session.tokens_generated == 0 {
+            return Ok(None);
+        }
+        let total_time = session.start_time.elapsed();
+        let time_to_first_token = session.first_token_time
+            .map(|t| t.duration_since(session.start_time))
+            .unwrap_or(Duration::from_secs(0));
+        // Prompt processing is in the time to first token, counting it again would
+        // make long prompts look like slow generation
+        let generation_time = total_time.saturating_sub(time_to_first_token);
+        let generation_seconds = if generation_time.is_zero() {
+            total_time.as_secs_f64()
+        } else {
+            generation_time.as_secs_f64()
+        };
+        let metrics = GenerationMetrics {
+            tokens_per_second: session.tokens_generated as f64 / generation_seconds.max(0.001),
+            time_to_first_token: time_to_first_token.as_secs_f64(),
+            input_tokens: session.input_tokens,
+            output_tokens: session.tokens_generated,
+            total_seconds: total_time.as_secs_f64(),
+        };
 
-            if session.tokens_generated > 0 {
-                let tokens_per_second = session.tokens_generated as f64 / total_time.as_secs_f64();
-                let ttft_seconds = time_to_first_token.as_secs_f64();
-
-                // Store in database
-                self.store_performance_metrics(
-                    &session.model_config,
-                    tokens_per_second,
-                    ttft_seconds,
-                    session.input_tokens,
-                    session.tokens_generated,
-                )?;
-
-                // Update cached metrics
-                let cache_key = format!("{}:{}", session.model_config.model_path, session.model_config.gpu_layers);
-                self.update_cached_metrics(&cache_key, tokens_per_second, ttft_seconds)?;
+        // Store in database
+        self.store_performance_metrics(&session.model_config, &session.source, &metrics)?;
+
+        // Update cached metrics
+        let cache_key = format!("{}:{}", session.model_config.model_path, session.model_config.gpu_layers);
+        self.update_cached_metrics(&cache_key, metrics.tokens_per_second, metrics.time_to_first_token)?;
+        Ok(Some(metrics))
+    }
+
+    /// Forget a session that ended without a reply
+    pub fn abandon_session(&mut self, session_id: &str) {
+        self.current_sessions.remove(session_id);
+    }
+
+    /// Per model aggregates of the metrics stored in the last `days` days
+    pub fn model_summaries(days: u32, source: Option<&str>) -> rusqlite::Result<Vec<ModelMetricsSummary>> {
+        let con = db_pool::connection()?;
+        let mut stmt = con.prepare(
+            "SELECT model_path, gpu_layers, COALESCE(device_type, 'CPU'),
+                COUNT(*), SUM(source = 'benchmark'),
+                AVG(tokens_per_second), MIN(tokens_per_second), MAX(tokens_per_second),
+                AVG(time_to_first_token), AVG(input_tokens), AVG(output_tokens), SUM(output_tokens),
+                MAX(created_at)
+             FROM inference_metrics
+             WHERE created_at > datetime('now', ?1) AND (?2 IS NULL OR source = ?2)
+             GROUP BY model_path, gpu_layers, device_type
+             ORDER BY MAX(created_at) DESC",
+        )?;
+        let since = format!("-{} days", days);
+        let rows = stmt.query_map(params![since, source], |row| {
+            Ok(ModelMetricsSummary {
+                model_path: row.get(0)?,
+                gpu_layers: row.get(1)?,
+                device_type: row.get(2)?,
+                samples: row.get(3)?,
+                benchmark_samples: row.get(4)?,
+                avg_tokens_per_second: row.get(5)?,
+                min_tokens_per_second: row.get(6)?,
+                max_tokens_per_second: row.get(7)?,
+                p10_tokens_per_second: 0.0,
+                median_tokens_per_second: 0.0,
+                p90_tokens_per_second: 0.0,
+                avg_time_to_first_token: row.get(8)?,
+                avg_input_tokens: row.get(9)?,
+                avg_output_tokens: row.get(10)?,
+                total_output_tokens: row.get(11)?,
+                last_run: row.get(12)?,
+            })
+        })?;
+        let mut summaries = rows.collect::<rusqlite::Result<Vec<_>>>()?;
+
+        // SQLite has no percentiles, the speeds of each combination are sorted here
+        let mut stmt = con.prepare(
+            "SELECT model_path, gpu_layers, COALESCE(device_type, 'CPU'), tokens_per_second
+             FROM inference_metrics
+             WHERE created_at > datetime('now', ?1) AND (?2 IS NULL OR source = ?2)
+             ORDER BY tokens_per_second",
+        )?;
+        let mut speeds: HashMap<(String, i32, String), Vec<f64>> = HashMap::new();
+        let rows = stmt.query_map(params![since, source], |row| {
+            Ok(((row.get(0)?, row.get(1)?, row.get(2)?), row.get::<_, f64>(3)?))
+        })?;
+        for row in rows {
+            let (key, speed) = row?;
+            speeds.entry(key).or_default().push(speed);
+        }
+        for summary in &mut summaries {
+            let key = (summary.model_path.clone(), summary.gpu_layers, summary.device_type.clone());
+            if let Some(sorted) = speeds.get(&key) {
+                summary.p10_tokens_per_second = percentile(sorted, 0.1);
+                summary.median_tokens_per_second = percentile(sorted, 0.5);
+                summary.p90_tokens_per_second = percentile(sorted, 0.9);
             }
         }
-        Ok(())
+        Ok(summaries)
+    }
+
+    /// Time to first token and speed per period, oldest first
+    pub fn metrics_trend(
+        days: u32,
+        bucket: TrendBucket,
+        model_path: Option<&str>,
+        source: Option<&str>,
+    ) -> rusqlite::Result<Vec<MetricsTrendPoint>> {
+        let con = db_pool::connection()?;
+        let mut stmt = con.prepare(
+            "SELECT strftime(?1, created_at), time_to_first_token, tokens_per_second
+             FROM inference_metrics
+             WHERE created_at > datetime('now', ?2) AND (?3 IS NULL OR model_path = ?3)
+                AND (?4 IS NULL OR source = ?4)
+             ORDER BY created_at",
+        )?;
+        let rows = stmt.query_map(
+            params![bucket.strftime(), format!("-{} days", days), model_path, source],
+            |row| Ok((row.get::<_, String>(0)?, row.get::<_, f64>(1)?, row.get::<_, f64>(2)?)),
+        )?;
+        let mut points: Vec<(String, Vec<f64>, f64)> = Vec::new();
+        for row in rows {
+            let (period, time_to_first_token, tokens_per_second) = row?;
+            match points.last_mut() {
+                Some((last, first_tokens, speed)) if *last == period => {
+                    first_tokens.push(time_to_first_token);
+                    *speed += tokens_per_second;
+                }
+                _ => points.push((period, vec![time_to_first_token], tokens_per_second)),
+            }
+        }
+        Ok(points
+            .into_iter()
+            .map(|(period, mut first_tokens, speed)| {
+                let samples = first_tokens.len();
+                first_tokens.sort_by(f64::total_cmp);
+                MetricsTrendPoint {
+                    period,
+                    samples: samples as u32,
+                    avg_time_to_first_token: first_tokens.iter().sum::<f64>() / samples as f64,
+                    median_time_to_first_token: percentile(&first_tokens, 0.5),
+                    avg_tokens_per_second: speed / samples as f64,
+                }
+            })
+            .collect())
+    }
+
+    /// Fastest proven gpu_layers and device combination of every model measured within `days`
+    pub fn best_configurations(days: u32) -> rusqlite::Result<Vec<ModelMetricsSummary>> {
+        let summaries = Self::model_summaries(days, None)?;
+        let mut models: Vec<&str> = Vec::new();
+        for summary in &summaries {
+            if !models.contains(&summary.model_path.as_str()) {
+                models.push(&summary.model_path);
+            }
+        }
+        Ok(models
+            .into_iter()
+            .filter_map(|model_path| {
+                let of_model: Vec<ModelMetricsSummary> =
+                    summaries.iter().filter(|summary| summary.model_path == model_path).cloned().collect();
+                fastest_proven(&of_model).cloned()
+            })
+            .collect())
+    }
+
+    /// Compare the configured combination of a model with the fastest proven one
+    pub fn recommend(
+        model_path: &str,
+        gpu_layers: i32,
+        device_type: &str,
+        days: u32,
+    ) -> rusqlite::Result<ConfigRecommendation> {
+        let summaries: Vec<ModelMetricsSummary> = Self::model_summaries(days, None)?
+            .into_iter()
+            .filter(|summary| summary.model_path == model_path)
+            .collect();
+        Ok(recommendation(model_path, gpu_layers, device_type, &summaries))
     }
 
     /// Get current performance estimate based on historical data
@@ -282,26 +679,30 @@ impl InferencePerformanceTracker {
     fn store_performance_metrics(
         &self,
         config: &ModelConfig,
-        tokens_per_second: f64,
-        time_to_first_token: f64,
-        input_tokens: u32,
-        output_tokens: u32,
+        source: &str,
+        metrics: &GenerationMetrics,
     ) -> rusqlite::Result<()> {
-        let con = rusqlite::Connection::open("companion_database.db")?;
+        let con = db_pool::connection()?;
         
+        let plan = config.vram_plan.as_ref();
         con.execute(
             "INSERT INTO inference_metrics (
                 model_path, gpu_layers, device_type, tokens_per_second, 
-                time_to_first_token, input_tokens, output_tokens, created_at
-            ) VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, datetime('now'))",
+                time_to_first_token, input_tokens, output_tokens, created_at,
+                free_vram_mb, layer_vram_mb, total_layers, source
+            ) VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, datetime('now'), ?8, ?9, ?10, ?11)",
             params![
                 config.model_path,
                 config.gpu_layers,
                 config.device_type,
-                tokens_per_second,
-                time_to_first_token,
-                input_tokens,
-                output_tokens
+                metrics.tokens_per_second,
+                metrics.time_to_first_token,
+                metrics.input_tokens,
+                metrics.output_tokens,
+                plan.map(|plan| plan.free_vram_mb as i64),
+                plan.map(|plan| plan.layer_mb + plan.kv_cache_mb_per_layer),
+                plan.map(|plan| plan.total_layers as i64),
+                source
             ],
         )?;
         
@@ -329,7 +730,7 @@ impl InferencePerformanceTracker {
 
     /// Load metrics from database
     fn load_metrics_from_db(&self, config: &ModelConfig) -> rusqlite::Result<Option<InferenceMetrics>> {
-        let con = rusqlite::Connection::open("companion_database.db")?;
+        let con = db_pool::connection()?;
         
         // Get aggregated metrics for this configuration
         let mut stmt = con.prepare("
@@ -424,6 +825,78 @@ mod tests {
         assert!(tracker.analyze_complexity("explain algorithms") > tracker.analyze_complexity("hello"));
     }
 
+    #[test]
+    fn test_benchmark_report_averages() {
+        let model_config = ModelConfig {
+            model_path: "model.gguf".to_string(),
+            gpu_layers: 20,
+            device_type: "GPU".to_string(),
+            vram_plan: None,
+        };
+        let result = |name: &str, tokens_per_second: f64, time_to_first_token: f64| BenchmarkResult {
+            name: name.to_string(),
+            metrics: GenerationMetrics {
+                tokens_per_second,
+                time_to_first_token,
+                input_tokens: 10,
+                output_tokens: 32,
+                total_seconds: 2.0,
+            },
+        };
+        let report = BenchmarkReport::new(
+            &model_config,
+            vec![result("short_reply", 20.0, 0.5), result("story", 10.0, 1.5)],
+        );
+        assert_eq!(report.avg_tokens_per_second, 15.0);
+        assert_eq!(report.avg_time_to_first_token, 1.0);
+        assert_eq!(BenchmarkReport::new(&model_config, Vec::new()).avg_tokens_per_second, 0.0);
+        assert!(BENCHMARK_SUITE[3].prompt().len() > 2000);
+    }
+
+    #[test]
+    fn test_metrics_recommendation() {
+        let sorted = [1.0, 2.0, 3.0, 4.0, 10.0];
+        assert_eq!(percentile(&sorted, 0.5), 3.0);
+        assert!((percentile(&sorted, 0.9) - 7.6).abs() < 1e-9);
+        assert_eq!(percentile(&sorted, 0.0), 1.0);
+        assert_eq!(percentile(&[], 0.5), 0.0);
+
+        let summary = |gpu_layers: i32, samples: u32, median: f64| ModelMetricsSummary {
+            model_path: "model.gguf".to_string(),
+            gpu_layers,
+            device_type: "GPU".to_string(),
+            samples,
+            benchmark_samples: 0,
+            avg_tokens_per_second: median,
+            min_tokens_per_second: median,
+            max_tokens_per_second: median,
+            p10_tokens_per_second: median,
+            median_tokens_per_second: median,
+            p90_tokens_per_second: median,
+            avg_time_to_first_token: 0.5,
+            avg_input_tokens: 100.0,
+            avg_output_tokens: 50.0,
+            total_output_tokens: 500,
+            last_run: "2026-10-16 12:00:00".to_string(),
+        };
+        // 40 layers are the fastest but not proven yet
+        let summaries = vec![summary(20, 10, 12.0), summary(30, 5, 18.0), summary(40, 2, 25.0)];
+        let advice = recommendation("model.gguf", 20, "GPU", &summaries);
+        assert_eq!(advice.recommended.as_ref().unwrap().gpu_layers, 30);
+        assert_eq!(advice.speedup, Some(1.5));
+        assert!(advice.change);
+
+        let advice = recommendation("model.gguf", 30, "GPU", &summaries);
+        assert!(!advice.change);
+        assert_eq!(advice.speedup, Some(1.0));
+
+        let advice = recommendation("model.gguf", 35, "GPU", &summaries);
+        assert!(advice.current.is_none() && advice.change && advice.speedup.is_none());
+
+        let advice = recommendation("model.gguf", 20, "GPU", &summaries[2..]);
+        assert!(advice.recommended.is_none() && !advice.change);
+    }
+
     #[test]
     fn test_context_penalty() {
         let tracker = InferencePerformanceTracker::new();
@@ -0,0 +1,401 @@
+use crate::conversations::Conversations;
+use crate::database::Database;
+use serde::Serialize;
+use std::collections::{HashSet, VecDeque};
+use std::sync::{Condvar, Mutex, MutexGuard, PoisonError};
+use std::time::Instant;
+
+/// Upper bound for max_concurrent_generations, more only fight over the same memory bandwidth
+pub const MAX_CONCURRENT_GENERATIONS: usize = 8;
+
+struct Waiting {
+    request_id: String,
+    session: Option<String>,
+    since: Instant,
+}
+
+struct Running {
+    request_id: String,
+    session: Option<String>,
+    since: Instant,
+}
+
+#[derive(Default)]
+struct QueueState {
+    running: Vec<Running>,
+    /// First in line first
+    waiting: VecDeque<Waiting>,
+    /// Requests taken out of the line that haven't noticed yet
+    cancelled: HashSet<String>,
+    /// The server is shutting down, nobody gets in line anymore
+    closed: bool,
+}
+
+/// Generations wait here for their turn, at most max_concurrent_generations of them run at once
+#[derive(Default)]
+struct InferenceQueue {
+    state: Mutex<QueueState>,
+    turn: Condvar,
+}
+
+lazy_static::lazy_static! {
+    static ref QUEUE: InferenceQueue = InferenceQueue::default();
+}
+
+/// Request waiting in the queue
+#[derive(Serialize)]
+pub struct QueuedRequest {
+    pub request_id: String,
+    pub session: Option<String>,
+    /// 1 is next in line
+    pub position: usize,
+    pub waiting_seconds: f64,
+}
+
+/// Request being generated
+#[derive(Serialize)]
+pub struct RunningRequest {
+    pub request_id: String,
+    pub session: Option<String>,
+    pub running_seconds: f64,
+}
+
+#[derive(Serialize)]
+pub struct QueueStatus {
+    pub max_concurrent_generations: usize,
+    pub running: usize,
+    /// Every generation slot is taken, new requests wait
+    pub busy: bool,
+    pub generating: Vec<RunningRequest>,
+    pub queued: Vec<QueuedRequest>,
+}
+
+/// Where a single request is
+#[derive(Serialize)]
+#[serde(tag = "status", rename_all = "lowercase")]
+pub enum RequestStatus {
+    Queued(QueuedRequest),
+    Running(RunningRequest),
+}
+
+/// The request was cancelled or its client went away before its turn came
+#[derive(Debug)]
+pub struct Cancelled;
+
+/// Place in line, leaves the queue when dropped before its turn
+pub struct Ticket {
+    request_id: String,
+    waiting: bool,
+}
+
+/// Turn to generate, the next request in line goes once it is dropped
+pub struct Slot {
+    request_id: String,
+}
+
+impl Drop for Slot {
+    fn drop(&mut self) {
+        let mut state = QUEUE.lock();
+        if let Some(index) = state.running.iter().position(|running| running.request_id == self.request_id) {
+            state.running.remove(index);
+        }
+        QUEUE.turn.notify_all();
+    }
+}
+
+impl InferenceQueue {
+    fn lock(&self) -> MutexGuard<'_, QueueState> {
+        self.state.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+}
+
+impl QueueState {
+    /// Index of the first waiting request that may start once a slot is free
+    ///
+    /// Requests of a session go one after another in the order they came in, so one waits while
+    /// its session is generating or has an earlier request in line. Requests without a session
+    /// and those of other sessions pass it.
+    fn next_in_turn(&self) -> Option<usize> {
+        self.waiting.iter().enumerate().position(|(index, waiting)| match &waiting.session {
+            None => true,
+            Some(session) => {
+                !self.running.iter().any(|running| running.session.as_ref() == Some(session))
+                    && !self.waiting.iter().take(index).any(|earlier| earlier.session.as_ref() == Some(session))
+            }
+        })
+    }
+
+}
+
+/// Unique id for a request, prefixed with the channel it came in on
+pub fn new_request_id(channel: &str) -> String {
+    format!(
+        "{}_{}",
+        channel,
+        std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_nanos()
+    )
+}
+
+/// Session of the active conversation, replies in it are generated in the order they were asked for
+pub fn chat_session() -> String {
+    format!("conversation_{}", Conversations::active_id())
+}
+
+fn max_concurrent() -> usize {
+    Database::get_global_config()
+        .map(|config| config.max_concurrent_generations)
+        .unwrap_or(1)
+        .clamp(1, MAX_CONCURRENT_GENERATIONS)
+}
+
+/// Get in line, None if a request with this id is in line or generating already or the queue is closed
+///
+/// Requests of the same `session` never run at the same time, see [`QueueState::next_in_turn`].
+pub fn join(request_id: &str, session: Option<&str>) -> Option<Ticket> {
+    let mut state = QUEUE.lock();
+    if state.closed
+        || state.waiting.iter().any(|waiting| waiting.request_id == request_id)
+        || state.running.iter().any(|running| running.request_id == request_id)
+    {
+        return None;
+    }
+    state.waiting.push_back(Waiting {
+        request_id: request_id.to_string(),
+        session: session.map(str::to_string),
+        since: Instant::now(),
+    });
+    Some(Ticket {
+        request_id: request_id.to_string(),
+        waiting: true,
+    })
+}
+
+impl Ticket {
+    /// Block until it is this request's turn
+    ///
+    /// `on_position` hears the position in line whenever it changes, not at all if a generation
+    /// slot is free right away. Returning false leaves the queue.
+    pub fn wait(self, on_position: &mut dyn FnMut(usize) -> bool) -> Result<Slot, Cancelled> {
+        // Read once, a changed setting applies to the requests that come after
+        self.wait_for(max_concurrent(), on_position)
+    }
+
+    fn wait_for(
+        mut self,
+        max_concurrent: usize,
+        on_position: &mut dyn FnMut(usize) -> bool,
+    ) -> Result<Slot, Cancelled> {
+        let mut reported = None;
+        let mut state = QUEUE.lock();
+        loop {
+            if state.cancelled.remove(&self.request_id) {
+                self.waiting = false;
+                return Err(Cancelled);
+            }
+            let index = state
+                .waiting
+                .iter()
+                .position(|waiting| waiting.request_id == self.request_id)
+                .ok_or(Cancelled)?;
+            if state.running.len() < max_concurrent && state.next_in_turn() == Some(index) {
+                if let Some(waiting) = state.waiting.remove(index) {
+                    state.running.push(Running {
+                        request_id: waiting.request_id,
+                        session: waiting.session,
+                        since: Instant::now(),
+                    });
+                }
+                self.waiting = false;
+                // The next one in line may fit as well
+                QUEUE.turn.notify_all();
+                return Ok(Slot {
+                    request_id: self.request_id.clone(),
+                });
+            }
+            let position = index + 1;
+            if reported != Some(position) {
+                reported = Some(position);
+                drop(state);
+                if !on_position(position) {
+                    // Drop leaves the queue
+                    return Err(Cancelled);
+                }
+                state = QUEUE.lock();
+                continue;
+            }
+            state = QUEUE.turn.wait(state).unwrap_or_else(PoisonError::into_inner);
+        }
+    }
+}
+
+impl Drop for Ticket {
+    fn drop(&mut self) {
+        if !self.waiting {
+            return;
+        }
+        let mut state = QUEUE.lock();
+        state.waiting.retain(|waiting| waiting.request_id != self.request_id);
+        state.cancelled.remove(&self.request_id);
+        QUEUE.turn.notify_all();
+    }
+}
+
+/// Wait for a turn without a client to report to, for background generations
+pub fn turn(channel: &str, session: Option<&str>) -> Result<Slot, Cancelled> {
+    join(&new_request_id(channel), session).ok_or(Cancelled)?.wait(&mut |_| true)
+}
+
+/// Take a request out of the line, false if it isn't waiting
+pub fn cancel(request_id: &str) -> bool {
+    let mut state = QUEUE.lock();
+    let before = state.waiting.len();
+    state.waiting.retain(|waiting| waiting.request_id != request_id);
+    if state.waiting.len() == before {
+        return false;
+    }
+    state.cancelled.insert(request_id.to_string());
+    QUEUE.turn.notify_all();
+    true
+}
+
+/// Turn everyone away for good and cancel the requests in line, returns how many were waiting
+///
+/// Generations already running carry on.
+pub fn close() -> usize {
+    let mut state = QUEUE.lock();
+    state.closed = true;
+    let waiting: Vec<Waiting> = state.waiting.drain(..).collect();
+    for waiting in &waiting {
+        state.cancelled.insert(waiting.request_id.clone());
+    }
+    QUEUE.turn.notify_all();
+    waiting.len()
+}
+
+/// Number of generations running right now
+pub fn running() -> usize {
+    QUEUE.lock().running.len()
+}
+
+fn queued_request(index: usize, waiting: &Waiting) -> QueuedRequest {
+    QueuedRequest {
+        request_id: waiting.request_id.clone(),
+        session: waiting.session.clone(),
+        position: index + 1,
+        waiting_seconds: waiting.since.elapsed().as_secs_f64(),
+    }
+}
+
+fn running_request(running: &Running) -> RunningRequest {
+    RunningRequest {
+        request_id: running.request_id.clone(),
+        session: running.session.clone(),
+        running_seconds: running.since.elapsed().as_secs_f64(),
+    }
+}
+
+pub fn status() -> QueueStatus {
+    let max_concurrent_generations = max_concurrent();
+    let state = QUEUE.lock();
+    QueueStatus {
+        max_concurrent_generations,
+        running: state.running.len(),
+        busy: state.running.len() >= max_concurrent_generations,
+        generating: state.running.iter().map(running_request).collect(),
+        queued: state
+            .waiting
+            .iter()
+            .enumerate()
+            .map(|(index, waiting)| queued_request(index, waiting))
+            .collect(),
+    }
+}
+
+/// Status of a request that is waiting or generating, None once it is done or unknown
+pub fn request_status(request_id: &str) -> Option<RequestStatus> {
+    let state = QUEUE.lock();
+    if let Some(running) = state.running.iter().find(|running| running.request_id == request_id) {
+        return Some(RequestStatus::Running(running_request(running)));
+    }
+    state
+        .waiting
+        .iter()
+        .enumerate()
+        .find(|(_, waiting)| waiting.request_id == request_id)
+        .map(|(index, waiting)| RequestStatus::Queued(queued_request(index, waiting)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::mpsc;
+
+    #[test]
+    fn test_inference_queue() {
+        let first = join("test_first", None)
+            .unwrap()
+            .wait_for(1, &mut |_| panic!("a slot was free"))
+            .unwrap();
+        let second = join("test_second", None).unwrap();
+        let third = join("test_third", None).unwrap();
+        assert!(join("test_second", None).is_none());
+        assert!(join("test_first", None).is_none());
+        assert!(matches!(request_status("test_first"), Some(RequestStatus::Running(_))));
+        assert!(matches!(
+            request_status("test_third"),
+            Some(RequestStatus::Queued(QueuedRequest { position: 2, .. }))
+        ));
+
+        assert!(cancel("test_third"));
+        assert!(!cancel("test_third"));
+        assert!(third.wait_for(1, &mut |_| true).is_err());
+
+        let (positions, reported) = mpsc::channel();
+        let waiter = std::thread::spawn(move || {
+            second
+                .wait_for(1, &mut |position| positions.send(position).is_ok())
+                .is_ok()
+        });
+        assert_eq!(reported.recv().unwrap(), 1);
+        drop(first);
+        assert!(waiter.join().unwrap());
+
+        assert!(request_status("test_third").is_none());
+
+        let leaving = join("test_leaving", None).unwrap();
+        assert!(join("test_leaving", None).is_none());
+        drop(leaving);
+        assert!(join("test_leaving", None).is_some());
+    }
+
+    #[test]
+    fn test_session_order() {
+        let mut state = QueueState::default();
+        for (request_id, session) in [("a2", Some("a")), ("b1", Some("b")), ("a3", Some("a")), ("x", None)] {
+            state.waiting.push_back(Waiting {
+                request_id: request_id.to_string(),
+                session: session.map(str::to_string),
+                since: Instant::now(),
+            });
+        }
+        assert_eq!(state.next_in_turn(), Some(0));
+        state.running.push(Running {
+            request_id: "a1".to_string(),
+            session: Some("a".to_string()),
+            since: Instant::now(),
+        });
+        // a2 waits for a1, b1 passes it
+        assert_eq!(state.next_in_turn(), Some(1));
+        state.waiting.remove(1);
+        state.running.push(Running {
+            request_id: "b1".to_string(),
+            session: Some("b".to_string()),
+            since: Instant::now(),
+        });
+        // a3 stays behind a2, requests without a session never wait for one
+        assert_eq!(state.next_in_turn(), Some(2));
+    }
+}
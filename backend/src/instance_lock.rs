@@ -0,0 +1,209 @@
+use crate::database::get_current_date;
+use crate::db_pool;
+use crate::event_bus;
+use actix_web::http::Method;
+use rusqlite::{params, Error, OptionalExtension, Result};
+use serde::Serialize;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::Duration;
+
+const HEARTBEAT_SECONDS: u64 = 10;
+// A leader that missed heartbeats for this long is presumed gone and can be replaced
+const LEASE_SECONDS: i64 = 30;
+
+pub const READ_ONLY_MESSAGE: &str =
+    "Another instance holds the database lock, this instance is read-only. See /api/health for details";
+
+/// This process, as recorded in the lease while it is the leader
+#[derive(Serialize, Clone, Debug)]
+pub struct InstanceInfo {
+    pub instance_id: String,
+    pub hostname: String,
+    pub pid: u32,
+}
+
+/// Instance currently allowed to run inference and background jobs
+#[derive(Serialize, Clone, Debug)]
+pub struct LeaseHolder {
+    pub instance_id: String,
+    pub hostname: String,
+    pub pid: u32,
+    pub acquired_at: String,
+    pub seconds_since_heartbeat: i64,
+}
+
+#[derive(Serialize, Debug)]
+pub struct InstanceStatus {
+    pub instance: InstanceInfo,
+    /// "leader" or "read_only"
+    pub role: &'static str,
+    pub leader: Option<LeaseHolder>,
+}
+
+lazy_static::lazy_static! {
+    static ref INSTANCE: InstanceInfo = InstanceInfo {
+        instance_id: uuid::Uuid::new_v4().to_string(),
+        hostname: std::env::var("HOSTNAME")
+            .or_else(|_| std::env::var("COMPUTERNAME"))
+            .unwrap_or_else(|_| "unknown".to_string()),
+        pid: std::process::id(),
+    };
+}
+
+static LEADER: AtomicBool = AtomicBool::new(false);
+
+pub fn create() -> Result<(), Error> {
+    let con = db_pool::connection()?;
+    con.execute(
+        "CREATE TABLE IF NOT EXISTS instance_lease (
+            id INTEGER PRIMARY KEY CHECK(id = 1),
+            instance_id TEXT NOT NULL,
+            hostname TEXT NOT NULL,
+            pid INTEGER NOT NULL,
+            acquired_at TEXT NOT NULL,
+            heartbeat_at INTEGER NOT NULL
+        )",
+        [],
+    )?;
+    Ok(())
+}
+
+/// Whether this instance may run inference, accept writes and run background jobs
+pub fn is_leader() -> bool {
+    LEADER.load(Ordering::SeqCst)
+}
+
+/// Requests a read-only instance has to turn away
+pub fn requires_leader(method: &Method, path: &str) -> bool {
+    // Logging in only checks the key
+    if path.starts_with("/api/health") || path == "/api/auth/login" {
+        return false;
+    }
+    let reads = [Method::GET, Method::HEAD, Method::OPTIONS];
+    // Regeneration is a GET for historical reasons but writes and runs the model
+    !reads.contains(method) || path == "/api/prompt/regenerate"
+}
+
+/// Take the lease if it is free or expired, or renew it if it is ours
+fn try_acquire() -> Result<bool> {
+    let con = db_pool::connection()?;
+    let now = chrono::Utc::now().timestamp();
+    let changed = con.execute(
+        "INSERT INTO instance_lease (id, instance_id, hostname, pid, acquired_at, heartbeat_at)
+         VALUES (1, ?1, ?2, ?3, ?4, ?5)
+         ON CONFLICT(id) DO UPDATE SET
+             acquired_at = CASE WHEN instance_id = excluded.instance_id THEN acquired_at ELSE excluded.acquired_at END,
+             instance_id = excluded.instance_id,
+             hostname = excluded.hostname,
+             pid = excluded.pid,
+             heartbeat_at = excluded.heartbeat_at
+         WHERE instance_id = excluded.instance_id OR heartbeat_at < ?6",
+        params![
+            INSTANCE.instance_id,
+            INSTANCE.hostname,
+            INSTANCE.pid,
+            get_current_date(),
+            now,
+            now - LEASE_SECONDS
+        ],
+    )?;
+    Ok(changed > 0)
+}
+
+fn lease_holder() -> Result<Option<LeaseHolder>> {
+    let con = db_pool::connection()?;
+    let now = chrono::Utc::now().timestamp();
+    con.query_row(
+        "SELECT instance_id, hostname, pid, acquired_at, heartbeat_at FROM instance_lease WHERE id = 1",
+        [],
+        |row| {
+            Ok(LeaseHolder {
+                instance_id: row.get(0)?,
+                hostname: row.get(1)?,
+                pid: row.get(2)?,
+                acquired_at: row.get(3)?,
+                seconds_since_heartbeat: now - row.get::<_, i64>(4)?,
+            })
+        },
+    )
+    .optional()
+}
+
+pub fn status() -> InstanceStatus {
+    InstanceStatus {
+        instance: INSTANCE.clone(),
+        role: if is_leader() { "leader" } else { "read_only" },
+        leader: lease_holder().unwrap_or(None),
+    }
+}
+
+/// Try to become (or stay) the leader and report role changes, returns the current role
+pub fn elect() -> bool {
+    let leader = match try_acquire() {
+        Ok(leader) => leader,
+        Err(e) => {
+            // Without a working lease nobody can tell whether another instance is active
+            tracing::warn!("⚠️ Failed to renew instance lease: {}", e);
+            false
+        }
+    };
+    if LEADER.swap(leader, Ordering::SeqCst) != leader {
+        report_role();
+        event_bus::publish("instance_role_changed", serde_json::json!(status()));
+    }
+    leader
+}
+
+/// Print which instance runs inference and background jobs
+pub fn report_role() {
+    if is_leader() {
+        tracing::info!("🔒 This instance holds the database lock and runs inference and background jobs");
+        return;
+    }
+    match lease_holder() {
+        Ok(Some(holder)) => tracing::info!(
+            "🔓 Instance {} (pid {}) holds the database lock, serving read-only",
+            holder.hostname, holder.pid
+        ),
+        _ => tracing::info!("🔓 Database lock unavailable, serving read-only"),
+    }
+}
+
+/// Keep the lease alive, or take it over once the current leader stops renewing it
+pub async fn run_elector() {
+    let mut interval = tokio::time::interval(Duration::from_secs(HEARTBEAT_SECONDS));
+    loop {
+        interval.tick().await;
+        let _ = tokio::task::spawn_blocking(elect).await;
+    }
+}
+
+/// Give the lease up on shutdown so another instance can take over right away
+pub fn release() {
+    if !LEADER.swap(false, Ordering::SeqCst) {
+        return;
+    }
+    let result = db_pool::connection().and_then(|con| {
+        con.execute(
+            "DELETE FROM instance_lease WHERE instance_id = ?",
+            [&INSTANCE.instance_id],
+        )
+    });
+    if let Err(e) = result {
+        tracing::warn!("⚠️ Failed to release instance lease: {}", e);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_requires_leader() {
+        assert!(!requires_leader(&Method::GET, "/api/message"));
+        assert!(requires_leader(&Method::POST, "/api/prompt"));
+        assert!(requires_leader(&Method::GET, "/api/prompt/regenerate"));
+        assert!(!requires_leader(&Method::POST, "/api/health"));
+        assert!(!requires_leader(&Method::POST, "/api/auth/login"));
+    }
+}
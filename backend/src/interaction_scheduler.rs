@@ -0,0 +1,169 @@
+use crate::database::{parse_stored_date, Database, NewMessage, ThirdPartyInteraction};
+use crate::delivery_queue::DeliveryQueue;
+use crate::event_bus;
+use crate::llm::proactive_prompt;
+use chrono::{Datelike, Duration, Local, NaiveDate, NaiveDateTime, NaiveTime, Weekday};
+use rusqlite::Error;
+
+// Plans without a time of day take place in the early evening
+const DEFAULT_HOUR: u32 = 18;
+
+fn at_hour(date: NaiveDate, hour: u32) -> NaiveDateTime {
+    date.and_time(NaiveTime::from_hms_opt(hour, 0, 0).unwrap_or_default())
+}
+
+/// When a planned interaction takes place, `planned_date` is relative to `planned_at`
+///
+/// Understands the phrases `extract_planned_date` produces ("tomorrow", "Friday", "soon", ...)
+/// as well as "YYYY-MM-DD" and "YYYY-MM-DD HH:MM" from the API. Anything else never comes due.
+pub fn due_at(planned_date: &str, planned_at: NaiveDateTime) -> Option<NaiveDateTime> {
+    let day = planned_at.date();
+    let planned_date = planned_date.trim().to_lowercase();
+    // Something planned for later today can't happen before it was planned
+    let later_today = |hour| at_hour(day, hour).max(planned_at + Duration::hours(1));
+    let due = match planned_date.as_str() {
+        "today" => later_today(DEFAULT_HOUR),
+        "tonight" => later_today(21),
+        "tomorrow" => at_hour(day + Duration::days(1), DEFAULT_HOUR),
+        "next week" => at_hour(day + Duration::days(7), DEFAULT_HOUR),
+        "soon" => planned_at + Duration::days(1),
+        "this weekend" => match day.weekday() {
+            Weekday::Sat | Weekday::Sun => later_today(14),
+            weekday => at_hour(
+                day + Duration::days(5 - weekday.num_days_from_monday() as i64),
+                14,
+            ),
+        },
+        other => {
+            if let Ok(weekday) = other.parse::<Weekday>() {
+                // The next such day, a week ahead when it is named on that same day
+                let ahead = (weekday.num_days_from_monday() as i64
+                    - day.weekday().num_days_from_monday() as i64)
+                    .rem_euclid(7);
+                let ahead = if ahead == 0 { 7 } else { ahead };
+                at_hour(day + Duration::days(ahead), DEFAULT_HOUR)
+            } else if let Ok(exact) = NaiveDateTime::parse_from_str(other, "%Y-%m-%d %H:%M") {
+                exact
+            } else {
+                at_hour(NaiveDate::parse_from_str(other, "%Y-%m-%d").ok()?, DEFAULT_HOUR)
+            }
+        }
+    };
+    Some(due)
+}
+
+fn is_due(interaction: &ThirdPartyInteraction, now: NaiveDateTime) -> bool {
+    let planned_at = match parse_stored_date(&interaction.created_at) {
+        Some(planned_at) => planned_at,
+        None => return false,
+    };
+    interaction
+        .planned_date
+        .as_deref()
+        .and_then(|planned_date| due_at(planned_date, planned_at))
+        .map_or(false, |due| due <= now)
+}
+
+/// Have the companion bring up how the interaction went, without being asked
+fn tell_user(interaction: &ThirdPartyInteraction, outcome: &str) {
+    let content = match proactive_prompt(&format!(
+        "{{{{char}}}} just did this: {}. How it went: {} {{{{char}}}} tells {{{{user}}}} about it unprompted, in a short casual message.",
+        interaction.description, outcome
+    )) {
+        Ok(reply) if !reply.trim().is_empty() => reply,
+        result => {
+            if let Err(e) = result {
+                tracing::warn!("⚠️ Failed to generate interaction message, storing the outcome: {}", e);
+            }
+            if let Err(e) = Database::insert_message(NewMessage {
+                ai: true,
+                content: outcome.to_string(),
+                images: Vec::new(),
+            }) {
+                tracing::error!("Error while adding message to database/short-term memory: {}", e);
+                return;
+            }
+            outcome.to_string()
+        }
+    };
+    let data = serde_json::json!({ "ai": true, "content": content });
+    event_bus::publish("companion_message", data.clone());
+    if let Err(e) = DeliveryQueue::enqueue_webhook_event("companion_message", data) {
+        tracing::error!("Failed to queue companion message for delivery: {}", e);
+    }
+}
+
+/// Maintenance job entry point, plays out planned interactions whose date has arrived
+pub fn run_due() -> Result<String, String> {
+    let config = Database::get_config().map_err(|e| e.to_string())?;
+    let now = Local::now().naive_local();
+    let due: Vec<ThirdPartyInteraction> = Database::get_all_planned_interactions()
+        .map_err(|e| e.to_string())?
+        .into_iter()
+        .filter(|interaction| is_due(interaction, now))
+        .collect();
+
+    let mut completed = 0;
+    for interaction in due {
+        let id = match interaction.id {
+            Some(id) => id,
+            None => continue,
+        };
+        let outcome = match Database::generate_interaction_outcome(id) {
+            Ok(outcome) => outcome,
+            // The person or the companion's attitude toward them is gone, it can't happen anymore
+            Err(Error::QueryReturnedNoRows) => {
+                Database::cancel_interaction(id).map_err(|e| e.to_string())?;
+                continue;
+            }
+            Err(e) => return Err(e.to_string()),
+        };
+        completed += 1;
+        event_bus::publish(
+            "interaction_completed",
+            serde_json::json!({
+                "interaction_id": id,
+                "companion_id": interaction.companion_id,
+                "third_party_id": interaction.third_party_id,
+                "description": interaction.description,
+                "outcome": outcome,
+            }),
+        );
+        // Messages go to the chat of the active companion, the others keep the outcome to themselves
+        if config.proactive_interaction_messages
+            && interaction.companion_id == Database::active_companion_id()
+        {
+            tell_user(&interaction, &outcome);
+        }
+    }
+
+    if completed == 0 {
+        return Ok(String::new());
+    }
+    Ok(format!("completed {} planned interactions", completed))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn date(text: &str) -> NaiveDateTime {
+        NaiveDateTime::parse_from_str(text, "%Y-%m-%d %H:%M").unwrap()
+    }
+
+    #[test]
+    fn test_due_at() {
+        // A Wednesday afternoon
+        let planned_at = date("2024-05-15 14:30");
+        assert_eq!(due_at("tomorrow", planned_at), Some(date("2024-05-16 18:00")));
+        assert_eq!(due_at("Friday", planned_at), Some(date("2024-05-17 18:00")));
+        assert_eq!(due_at("wednesday", planned_at), Some(date("2024-05-22 18:00")));
+        assert_eq!(due_at("this weekend", planned_at), Some(date("2024-05-18 14:00")));
+        assert_eq!(due_at("2024-06-01 09:15", planned_at), Some(date("2024-06-01 09:15")));
+        assert_eq!(due_at("whenever", planned_at), None);
+
+        let late = date("2024-05-15 20:30");
+        assert_eq!(due_at("today", late), Some(date("2024-05-15 21:30")));
+        assert_eq!(due_at("tonight", late), Some(date("2024-05-15 21:30")));
+    }
+}
@@ -0,0 +1,84 @@
+use crate::database::{get_current_date, Database};
+use crate::db_pool;
+use rusqlite::{params, Error, Result};
+use serde::{Deserialize, Serialize};
+
+pub const KIND_DAILY_RECAP: &str = "daily_recap";
+
+/// Entry in the companion's journal, one row per generated write-up
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct JournalEntry {
+    pub id: i64,
+    pub companion_id: i32,
+    pub kind: String,
+    pub entry_date: String,
+    pub content: String,
+    pub created_at: String,
+}
+
+pub struct Journal {}
+
+impl Journal {
+    pub fn create() -> Result<usize, Error> {
+        let con = db_pool::connection()?;
+        let created = con.execute(
+            "CREATE TABLE IF NOT EXISTS journal_entries (
+                id INTEGER PRIMARY KEY AUTOINCREMENT,
+                companion_id INTEGER NOT NULL DEFAULT 1,
+                kind TEXT NOT NULL,
+                entry_date TEXT NOT NULL,
+                content TEXT NOT NULL,
+                created_at TEXT NOT NULL
+            )",
+            [],
+        )?;
+        // Entries written before companions kept their own journal belong to the first one
+        if !Database::has_column(&con, "journal_entries", "companion_id")? {
+            con.execute(
+                "ALTER TABLE journal_entries ADD COLUMN companion_id INTEGER NOT NULL DEFAULT 1",
+                [],
+            )?;
+        }
+        Ok(created)
+    }
+
+    /// `entry_date` is the day the entry is about, formatted as %Y-%m-%d
+    pub fn add_entry(companion_id: i32, kind: &str, entry_date: &str, content: &str) -> Result<i64> {
+        let con = db_pool::connection()?;
+        con.execute(
+            "INSERT INTO journal_entries (companion_id, kind, entry_date, content, created_at) VALUES (?, ?, ?, ?, ?)",
+            params![companion_id, kind, entry_date, content, get_current_date()],
+        )?;
+        Ok(con.last_insert_rowid())
+    }
+
+    pub fn has_entry(companion_id: i32, kind: &str, entry_date: &str) -> Result<bool> {
+        let con = db_pool::connection()?;
+        let count: i64 = con.query_row(
+            "SELECT COUNT(*) FROM journal_entries WHERE companion_id = ? AND kind = ? AND entry_date = ?",
+            params![companion_id, kind, entry_date],
+            |row| row.get(0),
+        )?;
+        Ok(count > 0)
+    }
+
+    /// Latest entries of one companion, newest first
+    pub fn get_entries(companion_id: i32, limit: usize) -> Result<Vec<JournalEntry>> {
+        let con = db_pool::connection()?;
+        let mut stmt = con.prepare(
+            "SELECT id, companion_id, kind, entry_date, content, created_at
+             FROM journal_entries WHERE companion_id = ? ORDER BY id DESC LIMIT ?",
+        )?;
+        let rows = stmt.query_map(params![companion_id, limit], |row| {
+            Ok(JournalEntry {
+                id: row.get(0)?,
+                companion_id: row.get(1)?,
+                kind: row.get(2)?,
+                entry_date: row.get(3)?,
+                content: row.get(4)?,
+                created_at: row.get(5)?,
+            })
+        })?;
+        rows.collect()
+    }
+}
@@ -1,64 +1,543 @@
 use chrono::{DateTime, Local};
-use std::io::Write;
+use rand::rngs::StdRng;
+use rand::SeedableRng;
+use serde::Serialize;
+use std::collections::HashMap;
+use std::path::Path;
+use std::sync::{Arc, Mutex, PoisonError, RwLock, RwLockReadGuard};
 
 use crate::attitude_formatter::AttitudeFormatter;
-use crate::context_manager::ContextManager;
+use crate::context_manager::{ContextManager, ExampleDialogueSelection, LoreSelection, PromptCuts};
+use crate::conversations::{ConversationOverrides, Conversations};
 use crate::database::{
-    contains_time_question, get_current_date, CompanionView, ConfigView, Database, Device, Message,
-    NewMessage, PromptTemplate, UserView,
+    contains_time_question, get_current_date, CompanionAttitude, CompanionView, ConfigView, Database,
+    Device, Message, NewMessage, PromptTemplate, UserView,
 };
 use crate::dialogue_tuning::DialogueTuning;
-use crate::gpu_allocator::GpuAllocator;
+use crate::filters::{Filters, Stage};
+use crate::event_bus;
+use crate::hardware_probe::{self, LayerPlan};
 use crate::inference_optimizer::INFERENCE_OPTIMIZER;
-use crate::inference_performance::{ModelConfig, INFERENCE_TRACKER};
+use crate::inference_queue;
+use crate::inference_performance::{
+    BenchmarkReport, BenchmarkResult, ModelConfig, BENCHMARK_SUITE, INFERENCE_TRACKER,
+};
 use crate::long_term_mem::LongTermMem;
+use crate::lorebook::{self, Lorebook};
+use crate::maintenance::GenerationGuard;
+use crate::message_attempts::{MessageAttempt, MessageAttempts, SamplingSettings};
+use crate::message_feedback::MessageFeedback;
+use crate::message_images;
+use crate::message_usage::ReplyStats;
+use crate::memory_proposals::MemoryProposals;
+use crate::mood::Mood;
+use crate::mood_modifiers::MoodModifier;
+use crate::naming::{fill_placeholders, identity_note, reference};
+use crate::template_variables::Variables;
+use crate::personality_traits::{self, PersonalityTraits};
+use crate::prompt_layout::{cap_tokens, PromptLayout};
+use crate::prompt_templates::{self, PromptContext, PromptTemplateEntry, PromptTemplates, TemplateMessage};
+use crate::remote_llm::RemoteBackend;
+use crate::sampling::{SamplingOverrides, SamplingParams};
+use crate::scene::Scene;
+use crate::goals::{self, Goals};
+use crate::shutdown;
+use crate::token_budget::{PromptSection, TokenBudget};
 
-pub fn prompt(prompt: &str) -> Result<String, std::io::Error> {
-    let start_time = std::time::Instant::now();
-    let long_term_memory = match LongTermMem::connect() {
-        Ok(ltm) => ltm,
-        Err(e) => {
-            eprintln!("Error while connecting to tantivy: {}", e);
-            return Err(std::io::Error::new(
-                std::io::ErrorKind::Other,
-                "Error while connecting to tantivy",
-            ));
-        }
+/// Reply to a user message, `sampling` changes the config's sampling settings for this reply only
+pub fn prompt(prompt: &str, sampling: &SamplingOverrides) -> Result<Reply, std::io::Error> {
+    generate(prompt, None, ReplyMode::Chat, sampling, &mut |_| true)
+}
+
+/// Same as prompt(), but hands every generated token to `on_token` as soon as it is inferred
+///
+/// Tokens are raw model output, the returned reply is cleaned up. Generation
+/// stops early, keeping the reply so far, once `on_token` returns false.
+pub fn prompt_streaming(
+    prompt: &str,
+    sampling: &SamplingOverrides,
+    on_token: &mut dyn FnMut(&str) -> bool,
+) -> Result<Reply, std::io::Error> {
+    generate(prompt, None, ReplyMode::Chat, sampling, on_token)
+}
+
+/// Let the companion speak first, following a direction that is not shown in the chat
+///
+/// Waits for its turn in the inference queue, chat requests can't cancel it.
+pub fn proactive_prompt(direction: &str) -> Result<String, std::io::Error> {
+    let _slot = inference_queue::turn("proactive", Some(&inference_queue::chat_session()))
+        .map_err(|_| std::io::Error::new(std::io::ErrorKind::Other, "Left the inference queue"))?;
+    generate(direction, Some(direction), ReplyMode::Chat, &SamplingOverrides::default(), &mut |_| true)
+        .map(|reply| reply.text)
+}
+
+/// Reply within an incognito session, `history` is the session's transcript ending with the prompt
+///
+/// Nothing is read from or written to the chat log, long-term memory or inference metrics.
+pub fn incognito_prompt(prompt: &str, history: &[Message]) -> Result<String, std::io::Error> {
+    generate(prompt, None, ReplyMode::Incognito(history), &SamplingOverrides::default(), &mut |_| true)
+        .map(|reply| reply.text)
+}
+
+/// Replies to a past user message, at most this many per request
+pub const MAX_ALTERNATIVES: usize = 5;
+
+/// Generate `count` more replies to a user message, each with its own seed, and keep them as attempts
+///
+/// The context is the chat up to the user message. The reply shown in the chat stays canonical,
+/// the chat log and long-term memory only change once the client promotes one of the alternatives.
+/// A seed in `sampling` makes the alternatives reproducible, they get that seed counted up.
+pub fn alternatives(
+    user_message: &Message,
+    reply: &Message,
+    count: usize,
+    sampling: &SamplingOverrides,
+) -> Result<Vec<MessageAttempt>, std::io::Error> {
+    // Chats from before attempts were recorded would lose their reply once an alternative is promoted
+    MessageAttempts::keep_reply(user_message.id, &reply.content)
+        .map_err(|e| std::io::Error::new(std::io::ErrorKind::Other, e.to_string()))?;
+    let mut attempt_ids = Vec::new();
+    for i in 0..count {
+        let sampling = SamplingOverrides {
+            seed: Some(match sampling.seed {
+                Some(seed) => seed.wrapping_add(i as u64),
+                None => rand::random(),
+            }),
+            ..sampling.clone()
+        };
+        let reply = generate(
+            &user_message.content,
+            None,
+            ReplyMode::Alternative(user_message.id),
+            &sampling,
+            &mut |_| true,
+        )?;
+        attempt_ids.extend(reply.attempt_id);
+    }
+    let attempts = MessageAttempts::get_attempts(user_message.id)
+        .map_err(|e| std::io::Error::new(std::io::ErrorKind::Other, e.to_string()))?;
+    Ok(attempts
+        .into_iter()
+        .filter(|attempt| attempt_ids.contains(&attempt.id))
+        .collect())
+}
+
+/// Where the context of a reply comes from and where the reply goes
+#[derive(Clone, Copy)]
+enum ReplyMode<'a> {
+    /// Next reply in the active chat, stored in the chat log and long-term memory
+    Chat,
+    /// Reply within an incognito session's transcript, nothing is stored
+    Incognito(&'a [Message]),
+    /// One more reply to the user message with this id, only kept as an attempt that isn't canonical
+    Alternative(i32),
+}
+
+/// Cleaned up reply and the attempt it was recorded as
+pub struct Reply {
+    pub text: String,
+    attempt_id: Option<i64>,
+    /// Prompt sections left out so the prompt fit the context window
+    pub dropped_sections: Vec<PromptSection>,
+}
+
+/// Persona part of the prompt the next message would get, without loading the model
+#[derive(Serialize)]
+pub struct PromptPreview {
+    pub base_prompt: String,
+    pub example_dialogue: ExampleDialogueSelection,
+    pub lore: LoreSelection,
+}
+
+pub fn prompt_preview(prompt: &str) -> Result<PromptPreview, rusqlite::Error> {
+    let config = Database::get_config()?;
+    let user = Database::get_user_data()?;
+    let companion = Database::get_companion_data()?;
+    let layout = PromptLayout::get()?;
+    let mut context_manager = ContextManager::new(config.clone());
+    context_manager.apply_layout(&layout);
+    let variables = Variables::load(Database::active_companion_id(), Database::active_user_id());
+    let (mut parts, mut example_dialogue) =
+        persona_parts(&user, &companion, &context_manager, prompt, &variables);
+    fit_to_layout(&mut parts, &mut example_dialogue, &layout, &context_manager, &PromptCuts::default());
+    let recent = Database::get_x_messages(lorebook::SCAN_DEPTH, 0)?;
+    let lore = if layout.enabled(PromptSection::Lore) {
+        lore_selection(&context_manager, &companion, &user, prompt, &recent, &variables)
+    } else {
+        LoreSelection::default()
     };
-    let local: DateTime<Local> = Local::now();
-    let formatted_date = local.format("* at %A %d.%m.%Y %H:%M *\n").to_string();
-    let config: ConfigView = match Database::get_config() {
-        Ok(config) => config,
-        Err(e) => {
-            eprintln!("Error while getting config: {}", e);
-            return Err(std::io::Error::new(
-                std::io::ErrorKind::Other,
-                "Error while getting config",
-            ));
+    // A custom template is previewed without memories and history, as those depend on the reply
+    let base_prompt = match PromptTemplates::active(&config)? {
+        Some(template) => {
+            let mut context = template_context(&user, &companion, &parts);
+            context.lore = lore.entries.clone();
+            prompt_templates::render(&template.template, &context).map_err(|e| {
+                rusqlite::Error::InvalidParameterName(format!("Invalid prompt template: {}", e))
+            })?
+        }
+        None => {
+            let blocks = base_blocks(&config, &user, &companion, &parts, &layout);
+            let mut base_prompt = blocks.head;
+            for section in layout.order() {
+                match section {
+                    PromptSection::Persona => base_prompt += &blocks.persona,
+                    PromptSection::ExampleDialogue => base_prompt += &blocks.example_dialogue,
+                    _ => {}
+                }
+            }
+            base_prompt
         }
     };
-    let user: UserView = match Database::get_user_data() {
-        Ok(user) => user,
+    Ok(PromptPreview {
+        base_prompt,
+        example_dialogue,
+        lore,
+    })
+}
+
+/// Lorebook entries of the active companion triggered by the prompt or the latest messages
+fn lore_selection(
+    context_manager: &ContextManager,
+    companion: &CompanionView,
+    user: &UserView,
+    prompt: &str,
+    recent: &[Message],
+    variables: &Variables,
+) -> LoreSelection {
+    let entries = match Lorebook::list(Database::active_companion_id()) {
+        Ok(entries) => entries,
         Err(e) => {
-            eprintln!("Error while getting user data: {}", e);
-            return Err(std::io::Error::new(
-                std::io::ErrorKind::Other,
-                "Error while getting user data",
-            ));
+            tracing::warn!("⚠️ Could not load lorebook: {}", e);
+            return LoreSelection::default();
         }
     };
-    let companion: CompanionView = match Database::get_companion_data() {
-        Ok(companion) => companion,
-        Err(e) => {
-            eprintln!("Error while getting companion data: {}", e);
-            return Err(std::io::Error::new(
-                std::io::ErrorKind::Other,
-                "Error while getting companion data",
-            ));
+    let mut texts: Vec<&str> = recent[recent.len().saturating_sub(lorebook::SCAN_DEPTH)..]
+        .iter()
+        .map(|message| message.content.as_str())
+        .collect();
+    texts.push(prompt);
+    let mut selection = context_manager.select_lore(&entries, &texts);
+    for entry in selection.entries.iter_mut() {
+        *entry = variables.expand(entry, companion, user);
+    }
+    selection
+}
+
+/// Persona texts with placeholders filled in, every prompt format is built from these
+struct PersonaParts {
+    roleplay: &'static str,
+    user_note: String,
+    user_persona: String,
+    companion_note: String,
+    companion_persona: String,
+    example_dialogue: String,
+    tuned_dialogue: String,
+    system_prompt: String,
+    content_policy: String,
+    post_history_instructions: String,
+}
+
+fn persona_parts(
+    user: &UserView,
+    companion: &CompanionView,
+    context_manager: &ContextManager,
+    prompt: &str,
+    variables: &Variables,
+) -> (PersonaParts, ExampleDialogueSelection) {
+    let mut rp: &'static str = "";
+    let mut tuned_dialogue: String = String::from("");
+    // Large example dialogues would crowd out the conversation itself
+    let companion_id = Database::active_companion_id();
+    let disliked = MessageFeedback::disliked_replies(companion_id).unwrap_or_else(|e| {
+        tracing::warn!("⚠️ Could not load disliked replies: {}", e);
+        Vec::new()
+    });
+    let example_dialogue =
+        context_manager.select_example_dialogue(&companion.example_dialogue, prompt, &disliked);
+    if companion.roleplay {
+        rp = "gestures and other non-verbal actions are written between asterisks (for example, *waves hello* or *moves closer*)";
+    }
+    if companion.dialogue_tuning {
+        match DialogueTuning::get_random_dialogue(companion_id) {
+            Ok(dialogue) => {
+                tuned_dialogue = format!(
+                    "{}: {}\n{}: {}",
+                    &user.name, &dialogue.user_msg, &companion.name, &dialogue.ai_msg
+                );
+            }
+            Err(_) => {}
+        };
+    }
+    // Traits are told to the model as how they show, after the persona they refine
+    let traits = PersonalityTraits::get(companion_id).unwrap_or_else(|e| {
+        tracing::warn!("⚠️ Could not load personality traits: {}", e);
+        Vec::new()
+    });
+    let mut companion_persona = variables.expand(&companion.persona, companion, user);
+    if let Some(line) = personality_traits::prompt_line(&companion.name, &traits) {
+        if !companion_persona.trim().is_empty() {
+            companion_persona.push(' ');
         }
+        companion_persona.push_str(&line);
+    }
+    let parts = PersonaParts {
+        roleplay: rp,
+        user_note: identity_note(&user.name, &user.nickname, &user.pronouns),
+        user_persona: variables.expand(&user.persona, companion, user),
+        companion_note: identity_note(&companion.name, &companion.nickname, &companion.pronouns),
+        companion_persona,
+        example_dialogue: variables.expand(&example_dialogue.text, companion, user),
+        tuned_dialogue,
+        system_prompt: variables.expand(companion.system_prompt.trim(), companion, user),
+        content_policy: variables.expand(companion.content_policy.trim(), companion, user),
+        post_history_instructions: variables.expand(
+            companion.post_history_instructions.trim(),
+            companion,
+            user,
+        ),
     };
+    (parts, example_dialogue)
+}
+
+/// Context for a custom template, memories, history and direction are added by the caller
+fn template_context(user: &UserView, companion: &CompanionView, parts: &PersonaParts) -> PromptContext {
+    PromptContext {
+        char: companion.name.clone(),
+        user: user.name.clone(),
+        char_persona: format!("{}{}", parts.companion_note, parts.companion_persona),
+        user_persona: format!("{}{}", parts.user_note, parts.user_persona),
+        roleplay: parts.roleplay.to_string(),
+        example_dialogue: parts.example_dialogue.clone(),
+        tuned_dialogue: parts.tuned_dialogue.clone(),
+        system_prompt: parts.system_prompt.clone(),
+        content_policy: parts.content_policy.clone(),
+        post_history_instructions: parts.post_history_instructions.clone(),
+        date: get_current_date(),
+        ..Default::default()
+    }
+}
 
-    let llama_model_params = {
+/// The companion's system prompt, or the built-in opening when it has none
+fn opening(user: &UserView, companion: &CompanionView, parts: &PersonaParts) -> String {
+    if parts.system_prompt.is_empty() {
+        format!("Text transcript of a conversation between {} and {}.", user.name, companion.name)
+    } else {
+        parts.system_prompt.clone()
+    }
+}
+
+/// Content policy on a line of its own, nothing when the companion has none
+fn policy_line(parts: &PersonaParts) -> String {
+    if parts.content_policy.is_empty() {
+        String::new()
+    } else {
+        format!("{}\n", parts.content_policy)
+    }
+}
+
+/// Parts of a built-in prompt format that stay the same from one message to the next
+struct BaseBlocks {
+    /// System prompt and content policy, the prompt always opens with these
+    head: String,
+    persona: String,
+    /// Example dialogue and dialogue tuning
+    example_dialogue: String,
+}
+
+/// System prompt, content policy, persona, example dialogue and dialogue tuning blocks
+///
+/// Lore, memories, history and attitudes are placed around the persona and example dialogue as
+/// the layout says, post-history instructions always follow them.
+fn base_blocks(
+    config: &ConfigView,
+    user: &UserView,
+    companion: &CompanionView,
+    parts: &PersonaParts,
+    layout: &PromptLayout,
+) -> BaseBlocks {
+    let rp = parts.roleplay;
+    if config.prompt_template == PromptTemplate::Default {
+        BaseBlocks {
+            head: format!("{} {}\n{}", opening(user, companion, parts), rp, policy_line(parts)),
+            persona: format!(
+                "{}'s Persona: {}{}\n{}'s Persona: {}{}\n<START>\n",
+                user.name,
+                parts.user_note,
+                parts.user_persona,
+                companion.name,
+                parts.companion_note,
+                parts.companion_persona
+            ),
+            example_dialogue: format!(
+                "{}\n<START>\n{}\n<START>\n",
+                parts.example_dialogue, parts.tuned_dialogue
+            ),
+        }
+    } else if config.prompt_template == PromptTemplate::Llama2 {
+        let system_prompt = match parts.system_prompt.as_str() {
+            "" => String::new(),
+            system_prompt => format!("{}\n", system_prompt),
+        };
+        // The roleplay note closes the persona, it closes the system part without one
+        let closing = format!("{}\n[INST]\n", rp);
+        let mut head = format!("<<SYS>>\n{}{}", system_prompt, policy_line(parts));
+        if !layout.enabled(PromptSection::Persona) {
+            head += &closing;
+        }
+        BaseBlocks {
+            head,
+            persona: format!(
+                "You are {}, {}{}\nyou are talking with {}, {}{} is {}\n{}",
+                companion.name,
+                parts.companion_note,
+                parts.companion_persona,
+                user.name,
+                parts.user_note,
+                user.name,
+                parts.user_persona,
+                closing
+            ),
+            example_dialogue: format!(
+                "{}\n{}\n[/INST]\n",
+                parts.example_dialogue, parts.tuned_dialogue
+            ),
+        }
+    } else {
+        BaseBlocks {
+            head: format!(
+                "<s>[INST]{} {}\n{}",
+                opening(user, companion, parts),
+                rp,
+                policy_line(parts)
+            ),
+            persona: format!(
+                "{}'s Persona: {}{}\n{}'s Persona: {}{}[/INST]\n<s>[INST]\n",
+                user.name,
+                parts.user_note,
+                parts.user_persona,
+                companion.name,
+                parts.companion_note,
+                parts.companion_persona
+            ),
+            example_dialogue: format!(
+                "{}[/INST]\n<s>[INST]\n{}[/INST]\n",
+                parts.example_dialogue, parts.tuned_dialogue
+            ),
+        }
+    }
+}
+
+/// Clear the persona and example dialogue the layout switched off or `cuts` dropped, and cut
+/// the persona down to its cap, the companion's persona is kept before the user's
+fn fit_to_layout(
+    parts: &mut PersonaParts,
+    example_dialogue: &mut ExampleDialogueSelection,
+    layout: &PromptLayout,
+    context_manager: &ContextManager,
+    cuts: &PromptCuts,
+) {
+    if !layout.enabled(PromptSection::Persona) {
+        parts.user_note.clear();
+        parts.user_persona.clear();
+        parts.companion_note.clear();
+        parts.companion_persona.clear();
+    } else if let Some(cap) = context_manager.token_budget.cap(PromptSection::Persona) {
+        let notes = ContextManager::estimate_tokens(&parts.companion_note)
+            + ContextManager::estimate_tokens(&parts.user_note);
+        parts.companion_persona = cap_tokens(&parts.companion_persona, cap.saturating_sub(notes));
+        let left = cap
+            .saturating_sub(notes)
+            .saturating_sub(ContextManager::estimate_tokens(&parts.companion_persona));
+        parts.user_persona = cap_tokens(&parts.user_persona, left);
+    }
+    if !layout.enabled(PromptSection::ExampleDialogue) {
+        parts.tuned_dialogue.clear();
+    }
+    if !layout.enabled(PromptSection::ExampleDialogue) || cuts.has(PromptSection::ExampleDialogue) {
+        parts.example_dialogue.clear();
+        example_dialogue.text.clear();
+        example_dialogue.used_tokens = 0;
+        example_dialogue.kept = 0;
+    }
+}
+
+/// Settings the loaded model was built with, a generation with other settings loads it again
+#[derive(Clone, Debug, PartialEq)]
+struct ModelKey {
+    model_path: String,
+    device: String,
+    gpu_layers: usize,
+    dynamic_gpu_allocation: bool,
+}
+
+impl ModelKey {
+    fn new(config: &ConfigView) -> Self {
+        ModelKey {
+            model_path: config.llm_model_path.clone(),
+            device: config.device.to_string(),
+            gpu_layers: config.gpu_layers,
+            dynamic_gpu_allocation: config.dynamic_gpu_allocation,
+        }
+    }
+}
+
+struct LoadedModel {
+    key: ModelKey,
+    model: Arc<dyn llm::Model>,
+    gpu_layers: usize,
+    vram_plan: Option<LayerPlan>,
+}
+
+/// What the model manager is doing, served by /api/llm/status
+#[derive(Serialize, Debug, Clone, Default)]
+pub struct ModelStatus {
+    /// "unloaded", "loading", "loaded" or "failed"
+    pub state: String,
+    pub model_path: Option<String>,
+    pub tensors_loaded: usize,
+    pub tensor_count: usize,
+    /// Share of the tensors loaded so far, between 0 and 1
+    pub progress: f32,
+    pub gpu_layers: Option<usize>,
+    pub file_size_mb: Option<f32>,
+    pub loaded_at: Option<String>,
+    pub error: Option<String>,
+}
+
+lazy_static::lazy_static! {
+    // Generations hold a read lock while they run, loading takes the write lock so a swap
+    // waits for the replies in flight and holds new ones back until the new model is ready
+    static ref MODEL: RwLock<Option<LoadedModel>> = RwLock::new(None);
+    static ref MODEL_STATUS: Mutex<ModelStatus> = Mutex::new(ModelStatus {
+        state: String::from("unloaded"),
+        ..Default::default()
+    });
+}
+
+/// Read access to the loaded model for the length of one generation
+struct ModelLease(RwLockReadGuard<'static, Option<LoadedModel>>);
+
+impl std::ops::Deref for ModelLease {
+    type Target = LoadedModel;
+
+    fn deref(&self) -> &LoadedModel {
+        self.0.as_ref().expect("a lease is only handed out for a loaded model")
+    }
+}
+
+pub fn model_status() -> ModelStatus {
+    MODEL_STATUS.lock().map(|status| status.clone()).unwrap_or_default()
+}
+
+fn update_status(update: impl FnOnce(&mut ModelStatus)) {
+    if let Ok(mut status) = MODEL_STATUS.lock() {
+        update(&mut status);
+    }
+}
+
+fn model_parameters(config: &ConfigView) -> (llm::ModelParameters, Option<LayerPlan>) {
+    let mut vram_plan = None;
+    let params = {
         let mut params = llm::ModelParameters::default();
         
         // Enable performance optimizations for all devices
@@ -69,309 +548,807 @@ pub fn prompt(prompt: &str) -> Result<String, std::io::Error> {
 
             // Use dynamic GPU allocation if enabled
             if config.dynamic_gpu_allocation {
-                let allocator = GpuAllocator::new()
-                    .with_safety_margin(config.gpu_safety_margin)
-                    .with_min_free_vram(config.min_free_vram_mb);
-
-                match allocator.detect_gpu_memory(&config.device) {
-                    Ok(gpu_info) => {
-                        println!("🔍 GPU Detection: {}", gpu_info);
-
-                        let vram_limit = if config.vram_limit_gb > 0 {
-                            Some(config.vram_limit_gb as f32)
-                        } else {
-                            None
-                        };
-
-                        // Estimate model size (this would ideally come from model metadata)
-                        let estimated_model_size_mb = 4096;
-                        let estimated_total_layers = 32;
-
-                        // Use the new optimized allocation method
-                        let allocation = allocator.calculate_optimal_layers_v2(
-                            &gpu_info,
-                            &config.llm_model_path,
-                            estimated_model_size_mb,
-                            estimated_total_layers,
-                            vram_limit,
-                        );
-
-                        println!("🎯 Dynamic Allocation: {}", allocation);
-                        params.gpu_layers = Some(allocation.gpu_layers);
+                match hardware_probe::plan_for_config(config) {
+                    Ok(plan) => {
+                        tracing::info!("Dynamic allocation: {}", plan);
+                        params.gpu_layers = Some(plan.gpu_layers);
+                        vram_plan = Some(plan);
                     }
                     Err(e) => {
-                        eprintln!("⚠️ GPU detection failed, using configured layers: {}", e);
+                        tracing::warn!("GPU layer selection failed, using configured layers: {}", e);
                         params.gpu_layers = Some(config.gpu_layers);
                     }
                 }
             } else {
-                println!("📌 Static Allocation: {} GPU layers", config.gpu_layers);
+                tracing::info!("Static allocation: {} GPU layers", config.gpu_layers);
                 params.gpu_layers = Some(config.gpu_layers);
             }
         } else {
             params.use_gpu = false;
             params.gpu_layers = None;
-            println!("💻 CPU-only inference mode");
+            tracing::info!("CPU-only inference mode");
         }
         params
     };
+    (params, vram_plan)
+}
 
-    let llama = llm::load(
+/// Load the model `config` points at, reporting progress through /api/llm/status and the event stream
+fn load_model(config: &ConfigView) -> Result<LoadedModel, String> {
+    let (params, vram_plan) = model_parameters(config);
+    if let Some(plan) = &vram_plan {
+        hardware_probe::remember(plan);
+    }
+    let gpu_layers = params.gpu_layers.unwrap_or(config.gpu_layers);
+    update_status(|status| {
+        *status = ModelStatus {
+            state: String::from("loading"),
+            model_path: Some(config.llm_model_path.clone()),
+            gpu_layers: Some(gpu_layers),
+            ..Default::default()
+        }
+    });
+    event_bus::publish(
+        "model_loading",
+        serde_json::json!({ "model_path": config.llm_model_path }),
+    );
+    tracing::info!("Loading model {}", config.llm_model_path);
+
+    let mut last_percent = 0;
+    let loaded = llm::load(
         std::path::Path::new(&config.llm_model_path),
         llm::TokenizerSource::Embedded,
-        llama_model_params,
-        // Use a quiet callback that only shows essential information
-        |progress| {
-            match progress {
-                llm::LoadProgress::HyperparametersLoaded => {
-                    print!("📚 Loading model... ");
-                    std::io::stdout().flush().unwrap();
-                }
-                llm::LoadProgress::Loaded { file_size, tensor_count } => {
-                    println!("✓ Model loaded ({} tensors, {:.2} MB)", tensor_count, file_size as f32 / 1024.0 / 1024.0);
+        params,
+        |progress| match progress {
+            llm::LoadProgress::TensorLoaded { current_tensor, tensor_count } => {
+                let progress = (current_tensor + 1) as f32 / tensor_count.max(1) as f32;
+                update_status(|status| {
+                    status.tensors_loaded = current_tensor + 1;
+                    status.tensor_count = tensor_count;
+                    status.progress = progress;
+                });
+                // One event per 5% keeps the event stream readable
+                let percent = (progress * 100.0) as usize;
+                if percent >= last_percent + 5 {
+                    last_percent = percent;
+                    event_bus::publish("model_load_progress", serde_json::json!({ "percent": percent }));
                 }
-                // Suppress tensor loading messages
-                _ => {}
             }
+            llm::LoadProgress::Loaded { file_size, tensor_count } => {
+                let file_size_mb = file_size as f32 / 1024.0 / 1024.0;
+                tracing::info!("Model loaded ({} tensors, {:.2} MB)", tensor_count, file_size_mb);
+                update_status(|status| {
+                    status.tensors_loaded = tensor_count;
+                    status.tensor_count = tensor_count;
+                    status.file_size_mb = Some(file_size_mb);
+                });
+            }
+            _ => {}
         },
     );
 
-    let llama = match llama {
-        Ok(llama) => llama,
+    match loaded {
+        Ok(model) => {
+            update_status(|status| {
+                status.state = String::from("loaded");
+                status.progress = 1.0;
+                status.loaded_at = Some(get_current_date());
+            });
+            event_bus::publish(
+                "model_loaded",
+                serde_json::json!({ "model_path": config.llm_model_path, "gpu_layers": gpu_layers }),
+            );
+            Ok(LoadedModel {
+                key: ModelKey::new(config),
+                model: Arc::from(model),
+                gpu_layers,
+                vram_plan,
+            })
+        }
         Err(e) => {
-            return Err(std::io::Error::new(
-                std::io::ErrorKind::Other,
-                format!("Failed to load llm model: {}", e.to_string()),
-            ))
+            let message = format!("Failed to load llm model: {}", e);
+            tracing::error!("{}", message);
+            update_status(|status| {
+                status.state = String::from("failed");
+                status.error = Some(message.clone());
+            });
+            event_bus::publish("model_load_failed", serde_json::json!({ "error": message }));
+            Err(message)
         }
-    };
+    }
+}
 
-    // Calculate CPU cores for optimizations
-    let cpu_cores = std::thread::available_parallelism()
-        .map(|n| n.get())
-        .unwrap_or(4); // Fallback to 4 cores if detection fails
+/// Swap `slot` for the configured model, the old one is dropped first to free its memory
+fn swap_model(slot: &mut Option<LoadedModel>, config: &ConfigView) -> Result<(), String> {
+    *slot = None;
+    update_status(|status| status.state = String::from("unloaded"));
+    *slot = Some(load_model(config)?);
+    Ok(())
+}
 
-    // Create optimized session configuration for better caching
-    let session_config = llm::InferenceSessionConfig {
-        n_threads: cpu_cores,                         // Use all CPU cores for session
-        n_batch: 512,                                // Larger batch size
-        memory_k_type: llm::ModelKVMemoryType::Float16, // Use F16 for KV cache
-        memory_v_type: llm::ModelKVMemoryType::Float16,
+/// The model for this generation, loaded first when none is or the settings changed
+fn acquire_model(config: &ConfigView) -> Result<ModelLease, std::io::Error> {
+    let key = ModelKey::new(config);
+    loop {
+        let slot = MODEL.read().unwrap_or_else(PoisonError::into_inner);
+        if slot.as_ref().map_or(false, |model| model.key == key) {
+            return Ok(ModelLease(slot));
+        }
+        drop(slot);
+
+        let mut slot = MODEL.write().unwrap_or_else(PoisonError::into_inner);
+        // Another generation may have loaded it while this one waited
+        if slot.as_ref().map_or(true, |model| model.key != key) {
+            swap_model(&mut slot, config)
+                .map_err(|e| std::io::Error::new(std::io::ErrorKind::Other, e))?;
+        }
+    }
+}
+
+/// Unload the current model and load the configured one, blocks until generations in flight are done
+pub fn reload_model() -> Result<ModelStatus, String> {
+    let mut slot = MODEL.write().unwrap_or_else(PoisonError::into_inner);
+    let config = Database::get_config().map_err(|e| e.to_string())?;
+    swap_model(&mut slot, &config)?;
+    Ok(model_status())
+}
+
+/// Load the configured model ahead of the first reply, nothing to do when replies come from a server
+pub fn preload_model() -> Result<(), String> {
+    let config = Database::get_config().map_err(|e| e.to_string())?;
+    if !config.llm_api_url.trim().is_empty() {
+        return Ok(());
+    }
+    let mut slot = MODEL.write().unwrap_or_else(PoisonError::into_inner);
+    // A reply may have loaded it already
+    if slot.is_none() {
+        swap_model(&mut slot, &config)?;
+    }
+    Ok(())
+}
+
+/// Bring the loaded model in line with a changed config, so the next reply doesn't load it first
+///
+/// Replies in flight finish with the old model, new ones wait for the swap. Nothing happens when
+/// no model is loaded, and switching to a remote backend frees the local one.
+pub fn follow_config() -> Result<(), String> {
+    let config = Database::get_config().map_err(|e| e.to_string())?;
+    let key = ModelKey::new(&config);
+    let remote = !config.llm_api_url.trim().is_empty();
+    let outdated = |slot: &Option<LoadedModel>| {
+        slot.as_ref().is_some_and(|model| remote || model.key != key)
     };
-    
-    let mut session = llama.start_session(session_config);
-    println!("🚀 Generating AI response with optimized session...");
-    let mut base_prompt: String;
-    let mut rp: &str = "";
-    let mut tuned_dialogue: String = String::from("");
-    if companion.roleplay {
-        rp = "gestures and other non-verbal actions are written between asterisks (for example, *waves hello* or *moves closer*)";
+    if !outdated(&MODEL.read().unwrap_or_else(PoisonError::into_inner)) {
+        return Ok(());
     }
-    if companion.dialogue_tuning {
-        match DialogueTuning::get_random_dialogue() {
-            Ok(dialogue) => {
-                tuned_dialogue = format!(
-                    "{}: {}\n{}: {}",
-                    &user.name, &dialogue.user_msg, &companion.name, &dialogue.ai_msg
+    if remote {
+        tracing::info!("Unloading the local model, replies come from {}", config.llm_api_url);
+        unload_model();
+        return Ok(());
+    }
+    let mut slot = MODEL.write().unwrap_or_else(PoisonError::into_inner);
+    // A reply may have loaded the new model while this waited for the lock
+    if outdated(&slot) {
+        swap_model(&mut slot, &config)?;
+    }
+    Ok(())
+}
+
+/// Free the model's memory, the next generation loads it again
+pub fn unload_model() {
+    let mut slot = MODEL.write().unwrap_or_else(PoisonError::into_inner);
+    *slot = None;
+    update_status(|status| {
+        *status = ModelStatus {
+            state: String::from("unloaded"),
+            ..Default::default()
+        }
+    });
+}
+
+/// What a backend reports about a finished completion
+pub struct Completion {
+    /// Tokens generated, None when the backend can't tell
+    pub tokens: Option<u32>,
+    pub stats: Option<String>,
+}
+
+/// Where replies are generated, the loaded model or a server running it for us
+pub trait Backend {
+    /// Stored with the inference metrics of every completion
+    fn model_config(&self) -> ModelConfig;
+    /// Complete `prompt`, handing the text to `on_token` as it comes in until it returns false
+    fn complete(
+        &self,
+        prompt: &str,
+        sampling: &SamplingParams,
+        max_tokens: usize,
+        on_token: &mut dyn FnMut(&str) -> bool,
+    ) -> Result<Completion, String>;
+}
+
+/// The model loaded into this process, held for the length of one generation
+struct LocalBackend {
+    model: ModelLease,
+    model_config: ModelConfig,
+}
+
+impl Backend for LocalBackend {
+    fn model_config(&self) -> ModelConfig {
+        self.model_config.clone()
+    }
+
+    fn complete(
+        &self,
+        prompt: &str,
+        sampling: &SamplingParams,
+        max_tokens: usize,
+        on_token: &mut dyn FnMut(&str) -> bool,
+    ) -> Result<Completion, String> {
+        let cpu_cores = std::thread::available_parallelism()
+            .map(|n| n.get())
+            .unwrap_or(4); // Fallback to 4 cores if detection fails
+        // A fresh session per completion, nothing is reused from the previous one
+        let mut session = self.model.model.start_session(llm::InferenceSessionConfig {
+            n_threads: cpu_cores,                           // Use all CPU cores for session
+            n_batch: 512,                                   // Larger batch size
+            memory_k_type: llm::ModelKVMemoryType::Float16, // Use F16 for KV cache
+            memory_v_type: llm::ModelKVMemoryType::Float16,
+        });
+        let parameters = inference_parameters(self.model.model.as_ref(), sampling);
+        let mut rng = StdRng::seed_from_u64(sampling.seed.unwrap_or_else(rand::random));
+        let mut tokens = 0u32;
+        let stats = session
+            .infer::<std::convert::Infallible>(
+                self.model.model.as_ref(),
+                &mut rng,
+                &llm::InferenceRequest {
+                    prompt: llm::Prompt::Text(prompt),
+                    parameters: &parameters,
+                    play_back_previous_tokens: false,
+                    maximum_token_count: Some(max_tokens),
+                },
+                &mut Default::default(),
+                |t| {
+                    if let llm::InferenceResponse::InferredToken(token) = t {
+                        tokens += 1;
+                        if !on_token(&token) {
+                            return Ok(llm::InferenceFeedback::Halt);
+                        }
+                    }
+                    Ok(llm::InferenceFeedback::Continue)
+                },
+            )
+            .map_err(|e| e.to_string())?;
+        Ok(Completion {
+            tokens: Some(tokens),
+            stats: Some(stats.to_string()),
+        })
+    }
+}
+
+/// Backend of the active companion's config, the local model unless a server is set
+fn backend(config: &ConfigView) -> Result<Box<dyn Backend>, std::io::Error> {
+    if config.llm_api_url.trim().is_empty() {
+        // Held until the reply is done, a reload waits for it and then swaps the model
+        let model = acquire_model(config)?;
+        let model_config = ModelConfig {
+            model_path: config.llm_model_path.clone(),
+            gpu_layers: model.gpu_layers as i32,
+            device_type: config.device.to_string(),
+            vram_plan: model.vram_plan.clone(),
+        };
+        Ok(Box::new(LocalBackend { model, model_config }))
+    } else {
+        let api_key = Database::get_llm_api_key(&config.llm_api_url)
+            .map_err(|e| std::io::Error::new(std::io::ErrorKind::Other, e.to_string()))?;
+        Ok(Box::new(RemoteBackend::new(config, api_key)))
+    }
+}
+
+/// Model, gpu_layers and device the configuration generates with, as inference_metrics records them
+///
+/// The configured gpu_layers, the ones picked from the VRAM may differ when dynamic_gpu_allocation is on.
+pub fn configured_model(config: &ConfigView) -> ModelConfig {
+    if config.llm_api_url.trim().is_empty() {
+        ModelConfig {
+            model_path: config.llm_model_path.clone(),
+            gpu_layers: config.gpu_layers as i32,
+            device_type: config.device.to_string(),
+            vram_plan: None,
+        }
+    } else {
+        RemoteBackend::new(config, String::new()).model_config()
+    }
+}
+
+/// Name a reply's stats give the model, the file without its extension or the server's model
+fn model_name(config: &ConfigView) -> String {
+    if !config.llm_api_url.trim().is_empty() {
+        return if config.llm_api_model.trim().is_empty() {
+            config.llm_api_url.clone()
+        } else {
+            config.llm_api_model.clone()
+        };
+    }
+    Path::new(&config.llm_model_path)
+        .file_stem()
+        .map(|stem| stem.to_string_lossy().into_owned())
+        .unwrap_or_else(|| config.llm_model_path.clone())
+}
+
+/// Sampler chain for the loaded model, the llm crate's defaults when the settings are rejected
+fn inference_parameters(model: &dyn llm::Model, sampling: &SamplingParams) -> llm::InferenceParameters {
+    match llm::samplers::build_sampler(model.tokenizer().len(), &[], &sampling.sampler_args()) {
+        Ok(sampler) => llm::InferenceParameters { sampler },
+        Err(e) => {
+            tracing::warn!("⚠️ Invalid sampling settings ({}), using the defaults: {}", sampling.describe(), e);
+            llm::InferenceParameters::default()
+        }
+    }
+}
+
+/// Run the benchmark suite on the configured model, every prompt is stored in inference_metrics
+///
+/// Prompts go to the model as they are, without persona, history or memory.
+pub fn benchmark() -> Result<BenchmarkReport, std::io::Error> {
+    let _slot = inference_queue::turn("benchmark", None)
+        .map_err(|_| std::io::Error::new(std::io::ErrorKind::Other, "Left the inference queue"))?;
+    let _generation = GenerationGuard::begin();
+    let config = Database::get_config()
+        .map_err(|e| std::io::Error::new(std::io::ErrorKind::Other, e.to_string()))?;
+    let backend = backend(&config)?;
+    let model_config = backend.model_config();
+    let sampling = SamplingParams::from_config(&config);
+
+    let mut results = Vec::new();
+    for benchmark_prompt in BENCHMARK_SUITE.iter() {
+        let prompt = benchmark_prompt.prompt();
+        let session_id = format!("benchmark_{}", benchmark_prompt.name);
+        if let Ok(mut tracker) = INFERENCE_TRACKER.lock() {
+            tracker.start_session(
+                session_id.clone(),
+                model_config.clone(),
+                ContextManager::estimate_tokens(&prompt) as u32,
+                "benchmark",
+            );
+        }
+        let mut tokens_generated = 0u32;
+        let res = backend.complete(&prompt, &sampling, benchmark_prompt.max_tokens, &mut |_| {
+            tokens_generated += 1;
+            if let Ok(mut tracker) = INFERENCE_TRACKER.lock() {
+                if tokens_generated == 1 {
+                    tracker.record_first_token(&session_id);
+                }
+                tracker.update_token_count(&session_id, tokens_generated);
+            }
+            true
+        });
+        let mut tracker = INFERENCE_TRACKER.lock().unwrap_or_else(PoisonError::into_inner);
+        match res {
+            Ok(Completion { tokens: Some(tokens), .. }) => tracker.update_token_count(&session_id, tokens),
+            Ok(_) => {}
+            Err(e) => {
+                tracker.abandon_session(&session_id);
+                return Err(std::io::Error::new(
+                    std::io::ErrorKind::Other,
+                    format!("Benchmark prompt '{}' failed: {}", benchmark_prompt.name, e),
+                ));
+            }
+        }
+        let metrics = tracker
+            .complete_session(&session_id)
+            .map_err(|e| std::io::Error::new(std::io::ErrorKind::Other, e.to_string()))?;
+        match metrics {
+            Some(metrics) => {
+                tracing::info!(
+                    target: "inference",
+                    "📊 Benchmark {}: {:.1} tokens/s, first token after {:.2}s",
+                    benchmark_prompt.name,
+                    metrics.tokens_per_second,
+                    metrics.time_to_first_token
                 );
+                results.push(BenchmarkResult {
+                    name: benchmark_prompt.name.to_string(),
+                    metrics,
+                });
             }
-            Err(_) => {}
-        };
+            None => tracing::warn!("Benchmark prompt '{}' generated no tokens", benchmark_prompt.name),
+        }
     }
-    // Build base prompt components for caching optimization
-    let base_components = if config.prompt_template == PromptTemplate::Default {
-        vec![
-            format!(
-                "Text transcript of a conversation between {} and {}. {}\n",
-                user.name, companion.name, rp
-            ),
-            format!(
-                "{}'s Persona: {}\n",
-                user.name,
-                user.persona
-                    .replace("{{char}}", &companion.name)
-                    .replace("{{user}}", &user.name)
-            ),
-            format!(
-                "{}'s Persona: {}\n<START>\n",
-                companion.name,
-                companion
-                    .persona
-                    .replace("{{char}}", &companion.name)
-                    .replace("{{user}}", &user.name)
-            ),
-            format!(
-                "{}\n<START>\n",
-                companion
-                    .example_dialogue
-                    .replace("{{char}}", &companion.name)
-                    .replace("{{user}}", &user.name)
-            ),
-            format!("{}\n<START>\n", &tuned_dialogue),
-        ]
-    } else if config.prompt_template == PromptTemplate::Llama2 {
-        vec![
-            format!(
-                "<<SYS>>\nYou are {}, {}\n",
-                companion.name,
-                companion
-                    .persona
-                    .replace("{{char}}", &companion.name)
-                    .replace("{{user}}", &user.name)
-            ),
-            format!(
-                "you are talking with {}, {} is {}\n{}\n[INST]\n",
-                user.name,
-                user.name,
-                user.persona
-                    .replace("{{char}}", &companion.name)
-                    .replace("{{user}}", &user.name),
-                rp
-            ),
-            format!(
-                "{}\n",
-                companion
-                    .example_dialogue
-                    .replace("{{char}}", &companion.name)
-                    .replace("{{user}}", &user.name)
-            ),
-            format!("{}\n[/INST]\n", &tuned_dialogue),
-        ]
+    Ok(BenchmarkReport::new(&model_config, results))
+}
+
+/// Token estimates of the prompt's sections, as the context manager counts them
+#[derive(Serialize, Debug, Default)]
+pub struct SectionTokens {
+    pub persona: usize,
+    /// System prompt, content policy and post-history instructions
+    pub instructions: usize,
+    pub example_dialogue: usize,
+    pub lore: usize,
+    pub memories: usize,
+    pub attitude: usize,
+    /// Part of `attitude`
+    pub third_party: usize,
+    pub messages: usize,
+    /// The whole prompt
+    pub prompt: usize,
+    /// Tokens the reply may use
+    pub response_limit: usize,
+}
+
+/// Everything a reply is generated from, put together without touching the model
+#[derive(Serialize)]
+pub struct AssembledPrompt {
+    /// Exactly what the model is asked to complete
+    pub prompt: String,
+    /// Name of the custom template the prompt was rendered with
+    pub template: Option<String>,
+    /// Persona part the prompt starts with, None when a custom template renders it all at once
+    pub system_prompt: Option<String>,
+    pub example_dialogue: ExampleDialogueSelection,
+    pub lore: LoreSelection,
+    /// Recalled long-term memory entries
+    pub memories: Vec<String>,
+    pub attitude_context: String,
+    /// Attitudes toward other people, part of attitude_context
+    pub third_party_context: String,
+    /// Messages of the short-term memory that made it into the prompt
+    pub messages: Vec<Message>,
+    /// Messages of the short-term memory left out to stay within the budget
+    pub omitted_messages: usize,
+    /// Tokens the whole prompt was over the context window by, 0 when it fit
+    pub overflow_tokens: usize,
+    /// Sections left out so the prompt fits, in the order they were dropped
+    pub dropped_sections: Vec<PromptSection>,
+    pub tokens: SectionTokens,
+    pub budget: TokenBudget,
+    #[serde(skip)]
+    template_entry: Option<PromptTemplateEntry>,
+    /// Context size recorded with the inference metrics
+    #[serde(skip)]
+    input_tokens: u32,
+}
+
+/// Messages of the chat log the companion remembers word for word
+fn short_term_mem(companion: &CompanionView) -> usize {
+    if companion.short_term_mem > 0 {
+        companion.short_term_mem
     } else {
-        vec![
-            format!(
-                "<s>[INST]Text transcript of a conversation between {} and {}. {}\n",
-                user.name, companion.name, rp
-            ),
-            format!(
-                "{}'s Persona: {}\n",
-                user.name,
-                user.persona
-                    .replace("{{char}}", &companion.name)
-                    .replace("{{user}}", &user.name)
-            ),
-            format!(
-                "{}'s Persona: {}[/INST]\n<s>[INST]\n",
-                companion.name,
-                companion
-                    .persona
-                    .replace("{{char}}", &companion.name)
-                    .replace("{{user}}", &user.name)
-            ),
-            format!(
-                "{}[/INST]\n<s>[INST]\n",
-                companion
-                    .example_dialogue
-                    .replace("{{char}}", &companion.name)
-                    .replace("{{user}}", &user.name)
-            ),
-            format!("{}[/INST]\n", &tuned_dialogue),
-        ]
+        50
+    }
+}
+
+/// Complete `prompt` as it is on the active companion's backend, for analysis the user never sees
+///
+/// Stops at the first closing brace, answers are expected to be JSON objects. Nothing is stored
+/// and no metrics are recorded, the caller must hold a turn of the inference queue.
+pub fn analyze(prompt: &str, max_tokens: usize) -> Result<String, std::io::Error> {
+    let _generation = GenerationGuard::begin();
+    let config = Database::get_config()
+        .map_err(|e| std::io::Error::new(std::io::ErrorKind::Other, e.to_string()))?;
+    let backend = backend(&config)?;
+    // Cool and repeatable, the answer should follow from the exchange and not from chance
+    let sampling = SamplingParams {
+        temperature: 0.2,
+        stop_sequences: Vec::new(),
+        seed: Some(0),
+        ..SamplingParams::from_config(&config)
     };
+    let mut answer = String::new();
+    backend
+        .complete(prompt, &sampling, max_tokens, &mut |token| {
+            answer.push_str(token);
+            !answer.contains('}')
+        })
+        .map_err(|e| std::io::Error::new(std::io::ErrorKind::Other, e))?;
+    Ok(answer)
+}
+
+/// The prompt a reply to `prompt` would get right now, with what went into each of its sections
+///
+/// `prompt` is treated as the next user message. Nothing is stored and the model isn't touched.
+pub fn dry_run(prompt: &str) -> Result<AssembledPrompt, std::io::Error> {
+    let to_io = |e: rusqlite::Error| std::io::Error::new(std::io::ErrorKind::Other, e.to_string());
+    let config = Database::get_config().map_err(to_io)?;
+    let user = Database::get_user_data().map_err(to_io)?;
+    let companion = Database::get_companion_data().map_err(to_io)?;
+    let long_term_memory = LongTermMem::connect()
+        .map_err(|e| std::io::Error::new(std::io::ErrorKind::Other, e.to_string()))?;
+    let limit = short_term_mem(&companion);
+    let mut messages = Database::get_x_messages(limit.saturating_sub(1), 0).map_err(to_io)?;
+    messages.push(Message {
+        id: 0,
+        ai: false,
+        content: prompt.to_string(),
+        created_at: get_current_date(),
+        author_id: Some(Database::active_user_id()),
+        images: Vec::new(),
+        stats: None,
+    });
+    assemble_prompt(prompt, None, messages, &config, &user, &companion, &long_term_memory)
+}
+
+/// Build the prompt from the persona, lore, memories, attitudes and `short_term_memory_entries`
+///
+/// A prompt that doesn't fit the context window is built again without its least important
+/// sections, in TokenBudget::DROP_ORDER, until it fits or only the persona, the instructions and
+/// the newest message are left.
+fn assemble_prompt(
+    prompt: &str,
+    direction: Option<&str>,
+    short_term_memory_entries: Vec<Message>,
+    config: &ConfigView,
+    user: &UserView,
+    companion: &CompanionView,
+    long_term_memory: &LongTermMem,
+) -> Result<AssembledPrompt, std::io::Error> {
+    // Pictures can't go into a text prompt, they are described where they were sent
+    let short_term_memory_entries: Vec<Message> = short_term_memory_entries
+        .into_iter()
+        .map(message_images::with_placeholders)
+        .collect();
+    let layout = PromptLayout::get().unwrap_or_else(|e| {
+        tracing::warn!("⚠️ Could not load the prompt layout, using the default one: {}", e);
+        PromptLayout::default()
+    });
+    // Initialize context manager for intelligent memory management
+    let mut context_manager = ContextManager::new(config.clone());
+    context_manager.apply_layout(&layout);
+    let variables = Variables::load(Database::active_companion_id(), Database::active_user_id());
+    let sources = PromptSources {
+        prompt,
+        direction,
+        config,
+        user,
+        companion,
+        long_term_memory,
+        variables: &variables,
+        layout: &layout,
+    };
+    let mut cuts = PromptCuts::default();
+    let build = |cuts: &PromptCuts| {
+        assemble_sections(&sources, short_term_memory_entries.clone(), &context_manager, cuts)
+    };
+    let mut assembled = build(&cuts)?;
+    let overflow = context_manager.overflow(assembled.tokens.prompt);
+    if overflow == 0 {
+        return Ok(assembled);
+    }
+    let full_tokens = assembled.tokens.prompt;
+    while context_manager.overflow(assembled.tokens.prompt) > 0 && cuts.next(assembled.messages.len()) {
+        assembled = build(&cuts)?;
+    }
+    // Dropping a section the layout switched off changes nothing, it isn't reported
+    cuts.sections.retain(|section| layout.enabled(*section));
+    let dropped: Vec<&str> = cuts.sections.iter().map(|section| section.as_str()).collect();
+    if context_manager.overflow(assembled.tokens.prompt) > 0 {
+        tracing::warn!(
+            "⚠️ Prompt is {} tokens over the context window of {} even without {}",
+            context_manager.overflow(assembled.tokens.prompt),
+            config.context_window_size,
+            dropped.join(", ")
+        );
+    } else {
+        tracing::info!(
+            "✂️ Prompt was {} tokens over the context window, dropped {} ({} → {} tokens)",
+            overflow,
+            dropped.join(", "),
+            full_tokens,
+            assembled.tokens.prompt
+        );
+    }
+    assembled.overflow_tokens = overflow;
+    assembled.dropped_sections = cuts.sections;
+    Ok(assembled)
+}
 
-    // Use cache optimization for base prompt construction
-    let (optimized_base_prompt, cache_hit) =
-        INFERENCE_OPTIMIZER.optimize_prompt_construction(&base_components, "", &[]);
+/// What a prompt is built from, besides the messages
+struct PromptSources<'a> {
+    prompt: &'a str,
+    direction: Option<&'a str>,
+    config: &'a ConfigView,
+    user: &'a UserView,
+    companion: &'a CompanionView,
+    long_term_memory: &'a LongTermMem,
+    variables: &'a Variables,
+    layout: &'a PromptLayout,
+}
 
-    base_prompt = optimized_base_prompt;
+/// The prompt without the sections in `cuts` or switched off in the layout, in the layout's order
+fn assemble_sections(
+    sources: &PromptSources,
+    short_term_memory_entries: Vec<Message>,
+    context_manager: &ContextManager,
+    cuts: &PromptCuts,
+) -> Result<AssembledPrompt, std::io::Error> {
+    let PromptSources {
+        prompt,
+        direction,
+        config,
+        user,
+        companion,
+        long_term_memory,
+        variables,
+        layout,
+    } = *sources;
+    let included = |section: PromptSection| layout.enabled(section) && !cuts.has(section);
+    let mut base_prompt: String;
+    let (mut parts, mut example_dialogue) =
+        persona_parts(user, companion, context_manager, prompt, variables);
+    fit_to_layout(&mut parts, &mut example_dialogue, layout, context_manager, cuts);
+    if !example_dialogue.dropped.is_empty() {
+        tracing::info!(
+            "✂️ Example dialogue trimmed to {} exchanges ({}/{} tokens), {} dropped",
+            example_dialogue.kept,
+            example_dialogue.used_tokens,
+            example_dialogue.budget_tokens,
+            example_dialogue.dropped.len()
+        );
+    }
 
-    if cache_hit {
-        println!("✓ Cache hit for base prompt construction");
+    // A custom template renders the whole prompt at once from the parts gathered below
+    let template: Option<PromptTemplateEntry> = match PromptTemplates::active(config) {
+        Ok(template) => template,
+        Err(e) => {
+            tracing::error!("Error while getting prompt template: {}", e);
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::Other,
+                "Error while getting prompt template",
+            ));
+        }
+    };
+
+    // World info comes before memories, both describe what the conversation builds on
+    let lore = if included(PromptSection::Lore) {
+        lore_selection(context_manager, companion, user, prompt, &short_term_memory_entries, variables)
     } else {
-        println!("✗ Cache miss - caching base prompt for future use");
+        LoreSelection::default()
+    };
+    if !lore.inserted_ids.is_empty() {
+        tracing::info!(
+            "📖 Lorebook: {} entries inserted ({}/{} tokens), {} over budget",
+            lore.inserted_ids.len(),
+            lore.used_tokens,
+            lore.budget_tokens,
+            lore.dropped_ids.len()
+        );
+    }
+    let mut lore_text = String::new();
+    for entry in &lore.entries {
+        if config.prompt_template == PromptTemplate::Llama2 {
+            lore_text += &format!("[INST]{}[/INST]\n", entry);
+        } else if config.prompt_template == PromptTemplate::Mistral {
+            lore_text += &format!("<s>[INST]{}[/INST]\n", entry);
+        } else {
+            lore_text += &format!("{}\n", entry);
+        }
     }
-    if companion.long_term_mem > 0 {
+    let mut memories: Vec<String> = Vec::new();
+    let mut memories_text = String::new();
+    let mut template_messages: Vec<TemplateMessage> = Vec::new();
+    if companion.long_term_mem > 0 && included(PromptSection::Memories) {
         let long_term_memory_entries: Vec<String> =
-            match long_term_memory.get_matches(prompt, companion.long_term_mem) {
+            match long_term_memory.recall(prompt, companion.long_term_mem) {
                 Ok(entries) => entries,
                 Err(e) => {
-                    eprintln!("Error while getting long term memory entries: {}", e);
+                    tracing::error!("Error while getting long term memory entries: {}", e);
                     return Err(std::io::Error::new(
                         std::io::ErrorKind::Other,
                         "Error while getting long term memory entries",
                     ));
                 }
             };
+        let memories_cap = context_manager.token_budget.cap(PromptSection::Memories);
+        let mut memories_tokens = 0;
         for entry in long_term_memory_entries {
+            let entry = fill_placeholders(&entry, companion, user);
+            // Entries come most relevant first, the rest is left out once the cap is reached
+            memories_tokens += ContextManager::estimate_tokens(&entry);
+            if memories_cap.is_some_and(|cap| memories_tokens > cap) {
+                break;
+            }
+            memories.push(entry.trim_end().to_string());
             if config.prompt_template == PromptTemplate::Llama2 {
-                base_prompt += &format!("[INST]{}[/INST]\n", entry)
-                    .replace("{{char}}", &companion.name)
-                    .replace("{{user}}", &user.name);
+                memories_text += &format!("[INST]{}[/INST]\n", entry);
             } else if config.prompt_template == PromptTemplate::Mistral {
-                base_prompt += &format!("<s>[INST]{}[/INST]\n", entry)
-                    .replace("{{char}}", &companion.name)
-                    .replace("{{user}}", &user.name);
+                memories_text += &format!("<s>[INST]{}[/INST]\n", entry);
             } else {
-                base_prompt += &entry
-                    .replace("{{char}}", &companion.name)
-                    .replace("{{user}}", &user.name);
+                memories_text += &entry;
             }
         }
     }
-    // Initialize context manager for intelligent memory management
-    let context_manager = ContextManager::new(config.clone());
-
-    let short_term_memory_entries: Vec<Message> = match Database::get_x_messages(
-        if companion.short_term_mem > 0 {
-            companion.short_term_mem
-        } else {
-            50
-        },
-        0,
-    ) {
-        Ok(entries) => entries,
-        Err(e) => {
-            eprintln!("Error while getting short term memory entries: {}", e);
-            return Err(std::io::Error::new(
-                std::io::ErrorKind::Other,
-                "Error while getting short term memory entries",
-            ));
-        }
-    };
 
     // Apply context management to optimize memory usage
-    let managed_messages = context_manager.manage_message_context(short_term_memory_entries);
+    let available_messages = short_term_memory_entries.len();
+    // Post-history instructions come with the history, they take from its budget
+    let post_history_tokens = ContextManager::estimate_tokens(&parts.post_history_instructions);
+    let mut managed_messages =
+        context_manager.manage_message_context(short_term_memory_entries, post_history_tokens);
+    if let Some(limit) = cuts.history_limit {
+        let cut = managed_messages.len().saturating_sub(limit);
+        managed_messages.drain(..cut);
+    }
+    // Several people may share the chat, each message is labelled with whoever wrote it
+    let author_names: HashMap<i32, String> = Database::list_users()
+        .map(|users| users.into_iter().map(|u| (u.id, u.name)).collect())
+        .unwrap_or_default();
+    let mut history_text = String::new();
     let mut message_counter = 1;
     let short_term_mem_len = managed_messages.len();
     for message in &managed_messages {
         let prefix = if message.ai {
             &companion.name
         } else {
-            &user.name
+            message
+                .author_id
+                .and_then(|id| author_names.get(&id))
+                .unwrap_or(&user.name)
         };
         let text = &message.content;
         let mut formatted_message = format!("{}: {}\n", prefix, text);
-        if message_counter == short_term_mem_len && contains_time_question(&formatted_message) {
+        let time_question =
+            message_counter == short_term_mem_len && contains_time_question(&formatted_message);
+        if time_question {
             formatted_message = format!(
                 "\n* it's currently {} *\n{}",
                 get_current_date(),
                 formatted_message
             );
         }
-        if config.prompt_template == PromptTemplate::Llama2 {
+        if template.is_some() {
+            template_messages.push(TemplateMessage {
+                role: if message.ai { "assistant" } else { "user" },
+                name: prefix.to_string(),
+                content: if time_question {
+                    format!("* it's currently {} *\n{}", get_current_date(), text)
+                } else {
+                    text.to_string()
+                },
+            });
+        } else if config.prompt_template == PromptTemplate::Llama2 {
             if !message.ai {
-                base_prompt += &format!("[INST]{}", formatted_message);
+                history_text += &format!("[INST]{}", formatted_message);
             } else {
-                base_prompt += &format!("{}[/INST]\n", formatted_message);
+                history_text += &format!("{}[/INST]\n", formatted_message);
             }
         } else if config.prompt_template == PromptTemplate::Mistral {
             if !message.ai {
-                base_prompt += &format!("<s>[INST]{}", formatted_message);
+                history_text += &format!("<s>[INST]{}", formatted_message);
             } else {
-                base_prompt += &format!("{}[/INST]\n", formatted_message);
+                history_text += &format!("{}[/INST]\n", formatted_message);
             }
         } else {
-            base_prompt += &formatted_message;
+            history_text += &formatted_message;
         }
         message_counter += 1;
     }
 
-    // Load and integrate attitude context
-    let attitude_formatter = AttitudeFormatter::new();
-    let attitudes = match Database::get_all_companion_attitudes(1) {
-        Ok(attitudes) => attitudes,
+    // Load and integrate attitude context, colored by the moods the companion is in
+    let mood_modifiers = match MoodModifier::active(Database::active_companion_id()) {
+        Ok(modifiers) => modifiers,
         Err(e) => {
-            eprintln!("Warning: Could not load attitudes: {}", e);
+            tracing::warn!("⚠️ Could not load the companion's mood modifiers: {}", e);
+            Vec::new()
+        }
+    };
+    let attitude_formatter = AttitudeFormatter::new().with_mood_modifiers(mood_modifiers);
+    // Only the attitude toward the user being answered, others don't take part in this reply
+    let active_user_id = Database::active_user_id();
+    let attitudes = match Database::get_all_companion_attitudes(Database::active_companion_id()) {
+        Ok(mut attitudes) => {
+            attitudes.retain(|a| {
+                if a.target_type == "user" {
+                    a.target_id == active_user_id && included(PromptSection::Attitude)
+                } else {
+                    included(PromptSection::ThirdParty)
+                }
+            });
+            attitudes
+        }
+        Err(e) => {
+            tracing::warn!("⚠️ Could not load attitudes: {}", e);
             Vec::new()
         }
     };
@@ -379,15 +1356,24 @@ pub fn prompt(prompt: &str) -> Result<String, std::io::Error> {
     let third_parties = match Database::get_all_third_party_individuals() {
         Ok(parties) => parties,
         Err(e) => {
-            eprintln!("Warning: Could not load third parties: {}", e);
+            tracing::warn!("⚠️ Could not load third parties: {}", e);
             Vec::new()
         }
     };
 
-    // Add attitude context to prompt if attitudes exist
-    let attitude_context = if !attitudes.is_empty() {
+    let mut third_party_context = attitude_formatter
+        .format_third_party_context(&attitudes, &third_parties)
+        .trim()
+        .to_string();
+    if let Some(cap) = context_manager.token_budget.cap(PromptSection::ThirdParty) {
+        third_party_context = cap_tokens(&third_party_context, cap);
+    }
+    let user_attitudes: Vec<CompanionAttitude> =
+        attitudes.into_iter().filter(|a| a.target_type == "user").collect();
+    let attitude_context = if !user_attitudes.is_empty() {
+        let user_reference = reference(&user.name, &user.nickname, &user.pronouns);
         let context =
-            attitude_formatter.format_attitude_context(&attitudes, &third_parties, &user.name);
+            attitude_formatter.format_attitude_context(&user_attitudes, &third_parties, &user_reference);
         if !context.is_empty() {
             format!("\n{}\n", context)
         } else {
@@ -396,13 +1382,145 @@ pub fn prompt(prompt: &str) -> Result<String, std::io::Error> {
     } else {
         String::new()
     };
+    // What the companion is after comes before how it feels about the user
+    let goals_line = match Goals::active(Database::active_companion_id()) {
+        Ok(goals) => goals::prompt_line(&goals, &companion.name)
+            .map(|line| fill_placeholders(&line, companion, user)),
+        Err(e) => {
+            tracing::warn!("⚠️ Could not load the companion's goals: {}", e);
+            None
+        }
+    };
+    let attitude_context = match goals_line {
+        Some(line) if attitude_context.is_empty() => format!("\n{}\n", line),
+        Some(line) => format!("\n{}{}", line, attitude_context),
+        None => attitude_context,
+    };
+    // The mood lingers after the attitude changes that caused it, it leads the attitude context
+    let mood_line = match Mood::get(Database::active_companion_id()) {
+        Ok(_) if !included(PromptSection::Attitude) => None,
+        Ok(mood) => mood.prompt_line(&companion.name),
+        Err(e) => {
+            tracing::warn!("⚠️ Could not load the companion's mood: {}", e);
+            None
+        }
+    };
+    let attitude_context = match mood_line {
+        Some(line) if attitude_context.is_empty() => format!("\n{}\n", line),
+        Some(line) => format!("\n{}{}", line, attitude_context),
+        None => attitude_context,
+    };
+    // Where and when the roleplay stands is kept even when the attitude is cut, it's one line
+    let scene_line = match Scene::get(Conversations::active_id(), Database::active_companion_id()) {
+        Ok(scene) => scene.prompt_line(),
+        Err(e) => {
+            tracing::warn!("⚠️ Could not load the scene: {}", e);
+            None
+        }
+    };
+    let mut attitude_context = match scene_line {
+        Some(line) if attitude_context.is_empty() => format!("\n{}\n", line),
+        Some(line) => format!("\n{}{}", line, attitude_context),
+        None => attitude_context,
+    };
+    // The scene and mood lead, they are what a cap keeps
+    if let Some(cap) = context_manager.token_budget.cap(PromptSection::Attitude) {
+        if ContextManager::estimate_tokens(&attitude_context) > cap {
+            attitude_context = format!("\n{}\n", cap_tokens(attitude_context.trim(), cap));
+        }
+    }
+    let third_party_block = if third_party_context.is_empty() {
+        String::new()
+    } else {
+        format!("\n{}\n", third_party_context)
+    };
+
+    let system_prompt = if template.is_some() {
+        base_prompt = String::new();
+        None
+    } else {
+        let blocks = base_blocks(config, user, companion, &parts, layout);
+        // The persona and example dialogue the prompt opens with are the same for every message
+        let mut base_components = vec![blocks.head];
+        let mut dynamic_content = String::new();
+        let mut opening = true;
+        for section in layout.order() {
+            let block = match section {
+                PromptSection::Persona => &blocks.persona,
+                PromptSection::ExampleDialogue => &blocks.example_dialogue,
+                PromptSection::Lore => &lore_text,
+                PromptSection::Memories => &memories_text,
+                PromptSection::History => &history_text,
+                PromptSection::Attitude => &attitude_context,
+                PromptSection::ThirdParty => &third_party_block,
+            };
+            opening &= matches!(section, PromptSection::Persona | PromptSection::ExampleDialogue);
+            if opening {
+                base_components.push(block.clone());
+            } else {
+                dynamic_content += block;
+            }
+            // With the attitude switched off the scene follows the history on its own
+            if section == PromptSection::History && !layout.enabled(PromptSection::Attitude) {
+                dynamic_content += &attitude_context;
+            }
+        }
+        let (optimized_base_prompt, cache_hit) =
+            INFERENCE_OPTIMIZER.optimize_prompt_construction(&base_components, &dynamic_content, &[]);
+
+        base_prompt = optimized_base_prompt;
+
+        if cache_hit {
+            tracing::debug!("✓ Cache hit for base prompt construction");
+        } else {
+            tracing::debug!("✗ Cache miss - caching base prompt for future use");
+        }
+        if !attitude_context.is_empty() || !third_party_block.is_empty() {
+            tracing::debug!(
+                "✓ Attitude context integrated: {} characters",
+                attitude_context.len() + third_party_block.len()
+            );
+        }
+        Some(base_components.concat())
+    };
 
-    // Insert attitude context before conversation history
-    if !attitude_context.is_empty() {
-        base_prompt += &attitude_context;
-        println!(
-            "✓ Attitude context integrated: {} characters",
-            attitude_context.len()
+    if !parts.post_history_instructions.is_empty() && template.is_none() {
+        let instructions = &parts.post_history_instructions;
+        base_prompt += &match config.prompt_template {
+            PromptTemplate::Llama2 => format!("[INST]{}[/INST]\n", instructions),
+            PromptTemplate::Mistral => format!("<s>[INST]{}[/INST]\n", instructions),
+            _ => format!("{}\n", instructions),
+        };
+    }
+
+    // Attitudes toward other people are part of the attitude context outside the built-in formats
+    let attitude_context = [attitude_context.trim(), third_party_context.as_str()]
+        .into_iter()
+        .filter(|text| !text.is_empty())
+        .collect::<Vec<_>>()
+        .join("\n\n");
+    if let Some(template) = &template {
+        let mut context = template_context(user, companion, &parts);
+        context.lore = lore.entries.clone();
+        context.memories = memories.clone();
+        context.attitude_context = attitude_context.clone();
+        context.messages = template_messages;
+        context.direction = direction.map(|direction| fill_placeholders(direction, companion, user));
+        base_prompt = match prompt_templates::render(&template.template, &context) {
+            Ok(rendered) => rendered,
+            Err(e) => {
+                tracing::error!("Error while rendering prompt template '{}': {}", template.name, e);
+                return Err(std::io::Error::new(
+                    std::io::ErrorKind::Other,
+                    "Error while rendering prompt template",
+                ));
+            }
+        };
+        tracing::debug!("✓ Prompt rendered with template '{}'", template.name);
+    } else if let Some(direction) = direction {
+        base_prompt += &format!(
+            "\n* {} *\n",
+            fill_placeholders(direction, companion, user)
         );
     }
 
@@ -419,7 +1537,156 @@ pub fn prompt(prompt: &str) -> Result<String, std::io::Error> {
         context_manager.get_response_token_limit(system_tokens + attitude_tokens + message_tokens);
     let memory_stats =
         context_manager.get_memory_stats(system_tokens, attitude_tokens, message_tokens);
-    memory_stats.print_stats();
+    memory_stats.log_stats();
+
+    let persona = [
+        parts.roleplay,
+        &parts.user_note,
+        &parts.user_persona,
+        &parts.companion_note,
+        &parts.companion_persona,
+    ]
+    .concat();
+    let tokens = SectionTokens {
+        persona: ContextManager::estimate_tokens(&persona),
+        instructions: ContextManager::estimate_tokens(&parts.system_prompt)
+            + ContextManager::estimate_tokens(&parts.content_policy)
+            + post_history_tokens,
+        example_dialogue: example_dialogue.used_tokens,
+        lore: lore.used_tokens,
+        memories: memories.iter().map(|memory| ContextManager::estimate_tokens(memory)).sum(),
+        attitude: attitude_tokens,
+        third_party: ContextManager::estimate_tokens(&third_party_context),
+        messages: message_tokens,
+        prompt: system_tokens,
+        response_limit: response_token_limit,
+    };
+    // Templates end where the reply starts, the built-in formats still need the name
+    let prompt = match &template {
+        Some(_) => base_prompt,
+        None => format!("{}{}: ", base_prompt, companion.name),
+    };
+    Ok(AssembledPrompt {
+        prompt,
+        template: template.as_ref().map(|template| template.name.clone()),
+        system_prompt,
+        example_dialogue,
+        lore,
+        memories,
+        attitude_context,
+        third_party_context,
+        omitted_messages: available_messages - managed_messages.len(),
+        overflow_tokens: 0,
+        dropped_sections: Vec::new(),
+        messages: managed_messages,
+        tokens,
+        budget: context_manager.token_budget.clone(),
+        template_entry: template,
+        input_tokens: (system_tokens + attitude_tokens + message_tokens) as u32,
+    })
+}
+
+fn generate(
+    prompt: &str,
+    direction: Option<&str>,
+    mode: ReplyMode,
+    sampling: &SamplingOverrides,
+    on_token: &mut dyn FnMut(&str) -> bool,
+) -> Result<Reply, std::io::Error> {
+    // Incognito generations leave no trace behind, alternatives only count towards the metrics
+    let tracked = !matches!(mode, ReplyMode::Incognito(_));
+    let persist = matches!(mode, ReplyMode::Chat);
+    // Background maintenance holds off while this is alive
+    let _generation = GenerationGuard::begin();
+    let start_time = std::time::Instant::now();
+    let long_term_memory = match LongTermMem::connect() {
+        Ok(ltm) => ltm,
+        Err(e) => {
+            tracing::error!("Error while connecting to tantivy: {}", e);
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::Other,
+                "Error while connecting to tantivy",
+            ));
+        }
+    };
+    let local: DateTime<Local> = Local::now();
+    let formatted_date = local.format("* at %A %d.%m.%Y %H:%M *\n").to_string();
+    let mut config: ConfigView = match Database::get_config() {
+        Ok(config) => config,
+        Err(e) => {
+            tracing::error!("Error while getting config: {}", e);
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::Other,
+                "Error while getting config",
+            ));
+        }
+    };
+    // The thread's own model and sampling settings, the model is switched to when another one is loaded
+    let overrides = match mode {
+        ReplyMode::Incognito(_) => ConversationOverrides::default(),
+        _ => match Conversations::overrides(Conversations::active_id()) {
+            Ok(overrides) => overrides.unwrap_or_default(),
+            Err(e) => {
+                tracing::warn!("⚠️ Failed to get conversation overrides, using the config: {}", e);
+                ConversationOverrides::default()
+            }
+        },
+    };
+    if let Some(model_path) = &overrides.model_path {
+        config.llm_model_path = model_path.clone();
+    }
+    let user: UserView = match Database::get_user_data() {
+        Ok(user) => user,
+        Err(e) => {
+            tracing::error!("Error while getting user data: {}", e);
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::Other,
+                "Error while getting user data",
+            ));
+        }
+    };
+    let companion: CompanionView = match Database::get_companion_data() {
+        Ok(companion) => companion,
+        Err(e) => {
+            tracing::error!("Error while getting companion data: {}", e);
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::Other,
+                "Error while getting companion data",
+            ));
+        }
+    };
+
+    let limit = short_term_mem(&companion);
+    let short_term_memory_entries = match mode {
+        ReplyMode::Chat => Database::get_x_messages(limit, 0),
+        ReplyMode::Incognito(history) => Ok(history[history.len().saturating_sub(limit)..].to_vec()),
+        ReplyMode::Alternative(user_message_id) => Database::get_messages_until(user_message_id, limit),
+    };
+    let short_term_memory_entries: Vec<Message> = match short_term_memory_entries {
+        Ok(entries) => entries,
+        Err(e) => {
+            tracing::error!("Error while getting short term memory entries: {}", e);
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::Other,
+                "Error while getting short term memory entries",
+            ));
+        }
+    };
+    let assembled = assemble_prompt(
+        prompt,
+        direction,
+        short_term_memory_entries,
+        &config,
+        &user,
+        &companion,
+        &long_term_memory,
+    )?;
+    let template = &assembled.template_entry;
+    let response_token_limit = assembled.tokens.response_limit;
+    let input_tokens = assembled.input_tokens;
+
+    let backend = backend(&config)?;
+    tracing::debug!("🚀 Generating AI response...");
 
     // Initialize performance tracking
     let session_id = format!("llm_{}", std::time::SystemTime::now()
@@ -427,114 +1694,218 @@ pub fn prompt(prompt: &str) -> Result<String, std::io::Error> {
         .unwrap_or_default()
         .as_millis());
     
-    let model_config = ModelConfig {
-        model_path: config.llm_model_path.clone(),
-        gpu_layers: config.gpu_layers as i32,
-        device_type: config.device.to_string(),
-    };
-    
-    let input_tokens = (system_tokens + attitude_tokens + message_tokens) as u32;
+    let model_config = backend.model_config();
     
     // Start performance tracking
-    if let Ok(mut tracker) = INFERENCE_TRACKER.lock() {
-        tracker.start_session(session_id.clone(), model_config.clone(), input_tokens);
+    if tracked {
+        let source = match mode {
+            ReplyMode::Alternative(_) => "alternative",
+            _ if direction.is_some() => "proactive",
+            _ => "chat",
+        };
+        if let Ok(mut tracker) = INFERENCE_TRACKER.lock() {
+            tracker.start_session(session_id.clone(), model_config.clone(), input_tokens, source);
+        }
     }
 
-    // Create optimized inference parameters for better performance        
-    let optimized_inference_params = llm::InferenceParameters::default();
+    let sampling = SamplingParams::from_config(&config)
+        .with_overrides(&overrides.sampling)
+        .with_overrides(sampling);
 
     let mut end_of_generation = String::new();
     let mut tokens_generated = 0u32;
     let mut first_token_recorded = false;
     let eog = format!("\n{}:", user.name);
+    let stop_sequences: Vec<String> = match template {
+        Some(template) => template.stop.iter().cloned().chain([eog.clone()]).collect(),
+        None => Vec::new(),
+    }
+    .into_iter()
+    .chain(sampling.stop_sequences.iter().cloned())
+    .collect();
     
-    let res = session.infer::<std::convert::Infallible>(
-        llama.as_ref(),
-        &mut rand::thread_rng(),
-        &llm::InferenceRequest {
-            prompt: llm::Prompt::Text(&format!("{}{}: ", &base_prompt, companion.name)),
-            parameters: &optimized_inference_params,
-            play_back_previous_tokens: false,
-            maximum_token_count: Some(response_token_limit),
-        },
-        &mut Default::default(),
-        |t| {
-            match t {
-                llm::InferenceResponse::SnapshotToken(_) => { /*print!("{token}");*/ }
-                llm::InferenceResponse::PromptToken(_) => { /*print!("{token}");*/ }
-                llm::InferenceResponse::InferredToken(token) => {
-                    // Track first token for time-to-first-token metric
-                    if !first_token_recorded {
-                        if let Ok(mut tracker) = INFERENCE_TRACKER.lock() {
-                            tracker.record_first_token(&session_id);
-                        }
-                        first_token_recorded = true;
-                    }
-                    
-                    tokens_generated += 1;
-                    end_of_generation.push_str(&token);
-                    print!("{token}");
-                    
-                    // Update token count for progress tracking
-                    if let Ok(mut tracker) = INFERENCE_TRACKER.lock() {
-                        tracker.update_token_count(&session_id, tokens_generated);
-                    }
-                    
-                    if end_of_generation.contains(&eog)
-                        || end_of_generation.contains("[/INST]")
-                        || end_of_generation.contains("<</SYS>>")
-                        || end_of_generation.contains("[s]")
-                        || end_of_generation.contains(&format!("{}:", &companion.name))
-                        || end_of_generation.contains(&format!("{}:", &user.name))
-                        || end_of_generation.contains("<|user|>")
-                    {
-                        return Ok(llm::InferenceFeedback::Halt);
-                    }
-                }
-                llm::InferenceResponse::EotToken => {}
+    let mut cut_short = false;
+    let generation_start = std::time::Instant::now();
+    let res = backend.complete(&assembled.prompt, &sampling, response_token_limit, &mut |token| {
+        // Track first token for time-to-first-token metric
+        if !first_token_recorded {
+            if let Ok(mut tracker) = INFERENCE_TRACKER.lock() {
+                tracker.record_first_token(&session_id);
             }
-            std::io::stdout().flush().unwrap();
-            Ok(llm::InferenceFeedback::Continue)
-        },
-    );
-    let x: String = end_of_generation
-        .replace(&eog, "")
-        .replace("[INST]", "")
-        .replace("[/INST]", "")
-        .replace("<</SYS>>", "")
-        .replace("<s>", "")
-        .replace("</s>", "")
-        .replace("<|user|>", "");
+            first_token_recorded = true;
+        }
+
+        tokens_generated += 1;
+        end_of_generation.push_str(token);
+        tracing::trace!(target: "ai_companion::tokens", "{}", token);
+
+        // Update token count for progress tracking
+        if let Ok(mut tracker) = INFERENCE_TRACKER.lock() {
+            tracker.update_token_count(&session_id, tokens_generated);
+        }
+
+        if stop_sequences.iter().any(|stop| end_of_generation.contains(stop.as_str())) {
+            return false;
+        }
+        // The server is shutting down and the grace period is over
+        if shutdown::past_grace() {
+            cut_short = true;
+            return false;
+        }
+        if template.is_none() && (end_of_generation.contains(&eog)
+            || end_of_generation.contains("[/INST]")
+            || end_of_generation.contains("<</SYS>>")
+            || end_of_generation.contains("[s]")
+            || end_of_generation.contains(&format!("{}:", &companion.name))
+            || end_of_generation.contains(&format!("{}:", &user.name))
+            || end_of_generation.contains("<|user|>"))
+        {
+            return false;
+        }
+        // The listener went away, there is nobody left to generate for
+        on_token(token)
+    });
+    let generation_ms = generation_start.elapsed().as_millis() as u64;
+    // Remote servers stream several tokens per chunk at times, their own count is the exact one
+    if let Ok(Completion { tokens: Some(tokens), .. }) = &res {
+        tokens_generated = *tokens;
+        if let Ok(mut tracker) = INFERENCE_TRACKER.lock() {
+            tracker.update_token_count(&session_id, tokens_generated);
+        }
+    }
+    // Nothing to store when the backend could not be reached at all
+    if let Err(e) = &res {
+        if end_of_generation.is_empty() {
+            if let Ok(mut tracker) = INFERENCE_TRACKER.lock() {
+                tracker.abandon_session(&session_id);
+            }
+            tracing::error!("Error while generating a reply: {}", e);
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::Other,
+                format!("Error while generating a reply: {}", e),
+            ));
+        }
+    }
+    // Half a reply is never stored, the chat ends with the user's message until it is asked again
+    if cut_short {
+        if let Ok(mut tracker) = INFERENCE_TRACKER.lock() {
+            tracker.abandon_session(&session_id);
+        }
+        tracing::warn!("⚠️ Reply discarded after {} tokens, the server is shutting down", tokens_generated);
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::Interrupted,
+            "The server shut down before the reply was finished",
+        ));
+    }
+    // Measured before the reply is stored so database writes don't count as generation time
+    if tracked {
+        if let Ok(mut tracker) = INFERENCE_TRACKER.lock() {
+            if let Err(e) = tracker.complete_session(&session_id) {
+                tracing::error!("Failed to complete performance tracking session: {}", e);
+            }
+        }
+    }
+    let x: String = if template.is_some() {
+        let reply = prompt_templates::cut_at_stop(&end_of_generation, &stop_sequences);
+        // Models used to transcripts sometimes still start with the companion's name
+        reply
+            .trim_start()
+            .strip_prefix(&format!("{}:", companion.name))
+            .unwrap_or(reply)
+            .to_string()
+    } else {
+        prompt_templates::cut_at_stop(&end_of_generation, &stop_sequences)
+            .replace(&eog, "")
+            .replace("[INST]", "")
+            .replace("[/INST]", "")
+            .replace("<</SYS>>", "")
+            .replace("<s>", "")
+            .replace("</s>", "")
+            .replace("<|user|>", "")
+    };
     match res {
-        Ok(result) => println!("\n\nInference stats:\n{result}"),
-        Err(err) => println!("\n{err}"),
+        Ok(Completion { stats: Some(stats), .. }) => tracing::info!("Inference stats: {stats}"),
+        Ok(_) => {}
+        Err(err) => tracing::error!("Error while generating a reply: {err}"),
     }
     let companion_text = x
         .split(&format!("\n{}: ", &companion.name))
         .next()
         .unwrap_or("");
-    match Database::insert_message(NewMessage {
-        ai: true,
-        content: companion_text.to_string(),
-    }) {
-        Ok(_) => {}
-        Err(e) => eprintln!(
-            "Error while adding message to database/short-term memory: {}",
-            e
-        ),
-    };
-    match long_term_memory.add_entry(&format!(
-        "{}{}: {}\n{}: {}\n",
-        formatted_date, "{{user}}", &prompt, "{{char}}", &companion_text
-    )) {
-        Ok(_) => {}
-        Err(e) => eprintln!("Error while adding message to long-term memory: {}", e),
+    // Streamed tokens went out as generated, what is kept and returned is filtered
+    let companion_text = Filters::apply(Stage::Response, companion_text);
+    let settings = SamplingSettings {
+        model_path: config.llm_model_path.clone(),
+        device: config.device.clone(),
+        gpu_layers: config.gpu_layers,
+        prompt_template: config.prompt_template.clone(),
+        context_window_size: config.context_window_size,
+        max_tokens: response_token_limit,
+        sampler: sampling.describe(),
     };
-
-    // Complete the performance tracking session
-    if let Ok(mut tracker) = INFERENCE_TRACKER.lock() {
-        if let Err(e) = tracker.complete_session(&session_id) {
-            eprintln!("Failed to complete performance tracking session: {}", e);
+    let mut attempt_id = None;
+    // An empty proactive reply is left to the caller's fallback message
+    let persist = persist && !(direction.is_some() && companion_text.trim().is_empty());
+    if persist {
+        let stats = ReplyStats {
+            prompt_tokens: ContextManager::estimate_tokens(&assembled.prompt) as u32,
+            completion_tokens: tokens_generated,
+            generation_ms: Some(generation_ms),
+            model_name: Some(model_name(&config)),
+        };
+        match Database::insert_message_with_stats(
+            NewMessage {
+                ai: true,
+                content: companion_text.to_string(),
+                images: Vec::new(),
+            },
+            Some(stats),
+        ) {
+            Ok(_) => {}
+            Err(e) => tracing::error!(
+                "Error while adding message to database/short-term memory: {}",
+                e
+            ),
+        };
+        // Keep every reply to a user message so regenerations can be compared and restored
+        if direction.is_none() {
+            let recorded = Database::get_latest_user_message_id().and_then(|user_message_id| {
+                MessageAttempts::record(
+                    user_message_id,
+                    companion_text.trim_start(),
+                    &settings,
+                    input_tokens,
+                    tokens_generated,
+                )
+            });
+            match recorded {
+                Ok(id) => attempt_id = Some(id),
+                Err(e) => tracing::error!("Error while recording reply attempt: {}", e),
+            }
+        }
+        let memory_entry = if direction.is_some() {
+            format!("{}{}: {}\n", formatted_date, "{{char}}", &companion_text)
+        } else {
+            format!(
+                "{}{}: {}\n{}: {}\n",
+                formatted_date, "{{user}}", &prompt, "{{char}}", &companion_text
+            )
+        };
+        match MemoryProposals::write_long_term_memory(&long_term_memory, &memory_entry) {
+            Ok(_) => {}
+            Err(e) => tracing::error!("Error while adding message to long-term memory: {}", e),
+        };
+    }
+    if let ReplyMode::Alternative(user_message_id) = mode {
+        match MessageAttempts::record_alternative(
+            user_message_id,
+            companion_text.trim_start(),
+            &settings,
+            input_tokens,
+            tokens_generated,
+        ) {
+            Ok(id) => attempt_id = Some(id),
+            Err(e) => tracing::error!("Error while recording alternative reply: {}", e),
         }
     }
 
@@ -549,28 +1920,37 @@ pub fn prompt(prompt: &str) -> Result<String, std::io::Error> {
         0.0
     };
     
-    println!("⚡ Performance Metrics:");
-    println!("  • Total time: {:.2}s", response_time.as_secs_f64());
-    println!("  • Tokens generated: {}", tokens_generated);
-    println!("  • Tokens per second: {:.1}", tokens_per_second);
-    println!("  • CPU cores used: {}", cpu_cores);
-    println!("  • Context size: {} tokens", input_tokens);
+    tracing::info!(
+        target: "inference",
+        elapsed_ms = response_time.as_millis() as u64,
+        tokens_generated,
+        tokens_per_second,
+        context_tokens = input_tokens,
+        "⚡ Generated {} tokens in {:.2}s ({:.1} tokens/s)",
+        tokens_generated,
+        response_time.as_secs_f64(),
+        tokens_per_second
+    );
 
     // Print cache statistics periodically
     let stats = INFERENCE_OPTIMIZER.get_stats();
     if stats.total_requests % 10 == 0 {
         let (cache_size, cache_hits, hit_rate) = INFERENCE_OPTIMIZER.get_cache_stats();
-        println!(
+        tracing::debug!(
             "📊 Cache Stats: {} entries, {} hits, {:.2}% hit rate",
             cache_size,
             cache_hits,
             hit_rate * 100.0
         );
-        println!(
+        tracing::debug!(
             "📈 Performance: {} requests, avg response time: {:?}",
             stats.total_requests, stats.avg_response_time
         );
     }
 
-    Ok(companion_text.trim_start().to_string())
+    Ok(Reply {
+        text: companion_text.trim_start().to_string(),
+        attempt_id,
+        dropped_sections: assembled.dropped_sections,
+    })
 }
@@ -1,5 +1,9 @@
+use crate::database::{Database, PromptTemplate};
+use crate::db_pool;
+use crate::hardware_probe::{self, GgufMetadata};
+use crate::settings;
 use chrono::Local;
-use rusqlite::{params, Connection, Result};
+use rusqlite::{params, Result};
 use serde::{Deserialize, Serialize};
 use std::collections::HashSet;
 use std::env;
@@ -21,6 +25,10 @@ pub struct ModelInfo {
     pub size_bytes: u64,
     pub directory: String,
     pub last_modified: String,
+    /// From the GGUF header, None when it can't be read
+    pub architecture: Option<String>,
+    /// Prompt format the model was trained with, a built-in format or a prompt template preset
+    pub detected_template: Option<String>,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -30,20 +38,16 @@ pub struct DirectoryInfo {
     pub created_at: String,
 }
 
-pub struct LlmScanner {
-    database_path: String,
-}
+pub struct LlmScanner {}
 
 impl LlmScanner {
     pub fn new() -> Self {
-        Self {
-            database_path: "companion_database.db".to_string(),
-        }
+        Self {}
     }
 
-    /// Get default directories relative to the executable
+    /// Get the directories given at startup and the default ones relative to the executable
     fn get_default_directories() -> Vec<PathBuf> {
-        let mut dirs = Vec::new();
+        let mut dirs = settings::get().model_dirs.clone();
         
         if let Ok(exe_path) = env::current_exe() {
             if let Some(exe_dir) = exe_path.parent() {
@@ -95,6 +99,11 @@ impl LlmScanner {
                                 .map(|dt| dt.format("%Y-%m-%d %H:%M:%S").to_string())
                                 .unwrap_or_else(|| "Unknown".to_string());
                             
+                            let metadata = hardware_probe::read_gguf_metadata(path).ok();
+                            let filename = path.file_name().unwrap_or_default().to_string_lossy();
+                            let detected_template = metadata
+                                .as_ref()
+                                .and_then(|metadata| detect_template(metadata, &filename));
                             models.push(ModelInfo {
                                 path: path.display().to_string(),
                                 filename: path.file_name()
@@ -104,6 +113,8 @@ impl LlmScanner {
                                 size_bytes,
                                 directory: dir_path.display().to_string(),
                                 last_modified: last_modified_str,
+                                architecture: metadata.map(|metadata| metadata.architecture),
+                                detected_template: detected_template.map(str::to_string),
                             });
                         }
                     }
@@ -116,7 +127,7 @@ impl LlmScanner {
 
     /// Get all configured directories from the database
     pub fn get_directories(&self) -> Result<Vec<DirectoryInfo>> {
-        let conn = Connection::open(&self.database_path)?;
+        let conn = db_pool::connection()?;
         let mut stmt = conn.prepare("SELECT id, path, created_at FROM llm_directories ORDER BY id")?;
         
         let directories = stmt.query_map([], |row| {
@@ -155,7 +166,7 @@ impl LlmScanner {
         // Convert to string using display() for better cross-platform compatibility
         let path_string = normalized_path.display().to_string();
         
-        let conn = Connection::open(&self.database_path)?;
+        let conn = db_pool::connection()?;
         let created_at = Local::now().format("%Y-%m-%d %H:%M:%S").to_string();
         
         conn.execute(
@@ -168,7 +179,7 @@ impl LlmScanner {
 
     /// Remove a directory from the scan list
     pub fn remove_directory(&self, id: i32) -> Result<()> {
-        let conn = Connection::open(&self.database_path)?;
+        let conn = db_pool::connection()?;
         conn.execute(
             "DELETE FROM llm_directories WHERE id = ?1",
             params![id],
@@ -211,7 +222,7 @@ impl LlmScanner {
 
     /// Check if the old llm_model_path exists and migrate it to a directory
     pub fn migrate_existing_config(&self) -> Result<()> {
-        let conn = Connection::open(&self.database_path)?;
+        let conn = db_pool::connection()?;
         
         // Get the current llm_model_path from config
         let model_path: Option<String> = conn
@@ -241,4 +252,120 @@ impl LlmScanner {
         
         Ok(())
     }
-}
\ No newline at end of file
+}
+
+/// Prompt format a model was trained with, from its chat template, architecture or file name
+///
+/// The names are those of the built-in formats (Llama2, Mistral) and of the prompt template
+/// presets. None when the model's format has no match.
+pub fn detect_template(metadata: &GgufMetadata, filename: &str) -> Option<&'static str> {
+    if let Some(template) = &metadata.chat_template {
+        let detected = if template.contains("<|im_start|>") {
+            Some("ChatML")
+        } else if template.contains("<start_of_turn>") {
+            Some("Gemma")
+        } else if template.contains("<<SYS>>") {
+            Some("Llama2")
+        } else if template.contains("[INST]") {
+            Some("Mistral")
+        } else if template.contains("<|user|>") && template.contains("<|end|>") {
+            Some("Phi")
+        } else if template.contains("### Instruction") {
+            Some("Alpaca")
+        } else if template.contains("USER:") && template.contains("ASSISTANT:") {
+            Some("Vicuna")
+        } else {
+            None
+        };
+        if detected.is_some() {
+            return detected;
+        }
+    }
+    match metadata.architecture.as_str() {
+        architecture if architecture.starts_with("gemma") => return Some("Gemma"),
+        architecture if architecture.starts_with("qwen") => return Some("ChatML"),
+        architecture if architecture.starts_with("phi3") => return Some("Phi"),
+        _ => {}
+    }
+    // Llama-architecture models share their architecture, the file name tells them apart
+    let filename = filename.to_lowercase();
+    [
+        ("mistral", "Mistral"),
+        ("mixtral", "Mistral"),
+        ("llama-2", "Llama2"),
+        ("llama2", "Llama2"),
+        ("vicuna", "Vicuna"),
+        ("alpaca", "Alpaca"),
+    ]
+    .into_iter()
+    .find(|(hint, _)| filename.contains(hint))
+    .map(|(_, template)| template)
+}
+
+/// Config values selecting a detected template, the built-in format and the preset name
+pub fn template_config(template: &str) -> (PromptTemplate, &str) {
+    match template {
+        "Llama2" => (PromptTemplate::Llama2, ""),
+        "Mistral" => (PromptTemplate::Mistral, ""),
+        preset => (PromptTemplate::Default, preset),
+    }
+}
+
+/// Select the prompt template of the model at `model_path` in the config, if the config picks
+/// templates on its own and one is detected
+///
+/// Returns the template that was selected.
+pub fn apply_detected_template(model_path: &str) -> rusqlite::Result<Option<&'static str>> {
+    if !Database::get_config()?.auto_prompt_template {
+        return Ok(None);
+    }
+    let path = Path::new(model_path);
+    let metadata = match hardware_probe::read_gguf_metadata(path) {
+        Ok(metadata) => metadata,
+        Err(e) => {
+            tracing::debug!("Could not read the metadata of {}: {}", model_path, e);
+            return Ok(None);
+        }
+    };
+    let filename = path.file_name().unwrap_or_default().to_string_lossy();
+    let Some(template) = detect_template(&metadata, &filename) else {
+        return Ok(None);
+    };
+    let (prompt_template, custom_prompt_template) = template_config(template);
+    Database::set_prompt_template(prompt_template, custom_prompt_template)?;
+    tracing::info!("📝 Prompt template {} picked for {}", template, model_path);
+    Ok(Some(template))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_detect_template() {
+        let metadata = |architecture: &str, chat_template: Option<&str>| GgufMetadata {
+            architecture: architecture.to_string(),
+            name: None,
+            chat_template: chat_template.map(str::to_string),
+        };
+        let chatml = "{% for message in messages %}<|im_start|>{{ message['role'] }}\n";
+        assert_eq!(detect_template(&metadata("llama", Some(chatml)), "hermes.gguf"), Some("ChatML"));
+        let mistral = "{{ bos_token }}{% for message in messages %}[INST] {{ message['content'] }} [/INST]";
+        assert_eq!(detect_template(&metadata("llama", Some(mistral)), "model.gguf"), Some("Mistral"));
+        let llama2 = "[INST] <<SYS>>\n{{ system_message }}\n<</SYS>>";
+        assert_eq!(detect_template(&metadata("llama", Some(llama2)), "model.gguf"), Some("Llama2"));
+        assert_eq!(detect_template(&metadata("gemma2", None), "model.gguf"), Some("Gemma"));
+        assert_eq!(detect_template(&metadata("qwen2", None), "model.gguf"), Some("ChatML"));
+        // Nothing in the metadata, the file name still tells
+        assert_eq!(
+            detect_template(&metadata("llama", None), "Mistral-7B-Instruct-v0.2.Q4_K_M.gguf"),
+            Some("Mistral")
+        );
+        // Llama 3 has no preset
+        let llama3 = "<|start_header_id|>{{ message['role'] }}<|end_header_id|>";
+        assert_eq!(detect_template(&metadata("llama", Some(llama3)), "llama-3.gguf"), None);
+
+        assert!(template_config("Mistral") == (PromptTemplate::Mistral, ""));
+        assert!(template_config("ChatML") == (PromptTemplate::Default, "ChatML"));
+    }
+}
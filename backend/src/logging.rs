@@ -0,0 +1,402 @@
+use crate::settings;
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+use std::collections::VecDeque;
+use std::fmt;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Write};
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Mutex, OnceLock};
+use tracing::field::{Field, Visit};
+use tracing::span::{Attributes, Id, Record};
+use tracing::{Event, Level, Subscriber};
+use tracing_subscriber::field::RecordFields;
+use tracing_subscriber::fmt::format::{DefaultFields, Writer};
+use tracing_subscriber::fmt::FormatFields;
+use tracing_subscriber::layer::{Context, SubscriberExt};
+use tracing_subscriber::registry::LookupSpan;
+use tracing_subscriber::util::SubscriberInitExt;
+use tracing_subscriber::{reload, EnvFilter, Layer, Registry};
+
+// Events kept for /api/logs unless COMPANION_LOG_BUFFER says otherwise
+const DEFAULT_BUFFER_SIZE: usize = 2000;
+// Tantivy reports every index reload at info
+const DEFAULT_FILTER: &str = "info,tantivy=warn";
+const LOG_FILE_NAME: &str = "companion.log";
+const DEFAULT_LOG_DIR: &str = "logs";
+const DEFAULT_LOG_FILE_SIZE: u64 = 10 * 1024 * 1024;
+const DEFAULT_LOG_FILES: usize = 5;
+
+lazy_static::lazy_static! {
+    static ref RECENT: Mutex<VecDeque<LogEntry>> = Mutex::new(VecDeque::new());
+}
+static BUFFER_SIZE: AtomicUsize = AtomicUsize::new(0);
+static FILTER: OnceLock<reload::Handle<EnvFilter, Registry>> = OnceLock::new();
+
+/// Logged event as served by /api/logs
+#[derive(Clone, Debug, Serialize)]
+pub struct LogEntry {
+    pub timestamp: DateTime<Utc>,
+    pub level: String,
+    pub target: String,
+    pub message: String,
+    /// Fields of the event and of the spans it happened in, such as the request path
+    pub fields: serde_json::Map<String, serde_json::Value>,
+    pub spans: Vec<String>,
+    #[serde(skip)]
+    severity: Level,
+}
+
+fn env_or<T: std::str::FromStr>(name: &str, default: T) -> T {
+    std::env::var(name)
+        .ok()
+        .and_then(|value| value.parse().ok())
+        .unwrap_or(default)
+}
+
+/// Log to stderr and to rotating files under COMPANION_LOG_DIR, logs in the data directory by
+/// default, filtered by RUST_LOG, and keep recent events in memory
+pub fn init() {
+    BUFFER_SIZE.store(env_or("COMPANION_LOG_BUFFER", DEFAULT_BUFFER_SIZE), Ordering::Relaxed);
+
+    let filter =
+        EnvFilter::try_from_default_env().unwrap_or_else(|_| EnvFilter::new(DEFAULT_FILTER));
+    let (filter, handle) = reload::Layer::new(filter);
+
+    // An empty COMPANION_LOG_DIR only logs to stderr
+    let log_dir = std::env::var("COMPANION_LOG_DIR").unwrap_or_else(|_| {
+        settings::get()
+            .data_dir
+            .join(DEFAULT_LOG_DIR)
+            .display()
+            .to_string()
+    });
+    let mut file_error = None;
+    let file_layer = if log_dir.is_empty() {
+        None
+    } else {
+        match RotatingFile::open(
+            PathBuf::from(&log_dir),
+            env_or("COMPANION_LOG_FILE_SIZE", DEFAULT_LOG_FILE_SIZE),
+            env_or("COMPANION_LOG_FILES", DEFAULT_LOG_FILES),
+        ) {
+            Ok(file) => Some(
+                tracing_subscriber::fmt::layer()
+                    .with_ansi(false)
+                    .fmt_fields(PlainFields::default())
+                    .with_writer(Mutex::new(file)),
+            ),
+            Err(e) => {
+                file_error = Some(e);
+                None
+            }
+        }
+    };
+
+    let result = tracing_subscriber::registry()
+        .with(filter)
+        .with(tracing_subscriber::fmt::layer().with_writer(std::io::stderr))
+        .with(file_layer)
+        .with(RecentLogs)
+        .try_init();
+    match result {
+        Ok(()) => {
+            let _ = FILTER.set(handle);
+        }
+        Err(e) => eprintln!("⚠️ Failed to set up logging: {}", e),
+    }
+    if let Some(e) = file_error {
+        tracing::warn!("⚠️ Not logging to files, failed to open {}: {}", log_dir, e);
+    }
+}
+
+/// Filter currently applied to every log output, in RUST_LOG syntax
+pub fn filter() -> Option<String> {
+    FILTER.get()?.with_current(|filter| filter.to_string()).ok()
+}
+
+/// Replace the filter of every log output, takes RUST_LOG syntax such as "debug,tantivy=warn"
+pub fn set_filter(directives: &str) -> Result<(), String> {
+    let filter = EnvFilter::try_new(directives).map_err(|e| e.to_string())?;
+    FILTER
+        .get()
+        .ok_or("Logging is not set up".to_string())?
+        .reload(filter)
+        .map_err(|e| e.to_string())
+}
+
+/// Whether /api/logs has anything to serve, COMPANION_LOG_BUFFER=0 turns it off
+pub fn enabled() -> bool {
+    BUFFER_SIZE.load(Ordering::Relaxed) > 0
+}
+
+/// Most recent `limit` events at `level` or more severe, oldest first
+pub fn query(
+    level: Level,
+    since: Option<DateTime<Utc>>,
+    until: Option<DateTime<Utc>>,
+    target: Option<&str>,
+    limit: usize,
+) -> Vec<LogEntry> {
+    let recent = match RECENT.lock() {
+        Ok(recent) => recent,
+        Err(_) => return Vec::new(),
+    };
+    let mut entries: Vec<LogEntry> = recent
+        .iter()
+        .rev()
+        .filter(|entry| entry.severity <= level)
+        .filter(|entry| since.map_or(true, |since| entry.timestamp >= since))
+        .filter(|entry| until.map_or(true, |until| entry.timestamp <= until))
+        .filter(|entry| target.map_or(true, |target| entry.target.starts_with(target)))
+        .take(limit)
+        .cloned()
+        .collect();
+    entries.reverse();
+    entries
+}
+
+/// Default field formatting under its own type. Span fields are formatted once per type
+/// and kept, sharing the stderr layer's would put its colors into the file
+#[derive(Default)]
+struct PlainFields(DefaultFields);
+
+impl<'writer> FormatFields<'writer> for PlainFields {
+    fn format_fields<R: RecordFields>(&self, writer: Writer<'writer>, fields: R) -> fmt::Result {
+        self.0.format_fields(writer, fields)
+    }
+}
+
+/// Log file that moves to companion.log.1, .2, ... once it grows past `max_size` bytes,
+/// keeping `max_files` old files
+struct RotatingFile {
+    dir: PathBuf,
+    max_size: u64,
+    max_files: usize,
+    file: File,
+    size: u64,
+}
+
+impl RotatingFile {
+    fn open(dir: PathBuf, max_size: u64, max_files: usize) -> io::Result<RotatingFile> {
+        fs::create_dir_all(&dir)?;
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(dir.join(LOG_FILE_NAME))?;
+        let size = file.metadata()?.len();
+        Ok(RotatingFile {
+            dir,
+            max_size,
+            max_files,
+            file,
+            size,
+        })
+    }
+
+    fn path(&self, index: usize) -> PathBuf {
+        match index {
+            0 => self.dir.join(LOG_FILE_NAME),
+            _ => self.dir.join(format!("{}.{}", LOG_FILE_NAME, index)),
+        }
+    }
+
+    fn rotate(&mut self) -> io::Result<()> {
+        let _ = fs::remove_file(self.path(self.max_files));
+        for index in (0..self.max_files).rev() {
+            let from = self.path(index);
+            if from.exists() {
+                fs::rename(&from, self.path(index + 1))?;
+            }
+        }
+        self.file = OpenOptions::new()
+            .create(true)
+            .write(true)
+            .truncate(true)
+            .open(self.path(0))?;
+        self.size = 0;
+        Ok(())
+    }
+}
+
+impl Write for RotatingFile {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        // Every event is written at once, so a rotation never splits one
+        if self.size > 0 && self.size + buf.len() as u64 > self.max_size {
+            self.rotate()?;
+        }
+        let written = self.file.write(buf)?;
+        self.size += written as u64;
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.file.flush()
+    }
+}
+
+#[derive(Default)]
+struct JsonVisitor {
+    message: String,
+    fields: serde_json::Map<String, serde_json::Value>,
+}
+
+impl JsonVisitor {
+    fn insert(&mut self, field: &Field, value: serde_json::Value) {
+        self.fields.insert(field.name().to_string(), value);
+    }
+}
+
+impl Visit for JsonVisitor {
+    fn record_debug(&mut self, field: &Field, value: &dyn fmt::Debug) {
+        if field.name() == "message" {
+            self.message = format!("{:?}", value);
+        } else {
+            self.insert(field, format!("{:?}", value).into());
+        }
+    }
+
+    fn record_str(&mut self, field: &Field, value: &str) {
+        if field.name() == "message" {
+            self.message = value.to_string();
+        } else {
+            self.insert(field, value.into());
+        }
+    }
+
+    fn record_i64(&mut self, field: &Field, value: i64) {
+        self.insert(field, value.into());
+    }
+
+    fn record_u64(&mut self, field: &Field, value: u64) {
+        self.insert(field, value.into());
+    }
+
+    fn record_f64(&mut self, field: &Field, value: f64) {
+        self.insert(field, value.into());
+    }
+
+    fn record_bool(&mut self, field: &Field, value: bool) {
+        self.insert(field, value.into());
+    }
+}
+
+/// Fields recorded on a span, stored in its extensions
+struct SpanFields(serde_json::Map<String, serde_json::Value>);
+
+/// Layer copying every event into the ring buffer behind /api/logs
+struct RecentLogs;
+
+impl<S> Layer<S> for RecentLogs
+where
+    S: Subscriber + for<'a> LookupSpan<'a>,
+{
+    fn on_new_span(&self, attrs: &Attributes<'_>, id: &Id, ctx: Context<'_, S>) {
+        let mut visitor = JsonVisitor::default();
+        attrs.record(&mut visitor);
+        if let Some(span) = ctx.span(id) {
+            span.extensions_mut().insert(SpanFields(visitor.fields));
+        }
+    }
+
+    fn on_record(&self, id: &Id, values: &Record<'_>, ctx: Context<'_, S>) {
+        let mut visitor = JsonVisitor::default();
+        values.record(&mut visitor);
+        if let Some(span) = ctx.span(id) {
+            if let Some(fields) = span.extensions_mut().get_mut::<SpanFields>() {
+                fields.0.extend(visitor.fields);
+            }
+        }
+    }
+
+    fn on_event(&self, event: &Event<'_>, ctx: Context<'_, S>) {
+        let capacity = BUFFER_SIZE.load(Ordering::Relaxed);
+        if capacity == 0 {
+            return;
+        }
+        let mut visitor = JsonVisitor::default();
+        event.record(&mut visitor);
+
+        let mut fields = serde_json::Map::new();
+        let mut spans = Vec::new();
+        if let Some(scope) = ctx.event_scope(event) {
+            for span in scope.from_root() {
+                spans.push(span.name().to_string());
+                if let Some(span_fields) = span.extensions().get::<SpanFields>() {
+                    fields.extend(span_fields.0.clone());
+                }
+            }
+        }
+        // Event fields win over span fields of the same name
+        fields.extend(visitor.fields);
+
+        let metadata = event.metadata();
+        // Records of crates using the log crate name their real target in a field
+        let target = match fields.remove("log.target") {
+            Some(serde_json::Value::String(target)) => target,
+            _ => metadata.target().to_string(),
+        };
+        fields.retain(|name, _| !name.starts_with("log."));
+        let entry = LogEntry {
+            timestamp: Utc::now(),
+            level: metadata.level().to_string(),
+            target,
+            message: visitor.message,
+            fields,
+            spans,
+            severity: *metadata.level(),
+        };
+        if let Ok(mut recent) = RECENT.lock() {
+            while recent.len() >= capacity {
+                recent.pop_front();
+            }
+            recent.push_back(entry);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_recent_logs_layer() {
+        BUFFER_SIZE.store(3, Ordering::Relaxed);
+        let subscriber = tracing_subscriber::registry().with(RecentLogs);
+        tracing::subscriber::with_default(subscriber, || {
+            let span = tracing::info_span!("request", path = "/api/test");
+            let _entered = span.enter();
+            tracing::debug!(target: "logging_test", "below the queried level");
+            tracing::warn!(target: "logging_test", elapsed_ms = 12u64, "slow");
+            tracing::error!(target: "logging_test", "failed");
+        });
+
+        let warnings = query(Level::WARN, None, None, Some("logging_test"), 10);
+        assert_eq!(warnings.len(), 2);
+        assert_eq!(warnings[0].message, "slow");
+        assert_eq!(warnings[0].fields["elapsed_ms"], 12);
+        assert_eq!(warnings[0].fields["path"], "/api/test");
+        assert_eq!(warnings[0].spans, vec!["request"]);
+
+        let latest = query(Level::TRACE, None, None, Some("logging_test"), 1);
+        assert_eq!(latest[0].message, "failed");
+    }
+
+    #[test]
+    fn test_rotating_file() {
+        let dir = std::env::temp_dir().join(format!("companion_logs_{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        let mut file = RotatingFile::open(dir.clone(), 10, 2).unwrap();
+        for line in ["first\n", "second\n", "third\n", "fourth\n"] {
+            file.write_all(line.as_bytes()).unwrap();
+        }
+        let read = |name: &str| fs::read_to_string(dir.join(name)).unwrap();
+        assert_eq!(read("companion.log"), "fourth\n");
+        assert_eq!(read("companion.log.1"), "third\n");
+        assert_eq!(read("companion.log.2"), "second\n");
+        // Only two old files are kept
+        assert!(!dir.join("companion.log.3").exists());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -1,15 +1,19 @@
+use crate::database::Database;
+use crate::settings;
+use crate::memory_embeddings::{self, fuse_rankings, MemoryEmbeddings, RETRIEVAL_KEYWORD, RETRIEVAL_VECTOR};
 use std::collections::HashMap;
 use std::fs;
-use std::path::Path;
+use std::path::PathBuf;
 use std::sync::{Arc, Mutex};
 use std::time::{Duration, Instant};
 use tantivy::collector::TopDocs;
 use tantivy::error::TantivyError;
-use tantivy::query::QueryParser;
+use tantivy::query::{AllQuery, QueryParser};
 use tantivy::schema::*;
 use tantivy::{Index, IndexReader};
 
 pub struct LongTermMem {
+    companion_id: i32,
     index: Index,
     chat_field: Field,
     reader: Arc<IndexReader>,
@@ -17,16 +21,37 @@ pub struct LongTermMem {
 }
 
 impl LongTermMem {
+    /// Index directory of a companion in the data directory, the first companion keeps the original name
+    pub fn directory(companion_id: i32) -> PathBuf {
+        let name = if companion_id == 1 {
+            "longterm_memory".to_string()
+        } else {
+            format!("longterm_memory_{}", companion_id)
+        };
+        settings::get().data_dir.join(name)
+    }
+
+    pub fn companion_id(&self) -> i32 {
+        self.companion_id
+    }
+
+    /// Open the long-term memory of the active companion
     pub fn connect() -> tantivy::Result<Self> {
+        LongTermMem::open(Database::active_companion_id())
+    }
+
+    /// Open the long-term memory of any companion, creating it if it does not exist yet
+    pub fn open(companion_id: i32) -> tantivy::Result<Self> {
+        let directory = LongTermMem::directory(companion_id);
         let mut schema_builder = SchemaBuilder::default();
         let chat_field = schema_builder.add_text_field("chat", TEXT | STORED);
         let schema = schema_builder.build();
-        if !Path::new("longterm_memory").exists() {
-            fs::create_dir("longterm_memory")?;
+        if !directory.exists() {
+            fs::create_dir(&directory)?;
         }
-        let companion_vector = match Index::open_in_dir("longterm_memory") {
+        let companion_vector = match Index::open_in_dir(&directory) {
             Ok(index) => index,
-            Err(_) => Index::create_in_dir("longterm_memory", schema)?,
+            Err(_) => Index::create_in_dir(&directory, schema)?,
         };
 
         // Create shared reader for better performance
@@ -34,6 +59,7 @@ impl LongTermMem {
         let query_cache = Arc::new(Mutex::new(HashMap::new()));
 
         Ok(LongTermMem {
+            companion_id,
             index: companion_vector,
             chat_field,
             reader,
@@ -53,9 +79,76 @@ impl LongTermMem {
             cache.clear();
         }
 
+        // The entry is stored either way, a missing vector is added by the next backfill
+        if let Ok(config) = Database::get_config() {
+            if config.memory_retrieval != RETRIEVAL_KEYWORD {
+                let embedder = memory_embeddings::embedder(&config);
+                if let Err(e) = MemoryEmbeddings::add(self.companion_id, embedder.as_ref(), text) {
+                    tracing::warn!("⚠️ Failed to embed long-term memory entry: {}", e);
+                }
+            }
+        }
+
         Ok(())
     }
 
+    /// Entries related to the query, ranked by keywords, vector similarity or both as configured
+    pub fn recall(&self, query: &str, limit: usize) -> Result<Vec<String>, TantivyError> {
+        let config = Database::get_config().map_err(|e| TantivyError::InternalError(e.to_string()))?;
+        if limit == 0 || config.memory_retrieval == RETRIEVAL_KEYWORD {
+            return self.get_matches(query, limit);
+        }
+        let embedder = memory_embeddings::embedder(&config);
+        // Candidates from both searches, the fusion picks the best `limit` of them
+        let vector = match MemoryEmbeddings::search(self.companion_id, embedder.as_ref(), query, limit * 2) {
+            Ok(entries) => entries,
+            Err(e) => {
+                tracing::warn!("⚠️ Vector memory search failed, using keyword search: {}", e);
+                return self.get_matches(query, limit);
+            }
+        };
+        if config.memory_retrieval == RETRIEVAL_VECTOR {
+            return Ok(vector.into_iter().take(limit).collect());
+        }
+        let keyword = self.get_matches(query, limit * 2)?;
+        Ok(fuse_rankings(&keyword, &vector, limit))
+    }
+
+    /// Every stored entry, for embedding the ones written before vector retrieval was enabled
+    pub fn all_entries(&self) -> Result<Vec<String>, TantivyError> {
+        self.reader.reload()?;
+        let searcher = self.reader.searcher();
+        let count = searcher.num_docs() as usize;
+        if count == 0 {
+            return Ok(Vec::new());
+        }
+        let mut result = Vec::new();
+        for (_, text_addr) in searcher.search(&AllQuery, &TopDocs::with_limit(count))? {
+            let retrieved = searcher.doc(text_addr)?;
+            let r = retrieved
+                .get_first(self.chat_field)
+                .and_then(|val| val.as_text())
+                .unwrap_or("");
+            result.push(r.to_string());
+        }
+        Ok(result)
+    }
+
+    /// Maintenance job entry point, embeds the active companion's entries that have no vector yet
+    pub fn backfill_embeddings() -> Result<String, String> {
+        let config = Database::get_config().map_err(|e| e.to_string())?;
+        if config.memory_retrieval == RETRIEVAL_KEYWORD {
+            return Ok(String::new());
+        }
+        let ltm = LongTermMem::connect().map_err(|e| e.to_string())?;
+        let entries = ltm.all_entries().map_err(|e| e.to_string())?;
+        let embedder = memory_embeddings::embedder(&config);
+        match MemoryEmbeddings::backfill(ltm.companion_id, embedder.as_ref(), &entries)? {
+            0 => Ok(String::new()),
+            added => Ok(format!("embedded {} long-term memory entries", added)),
+        }
+    }
+
     pub fn get_matches(
         &self,
         query_string: &str,
@@ -122,6 +215,9 @@ impl LongTermMem {
         let mut writer = self.index.writer(50_000_000)?;
         writer.delete_all_documents()?;
         writer.commit()?;
+        if let Err(e) = MemoryEmbeddings::erase(self.companion_id) {
+            tracing::warn!("⚠️ Failed to erase long-term memory vectors: {}", e);
+        }
 
         // Clear cache when memory is erased
         if let Ok(mut cache) = self.query_cache.lock() {
@@ -131,6 +227,24 @@ impl LongTermMem {
         Ok(())
     }
 
+    /// Swap every entry for the given ones in a single commit, used when restoring a backup
+    ///
+    /// Vectors are not computed here, the embedding backfill adds them later.
+    pub fn replace_entries(&self, entries: &[String]) -> Result<(), TantivyError> {
+        let mut writer = self.index.writer(50_000_000)?;
+        writer.delete_all_documents()?;
+        for entry in entries {
+            writer.add_document(tantivy::doc!(
+                self.chat_field => entry.as_str()
+            ))?;
+        }
+        writer.commit()?;
+        if let Ok(mut cache) = self.query_cache.lock() {
+            cache.clear();
+        }
+        Ok(())
+    }
+
     pub fn refresh_reader(&self) -> Result<(), TantivyError> {
         // Force refresh the reader to see latest changes
         self.reader.reload()?;
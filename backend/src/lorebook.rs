@@ -0,0 +1,217 @@
+use crate::database::get_current_date;
+use crate::db_pool;
+use rusqlite::{params, Connection, Error, OptionalExtension, Result};
+use serde::{Deserialize, Serialize};
+
+// Messages at the end of the chat searched for keys, older mentions no longer bring lore in
+pub const SCAN_DEPTH: usize = 4;
+
+/// World info the companion gets told about once one of its keys comes up in the chat
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct LorebookEntry {
+    pub id: i32,
+    /// None for entries every companion shares
+    pub companion_id: Option<i32>,
+    pub keys: Vec<String>,
+    pub content: String,
+    /// Entries with a higher priority are inserted first when the token budget runs out
+    pub priority: i32,
+    pub enabled: bool,
+    pub created_at: String,
+    pub updated_at: String,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct LorebookEntryModify {
+    pub keys: Vec<String>,
+    pub content: String,
+    #[serde(default = "default_priority")]
+    pub priority: i32,
+    #[serde(default = "default_enabled")]
+    pub enabled: bool,
+    /// Shared by every companion instead of belonging to the active one
+    #[serde(default)]
+    pub global: bool,
+}
+
+fn default_priority() -> i32 {
+    100
+}
+
+fn default_enabled() -> bool {
+    true
+}
+
+impl LorebookEntryModify {
+    /// Keys trimmed with empty ones dropped, an error when nothing is left to trigger the entry
+    pub fn validate(&mut self) -> Result<(), String> {
+        self.keys = self
+            .keys
+            .iter()
+            .map(|key| key.trim().to_string())
+            .filter(|key| !key.is_empty())
+            .collect();
+        if self.keys.is_empty() {
+            return Err("A lorebook entry needs at least one key".to_string());
+        }
+        if self.content.trim().is_empty() {
+            return Err("A lorebook entry needs content".to_string());
+        }
+        Ok(())
+    }
+}
+
+impl LorebookEntry {
+    /// Whether a key appears in `text` as a whole word or phrase, ignoring case
+    pub fn is_triggered_by(&self, text: &str) -> bool {
+        let text = text.to_lowercase();
+        self.keys.iter().any(|key| contains_phrase(&text, &key.to_lowercase()))
+    }
+}
+
+fn contains_phrase(text: &str, phrase: &str) -> bool {
+    if phrase.is_empty() {
+        return false;
+    }
+    text.match_indices(phrase).any(|(start, _)| {
+        let before = text[..start].chars().next_back();
+        let after = text[start + phrase.len()..].chars().next();
+        !before.map_or(false, char::is_alphanumeric) && !after.map_or(false, char::is_alphanumeric)
+    })
+}
+
+pub struct Lorebook {}
+
+impl Lorebook {
+    pub fn create() -> Result<()> {
+        let con = db_pool::connection()?;
+        con.execute(
+            "CREATE TABLE IF NOT EXISTS lorebook_entries (
+                id INTEGER PRIMARY KEY AUTOINCREMENT,
+                companion_id INTEGER,
+                keys TEXT NOT NULL DEFAULT '[]',
+                content TEXT NOT NULL,
+                priority INTEGER NOT NULL DEFAULT 100,
+                enabled BOOLEAN NOT NULL DEFAULT 1,
+                created_at TEXT NOT NULL,
+                updated_at TEXT NOT NULL
+            )",
+            [],
+        )?;
+        con.execute(
+            "CREATE INDEX IF NOT EXISTS idx_lorebook_entries_companion ON lorebook_entries(companion_id)",
+            [],
+        )?;
+        Ok(())
+    }
+
+    fn from_row(row: &rusqlite::Row) -> Result<LorebookEntry> {
+        let keys: String = row.get(2)?;
+        Ok(LorebookEntry {
+            id: row.get(0)?,
+            companion_id: row.get(1)?,
+            keys: serde_json::from_str(&keys).map_err(|e| {
+                Error::FromSqlConversionFailure(2, rusqlite::types::Type::Text, Box::new(e))
+            })?,
+            content: row.get(3)?,
+            priority: row.get(4)?,
+            enabled: row.get(5)?,
+            created_at: row.get(6)?,
+            updated_at: row.get(7)?,
+        })
+    }
+
+    /// Entries of a companion and the global ones, the highest priority first
+    pub fn list(companion_id: i32) -> Result<Vec<LorebookEntry>> {
+        let con = db_pool::connection()?;
+        let mut stmt = con.prepare(
+            "SELECT id, companion_id, keys, content, priority, enabled, created_at, updated_at
+             FROM lorebook_entries WHERE companion_id IS NULL OR companion_id = ?
+             ORDER BY priority DESC, id",
+        )?;
+        let rows = stmt.query_map([companion_id], Lorebook::from_row)?;
+        rows.collect()
+    }
+
+    pub fn get(id: i32) -> Result<Option<LorebookEntry>> {
+        let con = db_pool::connection()?;
+        con.query_row(
+            "SELECT id, companion_id, keys, content, priority, enabled, created_at, updated_at
+             FROM lorebook_entries WHERE id = ?",
+            [id],
+            Lorebook::from_row,
+        )
+        .optional()
+    }
+
+    pub fn add(companion_id: i32, entry: &LorebookEntryModify) -> Result<i32> {
+        let con = db_pool::connection()?;
+        Lorebook::insert(&con, companion_id, entry)
+    }
+
+    /// Insert on a caller's connection so it can be part of a larger transaction
+    pub fn insert(con: &Connection, companion_id: i32, entry: &LorebookEntryModify) -> Result<i32> {
+        con.execute(
+            "INSERT INTO lorebook_entries (companion_id, keys, content, priority, enabled, created_at, updated_at)
+             VALUES (?, ?, ?, ?, ?, ?, ?)",
+            params![
+                if entry.global { None } else { Some(companion_id) },
+                serde_json::json!(entry.keys).to_string(),
+                entry.content,
+                entry.priority,
+                entry.enabled,
+                get_current_date(),
+                get_current_date()
+            ],
+        )?;
+        Ok(con.last_insert_rowid() as i32)
+    }
+
+    /// Edit an entry, `global` moves it between the companion and every companion
+    pub fn edit(id: i32, companion_id: i32, entry: &LorebookEntryModify) -> Result<bool> {
+        let con = db_pool::connection()?;
+        let changed = con.execute(
+            "UPDATE lorebook_entries SET companion_id = ?, keys = ?, content = ?, priority = ?, enabled = ?, updated_at = ?
+             WHERE id = ?",
+            params![
+                if entry.global { None } else { Some(companion_id) },
+                serde_json::json!(entry.keys).to_string(),
+                entry.content,
+                entry.priority,
+                entry.enabled,
+                get_current_date(),
+                id
+            ],
+        )?;
+        Ok(changed > 0)
+    }
+
+    pub fn delete(id: i32) -> Result<bool> {
+        let con = db_pool::connection()?;
+        let changed = con.execute("DELETE FROM lorebook_entries WHERE id = ?", [id])?;
+        Ok(changed > 0)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_is_triggered_by() {
+        let entry = LorebookEntry {
+            id: 1,
+            companion_id: None,
+            keys: vec!["Rivermoor".to_string(), "old mill".to_string()],
+            content: "Rivermoor is a fishing village.".to_string(),
+            priority: 100,
+            enabled: true,
+            created_at: String::new(),
+            updated_at: String::new(),
+        };
+        assert!(entry.is_triggered_by("Have you ever been to rivermoor?"));
+        assert!(entry.is_triggered_by("We met at the Old Mill."));
+        assert!(!entry.is_triggered_by("Rivermoorland is far away"));
+        assert!(!entry.is_triggered_by("the old millstone"));
+    }
+}
@@ -1,97 +1,312 @@
-use actix_web::{delete, get, post, put, web, App, HttpResponse, HttpServer};
+use actix_web::dev::Service as _;
+use actix_web::{delete, get, post, put, routes, web, App, HttpResponse, HttpServer};
+use futures_util::future::{self, Either};
 use futures_util::StreamExt as _;
+use tracing::{debug, error, info, warn, Instrument as _};
+mod api_error;
+use crate::api_error::{ApiError, OrInternal};
 mod database;
+mod db_pool;
 use database::{
-    CompanionAttitude, CompanionView, ConfigModify, Database, Message, NewMessage,
-    ThirdPartyInteraction, UserView,
+    CompanionAttitude, CompanionView, ConfigModify, ConfigView, Database, Message, NewMessage,
+    ThirdPartyInteraction, ThirdPartyRelationshipModify, UserView,
 };
 mod long_term_mem;
 use long_term_mem::LongTermMem;
 mod dialogue_tuning;
 use dialogue_tuning::DialogueTuning;
 mod character_card;
+mod conversations;
+use crate::conversations::{ConversationOverrides, Conversations};
 use character_card::CharacterCard;
+mod persona_pack;
+mod personality_traits;
+mod template_variables;
+use crate::template_variables::{TemplateVariable, TemplateVariables, Variables};
+use crate::personality_traits::{PersonalityTrait, PersonalityTraits, PERSONALITY_TRAITS};
+mod prompt_templates;
+use crate::prompt_templates::{PromptTemplateModify, PromptTemplates};
+mod prompt_layout;
+use crate::prompt_layout::PromptLayout;
+mod config_profiles;
+use crate::config_profiles::{ConfigProfileModify, ConfigProfiles};
+use persona_pack::{PackManifest, PersonaPack};
 use serde::Deserialize;
 mod llm;
-use crate::llm::prompt;
+use crate::llm::{incognito_prompt, prompt, prompt_preview};
+mod remote_llm;
 mod context_manager;
 mod inference_optimizer;
-use crate::inference_optimizer::{StreamChunk, INFERENCE_OPTIMIZER};
+use crate::inference_optimizer::{InferenceOptimizer, StreamChunk, INFERENCE_OPTIMIZER};
+mod inference_queue;
+mod rate_limit;
+mod web_ui;
+mod proactivity;
+use crate::proactivity::Proactivity;
+mod sampling;
+use crate::sampling::{SamplingOverrides, SamplingParams};
 mod session_manager;
+mod settings;
+mod shutdown;
+mod social_graph;
+use crate::social_graph::SocialGraph;
 mod token_budget;
+use crate::token_budget::PromptSection;
 use crate::session_manager::SessionManager;
+mod attitude_dimensions;
+mod attitude_engine;
+mod attitude_history;
+mod attitude_inference;
+use crate::attitude_history::AttitudeHistory;
+mod mood;
+mod mood_modifiers;
+mod scene;
+use crate::scene::{Scene, SceneModify};
+use crate::mood_modifiers::{MoodModifier, NewMoodModifier, MOOD_PRESETS};
+use crate::mood::{Mood, MoodKind};
+use crate::attitude_dimensions::FieldError;
 mod attitude_formatter;
+mod auth;
+mod backup;
+use crate::backup::Backup;
 mod gpu_allocator;
+mod hardware_probe;
 use crate::gpu_allocator::{GpuAllocator, LayerAllocation};
 mod system_memory;
 // Removed unused system_memory imports
 mod inference_performance;
-use crate::inference_performance::{ModelConfig, ResponseEstimate, INFERENCE_TRACKER};
+use crate::inference_performance::{
+    InferencePerformanceTracker, ModelConfig, ResponseEstimate, TrendBucket, BENCHMARK_RUNNING, INFERENCE_TRACKER,
+};
 mod llm_scanner;
 use crate::llm_scanner::{DirectoryInfo, LlmScanner, ModelInfo};
+mod daily_recap;
+mod delivery_queue;
+#[cfg(feature = "discord")]
+mod discord;
+mod event_bus;
+mod instance_lock;
+mod health;
+mod interaction_scheduler;
+mod websocket;
+use crate::delivery_queue::DeliveryQueue;
+mod journal;
+mod logging;
+use crate::journal::Journal;
+mod avatar_expressions;
+use crate::avatar_expressions::AvatarExpressions;
+mod filters;
+use crate::filters::{ContentFilterModify, FilterRuleModify, Filters, Stage};
+mod lorebook;
+use crate::lorebook::{Lorebook, LorebookEntryModify};
+mod goals;
+use crate::goals::{GoalModify, GoalStatus, Goals};
+mod memory_embeddings;
+use crate::memory_embeddings::MemoryEmbeddings;
+mod memory_consolidation;
+use crate::memory_consolidation::MemoryConsolidation;
+mod memory_proposals;
+mod naming;
+use crate::naming::fill_placeholders;
+mod ner;
+mod message_attempts;
+use crate::message_attempts::MessageAttempts;
+mod message_feedback;
+use crate::message_feedback::{FeedbackError, FeedbackModify, MessageFeedback};
+mod message_images;
+mod message_usage;
+use crate::message_usage::MessageUsage;
+mod chat_import;
+use crate::chat_import::ImportJobs;
+mod chat_export;
+use crate::chat_export::{ChatExport, ExportFormat};
+use crate::message_images::{ImageFormat, MessageImages};
+mod message_search;
+use crate::message_search::{MessageSearch, SearchFilters, Speaker};
+use crate::memory_proposals::MemoryProposals;
+#[cfg(feature = "dev")]
+mod dev_seed;
+mod maintenance;
+mod model_downloads;
+mod stt;
+use crate::stt::AudioFormat;
+mod tts;
+use crate::tts::MessageAudio;
+mod vision;
+mod image_gen;
+use crate::image_gen::Shot;
+use crate::model_downloads::{huggingface_download_url, ModelDownloads};
 #[cfg(test)]
 mod simple_tests;
 
 use std::fs;
 use std::fs::File;
 use std::io::{Read, Write};
+use std::sync::atomic::Ordering;
+
+// Avatar every companion shared before each got its own, still served for companions that point at it
+const SHARED_AVATAR_FILE: &str = "avatar.png";
 
-#[get("/")]
-async fn index() -> HttpResponse {
-    HttpResponse::Ok().body(include_str!("../../dist/index.html"))
+/// Avatar path a companion stores, the file itself is in the assets directory
+fn avatar_url(companion_id: i32) -> String {
+    format!("assets/avatar_{}.png", companion_id)
 }
 
-#[get("/assets/index-4rust.js")]
-async fn js() -> HttpResponse {
-    HttpResponse::Ok()
-        .content_type("application/javascript")
-        .body(include_str!("../../dist/assets/index-4rust.js"))
+fn avatar_file(companion_id: i32) -> std::path::PathBuf {
+    settings::get().assets_dir.join(format!("avatar_{}.png", companion_id))
 }
 
-#[get("/assets/index-4rust2.js")]
-async fn js2() -> HttpResponse {
-    HttpResponse::Ok()
-        .content_type("application/javascript")
-        .body(include_str!("../../dist/assets/index-4rust2.js"))
+/// Custom avatar a companion points at, None for the default one embedded in the binary
+fn read_avatar(avatar_path: &str) -> Option<Vec<u8>> {
+    let name = std::path::Path::new(avatar_path.trim_start_matches('/'))
+        .strip_prefix("assets")
+        .ok()?
+        .file_name()?
+        .to_owned();
+    fs::read(settings::get().assets_dir.join(name)).ok()
 }
 
-#[get("/assets/index-4rust.css")]
-async fn css() -> HttpResponse {
-    HttpResponse::Ok()
-        .content_type("text/css")
-        .body(include_str!("../../dist/assets/index-4rust.css"))
+fn avatar_response(path: std::path::PathBuf) -> Result<HttpResponse, ApiError> {
+    match File::open(path) {
+        Ok(mut file) => {
+            let mut buffer = Vec::new();
+            file.read_to_end(&mut buffer)
+                .or_internal("Error while reading companion avatar")?;
+
+            Ok(HttpResponse::Ok().content_type("image/png").body(buffer))
+        }
+        Err(_) => Err(ApiError::NotFound("File not found".to_string())),
+    }
 }
 
-#[get("/ai_companion_logo.jpg")]
-async fn project_logo() -> HttpResponse {
-    HttpResponse::Ok()
-        .content_type("image/jpeg")
-        .body(&include_bytes!("../../dist/ai_companion_logo.jpg")[..])
+#[get("/assets/avatar.png")]
+async fn companion_avatar_shared() -> Result<HttpResponse, ApiError> {
+    avatar_response(settings::get().assets_dir.join(SHARED_AVATAR_FILE))
 }
 
-#[get("/assets/companion_avatar-4rust.jpg")]
-async fn companion_avatar_img() -> HttpResponse {
-    HttpResponse::Ok()
-        .content_type("image/jpeg")
-        .body(&include_bytes!("../../dist/assets/companion_avatar-4rust.jpg")[..])
+#[get("/assets/avatar_{companion_id}.png")]
+async fn companion_avatar_custom(companion_id: web::Path<i32>) -> Result<HttpResponse, ApiError> {
+    avatar_response(avatar_file(companion_id.into_inner()))
 }
 
-#[get("/assets/avatar.png")]
-async fn companion_avatar_custom() -> actix_web::Result<actix_web::HttpResponse> {
-    match File::open("assets/avatar.png") {
-        Ok(mut file) => {
-            let mut buffer = Vec::new();
-            file.read_to_end(&mut buffer)?;
+//              API
 
-            Ok(actix_web::HttpResponse::Ok()
-                .content_type("image/png")
-                .body(buffer))
-        }
-        Err(_) => Err(actix_web::error::ErrorNotFound("File not found")),
+#[get("/api/backup")]
+async fn backup_export() -> Result<HttpResponse, ApiError> {
+    let backup = Backup::capture().or_internal("Error while creating backup")?;
+    let bytes = backup.to_zip().or_internal("Error while creating backup")?;
+    Ok(HttpResponse::Ok()
+        .content_type("application/zip")
+        .insert_header((
+            "Content-Disposition",
+            format!(
+                "attachment; filename=\"companion_backup_{}.zip\"",
+                chrono::Local::now().format("%Y-%m-%d")
+            ),
+        ))
+        .body(bytes))
+}
+
+#[post("/api/restore")]
+async fn backup_restore(mut received: actix_web::web::Payload) -> Result<HttpResponse, ApiError> {
+    // curl -X POST -H "Content-Type: application/zip" -T companion_backup.zip http://localhost:3000/api/restore
+    let mut data = web::BytesMut::new();
+    while let Some(chunk) = received.next().await {
+        let d = chunk.map_err(|e| ApiError::BadRequest(format!("Error while receiving backup: {}", e)))?;
+        data.extend_from_slice(&d);
     }
+    // Everything is parsed and validated before anything is written
+    let backup = Backup::from_zip(&data)
+        .map_err(|e| ApiError::BadRequest(format!("Invalid backup: {}", e)))?;
+    let summary = backup.restore().or_internal("Error while restoring backup")?;
+    info!("♻️ Restored backup with {} tables", summary.tables.len());
+    let summary_json = serde_json::to_string(&summary)
+        .unwrap_or(String::from("Error serializing restore summary as JSON"));
+    Ok(HttpResponse::Ok().body(summary_json))
 }
 
-//              API
+#[derive(Deserialize)]
+struct LogsQuery {
+    level: Option<String>,
+    since: Option<chrono::DateTime<chrono::Utc>>,
+    until: Option<chrono::DateTime<chrono::Utc>>,
+    target: Option<String>,
+    limit: Option<usize>,
+}
+
+#[get("/api/logs")]
+async fn get_logs(query: web::Query<LogsQuery>) -> Result<HttpResponse, ApiError> {
+    if !logging::enabled() {
+        return Err(ApiError::NotFound(
+            "The log buffer is disabled, set COMPANION_LOG_BUFFER to keep recent logs".to_string(),
+        ));
+    }
+    let level = match &query.level {
+        Some(level) => level
+            .parse::<tracing::Level>()
+            .map_err(|_| ApiError::BadRequest(format!("Unknown log level '{}'", level)))?,
+        None => tracing::Level::INFO,
+    };
+    let entries = logging::query(
+        level,
+        query.since,
+        query.until,
+        query.target.as_deref(),
+        query.limit.unwrap_or(200),
+    );
+    let entries_json =
+        serde_json::to_string(&entries).unwrap_or(String::from("Error serializing logs as JSON"));
+    Ok(HttpResponse::Ok().body(entries_json))
+}
+
+#[derive(Deserialize)]
+struct LogLevel {
+    filter: String,
+}
+
+#[get("/api/logs/level")]
+async fn get_log_level() -> Result<HttpResponse, ApiError> {
+    let filter = logging::filter()
+        .ok_or(ApiError::Unavailable("Logging is not set up".to_string()))?;
+    Ok(HttpResponse::Ok().json(serde_json::json!({ "filter": filter })))
+}
+
+#[put("/api/logs/level")]
+async fn set_log_level(received: web::Json<LogLevel>) -> Result<HttpResponse, ApiError> {
+    logging::set_filter(&received.filter).map_err(ApiError::BadRequest)?;
+    info!("📝 Log filter set to {}", received.filter);
+    Ok(HttpResponse::Ok().body("Log filter updated!"))
+}
+
+#[get("/api/health")]
+async fn get_health(request: actix_web::HttpRequest) -> Result<HttpResponse, ApiError> {
+    let detailed = auth::permitted(&request).or_internal("Error while checking credentials")?;
+    let health = web::block(health::health)
+        .await
+        .or_internal("Error while checking health")?;
+    // Paths, errors and hardware are only for those who may use the API
+    if !detailed {
+        return Ok(HttpResponse::Ok().json(serde_json::json!({ "status": health.status })));
+    }
+    Ok(HttpResponse::Ok().json(health))
+}
+
+/// For load balancers and orchestrators, 503 until replies can be served
+#[get("/api/ready")]
+async fn get_readiness(request: actix_web::HttpRequest) -> Result<HttpResponse, ApiError> {
+    let detailed = auth::permitted(&request).or_internal("Error while checking credentials")?;
+    let mut readiness = web::block(health::readiness)
+        .await
+        .or_internal("Error while checking readiness")?;
+    if !detailed {
+        readiness.reasons.clear();
+    }
+    if readiness.ready {
+        Ok(HttpResponse::Ok().json(readiness))
+    } else {
+        Ok(HttpResponse::ServiceUnavailable().json(readiness))
+    }
+}
 
 //              Message
 
@@ -109,32 +324,19 @@ struct MessagePage {
 }
 
 #[get("/api/message")]
-async fn message(query_params: web::Query<MessageQuery>) -> HttpResponse {
+async fn message(query_params: web::Query<MessageQuery>) -> Result<HttpResponse, ApiError> {
     let start_index: usize = query_params.start_index.unwrap_or(0);
 
     // 50 Messages is the max
     let limit: usize = query_params.limit.unwrap_or(15).min(50);
 
     // Get total message count for pagination metadata
-    let total_count = match Database::get_total_message_count() {
-        Ok(count) => count,
-        Err(e) => {
-            println!("Failed to get total message count: {}", e);
-            return HttpResponse::InternalServerError()
-                .body("Error while getting message count, check logs for more information");
-        }
-    };
+    let total_count =
+        Database::get_total_message_count().or_internal("Error while getting message count")?;
 
     // Query to database, and return messages
-    let messages: Vec<Message> = match Database::get_x_messages(limit, start_index) {
-        Ok(v) => v,
-        Err(e) => {
-            println!("Failed to get messages from database: {}", e);
-            return HttpResponse::InternalServerError().body(
-                "Error while getting messages from database, check logs for more information",
-            );
-        }
-    };
+    let messages: Vec<Message> = Database::get_x_messages(limit, start_index)
+        .or_internal("Error while getting messages from database")?;
 
     let has_more = start_index + messages.len() < total_count;
     let message_page = MessagePage {
@@ -145,109 +347,533 @@ async fn message(query_params: web::Query<MessageQuery>) -> HttpResponse {
 
     let page_json = serde_json::to_string(&message_page)
         .unwrap_or(String::from("Error serializing message page as JSON"));
-    HttpResponse::Ok().body(page_json)
+    Ok(HttpResponse::Ok().body(page_json))
 }
 
 #[post("/api/message")]
-async fn message_post(received: web::Json<NewMessage>) -> HttpResponse {
-    match Database::insert_message(received.into_inner()) {
-        Ok(_) => HttpResponse::Ok().body("Message added!"),
-        Err(e) => {
-            println!("Failed to add message: {}", e);
-            HttpResponse::InternalServerError()
-                .body("Error while adding message, check logs for more information")
+async fn message_post(received: web::Json<NewMessage>) -> Result<HttpResponse, ApiError> {
+    let mut received = received.into_inner();
+    received.content = Filters::apply(Stage::of_message(received.ai), &received.content);
+    let images = received.images.clone();
+    match Database::insert_message(received) {
+        Ok(_) => {}
+        Err(rusqlite::Error::InvalidParameterName(e)) => return Err(ApiError::BadRequest(e)),
+        Err(e) => return Err(ApiError::internal("Error while adding message", e)),
+    }
+    if !images.is_empty() {
+        let config_data = Database::get_config().or_internal("Error while getting config")?;
+        web::block(move || vision::describe_uploads(&config_data, &images))
+            .await
+            .or_internal("Error while describing images")?;
+    }
+    Ok(HttpResponse::Ok().body("Message added!"))
+}
+
+#[derive(Deserialize)]
+struct ChatImportQuery {
+    format: Option<String>,
+    title: Option<String>,
+    #[serde(default)]
+    analyze: bool,
+}
+
+#[post("/api/message/import")]
+async fn message_import(
+    query: web::Query<ChatImportQuery>,
+    mut received: web::Payload,
+) -> Result<HttpResponse, ApiError> {
+    let mut data = web::BytesMut::new();
+    while let Some(chunk) = received.next().await {
+        let d = chunk.map_err(|e| ApiError::BadRequest(format!("Error while receiving chat export: {}", e)))?;
+        if data.len() + d.len() > chat_import::MAX_IMPORT_BYTES {
+            return Err(ApiError::BadRequest(format!(
+                "Chat export is larger than {} MB",
+                chat_import::MAX_IMPORT_BYTES / 1024 / 1024
+            )));
+        }
+        data.extend_from_slice(&d);
+    }
+    let data = String::from_utf8(data.to_vec())
+        .map_err(|_| ApiError::BadRequest("Chat export is not UTF-8 text".to_string()))?;
+    let mut chat = chat_import::parse(&data, query.format.as_deref()).map_err(ApiError::BadRequest)?;
+    for imported in chat.threads.iter_mut().flatten() {
+        imported.content = Filters::apply(Stage::of_message(imported.ai), &imported.content);
+    }
+
+    let companion_id = Database::active_companion_id();
+    let user_id = Database::active_user_id();
+    let title = match (query.title.as_deref().map(str::trim), &chat.character) {
+        (Some(title), _) if !title.is_empty() => title.to_string(),
+        (_, Some(character)) => format!("Imported chat with {}", character),
+        _ => "Imported chat".to_string(),
+    };
+    let conversation_ids = chat_import::store(&chat, companion_id, user_id, &title)
+        .or_internal("Error while importing chat")?;
+    let imported = chat.threads.iter().map(Vec::len).sum::<usize>();
+    let (format, skipped) = (chat.format, chat.skipped);
+    // Detection and inference take a while on a long history, the chat is usable meanwhile
+    let job = if query.analyze {
+        let config_data = Database::get_config().or_internal("Error while getting config")?;
+        let job = ImportJobs::start(&chat, conversation_ids.clone());
+        let job_id = job.id;
+        actix_web::rt::task::spawn_blocking(move || {
+            ImportJobs::run(job_id, chat, config_data, companion_id, user_id)
+        });
+        Some(job)
+    } else {
+        None
+    };
+    Ok(HttpResponse::Created().json(serde_json::json!({
+        "format": format,
+        "conversation_ids": conversation_ids,
+        "imported": imported,
+        "skipped": skipped,
+        "job": job,
+    })))
+}
+
+#[get("/api/message/import/{id}")]
+async fn message_import_job(id: web::Path<u64>) -> Result<HttpResponse, ApiError> {
+    match ImportJobs::get(*id) {
+        Some(job) => Ok(HttpResponse::Ok().json(job)),
+        None => Err(ApiError::NotFound(format!("Import job {} not found", id))),
+    }
+}
+
+#[derive(Deserialize)]
+struct ChatExportQuery {
+    format: Option<String>,
+    conversation_id: Option<i32>,
+    #[serde(default)]
+    attitudes: bool,
+}
+
+#[get("/api/message/export")]
+async fn message_export(query: web::Query<ChatExportQuery>) -> Result<HttpResponse, ApiError> {
+    let format = match query.format.as_deref() {
+        Some(name) => ExportFormat::from_name(name).ok_or_else(|| {
+            ApiError::BadRequest(format!(
+                "Unknown export format '{}', use markdown, json or txt",
+                name
+            ))
+        })?,
+        None => ExportFormat::Markdown,
+    };
+    let conversation_id = query.conversation_id.unwrap_or_else(Conversations::active_id);
+    let attitudes = query.attitudes;
+    let export = web::block(move || ChatExport::load(conversation_id, attitudes))
+        .await
+        .or_internal("Error while exporting conversation")?
+        .or_internal("Error while exporting conversation")?
+        .ok_or_else(|| ApiError::NotFound(format!("Conversation {} not found", conversation_id)))?;
+    Ok(HttpResponse::Ok()
+        .content_type(format.mime())
+        .insert_header((
+            "Content-Disposition",
+            format!("attachment; filename=\"{}\"", export.file_name(format)),
+        ))
+        .body(export.render(format)))
+}
+
+#[post("/api/upload/image")]
+async fn upload_image(mut received: web::Payload) -> Result<HttpResponse, ApiError> {
+    let mut data = web::BytesMut::new();
+    while let Some(chunk) = received.next().await {
+        let d = chunk.map_err(|e| ApiError::BadRequest(format!("Error while receiving image: {}", e)))?;
+        if data.len() + d.len() > message_images::MAX_IMAGE_BYTES {
+            return Err(ApiError::BadRequest(format!(
+                "Image is larger than {} MB",
+                message_images::MAX_IMAGE_BYTES / 1024 / 1024
+            )));
         }
+        data.extend_from_slice(&d);
+    }
+    let format = ImageFormat::sniff(&data).ok_or_else(|| {
+        ApiError::BadRequest("Unsupported image format, expected png, jpeg, gif or webp".to_string())
+    })?;
+    let config_data = Database::get_config().or_internal("Error while getting config")?;
+    // Described right away, so the reply to the message it is sent with doesn't wait for it
+    let image = web::block(move || {
+        let image = MessageImages::store(&data, format)?;
+        vision::describe_uploads(&config_data, &[image.file.clone()]);
+        Ok::<_, std::io::Error>(MessageImages::get(&image.file).ok().flatten().unwrap_or(image))
+    })
+    .await
+    .or_internal("Error while storing image")?
+    .or_internal("Error while storing image")?;
+    Ok(HttpResponse::Ok().json(image))
+}
+
+#[get("/api/uploads/{file}")]
+async fn uploaded_image(file: web::Path<String>) -> Result<HttpResponse, ApiError> {
+    let file = file.into_inner();
+    let name = file.clone();
+    let (image, format) = web::block(move || MessageImages::read(&name))
+        .await
+        .or_internal("Error while reading image")?
+        .or_internal("Error while reading image")?
+        .ok_or_else(|| ApiError::NotFound(format!("Image {} not found", file)))?;
+    // Uploads are named after their content, so they never change
+    Ok(HttpResponse::Ok()
+        .content_type(format.mime())
+        .insert_header(("Cache-Control", "private, max-age=31536000, immutable"))
+        .body(image))
+}
+
+#[derive(Deserialize)]
+struct ImageGenerateRequest {
+    /// Message asking for the picture, the latest one of the active chat when left out
+    message_id: Option<i32>,
+    /// Where the companion is and what it does, taken from the message when left out
+    scene: Option<String>,
+    #[serde(default)]
+    kind: Shot,
+}
+
+#[post("/api/image/generate")]
+async fn image_generate(received: web::Json<ImageGenerateRequest>) -> Result<HttpResponse, ApiError> {
+    let received = received.into_inner();
+    let asked = match received.message_id {
+        Some(id) => match Database::get_message(id) {
+            Ok(asked) => asked,
+            Err(rusqlite::Error::QueryReturnedNoRows) => {
+                return Err(ApiError::NotFound(format!("Message {} not found", id)))
+            }
+            Err(e) => return Err(ApiError::internal(&format!("Error while getting message at id {}", id), e)),
+        },
+        None => Database::get_latest_message().or_internal("Error while getting latest message")?,
+    };
+    // Drawn as the companion that wrote the chat, which need not be the active one
+    let companion_id = Database::message_companion_id(asked.id)
+        .or_internal(&format!("Error while getting message at id {}", asked.id))?
+        .ok_or_else(|| ApiError::NotFound(format!("Message {} not found", asked.id)))?;
+    let config_data = Database::get_config_for(companion_id).or_internal("Error while getting config")?;
+    if !image_gen::is_configured(&config_data) {
+        return Err(ApiError::Unavailable(
+            "Image generation is not configured, set image_gen_api_url in the config".to_string(),
+        ));
     }
+    let companion_data = Database::get_companion_data_by_id(companion_id)
+        .or_internal(&format!("Error while getting companion at id {}", companion_id))?;
+    let scene = received.scene.unwrap_or_else(|| image_gen::scene_of(&asked));
+    let description = image_gen::describe(&companion_data, &scene, received.kind);
+    let kept = description.clone();
+    let image = web::block(move || {
+        let (image, format) = image_gen::generate(&config_data, &kept, received.kind)?;
+        let image = MessageImages::store(&image, format).map_err(|e| e.to_string())?;
+        MessageImages::set_description(&image.file, &kept).map_err(|e| e.to_string())?;
+        MessageImages::append(asked.id, &image.file).map_err(|e| e.to_string())?;
+        Ok::<_, String>(MessageImages::get(&image.file).ok().flatten().unwrap_or(image))
+    })
+    .await
+    .or_internal("Error while generating image")?
+    .map_err(|e| ApiError::internal("Error while generating image", e))?;
+    let generated = serde_json::json!({
+        "message_id": asked.id,
+        "companion_id": companion_id,
+        "prompt": description,
+        "image": image,
+    });
+    event_bus::publish("image_generated", generated.clone());
+    Ok(HttpResponse::Ok().json(generated))
 }
 
 #[delete("/api/message")]
-async fn clear_messages() -> HttpResponse {
-    match Database::erase_messages() {
-        Ok(_) => HttpResponse::Ok().body("Chat log cleared!"),
-        Err(e) => {
-            println!("Failed to clear chat log: {}", e);
-            HttpResponse::InternalServerError()
-                .body("Error while clearing chat log, check logs for more information")
+async fn clear_messages() -> Result<HttpResponse, ApiError> {
+    Database::erase_messages().or_internal("Error while clearing chat log")?;
+    Ok(HttpResponse::Ok().body("Chat log cleared!"))
+}
+
+#[derive(Deserialize)]
+struct MessageSearchQuery {
+    q: String,
+    companion_id: Option<i32>,
+    conversation_id: Option<i32>,
+    speaker: Option<String>,
+    from: Option<String>,
+    to: Option<String>,
+    limit: Option<usize>,
+    offset: Option<usize>,
+    context: Option<usize>,
+}
+
+#[get("/api/message/search")]
+async fn search_messages(query: web::Query<MessageSearchQuery>) -> Result<HttpResponse, ApiError> {
+    let query = query.into_inner();
+    let speaker = match query.speaker.as_deref() {
+        None => None,
+        Some(speaker) => Some(Speaker::parse(speaker).ok_or_else(|| {
+            ApiError::BadRequest("Invalid speaker, expected ai or user".to_string())
+        })?),
+    };
+    let day = |date: Option<String>, name: &str| match date {
+        None => Ok(None),
+        Some(date) => chrono::NaiveDate::parse_from_str(&date, "%Y-%m-%d")
+            .map(Some)
+            .map_err(|_| ApiError::BadRequest(format!("Invalid {} date, expected YYYY-MM-DD", name))),
+    };
+    let filters = SearchFilters {
+        companion_id: query.companion_id.unwrap_or_else(Database::active_companion_id),
+        conversation_id: query.conversation_id,
+        speaker,
+        from: day(query.from, "from")?,
+        to: day(query.to, "to")?,
+        limit: query.limit.unwrap_or(20).clamp(1, message_search::MAX_LIMIT),
+        offset: query.offset.unwrap_or(0),
+        context: query.context.unwrap_or(1).min(message_search::MAX_CONTEXT),
+    };
+    let page = web::block(move || MessageSearch::search(&query.q, &filters))
+        .await
+        .or_internal("Error while searching messages")?
+        .or_internal("Error while searching messages")?
+        .ok_or_else(|| ApiError::BadRequest("Nothing to search for, q has no words".to_string()))?;
+    Ok(HttpResponse::Ok().json(page))
+}
+
+#[derive(Deserialize)]
+struct UsageQuery {
+    // Replies of every companion when omitted
+    companion_id: Option<i32>,
+    days: Option<u32>,
+    weeks: Option<u32>,
+}
+
+#[get("/api/usage")]
+async fn usage(query: web::Query<UsageQuery>) -> Result<HttpResponse, ApiError> {
+    let query = query.into_inner();
+    let config_data = match query.companion_id {
+        Some(id) => {
+            if let Err(rusqlite::Error::QueryReturnedNoRows) = Database::get_companion_data_by_id(id) {
+                return Err(ApiError::NotFound(format!("Companion {} not found", id)));
+            }
+            Database::get_config_for(id)
         }
+        None => Database::get_config(),
     }
+    .or_internal("Error while getting config")?;
+    let days = query.days.unwrap_or(14).clamp(1, message_usage::MAX_DAYS);
+    let weeks = query.weeks.unwrap_or(8).clamp(1, message_usage::MAX_WEEKS);
+    let report = web::block(move || {
+        MessageUsage::report(
+            query.companion_id,
+            chrono::Local::now().date_naive(),
+            days,
+            weeks,
+            config_data.context_window_size,
+            config_data.max_response_tokens,
+        )
+    })
+    .await
+    .or_internal("Error while getting token usage")?
+    .or_internal("Error while getting token usage")?;
+    Ok(HttpResponse::Ok().json(report))
 }
 
 #[get("/api/message/{id}")]
-async fn message_id(id: web::Path<i32>) -> HttpResponse {
+async fn message_id(id: web::Path<i32>) -> Result<HttpResponse, ApiError> {
     let msg: Message = match Database::get_message(*id) {
         Ok(v) => v,
-        Err(e) => {
-            println!("Failed to get message at id {}: {}", id, e);
-            return HttpResponse::InternalServerError().body(format!(
-                "Error while getting message at id {}, check logs for more information",
-                id
-            ));
+        Err(rusqlite::Error::QueryReturnedNoRows) => {
+            return Err(ApiError::NotFound(format!("Message {} not found", id)))
         }
+        Err(e) => return Err(ApiError::internal(&format!("Error while getting message at id {}", id), e)),
     };
     let message_json =
         serde_json::to_string(&msg).unwrap_or(String::from("Error serializing message as JSON"));
-    HttpResponse::Ok().body(message_json)
+    Ok(HttpResponse::Ok().body(message_json))
 }
 
 #[put("/api/message/{id}")]
-async fn message_put(id: web::Path<i32>, received: web::Json<NewMessage>) -> HttpResponse {
-    match Database::edit_message(*id, received.into_inner()) {
-        Ok(_) => HttpResponse::Ok().body(format!("Message edited at id {}!", id)),
-        Err(e) => {
-            println!("Failed to edit message at id {}: {}", id, e);
-            HttpResponse::InternalServerError().body(format!(
-                "Error while editing message at id {}, check logs for more information",
-                id
-            ))
-        }
-    }
+async fn message_put(
+    id: web::Path<i32>,
+    received: web::Json<NewMessage>,
+) -> Result<HttpResponse, ApiError> {
+    let mut received = received.into_inner();
+    received.content = Filters::apply(Stage::of_message(received.ai), &received.content);
+    Database::edit_message(*id, received)
+        .or_internal(&format!("Error while editing message at id {}", id))?;
+    Ok(HttpResponse::Ok().body(format!("Message edited at id {}!", id)))
 }
 
 #[delete("/api/message/{id}")]
-async fn message_delete(id: web::Path<i32>) -> HttpResponse {
-    match Database::delete_message(*id) {
-        Ok(_) => HttpResponse::Ok().body(format!("Message deleted at id {}!", id)),
-        Err(e) => {
-            println!("Failed to delete message at id {}: {}", id, e);
-            HttpResponse::InternalServerError().body(format!(
-                "Error while deleting message at id {}, check logs for more information",
-                id
-            ))
+async fn message_delete(id: web::Path<i32>) -> Result<HttpResponse, ApiError> {
+    Database::delete_message(*id)
+        .or_internal(&format!("Error while deleting message at id {}", id))?;
+    Ok(HttpResponse::Ok().body(format!("Message deleted at id {}!", id)))
+}
+
+#[get("/api/message/{id}/audio")]
+async fn message_audio(id: web::Path<i32>) -> Result<HttpResponse, ApiError> {
+    // curl -o reply.wav http://localhost:3000/api/message/42/audio
+    let id = id.into_inner();
+    let companion_id = Database::message_companion_id(id)
+        .or_internal(&format!("Error while getting message at id {}", id))?
+        .ok_or_else(|| ApiError::NotFound(format!("Message {} not found", id)))?;
+    let spoken = Database::get_message(id).or_internal(&format!("Error while getting message at id {}", id))?;
+    if !spoken.ai {
+        return Err(ApiError::BadRequest("Only the companion's messages can be read out".to_string()));
+    }
+    // The voice of the companion that wrote it, which need not be the active one
+    let config_data = Database::get_config_for(companion_id).or_internal("Error while getting config")?;
+    check_tts(&config_data)?;
+    let audio = web::block(move || MessageAudio::get_or_synthesize(&config_data, id, &spoken.content))
+        .await
+        .or_internal("Error while synthesizing speech")?
+        .map_err(|e| ApiError::internal("Error while synthesizing speech", e))?;
+    match audio {
+        Some((audio, format)) => Ok(HttpResponse::Ok()
+            .content_type(format.mime())
+            .insert_header(("Cache-Control", "private, max-age=3600"))
+            .body(audio)),
+        None => Err(ApiError::BadRequest(format!("Message {} has nothing to read out", id))),
+    }
+}
+
+#[get("/api/message/{id}/attempts")]
+async fn message_attempts_list(id: web::Path<i32>) -> Result<HttpResponse, ApiError> {
+    let attempts =
+        MessageAttempts::get_attempts(*id).or_internal("Error while getting message attempts")?;
+    let attempts_json = serde_json::to_string(&attempts)
+        .unwrap_or(String::from("Error serializing message attempts as JSON"));
+    Ok(HttpResponse::Ok().body(attempts_json))
+}
+
+#[post("/api/message/{id}/attempts/{attempt_id}/promote")]
+async fn message_attempt_promote(path: web::Path<(i32, i64)>) -> Result<HttpResponse, ApiError> {
+    let (id, attempt_id) = path.into_inner();
+    match MessageAttempts::promote(id, attempt_id)
+        .or_internal("Error while promoting message attempt")?
+    {
+        Some(reply_id) => Ok(HttpResponse::Ok().body(format!(
+            "Attempt {} is now the reply at id {}!",
+            attempt_id, reply_id
+        ))),
+        None => Err(ApiError::NotFound(format!(
+            "No attempt {} with a reply to replace for message {}",
+            attempt_id, id
+        ))),
+    }
+}
+
+#[derive(Deserialize)]
+struct AlternativesRequest {
+    count: Option<usize>,
+    #[serde(default)]
+    sampling: SamplingOverrides,
+}
+
+#[post("/api/message/{id}/alternatives")]
+async fn message_alternatives(
+    id: web::Path<i32>,
+    received: Option<web::Json<AlternativesRequest>>,
+) -> Result<HttpResponse, ApiError> {
+    let (count, sampling) = match received {
+        Some(request) => {
+            let request = request.into_inner();
+            (request.count.unwrap_or(3), request.sampling)
         }
+        None => (3, SamplingOverrides::default()),
+    };
+    if !(1..=llm::MAX_ALTERNATIVES).contains(&count) {
+        return Err(ApiError::BadRequest(format!(
+            "count must be between 1 and {}",
+            llm::MAX_ALTERNATIVES
+        )));
+    }
+    check_sampling(&sampling)?;
+    let user_message = match Database::get_message(*id) {
+        Ok(found) => found,
+        Err(rusqlite::Error::QueryReturnedNoRows) => {
+            return Err(ApiError::NotFound(format!("No message at id {}", id)))
+        }
+        Err(e) => return Err(ApiError::internal("Error while getting message", e)),
+    };
+    if user_message.ai {
+        return Err(ApiError::BadRequest(
+            "Alternatives are generated for the user message a reply answers".to_string(),
+        ));
+    }
+    let reply_id = MessageAttempts::reply_id(user_message.id)
+        .or_internal("Error while getting the reply to the message")?
+        .ok_or_else(|| ApiError::NotFound(format!("No reply to message {}", id)))?;
+    let reply = Database::get_message(reply_id).or_internal("Error while getting the reply to the message")?;
+    let attempts = web::block(move || {
+        let _slot = wait_for_turn(
+            &inference_queue::new_request_id("alternatives"),
+            Some(&inference_queue::chat_session()),
+            &mut |_| true,
+        )?;
+        llm::alternatives(&user_message, &reply, count, &sampling)
+            .or_internal("Error while generating alternatives")
+    })
+    .await
+    .or_internal("Error while generating alternatives")??;
+    Ok(HttpResponse::Created().json(attempts))
+}
+
+#[get("/api/message/{id}/feedback")]
+async fn message_feedback_get(id: web::Path<i32>) -> Result<HttpResponse, ApiError> {
+    let feedback = MessageFeedback::get(*id)
+        .or_internal("Error while getting message feedback")?
+        .ok_or_else(|| ApiError::NotFound(format!("No feedback on message {}", id)))?;
+    Ok(HttpResponse::Ok().json(feedback))
+}
+
+#[post("/api/message/{id}/feedback")]
+async fn message_feedback_post(
+    id: web::Path<i32>,
+    received: web::Json<FeedbackModify>,
+) -> Result<HttpResponse, ApiError> {
+    // curl -X POST -H "Content-Type: application/json" -d '{"rating":"up","comment":"Sounds just like her"}' http://localhost:3000/api/message/12/feedback
+    match MessageFeedback::rate(*id, &received.into_inner()) {
+        Ok(feedback) => Ok(HttpResponse::Ok().json(feedback)),
+        Err(FeedbackError::NoSuchMessage) => Err(ApiError::NotFound(format!("Message {} not found", id))),
+        Err(FeedbackError::NotAReply) => Err(ApiError::BadRequest(
+            "Only replies of the companion can be rated".to_string(),
+        )),
+        Err(FeedbackError::Database(e)) => Err(ApiError::internal("Error while saving message feedback", e)),
+    }
+}
+
+#[delete("/api/message/{id}/feedback")]
+async fn message_feedback_delete(id: web::Path<i32>) -> Result<HttpResponse, ApiError> {
+    if !MessageFeedback::delete(*id).or_internal("Error while deleting message feedback")? {
+        return Err(ApiError::NotFound(format!("No feedback on message {}", id)));
     }
+    Ok(HttpResponse::Ok().body("Message feedback deleted!"))
 }
 
 //              Companion
 
+/// Fill in the avatar expression for the companion's mood, leaving none when it can't be read
+fn with_expression(companion_id: i32, mut companion_data: CompanionView) -> CompanionView {
+    companion_data.expression = AvatarExpressions::active(companion_id).unwrap_or_else(|e| {
+        warn!("⚠️ Could not get the companion's avatar expression: {}", e);
+        None
+    });
+    companion_data
+}
+
 #[get("/api/companion")]
-async fn companion() -> HttpResponse {
-    let companion_data: CompanionView = match Database::get_companion_data() {
-        Ok(v) => v,
-        Err(e) => {
-            println!("Failed to get companion data: {}", e);
-            return HttpResponse::InternalServerError()
-                .body("Error while getting companion data, check logs for more information");
-        }
-    };
+async fn companion() -> Result<HttpResponse, ApiError> {
+    let companion_data: CompanionView = with_expression(
+        Database::active_companion_id(),
+        Database::get_companion_data().or_internal("Error while getting companion data")?,
+    );
     let companion_json: String = serde_json::to_string(&companion_data)
         .unwrap_or(String::from("Error serializing companion data as JSON"));
-    HttpResponse::Ok().body(companion_json)
+    Ok(HttpResponse::Ok().body(companion_json))
 }
 
 #[put("/api/companion")]
-async fn companion_edit_data(received: web::Json<CompanionView>) -> HttpResponse {
-    match Database::edit_companion(received.into_inner()) {
-        Ok(_) => HttpResponse::Ok().body("Companion data edited!"),
-        Err(e) => {
-            println!("Failed to edit companion data: {}", e);
-            HttpResponse::InternalServerError()
-                .body("Error while editing companion data, check logs for more information")
-        }
-    }
+async fn companion_edit_data(received: web::Json<CompanionView>) -> Result<HttpResponse, ApiError> {
+    Database::edit_companion(received.into_inner())
+        .or_internal("Error while editing companion data")?;
+    Ok(HttpResponse::Ok().body("Companion data edited!"))
 }
 
 #[post("/api/companion/card")]
-async fn companion_card(mut received: actix_web::web::Payload) -> HttpResponse {
+async fn companion_card(mut received: actix_web::web::Payload) -> Result<HttpResponse, ApiError> {
     // curl -X POST -H "Content-Type: image/png" -T card.png http://localhost:3000/api/companion/card
     let mut data = web::BytesMut::new();
     while let Some(chunk) = received.next().await {
@@ -257,484 +883,2266 @@ async fn companion_card(mut received: actix_web::web::Payload) -> HttpResponse {
     let character_card: CharacterCard = match CharacterCard::load_character_card(&data) {
         Ok(c) => c,
         Err(e) => {
-            eprintln!("Error while loading character card from a file: {}", e);
-            return HttpResponse::InternalServerError()
-                .body("Error while importing character card, check logs for more information");
+            warn!("Error while loading character card from a file: {}", e);
+            return Err(ApiError::BadRequest(format!("Invalid character card: {}", e)));
         }
     };
     let character_name = character_card.name.to_string();
-    let mut avatar_file = match File::create("assets/avatar.png") {
-        Ok(f) => f,
-        Err(e) => {
-            eprintln!(
-                "Error while creating 'avatar.png' file in a 'assets' folder: {}",
-                e
-            );
-            return HttpResponse::InternalServerError()
-                .body("Error while importing character card, check logs for more information");
-        }
-    };
-    match avatar_file.write_all(&data) {
-        Ok(_) => {}
-        Err(e) => {
-            eprintln!(
-                "Error while writing bytes to 'avatar.png' file in a 'assets' folder: {}",
-                e
-            );
-            return HttpResponse::InternalServerError()
-                .body("Error while importing character card, check logs for more information");
-        }
-    };
-    match Database::import_character_card(character_card, "assets/avatar.png") {
-        Ok(_) => {}
-        Err(e) => {
-            eprintln!(
-                "Error while changing companion avatar using character card: {}",
-                e
-            );
-            return HttpResponse::InternalServerError()
-                .body("Error while importing character card, check logs for more information");
-        }
-    };
-    println!(
+    let companion_id = Database::active_companion_id();
+    fs::create_dir_all(&settings::get().assets_dir)
+        .or_internal("Error while creating 'assets' directory")?;
+    let mut avatar = File::create(avatar_file(companion_id))
+        .or_internal("Error while creating avatar file in a 'assets' folder")?;
+    avatar
+        .write_all(&data)
+        .or_internal("Error while writing bytes to avatar file in a 'assets' folder")?;
+    Database::import_character_card(character_card, &avatar_url(companion_id))
+        .or_internal("Error while changing companion avatar using character card")?;
+    info!(
         "Character \"{}\" imported successfully! (from character card)",
         character_name
     );
-    HttpResponse::Ok().body("Updated companion data via character card!")
+    Ok(HttpResponse::Ok().body("Updated companion data via character card!"))
+}
+
+#[get("/api/companion/card")]
+async fn export_companion_card() -> Result<HttpResponse, ApiError> {
+    let companion_data =
+        Database::get_companion_data().or_internal("Error while exporting character card")?;
+    let card =
+        Database::get_companion_card_data().or_internal("Error while exporting character card")?;
+    // The default avatar is a JPEG embedded in the binary, a card needs a PNG to live in
+    let avatar = read_avatar(&companion_data.avatar_path).filter(|image| image.starts_with(b"\x89PNG"));
+    let image = match avatar {
+        Some(image) => image,
+        None => character_card::placeholder_png()
+            .or_internal("Error while creating character card image")?,
+    };
+    let png = card
+        .embed_in_png(&image)
+        .or_internal("Error while embedding character card into image")?;
+    Ok(HttpResponse::Ok()
+        .content_type("image/png")
+        .insert_header((
+            "Content-Disposition",
+            format!("attachment; filename=\"{}.png\"", card.name.replace('"', "")),
+        ))
+        .body(png))
 }
 
 #[post("/api/companion/characterJson")]
-async fn companion_character_json(received: web::Json<CharacterCard>) -> HttpResponse {
-    let character_name = received.name.to_string();
-    match Database::import_character_json(received.into_inner()) {
-        Ok(_) => {
-            println!(
-                "Character \"{}\" imported successfully! (from character JSON)",
-                character_name
-            );
-            HttpResponse::Ok().body("Character json imported successfully!")
-        }
-        Err(e) => {
-            println!("Failed to import character json: {}", e);
-            HttpResponse::InternalServerError()
-                .body("Error while importing character json, check logs for more information")
-        }
-    }
+async fn companion_character_json(received: web::Json<serde_json::Value>) -> Result<HttpResponse, ApiError> {
+    let character_card = CharacterCard::from_json(received.into_inner())
+        .map_err(|e| ApiError::BadRequest(format!("Invalid character JSON: {}", e)))?;
+    let character_name = character_card.name.to_string();
+    Database::import_character_json(character_card)
+        .or_internal("Error while importing character json")?;
+    info!(
+        "Character \"{}\" imported successfully! (from character JSON)",
+        character_name
+    );
+    Ok(HttpResponse::Ok().body("Character json imported successfully!"))
 }
 
 #[get("/api/companion/characterJson")]
-async fn get_companion_character_json() -> HttpResponse {
-    match Database::get_companion_card_data() {
-        Ok(v) => {
-            let character_json: String = serde_json::to_string_pretty(&v as &CharacterCard)
-                .unwrap_or(String::from("Error serializing companion data as JSON"));
-            return HttpResponse::Ok().body(character_json);
-        }
-        Err(e) => {
-            println!("Failed to get companion card data: {}", e);
-            return HttpResponse::InternalServerError()
-                .body("Error while getting companion card data, check logs for more information");
-        }
+async fn get_companion_character_json() -> Result<HttpResponse, ApiError> {
+    let card = Database::get_companion_card_data()
+        .or_internal("Error while getting companion card data")?;
+    let character_json: String = serde_json::to_string_pretty(&card.to_v2_json())
+        .unwrap_or(String::from("Error serializing companion data as JSON"));
+    Ok(HttpResponse::Ok().body(character_json))
+}
+
+#[get("/api/companion/pack")]
+async fn export_persona_pack() -> Result<HttpResponse, ApiError> {
+    let companion_data =
+        Database::get_companion_data().or_internal("Error while exporting persona pack")?;
+    let character =
+        Database::get_companion_card_data().or_internal("Error while exporting persona pack")?;
+    let attitude_preset = match Database::get_attitude(Database::active_companion_id(), Database::active_user_id(), "user")
+        .or_internal("Error while exporting persona pack")?
+    {
+        Some(attitude) => attitude_dimensions::ATTITUDE_DIMENSIONS
+            .iter()
+            .filter_map(|d| {
+                attitude_dimensions::dimension_value(&attitude, d.name)
+                    .map(|v| (d.name.to_string(), v))
+            })
+            .collect(),
+        None => std::collections::HashMap::new(),
+    };
+    // Global entries go in as the companion's own, the pack is meant to stand on its own
+    let lorebook = Lorebook::list(Database::active_companion_id())
+        .or_internal("Error while exporting persona pack")?
+        .into_iter()
+        .map(|entry| LorebookEntryModify {
+            keys: entry.keys,
+            content: entry.content,
+            priority: entry.priority,
+            enabled: entry.enabled,
+            global: false,
+        })
+        .collect();
+    // Only custom avatars live on disk, the default one is embedded in the binary
+    let avatar = read_avatar(&companion_data.avatar_path);
+
+    let pack = PersonaPack {
+        manifest: PackManifest {
+            format_version: persona_pack::PACK_FORMAT_VERSION,
+            name: character.name.clone(),
+            created_at: database::get_current_date(),
+        },
+        greetings: vec![character.first_mes.clone()],
+        character,
+        lorebook,
+        attitude_preset,
+        avatar,
     };
+    let bytes = pack.to_zip().or_internal("Error while exporting persona pack")?;
+    Ok(HttpResponse::Ok()
+        .content_type("application/zip")
+        .insert_header((
+            "Content-Disposition",
+            "attachment; filename=\"persona_pack.zip\"",
+        ))
+        .body(bytes))
 }
 
-#[post("/api/companion/avatar")]
-async fn companion_avatar(mut received: actix_web::web::Payload) -> HttpResponse {
-    // curl -X POST -H "Content-Type: image/png" -T avatar.png http://localhost:3000/api/companion/avatar
+#[post("/api/companion/pack/preview")]
+async fn preview_persona_pack(mut received: actix_web::web::Payload) -> Result<HttpResponse, ApiError> {
+    // curl -X POST -H "Content-Type: application/zip" -T pack.zip http://localhost:3000/api/companion/pack/preview
     let mut data = web::BytesMut::new();
     while let Some(chunk) = received.next().await {
         let d = chunk.unwrap();
         data.extend_from_slice(&d);
     }
-    if fs::metadata("assets").is_err() {
-        match fs::create_dir("assets") {
-            Ok(_) => {}
-            Err(e) => {
-                eprintln!("Error while creating 'assets' directory: {}", e);
-                return HttpResponse::InternalServerError()
-                    .body("Error while importing character card, check logs for more information");
-            }
-        };
-    }
-    let mut avatar_file = match File::create("assets/avatar.png") {
-        Ok(f) => f,
-        Err(e) => {
-            eprintln!(
-                "Error while creating 'avatar.png' file in a 'assets' folder: {}",
-                e
-            );
-            return HttpResponse::InternalServerError()
-                .body("Error while importing character card, check logs for more information");
-        }
-    };
-    match avatar_file.write_all(&data) {
-        Ok(_) => {}
-        Err(e) => {
-            eprintln!(
-                "Error while writing bytes to 'avatar.png' file in a 'assets' folder: {}",
-                e
-            );
-            return HttpResponse::InternalServerError()
-                .body("Error while importing character card, check logs for more information");
-        }
-    };
-    match Database::change_companion_avatar("assets/avatar.png") {
-        Ok(_) => {}
-        Err(e) => {
-            eprintln!("Error while changing companion avatar: {}", e);
-            return HttpResponse::InternalServerError()
-                .body("Error while changing companion avatar, check logs for more information");
-        }
-    };
-    HttpResponse::Ok().body("Companion avatar changed!")
+    let pack = PersonaPack::from_zip(&data)
+        .map_err(|e| ApiError::BadRequest(format!("Invalid persona pack: {}", e)))?;
+    let preview_json = serde_json::to_string(&pack.preview())
+        .unwrap_or(String::from("Error serializing persona pack preview as JSON"));
+    Ok(HttpResponse::Ok().body(preview_json))
 }
 
-//              User
+#[post("/api/companion/pack")]
+async fn import_persona_pack(mut received: actix_web::web::Payload) -> Result<HttpResponse, ApiError> {
+    // curl -X POST -H "Content-Type: application/zip" -T pack.zip http://localhost:3000/api/companion/pack
+    let mut data = web::BytesMut::new();
+    while let Some(chunk) = received.next().await {
+        let d = chunk.unwrap();
+        data.extend_from_slice(&d);
+    }
+    // Everything is parsed and validated before anything is written
+    let mut pack = PersonaPack::from_zip(&data)
+        .map_err(|e| ApiError::BadRequest(format!("Invalid persona pack: {}", e)))?;
+    pack.character.first_mes = pack.first_message().to_string();
+
+    let companion_id = Database::active_companion_id();
+    let traits = PersonalityTraits::get(companion_id).or_internal("Error while getting personality traits")?;
+    let mut user_attitude = Database::initial_user_attitude(companion_id, Database::active_user_id(), &traits);
+    for (dimension, value) in &pack.attitude_preset {
+        attitude_dimensions::set_dimension_value(&mut user_attitude, dimension, *value);
+    }
 
-#[get("/api/user")]
-async fn user() -> HttpResponse {
-    let user_data: UserView = match Database::get_user_data() {
-        Ok(v) => v,
-        Err(e) => {
-            println!("Failed to get user data: {}", e);
-            return HttpResponse::InternalServerError().finish();
+    // Stage the avatar next to its final location so a failed import leaves the old one intact
+    let staged_avatar = avatar_file(companion_id).with_extension("png.importing");
+    if let Some(avatar) = &pack.avatar {
+        fs::create_dir_all(&settings::get().assets_dir)
+            .and_then(|_| fs::write(&staged_avatar, avatar))
+            .or_internal("Error while staging persona pack avatar")?;
+    }
+    let avatar_path = pack.avatar.as_ref().map(|_| avatar_url(companion_id));
+
+    if let Err(e) = Database::import_persona_pack(
+        &pack.character,
+        avatar_path.as_deref(),
+        &user_attitude,
+        &pack.lorebook,
+    ) {
+        let _ = fs::remove_file(&staged_avatar);
+        return Err(ApiError::internal("Error while importing persona pack", e));
+    }
+    if avatar_path.is_some() {
+        if let Err(e) = fs::rename(&staged_avatar, avatar_file(companion_id)) {
+            error!("Error while moving persona pack avatar into place: {}", e);
         }
-    };
-    let user_json: String = serde_json::to_string(&user_data)
-        .unwrap_or(String::from("Error serializing user data as JSON"));
-    HttpResponse::Ok().body(user_json)
+    }
+    info!(
+        "Character \"{}\" imported successfully! (from persona pack)",
+        pack.character.name
+    );
+    Ok(HttpResponse::Ok().body("Persona pack imported successfully!"))
 }
 
-#[put("/api/user")]
-async fn user_put(received: web::Json<UserView>) -> HttpResponse {
-    match Database::edit_user(received.into_inner()) {
-        Ok(_) => HttpResponse::Ok().body("User data edited!"),
-        Err(e) => {
-            println!("Failed to edit user data: {}", e);
-            HttpResponse::InternalServerError()
-                .body("Error while editing user data, check logs for more information")
-        }
+#[post("/api/companion/avatar")]
+async fn companion_avatar(mut received: actix_web::web::Payload) -> Result<HttpResponse, ApiError> {
+    // curl -X POST -H "Content-Type: image/png" -T avatar.png http://localhost:3000/api/companion/avatar
+    let mut data = web::BytesMut::new();
+    while let Some(chunk) = received.next().await {
+        let d = chunk.unwrap();
+        data.extend_from_slice(&d);
     }
+    let companion_id = Database::active_companion_id();
+    fs::create_dir_all(&settings::get().assets_dir)
+        .or_internal("Error while creating 'assets' directory")?;
+    let mut avatar = File::create(avatar_file(companion_id))
+        .or_internal("Error while creating avatar file in a 'assets' folder")?;
+    avatar
+        .write_all(&data)
+        .or_internal("Error while writing bytes to avatar file in a 'assets' folder")?;
+    Database::change_companion_avatar(&avatar_url(companion_id))
+        .or_internal("Error while changing companion avatar")?;
+    Ok(HttpResponse::Ok().body("Companion avatar changed!"))
 }
 
-//              Memory
+fn expression_mood(mood: &str) -> Result<MoodKind, ApiError> {
+    MoodKind::from_name(mood).ok_or_else(|| {
+        ApiError::BadRequest(format!(
+            "Unknown mood {}, expected neutral, cheerful, sulky, anxious or affectionate",
+            mood
+        ))
+    })
+}
 
-#[derive(Deserialize)]
-struct LongTermMemMessage {
-    entry: String,
+#[get("/api/companion/avatar/expressions")]
+async fn avatar_expressions_list() -> Result<HttpResponse, ApiError> {
+    let companion_id = Database::active_companion_id();
+    let expressions = AvatarExpressions::list(companion_id)
+        .or_internal("Error while getting avatar expressions")?;
+    let active = AvatarExpressions::active(companion_id)
+        .or_internal("Error while getting avatar expressions")?;
+    Ok(HttpResponse::Ok().json(serde_json::json!({
+        "expressions": expressions,
+        "active": active,
+    })))
 }
 
-#[post("/api/memory/longTerm")]
-async fn add_memory_long_term_message(received: web::Json<LongTermMemMessage>) -> HttpResponse {
-    let ltm = match LongTermMem::connect() {
-        Ok(v) => v,
-        Err(e) => {
-            println!("Failed to connect to long term memory: {}", e);
-            return HttpResponse::InternalServerError().body(
-                "Error while connecting to long term memory, check logs for more information",
-            );
+#[derive(Deserialize)]
+struct ExpressionQuery {
+    #[serde(default)]
+    min_intensity: f64,
+}
+
+#[put("/api/companion/avatar/expressions/{mood}")]
+async fn avatar_expressions_put(
+    mood: web::Path<String>,
+    query: web::Query<ExpressionQuery>,
+    mut received: web::Payload,
+) -> Result<HttpResponse, ApiError> {
+    // curl -X PUT -H "Content-Type: image/png" -T happy.png http://localhost:3000/api/companion/avatar/expressions/cheerful
+    let mood = expression_mood(&mood)?;
+    if !(0.0..=1.0).contains(&query.min_intensity) {
+        return Err(ApiError::BadRequest(
+            "min_intensity must be between 0 and 1".to_string(),
+        ));
+    }
+    let mut data = web::BytesMut::new();
+    while let Some(chunk) = received.next().await {
+        let d = chunk.map_err(|e| ApiError::BadRequest(format!("Error while receiving image: {}", e)))?;
+        if data.len() + d.len() > message_images::MAX_IMAGE_BYTES {
+            return Err(ApiError::BadRequest(format!(
+                "Image is larger than {} MB",
+                message_images::MAX_IMAGE_BYTES / 1024 / 1024
+            )));
         }
-    };
-    match ltm.add_entry(&received.into_inner().entry) {
-        Ok(_) => HttpResponse::Ok().body("Long term memory entry added!"),
-        Err(e) => {
-            println!("Failed to add long term memory entry: {}", e);
-            HttpResponse::InternalServerError()
-                .body("Error while adding long term memory entry, check logs for more information")
+        data.extend_from_slice(&d);
+    }
+    let format = ImageFormat::sniff(&data).ok_or_else(|| {
+        ApiError::BadRequest("Unsupported image format, expected png, jpeg, gif or webp".to_string())
+    })?;
+    let companion_id = Database::active_companion_id();
+    let min_intensity = query.min_intensity;
+    let expression = web::block(move || {
+        AvatarExpressions::set(companion_id, mood, &data, format, min_intensity)
+    })
+    .await
+    .or_internal("Error while storing avatar expression")?
+    .or_internal("Error while storing avatar expression")?;
+    Ok(HttpResponse::Ok().json(expression))
+}
+
+#[delete("/api/companion/avatar/expressions/{mood}")]
+async fn avatar_expressions_delete(mood: web::Path<String>) -> Result<HttpResponse, ApiError> {
+    let mood = expression_mood(&mood)?;
+    let removed = AvatarExpressions::remove(Database::active_companion_id(), mood)
+        .or_internal("Error while removing avatar expression")?;
+    if !removed {
+        return Err(ApiError::NotFound(format!(
+            "No avatar expression for the {} mood",
+            mood.name()
+        )));
+    }
+    Ok(HttpResponse::Ok().body("Avatar expression removed!"))
+}
+
+#[get("/assets/expressions/{file}")]
+async fn avatar_expression_image(file: web::Path<String>) -> Result<HttpResponse, ApiError> {
+    let file = file.into_inner();
+    let name = file.clone();
+    let (image, format) = web::block(move || AvatarExpressions::read(&name))
+        .await
+        .or_internal("Error while reading avatar expression")?
+        .or_internal("Error while reading avatar expression")?
+        .ok_or_else(|| ApiError::NotFound(format!("Image {} not found", file)))?;
+    // A replaced picture gets a new name, so they never change
+    Ok(HttpResponse::Ok()
+        .content_type(format.mime())
+        .insert_header(("Cache-Control", "private, max-age=31536000, immutable"))
+        .body(image))
+}
+
+#[get("/api/companions")]
+async fn companions_list() -> Result<HttpResponse, ApiError> {
+    let companions = Database::list_companions().or_internal("Error while listing companions")?;
+    let companions_json = serde_json::to_string(&companions)
+        .unwrap_or(String::from("Error serializing companions as JSON"));
+    Ok(HttpResponse::Ok().body(companions_json))
+}
+
+#[post("/api/companions")]
+async fn companions_create(received: web::Json<CompanionView>) -> Result<HttpResponse, ApiError> {
+    let id = Database::create_companion(received.into_inner())
+        .or_internal("Error while creating companion")?;
+    Ok(HttpResponse::Created().json(serde_json::json!({ "id": id })))
+}
+
+#[post("/api/companions/{id}/activate")]
+async fn companions_activate(id: web::Path<i32>) -> Result<HttpResponse, ApiError> {
+    let id = id.into_inner();
+    if !Database::set_active_companion(id).or_internal("Error while switching companion")? {
+        return Err(ApiError::NotFound(format!("Companion {} not found", id)));
+    }
+    event_bus::publish("companion_switched", serde_json::json!({ "companion_id": id }));
+    Ok(HttpResponse::Ok().body(format!("Switched to companion {}", id)))
+}
+
+#[delete("/api/companions/{id}")]
+async fn companions_delete(id: web::Path<i32>) -> Result<HttpResponse, ApiError> {
+    let id = id.into_inner();
+    if !Database::delete_companion(id).or_internal("Error while deleting companion")? {
+        return Err(ApiError::Conflict(
+            "Companion not found or it is the last one, at least one companion must remain"
+                .to_string(),
+        ));
+    }
+    let directory = LongTermMem::directory(id);
+    if fs::metadata(&directory).is_ok() {
+        if let Err(e) = fs::remove_dir_all(&directory) {
+            error!("Failed to remove long-term memory of companion {}: {}", id, e);
         }
     }
+    let _ = fs::remove_file(avatar_file(id));
+    Ok(HttpResponse::Ok().body(format!("Companion {} deleted", id)))
 }
 
-#[delete("/api/memory/longTerm")]
-async fn erase_long_term() -> HttpResponse {
-    let ltm = match LongTermMem::connect() {
-        Ok(v) => v,
-        Err(e) => {
-            println!("Failed to connect to long term memory: {}", e);
-            return HttpResponse::InternalServerError().body(
-                "Error while connecting to long term memory, check logs for more information",
-            );
+#[get("/api/companions/{id}")]
+async fn companions_get(id: web::Path<i32>) -> Result<HttpResponse, ApiError> {
+    let companion_data = match Database::get_companion_data_by_id(*id) {
+        Ok(companion_data) => with_expression(*id, companion_data),
+        Err(rusqlite::Error::QueryReturnedNoRows) => {
+            return Err(ApiError::NotFound(format!("Companion {} not found", id)))
         }
+        Err(e) => return Err(ApiError::internal("Error while getting companion data", e)),
     };
-    match ltm.erase_memory() {
-        Ok(_) => HttpResponse::Ok().body("Long term memory cleared!"),
-        Err(e) => {
-            println!("Failed to clear long term memory: {}", e);
-            HttpResponse::InternalServerError()
-                .body("Error while clearing long term memory, check logs for more information")
+    let companion_json = serde_json::to_string(&companion_data)
+        .unwrap_or(String::from("Error serializing companion data as JSON"));
+    Ok(HttpResponse::Ok().body(companion_json))
+}
+
+#[put("/api/companions/{id}")]
+async fn companions_put(
+    id: web::Path<i32>,
+    received: web::Json<CompanionView>,
+) -> Result<HttpResponse, ApiError> {
+    if !Database::edit_companion_by_id(*id, received.into_inner())
+        .or_internal("Error while editing companion data")?
+    {
+        return Err(ApiError::NotFound(format!("Companion {} not found", id)));
+    }
+    Ok(HttpResponse::Ok().body("Companion data edited!"))
+}
+
+#[get("/api/companions/{id}/config")]
+async fn companions_config(id: web::Path<i32>) -> Result<HttpResponse, ApiError> {
+    let overrides =
+        Database::get_companion_config(*id).or_internal("Error while getting companion config")?;
+    Ok(HttpResponse::Ok().json(serde_json::json!({
+        "overrides": overrides,
+        "keys": database::COMPANION_CONFIG_KEYS,
+    })))
+}
+
+#[put("/api/companions/{id}/config")]
+async fn companions_config_put(
+    id: web::Path<i32>,
+    received: web::Json<serde_json::Map<String, serde_json::Value>>,
+) -> Result<HttpResponse, ApiError> {
+    match Database::get_companion_data_by_id(*id) {
+        Ok(_) => {}
+        Err(rusqlite::Error::QueryReturnedNoRows) => {
+            return Err(ApiError::NotFound(format!("Companion {} not found", id)))
         }
+        Err(e) => return Err(ApiError::internal("Error while getting companion data", e)),
+    }
+    match Database::set_companion_config(*id, received.into_inner()) {
+        Ok(_) => Ok(HttpResponse::Ok().body("Companion config updated!")),
+        Err(rusqlite::Error::InvalidParameterName(e)) => Err(ApiError::BadRequest(e)),
+        Err(e) => Err(ApiError::internal("Error while updating companion config", e)),
     }
 }
 
-#[post("/api/memory/dialogueTuning")]
-async fn add_tuning_message() -> HttpResponse {
-    let messages = match Database::get_x_messages(2, 0) {
-        Ok(v) => v,
-        Err(e) => {
-            println!("Failed to get last 2 messages from database: {}", e);
-            return HttpResponse::InternalServerError().body("Error while getting last 2 messages from database, check logs for more information");
+fn ensure_companion(id: i32) -> Result<(), ApiError> {
+    match Database::get_companion_data_by_id(id) {
+        Ok(_) => Ok(()),
+        Err(rusqlite::Error::QueryReturnedNoRows) => {
+            Err(ApiError::NotFound(format!("Companion {} not found", id)))
         }
+        Err(e) => Err(ApiError::internal("Error while getting companion data", e)),
+    }
+}
+
+#[get("/api/companions/{id}/traits")]
+async fn companions_traits(id: web::Path<i32>) -> Result<HttpResponse, ApiError> {
+    ensure_companion(*id)?;
+    let traits =
+        PersonalityTraits::get(*id).or_internal("Error while getting personality traits")?;
+    let available: Vec<serde_json::Value> = PERSONALITY_TRAITS
+        .iter()
+        .map(|definition| {
+            serde_json::json!({
+                "name": definition.name,
+                "description": definition.description,
+                "shifts": definition.shifts.iter().cloned().collect::<std::collections::BTreeMap<_, _>>(),
+                "instruction": definition.instruction,
+            })
+        })
+        .collect();
+    Ok(HttpResponse::Ok().json(serde_json::json!({ "traits": traits, "available": available })))
+}
+
+#[put("/api/companions/{id}/traits")]
+async fn companions_traits_put(
+    id: web::Path<i32>,
+    received: web::Json<Vec<PersonalityTrait>>,
+) -> Result<HttpResponse, ApiError> {
+    // curl -X PUT -H "Content-Type: application/json" -d '[{"name":"shy","intensity":70}]' http://localhost:3000/api/companions/1/traits
+    ensure_companion(*id)?;
+    match PersonalityTraits::set(*id, &received) {
+        Ok(traits) => Ok(HttpResponse::Ok().json(serde_json::json!({ "traits": traits }))),
+        Err(rusqlite::Error::InvalidParameterName(e)) => Err(ApiError::BadRequest(e)),
+        Err(e) => Err(ApiError::internal("Error while setting personality traits", e)),
+    }
+}
+
+#[derive(Deserialize)]
+struct TraitIntensity {
+    intensity: i32,
+}
+
+#[put("/api/companions/{id}/traits/{name}")]
+async fn companions_trait_put(
+    path: web::Path<(i32, String)>,
+    received: web::Json<TraitIntensity>,
+) -> Result<HttpResponse, ApiError> {
+    let (id, name) = path.into_inner();
+    ensure_companion(id)?;
+    let personality_trait = PersonalityTrait {
+        name,
+        intensity: received.intensity,
     };
-    match DialogueTuning::insert(&messages[0].content, &messages[1].content) {
-        Ok(_) => HttpResponse::Ok().body("Saved previous dialogue as template dialogue"),
-        Err(e) => {
-            println!(
-                "Failed to save previous dialogue as template dialogue: {}",
-                e
-            );
-            HttpResponse::InternalServerError().body("Error while saving previous dialogue as template dialogue, check logs for more information")
-        }
+    match PersonalityTraits::set_one(id, &personality_trait) {
+        Ok(traits) => Ok(HttpResponse::Ok().json(serde_json::json!({ "traits": traits }))),
+        Err(rusqlite::Error::InvalidParameterName(e)) => Err(ApiError::BadRequest(e)),
+        Err(e) => Err(ApiError::internal("Error while setting personality trait", e)),
     }
 }
 
-#[delete("/api/memory/dialogueTuning")]
-async fn erase_tuning_message() -> HttpResponse {
-    match DialogueTuning::clear_dialogues() {
-        Ok(_) => HttpResponse::Ok().body("Dialogue tuning memory cleared!"),
-        Err(e) => {
-            println!("Failed to clear dialogue tuning: {}", e);
-            HttpResponse::InternalServerError()
-                .body("Error while clearing dialogue tuning, check logs for more information")
-        }
+#[delete("/api/companions/{id}/traits/{name}")]
+async fn companions_trait_delete(path: web::Path<(i32, String)>) -> Result<HttpResponse, ApiError> {
+    let (id, name) = path.into_inner();
+    ensure_companion(id)?;
+    if !PersonalityTraits::remove(id, &name).or_internal("Error while removing personality trait")? {
+        return Err(ApiError::NotFound(format!("Companion {} has no trait '{}'", id, name)));
     }
+    let traits = PersonalityTraits::get(id).or_internal("Error while getting personality traits")?;
+    Ok(HttpResponse::Ok().json(serde_json::json!({ "traits": traits })))
 }
 
-//              Prompting
+//              Template variables
 
 #[derive(Deserialize)]
-struct Prompt {
-    prompt: String,
+struct VariablesQuery {
+    // Every stored variable when omitted
+    companion_id: Option<i32>,
+}
+
+#[get("/api/variables")]
+async fn variables_list(query: web::Query<VariablesQuery>) -> Result<HttpResponse, ApiError> {
+    let variables = match query.companion_id {
+        Some(id) => {
+            ensure_companion(id)?;
+            TemplateVariables::for_companion(id)
+        }
+        None => TemplateVariables::list(),
+    }
+    .or_internal("Error while getting template variables")?;
+    let companion_id = query.companion_id.unwrap_or_else(Database::active_companion_id);
+    let current = web::block(move || Variables::load(companion_id, Database::active_user_id()))
+        .await
+        .or_internal("Error while getting template variables")?;
+    let built_in: serde_json::Map<String, serde_json::Value> = template_variables::BUILT_IN
+        .iter()
+        .filter_map(|name| Some((name.to_string(), current.get(name)?.into())))
+        .collect();
+    Ok(HttpResponse::Ok().json(serde_json::json!({ "variables": variables, "built_in": built_in })))
 }
 
 #[derive(Deserialize)]
-struct StreamingRequest {
-    prompt: String,
-    session_id: String,
+struct VariableValue {
+    value: String,
+    // Sets the variable for every companion when omitted
+    companion_id: Option<i32>,
 }
 
-#[post("/api/prompt")]
-async fn prompt_message(received: web::Json<Prompt>) -> HttpResponse {
-    let prompt_message = received.into_inner().prompt.clone();
-    let start_time = std::time::Instant::now();
+#[put("/api/variables/{name}")]
+async fn variables_put(
+    name: web::Path<String>,
+    received: web::Json<VariableValue>,
+) -> Result<HttpResponse, ApiError> {
+    // curl -X PUT -H "Content-Type: application/json" -d '{"value":"Lisbon"}' http://localhost:3000/api/variables/home_town
+    let received = received.into_inner();
+    if let Some(id) = received.companion_id {
+        ensure_companion(id)?;
+    }
+    let variable = TemplateVariable {
+        name: name.into_inner(),
+        value: received.value,
+        companion_id: received.companion_id,
+    };
+    match TemplateVariables::set(&variable) {
+        Ok(()) => Ok(HttpResponse::Ok().json(variable)),
+        Err(rusqlite::Error::InvalidParameterName(e)) => Err(ApiError::BadRequest(e)),
+        Err(e) => Err(ApiError::internal("Error while setting template variable", e)),
+    }
+}
 
-    // Track third-party mentions and display console output
-    match Database::track_third_party_mentions(&prompt_message) {
-        Ok(mention_output) => {
-            if !mention_output.is_empty() {
-                println!("{}", mention_output);
-            }
-        },
-        Err(e) => eprintln!("Failed to track third-party mentions: {}", e),
+#[delete("/api/variables/{name}")]
+async fn variables_delete(
+    name: web::Path<String>,
+    query: web::Query<VariablesQuery>,
+) -> Result<HttpResponse, ApiError> {
+    if !TemplateVariables::remove(query.companion_id, &name)
+        .or_internal("Error while removing template variable")?
+    {
+        return Err(ApiError::NotFound(match query.companion_id {
+            Some(id) => format!("Companion {} has no variable '{}'", id, name),
+            None => format!("There is no variable '{}' for all companions", name),
+        }));
     }
+    Ok(HttpResponse::Ok().body("Variable removed!"))
+}
 
-    // Automatically detect new persons in the message
-    let companion_id = 1; // Default companion ID
-    if let Err(e) = Database::detect_new_persons_in_message(&prompt_message, companion_id) {
-        eprintln!("Failed to detect persons in message: {}", e);
-        // Continue processing even if person detection fails
+//              Conversations
+
+#[derive(Deserialize)]
+struct ConversationsQuery {
+    companion_id: Option<i32>,
+    include_archived: Option<bool>,
+}
+
+#[derive(Deserialize)]
+struct ConversationBody {
+    title: Option<String>,
+}
+
+#[get("/api/conversations")]
+async fn conversations_list(
+    query: web::Query<ConversationsQuery>,
+) -> Result<HttpResponse, ApiError> {
+    let companion_id = query.companion_id.unwrap_or_else(Database::active_companion_id);
+    let conversations = Conversations::list(companion_id, query.include_archived.unwrap_or(false))
+        .or_internal("Error while listing conversations")?;
+    let conversations_json = serde_json::to_string(&conversations)
+        .unwrap_or(String::from("Error serializing conversations as JSON"));
+    Ok(HttpResponse::Ok().body(conversations_json))
+}
+
+#[post("/api/conversations")]
+async fn conversations_create(
+    received: Option<web::Json<ConversationBody>>,
+) -> Result<HttpResponse, ApiError> {
+    let title = received.and_then(|body| body.into_inner().title);
+    let id = Conversations::start(title.as_deref()).or_internal("Error while starting conversation")?;
+    event_bus::publish("conversation_switched", serde_json::json!({ "conversation_id": id }));
+    Ok(HttpResponse::Created().json(serde_json::json!({ "id": id })))
+}
+
+#[put("/api/conversations/{id}")]
+async fn conversations_put(
+    id: web::Path<i32>,
+    received: web::Json<ConversationBody>,
+) -> Result<HttpResponse, ApiError> {
+    let title = received.into_inner().title.unwrap_or_default();
+    if title.trim().is_empty() {
+        return Err(ApiError::BadRequest("Title must not be empty".to_string()));
+    }
+    if !Conversations::rename(*id, title.trim()).or_internal("Error while renaming conversation")? {
+        return Err(ApiError::NotFound(format!("Conversation {} not found", id)));
     }
+    Ok(HttpResponse::Ok().body("Conversation renamed!"))
+}
 
-    // Get current attitude for comparison (before processing)
-    let user_id = 1; // Default user ID
-    let previous_attitude = match Database::get_all_companion_attitudes(companion_id) {
-        Ok(attitudes) => {
-            // Find the user attitude
-            attitudes.into_iter().find(|a| a.target_id == user_id && a.target_type == "user")
-        },
-        _ => None,
-    };
+#[post("/api/conversations/{id}/activate")]
+async fn conversations_activate(id: web::Path<i32>) -> Result<HttpResponse, ApiError> {
+    let id = id.into_inner();
+    if !Conversations::activate(id).or_internal("Error while switching conversation")? {
+        return Err(ApiError::NotFound(format!("Conversation {} not found", id)));
+    }
+    event_bus::publish("conversation_switched", serde_json::json!({ "conversation_id": id }));
+    Ok(HttpResponse::Ok().body(format!("Switched to conversation {}", id)))
+}
 
-    // Estimate response time based on message complexity
-    let estimate = estimate_response_time_enhanced(&prompt_message);
-    println!(
-        "⏱️ Response ETA: {}s (range: {}-{}s, confidence: {:.1}%)",
-        estimate.expected_seconds,
-        estimate.min_seconds,
-        estimate.max_seconds,
-        estimate.confidence * 100.0
-    );
-    if !estimate.factors.is_empty() {
-        println!("   Factors: {}", estimate.factors.join(", "));
+#[post("/api/conversations/{id}/archive")]
+async fn conversations_archive(id: web::Path<i32>) -> Result<HttpResponse, ApiError> {
+    let id = id.into_inner();
+    if !Conversations::set_archived(id, true).or_internal("Error while archiving conversation")? {
+        return Err(ApiError::NotFound(format!("Conversation {} not found", id)));
     }
+    Ok(HttpResponse::Ok().body(format!("Conversation {} archived", id)))
+}
 
-    // Detect and handle interaction requests
-    if let Ok(Some(interaction)) =
-        Database::detect_interaction_request(&prompt_message, companion_id)
-    {
-        // Store interaction context for LLM to use
-        if interaction.outcome.is_some() {
-            // If interaction has outcome, include it in the context
-            let enhanced_prompt = format!(
-                "{}\n[Context: Interaction with {} - {}]",
-                prompt_message,
-                Database::get_third_party_by_id(interaction.third_party_id)
-                    .ok()
-                    .flatten()
-                    .map(|p| p.name)
-                    .unwrap_or_else(|| "unknown".to_string()),
-                interaction.outcome.as_ref().unwrap_or(&"".to_string())
-            );
+#[post("/api/conversations/{id}/unarchive")]
+async fn conversations_unarchive(id: web::Path<i32>) -> Result<HttpResponse, ApiError> {
+    let id = id.into_inner();
+    if !Conversations::set_archived(id, false).or_internal("Error while restoring conversation")? {
+        return Err(ApiError::NotFound(format!("Conversation {} not found", id)));
+    }
+    Ok(HttpResponse::Ok().body(format!("Conversation {} restored", id)))
+}
 
-            match Database::insert_message(NewMessage {
-                ai: false,
-                content: prompt_message.to_string(),
-            }) {
-                Ok(_) => {}
-                Err(e) => {
-                    eprintln!("Failed to add message to database: {}", e);
-                    return HttpResponse::InternalServerError().body(
-                        "Error while adding message to database, check logs for more information",
-                    );
-                }
-            };
+#[get("/api/conversations/{id}/overrides")]
+async fn conversations_overrides(id: web::Path<i32>) -> Result<HttpResponse, ApiError> {
+    let id = id.into_inner();
+    match Conversations::overrides(id).or_internal("Error while getting conversation overrides")? {
+        Some(overrides) => Ok(HttpResponse::Ok().json(overrides)),
+        None => Err(ApiError::NotFound(format!("Conversation {} not found", id))),
+    }
+}
 
-            // Generate response with interaction context
-            match prompt(&enhanced_prompt) {
-                Ok(v) => return HttpResponse::Ok().body(v),
-                Err(e) => {
-                    println!("Failed to generate prompt with interaction context: {}", e);
-                }
-            }
+#[put("/api/conversations/{id}/overrides")]
+async fn conversations_overrides_put(
+    id: web::Path<i32>,
+    received: web::Json<ConversationOverrides>,
+) -> Result<HttpResponse, ApiError> {
+    let id = id.into_inner();
+    let mut overrides = received.into_inner();
+    overrides.model_path = overrides
+        .model_path
+        .map(|path| path.trim().to_string())
+        .filter(|path| !path.is_empty());
+    if let Some(path) = &overrides.model_path {
+        if !std::path::Path::new(path).is_file() {
+            return Err(ApiError::BadRequest(format!("Model file {} not found", path)));
         }
     }
+    check_sampling(&overrides.sampling)?;
+    if !Conversations::set_overrides(id, &overrides).or_internal("Error while setting conversation overrides")? {
+        return Err(ApiError::NotFound(format!("Conversation {} not found", id)));
+    }
+    Ok(HttpResponse::Ok().json(overrides))
+}
 
-    match Database::insert_message(NewMessage {
-        ai: false,
-        content: prompt_message.to_string(),
-    }) {
-        Ok(_) => {}
-        Err(e) => {
-            eprintln!("Failed to add message to database: {}", e);
-            return HttpResponse::InternalServerError()
-                .body("Error while adding message to database, check logs for more information");
-        }
+//              Scene
+
+#[derive(Deserialize)]
+struct SceneQuery {
+    conversation_id: Option<i32>,
+}
+
+/// Conversation and companion a scene request is about, the active conversation by default
+fn scene_conversation(conversation_id: Option<i32>) -> Result<(i32, i32), ApiError> {
+    let conversation_id = match conversation_id {
+        Some(id) => id,
+        None => return Ok((Conversations::active_id(), Database::active_companion_id())),
     };
-    match prompt(&prompt_message) {
-        Ok(v) => {
-            // Check for attitude changes after processing
-            if let Some(prev_attitude) = previous_attitude {
-                if let Ok(attitudes) = Database::get_all_companion_attitudes(companion_id) {
-                    if let Some(current_attitude) = attitudes.into_iter().find(|a| a.target_id == user_id && a.target_type == "user") {
-                        let formatter = crate::attitude_formatter::AttitudeFormatter::new();
-                        let attitude_changes = formatter.format_attitude_changes_for_console(&prev_attitude, &current_attitude);
-                        if !attitude_changes.is_empty() {
-                            println!("{}", attitude_changes);
-                        }
-                    }
-                }
-            }
+    match Conversations::companion_of(conversation_id).or_internal("Error while getting conversation")? {
+        Some(companion_id) => Ok((conversation_id, companion_id)),
+        None => Err(ApiError::NotFound(format!("Conversation {} not found", conversation_id))),
+    }
+}
 
-            // Display actual response time
-            let elapsed = start_time.elapsed();
-            println!("✓ Response completed in {:.1}s", elapsed.as_secs_f32());
+#[get("/api/scene")]
+async fn scene_get(query: web::Query<SceneQuery>) -> Result<HttpResponse, ApiError> {
+    let (conversation_id, companion_id) = scene_conversation(query.conversation_id)?;
+    let scene = Scene::get(conversation_id, companion_id).or_internal("Error while getting scene")?;
+    Ok(HttpResponse::Ok().json(scene))
+}
 
-            HttpResponse::Ok().body(v)
-        },
-        Err(e) => {
-            println!("Failed to generate prompt: {}", e);
-            HttpResponse::InternalServerError()
-                .body("Error while generating prompt, check logs for more information")
-        }
+#[put("/api/scene")]
+async fn scene_put(
+    query: web::Query<SceneQuery>,
+    received: web::Json<SceneModify>,
+) -> Result<HttpResponse, ApiError> {
+    // curl -X PUT -H "Content-Type: application/json" -d '{"location":"the beach","time_of_day":"evening","present":["Anna"]}' http://localhost:3000/api/scene
+    let (conversation_id, companion_id) = scene_conversation(query.conversation_id)?;
+    match Scene::set(conversation_id, companion_id, received.into_inner()) {
+        Ok(scene) => Ok(HttpResponse::Ok().json(scene)),
+        Err(rusqlite::Error::InvalidParameterName(e)) => Err(ApiError::BadRequest(e)),
+        Err(e) => Err(ApiError::internal("Error while setting scene", e)),
     }
 }
 
-#[get("/api/prompt/regenerate")]
-async fn regenerate_prompt() -> HttpResponse {
-    match Database::delete_latest_message() {
-        Ok(_) => {}
-        Err(e) => {
-            println!("Failed to delete latest message: {}", e);
-            return HttpResponse::InternalServerError()
-                .body("Error while deleting latest message, check logs for more information");
+#[delete("/api/scene")]
+async fn scene_delete(query: web::Query<SceneQuery>) -> Result<HttpResponse, ApiError> {
+    let (conversation_id, companion_id) = scene_conversation(query.conversation_id)?;
+    Scene::clear(conversation_id, companion_id).or_internal("Error while clearing scene")?;
+    Ok(HttpResponse::Ok().body("Scene cleared!"))
+}
+
+//              User
+
+#[get("/api/user")]
+async fn user() -> Result<HttpResponse, ApiError> {
+    let user_data: UserView = Database::get_user_data().or_internal("Error while getting user data")?;
+    let user_json: String = serde_json::to_string(&user_data)
+        .unwrap_or(String::from("Error serializing user data as JSON"));
+    Ok(HttpResponse::Ok().body(user_json))
+}
+
+#[put("/api/user")]
+async fn user_put(received: web::Json<UserView>) -> Result<HttpResponse, ApiError> {
+    Database::edit_user(received.into_inner()).or_internal("Error while editing user data")?;
+    Ok(HttpResponse::Ok().body("User data edited!"))
+}
+
+#[get("/api/users")]
+async fn users_list() -> Result<HttpResponse, ApiError> {
+    let users = Database::list_users().or_internal("Error while listing users")?;
+    let users_json =
+        serde_json::to_string(&users).unwrap_or(String::from("Error serializing users as JSON"));
+    Ok(HttpResponse::Ok().body(users_json))
+}
+
+#[post("/api/users")]
+async fn users_create(received: web::Json<UserView>) -> Result<HttpResponse, ApiError> {
+    let id = Database::create_user(received.into_inner()).or_internal("Error while creating user")?;
+    Ok(HttpResponse::Created().json(serde_json::json!({ "id": id })))
+}
+
+#[post("/api/users/{id}/activate")]
+async fn users_activate(id: web::Path<i32>) -> Result<HttpResponse, ApiError> {
+    let id = id.into_inner();
+    select_user(Some(id))?;
+    Ok(HttpResponse::Ok().body(format!("Switched to user {}", id)))
+}
+
+#[get("/api/users/{id}")]
+async fn users_get(id: web::Path<i32>) -> Result<HttpResponse, ApiError> {
+    let user_data = match Database::get_user_by_id(*id) {
+        Ok(user_data) => user_data,
+        Err(rusqlite::Error::QueryReturnedNoRows) => {
+            return Err(ApiError::NotFound(format!("User {} not found", id)))
         }
+        Err(e) => return Err(ApiError::internal("Error while getting user data", e)),
+    };
+    let user_json = serde_json::to_string(&user_data)
+        .unwrap_or(String::from("Error serializing user data as JSON"));
+    Ok(HttpResponse::Ok().body(user_json))
+}
+
+#[put("/api/users/{id}")]
+async fn users_put(
+    id: web::Path<i32>,
+    received: web::Json<UserView>,
+) -> Result<HttpResponse, ApiError> {
+    if !Database::edit_user_by_id(*id, received.into_inner())
+        .or_internal("Error while editing user data")?
+    {
+        return Err(ApiError::NotFound(format!("User {} not found", id)));
     }
-    let prompt_msg: String = match Database::get_latest_message() {
-        Ok(v) => v.content,
-        Err(e) => {
-            println!("Failed to get latest message: {}", e);
-            return HttpResponse::InternalServerError()
-                .body("Error while getting latest message, check logs for more information");
-        }
+    Ok(HttpResponse::Ok().body("User data edited!"))
+}
+
+#[delete("/api/users/{id}")]
+async fn users_delete(id: web::Path<i32>) -> Result<HttpResponse, ApiError> {
+    let id = id.into_inner();
+    if !Database::delete_user(id).or_internal("Error while deleting user")? {
+        return Err(ApiError::Conflict(
+            "User not found or it is the last one, at least one user must remain".to_string(),
+        ));
+    }
+    Ok(HttpResponse::Ok().body(format!("User {} deleted", id)))
+}
+
+//              Memory
+
+#[derive(Deserialize)]
+struct LongTermMemMessage {
+    entry: String,
+}
+
+#[post("/api/memory/longTerm")]
+async fn add_memory_long_term_message(
+    received: web::Json<LongTermMemMessage>,
+) -> Result<HttpResponse, ApiError> {
+    let ltm = LongTermMem::connect().or_internal("Error while connecting to long term memory")?;
+    ltm.add_entry(&received.into_inner().entry)
+        .or_internal("Error while adding long term memory entry")?;
+    Ok(HttpResponse::Ok().body("Long term memory entry added!"))
+}
+
+#[delete("/api/memory/longTerm")]
+async fn erase_long_term() -> Result<HttpResponse, ApiError> {
+    let ltm = LongTermMem::connect().or_internal("Error while connecting to long term memory")?;
+    ltm.erase_memory().or_internal("Error while clearing long term memory")?;
+    Ok(HttpResponse::Ok().body("Long term memory cleared!"))
+}
+
+#[post("/api/memory/dialogueTuning")]
+async fn add_tuning_message() -> Result<HttpResponse, ApiError> {
+    let messages = Database::get_x_messages(2, 0)
+        .or_internal("Error while getting last 2 messages from database")?;
+    if messages.len() < 2 {
+        return Err(ApiError::BadRequest(
+            "At least 2 messages are needed to save a template dialogue".to_string(),
+        ));
+    }
+    DialogueTuning::insert(&messages[0].content, &messages[1].content)
+        .or_internal("Error while saving previous dialogue as template dialogue")?;
+    Ok(HttpResponse::Ok().body("Saved previous dialogue as template dialogue"))
+}
+
+#[delete("/api/memory/dialogueTuning")]
+async fn erase_tuning_message() -> Result<HttpResponse, ApiError> {
+    DialogueTuning::clear_dialogues().or_internal("Error while clearing dialogue tuning")?;
+    Ok(HttpResponse::Ok().body("Dialogue tuning memory cleared!"))
+}
+
+#[get("/api/memory/proposals")]
+async fn get_memory_proposals() -> Result<HttpResponse, ApiError> {
+    let proposals = MemoryProposals::get_pending(Database::active_companion_id())
+        .or_internal("Error while getting memory proposals")?;
+    let proposals_json = serde_json::to_string(&proposals)
+        .unwrap_or(String::from("Error serializing memory proposals as JSON"));
+    Ok(HttpResponse::Ok().body(proposals_json))
+}
+
+#[derive(Deserialize)]
+struct ResolveProposalsRequest {
+    // Resolves every pending proposal when omitted
+    ids: Option<Vec<i64>>,
+    approve: bool,
+}
+
+#[post("/api/memory/proposals/resolve")]
+async fn resolve_memory_proposals(
+    received: web::Json<ResolveProposalsRequest>,
+) -> Result<HttpResponse, ApiError> {
+    let request = received.into_inner();
+    let ids = match request.ids {
+        Some(ids) => ids,
+        None => MemoryProposals::get_pending(Database::active_companion_id())
+            .or_internal("Error while getting memory proposals")?
+            .iter()
+            .map(|p| p.id)
+            .collect(),
     };
-    match prompt(&prompt_msg) {
-        Ok(v) => HttpResponse::Ok().body(v),
-        Err(e) => {
-            println!("Failed to re-generate prompt: {}", e);
-            HttpResponse::InternalServerError()
-                .body("Error while generating prompt, check logs for more information")
+    let resolution = MemoryProposals::resolve(&ids, request.approve)
+        .or_internal("Error while resolving memory proposals")?;
+    let resolution_json = serde_json::to_string(&resolution)
+        .unwrap_or(String::from("Error serializing proposal resolution as JSON"));
+    Ok(HttpResponse::Ok().body(resolution_json))
+}
+
+#[derive(Deserialize)]
+struct ConsolidateQuery {
+    // Consolidates the memories of every companion when omitted
+    companion_id: Option<i32>,
+    #[serde(default)]
+    dry_run: bool,
+}
+
+#[post("/api/memory/consolidate")]
+async fn consolidate_memories(
+    query: web::Query<ConsolidateQuery>,
+) -> Result<HttpResponse, ApiError> {
+    let query = query.into_inner();
+    if let Some(id) = query.companion_id {
+        if let Err(rusqlite::Error::QueryReturnedNoRows) = Database::get_companion_data_by_id(id) {
+            return Err(ApiError::NotFound(format!("Companion {} not found", id)));
         }
     }
+    let report = web::block(move || MemoryConsolidation::run(query.companion_id, query.dry_run))
+        .await
+        .or_internal("Error while consolidating memories")?
+        .or_internal("Error while consolidating memories")?;
+    Ok(HttpResponse::Ok().json(report))
+}
+
+//              Lorebook
+
+#[derive(Deserialize)]
+struct LorebookQuery {
+    companion_id: Option<i32>,
+}
+
+#[get("/api/lorebook")]
+async fn lorebook_list(query: web::Query<LorebookQuery>) -> Result<HttpResponse, ApiError> {
+    let companion_id = query.companion_id.unwrap_or_else(Database::active_companion_id);
+    let entries = Lorebook::list(companion_id).or_internal("Error while listing lorebook entries")?;
+    Ok(HttpResponse::Ok().json(entries))
+}
+
+#[post("/api/lorebook")]
+async fn lorebook_add(received: web::Json<LorebookEntryModify>) -> Result<HttpResponse, ApiError> {
+    let mut entry = received.into_inner();
+    entry.validate().map_err(ApiError::BadRequest)?;
+    let id = Lorebook::add(Database::active_companion_id(), &entry)
+        .or_internal("Error while adding lorebook entry")?;
+    Ok(HttpResponse::Created().json(serde_json::json!({ "id": id })))
+}
+
+#[put("/api/lorebook/{id}")]
+async fn lorebook_edit(
+    id: web::Path<i32>,
+    received: web::Json<LorebookEntryModify>,
+) -> Result<HttpResponse, ApiError> {
+    let id = id.into_inner();
+    let mut entry = received.into_inner();
+    entry.validate().map_err(ApiError::BadRequest)?;
+    let existing = Lorebook::get(id)
+        .or_internal("Error while getting lorebook entry")?
+        .ok_or_else(|| ApiError::NotFound("Lorebook entry not found".to_string()))?;
+    // An entry taken out of the global lorebook goes to the active companion
+    let companion_id = existing.companion_id.unwrap_or_else(Database::active_companion_id);
+    Lorebook::edit(id, companion_id, &entry).or_internal("Error while editing lorebook entry")?;
+    Ok(HttpResponse::Ok().body("Lorebook entry edited!"))
+}
+
+#[delete("/api/lorebook/{id}")]
+async fn lorebook_delete(id: web::Path<i32>) -> Result<HttpResponse, ApiError> {
+    if !Lorebook::delete(id.into_inner()).or_internal("Error while deleting lorebook entry")? {
+        return Err(ApiError::NotFound("Lorebook entry not found".to_string()));
+    }
+    Ok(HttpResponse::Ok().body("Lorebook entry deleted!"))
+}
+
+//              Goals
+
+#[derive(Deserialize)]
+struct GoalsQuery {
+    companion_id: Option<i32>,
+    status: Option<GoalStatus>,
+}
+
+/// 409 when the companion pursues as many goals as it may already, `except` is the goal being edited
+fn check_active_goals(companion_id: i32, except: Option<i32>) -> Result<(), ApiError> {
+    let active = Goals::active(companion_id).or_internal("Error while getting goals")?;
+    if active.iter().filter(|goal| Some(goal.id) != except).count() >= goals::MAX_ACTIVE_GOALS {
+        return Err(ApiError::Conflict(format!(
+            "A companion pursues {} goals at most, mark one as achieved or abandoned first",
+            goals::MAX_ACTIVE_GOALS
+        )));
+    }
+    Ok(())
+}
+
+#[get("/api/goals")]
+async fn goals_list(query: web::Query<GoalsQuery>) -> Result<HttpResponse, ApiError> {
+    let companion_id = query.companion_id.unwrap_or_else(Database::active_companion_id);
+    let goals = Goals::list(companion_id, query.status).or_internal("Error while listing goals")?;
+    Ok(HttpResponse::Ok().json(goals))
+}
+
+#[post("/api/goals")]
+async fn goals_add(received: web::Json<GoalModify>) -> Result<HttpResponse, ApiError> {
+    let mut goal = received.into_inner();
+    goal.validate().map_err(ApiError::BadRequest)?;
+    let companion_id = Database::active_companion_id();
+    if goal.status.unwrap_or(GoalStatus::Active) == GoalStatus::Active {
+        check_active_goals(companion_id, None)?;
+    }
+    let id = Goals::add(companion_id, &goal).or_internal("Error while adding goal")?;
+    Ok(HttpResponse::Created().json(serde_json::json!({ "id": id })))
+}
+
+#[get("/api/goals/{id}")]
+async fn goals_get(id: web::Path<i32>) -> Result<HttpResponse, ApiError> {
+    let goal = Goals::get(id.into_inner())
+        .or_internal("Error while getting goal")?
+        .ok_or_else(|| ApiError::NotFound("Goal not found".to_string()))?;
+    Ok(HttpResponse::Ok().json(goal))
+}
+
+#[put("/api/goals/{id}")]
+async fn goals_edit(
+    id: web::Path<i32>,
+    received: web::Json<GoalModify>,
+) -> Result<HttpResponse, ApiError> {
+    let id = id.into_inner();
+    let mut goal = received.into_inner();
+    goal.validate().map_err(ApiError::BadRequest)?;
+    let existing = Goals::get(id)
+        .or_internal("Error while getting goal")?
+        .ok_or_else(|| ApiError::NotFound("Goal not found".to_string()))?;
+    // Taking up a goal again counts toward the limit
+    if goal.status == Some(GoalStatus::Active) && existing.status != GoalStatus::Active {
+        check_active_goals(existing.companion_id, Some(id))?;
+    }
+    Goals::edit(id, &goal).or_internal("Error while editing goal")?;
+    Ok(HttpResponse::Ok().body("Goal edited!"))
+}
+
+#[delete("/api/goals/{id}")]
+async fn goals_delete(id: web::Path<i32>) -> Result<HttpResponse, ApiError> {
+    if !Goals::delete(id.into_inner()).or_internal("Error while deleting goal")? {
+        return Err(ApiError::NotFound("Goal not found".to_string()));
+    }
+    Ok(HttpResponse::Ok().body("Goal deleted!"))
+}
+
+//              Content filters
+
+#[get("/api/filters")]
+async fn filters_list() -> Result<HttpResponse, ApiError> {
+    let filters = Filters::list().or_internal("Error while listing content filters")?;
+    let rules = Filters::rules().or_internal("Error while listing filter rules")?;
+    Ok(HttpResponse::Ok().json(serde_json::json!({ "filters": filters, "rules": rules })))
+}
+
+#[put("/api/filters/{name}")]
+async fn filters_set(
+    name: web::Path<String>,
+    received: web::Json<ContentFilterModify>,
+) -> Result<HttpResponse, ApiError> {
+    // curl -X PUT -H "Content-Type: application/json" -d '{"enabled":true,"applies_to":"prompt"}' http://localhost:3000/api/filters/pii
+    match Filters::set(&name, &received) {
+        Ok(filter) => Ok(HttpResponse::Ok().json(filter)),
+        Err(rusqlite::Error::QueryReturnedNoRows) => {
+            Err(ApiError::NotFound(format!("No content filter named {}", name)))
+        }
+        Err(e) => Err(ApiError::internal("Error while changing content filter", e)),
+    }
+}
+
+#[post("/api/filters/rules")]
+async fn filter_rules_add(received: web::Json<FilterRuleModify>) -> Result<HttpResponse, ApiError> {
+    let rule = received.into_inner();
+    rule.validate().map_err(ApiError::BadRequest)?;
+    let id = Filters::add_rule(&rule).or_internal("Error while adding filter rule")?;
+    Ok(HttpResponse::Created().json(serde_json::json!({ "id": id })))
+}
+
+#[put("/api/filters/rules/{id}")]
+async fn filter_rules_edit(
+    id: web::Path<i32>,
+    received: web::Json<FilterRuleModify>,
+) -> Result<HttpResponse, ApiError> {
+    let rule = received.into_inner();
+    rule.validate().map_err(ApiError::BadRequest)?;
+    if !Filters::edit_rule(id.into_inner(), &rule).or_internal("Error while editing filter rule")? {
+        return Err(ApiError::NotFound("Filter rule not found".to_string()));
+    }
+    Ok(HttpResponse::Ok().body("Filter rule edited!"))
+}
+
+#[delete("/api/filters/rules/{id}")]
+async fn filter_rules_delete(id: web::Path<i32>) -> Result<HttpResponse, ApiError> {
+    if !Filters::delete_rule(id.into_inner()).or_internal("Error while deleting filter rule")? {
+        return Err(ApiError::NotFound("Filter rule not found".to_string()));
+    }
+    Ok(HttpResponse::Ok().body("Filter rule deleted!"))
+}
+
+#[derive(Deserialize)]
+struct FilterPreview {
+    text: String,
+    stage: Stage,
+}
+
+#[post("/api/filters/preview")]
+async fn filters_preview(received: web::Json<FilterPreview>) -> Result<HttpResponse, ApiError> {
+    let text = Filters::run(received.stage, &received.text).or_internal("Error while filtering text")?;
+    Ok(HttpResponse::Ok().json(serde_json::json!({ "text": text })))
+}
+
+//              Prompting
+
+#[derive(Deserialize)]
+struct Prompt {
+    prompt: String,
+    /// Sampling settings for this reply only, on top of the config
+    #[serde(default)]
+    sampling: SamplingOverrides,
+    /// Who is writing, switches the active user
+    #[serde(default)]
+    user_id: Option<i32>,
+    /// Id to cancel the request with while it waits in the inference queue
+    #[serde(default)]
+    request_id: Option<String>,
+    /// Defaults to true, pass false to be refused instead of waiting in the inference queue
+    #[serde(default)]
+    wait: Option<bool>,
+    /// Send the URL the reply can be listened to at in X-Audio-Url
+    #[serde(default)]
+    audio: bool,
+    /// Pictures uploaded with POST /api/upload/image to send with the prompt
+    #[serde(default)]
+    images: Vec<String>,
+}
+
+/// Reply to a message that came in over Discord, as the Discord user in the channel's conversation
+#[cfg(feature = "discord")]
+fn discord_reply(text: &str, user_id: i32, conversation_id: i32) -> Result<String, String> {
+    let request_id = inference_queue::new_request_id("discord");
+    let thread = Some(Thread {
+        conversation_id,
+        user_id,
+    });
+    reply_to(text, &[], &SamplingOverrides::default(), None, thread, &request_id, true)
+        .map(|reply| reply.text)
+        .map_err(|e| e.to_string())
+}
+
+#[derive(Deserialize)]
+struct StreamingRequest {
+    prompt: String,
+    session_id: String,
+    #[serde(default)]
+    sampling: SamplingOverrides,
+    #[serde(default)]
+    user_id: Option<i32>,
+}
+
+/// Tells real-time clients the companion is writing a reply, until it is dropped
+struct Typing;
+
+impl Typing {
+    fn start(eta_seconds: Option<u32>) -> Typing {
+        event_bus::publish(
+            "companion_typing",
+            serde_json::json!({ "typing": true, "eta_seconds": eta_seconds }),
+        );
+        Typing
+    }
+}
+
+impl Drop for Typing {
+    fn drop(&mut self) {
+        event_bus::publish("companion_typing", serde_json::json!({ "typing": false }));
+    }
+}
+
+/// Bookkeeping done before any prompt is generated
+///
+/// Returns the user attitude to compare against and the typing indicator, which should live
+/// until the reply is done.
+fn before_prompt(text: &str, companion_id: i32, user_id: i32) -> (Option<CompanionAttitude>, Typing) {
+    // Track third-party mentions and display console output
+    match Database::track_third_party_mentions(text) {
+        Ok(mention_output) => {
+            if !mention_output.is_empty() {
+                info!("{}", mention_output);
+            }
+        },
+        Err(e) => warn!("Failed to track third-party mentions: {}", e),
+    }
+
+    // Automatically detect new persons in the message
+    if let Err(e) = MemoryProposals::detect_persons(text, companion_id) {
+        warn!("Failed to detect persons in message: {}", e);
+        // Continue processing even if person detection fails
+    }
+
+    // Ties between persons the companion knows, like "Anna is Tom's sister", are memory writes as well
+    if MemoryProposals::auto_approve() {
+        if let Err(e) = SocialGraph::learn_relationships(text, companion_id) {
+            warn!("Failed to learn relationships from message: {}", e);
+        }
+    }
+
+    // The reply is written from wherever the user's message took the scene
+    follow_scene(text, companion_id, user_id, true);
+
+    // Estimate response time based on message complexity
+    let estimate = estimate_response_time_enhanced(text);
+    info!(
+        expected_seconds = estimate.expected_seconds,
+        min_seconds = estimate.min_seconds,
+        max_seconds = estimate.max_seconds,
+        confidence = estimate.confidence,
+        factors = %estimate.factors.join(", "),
+        "⏱️ Response ETA: {}s",
+        estimate.expected_seconds
+    );
+
+    // Get current attitude for comparison (before processing)
+    let previous_attitude = match Database::get_all_companion_attitudes(companion_id) {
+        Ok(attitudes) => {
+            // Find the user attitude
+            attitudes.into_iter().find(|a| a.target_id == user_id && a.target_type == "user")
+        },
+        _ => None,
+    };
+    (previous_attitude, Typing::start(Some(estimate.expected_seconds)))
+}
+
+/// Move the scene of the active conversation along with a message of the user or the companion
+fn follow_scene(text: &str, companion_id: i32, user_id: i32, from_user: bool) {
+    let names = Database::get_companion_data_by_id(companion_id)
+        .and_then(|companion_data| Ok((companion_data.name, Database::get_user_by_id(user_id)?.name)));
+    let (companion_name, user_name) = match names {
+        Ok(names) => names,
+        Err(e) => {
+            warn!("⚠️ Could not update the scene: {}", e);
+            return;
+        }
+    };
+    let (speaker, listener) = match from_user {
+        true => (&user_name, &companion_name),
+        false => (&companion_name, &user_name),
+    };
+    if let Err(e) = Scene::follow(Conversations::active_id(), companion_id, text, speaker, listener) {
+        warn!("⚠️ Could not update the scene: {}", e);
+    }
+}
+
+/// Text handed to the model, carrying the outcome of a detected interaction if there is one
+fn interaction_prompt(text: &str, companion_id: i32) -> String {
+    if let Ok(Some(interaction)) =
+        Database::detect_interaction_request(text, companion_id)
+    {
+        if let Some(outcome) = &interaction.outcome {
+            return format!(
+                "{}\n[Context: Interaction with {} - {}]",
+                text,
+                Database::get_third_party_by_id(interaction.third_party_id)
+                    .ok()
+                    .flatten()
+                    .map(|p| p.name)
+                    .unwrap_or_else(|| "unknown".to_string()),
+                outcome
+            );
+        }
+    }
+    text.to_string()
+}
+
+/// Reporting done after the companion replied
+fn after_prompt(
+    text: &str,
+    reply: &str,
+    previous_attitude: Option<CompanionAttitude>,
+    companion_id: i32,
+    user_id: i32,
+    start_time: std::time::Instant,
+) {
+    // Let the exchange move the companion's attitude toward the user
+    match Database::get_config() {
+        Ok(config_data) => match attitude_inference::infer(&config_data, companion_id, user_id, text, reply) {
+            Ok(deltas) if !deltas.is_empty() => debug!("Attitude inferred from the exchange: {:?}", deltas),
+            Ok(_) => {}
+            Err(e) => warn!("⚠️ Could not infer attitude changes: {}", e),
+        },
+        Err(e) => warn!("⚠️ Could not infer attitude changes: {}", e),
+    }
+
+    // Check for attitude changes after processing
+    if let Some(prev_attitude) = previous_attitude {
+        if let Ok(attitudes) = Database::get_all_companion_attitudes(companion_id) {
+            if let Some(current_attitude) = attitudes.into_iter().find(|a| a.target_id == user_id && a.target_type == "user") {
+                let formatter = crate::attitude_formatter::AttitudeFormatter::new();
+                let attitude_changes = formatter.format_attitude_changes_for_console(&prev_attitude, &current_attitude);
+                if !attitude_changes.is_empty() {
+                    debug!("{}", attitude_changes);
+                }
+                publish_attitude_change(&prev_attitude, &current_attitude);
+                if let Err(e) = Mood::feel(companion_id, &prev_attitude, &current_attitude) {
+                    warn!("⚠️ Could not update the companion's mood: {}", e);
+                }
+            }
+        }
+    }
+
+    follow_scene(reply, companion_id, user_id, false);
+
+    match Goals::follow(companion_id, text, reply) {
+        Ok(moved) => {
+            for goal in moved {
+                debug!("Goal \"{}\" at {:.0}%", goal.description, goal.progress * 100.0);
+            }
+        }
+        Err(e) => warn!("⚠️ Could not score the companion's goals: {}", e),
+    }
+
+    // Display actual response time
+    let elapsed = start_time.elapsed();
+    info!(elapsed_ms = elapsed.as_millis() as u64, "✓ Response completed in {:.1}s", elapsed.as_secs_f32());
+
+    notify_companion_message(reply);
+}
+
+#[post("/api/prompt")]
+async fn prompt_message(received: web::Json<Prompt>) -> Result<HttpResponse, ApiError> {
+    // curl -X POST -H "Content-Type: application/json" -d '{"prompt":"Hi!","sampling":{"temperature":1.1}}' http://localhost:3000/api/prompt
+    let received = received.into_inner();
+    let audio = received.audio;
+    if audio {
+        check_tts(&Database::get_config().or_internal("Error while getting config")?)?;
+    }
+    let request_id = received
+        .request_id
+        .unwrap_or_else(|| inference_queue::new_request_id("prompt"));
+    let header_request_id = request_id.clone();
+    let reply = web::block(move || {
+        let wait = received.wait.unwrap_or(true);
+        reply_to(&received.prompt, &received.images, &received.sampling, received.user_id, None, &request_id, wait)
+    })
+    .await
+    .or_internal("Error while generating prompt")??;
+    let mut response = HttpResponse::Ok();
+    response
+        .insert_header(("X-Request-Id", header_request_id))
+        .insert_header(("X-Queue-Position", reply.queue_position.to_string()));
+    if !reply.dropped_sections.is_empty() {
+        response.insert_header(("X-Context-Dropped", dropped_header(&reply.dropped_sections)));
+    }
+    if let Some(reply_id) = reply.message_id.filter(|_| audio) {
+        response.insert_header(("X-Audio-Url", audio_url(reply_id)));
+    }
+    Ok(response.body(reply.text))
+}
+
+/// Asking for audio of a reply is refused before the reply is generated when it can't be read out
+fn check_tts(config_data: &ConfigView) -> Result<(), ApiError> {
+    if !tts::is_configured(config_data) {
+        return Err(ApiError::Unavailable(
+            "Text to speech is not configured, set tts_api_url in the config".to_string(),
+        ));
+    }
+    Ok(())
+}
+
+fn audio_url(reply_id: i32) -> String {
+    format!("/api/message/{}/audio", reply_id)
+}
+
+/// X-Context-Dropped value, the sections in the order they were dropped
+fn dropped_header(sections: &[PromptSection]) -> String {
+    sections.iter().map(|section| section.as_str()).collect::<Vec<_>>().join(",")
+}
+
+/// Get in line for a turn to generate, `on_position` hears the place in line while waiting
+fn wait_for_turn(
+    request_id: &str,
+    session: Option<&str>,
+    on_position: &mut dyn FnMut(usize) -> bool,
+) -> Result<inference_queue::Slot, ApiError> {
+    if shutdown::is_stopping() {
+        return Err(ApiError::Unavailable("The server is shutting down".to_string()));
+    }
+    inference_queue::join(request_id, session)
+        .ok_or_else(|| ApiError::Conflict(format!("Request {} is already queued", request_id)))?
+        .wait(on_position)
+        .map_err(|_| {
+            if shutdown::is_stopping() {
+                ApiError::Unavailable("The server is shutting down".to_string())
+            } else {
+                ApiError::Conflict(format!("Request {} was cancelled", request_id))
+            }
+        })
+}
+
+/// Overrides that would leave the sampling settings out of range are rejected before anything is stored
+fn check_sampling(sampling: &SamplingOverrides) -> Result<(), ApiError> {
+    let config_data = Database::get_config().or_internal("Error while getting config")?;
+    SamplingParams::from_config(&config_data)
+        .with_overrides(sampling)
+        .validate()
+        .map_err(ApiError::BadRequest)
+}
+
+/// Make the user a message comes from the active one, returns the active user
+fn select_user(user_id: Option<i32>) -> Result<i32, ApiError> {
+    let user_id = match user_id {
+        Some(user_id) => user_id,
+        None => return Ok(Database::active_user_id()),
+    };
+    if user_id != Database::active_user_id() {
+        if !Database::set_active_user(user_id).or_internal("Error while switching user")? {
+            return Err(ApiError::NotFound(format!("User {} not found", user_id)));
+        }
+        event_bus::publish("user_switched", serde_json::json!({ "user_id": user_id }));
+    }
+    Ok(user_id)
+}
+
+/// Conversation and user a reply belongs to when it isn't the active chat
+#[cfg_attr(not(feature = "discord"), allow(dead_code))]
+struct Thread {
+    conversation_id: i32,
+    user_id: i32,
+}
+
+struct ChatReply {
+    text: String,
+    /// None when the reply couldn't be stored
+    message_id: Option<i32>,
+    /// Place in the inference queue the request started at, 0 when it didn't have to wait
+    queue_position: usize,
+    /// Prompt sections left out so the prompt fit the context window
+    dropped_sections: Vec<PromptSection>,
+}
+
+/// Store the user message, generate the companion's reply and update attitude and memory from it
+///
+/// Waits for a turn in the inference queue first, the position it started at comes with the
+/// reply, 0 when it didn't have to wait. Without `wait` a request that would have to wait is
+/// refused as busy. With a `thread` the reply is generated and stored there, the active
+/// conversation and user stay as they are.
+fn reply_to(
+    text: &str,
+    images: &[String],
+    sampling: &SamplingOverrides,
+    user_id: Option<i32>,
+    thread: Option<Thread>,
+    request_id: &str,
+    wait: bool,
+) -> Result<ChatReply, ApiError> {
+    let _scope = match thread {
+        Some(thread) => Some(
+            Conversations::scope(thread.conversation_id, thread.user_id)
+                .or_internal("Error while opening conversation")?
+                .ok_or_else(|| {
+                    ApiError::NotFound(format!(
+                        "Conversation {} or user {} not found",
+                        thread.conversation_id, thread.user_id
+                    ))
+                })?,
+        ),
+        None => None,
+    };
+    check_sampling(sampling)?;
+    let text = &Filters::apply(Stage::Prompt, text);
+    let images = MessageImages::check(images).map_err(|e| match e {
+        rusqlite::Error::InvalidParameterName(e) => ApiError::BadRequest(e),
+        e => ApiError::internal("Error while checking images", e),
+    })?;
+    let companion_id = Database::active_companion_id();
+    let user_id = select_user(user_id)?;
+    let mut queue_position = 0;
+    let slot = wait_for_turn(request_id, Some(&inference_queue::chat_session()), &mut |position| {
+        if queue_position == 0 {
+            queue_position = position;
+        }
+        wait
+    });
+    let _slot = match slot {
+        Err(_) if !wait && queue_position > 0 => {
+            return Err(ApiError::Unavailable(
+                "The companion is busy with another reply, try again later".to_string(),
+            ))
+        }
+        slot => slot?,
+    };
+    let start_time = std::time::Instant::now();
+
+    let (previous_attitude, _typing) = before_prompt(text, companion_id, user_id);
+    let llm_prompt = interaction_prompt(text, companion_id);
+
+    if !images.is_empty() {
+        let config_data = Database::get_config().or_internal("Error while getting config")?;
+        // Pictures uploaded before a vision model was set up get their description now
+        vision::describe_uploads(&config_data, &images);
+    }
+    Database::insert_message(NewMessage {
+        ai: false,
+        content: text.to_string(),
+        images,
+    })
+    .or_internal("Error while adding message to database")?;
+    let reply = prompt(&llm_prompt, sampling).or_internal("Error while generating prompt")?;
+    after_prompt(text, &reply.text, previous_attitude, companion_id, user_id, start_time);
+    // Still our turn, the newest message is the reply that was just stored
+    let reply_id = Database::get_x_messages(1, 0)
+        .ok()
+        .and_then(|mut messages| messages.pop())
+        .filter(|newest| newest.ai)
+        .map(|newest| newest.id);
+    Ok(ChatReply {
+        text: reply.text,
+        message_id: reply_id,
+        queue_position,
+        dropped_sections: reply.dropped_sections,
+    })
+}
+
+#[derive(Deserialize)]
+struct SttQuery {
+    #[serde(default)]
+    prompt: bool,
+    /// With prompt, also answer with the URL the reply can be listened to at
+    #[serde(default)]
+    audio: bool,
+}
+
+#[post("/api/stt")]
+async fn speech_to_text(
+    request: actix_web::HttpRequest,
+    query: web::Query<SttQuery>,
+    received: actix_web::web::Payload,
+) -> Result<HttpResponse, ApiError> {
+    // curl -X POST -H "Content-Type: audio/webm" --data-binary @speech.webm "http://localhost:3000/api/stt?prompt=true"
+    let (data, format) = receive_audio(&request, received).await?;
+    let config_data = Database::get_config().or_internal("Error while getting config")?;
+    if query.prompt && query.audio {
+        check_tts(&config_data)?;
+    }
+    let transcript = transcribe_audio(config_data, data, format).await?;
+    if !query.prompt {
+        return Ok(HttpResponse::Ok().json(serde_json::json!({ "transcript": transcript })));
+    }
+    let request_id = inference_queue::new_request_id("stt");
+    let answer = answer_transcript(transcript, None, request_id, query.audio).await?;
+    Ok(HttpResponse::Ok().json(answer))
+}
+
+#[derive(Deserialize)]
+struct AudioPromptQuery {
+    /// Who is speaking, switches the active user
+    #[serde(default)]
+    user_id: Option<i32>,
+    #[serde(default)]
+    request_id: Option<String>,
+    /// Also answer with the URL the reply can be listened to at
+    #[serde(default)]
+    audio: bool,
+}
+
+#[post("/api/prompt/audio")]
+async fn prompt_audio(
+    request: actix_web::HttpRequest,
+    query: web::Query<AudioPromptQuery>,
+    received: actix_web::web::Payload,
+) -> Result<HttpResponse, ApiError> {
+    // curl -X POST -H "Content-Type: audio/wav" --data-binary @speech.wav http://localhost:3000/api/prompt/audio
+    let query = query.into_inner();
+    let (data, format) = receive_audio(&request, received).await?;
+    let config_data = Database::get_config().or_internal("Error while getting config")?;
+    if query.audio {
+        check_tts(&config_data)?;
+    }
+    let transcript = transcribe_audio(config_data, data, format).await?;
+    let request_id = query
+        .request_id
+        .unwrap_or_else(|| inference_queue::new_request_id("audio"));
+    let answer = answer_transcript(transcript, query.user_id, request_id.clone(), query.audio).await?;
+    Ok(HttpResponse::Ok()
+        .insert_header(("X-Request-Id", request_id))
+        .json(answer))
+}
+
+/// A recording sent as the raw request body, up to stt::MAX_AUDIO_BYTES
+async fn receive_audio(
+    request: &actix_web::HttpRequest,
+    mut received: actix_web::web::Payload,
+) -> Result<(Vec<u8>, AudioFormat), ApiError> {
+    let mut data = web::BytesMut::new();
+    while let Some(chunk) = received.next().await {
+        let d = chunk.map_err(|e| ApiError::BadRequest(format!("Error while receiving audio: {}", e)))?;
+        if data.len() + d.len() > stt::MAX_AUDIO_BYTES {
+            return Err(ApiError::BadRequest(format!(
+                "Audio is larger than {} MB",
+                stt::MAX_AUDIO_BYTES / 1024 / 1024
+            )));
+        }
+        data.extend_from_slice(&d);
+    }
+    if data.is_empty() {
+        return Err(ApiError::BadRequest("No audio received".to_string()));
+    }
+    let content_type = request
+        .headers()
+        .get(actix_web::http::header::CONTENT_TYPE)
+        .and_then(|value| value.to_str().ok());
+    let format = AudioFormat::detect(&data, content_type).ok_or_else(|| {
+        ApiError::BadRequest("Unsupported audio format, expected wav, ogg or webm".to_string())
+    })?;
+    Ok((data.to_vec(), format))
+}
+
+async fn transcribe_audio(config_data: ConfigView, data: Vec<u8>, format: AudioFormat) -> Result<String, ApiError> {
+    if !stt::is_configured(&config_data) {
+        return Err(ApiError::Unavailable(
+            "Speech to text is not configured, set stt_api_url in the config".to_string(),
+        ));
+    }
+    web::block(move || stt::transcribe(&config_data, data, format))
+        .await
+        .or_internal("Error while transcribing audio")?
+        .map_err(|e| ApiError::internal("Error while transcribing audio", e))
+}
+
+/// Send what was said to the companion like a typed prompt, `{transcript, reply, message_id}`
+/// and with `audio` the reply's audio_url
+async fn answer_transcript(
+    transcript: String,
+    user_id: Option<i32>,
+    request_id: String,
+    audio: bool,
+) -> Result<serde_json::Value, ApiError> {
+    if transcript.is_empty() {
+        return Err(ApiError::BadRequest("No speech recognized in the audio".to_string()));
+    }
+    let text = transcript.clone();
+    let reply = web::block(move || reply_to(&text, &[], &SamplingOverrides::default(), user_id, None, &request_id, true))
+        .await
+        .or_internal("Error while generating prompt")??;
+    let mut answer = serde_json::json!({
+        "transcript": transcript,
+        "reply": reply.text,
+        "message_id": reply.message_id,
+    });
+    if let Some(reply_id) = reply.message_id.filter(|_| audio) {
+        answer["audio_url"] = audio_url(reply_id).into();
+    }
+    Ok(answer)
+}
+
+/// Store the user message and generate the reply on a blocking thread, chunks arrive on the receiver
+///
+/// While the request waits in the inference queue its position arrives as queued chunks. The last
+/// chunk carries the cleaned up reply or an error. Dropping the receiver stops generation, the
+/// reply so far is still stored.
+fn start_streamed_reply(
+    text: String,
+    sampling: SamplingOverrides,
+    user_id: Option<i32>,
+    session_id: String,
+) -> Result<tokio::sync::mpsc::UnboundedReceiver<StreamChunk>, ApiError> {
+    if !instance_lock::is_leader() {
+        return Err(ApiError::Unavailable(instance_lock::READ_ONLY_MESSAGE.to_string()));
+    }
+    if shutdown::is_stopping() {
+        return Err(ApiError::Unavailable("The server is shutting down".to_string()));
+    }
+    check_sampling(&sampling)?;
+    let text = Filters::apply(Stage::Prompt, &text);
+    let companion_id = Database::active_companion_id();
+    let user_id = select_user(user_id)?;
+    let ticket = inference_queue::join(&session_id, Some(&inference_queue::chat_session()))
+        .ok_or_else(|| ApiError::Conflict(format!("Request {} is already queued", session_id)))?;
+
+    let receiver = INFERENCE_OPTIMIZER.start_streaming_session(session_id.clone());
+
+    actix_web::rt::task::spawn_blocking(move || {
+        let error_chunk = |error: &str| StreamChunk {
+            request_id: session_id.clone(),
+            content: String::new(),
+            is_complete: true,
+            token_count: None,
+            error: Some(error.to_string()),
+            queue_position: None,
+            dropped_sections: Vec::new(),
+        };
+        // Nothing is stored until it is this request's turn, a cancelled one leaves no trace
+        let _slot = match ticket.wait(&mut |position| {
+            INFERENCE_OPTIMIZER
+                .stream_chunk(&session_id, StreamChunk::queued(&session_id, position))
+                .is_ok()
+        }) {
+            Ok(slot) => slot,
+            Err(_) => {
+                let _ = INFERENCE_OPTIMIZER.stream_chunk(&session_id, error_chunk("Request was cancelled"));
+                INFERENCE_OPTIMIZER.end_streaming_session(&session_id);
+                return;
+            }
+        };
+        let start_time = std::time::Instant::now();
+
+        let (previous_attitude, typing) = before_prompt(&text, companion_id, user_id);
+        let llm_prompt = interaction_prompt(&text, companion_id);
+        if let Err(e) = Database::insert_message(NewMessage {
+            ai: false,
+            content: text.to_string(),
+            images: Vec::new(),
+        }) {
+            error!("Error while adding message to database: {}", e);
+            let _ = INFERENCE_OPTIMIZER.stream_chunk(
+                &session_id,
+                error_chunk("Error while adding message to database, check logs for more information"),
+            );
+            INFERENCE_OPTIMIZER.end_streaming_session(&session_id);
+            return;
+        }
+
+        let mut token_count = 0;
+        let result = llm::prompt_streaming(&llm_prompt, &sampling, &mut |token| {
+            token_count += 1;
+            INFERENCE_OPTIMIZER
+                .stream_chunk(
+                    &session_id,
+                    StreamChunk {
+                        request_id: session_id.clone(),
+                        content: token.to_string(),
+                        is_complete: false,
+                        token_count: Some(token_count),
+                        error: None,
+                        queue_position: None,
+                        dropped_sections: Vec::new(),
+                    },
+                )
+                .is_ok()
+        });
+        // The final chunk carries the cleaned up reply, clients should replace the partial text with it
+        let final_chunk = match result {
+            Ok(reply) => {
+                after_prompt(&text, &reply.text, previous_attitude, companion_id, user_id, start_time);
+                StreamChunk {
+                    request_id: session_id.clone(),
+                    content: reply.text,
+                    is_complete: true,
+                    token_count: Some(token_count),
+                    error: None,
+                    queue_position: None,
+                    dropped_sections: reply.dropped_sections,
+                }
+            }
+            Err(e) => {
+                error!("Failed to generate prompt: {}", e);
+                StreamChunk {
+                    token_count: Some(token_count),
+                    ..error_chunk("Error while generating prompt, check logs for more information")
+                }
+            }
+        };
+        let _ = INFERENCE_OPTIMIZER.stream_chunk(&session_id, final_chunk);
+        INFERENCE_OPTIMIZER.end_streaming_session(&session_id);
+        drop(typing);
+    });
+
+    Ok(receiver)
+}
+
+#[post("/api/prompt/sse")]
+async fn prompt_message_sse(received: web::Json<Prompt>) -> Result<HttpResponse, ApiError> {
+    // curl -N -X POST -H "Content-Type: application/json" -d '{"prompt":"Hi!"}' http://localhost:3000/api/prompt/sse
+    let received = received.into_inner();
+    let session_id = received
+        .request_id
+        .unwrap_or_else(|| inference_queue::new_request_id("sse"));
+    let receiver = start_streamed_reply(received.prompt, received.sampling, received.user_id, session_id)?;
+    Ok(sse_response(receiver))
+}
+
+/// Server-sent events for a streamed reply, disconnecting drops the receiver and stops generation
+fn sse_response(receiver: tokio::sync::mpsc::UnboundedReceiver<StreamChunk>) -> HttpResponse {
+    // Ends once the session is closed and its sender dropped
+    let events = futures_util::stream::unfold(receiver, |mut receiver| async move {
+        let chunk = receiver.recv().await?;
+        Some((
+            Ok::<_, actix_web::Error>(web::Bytes::from(chunk.to_sse())),
+            receiver,
+        ))
+    });
+    HttpResponse::Ok()
+        .content_type("text/event-stream")
+        .insert_header(("Cache-Control", "no-cache"))
+        .streaming(events)
+}
+
+#[post("/api/prompt/preview")]
+async fn preview_prompt(received: web::Json<Prompt>) -> Result<HttpResponse, ApiError> {
+    let preview = prompt_preview(&received.prompt).or_internal("Error while building prompt preview")?;
+    let preview_json = serde_json::to_string(&preview)
+        .unwrap_or(String::from("Error serializing prompt preview as JSON"));
+    Ok(HttpResponse::Ok().body(preview_json))
+}
+
+/// What a reply to the prompt would be generated from, without storing it or running the model
+#[post("/api/debug/context")]
+async fn debug_context(received: web::Json<Prompt>) -> Result<HttpResponse, ApiError> {
+    let prompt = received.into_inner().prompt;
+    let assembled = web::block(move || llm::dry_run(&prompt))
+        .await
+        .or_internal("Error while assembling context")?
+        .or_internal("Error while assembling context")?;
+    Ok(HttpResponse::Ok().json(assembled))
+}
+
+#[get("/api/prompt/queue")]
+async fn prompt_queue() -> HttpResponse {
+    HttpResponse::Ok().json(inference_queue::status())
+}
+
+#[get("/api/prompt/queue/{id}")]
+async fn prompt_queue_request(id: web::Path<String>) -> Result<HttpResponse, ApiError> {
+    let status = inference_queue::request_status(&id)
+        .ok_or_else(|| ApiError::NotFound(format!("No queued or running request {}", id)))?;
+    Ok(HttpResponse::Ok().json(status))
+}
+
+/// Take a waiting request out of the queue
+fn cancel_queued(id: &str) -> Result<HttpResponse, ApiError> {
+    if inference_queue::cancel(id) {
+        return Ok(HttpResponse::Ok().body(format!("Request {} cancelled!", id)));
+    }
+    match inference_queue::request_status(id) {
+        Some(inference_queue::RequestStatus::Running(_)) => Err(ApiError::Conflict(format!(
+            "Request {} is being generated already",
+            id
+        ))),
+        _ => Err(ApiError::NotFound(format!("No queued request {}", id))),
+    }
+}
+
+#[delete("/api/prompt/queue/{id}")]
+async fn prompt_queue_delete(id: web::Path<String>) -> Result<HttpResponse, ApiError> {
+    cancel_queued(&id)
+}
+
+#[post("/api/prompt/cancel/{id}")]
+async fn prompt_cancel(id: web::Path<String>) -> Result<HttpResponse, ApiError> {
+    cancel_queued(&id)
+}
+
+#[get("/api/prompt/regenerate")]
+async fn regenerate_prompt() -> Result<HttpResponse, ApiError> {
+    let reply = web::block(|| {
+        let _slot = wait_for_turn(
+            &inference_queue::new_request_id("regenerate"),
+            Some(&inference_queue::chat_session()),
+            &mut |_| true,
+        )?;
+        let latest = Database::get_latest_message().or_internal("Error while getting latest message")?;
+        let _typing = Typing::start(None);
+        if !latest.ai {
+            // Nothing answered the message yet, e.g. after a failed generation
+            return prompt(&latest.content, &SamplingOverrides::default())
+                .map(|reply| reply.text)
+                .or_internal("Error while generating prompt");
+        }
+        let user_message_id =
+            Database::get_latest_user_message_id().or_internal("Error while getting latest message")?;
+        if MessageAttempts::reply_id(user_message_id).or_internal("Error while getting latest message")?
+            != Some(latest.id)
+        {
+            return Err(ApiError::BadRequest(
+                "The latest message of the companion does not answer a message of the user".to_string(),
+            ));
+        }
+        let user_message =
+            Database::get_message(user_message_id).or_internal("Error while getting latest message")?;
+        // The earlier replies stay as attempts, the client can swipe back to them through promote
+        let attempt = llm::alternatives(&user_message, &latest, 1, &SamplingOverrides::default())
+            .or_internal("Error while generating prompt")?
+            .pop()
+            .ok_or_else(|| ApiError::Unavailable("No reply was generated".to_string()))?;
+        MessageAttempts::promote(user_message.id, attempt.id).or_internal("Error while replacing the reply")?;
+        Ok(attempt.content)
+    })
+    .await
+    .or_internal("Error while generating prompt")??;
+    notify_companion_message(&reply);
+    Ok(HttpResponse::Ok().body(reply))
+}
+
+//              Config
+
+#[get("/api/config")]
+async fn config() -> Result<HttpResponse, ApiError> {
+    let config = Database::get_global_config().or_internal("Error while getting config")?;
+    let config_json =
+        serde_json::to_string(&config).unwrap_or(String::from("Error serializing config as JSON"));
+    Ok(HttpResponse::Ok().body(config_json))
+}
+
+#[put("/api/config")]
+async fn config_post(received: web::Json<ConfigModify>) -> Result<HttpResponse, ApiError> {
+    // The global config is what changes, a companion's own model doesn't count
+    let previous_model = Database::get_global_config()
+        .or_internal("Error while updating config")?
+        .llm_model_path;
+    let model_path = received.llm_model_path.clone();
+    match Database::change_config(received.into_inner()) {
+        Ok(_) => {
+            // A template chosen along with another model is replaced by the detected one
+            if model_path != previous_model {
+                llm_scanner::apply_detected_template(&model_path)
+                    .or_internal("Error while detecting prompt template")?;
+            }
+            follow_config();
+            Ok(HttpResponse::Ok().body("Config updated!"))
+        }
+        Err(rusqlite::Error::InvalidParameterName(e)) => Err(ApiError::BadRequest(e)),
+        Err(e) => Err(ApiError::internal("Error while updating config", e)),
+    }
+}
+
+//              Config profiles
+
+fn config_profile_error(context: &str, error: rusqlite::Error) -> ApiError {
+    match error {
+        rusqlite::Error::InvalidParameterName(e) => ApiError::BadRequest(e),
+        rusqlite::Error::SqliteFailure(e, _) if e.code == rusqlite::ErrorCode::ConstraintViolation => {
+            ApiError::Conflict("A config profile with this name already exists".to_string())
+        }
+        e => ApiError::internal(context, e),
+    }
+}
+
+#[get("/api/config/profiles")]
+async fn config_profiles_list() -> Result<HttpResponse, ApiError> {
+    let profiles = ConfigProfiles::list().or_internal("Error while getting config profiles")?;
+    Ok(HttpResponse::Ok().json(profiles))
+}
+
+#[get("/api/config/profiles/{id}")]
+async fn config_profiles_get(id: web::Path<i32>) -> Result<HttpResponse, ApiError> {
+    match ConfigProfiles::get(*id).or_internal("Error while getting config profile")? {
+        Some(profile) => Ok(HttpResponse::Ok().json(profile)),
+        None => Err(ApiError::NotFound(format!("Config profile {} not found", id))),
+    }
+}
+
+#[post("/api/config/profiles")]
+async fn config_profiles_create(
+    received: web::Json<ConfigProfileModify>,
+) -> Result<HttpResponse, ApiError> {
+    let id = ConfigProfiles::add(received.into_inner())
+        .map_err(|e| config_profile_error("Error while creating config profile", e))?;
+    Ok(HttpResponse::Created().json(serde_json::json!({ "id": id })))
+}
+
+#[put("/api/config/profiles/{id}")]
+async fn config_profiles_put(
+    id: web::Path<i32>,
+    received: web::Json<ConfigProfileModify>,
+) -> Result<HttpResponse, ApiError> {
+    let updated = ConfigProfiles::update(*id, received.into_inner())
+        .map_err(|e| config_profile_error("Error while editing config profile", e))?;
+    if updated == 0 {
+        return Err(ApiError::NotFound(format!("Config profile {} not found", id)));
+    }
+    Ok(HttpResponse::Ok().body("Config profile edited!"))
+}
+
+#[delete("/api/config/profiles/{id}")]
+async fn config_profiles_delete(id: web::Path<i32>) -> Result<HttpResponse, ApiError> {
+    if ConfigProfiles::delete(*id).or_internal("Error while deleting config profile")? == 0 {
+        return Err(ApiError::NotFound(format!("Config profile {} not found", id)));
+    }
+    Ok(HttpResponse::Ok().body(format!("Config profile {} deleted", id)))
+}
+
+#[post("/api/config/profiles/{id}/activate")]
+async fn config_profiles_activate(id: web::Path<i32>) -> Result<HttpResponse, ApiError> {
+    if !ConfigProfiles::activate(*id)
+        .map_err(|e| config_profile_error("Error while activating config profile", e))?
+    {
+        return Err(ApiError::NotFound(format!("Config profile {} not found", id)));
+    }
+    // The model is only reloaded when the profile changed what it is loaded with
+    follow_config();
+    Ok(HttpResponse::Ok().body("Config profile activated!"))
+}
+
+//              Prompt templates
+
+fn prompt_template_error(context: &str, error: rusqlite::Error) -> ApiError {
+    match error {
+        rusqlite::Error::InvalidParameterName(e) => ApiError::BadRequest(e),
+        rusqlite::Error::SqliteFailure(e, _) if e.code == rusqlite::ErrorCode::ConstraintViolation => {
+            ApiError::Conflict("A prompt template with this name already exists".to_string())
+        }
+        e => ApiError::internal(context, e),
+    }
+}
+
+#[get("/api/templates")]
+async fn prompt_templates_list() -> Result<HttpResponse, ApiError> {
+    let templates = PromptTemplates::list().or_internal("Error while getting prompt templates")?;
+    let templates_json: String = serde_json::to_string(&templates)
+        .unwrap_or(String::from("Error serializing prompt templates as JSON"));
+    Ok(HttpResponse::Ok().body(templates_json))
+}
+
+#[get("/api/templates/{id}")]
+async fn prompt_templates_get(id: web::Path<i32>) -> Result<HttpResponse, ApiError> {
+    match PromptTemplates::get(*id).or_internal("Error while getting prompt template")? {
+        Some(template) => Ok(HttpResponse::Ok().json(template)),
+        None => Err(ApiError::NotFound(format!("Prompt template {} not found", id))),
+    }
+}
+
+#[post("/api/templates")]
+async fn prompt_templates_create(
+    received: web::Json<PromptTemplateModify>,
+) -> Result<HttpResponse, ApiError> {
+    let id = PromptTemplates::add(&received)
+        .map_err(|e| prompt_template_error("Error while creating prompt template", e))?;
+    Ok(HttpResponse::Created().json(serde_json::json!({ "id": id })))
+}
+
+#[put("/api/templates/{id}")]
+async fn prompt_templates_put(
+    id: web::Path<i32>,
+    received: web::Json<PromptTemplateModify>,
+) -> Result<HttpResponse, ApiError> {
+    let template = PromptTemplates::get(*id)
+        .or_internal("Error while editing prompt template")?
+        .ok_or_else(|| ApiError::NotFound(format!("Prompt template {} not found", id)))?;
+    if template.builtin {
+        return Err(ApiError::Forbidden(
+            "Built-in prompt templates can't be edited, create a copy instead".to_string(),
+        ));
+    }
+    if template.name != received.name.trim()
+        && PromptTemplates::in_use(&template.name).or_internal("Error while editing prompt template")?
+    {
+        return Err(ApiError::Conflict(
+            "The prompt template is in use and can't be renamed".to_string(),
+        ));
+    }
+    PromptTemplates::update(*id, &received)
+        .map_err(|e| prompt_template_error("Error while editing prompt template", e))?;
+    Ok(HttpResponse::Ok().body("Prompt template edited!"))
+}
+
+#[delete("/api/templates/{id}")]
+async fn prompt_templates_delete(id: web::Path<i32>) -> Result<HttpResponse, ApiError> {
+    let template = PromptTemplates::get(*id)
+        .or_internal("Error while deleting prompt template")?
+        .ok_or_else(|| ApiError::NotFound(format!("Prompt template {} not found", id)))?;
+    if template.builtin {
+        return Err(ApiError::Forbidden(
+            "Built-in prompt templates can't be deleted".to_string(),
+        ));
+    }
+    if PromptTemplates::in_use(&template.name).or_internal("Error while deleting prompt template")? {
+        return Err(ApiError::Conflict(
+            "The prompt template is in use, select another one first".to_string(),
+        ));
+    }
+    PromptTemplates::delete(*id).or_internal("Error while deleting prompt template")?;
+    Ok(HttpResponse::Ok().body(format!("Prompt template {} deleted", id)))
+}
+
+#[get("/api/prompt/layout")]
+async fn prompt_layout_get() -> Result<HttpResponse, ApiError> {
+    let layout = PromptLayout::get().or_internal("Error while getting prompt layout")?;
+    Ok(HttpResponse::Ok().json(layout))
+}
+
+#[put("/api/prompt/layout")]
+async fn prompt_layout_put(received: web::Json<PromptLayout>) -> Result<HttpResponse, ApiError> {
+    let received = received.into_inner();
+    received.validate().map_err(ApiError::BadRequest)?;
+    let layout = PromptLayout::set(received).or_internal("Error while editing prompt layout")?;
+    Ok(HttpResponse::Ok().json(layout))
+}
+
+#[delete("/api/prompt/layout")]
+async fn prompt_layout_delete() -> Result<HttpResponse, ApiError> {
+    PromptLayout::reset().or_internal("Error while resetting prompt layout")?;
+    Ok(HttpResponse::Ok().body("Prompt layout reset to the default!"))
+}
+
+//              Auth
+
+#[derive(Deserialize)]
+struct TokenRequest {
+    // A new key is generated when omitted
+    token: Option<String>,
+}
+
+#[get("/api/auth/token")]
+async fn auth_token_status() -> Result<HttpResponse, ApiError> {
+    let enabled = auth::enabled().or_internal("Error while checking API key")?;
+    Ok(HttpResponse::Ok().json(serde_json::json!({ "enabled": enabled })))
+}
+
+#[post("/api/auth/token")]
+async fn auth_token_set(
+    request: actix_web::HttpRequest,
+    received: Option<web::Json<TokenRequest>>,
+) -> Result<HttpResponse, ApiError> {
+    // curl -X POST http://localhost:3000/api/auth/token
+    // Replacing a key is already authorized by the middleware, the first credential may only be set locally
+    if !auth::is_local(&request) && !auth::enabled().or_internal("Error while checking API key")? {
+        return Err(ApiError::Forbidden(
+            "The first API key can only be set from the machine the companion runs on".to_string(),
+        ));
+    }
+    let token = match received.and_then(|r| r.into_inner().token) {
+        Some(token) if token.trim().len() < auth::MIN_TOKEN_LENGTH => {
+            return Err(ApiError::BadRequest(format!(
+                "API key must be at least {} characters long",
+                auth::MIN_TOKEN_LENGTH
+            )))
+        }
+        Some(token) => token.trim().to_string(),
+        None => auth::generate_token(),
+    };
+    auth::set_token(&token).or_internal("Error while setting API key")?;
+    info!("🔒 API key set, requests to /api now need it");
+    // The key is only ever shown here, the database keeps its hash
+    Ok(HttpResponse::Ok()
+        .cookie(auth::token_cookie(&token))
+        .json(serde_json::json!({ "token": token })))
+}
+
+#[delete("/api/auth/token")]
+async fn auth_token_clear() -> Result<HttpResponse, ApiError> {
+    auth::clear_token().or_internal("Error while removing API key")?;
+    info!("🔓 API key removed, the API is open again");
+    Ok(HttpResponse::Ok().body("API key removed, authentication disabled"))
+}
+
+#[derive(Deserialize)]
+struct LoginRequest {
+    token: Option<String>,
+    password: Option<String>,
+}
+
+#[post("/api/auth/login")]
+async fn auth_login(received: web::Json<LoginRequest>) -> Result<HttpResponse, ApiError> {
+    let received = received.into_inner();
+    if let Some(password) = received.password {
+        if !auth::verify_password(&password).or_internal("Error while checking password")? {
+            return Err(ApiError::Unauthorized("Invalid password".to_string()));
+        }
+        let session = auth::start_session().or_internal("Error while starting login session")?;
+        return Ok(HttpResponse::Ok()
+            .cookie(auth::session_cookie(&session))
+            .body("Logged in!"));
+    }
+    let token = received
+        .token
+        .ok_or_else(|| ApiError::BadRequest("Send either a password or an API key".to_string()))?;
+    if !auth::verify(token.trim()).or_internal("Error while checking API key")? {
+        return Err(ApiError::Unauthorized("Invalid API key".to_string()));
+    }
+    Ok(HttpResponse::Ok()
+        .cookie(auth::token_cookie(token.trim()))
+        .body("Logged in!"))
+}
+
+#[post("/api/auth/logout")]
+async fn auth_logout(request: actix_web::HttpRequest) -> Result<HttpResponse, ApiError> {
+    if let Some(cookie) = request.cookie(auth::SESSION_COOKIE) {
+        auth::end_session(cookie.value()).or_internal("Error while ending login session")?;
+    }
+    let mut response = HttpResponse::Ok();
+    for cookie in auth::removal_cookies() {
+        response.cookie(cookie);
+    }
+    Ok(response.body("Logged out!"))
+}
+
+#[get("/api/auth/status")]
+async fn auth_status(request: actix_web::HttpRequest) -> Result<HttpResponse, ApiError> {
+    let password = auth::password_set().or_internal("Error while checking password")?;
+    let api_key = auth::token_set().or_internal("Error while checking API key")?;
+    let authenticated = (!password && !api_key)
+        || auth::authenticated(&request).or_internal("Error while checking credentials")?;
+    Ok(HttpResponse::Ok().json(serde_json::json!({
+        "setup_required": !password && !api_key,
+        "password": password,
+        "api_key": api_key,
+        "authenticated": authenticated,
+        // The first credential can only be set up from here
+        "local": auth::is_local(&request),
+    })))
+}
+
+#[derive(Deserialize)]
+struct PasswordRequest {
+    password: String,
+}
+
+fn check_password_length(password: &str) -> Result<(), ApiError> {
+    if password.chars().count() < auth::MIN_PASSWORD_LENGTH {
+        return Err(ApiError::BadRequest(format!(
+            "Password must be at least {} characters long",
+            auth::MIN_PASSWORD_LENGTH
+        )));
+    }
+    Ok(())
+}
+
+#[post("/api/auth/setup")]
+async fn auth_setup(
+    request: actix_web::HttpRequest,
+    received: web::Json<PasswordRequest>,
+) -> Result<HttpResponse, ApiError> {
+    // First run only, afterwards the password is changed with PUT /api/auth/password
+    if auth::enabled().or_internal("Error while checking credentials")? {
+        return Err(ApiError::Conflict(
+            "Credentials are already set up, log in to change them".to_string(),
+        ));
+    }
+    if !auth::is_local(&request) {
+        return Err(ApiError::Forbidden(
+            "The first password can only be set from the machine the companion runs on".to_string(),
+        ));
+    }
+    check_password_length(&received.password)?;
+    auth::set_password(&received.password).or_internal("Error while setting password")?;
+    let session = auth::start_session().or_internal("Error while starting login session")?;
+    info!("🔒 Password set, requests to /api now need a login");
+    Ok(HttpResponse::Ok()
+        .cookie(auth::session_cookie(&session))
+        .body("Password set, you are logged in"))
 }
 
-//              Config
-
-#[get("/api/config")]
-async fn config() -> HttpResponse {
-    let config = match Database::get_config() {
-        Ok(v) => v,
-        Err(e) => {
-            println!("Failed to get config: {}", e);
-            return HttpResponse::InternalServerError()
-                .body("Error while getting config, check logs for more information");
-        }
-    };
-    let config_json =
-        serde_json::to_string(&config).unwrap_or(String::from("Error serializing config as JSON"));
-    HttpResponse::Ok().body(config_json)
+#[put("/api/auth/password")]
+async fn auth_password_set(received: web::Json<PasswordRequest>) -> Result<HttpResponse, ApiError> {
+    // Without credentials every request gets through, the first password goes through the local-only setup
+    if !auth::enabled().or_internal("Error while checking credentials")? {
+        return Err(ApiError::Forbidden(
+            "No credentials are set up yet, set the first password with POST /api/auth/setup".to_string(),
+        ));
+    }
+    check_password_length(&received.password)?;
+    // Also ends every login session, the caller gets a new one
+    auth::set_password(&received.password).or_internal("Error while setting password")?;
+    let session = auth::start_session().or_internal("Error while starting login session")?;
+    Ok(HttpResponse::Ok()
+        .cookie(auth::session_cookie(&session))
+        .body("Password changed, other devices have to log in again"))
 }
 
-#[put("/api/config")]
-async fn config_post(received: web::Json<ConfigModify>) -> HttpResponse {
-    match Database::change_config(received.into_inner()) {
-        Ok(_) => HttpResponse::Ok().body("Config updated!"),
-        Err(e) => {
-            println!("Failed to update config: {}", e);
-            HttpResponse::InternalServerError()
-                .body("Error while updating config, check logs for more information")
-        }
-    }
+#[delete("/api/auth/password")]
+async fn auth_password_clear() -> Result<HttpResponse, ApiError> {
+    auth::clear_password().or_internal("Error while removing password")?;
+    info!("🔓 Password removed");
+    Ok(HttpResponse::Ok().body("Password removed"))
 }
 
 //              LLM Model Management
 
 #[get("/api/llm/models")]
-async fn get_llm_models() -> HttpResponse {
+async fn get_llm_models() -> Result<HttpResponse, ApiError> {
     let scanner = LlmScanner::new();
     
     // Perform migration of existing config if needed
     if let Err(e) = scanner.migrate_existing_config() {
-        println!("Warning: Failed to migrate existing config: {}", e);
+        warn!("Failed to migrate existing config: {}", e);
     }
     
-    match scanner.scan_for_models() {
-        Ok(models) => {
-            let models_json = serde_json::to_string(&models)
-                .unwrap_or(String::from("Error serializing models as JSON"));
-            HttpResponse::Ok().body(models_json)
-        }
-        Err(e) => {
-            println!("Failed to scan for models: {}", e);
-            HttpResponse::InternalServerError()
-                .body("Error while scanning for models, check logs for more information")
-        }
-    }
+    let models = scanner.scan_for_models().or_internal("Error while scanning for models")?;
+    let models_json =
+        serde_json::to_string(&models).unwrap_or(String::from("Error serializing models as JSON"));
+    Ok(HttpResponse::Ok().body(models_json))
 }
 
 #[get("/api/llm/directories")]
-async fn get_llm_directories() -> HttpResponse {
+async fn get_llm_directories() -> Result<HttpResponse, ApiError> {
     let scanner = LlmScanner::new();
-    match scanner.get_directories() {
-        Ok(directories) => {
-            let directories_json = serde_json::to_string(&directories)
-                .unwrap_or(String::from("Error serializing directories as JSON"));
-            HttpResponse::Ok().body(directories_json)
-        }
-        Err(e) => {
-            println!("Failed to get directories: {}", e);
-            HttpResponse::InternalServerError()
-                .body("Error while getting directories, check logs for more information")
-        }
-    }
+    let directories = scanner.get_directories().or_internal("Error while getting directories")?;
+    let directories_json = serde_json::to_string(&directories)
+        .unwrap_or(String::from("Error serializing directories as JSON"));
+    Ok(HttpResponse::Ok().body(directories_json))
 }
 
 #[derive(Deserialize)]
@@ -743,29 +3151,147 @@ struct AddDirectoryRequest {
 }
 
 #[post("/api/llm/directories")]
-async fn add_llm_directory(received: web::Json<AddDirectoryRequest>) -> HttpResponse {
+async fn add_llm_directory(received: web::Json<AddDirectoryRequest>) -> Result<HttpResponse, ApiError> {
     let scanner = LlmScanner::new();
-    match scanner.add_directory(&received.path) {
-        Ok(_) => HttpResponse::Ok().body("Directory added successfully"),
-        Err(e) => {
-            println!("Failed to add directory: {}", e);
-            HttpResponse::InternalServerError()
-                .body("Error while adding directory, check logs for more information")
-        }
-    }
+    scanner.add_directory(&received.path).or_internal("Error while adding directory")?;
+    Ok(HttpResponse::Ok().body("Directory added successfully"))
 }
 
 #[delete("/api/llm/directories/{id}")]
-async fn remove_llm_directory(id: web::Path<i32>) -> HttpResponse {
+async fn remove_llm_directory(id: web::Path<i32>) -> Result<HttpResponse, ApiError> {
     let scanner = LlmScanner::new();
-    match scanner.remove_directory(*id) {
-        Ok(_) => HttpResponse::Ok().body("Directory removed successfully"),
-        Err(e) => {
-            println!("Failed to remove directory: {}", e);
-            HttpResponse::InternalServerError()
-                .body("Error while removing directory, check logs for more information")
+    scanner.remove_directory(*id).or_internal("Error while removing directory")?;
+    Ok(HttpResponse::Ok().body("Directory removed successfully"))
+}
+
+#[derive(Deserialize)]
+struct ModelDownloadRequest {
+    url: String,
+    directory_id: Option<i32>,
+    sha256: Option<String>,
+}
+
+#[post("/api/llm/downloads")]
+async fn model_downloads_start(
+    received: web::Json<ModelDownloadRequest>,
+) -> Result<HttpResponse, ApiError> {
+    let (url, filename) = huggingface_download_url(&received.url).map_err(ApiError::BadRequest)?;
+    if let Some(sha256) = &received.sha256 {
+        if model_downloads::sha256_from_etag(sha256).is_none() {
+            return Err(ApiError::BadRequest(
+                "sha256 must be 64 hexadecimal characters".to_string(),
+            ));
+        }
+    }
+    let directories = LlmScanner::new()
+        .get_directories()
+        .or_internal("Error while getting directories")?;
+    // Without an explicit directory the first configured one is used
+    let directory = directories
+        .into_iter()
+        .find(|d| received.directory_id.map_or(true, |id| d.id == id))
+        .ok_or_else(|| {
+            ApiError::BadRequest(
+                "No matching model directory, add one under /api/llm/directories first".to_string(),
+            )
+        })?;
+    if std::path::Path::new(&directory.path).join(&filename).exists() {
+        return Err(ApiError::Conflict(
+            "A model with this file name already exists in the directory".to_string(),
+        ));
+    }
+    let id = ModelDownloads::enqueue(&url, &filename, &directory.path, received.sha256.as_deref())
+        .or_internal("Error while queueing model download")?;
+    Ok(HttpResponse::Accepted().json(serde_json::json!({ "id": id })))
+}
+
+#[get("/api/llm/downloads")]
+async fn model_downloads_list() -> Result<HttpResponse, ApiError> {
+    let downloads =
+        ModelDownloads::get_downloads().or_internal("Error while getting model downloads")?;
+    let downloads_json = serde_json::to_string(&downloads)
+        .unwrap_or(String::from("Error serializing model downloads as JSON"));
+    Ok(HttpResponse::Ok().body(downloads_json))
+}
+
+#[get("/api/llm/downloads/{id}")]
+async fn model_download_status(id: web::Path<i64>) -> Result<HttpResponse, ApiError> {
+    let download = ModelDownloads::get_download(*id)
+        .or_internal("Error while getting model download")?
+        .ok_or_else(|| ApiError::NotFound("Model download not found".to_string()))?;
+    let download_json = serde_json::to_string(&download)
+        .unwrap_or(String::from("Error serializing model download as JSON"));
+    Ok(HttpResponse::Ok().body(download_json))
+}
+
+#[post("/api/llm/downloads/{id}/resume")]
+async fn model_download_resume(id: web::Path<i64>) -> Result<HttpResponse, ApiError> {
+    if !ModelDownloads::resume(*id).or_internal("Error while resuming model download")? {
+        return Err(ApiError::NotFound(
+            "No failed or cancelled download with this id".to_string(),
+        ));
+    }
+    Ok(HttpResponse::Ok().body("Model download queued again"))
+}
+
+#[delete("/api/llm/downloads/{id}")]
+async fn model_download_cancel(id: web::Path<i64>) -> Result<HttpResponse, ApiError> {
+    if !ModelDownloads::cancel(*id).or_internal("Error while cancelling model download")? {
+        return Err(ApiError::NotFound("No unfinished download with this id".to_string()));
+    }
+    Ok(HttpResponse::Ok().body("Model download cancelled"))
+}
+
+/// Reload the model in the background when the config changed its settings
+fn follow_config() {
+    actix_web::rt::task::spawn_blocking(|| {
+        if let Err(e) = llm::follow_config() {
+            error!("Model reload after config change failed: {}", e);
         }
+    });
+}
+
+#[routes]
+#[get("/api/llm/status")]
+#[get("/api/model/status")]
+async fn llm_status() -> Result<HttpResponse, ApiError> {
+    Ok(HttpResponse::Ok().json(llm::model_status()))
+}
+
+#[derive(Deserialize)]
+struct ModelReloadRequest {
+    llm_model_path: Option<String>,
+}
+
+#[post("/api/llm/reload")]
+async fn llm_reload(
+    received: Option<web::Json<ModelReloadRequest>>,
+) -> Result<HttpResponse, ApiError> {
+    if llm::model_status().state == "loading" {
+        return Err(ApiError::Conflict("A model is already being loaded".to_string()));
     }
+    if let Some(path) = received.and_then(|body| body.into_inner().llm_model_path) {
+        if !std::path::Path::new(&path).is_file() {
+            return Err(ApiError::BadRequest(format!("Model file {} not found", path)));
+        }
+        Database::set_llm_model_path(&path).or_internal("Error while updating model path")?;
+        llm_scanner::apply_detected_template(&path).or_internal("Error while detecting prompt template")?;
+    }
+    // Loading takes a while, progress is served by /api/llm/status and the event stream
+    actix_web::rt::task::spawn_blocking(|| {
+        if let Err(e) = llm::reload_model() {
+            error!("Model reload failed: {}", e);
+        }
+    });
+    Ok(HttpResponse::Accepted().json(llm::model_status()))
+}
+
+#[post("/api/llm/unload")]
+async fn llm_unload() -> Result<HttpResponse, ApiError> {
+    actix_web::rt::task::spawn_blocking(llm::unload_model)
+        .await
+        .or_internal("Error while unloading model")?;
+    Ok(HttpResponse::Ok().body("Model unloaded"))
 }
 
 //              Attitude Tracking
@@ -778,54 +3304,204 @@ struct AttitudeParams {
 }
 
 #[get("/api/attitude")]
-async fn get_attitude(query: web::Query<AttitudeParams>) -> HttpResponse {
-    match Database::get_attitude(query.companion_id, query.target_id, &query.target_type) {
-        Ok(Some(attitude)) => {
-            let attitude_json = serde_json::to_string(&attitude)
-                .unwrap_or(String::from("Error serializing attitude as JSON"));
-            HttpResponse::Ok().body(attitude_json)
+async fn get_attitude(query: web::Query<AttitudeParams>) -> Result<HttpResponse, ApiError> {
+    let attitude = Database::get_attitude(query.companion_id, query.target_id, &query.target_type)
+        .or_internal("Error while getting attitude")?
+        .ok_or_else(|| ApiError::NotFound("Attitude not found".to_string()))?;
+    let attitude_json = serde_json::to_string(&attitude)
+        .unwrap_or(String::from("Error serializing attitude as JSON"));
+    Ok(HttpResponse::Ok().body(attitude_json))
+}
+
+#[derive(Deserialize)]
+struct AttitudeSchemaParams {
+    companion_id: Option<i32>,
+    target_id: Option<i32>,
+    target_type: Option<String>,
+}
+
+#[get("/api/attitude/schema")]
+async fn get_attitude_schema(
+    query: web::Query<AttitudeSchemaParams>,
+) -> Result<HttpResponse, ApiError> {
+    // Live values are only included when a full attitude target is specified
+    let attitude = match (query.companion_id, query.target_id, &query.target_type) {
+        (Some(companion_id), Some(target_id), Some(target_type)) => {
+            Database::get_attitude(companion_id, target_id, target_type)
+                .or_internal("Error while getting attitude")?
         }
-        Ok(None) => HttpResponse::NotFound().body("Attitude not found"),
-        Err(e) => {
-            println!("Failed to get attitude: {}", e);
-            HttpResponse::InternalServerError()
-                .body("Error while getting attitude, check logs for more information")
+        _ => None,
+    };
+
+    let schema = attitude_dimensions::build_schema(attitude.as_ref());
+    let schema_json = serde_json::to_string(&schema)
+        .unwrap_or(String::from("Error serializing attitude schema as JSON"));
+    Ok(HttpResponse::Ok().body(schema_json))
+}
+
+#[derive(Deserialize)]
+struct AttitudeHistoryParams {
+    companion_id: Option<i32>,
+    target_id: Option<i32>,
+    target_type: Option<String>,
+    dimension: Option<String>,
+    range: Option<String>,
+    from: Option<chrono::DateTime<chrono::Utc>>,
+    to: Option<chrono::DateTime<chrono::Utc>>,
+}
+
+#[get("/api/attitude/history")]
+async fn get_attitude_history(
+    query: web::Query<AttitudeHistoryParams>,
+) -> Result<HttpResponse, ApiError> {
+    // The active companion's attitude toward the active user unless asked otherwise
+    let companion_id = query.companion_id.unwrap_or_else(Database::active_companion_id);
+    let target_id = query.target_id.unwrap_or_else(Database::active_user_id);
+    let target_type = query.target_type.as_deref().unwrap_or("user");
+    let dimensions =
+        attitude_history::parse_dimensions(query.dimension.as_deref()).map_err(ApiError::BadRequest)?;
+    // An explicit start wins over the range
+    let since = match query.from {
+        Some(from) => Some(from),
+        None => attitude_history::parse_range(query.range.as_deref().unwrap_or("30d"))
+            .map_err(ApiError::BadRequest)?
+            .map(|range| chrono::Utc::now() - range),
+    };
+    if let (Some(since), Some(to)) = (since, query.to) {
+        if since > to {
+            return Err(ApiError::BadRequest("The range ends before it starts".to_string()));
         }
     }
+    let history = AttitudeHistory::get(companion_id, target_id, target_type, &dimensions, since, query.to)
+        .or_internal("Error while getting attitude history")?;
+    Ok(HttpResponse::Ok().json(history))
+}
+
+#[get("/api/companion/mood")]
+async fn get_companion_mood() -> Result<HttpResponse, ApiError> {
+    let mood = Mood::get(Database::active_companion_id()).or_internal("Error while getting companion mood")?;
+    Ok(HttpResponse::Ok().json(mood))
+}
+
+#[get("/api/companion/mood/modifiers")]
+async fn mood_modifiers_list() -> Result<HttpResponse, ApiError> {
+    let modifiers = MoodModifier::active(Database::active_companion_id())
+        .or_internal("Error while getting mood modifiers")?;
+    let presets: Vec<serde_json::Value> = MOOD_PRESETS
+        .iter()
+        .map(|preset| {
+            serde_json::json!({
+                "name": preset.name,
+                "shifts": preset.shifts.iter().cloned().collect::<std::collections::BTreeMap<_, _>>(),
+                "instruction": preset.instruction,
+            })
+        })
+        .collect();
+    Ok(HttpResponse::Ok().json(serde_json::json!({ "modifiers": modifiers, "presets": presets })))
+}
+
+#[post("/api/companion/mood/modifiers")]
+async fn mood_modifiers_post(received: web::Json<NewMoodModifier>) -> Result<HttpResponse, ApiError> {
+    // curl -X POST -H "Content-Type: application/json" -d '{"name":"tired","hours":4}' http://localhost:3000/api/companion/mood/modifiers
+    match MoodModifier::set(Database::active_companion_id(), &received) {
+        Ok(modifier) => Ok(HttpResponse::Created().json(modifier)),
+        Err(rusqlite::Error::InvalidParameterName(e)) => Err(ApiError::BadRequest(e)),
+        Err(e) => Err(ApiError::internal("Error while setting mood modifier", e)),
+    }
+}
+
+/// Roll the day's mood now, whether daily_mood_roll is on or not
+#[post("/api/companion/mood/modifiers/roll")]
+async fn mood_modifiers_roll() -> Result<HttpResponse, ApiError> {
+    let modifier = MoodModifier::roll(Database::active_companion_id())
+        .or_internal("Error while rolling mood")?;
+    Ok(HttpResponse::Ok().json(serde_json::json!({ "modifier": modifier })))
+}
+
+#[delete("/api/companion/mood/modifiers")]
+async fn mood_modifiers_clear() -> Result<HttpResponse, ApiError> {
+    let cleared = MoodModifier::clear(Database::active_companion_id(), None)
+        .or_internal("Error while clearing mood modifiers")?;
+    Ok(HttpResponse::Ok().json(serde_json::json!({ "cleared": cleared })))
+}
+
+#[delete("/api/companion/mood/modifiers/{id}")]
+async fn mood_modifier_delete(id: web::Path<i32>) -> Result<HttpResponse, ApiError> {
+    let cleared = MoodModifier::clear(Database::active_companion_id(), Some(*id))
+        .or_internal("Error while clearing mood modifier")?;
+    if cleared == 0 {
+        return Err(ApiError::NotFound(format!("Mood modifier {} not found", id)));
+    }
+    Ok(HttpResponse::Ok().json(serde_json::json!({ "cleared": cleared })))
+}
+
+#[derive(Deserialize)]
+struct AttitudeWriteParams {
+    // Defaults to true, pass false to only create new attitudes
+    upsert: Option<bool>,
 }
 
 #[post("/api/attitude")]
-async fn create_or_update_attitude(received: web::Json<CompanionAttitude>) -> HttpResponse {
+async fn create_or_update_attitude(
+    received: web::Json<CompanionAttitude>,
+    query: web::Query<AttitudeWriteParams>,
+) -> Result<HttpResponse, ApiError> {
     let attitude = received.into_inner();
-    match Database::create_or_update_attitude(
+    let upsert = query.upsert.unwrap_or(true);
+
+    let mut errors = attitude_dimensions::validate_attitude_values(&attitude);
+    // Only look the target up once its type is known to be valid
+    if !errors.iter().any(|e| e.field == "target_type")
+        && !Database::attitude_target_exists(attitude.target_id, &attitude.target_type)
+            .or_internal("Error while creating/updating attitude")?
+    {
+        errors.push(FieldError::new(
+            "target_id",
+            format!("no {} with id {}", attitude.target_type, attitude.target_id),
+        ));
+    }
+    if !errors.is_empty() {
+        return Err(ApiError::Invalid(
+            "Invalid attitude".to_string(),
+            serde_json::json!(errors),
+        ));
+    }
+
+    let existing = Database::get_attitude(
+        attitude.companion_id,
+        attitude.target_id,
+        &attitude.target_type,
+    )
+    .or_internal("Error while creating/updating attitude")?;
+    if existing.is_some() && !upsert {
+        return Err(ApiError::Conflict(
+            "An attitude towards this target already exists, pass upsert=true to update it"
+                .to_string(),
+        ));
+    }
+
+    let id = Database::create_or_update_attitude(
         attitude.companion_id,
         attitude.target_id,
         &attitude.target_type,
         &attitude,
-    ) {
-        Ok(id) => HttpResponse::Ok().body(format!("Attitude created/updated with id: {}", id)),
-        Err(e) => {
-            println!("Failed to create/update attitude: {}", e);
-            HttpResponse::InternalServerError()
-                .body("Error while creating/updating attitude, check logs for more information")
-        }
+    )
+    .or_internal("Error while creating/updating attitude")?;
+    event_bus::publish("attitude_updated", serde_json::json!(attitude));
+    if existing.is_some() {
+        Ok(HttpResponse::Ok().body(format!("Attitude updated with id: {}", id)))
+    } else {
+        Ok(HttpResponse::Created().body(format!("Attitude created with id: {}", id)))
     }
 }
 
 #[get("/api/attitude/companion/{companion_id}")]
-async fn get_companion_attitudes(companion_id: web::Path<i32>) -> HttpResponse {
-    match Database::get_all_companion_attitudes(*companion_id) {
-        Ok(attitudes) => {
-            let attitudes_json = serde_json::to_string(&attitudes)
-                .unwrap_or(String::from("Error serializing attitudes as JSON"));
-            HttpResponse::Ok().body(attitudes_json)
-        }
-        Err(e) => {
-            println!("Failed to get companion attitudes: {}", e);
-            HttpResponse::InternalServerError()
-                .body("Error while getting companion attitudes, check logs for more information")
-        }
-    }
+async fn get_companion_attitudes(companion_id: web::Path<i32>) -> Result<HttpResponse, ApiError> {
+    let attitudes = Database::get_all_companion_attitudes(*companion_id)
+        .or_internal("Error while getting companion attitudes")?;
+    let attitudes_json = serde_json::to_string(&attitudes)
+        .unwrap_or(String::from("Error serializing attitudes as JSON"));
+    Ok(HttpResponse::Ok().body(attitudes_json))
 }
 
 #[derive(serde::Serialize)]
@@ -835,35 +3511,29 @@ struct AttitudeSummaryResponse {
 }
 
 #[get("/api/attitude/summary/{companion_id}/{user_id}")]
-async fn get_attitude_summary(path: web::Path<(i32, i32)>) -> HttpResponse {
+async fn get_attitude_summary(path: web::Path<(i32, i32)>) -> Result<HttpResponse, ApiError> {
     let (companion_id, user_id) = path.into_inner();
     
-    match Database::get_attitude(companion_id, user_id, "user") {
-        Ok(Some(attitude)) => {
-            let formatter = attitude_formatter::AttitudeFormatter::new();
-            let summary = formatter.generate_natural_language_summary(&attitude);
-            
-            let response = AttitudeSummaryResponse {
-                attitude,
-                summary,
-            };
-            
-            match serde_json::to_string(&response) {
-                Ok(json) => HttpResponse::Ok().body(json),
-                Err(e) => {
-                    println!("Failed to serialize attitude summary: {}", e);
-                    HttpResponse::InternalServerError()
-                        .body("Error while serializing attitude summary")
-                }
-            }
-        }
-        Ok(None) => HttpResponse::NotFound().body("Attitude not found"),
-        Err(e) => {
-            println!("Failed to get attitude for summary: {}", e);
-            HttpResponse::InternalServerError()
-                .body("Error while getting attitude for summary, check logs for more information")
-        }
+    let attitude = Database::get_attitude(companion_id, user_id, "user")
+        .or_internal("Error while getting attitude for summary")?
+        .ok_or_else(|| ApiError::NotFound("Attitude not found".to_string()))?;
+    let formatter = attitude_formatter::AttitudeFormatter::new();
+    let mut summary = formatter.generate_natural_language_summary(&attitude);
+    if let (Ok(companion_data), Ok(user_data)) = (
+        Database::get_companion_data_by_id(companion_id),
+        Database::get_user_data(),
+    ) {
+        summary = fill_placeholders(&summary, &companion_data, &user_data);
     }
+    
+    let response = AttitudeSummaryResponse {
+        attitude,
+        summary,
+    };
+    
+    let json =
+        serde_json::to_string(&response).or_internal("Error while serializing attitude summary")?;
+    Ok(HttpResponse::Ok().body(json))
 }
 
 #[derive(Deserialize)]
@@ -876,195 +3546,237 @@ struct AttitudeDimensionUpdate {
 }
 
 #[put("/api/attitude/dimension")]
-async fn update_attitude_dimension(received: web::Json<AttitudeDimensionUpdate>) -> HttpResponse {
+async fn update_attitude_dimension(
+    received: web::Json<AttitudeDimensionUpdate>,
+) -> Result<HttpResponse, ApiError> {
     let update = received.into_inner();
-    match Database::update_attitude_dimension(
+    // The dimension ends up in the query as a column name
+    if !attitude_dimensions::ATTITUDE_DIMENSIONS
+        .iter()
+        .any(|d| d.name == update.dimension)
+    {
+        return Err(ApiError::BadRequest(format!(
+            "Unknown attitude dimension '{}'",
+            update.dimension
+        )));
+    }
+    Database::update_attitude_dimension(
         update.companion_id,
         update.target_id,
         &update.target_type,
         &update.dimension,
         update.delta,
-    ) {
-        Ok(_) => HttpResponse::Ok().body("Attitude dimension updated!"),
-        Err(e) => {
-            println!("Failed to update attitude dimension: {}", e);
-            HttpResponse::InternalServerError()
-                .body("Error while updating attitude dimension, check logs for more information")
-        }
-    }
+    )
+    .or_internal("Error while updating attitude dimension")?;
+    Ok(HttpResponse::Ok().body("Attitude dimension updated!"))
 }
 
 #[get("/api/attitude/memories/{companion_id}")]
-async fn get_attitude_memories(companion_id: web::Path<i32>) -> HttpResponse {
-    match Database::get_priority_attitude_memories(*companion_id, 20) {
-        Ok(memories) => {
-            let memories_json = serde_json::to_string(&memories)
-                .unwrap_or(String::from("Error serializing attitude memories as JSON"));
-            HttpResponse::Ok().body(memories_json)
-        }
-        Err(e) => {
-            println!("Failed to get attitude memories: {}", e);
-            HttpResponse::InternalServerError()
-                .body("Error while getting attitude memories, check logs for more information")
-        }
-    }
+async fn get_attitude_memories(companion_id: web::Path<i32>) -> Result<HttpResponse, ApiError> {
+    let memories = Database::get_priority_attitude_memories(*companion_id, 20)
+        .or_internal("Error while getting attitude memories")?;
+    let memories_json = serde_json::to_string(&memories)
+        .unwrap_or(String::from("Error serializing attitude memories as JSON"));
+    Ok(HttpResponse::Ok().body(memories_json))
 }
 
 #[delete("/api/attitude/clear")]
-async fn clear_attitudes() -> HttpResponse {
-    let companion_id = 1;
-    let user_id = 1;
+async fn clear_attitudes() -> Result<HttpResponse, ApiError> {
+    let companion_id = Database::active_companion_id();
+    let users = Database::list_users().or_internal("Error while getting users")?;
 
-    let companion_persona = match Database::get_companion_data() {
-        Ok(companion_data) => companion_data.persona,
-        Err(e) => {
-            println!("Failed to get companion persona: {}", e);
-            return HttpResponse::InternalServerError()
-                .body("Error while getting companion data, check logs for more information");
-        }
-    };
+    let traits = PersonalityTraits::get(companion_id).or_internal("Error while getting personality traits")?;
 
-    match Database::clear_companion_attitudes(companion_id) {
-        Ok(_) => {
-            match Database::create_initial_user_attitude(companion_id, user_id, &companion_persona) {
-                Ok(_) => HttpResponse::Ok().body("Attitudes cleared and reset based on companion persona!"),
-                Err(e) => {
-                    println!("Failed to create initial attitude: {}", e);
-                    HttpResponse::InternalServerError()
-                        .body("Attitudes cleared but failed to create initial attitude, check logs for more information")
-                }
-            }
-        }
-        Err(e) => {
-            println!("Failed to clear attitudes: {}", e);
-            HttpResponse::InternalServerError()
-                .body("Error while clearing attitudes, check logs for more information")
-        }
+    Database::clear_companion_attitudes(companion_id).or_internal("Error while clearing attitudes")?;
+    for user_summary in users {
+        Database::create_initial_user_attitude(companion_id, user_summary.id, &traits)
+            .or_internal("Attitudes cleared but failed to create initial attitude")?;
     }
+    Ok(HttpResponse::Ok().body("Attitudes cleared and reset based on companion personality traits!"))
 }
 
 #[post("/api/persons/detect")]
-async fn detect_persons(received: web::Json<Prompt>) -> HttpResponse {
-    let companion_id = 1; // Default companion ID - in a real system this would come from context
-
-    match Database::detect_new_persons_in_message(&received.prompt, companion_id) {
-        Ok(new_person_ids) => {
-            let response = serde_json::json!({
-                "detected_persons": new_person_ids,
-                "message": format!("Detected {} new persons", new_person_ids.len())
-            });
-            HttpResponse::Ok().body(response.to_string())
-        }
-        Err(e) => {
-            println!("Failed to detect persons: {}", e);
-            HttpResponse::InternalServerError()
-                .body("Error while detecting persons, check logs for more information")
-        }
-    }
+async fn detect_persons(received: web::Json<Prompt>) -> Result<HttpResponse, ApiError> {
+    let companion_id = Database::active_companion_id();
+
+    let new_person_ids = Database::detect_new_persons_in_message(&received.prompt, companion_id)
+        .or_internal("Error while detecting persons")?;
+    let response = serde_json::json!({
+        "detected_persons": new_person_ids,
+        "message": format!("Detected {} new persons", new_person_ids.len())
+    });
+    Ok(HttpResponse::Ok().body(response.to_string()))
 }
 
 #[get("/api/persons")]
-async fn get_all_persons() -> HttpResponse {
-    match Database::get_all_third_party_individuals() {
-        Ok(persons) => {
-            let persons_json = serde_json::to_string(&persons)
-                .unwrap_or(String::from("Error serializing persons as JSON"));
-            HttpResponse::Ok().body(persons_json)
+async fn get_all_persons() -> Result<HttpResponse, ApiError> {
+    let persons = Database::get_all_third_party_individuals()
+        .or_internal("Error while getting persons")?;
+    let persons_json = serde_json::to_string(&persons)
+        .unwrap_or(String::from("Error serializing persons as JSON"));
+    Ok(HttpResponse::Ok().body(persons_json))
+}
+
+#[get("/api/persons/graph")]
+async fn get_persons_graph() -> Result<HttpResponse, ApiError> {
+    let graph = SocialGraph::build().or_internal("Error while building the relationship graph")?;
+    Ok(HttpResponse::Ok().json(graph))
+}
+
+// Both ends of a relationship have to be persons the active companion knows about
+fn check_relationship(relationship: &mut ThirdPartyRelationshipModify) -> Result<(), ApiError> {
+    relationship.validate().map_err(ApiError::BadRequest)?;
+    let persons = Database::get_all_third_party_individuals()
+        .or_internal("Error while getting persons")?;
+    for id in [relationship.from_party_id, relationship.to_party_id] {
+        if !persons.iter().any(|p| p.id == Some(id)) {
+            return Err(ApiError::NotFound(format!("Person {} not found", id)));
         }
-        Err(e) => {
-            println!("Failed to get all persons: {}", e);
-            HttpResponse::InternalServerError()
-                .body("Error while getting persons, check logs for more information")
+    }
+    Ok(())
+}
+
+fn relationship_error(error: rusqlite::Error) -> ApiError {
+    match error {
+        rusqlite::Error::SqliteFailure(e, _) if e.code == rusqlite::ErrorCode::ConstraintViolation => {
+            ApiError::Conflict("These persons already have a relationship in this direction".to_string())
         }
+        e => ApiError::internal("Error while saving relationship", e),
+    }
+}
+
+#[get("/api/persons/relationships")]
+async fn get_person_relationships() -> Result<HttpResponse, ApiError> {
+    let relationships = Database::get_third_party_relationships(Database::active_companion_id())
+        .or_internal("Error while getting relationships")?;
+    Ok(HttpResponse::Ok().json(relationships))
+}
+
+#[post("/api/persons/relationships")]
+async fn add_person_relationship(
+    received: web::Json<ThirdPartyRelationshipModify>,
+) -> Result<HttpResponse, ApiError> {
+    let mut relationship = received.into_inner();
+    check_relationship(&mut relationship)?;
+    let id = Database::add_third_party_relationship(&relationship).map_err(relationship_error)?;
+    Ok(HttpResponse::Created().json(serde_json::json!({ "id": id })))
+}
+
+#[put("/api/persons/relationships/{id}")]
+async fn edit_person_relationship(
+    id: web::Path<i32>,
+    received: web::Json<ThirdPartyRelationshipModify>,
+) -> Result<HttpResponse, ApiError> {
+    let id = id.into_inner();
+    let mut relationship = received.into_inner();
+    check_relationship(&mut relationship)?;
+    if !Database::edit_third_party_relationship(id, &relationship).map_err(relationship_error)? {
+        return Err(ApiError::NotFound(format!("Relationship {} not found", id)));
+    }
+    let relationship = Database::get_third_party_relationship(id)
+        .or_internal("Error while getting relationship")?;
+    Ok(HttpResponse::Ok().json(relationship))
+}
+
+#[delete("/api/persons/relationships/{id}")]
+async fn delete_person_relationship(id: web::Path<i32>) -> Result<HttpResponse, ApiError> {
+    let id = id.into_inner();
+    if !Database::delete_third_party_relationship(id).or_internal("Error while deleting relationship")? {
+        return Err(ApiError::NotFound(format!("Relationship {} not found", id)));
     }
+    Ok(HttpResponse::Ok().body(format!("Relationship {} deleted", id)))
 }
 
 #[get("/api/persons/{name}")]
-async fn get_person_by_name(name: web::Path<String>) -> HttpResponse {
-    match Database::get_third_party_by_name(&name) {
-        Ok(Some(person)) => {
-            let person_json = serde_json::to_string(&person)
-                .unwrap_or(String::from("Error serializing person as JSON"));
-            HttpResponse::Ok().body(person_json)
-        }
-        Ok(None) => HttpResponse::NotFound().body("Person not found"),
-        Err(e) => {
-            println!("Failed to get person by name: {}", e);
-            HttpResponse::InternalServerError()
-                .body("Error while getting person, check logs for more information")
-        }
+async fn get_person_by_name(name: web::Path<String>) -> Result<HttpResponse, ApiError> {
+    let person = Database::get_third_party_by_name(&name)
+        .or_internal("Error while getting person")?
+        .ok_or_else(|| ApiError::NotFound("Person not found".to_string()))?;
+    let person_json = serde_json::to_string(&person)
+        .unwrap_or(String::from("Error serializing person as JSON"));
+    Ok(HttpResponse::Ok().body(person_json))
+}
+
+#[delete("/api/persons/{id}")]
+async fn delete_person(id: web::Path<i32>) -> Result<HttpResponse, ApiError> {
+    let id = id.into_inner();
+    let deleted = Database::delete_third_party(id)
+        .or_internal("Error while deleting person")?
+        .ok_or_else(|| ApiError::NotFound(format!("Person {} not found", id)))?;
+    Ok(HttpResponse::Ok().json(serde_json::json!({ "id": id, "deleted": deleted })))
+}
+
+#[derive(Deserialize)]
+struct PersonMergeRequest {
+    /// Person that stays
+    survivor_id: i32,
+    /// Duplicate folded into the survivor and removed
+    merged_id: i32,
+}
+
+#[post("/api/persons/merge")]
+async fn merge_persons(received: web::Json<PersonMergeRequest>) -> Result<HttpResponse, ApiError> {
+    // curl -X POST -H "Content-Type: application/json" -d '{"survivor_id":3,"merged_id":7}' http://localhost:3000/api/persons/merge
+    match Database::merge_third_parties(received.survivor_id, received.merged_id) {
+        Ok(merge) => Ok(HttpResponse::Ok().json(merge)),
+        Err(rusqlite::Error::InvalidParameterName(e)) => Err(ApiError::BadRequest(e)),
+        Err(rusqlite::Error::QueryReturnedNoRows) => Err(ApiError::NotFound(format!(
+            "Person {} or {} not found",
+            received.survivor_id, received.merged_id
+        ))),
+        Err(e) => Err(ApiError::internal("Error while merging persons", e)),
     }
 }
 
 #[post("/api/interactions/plan")]
-async fn plan_interaction(received: web::Json<ThirdPartyInteraction>) -> HttpResponse {
-    match Database::plan_third_party_interaction(&received.into_inner()) {
-        Ok(interaction_id) => {
-            let response = serde_json::json!({
-                "success": true,
-                "interaction_id": interaction_id,
-                "message": "Interaction planned successfully"
-            });
-            HttpResponse::Ok().body(response.to_string())
-        }
-        Err(e) => {
-            println!("Failed to plan interaction: {}", e);
-            HttpResponse::InternalServerError()
-                .body("Error while planning interaction, check logs for more information")
-        }
-    }
+async fn plan_interaction(received: web::Json<ThirdPartyInteraction>) -> Result<HttpResponse, ApiError> {
+    let interaction_id = Database::plan_third_party_interaction(&received.into_inner())
+        .or_internal("Error while planning interaction")?;
+    let response = serde_json::json!({
+        "success": true,
+        "interaction_id": interaction_id,
+        "message": "Interaction planned successfully"
+    });
+    Ok(HttpResponse::Ok().body(response.to_string()))
 }
 
 #[get("/api/interactions/planned/{companion_id}")]
-async fn get_planned_interactions(companion_id: web::Path<i32>) -> HttpResponse {
-    match Database::get_planned_interactions(*companion_id, Some(10)) {
-        Ok(interactions) => {
-            let interactions_json = serde_json::to_string(&interactions)
-                .unwrap_or(String::from("Error serializing interactions as JSON"));
-            HttpResponse::Ok().body(interactions_json)
-        }
-        Err(e) => {
-            println!("Failed to get planned interactions: {}", e);
-            HttpResponse::InternalServerError()
-                .body("Error while getting planned interactions, check logs for more information")
-        }
-    }
+async fn get_planned_interactions(companion_id: web::Path<i32>) -> Result<HttpResponse, ApiError> {
+    let interactions = Database::get_planned_interactions(*companion_id, Some(10))
+        .or_internal("Error while getting planned interactions")?;
+    let interactions_json = serde_json::to_string(&interactions)
+        .unwrap_or(String::from("Error serializing interactions as JSON"));
+    Ok(HttpResponse::Ok().body(interactions_json))
 }
 
 #[post("/api/interactions/{interaction_id}/complete")]
-async fn complete_interaction(interaction_id: web::Path<i32>) -> HttpResponse {
-    match Database::generate_interaction_outcome(*interaction_id) {
-        Ok(outcome) => {
-            let response = serde_json::json!({
-                "success": true,
-                "outcome": outcome,
-                "message": "Interaction completed successfully"
-            });
-            HttpResponse::Ok().body(response.to_string())
+async fn complete_interaction(interaction_id: web::Path<i32>) -> Result<HttpResponse, ApiError> {
+    let outcome = match Database::generate_interaction_outcome(*interaction_id) {
+        Ok(outcome) => outcome,
+        Err(rusqlite::Error::QueryReturnedNoRows) => {
+            return Err(ApiError::NotFound(format!(
+                "Interaction {} not found",
+                interaction_id
+            )))
         }
-        Err(e) => {
-            println!("Failed to complete interaction: {}", e);
-            HttpResponse::InternalServerError()
-                .body("Error while completing interaction, check logs for more information")
-        }
-    }
+        Err(e) => return Err(ApiError::internal("Error while completing interaction", e)),
+    };
+    let response = serde_json::json!({
+        "success": true,
+        "outcome": outcome,
+        "message": "Interaction completed successfully"
+    });
+    Ok(HttpResponse::Ok().body(response.to_string()))
 }
 
 #[get("/api/interactions/history/{companion_id}/{third_party_id}")]
-async fn get_interaction_history(params: web::Path<(i32, i32)>) -> HttpResponse {
+async fn get_interaction_history(params: web::Path<(i32, i32)>) -> Result<HttpResponse, ApiError> {
     let (companion_id, third_party_id) = params.into_inner();
-    match Database::get_interaction_history(companion_id, third_party_id) {
-        Ok(history) => {
-            let history_json = serde_json::to_string(&history)
-                .unwrap_or(String::from("Error serializing history as JSON"));
-            HttpResponse::Ok().body(history_json)
-        }
-        Err(e) => {
-            println!("Failed to get interaction history: {}", e);
-            HttpResponse::InternalServerError()
-                .body("Error while getting interaction history, check logs for more information")
-        }
-    }
+    let history = Database::get_interaction_history(companion_id, third_party_id)
+        .or_internal("Error while getting interaction history")?;
+    let history_json = serde_json::to_string(&history)
+        .unwrap_or(String::from("Error serializing history as JSON"));
+    Ok(HttpResponse::Ok().body(history_json))
 }
 
 #[derive(Deserialize)]
@@ -1074,56 +3786,39 @@ struct InteractionQuery {
 }
 
 #[post("/api/interactions/detect")]
-async fn detect_interaction(received: web::Json<InteractionQuery>) -> HttpResponse {
-    match Database::detect_interaction_request(&received.message, received.companion_id) {
-        Ok(Some(interaction)) => {
+async fn detect_interaction(received: web::Json<InteractionQuery>) -> Result<HttpResponse, ApiError> {
+    match Database::detect_interaction_request(&received.message, received.companion_id)
+        .or_internal("Error while detecting interaction")?
+    {
+        Some(interaction) => {
             let interaction_json = serde_json::to_string(&interaction)
                 .unwrap_or(String::from("Error serializing interaction as JSON"));
-            HttpResponse::Ok().body(interaction_json)
-        }
-        Ok(None) => HttpResponse::Ok().body("{\"message\": \"No interaction detected\"}"),
-        Err(e) => {
-            println!("Failed to detect interaction: {}", e);
-            HttpResponse::InternalServerError()
-                .body("Error while detecting interaction, check logs for more information")
+            Ok(HttpResponse::Ok().body(interaction_json))
         }
+        None => Ok(HttpResponse::Ok().body("{\"message\": \"No interaction detected\"}")),
     }
 }
 
 #[post("/api/persons/cleanup-duplicates")]
-async fn cleanup_duplicate_third_parties() -> HttpResponse {
-    match Database::cleanup_duplicate_third_parties() {
-        Ok(count) => {
-            let response = serde_json::json!({
-                "message": format!("Cleaned up {} duplicate third party entries", count),
-                "cleaned_count": count
-            });
-            HttpResponse::Ok().body(response.to_string())
-        }
-        Err(e) => {
-            println!("Failed to cleanup duplicate third parties: {}", e);
-            HttpResponse::InternalServerError()
-                .body("Error while cleaning up duplicates, check logs for more information")
-        }
-    }
+async fn cleanup_duplicate_third_parties() -> Result<HttpResponse, ApiError> {
+    let count = Database::cleanup_duplicate_third_parties()
+        .or_internal("Error while cleaning up duplicates")?;
+    let response = serde_json::json!({
+        "message": format!("Cleaned up {} duplicate third party entries", count),
+        "cleaned_count": count
+    });
+    Ok(HttpResponse::Ok().body(response.to_string()))
 }
 
 #[post("/api/persons/cleanup-invalid")]
-async fn cleanup_invalid_third_parties() -> HttpResponse {
-    match Database::cleanup_invalid_third_parties() {
-        Ok(count) => {
-            let response = serde_json::json!({
-                "message": format!("Cleaned up {} invalid third party entries", count),
-                "cleaned_count": count
-            });
-            HttpResponse::Ok().body(response.to_string())
-        }
-        Err(e) => {
-            println!("Failed to cleanup invalid third parties: {}", e);
-            HttpResponse::InternalServerError()
-                .body("Error while cleaning up invalid entries, check logs for more information")
-        }
-    }
+async fn cleanup_invalid_third_parties() -> Result<HttpResponse, ApiError> {
+    let count = Database::cleanup_invalid_third_parties()
+        .or_internal("Error while cleaning up invalid entries")?;
+    let response = serde_json::json!({
+        "message": format!("Cleaned up {} invalid third party entries", count),
+        "cleaned_count": count
+    });
+    Ok(HttpResponse::Ok().body(response.to_string()))
 }
 
 #[derive(Deserialize)]
@@ -1145,44 +3840,16 @@ async fn estimate_response_time_endpoint(req: web::Json<EstimateRequest>) -> Htt
 }
 
 #[post("/api/prompt/stream")]
-async fn start_streaming_session(received: web::Json<StreamingRequest>) -> HttpResponse {
+async fn start_streaming_session(
+    received: web::Json<StreamingRequest>,
+) -> Result<HttpResponse, ApiError> {
+    // Same as /api/prompt/sse, with the session id chosen by the client
     let request = received.into_inner();
-    let session_id = request.session_id.clone();
-    let session_id_clone = session_id.clone();
-
-    // Start streaming session
-    let mut _rx = INFERENCE_OPTIMIZER.start_streaming_session(session_id.clone());
-
-    // In a real implementation, this would start async LLM inference
-    // For now, we'll simulate streaming by sending chunks
-    tokio::spawn(async move {
-        // Simulate processing chunks
-        for i in 1..=5 {
-            let chunk = StreamChunk {
-                request_id: session_id_clone.clone(),
-                content: format!("Chunk {} of response... ", i),
-                is_complete: i == 5,
-                token_count: Some(i * 10),
-            };
-
-            if INFERENCE_OPTIMIZER
-                .stream_chunk(&session_id_clone, chunk)
-                .is_err()
-            {
-                break;
-            }
-
-            tokio::time::sleep(tokio::time::Duration::from_millis(500)).await;
-        }
-
-        // End session
-        INFERENCE_OPTIMIZER.end_streaming_session(&session_id_clone);
-    });
-
-    HttpResponse::Ok().json(serde_json::json!({
-        "session_id": session_id,
-        "status": "streaming_started"
-    }))
+    if INFERENCE_OPTIMIZER.has_streaming_session(&request.session_id) {
+        return Err(ApiError::Conflict("Streaming session is already active".to_string()));
+    }
+    let receiver = start_streamed_reply(request.prompt, request.sampling, request.user_id, request.session_id)?;
+    Ok(sse_response(receiver))
 }
 
 #[get("/api/inference/stats")]
@@ -1197,6 +3864,12 @@ async fn get_inference_stats() -> HttpResponse {
             "batch_processed": stats.batch_processed,
             "streaming_sessions": stats.streaming_sessions
         },
+        "streaming": {
+            "streamed_tokens": stats.streamed_tokens,
+            "cancelled_streams": stats.cancelled_streams,
+            "avg_first_token_latency_ms": stats.avg_first_token_latency.as_millis(),
+            "avg_tokens_per_second": stats.avg_stream_tokens_per_second
+        },
         "cache": {
             "size": cache_size,
             "hits": cache_hits,
@@ -1216,44 +3889,158 @@ async fn cleanup_cache() -> HttpResponse {
     }))
 }
 
+#[derive(Deserialize)]
+struct MetricsQuery {
+    days: Option<u32>,
+    source: Option<String>,
+}
+
+fn check_metrics_source(source: Option<&str>) -> Result<(), ApiError> {
+    match source {
+        Some(source) if !["chat", "proactive", "alternative", "benchmark"].contains(&source) => Err(
+            ApiError::BadRequest("Invalid source, expected chat, proactive, alternative or benchmark".to_string()),
+        ),
+        _ => Ok(()),
+    }
+}
+
+#[get("/api/inference/metrics")]
+async fn get_inference_metrics(query: web::Query<MetricsQuery>) -> Result<HttpResponse, ApiError> {
+    let query = query.into_inner();
+    check_metrics_source(query.source.as_deref())?;
+    let summaries =
+        InferencePerformanceTracker::model_summaries(query.days.unwrap_or(30), query.source.as_deref())
+            .or_internal("Error while getting inference metrics")?;
+    Ok(HttpResponse::Ok().json(summaries))
+}
+
+#[derive(Deserialize)]
+struct MetricsTrendQuery {
+    days: Option<u32>,
+    bucket: Option<String>,
+    model_path: Option<String>,
+    source: Option<String>,
+}
+
+#[get("/api/inference/metrics/trend")]
+async fn get_inference_metrics_trend(query: web::Query<MetricsTrendQuery>) -> Result<HttpResponse, ApiError> {
+    let query = query.into_inner();
+    check_metrics_source(query.source.as_deref())?;
+    let bucket = TrendBucket::parse(query.bucket.as_deref().unwrap_or("day"))
+        .ok_or_else(|| ApiError::BadRequest("Invalid bucket, expected hour, day or week".to_string()))?;
+    let trend = InferencePerformanceTracker::metrics_trend(
+        query.days.unwrap_or(30),
+        bucket,
+        query.model_path.as_deref(),
+        query.source.as_deref(),
+    )
+    .or_internal("Error while getting inference metrics trend")?;
+    Ok(HttpResponse::Ok().json(trend))
+}
+
+#[get("/api/inference/metrics/best")]
+async fn get_best_inference_configurations(query: web::Query<MetricsQuery>) -> Result<HttpResponse, ApiError> {
+    let best = InferencePerformanceTracker::best_configurations(query.days.unwrap_or(30))
+        .or_internal("Error while getting best inference configurations")?;
+    Ok(HttpResponse::Ok().json(best))
+}
+
+#[get("/api/inference/recommendation")]
+async fn get_inference_recommendation(query: web::Query<MetricsQuery>) -> Result<HttpResponse, ApiError> {
+    let config_data = Database::get_config().or_internal("Error while getting config")?;
+    let model = llm::configured_model(&config_data);
+    let recommendation = InferencePerformanceTracker::recommend(
+        &model.model_path,
+        model.gpu_layers,
+        &model.device_type,
+        query.days.unwrap_or(30),
+    )
+    .or_internal("Error while getting inference recommendation")?;
+    Ok(HttpResponse::Ok().json(recommendation))
+}
+
+#[post("/api/inference/benchmark")]
+async fn run_inference_benchmark() -> Result<HttpResponse, ApiError> {
+    // curl -X POST http://localhost:3000/api/inference/benchmark
+    if BENCHMARK_RUNNING
+        .compare_exchange(false, true, Ordering::SeqCst, Ordering::SeqCst)
+        .is_err()
+    {
+        return Err(ApiError::Conflict("A benchmark is already running".to_string()));
+    }
+    let config_data = Database::get_config().or_internal("Error while getting config")?;
+    if !std::path::Path::new(&config_data.llm_model_path).is_file() {
+        BENCHMARK_RUNNING.store(false, Ordering::SeqCst);
+        return Err(ApiError::BadRequest(format!(
+            "Model file {} not found",
+            config_data.llm_model_path
+        )));
+    }
+    let report = web::block(llm::benchmark).await;
+    BENCHMARK_RUNNING.store(false, Ordering::SeqCst);
+    let report = report
+        .or_internal("Error while running benchmark")?
+        .or_internal("Error while running benchmark")?;
+    Ok(HttpResponse::Ok().json(report))
+}
+
 // Session Management Endpoints
 #[derive(Deserialize)]
 struct CreateSessionRequest {
     companion_id: i32,
     user_id: Option<i32>,
+    incognito: Option<bool>,
 }
 
 #[post("/api/session")]
 async fn create_session(
     session_manager: web::Data<SessionManager>,
     req: web::Json<CreateSessionRequest>,
-) -> HttpResponse {
-    match session_manager.create_session(req.companion_id, req.user_id) {
-        Ok(session) => {
-            let response_json =
-                serde_json::to_string(&session).unwrap_or_else(|_| "{}".to_string());
-            HttpResponse::Ok().body(response_json)
-        }
-        Err(e) => {
-            println!("Failed to create session: {}", e);
-            HttpResponse::InternalServerError().body(format!("Error creating session: {}", e))
-        }
+) -> Result<HttpResponse, ApiError> {
+    let session = if req.incognito.unwrap_or(false) {
+        session_manager.create_incognito_session(req.companion_id, req.user_id)
+    } else {
+        session_manager.create_session(req.companion_id, req.user_id)
     }
+    .or_internal("Error while creating session")?;
+    let response_json = serde_json::to_string(&session).unwrap_or_else(|_| "{}".to_string());
+    Ok(HttpResponse::Ok().body(response_json))
+}
+
+#[derive(Deserialize)]
+struct SessionListQuery {
+    active: Option<bool>,
+    limit: Option<usize>,
+}
+
+#[get("/api/session")]
+async fn list_sessions(query: web::Query<SessionListQuery>) -> Result<HttpResponse, ApiError> {
+    let sessions =
+        SessionManager::list_sessions(query.active.unwrap_or(false), query.limit.unwrap_or(50))
+            .or_internal("Error while listing sessions")?;
+    let response_json = serde_json::to_string(&sessions).unwrap_or_else(|_| "[]".to_string());
+    Ok(HttpResponse::Ok().body(response_json))
+}
+
+#[get("/api/session/{session_id}/history")]
+async fn get_session_history(session_id: web::Path<String>) -> Result<HttpResponse, ApiError> {
+    let history = SessionManager::session_history(&session_id)
+        .or_internal("Error while getting session history")?
+        .ok_or_else(|| ApiError::NotFound("Session not found".to_string()))?;
+    let response_json = serde_json::to_string(&history).unwrap_or_else(|_| "{}".to_string());
+    Ok(HttpResponse::Ok().body(response_json))
 }
 
 #[get("/api/session/{session_id}")]
 async fn get_session(
     session_manager: web::Data<SessionManager>,
     session_id: web::Path<String>,
-) -> HttpResponse {
-    match session_manager.get_session(&session_id) {
-        Ok(session) => {
-            let response_json =
-                serde_json::to_string(&session).unwrap_or_else(|_| "{}".to_string());
-            HttpResponse::Ok().body(response_json)
-        }
-        Err(e) => HttpResponse::NotFound().body(format!("Session not found: {}", e)),
-    }
+) -> Result<HttpResponse, ApiError> {
+    let session = session_manager
+        .get_session(&session_id)
+        .map_err(|e| ApiError::NotFound(format!("Session not found: {}", e)))?;
+    let response_json = serde_json::to_string(&session).unwrap_or_else(|_| "{}".to_string());
+    Ok(HttpResponse::Ok().body(response_json))
 }
 
 #[derive(Deserialize)]
@@ -1266,82 +4053,120 @@ struct UpdateAttitudeRequest {
 async fn update_session_attitude(
     session_manager: web::Data<SessionManager>,
     req: web::Json<UpdateAttitudeRequest>,
-) -> HttpResponse {
-    match session_manager.update_attitude(&req.session_id, req.attitude.clone()) {
-        Ok(()) => HttpResponse::Ok().body("Attitude updated successfully"),
-        Err(e) => {
-            println!("Failed to update session attitude: {}", e);
-            HttpResponse::InternalServerError().body(format!("Error updating attitude: {}", e))
-        }
-    }
+) -> Result<HttpResponse, ApiError> {
+    session_manager
+        .get_session(&req.session_id)
+        .map_err(|e| ApiError::NotFound(format!("Session not found: {}", e)))?;
+    session_manager
+        .update_attitude(&req.session_id, req.attitude.clone())
+        .or_internal("Error while updating session attitude")?;
+    Ok(HttpResponse::Ok().body("Attitude updated successfully"))
 }
 
 #[post("/api/session/{session_id}/end")]
 async fn end_session(
     session_manager: web::Data<SessionManager>,
     session_id: web::Path<String>,
-) -> HttpResponse {
-    match session_manager.end_session(&session_id) {
-        Ok(()) => HttpResponse::Ok().body("Session ended successfully"),
-        Err(e) => {
-            println!("Failed to end session: {}", e);
-            HttpResponse::InternalServerError().body(format!("Error ending session: {}", e))
-        }
+) -> Result<HttpResponse, ApiError> {
+    session_manager
+        .get_session(&session_id)
+        .map_err(|e| ApiError::NotFound(format!("Session not found: {}", e)))?;
+    session_manager
+        .end_session(&session_id)
+        .or_internal("Error while ending session")?;
+    Ok(HttpResponse::Ok().body("Session ended successfully"))
+}
+
+#[post("/api/session/{session_id}/prompt")]
+async fn session_prompt(
+    session_manager: web::Data<SessionManager>,
+    session_id: web::Path<String>,
+    received: web::Json<Prompt>,
+) -> Result<HttpResponse, ApiError> {
+    let text = Filters::apply(Stage::Prompt, &received.into_inner().prompt);
+    let session = session_manager
+        .get_session(&session_id)
+        .map_err(|e| ApiError::NotFound(format!("Session not found: {}", e)))?;
+    if !session.incognito {
+        return Err(ApiError::BadRequest(
+            "Only incognito sessions are prompted here, use /api/prompt instead".to_string(),
+        ));
+    }
+
+    // Detections stay in the session instead of becoming third parties
+    if let Ok(names) = Database::find_person_names_in_message(&text) {
+        let _ = session_manager.add_detected_persons(&session_id, names);
     }
+    let history = session_manager
+        .add_incognito_message(&session_id, false, &text)
+        .map_err(ApiError::BadRequest)?;
+    let session = format!("incognito_{}", session_id);
+    let reply = web::block(move || {
+        let _slot = wait_for_turn(&inference_queue::new_request_id("incognito"), Some(&session), &mut |_| true)?;
+        incognito_prompt(&text, &history).or_internal("Error while generating prompt")
+    })
+    .await
+    .or_internal("Error while generating prompt")??;
+    let _ = session_manager.add_incognito_message(&session_id, true, &reply);
+    Ok(HttpResponse::Ok().body(reply))
 }
 
 #[get("/api/session/stats/summary")]
-async fn get_session_stats(session_manager: web::Data<SessionManager>) -> HttpResponse {
-    match session_manager.get_session_stats() {
-        Ok(stats) => {
-            let stats_json = serde_json::to_string(&stats).unwrap_or_else(|_| "{}".to_string());
-            HttpResponse::Ok().body(stats_json)
-        }
-        Err(e) => {
-            println!("Failed to get session stats: {}", e);
-            HttpResponse::InternalServerError().body(format!("Error getting stats: {}", e))
-        }
-    }
+async fn get_session_stats(
+    session_manager: web::Data<SessionManager>,
+) -> Result<HttpResponse, ApiError> {
+    let stats = session_manager
+        .get_session_stats()
+        .or_internal("Error while getting session stats")?;
+    let stats_json = serde_json::to_string(&stats).unwrap_or_else(|_| "{}".to_string());
+    Ok(HttpResponse::Ok().body(stats_json))
+}
+
+#[get("/api/hardware")]
+async fn hardware() -> Result<HttpResponse, ApiError> {
+    let config_data = Database::get_config().or_internal("Error while getting config")?;
+    // nvidia-smi and friends take a moment, keep them off the worker thread
+    let report = web::block(move || hardware_probe::report(&config_data))
+        .await
+        .or_internal("Error while probing hardware")?;
+    Ok(HttpResponse::Ok().json(report))
+}
+
+#[post("/api/hardware/apply")]
+async fn hardware_apply() -> Result<HttpResponse, ApiError> {
+    let config_data = Database::get_config().or_internal("Error while getting config")?;
+    let report = web::block(move || hardware_probe::report(&config_data))
+        .await
+        .or_internal("Error while probing hardware")?;
+    let gpu_layers = report.recommended_gpu_layers.ok_or_else(|| {
+        ApiError::Conflict(format!(
+            "No recommendation available: {}",
+            report.warnings.join(", ")
+        ))
+    })?;
+    Database::set_gpu_layers(gpu_layers).or_internal("Error while updating gpu_layers")?;
+    follow_config();
+    Ok(HttpResponse::Ok().json(report))
 }
 
 #[get("/api/gpu/memory")]
-async fn get_gpu_memory() -> HttpResponse {
-    let config_data = match Database::get_config() {
-        Ok(cfg) => cfg,
-        Err(e) => {
-            println!("Failed to get config: {}", e);
-            return HttpResponse::InternalServerError().body("Failed to get configuration");
-        }
-    };
+async fn get_gpu_memory() -> Result<HttpResponse, ApiError> {
+    let config_data = Database::get_config().or_internal("Error while getting config")?;
 
     let allocator = GpuAllocator::new()
         .with_safety_margin(config_data.gpu_safety_margin)
         .with_min_free_vram(config_data.min_free_vram_mb);
 
-    match allocator.detect_gpu_memory(&config_data.device) {
-        Ok(gpu_info) => match serde_json::to_string(&gpu_info) {
-            Ok(json) => HttpResponse::Ok().body(json),
-            Err(e) => {
-                println!("Failed to serialize GPU memory info: {}", e);
-                HttpResponse::InternalServerError().body("Failed to serialize GPU info")
-            }
-        },
-        Err(e) => {
-            println!("Failed to detect GPU memory: {}", e);
-            HttpResponse::InternalServerError().body(format!("Failed to detect GPU memory: {}", e))
-        }
-    }
+    let gpu_info = allocator
+        .detect_gpu_memory(&config_data.device)
+        .or_internal("Error while detecting GPU memory")?;
+    let json = serde_json::to_string(&gpu_info).or_internal("Error while serializing GPU info")?;
+    Ok(HttpResponse::Ok().body(json))
 }
 
 #[get("/api/gpu/allocation")]
-async fn get_gpu_allocation() -> HttpResponse {
-    let config_data = match Database::get_config() {
-        Ok(cfg) => cfg,
-        Err(e) => {
-            println!("Failed to get config: {}", e);
-            return HttpResponse::InternalServerError().body("Failed to get configuration");
-        }
-    };
+async fn get_gpu_allocation() -> Result<HttpResponse, ApiError> {
+    let config_data = Database::get_config().or_internal("Error while getting config")?;
 
     if !config_data.dynamic_gpu_allocation {
         let static_allocation = LayerAllocation {
@@ -1351,51 +4176,356 @@ async fn get_gpu_allocation() -> HttpResponse {
             estimated_vram_usage_mb: 0,
             allocation_strategy: crate::gpu_allocator::AllocationStrategy::MaxGpu,
         };
-        match serde_json::to_string(&static_allocation) {
-            Ok(json) => return HttpResponse::Ok().body(json),
-            Err(e) => {
-                println!("Failed to serialize allocation: {}", e);
-                return HttpResponse::InternalServerError().body("Failed to serialize allocation");
-            }
-        }
+        let json = serde_json::to_string(&static_allocation)
+            .or_internal("Error while serializing allocation")?;
+        return Ok(HttpResponse::Ok().body(json));
+    }
+
+    // Sized from the model's own metadata whenever the VRAM can be measured
+    if let Ok(plan) = hardware_probe::plan_for_config(&config_data) {
+        let json = serde_json::to_string(&plan.allocation())
+            .or_internal("Error while serializing allocation")?;
+        return Ok(HttpResponse::Ok().body(json));
     }
 
     let allocator = GpuAllocator::new()
         .with_safety_margin(config_data.gpu_safety_margin)
         .with_min_free_vram(config_data.min_free_vram_mb);
 
-    match allocator.detect_gpu_memory(&config_data.device) {
-        Ok(gpu_info) => {
-            let vram_limit = if config_data.vram_limit_gb > 0 {
-                Some(config_data.vram_limit_gb as f32)
-            } else {
-                None
-            };
+    let gpu_info = allocator
+        .detect_gpu_memory(&config_data.device)
+        .or_internal("Error while detecting GPU memory")?;
+    let vram_limit = if config_data.vram_limit_gb > 0 {
+        Some(config_data.vram_limit_gb as f32)
+    } else {
+        None
+    };
 
-            // Estimate model size (this would ideally come from model metadata)
-            let estimated_model_size_mb = 4096; // 4GB default estimate
-            let estimated_total_layers = 32; // Default layer count
+    // Estimate model size (this would ideally come from model metadata)
+    let estimated_model_size_mb = 4096; // 4GB default estimate
+    let estimated_total_layers = 32; // Default layer count
 
-            let allocation = allocator.calculate_optimal_layers(
-                &gpu_info,
-                estimated_model_size_mb,
-                estimated_total_layers,
-                vram_limit,
-            );
+    let allocation = allocator.calculate_optimal_layers(
+        &gpu_info,
+        estimated_model_size_mb,
+        estimated_total_layers,
+        vram_limit,
+    );
 
-            match serde_json::to_string(&allocation) {
-                Ok(json) => HttpResponse::Ok().body(json),
-                Err(e) => {
-                    println!("Failed to serialize allocation: {}", e);
-                    HttpResponse::InternalServerError().body("Failed to serialize allocation")
+    let json = serde_json::to_string(&allocation).or_internal("Error while serializing allocation")?;
+    Ok(HttpResponse::Ok().body(json))
+}
+
+//              Journal
+
+#[derive(Deserialize)]
+struct JournalParams {
+    limit: Option<usize>,
+}
+
+#[get("/api/journal")]
+async fn get_journal(query: web::Query<JournalParams>) -> Result<HttpResponse, ApiError> {
+    let entries = Journal::get_entries(Database::active_companion_id(), query.limit.unwrap_or(30))
+        .or_internal("Error while getting journal entries")?;
+    let entries_json = serde_json::to_string(&entries)
+        .unwrap_or(String::from("Error serializing journal entries as JSON"));
+    Ok(HttpResponse::Ok().body(entries_json))
+}
+
+#[post("/api/journal/recap")]
+async fn write_daily_recap() -> Result<HttpResponse, ApiError> {
+    // Writes today's recap right away, regardless of the configured time
+    let content = daily_recap::write_recap(chrono::Local::now().date_naive())
+        .or_internal("Error while writing daily recap")?;
+    Ok(HttpResponse::Ok().body(content))
+}
+
+//              Proactive messages
+
+#[get("/api/proactive/status")]
+async fn get_proactive_status() -> Result<HttpResponse, ApiError> {
+    let status = Proactivity::status().or_internal("Error while getting proactive message status")?;
+    Ok(HttpResponse::Ok().json(status))
+}
+
+#[post("/api/proactive/opener")]
+async fn send_proactive_opener() -> Result<HttpResponse, ApiError> {
+    // Reaches out right away, regardless of the idle thresholds and quiet hours
+    if maintenance::generation_active() {
+        return Err(ApiError::Conflict("A reply is being generated".to_string()));
+    }
+    let state = Proactivity::idle_state()
+        .or_internal("Error while getting proactive message status")?
+        .ok_or_else(|| ApiError::BadRequest("The user has not written anything yet".to_string()))?;
+    let content = web::block(move || Proactivity::send_opener(&state))
+        .await
+        .or_internal("Error while sending proactive message")?
+        .or_internal("Error while sending proactive message")?;
+    Ok(HttpResponse::Ok().body(content))
+}
+
+//              Integrations
+
+#[derive(Deserialize)]
+struct WebhookRequest {
+    url: String,
+}
+
+#[get("/api/webhooks")]
+async fn get_webhooks() -> Result<HttpResponse, ApiError> {
+    let webhooks = DeliveryQueue::get_webhooks().or_internal("Error while getting webhooks")?;
+    let webhooks_json = serde_json::to_string(&webhooks)
+        .unwrap_or(String::from("Error serializing webhooks as JSON"));
+    Ok(HttpResponse::Ok().body(webhooks_json))
+}
+
+#[post("/api/webhooks")]
+async fn add_webhook(received: web::Json<WebhookRequest>) -> Result<HttpResponse, ApiError> {
+    match DeliveryQueue::add_webhook(received.url.trim()) {
+        Ok(id) => Ok(HttpResponse::Ok().json(serde_json::json!({ "id": id }))),
+        Err(rusqlite::Error::InvalidParameterName(e)) => Err(ApiError::BadRequest(e)),
+        Err(e) => Err(ApiError::internal("Error while adding webhook", e)),
+    }
+}
+
+#[delete("/api/webhooks/{id}")]
+async fn remove_webhook(id: web::Path<i64>) -> Result<HttpResponse, ApiError> {
+    if !DeliveryQueue::remove_webhook(*id).or_internal("Error while removing webhook")? {
+        return Err(ApiError::NotFound("Webhook not found".to_string()));
+    }
+    Ok(HttpResponse::Ok().body("Webhook removed!"))
+}
+
+#[get("/api/deliveries/failed")]
+async fn get_failed_deliveries() -> Result<HttpResponse, ApiError> {
+    let deliveries =
+        DeliveryQueue::get_failed().or_internal("Error while getting failed deliveries")?;
+    let deliveries_json = serde_json::to_string(&deliveries)
+        .unwrap_or(String::from("Error serializing deliveries as JSON"));
+    Ok(HttpResponse::Ok().body(deliveries_json))
+}
+
+#[post("/api/deliveries/failed/retry")]
+async fn retry_failed_deliveries() -> Result<HttpResponse, ApiError> {
+    let count =
+        DeliveryQueue::retry_all_failed().or_internal("Error while requeueing deliveries")?;
+    Ok(HttpResponse::Ok().json(serde_json::json!({ "requeued": count })))
+}
+
+#[post("/api/deliveries/{id}/retry")]
+async fn retry_delivery(id: web::Path<i64>) -> Result<HttpResponse, ApiError> {
+    if !DeliveryQueue::retry(*id).or_internal("Error while requeueing delivery")? {
+        return Err(ApiError::NotFound("Failed delivery not found".to_string()));
+    }
+    Ok(HttpResponse::Ok().body("Delivery requeued!"))
+}
+
+/// Queue the companion's reply for delivery to registered integrations
+fn notify_companion_message(content: &str) {
+    let data = serde_json::json!({ "ai": true, "content": content });
+    event_bus::publish("companion_message", data.clone());
+    if let Err(e) = DeliveryQueue::enqueue_webhook_event("companion_message", data) {
+        error!("Failed to queue companion message for delivery: {}", e);
+    }
+}
+
+/// Push the dimensions that moved during a prompt to real-time clients
+fn publish_attitude_change(previous: &CompanionAttitude, current: &CompanionAttitude) {
+    let mut changes = serde_json::Map::new();
+    for dimension in attitude_dimensions::ATTITUDE_DIMENSIONS.iter() {
+        let before = attitude_dimensions::dimension_value(previous, dimension.name);
+        let after = attitude_dimensions::dimension_value(current, dimension.name);
+        if let (Some(before), Some(after)) = (before, after) {
+            if (after - before).abs() > f32::EPSILON {
+                changes.insert(
+                    dimension.name.to_string(),
+                    serde_json::json!({ "from": before, "to": after }),
+                );
+            }
+        }
+    }
+    if !changes.is_empty() {
+        info!(
+            target: "attitude",
+            companion_id = current.companion_id,
+            target_id = current.target_id,
+            target_type = %current.target_type,
+            changes = %serde_json::Value::Object(changes.clone()),
+            "Attitude changed"
+        );
+        event_bus::publish(
+            "attitude_changed",
+            serde_json::json!({
+                "companion_id": current.companion_id,
+                "target_id": current.target_id,
+                "target_type": current.target_type,
+                "changes": changes,
+            }),
+        );
+    }
+}
+
+//              Events
+
+#[derive(Deserialize)]
+struct EventsParams {
+    cursor: Option<u64>,
+}
+
+/// Cursor from the Last-Event-ID header browsers send on reconnect, or the cursor query parameter
+fn resume_cursor(request: &actix_web::HttpRequest, query: &EventsParams) -> Option<u64> {
+    request
+        .headers()
+        .get("Last-Event-ID")
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.trim().parse().ok())
+        .or(query.cursor)
+}
+
+#[get("/api/events")]
+async fn stream_events(
+    request: actix_web::HttpRequest,
+    query: web::Query<EventsParams>,
+) -> HttpResponse {
+    // curl -N -H "Last-Event-ID: 41" http://localhost:3000/api/events
+    let cursor = resume_cursor(&request, &query);
+    HttpResponse::Ok()
+        .content_type("text/event-stream")
+        .insert_header(("Cache-Control", "no-cache"))
+        .streaming(event_bus::sse_stream(cursor))
+}
+
+#[get("/api/events/replay")]
+async fn replay_events(query: web::Query<EventsParams>) -> HttpResponse {
+    // Clients without SSE can catch up with the same cursor and then keep polling latest_id
+    let replay = event_bus::EVENT_BUS.events_after(Some(query.cursor.unwrap_or(0)));
+    let replay_json = serde_json::to_string(&replay)
+        .unwrap_or(String::from("Error serializing events as JSON"));
+    HttpResponse::Ok().body(replay_json)
+}
+
+fn socket_event(event: &event_bus::Event) -> serde_json::Value {
+    serde_json::json!({
+        "type": "event",
+        "id": event.id,
+        "event": event.event,
+        "data": event.data,
+        "created_at": event.created_at,
+    })
+}
+
+/// Messages a WebSocket client sends
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum SocketMessage {
+    Prompt {
+        prompt: String,
+        #[serde(default)]
+        sampling: SamplingOverrides,
+        #[serde(default)]
+        user_id: Option<i32>,
+    },
+    // {"type": "typing", "typing": true} while the user writes, shared with the other clients
+    Typing { typing: bool },
+}
+
+#[get("/ws")]
+async fn chat_socket(
+    request: actix_web::HttpRequest,
+    payload: web::Payload,
+    query: web::Query<EventsParams>,
+) -> Result<HttpResponse, actix_web::Error> {
+    // Send {"prompt": "..."} to chat, replies arrive as token/done/error messages.
+    // Typing status, attitude changes, mentions and detected persons arrive as event messages
+    let (response, sender, mut receiver) = websocket::upgrade(&request, payload)?;
+    let caller = rate_limit::caller(&request);
+
+    let (replay, mut events) = event_bus::EVENT_BUS.subscribe(query.cursor);
+    let event_sender = sender.clone();
+    actix_web::rt::spawn(async move {
+        for event in &replay.events {
+            event_sender.json(&socket_event(event));
+        }
+        loop {
+            let event = tokio::select! {
+                _ = event_sender.closed() => break,
+                event = events.recv() => event,
+            };
+            match event {
+                Ok(event) => {
+                    event_sender.json(&socket_event(&event));
+                }
+                Err(tokio::sync::broadcast::error::RecvError::Lagged(skipped)) => {
+                    warn!("WebSocket client fell behind, skipped {} events", skipped);
                 }
+                Err(tokio::sync::broadcast::error::RecvError::Closed) => break,
             }
         }
-        Err(e) => {
-            println!("Failed to detect GPU memory: {}", e);
-            HttpResponse::InternalServerError().body(format!("Failed to detect GPU memory: {}", e))
+    });
+
+    actix_web::rt::spawn(async move {
+        while let Some(text) = receiver.next_text().await {
+            let (prompt, sampling, user_id) = match serde_json::from_str(&text) {
+                Ok(SocketMessage::Prompt { prompt, sampling, user_id }) => (prompt, sampling, user_id),
+                Ok(SocketMessage::Typing { typing }) => {
+                    event_bus::publish("user_typing", serde_json::json!({ "typing": typing }));
+                    continue;
+                }
+                Err(e) => {
+                    sender.json(&serde_json::json!({
+                        "type": "error",
+                        "error": format!("Invalid message: {}", e),
+                    }));
+                    continue;
+                }
+            };
+            if let Err(e) = rate_limit::check(&caller) {
+                sender.json(&serde_json::json!({ "type": "error", "error": e.to_string() }));
+                continue;
+            }
+            let mut chunks = match start_streamed_reply(prompt, sampling, user_id, inference_queue::new_request_id("ws")) {
+                Ok(chunks) => chunks,
+                Err(e) => {
+                    sender.json(&serde_json::json!({ "type": "error", "error": e.to_string() }));
+                    continue;
+                }
+            };
+            while let Some(chunk) = chunks.recv().await {
+                if !sender.json(&chunk.to_ws()) {
+                    return;
+                }
+            }
         }
-    }
+    });
+
+    Ok(response)
+}
+
+//              Development
+
+#[cfg(feature = "dev")]
+#[derive(Deserialize)]
+struct SeedRequest {
+    message_count: Option<usize>,
+    seed: Option<u64>,
+}
+
+#[cfg(feature = "dev")]
+#[post("/api/dev/seed")]
+async fn dev_seed_database(received: Option<web::Json<SeedRequest>>) -> Result<HttpResponse, ApiError> {
+    let (message_count, seed) = match received {
+        Some(request) => (request.message_count.unwrap_or(400).min(5000), request.seed.unwrap_or(42)),
+        None => (400, 42),
+    };
+    let summary = dev_seed::seed(message_count, seed).or_internal("Error while seeding database")?;
+    info!("🌱 Seeded database with development fixtures");
+    Ok(HttpResponse::Ok().json(summary))
+}
+
+/// Routes that only exist in development builds
+fn dev_routes(_cfg: &mut web::ServiceConfig) {
+    #[cfg(feature = "dev")]
+    _cfg.service(dev_seed_database);
 }
 
 //
@@ -1417,10 +4547,16 @@ fn estimate_response_time_enhanced(msg: &str) -> ResponseEstimate {
         }
     };
 
+    // Dynamic allocation records its metrics under the layer count it picked
+    let gpu_layers = match hardware_probe::last_plan() {
+        Some(plan) if db_config.dynamic_gpu_allocation => plan.gpu_layers,
+        _ => db_config.gpu_layers,
+    };
     let model_config = ModelConfig {
         model_path: db_config.llm_model_path,
-        gpu_layers: db_config.gpu_layers as i32,
+        gpu_layers: gpu_layers as i32,
         device_type: db_config.device.to_string(),
+        vram_plan: None,
     };
 
     // Use the performance tracker for accurate estimation
@@ -1449,73 +4585,449 @@ fn estimate_response_time(msg: &str) -> u32 {
 
 #[actix_web::main]
 async fn main() -> std::io::Result<()> {
-    let port: u16 = 3000;
-    let hostname: &str = "0.0.0.0";
+    let settings = match settings::init() {
+        Ok(settings) => settings,
+        Err(e) => {
+            eprintln!("❌ {}", e);
+            std::process::exit(1);
+        }
+    };
+    logging::init();
+    let port = settings.port;
+    let hostname = settings.host.as_str();
+    if let Some(config_file) = &settings.config_file {
+        info!("Settings read from {}", config_file.display());
+    }
+    info!(
+        "Keeping data in {}, the database in {}",
+        settings.data_dir.display(),
+        settings.database.display()
+    );
+    if web_ui::on_disk(&settings.ui_dir) {
+        info!("Serving the web UI from {}", settings.ui_dir.display());
+    }
 
     match Database::new() {
         Ok(_) => {}
-        Err(e) => eprintln!("⚠️ Failed to connect to sqlite database: {}\n", e),
+        Err(e) => error!("Failed to connect to sqlite database: {}", e),
+    }
+
+    match Conversations::create() {
+        Ok(_) => {}
+        Err(e) => error!("Failed to create conversations table in sqlite database: {}", e),
     }
 
     match LongTermMem::connect() {
         Ok(_) => {}
-        Err(e) => eprintln!("⚠️ Failed to connect to tantivy: {}\n", e),
+        Err(e) => error!("Failed to connect to tantivy: {}", e),
     }
 
     match DialogueTuning::create() {
         Ok(_) => {}
-        Err(e) => eprintln!(
-            "⚠️ Failed to create dialogue tuning table in sqlite database: {}\n",
-            e
-        ),
+        Err(e) => error!("Failed to create dialogue tuning table in sqlite database: {}", e),
     }
 
-    println!("AI Companion v1 successfully launched! 🚀\n");
+    match Lorebook::create() {
+        Ok(_) => {}
+        Err(e) => error!("Failed to create lorebook table in sqlite database: {}", e),
+    }
+    match Filters::create() {
+        Ok(_) => {}
+        Err(e) => error!("Failed to create content filter tables in sqlite database: {}", e),
+    }
 
-    println!("Listening on:\n  -> http://{}:{}/", hostname, port);
-    println!("  -> http://localhost:{}/\n", port);
-    println!("https://github.com/Hukasx0/ai-companion\n   By Hubert \"Hukasx0\" Kasperek\n");
+    match Journal::create() {
+        Ok(_) => {}
+        Err(e) => error!("Failed to create journal table in sqlite database: {}", e),
+    }
+    match MessageAttempts::create() {
+        Ok(_) => {}
+        Err(e) => error!("Failed to create message attempts table in sqlite database: {}", e),
+    }
+    match AttitudeHistory::create() {
+        Ok(_) => {}
+        Err(e) => error!("Failed to create attitude history table in sqlite database: {}", e),
+    }
+    match MessageSearch::create() {
+        Ok(_) => {}
+        Err(e) => error!("Failed to create message search index in sqlite database: {}", e),
+    }
+    match MessageImages::create() {
+        Ok(_) => {}
+        Err(e) => error!("Failed to create message image tables in sqlite database: {}", e),
+    }
+    match MessageAudio::create() {
+        Ok(_) => {}
+        Err(e) => error!("Failed to create message audio table in sqlite database: {}", e),
+    }
+    match Goals::create() {
+        Ok(_) => {}
+        Err(e) => error!("Failed to create goals table in sqlite database: {}", e),
+    }
+    match PersonalityTraits::create() {
+        Ok(_) => {}
+        Err(e) => error!("Failed to create personality traits table in sqlite database: {}", e),
+    }
+    match TemplateVariables::create() {
+        Ok(_) => {}
+        Err(e) => error!("Failed to create template variables table in sqlite database: {}", e),
+    }
+    match MoodModifier::create() {
+        Ok(_) => {}
+        Err(e) => error!("Failed to create mood modifier tables in sqlite database: {}", e),
+    }
+    match InferenceOptimizer::create_stats_table().and_then(|_| INFERENCE_OPTIMIZER.restore_stats()) {
+        Ok(_) => {}
+        Err(e) => error!("Failed to restore inference optimizer stats: {}", e),
+    }
+    match Mood::create() {
+        Ok(_) => {}
+        Err(e) => error!("Failed to create companion mood table in sqlite database: {}", e),
+    }
+    match AvatarExpressions::create() {
+        Ok(_) => {}
+        Err(e) => error!("Failed to create avatar expressions table in sqlite database: {}", e),
+    }
+    match Scene::create() {
+        Ok(_) => {}
+        Err(e) => error!("Failed to create scenes table in sqlite database: {}", e),
+    }
+    match rate_limit::create() {
+        Ok(_) => {}
+        Err(e) => error!("Failed to create rate limit table in sqlite database: {}", e),
+    }
+    if Database::get_global_config().is_ok_and(|config_data| config_data.rate_limit_persist) {
+        match rate_limit::restore() {
+            Ok(restored) => info!("Restored {} rate-limited requests from before the restart", restored),
+            Err(e) => error!("Failed to restore rate limit counters: {}", e),
+        }
+    }
+    match MessageFeedback::create() {
+        Ok(_) => {}
+        Err(e) => error!("Failed to create message feedback table in sqlite database: {}", e),
+    }
+    match Proactivity::create() {
+        Ok(_) => {}
+        Err(e) => error!("Failed to create proactive messages table in sqlite database: {}", e),
+    }
+    match MemoryProposals::create() {
+        Ok(_) => {}
+        Err(e) => error!("Failed to create memory proposals table in sqlite database: {}", e),
+    }
+    match MemoryConsolidation::create() {
+        Ok(_) => {}
+        Err(e) => error!("Failed to create memory consolidation table in sqlite database: {}", e),
+    }
+
+    match PromptTemplates::create() {
+        Ok(_) => {}
+        Err(e) => error!("Failed to create prompt templates table in sqlite database: {}", e),
+    }
+
+    match PromptLayout::create() {
+        Ok(_) => {}
+        Err(e) => error!("Failed to create prompt layout table in sqlite database: {}", e),
+    }
+
+    match ConfigProfiles::create() {
+        Ok(_) => {}
+        Err(e) => error!("Failed to create config profiles table in sqlite database: {}", e),
+    }
+
+    match MemoryEmbeddings::create() {
+        Ok(_) => {}
+        Err(e) => error!("Failed to create memory embeddings table in sqlite database: {}", e),
+    }
+
+    // Decide the role before any worker starts, so a second instance never runs jobs twice
+    match instance_lock::create() {
+        Ok(_) => {
+            if !instance_lock::elect() {
+                instance_lock::report_role();
+            }
+            actix_web::rt::spawn(instance_lock::run_elector());
+        }
+        Err(e) => error!("Failed to create instance lease table in sqlite database: {}", e),
+    }
+
+    match DeliveryQueue::create() {
+        Ok(_) => {
+            actix_web::rt::spawn(DeliveryQueue::run_worker());
+        }
+        Err(e) => error!("Failed to create delivery queue tables in sqlite database: {}", e),
+    }
+
+    match ModelDownloads::create() {
+        Ok(_) => {
+            actix_web::rt::spawn(ModelDownloads::run_worker());
+        }
+        Err(e) => error!("Failed to create model downloads table in sqlite database: {}", e),
+    }
+
+    actix_web::rt::spawn(maintenance::run_scheduler());
+
+    #[cfg(feature = "discord")]
+    match discord::create() {
+        Ok(_) => {
+            if let Some(settings) = discord::DiscordSettings::from_env() {
+                actix_web::rt::spawn(discord::run_bot(settings, discord_reply));
+            }
+        }
+        Err(e) => error!("Failed to create Discord tables in sqlite database: {}", e),
+    }
 
     // Initialize session manager with 30 minute timeout
-    let session_manager = web::Data::new(SessionManager::new(30));
+    let session_manager = SessionManager::new(30);
+    match SessionManager::create() {
+        Ok(_) => match session_manager.restore_sessions() {
+            Ok(0) => {}
+            Ok(restored) => info!("Restored {} active sessions", restored),
+            Err(e) => error!("Failed to restore sessions: {}", e),
+        },
+        Err(e) => error!("Failed to create sessions table in sqlite database: {}", e),
+    }
+    actix_web::rt::spawn(session_manager::run_sweeper(session_manager.clone()));
+    let stopping_sessions = session_manager.clone();
+    let session_manager = web::Data::new(session_manager);
+
+    match auth::create() {
+        Ok(_) => {
+            if !auth::enabled().unwrap_or(true) {
+                warn!(
+                    "🔓 No password or API key set, anyone who can reach port {} can use the API. Set one up in the web UI or with POST /api/auth/token from this machine",
+                    port
+                );
+            }
+        }
+        Err(e) => error!("Failed to set up authentication: {}", e),
+    }
+
+    // Read-only instances don't generate, they have no use for the model
+    if settings.preload_model && instance_lock::is_leader() {
+        // Replies wait for the model meanwhile, /api/ready tells when it is loaded
+        actix_web::rt::task::spawn_blocking(|| {
+            if let Err(e) = llm::preload_model() {
+                error!("Failed to preload model: {}", e);
+            }
+        });
+    }
 
-    HttpServer::new(move || {
+    info!("AI Companion v1 successfully launched! 🚀");
+    info!("Listening on http://{}:{}/ and http://localhost:{}/", hostname, port, port);
+    println!("https://github.com/Hukasx0/ai-companion\n   By Hubert \"Hukasx0\" Kasperek\n");
+
+    let server = HttpServer::new(move || {
         App::new()
             .app_data(session_manager.clone())
-            .service(index)
-            .service(js)
-            .service(js2)
-            .service(css)
-            .service(project_logo)
-            .service(companion_avatar_img)
+            // Malformed bodies, queries and paths get the same JSON error body as handler errors
+            .app_data(web::JsonConfig::default().error_handler(|err, _| {
+                ApiError::BadRequest(err.to_string()).into()
+            }))
+            .app_data(web::QueryConfig::default().error_handler(|err, _| {
+                ApiError::BadRequest(err.to_string()).into()
+            }))
+            .app_data(web::PathConfig::default().error_handler(|err, _| {
+                ApiError::BadRequest(err.to_string()).into()
+            }))
+            // Runs after authorization, so anonymous clients can't use up anyone's allowance
+            .wrap_fn(|req, srv| {
+                if rate_limit::applies_to(req.method(), req.path()) {
+                    if let Err(e) = rate_limit::check(&rate_limit::caller(req.request())) {
+                        return Either::Left(future::ready(Err(e.into())));
+                    }
+                }
+                Either::Right(srv.call(req))
+            })
+            .wrap_fn(|req, srv| {
+                // Instances without the database lock serve reads only
+                if !instance_lock::is_leader()
+                    && instance_lock::requires_leader(req.method(), req.path())
+                {
+                    return Either::Left(future::ready(Err(ApiError::Unavailable(
+                        instance_lock::READ_ONLY_MESSAGE.to_string(),
+                    )
+                    .into())));
+                }
+                Either::Right(srv.call(req))
+            })
+            // Runs before the middleware above, anonymous clients learn nothing about the instance
+            .wrap_fn(|req, srv| {
+                if let Err(e) = auth::authorize(req.request()) {
+                    return Either::Left(future::ready(Err(e.into())));
+                }
+                Either::Right(srv.call(req))
+            })
+            // Registered last so it runs first, everything logged while handling a request carries its span
+            .wrap_fn(|req, srv| {
+                let span = tracing::info_span!(
+                    "request",
+                    request_id = %uuid::Uuid::new_v4(),
+                    method = %req.method(),
+                    path = %req.path(),
+                );
+                let started = std::time::Instant::now();
+                let response = span.in_scope(|| srv.call(req));
+                async move {
+                    let response = response.await;
+                    let elapsed_ms = started.elapsed().as_millis() as u64;
+                    match &response {
+                        Ok(res) if res.status().is_server_error() => {
+                            warn!(status = res.status().as_u16(), elapsed_ms, "Request failed")
+                        }
+                        Ok(res) => debug!(status = res.status().as_u16(), elapsed_ms, "Request finished"),
+                        Err(e) => info!(status = e.as_response_error().status_code().as_u16(), elapsed_ms, "Request rejected"),
+                    }
+                    response
+                }
+                .instrument(span)
+            })
+            .service(companion_avatar_shared)
             .service(companion_avatar_custom)
+            .service(avatar_expression_image)
             .service(message)
             .service(clear_messages)
+            .service(search_messages)
+            .service(message_import)
+            .service(message_import_job)
+            .service(message_export)
+            .service(usage)
             .service(message_id)
             .service(message_put)
             .service(message_delete)
+            .service(message_attempts_list)
+            .service(message_audio)
+            .service(message_attempt_promote)
+            .service(message_alternatives)
+            .service(message_feedback_get)
+            .service(message_feedback_post)
+            .service(message_feedback_delete)
             .service(message_post)
             .service(companion)
             .service(companion_edit_data)
             .service(companion_card)
+            .service(export_companion_card)
             .service(companion_character_json)
             .service(get_companion_character_json)
             .service(companion_avatar)
+            .service(avatar_expressions_list)
+            .service(avatar_expressions_put)
+            .service(avatar_expressions_delete)
+            .service(companions_list)
+            .service(companions_get)
+            .service(companions_put)
+            .service(companions_config)
+            .service(companions_config_put)
+            .service(companions_traits)
+            .service(companions_traits_put)
+            .service(companions_trait_put)
+            .service(companions_trait_delete)
+            .service(variables_list)
+            .service(variables_put)
+            .service(variables_delete)
+            .service(companions_create)
+            .service(companions_activate)
+            .service(conversations_list)
+            .service(conversations_create)
+            .service(conversations_put)
+            .service(conversations_activate)
+            .service(conversations_archive)
+            .service(conversations_unarchive)
+            .service(conversations_overrides)
+            .service(conversations_overrides_put)
+            .service(scene_get)
+            .service(scene_put)
+            .service(scene_delete)
+            .service(companions_delete)
+            .service(export_persona_pack)
+            .service(preview_persona_pack)
+            .service(import_persona_pack)
             .service(user)
             .service(user_put)
+            .service(users_list)
+            .service(users_create)
+            .service(users_activate)
+            .service(users_get)
+            .service(users_put)
+            .service(users_delete)
             .service(add_memory_long_term_message)
             .service(erase_long_term)
             .service(add_tuning_message)
             .service(erase_tuning_message)
+            .service(get_memory_proposals)
+            .service(resolve_memory_proposals)
+            .service(consolidate_memories)
+            .service(lorebook_list)
+            .service(lorebook_add)
+            .service(lorebook_edit)
+            .service(lorebook_delete)
+            .service(goals_list)
+            .service(goals_add)
+            .service(goals_get)
+            .service(goals_edit)
+            .service(goals_delete)
+            .service(filters_list)
+            .service(filters_set)
+            .service(filter_rules_add)
+            .service(filter_rules_edit)
+            .service(filter_rules_delete)
+            .service(filters_preview)
             .service(prompt_message)
+            .service(prompt_message_sse)
+            .service(speech_to_text)
+            .service(prompt_audio)
+            .service(preview_prompt)
+            .service(debug_context)
             .service(regenerate_prompt)
+            .service(prompt_queue)
+            .service(prompt_queue_request)
+            .service(prompt_queue_delete)
+            .service(prompt_cancel)
             .service(config)
             .service(config_post)
+            .service(config_profiles_list)
+            .service(config_profiles_get)
+            .service(config_profiles_create)
+            .service(config_profiles_put)
+            .service(config_profiles_delete)
+            .service(config_profiles_activate)
+            .service(prompt_templates_list)
+            .service(prompt_templates_get)
+            .service(prompt_templates_create)
+            .service(prompt_templates_put)
+            .service(prompt_templates_delete)
+            .service(prompt_layout_get)
+            .service(prompt_layout_put)
+            .service(prompt_layout_delete)
+            .service(auth_token_status)
+            .service(auth_token_set)
+            .service(auth_token_clear)
+            .service(auth_login)
+            .service(auth_logout)
+            .service(auth_status)
+            .service(auth_setup)
+            .service(auth_password_set)
+            .service(auth_password_clear)
             .service(get_llm_models)
             .service(get_llm_directories)
             .service(add_llm_directory)
             .service(remove_llm_directory)
+            .service(model_downloads_start)
+            .service(model_downloads_list)
+            .service(model_download_status)
+            .service(model_download_resume)
+            .service(model_download_cancel)
+            .service(llm_status)
+            .service(llm_reload)
+            .service(llm_unload)
             .service(get_attitude)
+            .service(get_attitude_schema)
+            .service(get_attitude_history)
+            .service(get_companion_mood)
+            .service(mood_modifiers_list)
+            .service(mood_modifiers_post)
+            .service(mood_modifiers_roll)
+            .service(mood_modifiers_clear)
+            .service(mood_modifier_delete)
             .service(create_or_update_attitude)
             .service(get_companion_attitudes)
             .service(get_attitude_summary)
@@ -1524,7 +5036,15 @@ async fn main() -> std::io::Result<()> {
             .service(clear_attitudes)
             .service(detect_persons)
             .service(get_all_persons)
+            // Registered before /api/persons/{name} so "graph" and "relationships" are not taken for names
+            .service(get_persons_graph)
+            .service(get_person_relationships)
             .service(get_person_by_name)
+            .service(add_person_relationship)
+            .service(edit_person_relationship)
+            .service(delete_person_relationship)
+            .service(delete_person)
+            .service(merge_persons)
             .service(cleanup_duplicate_third_parties)
             .service(cleanup_invalid_third_parties)
             .service(estimate_response_time_endpoint)
@@ -1536,15 +5056,63 @@ async fn main() -> std::io::Result<()> {
             .service(start_streaming_session)
             .service(get_inference_stats)
             .service(cleanup_cache)
+            .service(get_inference_metrics)
+            .service(get_inference_metrics_trend)
+            .service(get_best_inference_configurations)
+            .service(get_inference_recommendation)
+            .service(run_inference_benchmark)
             .service(create_session)
+            .service(list_sessions)
+            .service(session_prompt)
+            .service(get_session_history)
             .service(get_session)
             .service(update_session_attitude)
             .service(end_session)
             .service(get_session_stats)
             .service(get_gpu_memory)
+            .service(hardware)
+            .service(hardware_apply)
             .service(get_gpu_allocation)
+            .service(get_webhooks)
+            .service(add_webhook)
+            .service(remove_webhook)
+            .service(get_failed_deliveries)
+            .service(retry_failed_deliveries)
+            .service(retry_delivery)
+            .service(get_health)
+            .service(get_readiness)
+            .service(upload_image)
+            .service(uploaded_image)
+            .service(image_generate)
+            .service(get_log_level)
+            .service(set_log_level)
+            .service(get_logs)
+            .service(backup_export)
+            .service(backup_restore)
+            .service(get_journal)
+            .service(write_daily_recap)
+            .service(get_proactive_status)
+            .service(send_proactive_opener)
+            .service(stream_events)
+            .service(replay_events)
+            .service(chat_socket)
+            .configure(dev_routes)
+            .configure(web_ui::routes)
     })
     .bind((hostname, port))?
-    .run()
-    .await
+    // Signals are handled below, so running replies get to finish before the server goes
+    .disable_signals()
+    .shutdown_timeout(shutdown::SERVER_SHUTDOWN_SECONDS)
+    .run();
+    let handle = server.handle();
+    actix_web::rt::spawn(async move {
+        shutdown::signal().await;
+        shutdown::begin();
+        handle.stop(true).await;
+    });
+    let server = server.await;
+    if let Err(e) = web::block(move || shutdown::finish(&stopping_sessions)).await {
+        error!("Failed to shut down cleanly: {}", e);
+    }
+    server
 }
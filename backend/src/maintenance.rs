@@ -0,0 +1,299 @@
+use crate::attitude_engine;
+use crate::daily_recap;
+use crate::database::{get_current_date, Database};
+use chrono::{Local, NaiveTime};
+use crate::db_pool;
+use crate::instance_lock;
+use crate::interaction_scheduler;
+use crate::long_term_mem::LongTermMem;
+use crate::memory_consolidation::MemoryConsolidation;
+use crate::message_images::MessageImages;
+use crate::mood_modifiers;
+use crate::proactivity;
+use serde::Serialize;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+// How often the scheduler wakes up to check for due jobs
+const TICK_INTERVAL_SECONDS: u64 = 60;
+// Heavy jobs wait until nobody has chatted for this long
+const IDLE_SECONDS: u64 = 10 * 60;
+
+/// Periodic background job run by the maintenance scheduler, an empty summary is not logged
+struct MaintenanceJob {
+    name: &'static str,
+    every: Duration,
+    /// Heavy jobs lock the database for a while and only run inside the maintenance window
+    heavy: bool,
+    run: fn() -> Result<String, String>,
+}
+
+const JOBS: &[MaintenanceJob] = &[
+    MaintenanceJob {
+        name: "third-party importance",
+        every: Duration::from_secs(60 * 60),
+        heavy: false,
+        run: recalculate_importance,
+    },
+    MaintenanceJob {
+        name: "daily recap",
+        every: Duration::from_secs(5 * 60),
+        heavy: false,
+        run: daily_recap::run_if_due,
+    },
+    MaintenanceJob {
+        name: "planned interactions",
+        every: Duration::from_secs(5 * 60),
+        heavy: false,
+        run: interaction_scheduler::run_due,
+    },
+    MaintenanceJob {
+        name: "proactive messages",
+        every: Duration::from_secs(5 * 60),
+        heavy: false,
+        run: proactivity::run_due,
+    },
+    MaintenanceJob {
+        name: "daily mood rolls",
+        every: Duration::from_secs(30 * 60),
+        heavy: false,
+        run: mood_modifiers::run_daily_rolls,
+    },
+    MaintenanceJob {
+        name: "unused uploads",
+        every: Duration::from_secs(6 * 60 * 60),
+        heavy: false,
+        run: MessageImages::prune_unused,
+    },
+    MaintenanceJob {
+        name: "attitude decay",
+        every: Duration::from_secs(60 * 60),
+        heavy: false,
+        run: attitude_engine::run_decay,
+    },
+    MaintenanceJob {
+        name: "memory embeddings",
+        every: Duration::from_secs(10 * 60),
+        heavy: false,
+        run: LongTermMem::backfill_embeddings,
+    },
+    MaintenanceJob {
+        name: "memory consolidation",
+        every: Duration::from_secs(24 * 60 * 60),
+        heavy: true,
+        run: MemoryConsolidation::run_due,
+    },
+    MaintenanceJob {
+        name: "vacuum",
+        every: Duration::from_secs(24 * 60 * 60),
+        heavy: true,
+        run: vacuum_database,
+    },
+    MaintenanceJob {
+        name: "reindex",
+        every: Duration::from_secs(7 * 24 * 60 * 60),
+        heavy: true,
+        run: reindex_database,
+    },
+];
+
+fn recalculate_importance() -> Result<String, String> {
+    Database::recalculate_third_party_importance()
+        .map(|updated| format!("updated {} third parties", updated))
+        .map_err(|e| e.to_string())
+}
+
+fn vacuum_database() -> Result<String, String> {
+    let con = db_pool::connection().map_err(|e| e.to_string())?;
+    con.execute_batch("VACUUM; PRAGMA optimize;")
+        .map(|_| "database compacted".to_string())
+        .map_err(|e| e.to_string())
+}
+
+fn reindex_database() -> Result<String, String> {
+    let con = db_pool::connection().map_err(|e| e.to_string())?;
+    con.execute_batch("REINDEX; ANALYZE;")
+        .map(|_| "indexes rebuilt".to_string())
+        .map_err(|e| e.to_string())
+}
+
+/// Parse a daily window such as "03:00-05:00", windows may wrap past midnight
+pub fn parse_window(window: &str) -> Option<(NaiveTime, NaiveTime)> {
+    let (start, end) = window.split_once('-')?;
+    let start = NaiveTime::parse_from_str(start.trim(), "%H:%M").ok()?;
+    let end = NaiveTime::parse_from_str(end.trim(), "%H:%M").ok()?;
+    if start == end {
+        return None;
+    }
+    Some((start, end))
+}
+
+pub fn in_window(now: NaiveTime, start: NaiveTime, end: NaiveTime) -> bool {
+    if start < end {
+        now >= start && now < end
+    } else {
+        now >= start || now < end
+    }
+}
+
+static ACTIVE_GENERATIONS: AtomicUsize = AtomicUsize::new(0);
+
+lazy_static::lazy_static! {
+    static ref LAST_GENERATION: Mutex<Option<Instant>> = Mutex::new(None);
+    static ref STATUS: Mutex<MaintenanceStatus> = Mutex::new(MaintenanceStatus::default());
+}
+
+/// Marks a model generation as active for as long as it is alive
+pub struct GenerationGuard {}
+
+impl GenerationGuard {
+    pub fn begin() -> Self {
+        ACTIVE_GENERATIONS.fetch_add(1, Ordering::SeqCst);
+        GenerationGuard {}
+    }
+}
+
+impl Drop for GenerationGuard {
+    fn drop(&mut self) {
+        ACTIVE_GENERATIONS.fetch_sub(1, Ordering::SeqCst);
+        if let Ok(mut last) = LAST_GENERATION.lock() {
+            *last = Some(Instant::now());
+        }
+    }
+}
+
+pub fn generation_active() -> bool {
+    ACTIVE_GENERATIONS.load(Ordering::SeqCst) > 0
+}
+
+fn idle() -> bool {
+    if generation_active() {
+        return false;
+    }
+    match LAST_GENERATION.lock() {
+        Ok(last) => last.map_or(true, |at| at.elapsed() >= Duration::from_secs(IDLE_SECONDS)),
+        Err(_) => false,
+    }
+}
+
+/// What the scheduler is doing, served by /api/health
+#[derive(Serialize, Debug, Clone, Default)]
+pub struct MaintenanceStatus {
+    /// "idle", "running", "paused", "read_only" or "stopping"
+    pub state: String,
+    pub current_job: Option<String>,
+    /// True while a heavy job holds the database, chat may be briefly slower
+    pub heavy: bool,
+    pub window: String,
+    pub in_window: bool,
+    pub generation_active: bool,
+    pub last_heavy_run: Option<String>,
+}
+
+pub fn status() -> MaintenanceStatus {
+    let mut status = STATUS.lock().map(|s| s.clone()).unwrap_or_default();
+    if status.state.is_empty() {
+        status.state = "idle".to_string();
+    }
+    status.generation_active = generation_active();
+    status
+}
+
+fn update_status(update: impl FnOnce(&mut MaintenanceStatus)) {
+    if let Ok(mut status) = STATUS.lock() {
+        update(&mut status);
+    }
+}
+
+/// Background loop that runs each maintenance job on its own schedule
+pub async fn run_scheduler() {
+    let mut last_runs: Vec<Option<Instant>> = vec![None; JOBS.len()];
+    let mut interval = tokio::time::interval(Duration::from_secs(TICK_INTERVAL_SECONDS));
+    loop {
+        interval.tick().await;
+        let window = Database::get_config()
+            .map(|config| config.maintenance_window)
+            .unwrap_or_default();
+        let window_open = parse_window(&window)
+            .map_or(false, |(start, end)| in_window(Local::now().time(), start, end));
+        update_status(|status| {
+            status.window = window.clone();
+            status.in_window = window_open;
+            status.state = "idle".to_string();
+        });
+        // Jobs run on the instance holding the database lock only
+        if !instance_lock::is_leader() {
+            update_status(|status| status.state = "read_only".to_string());
+            continue;
+        }
+        // A job started now could outlive the shutdown, the next start picks it up
+        if crate::shutdown::is_stopping() {
+            update_status(|status| status.state = "stopping".to_string());
+            continue;
+        }
+
+        for (index, job) in JOBS.iter().enumerate() {
+            let due = last_runs[index].map_or(true, |last| last.elapsed() >= job.every);
+            if !due || (job.heavy && !window_open) {
+                continue;
+            }
+            // Never compete with a reply that is being generated, try again next tick
+            if generation_active() || (job.heavy && !idle()) {
+                update_status(|status| status.state = "paused".to_string());
+                break;
+            }
+            last_runs[index] = Some(Instant::now());
+            update_status(|status| {
+                status.state = "running".to_string();
+                status.current_job = Some(job.name.to_string());
+                status.heavy = job.heavy;
+            });
+            match tokio::task::spawn_blocking(job.run).await {
+                Ok(Ok(summary)) if summary.is_empty() => {}
+                Ok(Ok(summary)) => tracing::info!("🧹 Maintenance ({}): {}", job.name, summary),
+                Ok(Err(e)) => tracing::warn!("⚠️ Maintenance job '{}' failed: {}", job.name, e),
+                Err(e) => tracing::warn!("⚠️ Maintenance job '{}' panicked: {}", job.name, e),
+            }
+            update_status(|status| {
+                if job.heavy {
+                    status.last_heavy_run = Some(get_current_date());
+                }
+                status.state = "idle".to_string();
+                status.current_job = None;
+                status.heavy = false;
+            });
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn time(value: &str) -> NaiveTime {
+        NaiveTime::parse_from_str(value, "%H:%M").unwrap()
+    }
+
+    #[test]
+    fn test_parse_window() {
+        assert_eq!(parse_window("03:00-05:00"), Some((time("03:00"), time("05:00"))));
+        assert_eq!(parse_window(" 23:30 - 01:00 "), Some((time("23:30"), time("01:00"))));
+        assert!(parse_window("03:00").is_none());
+        assert!(parse_window("03:00-03:00").is_none());
+        assert!(parse_window("3am-5am").is_none());
+    }
+
+    #[test]
+    fn test_in_window_wraps_midnight() {
+        let (start, end) = parse_window("23:00-02:00").unwrap();
+        assert!(in_window(time("23:30"), start, end));
+        assert!(in_window(time("01:59"), start, end));
+        assert!(!in_window(time("02:00"), start, end));
+        assert!(!in_window(time("12:00"), start, end));
+
+        let (start, end) = parse_window("03:00-05:00").unwrap();
+        assert!(in_window(time("03:00"), start, end));
+        assert!(!in_window(time("05:00"), start, end));
+    }
+}
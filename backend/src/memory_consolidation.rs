@@ -0,0 +1,415 @@
+use crate::database::parse_stored_date;
+use crate::db_pool;
+use crate::memory_embeddings::{cosine, Embedder, HashingEmbedder};
+use chrono::{Local, NaiveDateTime, Utc};
+use rusqlite::{params, Connection, OptionalExtension, Result};
+use serde::Serialize;
+use std::collections::BTreeMap;
+
+/// Entries about the same person or target this alike are taken for one memory told twice
+const DUPLICATE_SIMILARITY: f32 = 0.9;
+// Entries untouched for longer than this lose importance on every run
+const STALE_DAYS: i64 = 30;
+// Factor on the importance of a stale entry for every day since the last run
+const DAILY_DECAY: f32 = 0.98;
+// third_party_memories.importance goes from 0 to 1
+const MIN_THIRD_PARTY_IMPORTANCE: f32 = 0.05;
+// attitude_memories.priority_score goes from 0 to 100
+const MIN_ATTITUDE_PRIORITY: f32 = 20.0;
+// Decay of a single run is capped, so a server that was off for months doesn't wipe its memories
+const MAX_DECAY_DAYS: i64 = 30;
+
+/// Entries that were folded into the one that is kept
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct Merge {
+    pub kept: i32,
+    pub removed: Vec<i32>,
+}
+
+/// What a consolidation did to one table, or would do in a dry run
+#[derive(Serialize, Debug, Default)]
+pub struct TableReport {
+    pub before: usize,
+    pub merged: usize,
+    pub decayed: usize,
+    pub pruned: usize,
+    pub after: usize,
+    pub merges: Vec<Merge>,
+    pub pruned_ids: Vec<i32>,
+}
+
+#[derive(Serialize, Debug)]
+pub struct ConsolidationReport {
+    pub dry_run: bool,
+    pub companion_id: Option<i32>,
+    /// Days of decay applied, counted since the last run over all companions that wasn't a dry run
+    pub decay_days: i64,
+    pub third_party_memories: TableReport,
+    pub attitude_memories: TableReport,
+}
+
+/// Memory row as far as consolidation is concerned
+#[derive(Debug, Clone)]
+struct Entry {
+    id: i32,
+    /// Entries are only merged with others of the same group
+    group: String,
+    text: String,
+    score: f32,
+    created_at: String,
+}
+
+/// New state of a kept entry
+#[derive(Debug, Clone, PartialEq)]
+struct Kept {
+    id: i32,
+    score: f32,
+    created_at: String,
+}
+
+#[derive(Debug, Default)]
+struct Plan {
+    merges: Vec<Merge>,
+    updated: Vec<Kept>,
+    decayed: usize,
+    pruned: Vec<i32>,
+}
+
+fn is_newer(a: &str, b: &str) -> bool {
+    match (parse_stored_date(a), parse_stored_date(b)) {
+        (Some(a), Some(b)) => a > b,
+        (Some(_), None) => true,
+        _ => false,
+    }
+}
+
+fn is_stale(created_at: &str, now: NaiveDateTime) -> bool {
+    parse_stored_date(created_at).is_some_and(|created| (now - created).num_days() > STALE_DAYS)
+}
+
+/// Merge near-duplicates, decay stale entries by `decay_days` and prune those below `min_score`
+///
+/// Of a set of duplicates the most important entry is kept, it takes the date of the newest one
+/// since the memory came up again then.
+fn plan(entries: Vec<Entry>, decay_days: i64, min_score: f32, now: NaiveDateTime) -> Plan {
+    let embedder = HashingEmbedder {};
+    let mut groups: BTreeMap<String, Vec<Entry>> = BTreeMap::new();
+    for entry in entries {
+        groups.entry(entry.group.clone()).or_default().push(entry);
+    }
+    let decay = DAILY_DECAY.powi(decay_days.clamp(0, MAX_DECAY_DAYS) as i32);
+    let mut result = Plan::default();
+    for (_, mut group) in groups {
+        group.sort_by(|a, b| b.score.total_cmp(&a.score).then(b.id.cmp(&a.id)));
+        // Kept entries with their vector, the ids folded into them and the newest date among them
+        let mut kept: Vec<(Entry, Vec<f32>, Vec<i32>, String)> = Vec::new();
+        for entry in group {
+            let vector = embedder.embed(&entry.text).unwrap_or_default();
+            let duplicate_of = kept.iter_mut().find(|(_, kept_vector, _, _)| {
+                !vector.is_empty() && cosine(kept_vector, &vector) >= DUPLICATE_SIMILARITY
+            });
+            match duplicate_of {
+                Some((_, _, removed, latest)) => {
+                    if is_newer(&entry.created_at, latest) {
+                        *latest = entry.created_at.clone();
+                    }
+                    removed.push(entry.id);
+                }
+                None => {
+                    let latest = entry.created_at.clone();
+                    kept.push((entry, vector, Vec::new(), latest));
+                }
+            }
+        }
+        for (entry, _, removed, latest) in kept {
+            let original = Kept {
+                id: entry.id,
+                score: entry.score,
+                created_at: entry.created_at,
+            };
+            let mut updated = Kept {
+                created_at: latest,
+                ..original.clone()
+            };
+            if !removed.is_empty() {
+                result.merges.push(Merge {
+                    kept: entry.id,
+                    removed,
+                });
+            }
+            if decay < 1.0 && is_stale(&updated.created_at, now) {
+                updated.score *= decay;
+                result.decayed += 1;
+            }
+            if updated.score < min_score {
+                result.pruned.push(entry.id);
+                // Its duplicates go with it
+                if let Some(merge) = result.merges.last().filter(|merge| merge.kept == entry.id) {
+                    result.pruned.extend(merge.removed.iter().copied());
+                    result.merges.pop();
+                }
+            } else if updated != original {
+                result.updated.push(updated);
+            }
+        }
+    }
+    result
+}
+
+fn load_third_party_memories(con: &Connection, companion_id: Option<i32>) -> Result<Vec<Entry>> {
+    let mut stmt = con.prepare(
+        "SELECT id, companion_id, third_party_id, memory_type, content, importance, created_at
+         FROM third_party_memories WHERE ?1 IS NULL OR companion_id = ?1",
+    )?;
+    let rows = stmt.query_map([companion_id], |row| {
+        Ok(Entry {
+            id: row.get(0)?,
+            group: format!(
+                "{}:{}:{}",
+                row.get::<_, i32>(1)?,
+                row.get::<_, i32>(2)?,
+                row.get::<_, Option<String>>(3)?.unwrap_or_default()
+            ),
+            text: row.get(4)?,
+            score: row.get(5)?,
+            created_at: row.get(6)?,
+        })
+    })?;
+    rows.collect()
+}
+
+fn load_attitude_memories(con: &Connection, companion_id: Option<i32>) -> Result<Vec<Entry>> {
+    let mut stmt = con.prepare(
+        "SELECT id, companion_id, target_type, target_id, memory_type, description, priority_score, created_at
+         FROM attitude_memories WHERE ?1 IS NULL OR companion_id = ?1",
+    )?;
+    let rows = stmt.query_map([companion_id], |row| {
+        Ok(Entry {
+            id: row.get(0)?,
+            group: format!(
+                "{}:{}:{}:{}",
+                row.get::<_, i32>(1)?,
+                row.get::<_, String>(2)?,
+                row.get::<_, i32>(3)?,
+                row.get::<_, String>(4)?
+            ),
+            text: row.get(5)?,
+            score: row.get(6)?,
+            created_at: row.get(7)?,
+        })
+    })?;
+    rows.collect()
+}
+
+/// `table` and `score` are names from this file, never from a request
+fn apply(con: &Connection, table: &str, score: &str, plan: &Plan) -> Result<()> {
+    for kept in &plan.updated {
+        con.execute(
+            &format!(
+                "UPDATE {} SET {} = ?, created_at = ? WHERE id = ?",
+                table, score
+            ),
+            params![kept.score, kept.created_at, kept.id],
+        )?;
+    }
+    let removed = plan
+        .merges
+        .iter()
+        .flat_map(|merge| merge.removed.iter())
+        .chain(plan.pruned.iter());
+    for id in removed {
+        con.execute(&format!("DELETE FROM {} WHERE id = ?", table), [id])?;
+    }
+    Ok(())
+}
+
+fn report(before: usize, plan: Plan) -> TableReport {
+    let merged = plan
+        .merges
+        .iter()
+        .map(|merge| merge.removed.len())
+        .sum::<usize>();
+    TableReport {
+        before,
+        merged,
+        decayed: plan.decayed,
+        pruned: plan.pruned.len(),
+        after: before - merged - plan.pruned.len(),
+        merges: plan.merges,
+        pruned_ids: plan.pruned,
+    }
+}
+
+pub struct MemoryConsolidation {}
+
+impl MemoryConsolidation {
+    pub fn create() -> Result<()> {
+        let con = db_pool::connection()?;
+        con.execute(
+            "CREATE TABLE IF NOT EXISTS memory_consolidation_runs (
+                id INTEGER PRIMARY KEY CHECK (id = 1),
+                last_run TEXT NOT NULL
+            )",
+            [],
+        )?;
+        Ok(())
+    }
+
+    /// Merge, decay and prune third_party_memories and attitude_memories, of one companion or all
+    ///
+    /// A dry run only reports what would change. Runs over all companions decay stale entries by
+    /// the days since the last such run, one day when there was none. Runs for one companion
+    /// only merge and prune, so the others don't fall behind.
+    pub fn run(companion_id: Option<i32>, dry_run: bool) -> Result<ConsolidationReport> {
+        let mut con = db_pool::connection()?;
+        let tx = con.transaction()?;
+        let last_run: Option<chrono::DateTime<Utc>> = tx
+            .query_row(
+                "SELECT last_run FROM memory_consolidation_runs WHERE id = 1",
+                [],
+                |row| row.get(0),
+            )
+            .optional()?;
+        let decay_days = match companion_id {
+            Some(_) => 0,
+            None => last_run.map_or(1, |last_run| (Utc::now() - last_run).num_days()),
+        };
+        let now = Local::now().naive_local();
+
+        let third_party = load_third_party_memories(&tx, companion_id)?;
+        let third_party_count = third_party.len();
+        let third_party = plan(third_party, decay_days, MIN_THIRD_PARTY_IMPORTANCE, now);
+        let attitudes = load_attitude_memories(&tx, companion_id)?;
+        let attitude_count = attitudes.len();
+        let attitudes = plan(attitudes, decay_days, MIN_ATTITUDE_PRIORITY, now);
+
+        if !dry_run {
+            apply(&tx, "third_party_memories", "importance", &third_party)?;
+            apply(&tx, "attitude_memories", "priority_score", &attitudes)?;
+            // Days without a run are caught up on, so the clock only moves once a day has passed
+            if companion_id.is_none() && decay_days > 0 {
+                tx.execute(
+                    "INSERT INTO memory_consolidation_runs (id, last_run) VALUES (1, ?)
+                     ON CONFLICT(id) DO UPDATE SET last_run = excluded.last_run",
+                    [Utc::now()],
+                )?;
+            }
+            tx.commit()?;
+        }
+        Ok(ConsolidationReport {
+            dry_run,
+            companion_id,
+            decay_days,
+            third_party_memories: report(third_party_count, third_party),
+            attitude_memories: report(attitude_count, attitudes),
+        })
+    }
+
+    /// Maintenance job entry point
+    pub fn run_due() -> Result<String, String> {
+        let report = MemoryConsolidation::run(None, false).map_err(|e| e.to_string())?;
+        let changes = |table: &TableReport| table.merged + table.decayed + table.pruned;
+        if changes(&report.third_party_memories) + changes(&report.attitude_memories) == 0 {
+            return Ok(String::new());
+        }
+        Ok(format!(
+            "third-party memories: {} merged, {} decayed, {} pruned; attitude memories: {} merged, {} decayed, {} pruned",
+            report.third_party_memories.merged,
+            report.third_party_memories.decayed,
+            report.third_party_memories.pruned,
+            report.attitude_memories.merged,
+            report.attitude_memories.decayed,
+            report.attitude_memories.pruned,
+        ))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn entry(id: i32, group: &str, text: &str, score: f32, created_at: &str) -> Entry {
+        Entry {
+            id,
+            group: group.to_string(),
+            text: text.to_string(),
+            score,
+            created_at: created_at.to_string(),
+        }
+    }
+
+    #[test]
+    fn test_consolidation_plan() {
+        let now = parse_stored_date("Monday 01.06.2026 12:00").unwrap();
+        let entries = vec![
+            entry(
+                1,
+                "anna",
+                "Anna moved to Berlin last spring",
+                0.6,
+                "Friday 01.05.2026 10:00",
+            ),
+            entry(
+                2,
+                "anna",
+                "Anna moved to Berlin last spring",
+                0.8,
+                "Saturday 02.05.2026 10:00",
+            ),
+            entry(
+                3,
+                "anna",
+                "Anna moved to Berlin last spring",
+                0.4,
+                "Sunday 31.05.2026 09:00",
+            ),
+            // Same words about someone else stay apart
+            entry(
+                4,
+                "tom",
+                "Anna moved to Berlin last spring",
+                0.5,
+                "Sunday 31.05.2026 09:00",
+            ),
+            entry(
+                5,
+                "anna",
+                "Anna is allergic to cats",
+                0.5,
+                "Sunday 31.05.2026 09:00",
+            ),
+            // Stale and barely important
+            entry(
+                6,
+                "tom",
+                "Tom once mentioned the weather",
+                0.05,
+                "Sunday 01.02.2026 09:00",
+            ),
+        ];
+        let result = plan(entries.clone(), 3, MIN_THIRD_PARTY_IMPORTANCE, now);
+        assert_eq!(
+            result.merges,
+            vec![Merge {
+                kept: 2,
+                removed: vec![1, 3]
+            }]
+        );
+        assert_eq!(result.pruned, vec![6]);
+        assert_eq!(result.decayed, 1);
+        // The kept entry came up again on the 31st, so it is fresh and keeps its importance
+        assert_eq!(
+            result.updated,
+            vec![Kept {
+                id: 2,
+                score: 0.8,
+                created_at: "Sunday 31.05.2026 09:00".to_string()
+            }]
+        );
+
+        // Nothing decays when no day passed since the last run
+        let result = plan(entries, 0, MIN_THIRD_PARTY_IMPORTANCE, now);
+        assert_eq!(result.decayed, 0);
+        assert!(result.pruned.is_empty());
+    }
+}
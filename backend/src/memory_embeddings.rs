@@ -0,0 +1,297 @@
+use crate::database::{get_current_date, ConfigView};
+use crate::db_pool;
+use rusqlite::{params, Error, Result};
+use std::collections::HashMap;
+use std::time::Duration;
+
+pub const RETRIEVAL_KEYWORD: &str = "keyword";
+pub const RETRIEVAL_VECTOR: &str = "vector";
+pub const RETRIEVAL_HYBRID: &str = "hybrid";
+pub const RETRIEVAL_MODES: [&str; 3] = [RETRIEVAL_KEYWORD, RETRIEVAL_VECTOR, RETRIEVAL_HYBRID];
+
+const HASHING_DIMENSIONS: usize = 512;
+// Keeps documents ranked high by only one of the two searches from dominating the fused ranking
+const RRF_K: f32 = 60.0;
+
+const STOPWORDS: &[&str] = &[
+    "a", "an", "the", "and", "or", "but", "is", "are", "was", "were", "be", "been", "am", "i",
+    "you", "he", "she", "it", "we", "they", "me", "him", "her", "us", "them", "my", "your",
+    "his", "its", "our", "their", "to", "of", "in", "on", "at", "for", "with", "about", "from",
+    "by", "as", "that", "this", "these", "those", "do", "does", "did", "have", "has", "had",
+    "not", "no", "so", "if", "then", "than", "too", "very", "can", "will", "just", "what",
+    "when", "where", "who", "how", "why", "char", "user",
+];
+
+/// Turns text into a vector, texts about similar things end up close to each other
+pub trait Embedder {
+    /// Stored next to every vector, vectors of different embedders are never compared
+    fn id(&self) -> String;
+    fn embed(&self, text: &str) -> Result<Vec<f32>, String>;
+}
+
+/// Embedder selected in the config, the local one unless an embeddings API is set
+pub fn embedder(config: &ConfigView) -> Box<dyn Embedder> {
+    if config.embedding_api_url.trim().is_empty() {
+        Box::new(HashingEmbedder {})
+    } else {
+        Box::new(ApiEmbedder {
+            url: config.embedding_api_url.trim().to_string(),
+            model: config.embedding_model.trim().to_string(),
+        })
+    }
+}
+
+fn fnv1a(text: &str) -> u32 {
+    let mut hash: u32 = 0x811c9dc5;
+    for byte in text.bytes() {
+        hash ^= byte as u32;
+        hash = hash.wrapping_mul(0x01000193);
+    }
+    hash
+}
+
+/// Crude stemming so "walked", "walking" and "walks" share a feature
+fn stem(word: &str) -> &str {
+    for suffix in ["ing", "ed", "es", "s", "ly"] {
+        if let Some(stem) = word.strip_suffix(suffix) {
+            if stem.len() >= 3 {
+                return stem;
+            }
+        }
+    }
+    word
+}
+
+fn normalize(vector: &mut [f32]) {
+    let norm = vector.iter().map(|v| v * v).sum::<f32>().sqrt();
+    if norm > 0.0 {
+        vector.iter_mut().for_each(|v| *v /= norm);
+    }
+}
+
+pub fn cosine(a: &[f32], b: &[f32]) -> f32 {
+    if a.len() != b.len() {
+        return 0.0;
+    }
+    let dot: f32 = a.iter().zip(b).map(|(x, y)| x * y).sum();
+    let norms = a.iter().map(|v| v * v).sum::<f32>().sqrt() * b.iter().map(|v| v * v).sum::<f32>().sqrt();
+    if norms > 0.0 {
+        dot / norms
+    } else {
+        0.0
+    }
+}
+
+/// Local embedder hashing word stems and character trigrams, needs no model or network
+///
+/// Matches memories that share words in different forms or misspelled, but knows no synonyms.
+pub struct HashingEmbedder {}
+
+impl Embedder for HashingEmbedder {
+    fn id(&self) -> String {
+        "hashing-v1".to_string()
+    }
+
+    fn embed(&self, text: &str) -> Result<Vec<f32>, String> {
+        let mut vector = vec![0.0f32; HASHING_DIMENSIONS];
+        let lower = text.to_lowercase();
+        let words = lower
+            .split(|c: char| !c.is_alphanumeric())
+            .filter(|word| word.len() > 1 && !STOPWORDS.contains(word));
+        for word in words {
+            let stem = stem(word);
+            // Whole stems weigh more than the trigrams that only hint at a shared spelling
+            let hash = fnv1a(stem);
+            let sign = if hash & 1 == 0 { 1.0 } else { -1.0 };
+            vector[(hash >> 1) as usize % HASHING_DIMENSIONS] += 2.0 * sign;
+            let padded: Vec<char> = format!("^{}$", stem).chars().collect();
+            for trigram in padded.windows(3) {
+                let hash = fnv1a(&trigram.iter().collect::<String>());
+                let sign = if hash & 1 == 0 { 1.0 } else { -1.0 };
+                vector[(hash >> 1) as usize % HASHING_DIMENSIONS] += 0.5 * sign;
+            }
+        }
+        normalize(&mut vector);
+        Ok(vector)
+    }
+}
+
+/// OpenAI compatible embeddings endpoint, such as the one of Ollama or a llama.cpp server
+pub struct ApiEmbedder {
+    url: String,
+    model: String,
+}
+
+impl Embedder for ApiEmbedder {
+    fn id(&self) -> String {
+        format!("api:{}:{}", self.url, self.model)
+    }
+
+    fn embed(&self, text: &str) -> Result<Vec<f32>, String> {
+        let client = reqwest::blocking::Client::builder()
+            .timeout(Duration::from_secs(30))
+            .build()
+            .map_err(|e| e.to_string())?;
+        let response: serde_json::Value = client
+            .post(&self.url)
+            .json(&serde_json::json!({ "model": self.model, "input": text }))
+            .send()
+            .and_then(|response| response.error_for_status())
+            .and_then(|response| response.json())
+            .map_err(|e| format!("embeddings request failed: {}", e))?;
+        let vector: Vec<f32> = response["data"][0]["embedding"]
+            .as_array()
+            .ok_or("embeddings response has no data[0].embedding")?
+            .iter()
+            .filter_map(|value| value.as_f64().map(|v| v as f32))
+            .collect();
+        if vector.is_empty() {
+            return Err("embeddings response is empty".to_string());
+        }
+        Ok(vector)
+    }
+}
+
+/// Merge two rankings with reciprocal rank fusion, entries found by both rise to the top
+pub fn fuse_rankings(keyword: &[String], vector: &[String], limit: usize) -> Vec<String> {
+    let mut scores: HashMap<&String, f32> = HashMap::new();
+    let mut order: Vec<&String> = Vec::new();
+    for ranking in [keyword, vector] {
+        for (rank, entry) in ranking.iter().enumerate() {
+            if !scores.contains_key(entry) {
+                order.push(entry);
+            }
+            *scores.entry(entry).or_insert(0.0) += 1.0 / (RRF_K + rank as f32 + 1.0);
+        }
+    }
+    // Stable sort keeps keyword order between equally ranked entries
+    order.sort_by(|a, b| scores[b].total_cmp(&scores[a]));
+    order.into_iter().take(limit).cloned().collect()
+}
+
+fn to_blob(vector: &[f32]) -> Vec<u8> {
+    vector.iter().flat_map(|v| v.to_le_bytes()).collect()
+}
+
+fn from_blob(blob: &[u8]) -> Vec<f32> {
+    blob.chunks_exact(4)
+        .map(|bytes| f32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
+        .collect()
+}
+
+/// Vectors of long-term memory entries, the entries themselves live in the tantivy index
+pub struct MemoryEmbeddings {}
+
+impl MemoryEmbeddings {
+    pub fn create() -> Result<(), Error> {
+        let con = db_pool::connection()?;
+        con.execute(
+            "CREATE TABLE IF NOT EXISTS memory_embeddings (
+                id INTEGER PRIMARY KEY AUTOINCREMENT,
+                companion_id INTEGER NOT NULL,
+                embedder TEXT NOT NULL,
+                content TEXT NOT NULL,
+                embedding BLOB NOT NULL,
+                created_at TEXT NOT NULL,
+                UNIQUE(companion_id, embedder, content)
+            )",
+            [],
+        )?;
+        Ok(())
+    }
+
+    pub fn add(companion_id: i32, embedder: &dyn Embedder, content: &str) -> Result<(), String> {
+        let vector = embedder.embed(content)?;
+        let con = db_pool::connection().map_err(|e| e.to_string())?;
+        con.execute(
+            "INSERT OR IGNORE INTO memory_embeddings (companion_id, embedder, content, embedding, created_at)
+             VALUES (?, ?, ?, ?, ?)",
+            params![companion_id, embedder.id(), content, to_blob(&vector), get_current_date()],
+        )
+        .map_err(|e| e.to_string())?;
+        Ok(())
+    }
+
+    /// Entries most similar to the query, best first
+    pub fn search(
+        companion_id: i32,
+        embedder: &dyn Embedder,
+        query: &str,
+        limit: usize,
+    ) -> Result<Vec<String>, String> {
+        let query = embedder.embed(query)?;
+        let con = db_pool::connection().map_err(|e| e.to_string())?;
+        let mut stmt = con
+            .prepare("SELECT content, embedding FROM memory_embeddings WHERE companion_id = ? AND embedder = ?")
+            .map_err(|e| e.to_string())?;
+        let rows = stmt
+            .query_map(params![companion_id, embedder.id()], |row| {
+                Ok((row.get::<_, String>(0)?, row.get::<_, Vec<u8>>(1)?))
+            })
+            .map_err(|e| e.to_string())?;
+        let mut scored = Vec::new();
+        for row in rows {
+            let (content, blob) = row.map_err(|e| e.to_string())?;
+            let similarity = cosine(&query, &from_blob(&blob));
+            if similarity > 0.0 {
+                scored.push((similarity, content));
+            }
+        }
+        scored.sort_by(|a, b| b.0.total_cmp(&a.0));
+        Ok(scored.into_iter().take(limit).map(|(_, content)| content).collect())
+    }
+
+    /// Embed the entries the current embedder has no vector for yet, returns how many were added
+    pub fn backfill(companion_id: i32, embedder: &dyn Embedder, entries: &[String]) -> Result<usize, String> {
+        let con = db_pool::connection().map_err(|e| e.to_string())?;
+        let mut stmt = con
+            .prepare("SELECT content FROM memory_embeddings WHERE companion_id = ? AND embedder = ?")
+            .map_err(|e| e.to_string())?;
+        let known: std::collections::HashSet<String> = stmt
+            .query_map(params![companion_id, embedder.id()], |row| row.get(0))
+            .and_then(|rows| rows.collect())
+            .map_err(|e| e.to_string())?;
+        let mut added = 0;
+        for entry in entries.iter().filter(|entry| !known.contains(*entry)) {
+            MemoryEmbeddings::add(companion_id, embedder, entry)?;
+            added += 1;
+        }
+        Ok(added)
+    }
+
+    pub fn erase(companion_id: i32) -> Result<usize> {
+        let con = db_pool::connection()?;
+        con.execute("DELETE FROM memory_embeddings WHERE companion_id = ?", [companion_id])
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_hashing_embedder_similarity() {
+        let embedder = HashingEmbedder {};
+        let memory = embedder.embed("{{user}} went hiking in the mountains with a friend").unwrap();
+        let related = embedder.embed("Do you remember my hike?").unwrap();
+        let unrelated = embedder.embed("What should I cook for dinner tonight?").unwrap();
+        assert!(cosine(&memory, &related) > cosine(&memory, &unrelated));
+        assert!((cosine(&memory, &memory) - 1.0).abs() < 1e-5);
+    }
+
+    #[test]
+    fn test_blob_round_trip() {
+        let vector = vec![0.25, -1.5, 3.0];
+        assert_eq!(from_blob(&to_blob(&vector)), vector);
+    }
+
+    #[test]
+    fn test_fuse_rankings() {
+        let keyword = vec!["a".to_string(), "b".to_string(), "c".to_string()];
+        let vector = vec!["c".to_string(), "d".to_string()];
+        let fused = fuse_rankings(&keyword, &vector, 3);
+        assert_eq!(fused[0], "c");
+        assert_eq!(fused.len(), 3);
+        assert!(fused.contains(&"a".to_string()));
+    }
+}
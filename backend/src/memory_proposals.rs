@@ -0,0 +1,226 @@
+use crate::database::{get_current_date, Database};
+use crate::db_pool;
+use crate::event_bus;
+use crate::long_term_mem::LongTermMem;
+use rusqlite::{params, Error, OptionalExtension, Result};
+use std::collections::HashMap;
+use serde::{Deserialize, Serialize};
+
+pub const KIND_LONG_TERM_MEMORY: &str = "long_term_memory";
+pub const KIND_PERSON: &str = "person";
+
+/// A memory write that is waiting for the user's approval
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct MemoryProposal {
+    pub id: i64,
+    /// Companion whose memory the proposal is written to once approved
+    pub companion_id: i32,
+    pub kind: String,
+    pub content: String,
+    pub context: Option<String>,
+    pub status: String,
+    pub created_at: String,
+}
+
+#[derive(Serialize, Debug, Default)]
+pub struct ProposalResolution {
+    pub applied: Vec<i64>,
+    pub rejected: Vec<i64>,
+    pub failed: Vec<i64>,
+    pub not_found: Vec<i64>,
+}
+
+pub struct MemoryProposals {}
+
+impl MemoryProposals {
+    pub fn create() -> Result<usize, Error> {
+        let con = db_pool::connection()?;
+        let created = con.execute(
+            "CREATE TABLE IF NOT EXISTS memory_proposals (
+                id INTEGER PRIMARY KEY AUTOINCREMENT,
+                companion_id INTEGER NOT NULL DEFAULT 1,
+                kind TEXT NOT NULL CHECK(kind IN ('long_term_memory', 'person')),
+                content TEXT NOT NULL,
+                context TEXT,
+                status TEXT NOT NULL DEFAULT 'pending' CHECK(status IN ('pending', 'approved', 'rejected')),
+                created_at TEXT NOT NULL
+            )",
+            [],
+        )?;
+        // Proposals staged before companions had their own were made by the first one
+        if !Database::has_column(&con, "memory_proposals", "companion_id")? {
+            con.execute(
+                "ALTER TABLE memory_proposals ADD COLUMN companion_id INTEGER NOT NULL DEFAULT 1",
+                [],
+            )?;
+        }
+        Ok(created)
+    }
+
+    /// Whether memory writes should be applied immediately instead of staged
+    pub fn auto_approve() -> bool {
+        Database::get_config()
+            .map(|config| config.memory_auto_approve)
+            .unwrap_or(true)
+    }
+
+    pub fn propose(companion_id: i32, kind: &str, content: &str, context: Option<&str>) -> Result<i64> {
+        let con = db_pool::connection()?;
+        // Don't stack up identical pending proposals, e.g. the same name mentioned twice
+        let existing: Option<i64> = con
+            .query_row(
+                "SELECT id FROM memory_proposals
+                 WHERE companion_id = ? AND kind = ? AND content = ? AND status = 'pending'",
+                params![companion_id, kind, content],
+                |row| row.get(0),
+            )
+            .ok();
+        if let Some(id) = existing {
+            return Ok(id);
+        }
+        con.execute(
+            "INSERT INTO memory_proposals (companion_id, kind, content, context, created_at) VALUES (?, ?, ?, ?, ?)",
+            params![companion_id, kind, content, context, get_current_date()],
+        )?;
+        Ok(con.last_insert_rowid())
+    }
+
+    fn from_row(row: &rusqlite::Row) -> Result<MemoryProposal> {
+        Ok(MemoryProposal {
+            id: row.get(0)?,
+            companion_id: row.get(1)?,
+            kind: row.get(2)?,
+            content: row.get(3)?,
+            context: row.get(4)?,
+            status: row.get(5)?,
+            created_at: row.get(6)?,
+        })
+    }
+
+    /// Proposals of one companion still waiting for the user
+    pub fn get_pending(companion_id: i32) -> Result<Vec<MemoryProposal>> {
+        let con = db_pool::connection()?;
+        let mut stmt = con.prepare(
+            "SELECT id, companion_id, kind, content, context, status, created_at
+             FROM memory_proposals WHERE status = 'pending' AND companion_id = ? ORDER BY id",
+        )?;
+        let rows = stmt.query_map([companion_id], MemoryProposals::from_row)?;
+        rows.collect()
+    }
+
+    fn get_pending_by_id(id: i64) -> Result<Option<MemoryProposal>> {
+        let con = db_pool::connection()?;
+        con.query_row(
+            "SELECT id, companion_id, kind, content, context, status, created_at
+             FROM memory_proposals WHERE status = 'pending' AND id = ?",
+            [id],
+            MemoryProposals::from_row,
+        )
+        .optional()
+    }
+
+    fn set_status(id: i64, status: &str) -> Result<()> {
+        let con = db_pool::connection()?;
+        con.execute(
+            "UPDATE memory_proposals SET status = ? WHERE id = ?",
+            params![status, id],
+        )?;
+        Ok(())
+    }
+
+    /// Approve or reject a batch of pending proposals, applying the approved ones
+    /// to the companion that proposed them
+    pub fn resolve(ids: &[i64], approve: bool) -> Result<ProposalResolution> {
+        let mut resolution = ProposalResolution::default();
+        // Opened on first use, None once opening a companion's memory failed
+        let mut long_term_memories: HashMap<i32, Option<LongTermMem>> = HashMap::new();
+
+        for &id in ids {
+            let proposal = match MemoryProposals::get_pending_by_id(id)? {
+                Some(p) => p,
+                None => {
+                    resolution.not_found.push(id);
+                    continue;
+                }
+            };
+            if !approve {
+                MemoryProposals::set_status(id, "rejected")?;
+                resolution.rejected.push(id);
+                continue;
+            }
+
+            let applied = match proposal.kind.as_str() {
+                KIND_LONG_TERM_MEMORY => match long_term_memories
+                    .entry(proposal.companion_id)
+                    .or_insert_with(|| {
+                        LongTermMem::open(proposal.companion_id)
+                            .map_err(|e| tracing::error!("Failed to connect to long-term memory: {}", e))
+                            .ok()
+                    }) {
+                    Some(ltm) => ltm
+                        .add_entry(&proposal.content)
+                        .map_err(|e| tracing::error!("Failed to write approved memory {}: {}", id, e))
+                        .is_ok(),
+                    None => false,
+                },
+                KIND_PERSON => {
+                    let context = proposal.context.as_deref().unwrap_or("");
+                    let result = match Database::get_third_party_by_name(&proposal.content)? {
+                        Some(_) => Database::create_or_update_third_party(&proposal.content, None),
+                        None => Database::register_detected_person(&proposal.content, context, proposal.companion_id),
+                    };
+                    result
+                        .map_err(|e| tracing::error!("Failed to add approved person {}: {}", id, e))
+                        .is_ok()
+                }
+                _ => false,
+            };
+
+            if applied {
+                MemoryProposals::set_status(id, "approved")?;
+                resolution.applied.push(id);
+            } else {
+                resolution.failed.push(id);
+            }
+        }
+        Ok(resolution)
+    }
+
+    /// Either store a long-term memory entry right away or stage it for approval
+    pub fn write_long_term_memory(ltm: &LongTermMem, entry: &str) -> Result<(), String> {
+        if MemoryProposals::auto_approve() {
+            ltm.add_entry(entry).map_err(|e| e.to_string())
+        } else {
+            MemoryProposals::propose(ltm.companion_id(), KIND_LONG_TERM_MEMORY, entry, None)
+                .map(|_| ())
+                .map_err(|e| e.to_string())
+        }
+    }
+
+    /// Person detection that respects consent mode, returns ids of people created immediately
+    pub fn detect_persons(message: &str, companion_id: i32) -> Result<Vec<i32>> {
+        if MemoryProposals::auto_approve() {
+            let detected = Database::detect_new_persons_in_message(message, companion_id)?;
+            for third_party_id in &detected {
+                let name = Database::get_third_party_by_id(*third_party_id)?.map(|p| p.name);
+                event_bus::publish(
+                    "person_detected",
+                    serde_json::json!({ "companion_id": companion_id, "third_party_id": third_party_id, "name": name, "pending_review": false }),
+                );
+            }
+            return Ok(detected);
+        }
+        for name in Database::find_person_names_in_message(message)? {
+            if Database::get_third_party_by_name(&name)?.is_none() {
+                MemoryProposals::propose(companion_id, KIND_PERSON, &name, Some(message))?;
+                event_bus::publish(
+                    "person_detected",
+                    serde_json::json!({ "companion_id": companion_id, "name": name, "pending_review": true }),
+                );
+            } else {
+                Database::create_or_update_third_party(&name, None)?;
+            }
+        }
+        Ok(Vec::new())
+    }
+}
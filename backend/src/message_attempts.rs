@@ -0,0 +1,203 @@
+use crate::database::{get_current_date, Database, Device, PromptTemplate};
+use crate::db_pool;
+use rusqlite::{params, Error, OptionalExtension, Result};
+use serde::{Deserialize, Serialize};
+
+/// Settings a reply was generated with, so attempts can be compared against each other
+#[derive(Serialize, Deserialize, Clone)]
+pub struct SamplingSettings {
+    pub model_path: String,
+    pub device: Device,
+    pub gpu_layers: usize,
+    pub prompt_template: PromptTemplate,
+    pub context_window_size: usize,
+    pub max_tokens: usize,
+    pub sampler: String,
+}
+
+/// One generated reply to a user message, the canonical attempt is the one shown in the chat
+#[derive(Serialize, Deserialize, Clone)]
+pub struct MessageAttempt {
+    pub id: i64,
+    pub user_message_id: i32,
+    pub content: String,
+    pub settings: Option<SamplingSettings>,
+    pub input_tokens: u32,
+    pub output_tokens: u32,
+    pub canonical: bool,
+    pub created_at: String,
+}
+
+pub struct MessageAttempts {}
+
+impl MessageAttempts {
+    pub fn create() -> Result<usize, Error> {
+        let con = db_pool::connection()?;
+        con.execute(
+            "CREATE TABLE IF NOT EXISTS message_attempts (
+                id INTEGER PRIMARY KEY AUTOINCREMENT,
+                user_message_id INTEGER NOT NULL,
+                content TEXT NOT NULL,
+                settings TEXT,
+                input_tokens INTEGER DEFAULT 0,
+                output_tokens INTEGER DEFAULT 0,
+                canonical BOOLEAN DEFAULT false,
+                created_at TEXT NOT NULL
+            )",
+            [],
+        )?;
+        con.execute(
+            "CREATE INDEX IF NOT EXISTS idx_message_attempts_message ON message_attempts(user_message_id, id)",
+            [],
+        )
+    }
+
+    /// Store a fresh reply, it becomes the canonical attempt for its user message
+    pub fn record(
+        user_message_id: i32,
+        content: &str,
+        settings: &SamplingSettings,
+        input_tokens: u32,
+        output_tokens: u32,
+    ) -> Result<i64> {
+        let mut con = db_pool::connection()?;
+        let tx = con.transaction()?;
+        tx.execute(
+            "UPDATE message_attempts SET canonical = false WHERE user_message_id = ?",
+            [user_message_id],
+        )?;
+        let id = insert(&tx, user_message_id, content, Some(settings), input_tokens, output_tokens, true)?;
+        tx.commit()?;
+        Ok(id)
+    }
+
+    /// Store an alternative reply next to the one in the chat, it only shows up there once promoted
+    pub fn record_alternative(
+        user_message_id: i32,
+        content: &str,
+        settings: &SamplingSettings,
+        input_tokens: u32,
+        output_tokens: u32,
+    ) -> Result<i64> {
+        let con = db_pool::connection()?;
+        insert(&con, user_message_id, content, Some(settings), input_tokens, output_tokens, false)
+    }
+
+    /// Record the reply in the chat as the canonical attempt, unless the message has attempts already
+    ///
+    /// Replies from before attempts were kept have no settings or token counts.
+    pub fn keep_reply(user_message_id: i32, content: &str) -> Result<()> {
+        let con = db_pool::connection()?;
+        let recorded: bool = con.query_row(
+            "SELECT EXISTS(SELECT 1 FROM message_attempts WHERE user_message_id = ?)",
+            [user_message_id],
+            |row| row.get(0),
+        )?;
+        if !recorded {
+            insert(&con, user_message_id, content, None, 0, 0, true)?;
+        }
+        Ok(())
+    }
+
+    pub fn get_attempts(user_message_id: i32) -> Result<Vec<MessageAttempt>> {
+        let con = db_pool::connection()?;
+        let mut stmt = con.prepare(
+            "SELECT id, user_message_id, content, settings, input_tokens, output_tokens, canonical, created_at
+             FROM message_attempts WHERE user_message_id = ? ORDER BY id",
+        )?;
+        let rows = stmt.query_map([user_message_id], |row| {
+            let settings: Option<String> = row.get(3)?;
+            Ok(MessageAttempt {
+                id: row.get(0)?,
+                user_message_id: row.get(1)?,
+                content: row.get(2)?,
+                settings: settings.and_then(|s| serde_json::from_str(&s).ok()),
+                input_tokens: row.get(4)?,
+                output_tokens: row.get(5)?,
+                canonical: row.get(6)?,
+                created_at: row.get(7)?,
+            })
+        })?;
+        rows.collect()
+    }
+
+    /// Make a past attempt the reply shown in the chat, returns the id of the edited reply
+    ///
+    /// None if the attempt does not belong to the message or the reply was deleted since.
+    pub fn promote(user_message_id: i32, attempt_id: i64) -> Result<Option<i32>> {
+        let reply_id = match MessageAttempts::reply_id(user_message_id)? {
+            Some(id) => id,
+            None => return Ok(None),
+        };
+        let mut con = db_pool::connection()?;
+        let content: Option<String> = con
+            .query_row(
+                "SELECT content FROM message_attempts WHERE id = ? AND user_message_id = ?",
+                params![attempt_id, user_message_id],
+                |row| row.get(0),
+            )
+            .optional()?;
+        let content = match content {
+            Some(content) => content,
+            None => return Ok(None),
+        };
+
+        let tx = con.transaction()?;
+        tx.execute(
+            "UPDATE messages SET content = ? WHERE id = ?",
+            params![content, reply_id],
+        )?;
+        tx.execute(
+            "UPDATE message_attempts SET canonical = (id = ?) WHERE user_message_id = ?",
+            params![attempt_id, user_message_id],
+        )?;
+        tx.commit()?;
+
+        Database::clear_message_cache();
+        Ok(Some(reply_id))
+    }
+
+    /// Id of the companion's reply to a user message, the message right after it in the same chat
+    pub fn reply_id(user_message_id: i32) -> Result<Option<i32>> {
+        let con = db_pool::connection()?;
+        let reply: Option<(i32, bool)> = con
+            .query_row(
+                "SELECT id, ai FROM messages
+                 WHERE id > ?1 AND conversation_id = (SELECT conversation_id FROM messages WHERE id = ?1)
+                 ORDER BY id LIMIT 1",
+                [user_message_id],
+                |row| Ok((row.get(0)?, row.get(1)?)),
+            )
+            .optional()?;
+        Ok(match reply {
+            Some((id, true)) => Some(id),
+            _ => None,
+        })
+    }
+}
+
+fn insert(
+    con: &rusqlite::Connection,
+    user_message_id: i32,
+    content: &str,
+    settings: Option<&SamplingSettings>,
+    input_tokens: u32,
+    output_tokens: u32,
+    canonical: bool,
+) -> Result<i64> {
+    let settings = settings.map(|settings| serde_json::to_string(settings).unwrap_or_default());
+    con.execute(
+        "INSERT INTO message_attempts (user_message_id, content, settings, input_tokens, output_tokens, canonical, created_at)
+         VALUES (?, ?, ?, ?, ?, ?, ?)",
+        params![
+            user_message_id,
+            content,
+            settings,
+            input_tokens,
+            output_tokens,
+            canonical,
+            get_current_date()
+        ],
+    )?;
+    Ok(con.last_insert_rowid())
+}
@@ -0,0 +1,177 @@
+use crate::database::get_current_date;
+use crate::db_pool;
+use crate::dialogue_tuning::DialogueTuning;
+use rusqlite::{params, Error, OptionalExtension, Result};
+use serde::{Deserialize, Serialize};
+
+// Disliked replies example dialogue is steered away from, the most recent first
+const DISLIKED_LIMIT: usize = 20;
+
+/// Thumbs up or down on a reply of the companion
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum Rating {
+    Up,
+    Down,
+}
+
+impl Rating {
+    fn value(&self) -> i32 {
+        match self {
+            Rating::Up => 1,
+            Rating::Down => -1,
+        }
+    }
+
+    fn from_value(value: i32) -> Rating {
+        if value > 0 {
+            Rating::Up
+        } else {
+            Rating::Down
+        }
+    }
+}
+
+#[derive(Serialize, Debug, Clone)]
+pub struct Feedback {
+    pub message_id: i32,
+    pub rating: Rating,
+    pub comment: String,
+    /// Whether the exchange was added to dialogue tuning
+    pub harvested: bool,
+    pub created_at: String,
+    pub updated_at: String,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct FeedbackModify {
+    pub rating: Rating,
+    #[serde(default)]
+    pub comment: String,
+}
+
+/// Why feedback could not be stored
+pub enum FeedbackError {
+    NoSuchMessage,
+    NotAReply,
+    Database(Error),
+}
+
+impl From<Error> for FeedbackError {
+    fn from(e: Error) -> Self {
+        FeedbackError::Database(e)
+    }
+}
+
+pub struct MessageFeedback {}
+
+impl MessageFeedback {
+    pub fn create() -> Result<usize, Error> {
+        let con = db_pool::connection()?;
+        con.execute(
+            "CREATE TABLE IF NOT EXISTS message_feedback (
+                id INTEGER PRIMARY KEY AUTOINCREMENT,
+                message_id INTEGER NOT NULL UNIQUE,
+                companion_id INTEGER NOT NULL,
+                rating INTEGER NOT NULL,
+                comment TEXT NOT NULL DEFAULT '',
+                created_at TEXT NOT NULL,
+                updated_at TEXT NOT NULL
+            )",
+            [],
+        )
+    }
+
+    /// Rate a reply, liked replies join dialogue tuning together with the message they answer
+    pub fn rate(message_id: i32, feedback: &FeedbackModify) -> Result<Feedback, FeedbackError> {
+        let con = db_pool::connection()?;
+        let message: Option<(bool, String, i32, Option<i32>)> = con
+            .query_row(
+                "SELECT ai, content, companion_id, conversation_id FROM messages WHERE id = ?",
+                [message_id],
+                |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?, row.get(3)?)),
+            )
+            .optional()?;
+        let (ai, reply, companion_id, conversation_id) = match message {
+            Some(message) => message,
+            None => return Err(FeedbackError::NoSuchMessage),
+        };
+        if !ai {
+            return Err(FeedbackError::NotAReply);
+        }
+        con.execute(
+            "INSERT INTO message_feedback (message_id, companion_id, rating, comment, created_at, updated_at)
+             VALUES (?, ?, ?, ?, ?, ?)
+             ON CONFLICT(message_id) DO UPDATE SET rating = excluded.rating, comment = excluded.comment,
+                updated_at = excluded.updated_at",
+            params![
+                message_id,
+                companion_id,
+                feedback.rating.value(),
+                feedback.comment.trim(),
+                get_current_date(),
+                get_current_date()
+            ],
+        )?;
+
+        // The greeting answers nothing, so there is no exchange to learn from
+        let user_msg: Option<(bool, String)> = con
+            .query_row(
+                "SELECT ai, content FROM messages WHERE conversation_id IS ? AND id < ? ORDER BY id DESC LIMIT 1",
+                params![conversation_id, message_id],
+                |row| Ok((row.get(0)?, row.get(1)?)),
+            )
+            .optional()?;
+        match (feedback.rating, user_msg) {
+            (Rating::Up, Some((false, user_msg))) => {
+                DialogueTuning::harvest(message_id, companion_id, &user_msg, &reply)?;
+            }
+            _ => {
+                DialogueTuning::forget_harvested(message_id)?;
+            }
+        }
+        drop(con);
+        MessageFeedback::get(message_id)?.ok_or(FeedbackError::NoSuchMessage)
+    }
+
+    pub fn get(message_id: i32) -> Result<Option<Feedback>> {
+        let con = db_pool::connection()?;
+        con.query_row(
+            "SELECT f.message_id, f.rating, f.comment, f.created_at, f.updated_at,
+                EXISTS(SELECT 1 FROM dialogue_tuning d WHERE d.message_id = f.message_id)
+             FROM message_feedback f WHERE f.message_id = ?",
+            [message_id],
+            |row| {
+                Ok(Feedback {
+                    message_id: row.get(0)?,
+                    rating: Rating::from_value(row.get(1)?),
+                    comment: row.get(2)?,
+                    created_at: row.get(3)?,
+                    updated_at: row.get(4)?,
+                    harvested: row.get(5)?,
+                })
+            },
+        )
+        .optional()
+    }
+
+    /// Remove the rating, a harvested exchange goes with it
+    pub fn delete(message_id: i32) -> Result<bool> {
+        let con = db_pool::connection()?;
+        let changed = con.execute("DELETE FROM message_feedback WHERE message_id = ?", [message_id])?;
+        DialogueTuning::forget_harvested(message_id)?;
+        Ok(changed > 0)
+    }
+
+    /// Text of the companion's most recently disliked replies that are still in the chat
+    pub fn disliked_replies(companion_id: i32) -> Result<Vec<String>> {
+        let con = db_pool::connection()?;
+        let mut stmt = con.prepare(
+            "SELECT m.content FROM message_feedback f JOIN messages m ON m.id = f.message_id
+             WHERE f.companion_id = ? AND f.rating < 0
+             ORDER BY f.id DESC LIMIT ?",
+        )?;
+        let rows = stmt.query_map(params![companion_id, DISLIKED_LIMIT], |row| row.get(0))?;
+        rows.collect()
+    }
+}
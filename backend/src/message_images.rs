@@ -0,0 +1,358 @@
+use crate::database::{Database, Message};
+use crate::db_pool;
+use crate::settings;
+use rusqlite::{params, Connection, Error, OptionalExtension, Result};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::path::PathBuf;
+
+const UPLOAD_DIR: &str = "uploads";
+pub const MAX_IMAGE_BYTES: usize = 10 * 1024 * 1024;
+pub const MAX_IMAGES_PER_MESSAGE: usize = 4;
+// Uploads no message refers to are removed once they are this old
+const UNUSED_UPLOAD_HOURS: i64 = 24;
+
+/// Picture formats accepted by /api/upload/image
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ImageFormat {
+    Png,
+    Jpeg,
+    Gif,
+    Webp,
+}
+
+impl ImageFormat {
+    pub fn mime(&self) -> &'static str {
+        match self {
+            ImageFormat::Png => "image/png",
+            ImageFormat::Jpeg => "image/jpeg",
+            ImageFormat::Gif => "image/gif",
+            ImageFormat::Webp => "image/webp",
+        }
+    }
+
+    pub fn extension(&self) -> &'static str {
+        match self {
+            ImageFormat::Png => "png",
+            ImageFormat::Jpeg => "jpg",
+            ImageFormat::Gif => "gif",
+            ImageFormat::Webp => "webp",
+        }
+    }
+
+    /// Format recognized from the first bytes of the file, the Content-Type isn't trusted
+    pub fn sniff(image: &[u8]) -> Option<ImageFormat> {
+        if image.starts_with(b"\x89PNG\r\n\x1a\n") {
+            Some(ImageFormat::Png)
+        } else if image.starts_with(&[0xFF, 0xD8, 0xFF]) {
+            Some(ImageFormat::Jpeg)
+        } else if image.starts_with(b"GIF87a") || image.starts_with(b"GIF89a") {
+            Some(ImageFormat::Gif)
+        } else if image.len() >= 12 && &image[0..4] == b"RIFF" && &image[8..12] == b"WEBP" {
+            Some(ImageFormat::Webp)
+        } else {
+            None
+        }
+    }
+
+    pub fn from_extension(file: &str) -> Option<ImageFormat> {
+        match file.rsplit('.').next()? {
+            "png" => Some(ImageFormat::Png),
+            "jpg" => Some(ImageFormat::Jpeg),
+            "gif" => Some(ImageFormat::Gif),
+            "webp" => Some(ImageFormat::Webp),
+            _ => None,
+        }
+    }
+}
+
+/// Picture attached to a message
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct MessageImage {
+    /// Name under assets/uploads, what POST /api/message takes in `images`
+    pub file: String,
+    pub url: String,
+    pub mime: String,
+    /// What the vision model saw, None until a vision model described it
+    pub description: Option<String>,
+}
+
+impl MessageImage {
+    fn new(file: String, mime: String, description: Option<String>) -> Self {
+        MessageImage {
+            url: format!("/api/uploads/{}", file),
+            file,
+            mime,
+            description,
+        }
+    }
+
+    /// Stand-in for the picture in a text prompt
+    pub fn placeholder(&self) -> String {
+        match self.description.as_deref().map(str::trim).filter(|d| !d.is_empty()) {
+            Some(description) => format!("[image: {}]", description),
+            None => "[image]".to_string(),
+        }
+    }
+}
+
+/// The message as the model gets to read it, with its pictures described after the text
+pub fn with_placeholders(mut message: Message) -> Message {
+    if message.images.is_empty() {
+        return message;
+    }
+    let placeholders: Vec<String> = message.images.iter().map(MessageImage::placeholder).collect();
+    let text = message.content.trim_end();
+    message.content = if text.is_empty() {
+        placeholders.join(" ")
+    } else {
+        format!("{} {}", text, placeholders.join(" "))
+    };
+    message
+}
+
+/// Folder the uploads are kept in, under the assets folder
+pub fn upload_dir() -> PathBuf {
+    settings::get().assets_dir.join(UPLOAD_DIR)
+}
+
+fn upload_path(file: &str) -> PathBuf {
+    upload_dir().join(file)
+}
+
+/// Names are made up here, anything else could point outside the upload folder
+pub fn valid_name(file: &str) -> bool {
+    !file.is_empty()
+        && file.chars().all(|c| c.is_ascii_alphanumeric() || c == '.')
+        && !file.starts_with('.')
+        && ImageFormat::from_extension(file).is_some()
+}
+
+/// Name of an upload as sent by clients, either the name itself or its URL
+pub fn file_name(reference: &str) -> &str {
+    reference.trim().trim_start_matches("/api/uploads/")
+}
+
+pub struct MessageImages {}
+
+impl MessageImages {
+    pub fn create() -> Result<()> {
+        let con = db_pool::connection()?;
+        con.execute_batch(
+            "CREATE TABLE IF NOT EXISTS uploaded_images (
+                file TEXT PRIMARY KEY,
+                mime TEXT NOT NULL,
+                bytes INTEGER NOT NULL,
+                description TEXT,
+                created_at DATETIME DEFAULT CURRENT_TIMESTAMP
+            );
+            CREATE TABLE IF NOT EXISTS message_images (
+                message_id INTEGER NOT NULL,
+                position INTEGER NOT NULL,
+                file TEXT NOT NULL,
+                PRIMARY KEY (message_id, position)
+            );
+            CREATE INDEX IF NOT EXISTS idx_message_images_file ON message_images(file);
+            CREATE TRIGGER IF NOT EXISTS message_images_delete AFTER DELETE ON messages BEGIN
+                DELETE FROM message_images WHERE message_id = old.id;
+            END;",
+        )?;
+        Ok(())
+    }
+
+    /// Keep an uploaded picture under assets/uploads, the same picture uploaded twice is stored once
+    pub fn store(image: &[u8], format: ImageFormat) -> std::io::Result<MessageImage> {
+        let hash = format!("{:x}", Sha256::digest(image));
+        let file = format!("{}.{}", &hash[..32], format.extension());
+        std::fs::create_dir_all(upload_dir())?;
+        let path = upload_path(&file);
+        if !path.exists() {
+            // Written next to it first, so a crash never leaves half a picture behind
+            let staged = upload_path(&format!("{}.part", file));
+            std::fs::write(&staged, image)?;
+            std::fs::rename(&staged, &path)?;
+        }
+        let to_io = |e: Error| std::io::Error::other(e.to_string());
+        let con = db_pool::connection().map_err(to_io)?;
+        con.execute(
+            "INSERT INTO uploaded_images (file, mime, bytes) VALUES (?, ?, ?) ON CONFLICT(file) DO NOTHING",
+            params![file, format.mime(), image.len()],
+        )
+        .map_err(to_io)?;
+        Ok(MessageImages::get(&file).map_err(to_io)?.unwrap_or_else(|| {
+            MessageImage::new(file.clone(), format.mime().to_string(), None)
+        }))
+    }
+
+    pub fn get(file: &str) -> Result<Option<MessageImage>> {
+        let con = db_pool::connection()?;
+        con.query_row(
+            "SELECT file, mime, description FROM uploaded_images WHERE file = ?",
+            [file_name(file)],
+            |row| Ok(MessageImage::new(row.get(0)?, row.get(1)?, row.get(2)?)),
+        )
+        .optional()
+    }
+
+    /// Bytes of an upload, None when there is no such picture
+    pub fn read(file: &str) -> std::io::Result<Option<(Vec<u8>, ImageFormat)>> {
+        let file = file_name(file);
+        let format = match ImageFormat::from_extension(file) {
+            Some(format) if valid_name(file) => format,
+            _ => return Ok(None),
+        };
+        match std::fs::read(upload_path(file)) {
+            Ok(image) => Ok(Some((image, format))),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e),
+        }
+    }
+
+    pub fn set_description(file: &str, description: &str) -> Result<()> {
+        let con = db_pool::connection()?;
+        con.execute(
+            "UPDATE uploaded_images SET description = ? WHERE file = ?",
+            params![description.trim(), file_name(file)],
+        )?;
+        Ok(())
+    }
+
+    /// Make sure every picture a new message refers to was uploaded, returns their names
+    ///
+    /// Fails with InvalidParameterName for unknown pictures or too many of them.
+    pub fn check(references: &[String]) -> Result<Vec<String>> {
+        if references.len() > MAX_IMAGES_PER_MESSAGE {
+            return Err(Error::InvalidParameterName(format!(
+                "A message can have at most {} images",
+                MAX_IMAGES_PER_MESSAGE
+            )));
+        }
+        let mut files = Vec::new();
+        for reference in references {
+            let file = file_name(reference);
+            if !valid_name(file) || MessageImages::get(file)?.is_none() {
+                return Err(Error::InvalidParameterName(format!(
+                    "Image {} not found, upload it with POST /api/upload/image first",
+                    reference
+                )));
+            }
+            files.push(file.to_string());
+        }
+        Ok(files)
+    }
+
+    /// Attach checked pictures to a message that was just stored on `con`
+    pub fn attach(con: &Connection, message_id: i64, files: &[String]) -> Result<()> {
+        for (position, file) in files.iter().enumerate() {
+            con.execute(
+                "INSERT INTO message_images (message_id, position, file) VALUES (?, ?, ?)",
+                params![message_id, position, file],
+            )?;
+        }
+        Ok(())
+    }
+
+    /// Add a stored picture to a message after the ones it already has
+    pub fn append(message_id: i32, file: &str) -> Result<()> {
+        let con = db_pool::connection()?;
+        con.execute(
+            "INSERT INTO message_images (message_id, position, file)
+             SELECT ?1, COALESCE(MAX(position) + 1, 0), ?2 FROM message_images WHERE message_id = ?1",
+            params![message_id, file_name(file)],
+        )?;
+        Database::clear_message_cache();
+        Ok(())
+    }
+
+    /// Fill in the pictures of messages read from the database
+    pub fn load_for(con: &Connection, messages: &mut [Message]) -> Result<()> {
+        if messages.is_empty() {
+            return Ok(());
+        }
+        let ids = messages.iter().map(|m| m.id.to_string()).collect::<Vec<_>>().join(",");
+        let mut stmt = con.prepare(&format!(
+            "SELECT message_images.message_id, uploaded_images.file, uploaded_images.mime, uploaded_images.description
+             FROM message_images JOIN uploaded_images ON uploaded_images.file = message_images.file
+             WHERE message_images.message_id IN ({})
+             ORDER BY message_images.message_id, message_images.position",
+            ids
+        ))?;
+        let rows = stmt.query_map([], |row| {
+            Ok((row.get::<_, i32>(0)?, MessageImage::new(row.get(1)?, row.get(2)?, row.get(3)?)))
+        })?;
+        for row in rows {
+            let (message_id, image) = row?;
+            if let Some(message) = messages.iter_mut().find(|m| m.id == message_id) {
+                message.images.push(image);
+            }
+        }
+        Ok(())
+    }
+
+    /// Maintenance job entry point, removes uploads no message refers to after a day
+    pub fn prune_unused() -> Result<String, String> {
+        let con = db_pool::connection().map_err(|e| e.to_string())?;
+        let unused: Vec<String> = con
+            .prepare(
+                "SELECT file FROM uploaded_images
+                 WHERE created_at < datetime('now', ?)
+                   AND file NOT IN (SELECT file FROM message_images)",
+            )
+            .and_then(|mut stmt| {
+                stmt.query_map([format!("-{} hours", UNUSED_UPLOAD_HOURS)], |row| row.get(0))?
+                    .collect::<Result<Vec<String>>>()
+            })
+            .map_err(|e| e.to_string())?;
+        for file in &unused {
+            match std::fs::remove_file(upload_path(file)) {
+                Ok(()) => {}
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+                Err(e) => return Err(format!("could not remove {}: {}", file, e)),
+            }
+            con.execute("DELETE FROM uploaded_images WHERE file = ?", [file])
+                .map_err(|e| e.to_string())?;
+        }
+        if unused.is_empty() {
+            Ok(String::new())
+        } else {
+            Ok(format!("removed {} unused uploaded images", unused.len()))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_image_placeholders() {
+        assert_eq!(ImageFormat::sniff(b"\x89PNG\r\n\x1a\n...."), Some(ImageFormat::Png));
+        assert_eq!(ImageFormat::sniff(&[0xFF, 0xD8, 0xFF, 0xE0]), Some(ImageFormat::Jpeg));
+        assert_eq!(ImageFormat::sniff(b"RIFF\0\0\0\0WEBPVP8 "), Some(ImageFormat::Webp));
+        assert_eq!(ImageFormat::sniff(b"RIFF\0\0\0\0WAVEfmt "), None);
+
+        assert!(valid_name("0123abcd.png"));
+        assert!(!valid_name("../companion_database.db"));
+        assert!(!valid_name("a/b.png"));
+        assert!(!valid_name(".png"));
+        assert_eq!(file_name("/api/uploads/0123abcd.png"), "0123abcd.png");
+
+        let message = Message {
+            id: 1,
+            ai: false,
+            content: "Look at this! ".to_string(),
+            created_at: String::new(),
+            author_id: Some(1),
+            images: vec![
+                MessageImage::new("a.png".to_string(), "image/png".to_string(), Some("A cat on a sofa".to_string())),
+                MessageImage::new("b.jpg".to_string(), "image/jpeg".to_string(), None),
+            ],
+            stats: None,
+        };
+        assert_eq!(message.images[0].url, "/api/uploads/a.png");
+        assert_eq!(
+            with_placeholders(message).content,
+            "Look at this! [image: A cat on a sofa] [image]"
+        );
+    }
+}
@@ -0,0 +1,261 @@
+use crate::database::{Database, Message, MESSAGE_COLUMNS};
+use crate::db_pool;
+use chrono::NaiveDate;
+use rusqlite::types::Value;
+use rusqlite::{params, params_from_iter, Connection, Result};
+use serde::Serialize;
+
+// Marks FTS5 puts around matched terms, replaced by <mark> once the snippet is escaped
+const MATCH_START: &str = "\u{2}";
+const MATCH_END: &str = "\u{3}";
+// Words of the message shown around the matched terms
+const SNIPPET_WORDS: i32 = 16;
+pub const MAX_LIMIT: usize = 100;
+pub const MAX_CONTEXT: usize = 5;
+// created_at ("Monday 16.10.2026 17:21") as "2026-10-16", messages are stored in local time
+const MESSAGE_DAY: &str = "substr(m.created_at, -10, 4) || '-' || substr(m.created_at, -13, 2) || '-' || substr(m.created_at, -16, 2)";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Speaker {
+    Ai,
+    User,
+}
+
+impl Speaker {
+    pub fn parse(name: &str) -> Option<Speaker> {
+        match name {
+            "ai" => Some(Speaker::Ai),
+            "user" => Some(Speaker::User),
+            _ => None,
+        }
+    }
+}
+
+/// What to look for, everything but the query is optional
+#[derive(Debug, Clone)]
+pub struct SearchFilters {
+    pub companion_id: i32,
+    pub conversation_id: Option<i32>,
+    pub speaker: Option<Speaker>,
+    /// First and last day to search, both included
+    pub from: Option<NaiveDate>,
+    pub to: Option<NaiveDate>,
+    pub limit: usize,
+    pub offset: usize,
+    /// Messages shown before and after every hit
+    pub context: usize,
+}
+
+/// A message matching the query with the conversation around it
+#[derive(Serialize, Debug)]
+pub struct SearchHit {
+    pub message: Message,
+    pub conversation_id: Option<i32>,
+    /// Part of the message around the matched terms, HTML escaped with the terms in <mark>
+    pub snippet: String,
+    /// Earlier messages of the conversation, oldest first
+    pub before: Vec<Message>,
+    pub after: Vec<Message>,
+}
+
+#[derive(Serialize, Debug)]
+pub struct SearchPage {
+    pub total: usize,
+    pub results: Vec<SearchHit>,
+}
+
+/// Full-text index over the messages, kept up to date by triggers on the messages table
+pub struct MessageSearch {}
+
+impl MessageSearch {
+    pub fn create() -> Result<()> {
+        let con = db_pool::connection()?;
+        let indexed: bool = con.query_row(
+            "SELECT EXISTS(SELECT 1 FROM sqlite_master WHERE type = 'table' AND name = 'messages_fts')",
+            [],
+            |row| row.get(0),
+        )?;
+        con.execute_batch(
+            "CREATE VIRTUAL TABLE IF NOT EXISTS messages_fts USING fts5(
+                content,
+                content = 'messages',
+                content_rowid = 'id',
+                tokenize = 'unicode61 remove_diacritics 2'
+            );
+            CREATE TRIGGER IF NOT EXISTS messages_fts_insert AFTER INSERT ON messages BEGIN
+                INSERT INTO messages_fts (rowid, content) VALUES (new.id, new.content);
+            END;
+            CREATE TRIGGER IF NOT EXISTS messages_fts_delete AFTER DELETE ON messages BEGIN
+                INSERT INTO messages_fts (messages_fts, rowid, content) VALUES ('delete', old.id, old.content);
+            END;
+            CREATE TRIGGER IF NOT EXISTS messages_fts_update AFTER UPDATE OF content ON messages BEGIN
+                INSERT INTO messages_fts (messages_fts, rowid, content) VALUES ('delete', old.id, old.content);
+                INSERT INTO messages_fts (rowid, content) VALUES (new.id, new.content);
+            END;",
+        )?;
+        // Messages written before the index existed
+        if !indexed {
+            con.execute("INSERT INTO messages_fts (messages_fts) VALUES ('rebuild')", [])?;
+        }
+        Ok(())
+    }
+
+    /// Messages of a companion matching `query`, best matches first
+    ///
+    /// Returns None when the query has nothing to search for.
+    pub fn search(query: &str, filters: &SearchFilters) -> Result<Option<SearchPage>> {
+        let fts_query = match fts_query(query) {
+            Some(fts_query) => fts_query,
+            None => return Ok(None),
+        };
+        let mut conditions = vec!["messages_fts MATCH ?".to_string(), "m.companion_id = ?".to_string()];
+        let mut values = vec![Value::Text(fts_query), Value::Integer(filters.companion_id.into())];
+        if let Some(conversation_id) = filters.conversation_id {
+            conditions.push("m.conversation_id = ?".to_string());
+            values.push(Value::Integer(conversation_id.into()));
+        }
+        if let Some(speaker) = filters.speaker {
+            conditions.push("m.ai = ?".to_string());
+            values.push(Value::Integer((speaker == Speaker::Ai).into()));
+        }
+        if let Some(from) = filters.from {
+            conditions.push(format!("{} >= ?", MESSAGE_DAY));
+            values.push(Value::Text(from.format("%Y-%m-%d").to_string()));
+        }
+        if let Some(to) = filters.to {
+            conditions.push(format!("{} <= ?", MESSAGE_DAY));
+            values.push(Value::Text(to.format("%Y-%m-%d").to_string()));
+        }
+        let from_where = format!(
+            "FROM messages_fts JOIN messages m ON m.id = messages_fts.rowid WHERE {}",
+            conditions.join(" AND ")
+        );
+
+        let con = db_pool::connection()?;
+        let total: usize = con.query_row(
+            &format!("SELECT COUNT(*) {}", from_where),
+            params_from_iter(values.iter()),
+            |row| row.get(0),
+        )?;
+        let mut stmt = con.prepare(&format!(
+            "SELECT m.id, m.ai, m.content, m.created_at, m.author_id, m.prompt_tokens,
+                m.completion_tokens, m.generation_ms, m.model_name, m.conversation_id,
+                snippet(messages_fts, 0, '{}', '{}', '…', {})
+             {} ORDER BY messages_fts.rank, m.id DESC LIMIT {} OFFSET {}",
+            MATCH_START, MATCH_END, SNIPPET_WORDS, from_where, filters.limit, filters.offset
+        ))?;
+        let rows = stmt.query_map(params_from_iter(values.iter()), |row| {
+            Ok((
+                Database::message_from_row(row)?,
+                row.get::<_, Option<i32>>(9)?,
+                row.get::<_, String>(10)?,
+            ))
+        })?;
+        let mut results = Vec::new();
+        for row in rows {
+            let (message, conversation_id, snippet) = row?;
+            let (before, after) = match conversation_id {
+                Some(conversation_id) if filters.context > 0 => {
+                    surrounding(&con, conversation_id, message.id, filters.context)?
+                }
+                _ => (Vec::new(), Vec::new()),
+            };
+            results.push(SearchHit {
+                message,
+                conversation_id,
+                snippet: highlight(&snippet),
+                before,
+                after,
+            });
+        }
+        Ok(Some(SearchPage { total, results }))
+    }
+}
+
+/// Up to `count` messages of the conversation before and after the message
+fn surrounding(
+    con: &Connection,
+    conversation_id: i32,
+    message_id: i32,
+    count: usize,
+) -> Result<(Vec<Message>, Vec<Message>)> {
+    let neighbours = |condition: &str| -> Result<Vec<Message>> {
+        let mut stmt = con.prepare_cached(&format!(
+            "SELECT {} FROM messages WHERE conversation_id = ? AND {}",
+            MESSAGE_COLUMNS, condition
+        ))?;
+        let rows = stmt.query_map(
+            params![conversation_id, message_id, count],
+            Database::message_from_row,
+        )?;
+        rows.collect()
+    };
+    let mut before = neighbours("id < ? ORDER BY id DESC LIMIT ?")?;
+    before.reverse();
+    let after = neighbours("id > ? ORDER BY id LIMIT ?")?;
+    Ok((before, after))
+}
+
+/// Turn what the user typed into an FTS5 query, None when there is no word in it
+///
+/// "Double quoted" parts are searched as phrases, a word ending in * matches every word it starts,
+/// everything else has to appear somewhere in the message. Operators and other FTS5 syntax are
+/// taken literally so a typed query can't fail to parse.
+fn fts_query(query: &str) -> Option<String> {
+    let mut terms = Vec::new();
+    for (index, part) in query.split('"').enumerate() {
+        // Odd parts were between quotes, an unclosed quote runs to the end
+        if index % 2 == 1 {
+            let phrase = part.split_whitespace().collect::<Vec<_>>().join(" ");
+            if !phrase.is_empty() {
+                terms.push(format!("\"{}\"", phrase));
+            }
+            continue;
+        }
+        for word in part.split_whitespace() {
+            let (word, prefix) = match word.strip_suffix('*') {
+                Some(word) => (word, true),
+                None => (word, false),
+            };
+            if word.chars().any(char::is_alphanumeric) {
+                terms.push(format!("\"{}\"{}", word, if prefix { "*" } else { "" }));
+            }
+        }
+    }
+    if terms.is_empty() {
+        None
+    } else {
+        Some(terms.join(" "))
+    }
+}
+
+/// Escape the snippet for HTML and put the matched terms in <mark>
+fn highlight(snippet: &str) -> String {
+    snippet
+        .replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+        .replace(MATCH_START, "<mark>")
+        .replace(MATCH_END, "</mark>")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_search_query() {
+        assert_eq!(fts_query("coffee  rain"), Some(r#""coffee" "rain""#.to_string()));
+        assert_eq!(
+            fts_query(r#"coff* "rainy   day" OR"#),
+            Some(r#""coff"* "rainy day" "OR""#.to_string())
+        );
+        assert_eq!(fts_query(r#"say "hello there"#), Some(r#""say" "hello there""#.to_string()));
+        assert_eq!(fts_query(" * - \"\" "), None);
+        assert_eq!(
+            highlight("a <b> & \u{2}tea\u{3}…"),
+            "a &lt;b&gt; &amp; <mark>tea</mark>…"
+        );
+    }
+}
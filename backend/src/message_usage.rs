@@ -0,0 +1,284 @@
+use crate::db_pool;
+use chrono::{Datelike, Duration, NaiveDate, NaiveDateTime};
+use rusqlite::Result;
+use serde::{Deserialize, Serialize};
+
+pub const MAX_DAYS: u32 = 366;
+pub const MAX_WEEKS: u32 = 104;
+
+/// Tokens a reply took, the prompt as it was put together and what the model generated
+#[derive(Serialize, Debug, Clone, Copy, PartialEq, Default)]
+pub struct TokenCounts {
+    pub prompt_tokens: u32,
+    pub completion_tokens: u32,
+}
+
+/// What generating a reply took, stored with the reply
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Default)]
+pub struct ReplyStats {
+    pub prompt_tokens: u32,
+    pub completion_tokens: u32,
+    /// Time the model spent on the reply, None for replies stored before it was kept
+    pub generation_ms: Option<u64>,
+    /// Model file without its extension, or the model of the LLM server
+    pub model_name: Option<String>,
+}
+
+impl ReplyStats {
+    /// Stats from four columns starting at `first`, None for messages without token counts
+    pub fn from_row(row: &rusqlite::Row, first: usize) -> Result<Option<ReplyStats>> {
+        let prompt_tokens: Option<u32> = row.get(first)?;
+        let completion_tokens: Option<u32> = row.get(first + 1)?;
+        match (prompt_tokens, completion_tokens) {
+            (Some(prompt_tokens), Some(completion_tokens)) => Ok(Some(ReplyStats {
+                prompt_tokens,
+                completion_tokens,
+                generation_ms: row.get(first + 2)?,
+                model_name: row.get(first + 3)?,
+            })),
+            _ => Ok(None),
+        }
+    }
+}
+
+#[derive(Serialize, Debug, Clone, Copy, PartialEq, Default)]
+pub struct Usage {
+    pub replies: u32,
+    pub prompt_tokens: u64,
+    pub completion_tokens: u64,
+    pub total_tokens: u64,
+}
+
+impl Usage {
+    fn add(&mut self, counts: TokenCounts) {
+        self.replies += 1;
+        self.prompt_tokens += counts.prompt_tokens as u64;
+        self.completion_tokens += counts.completion_tokens as u64;
+        self.total_tokens = self.prompt_tokens + self.completion_tokens;
+    }
+}
+
+/// Usage of a day, or of the week starting on Monday `start`
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct Period {
+    pub start: NaiveDate,
+    #[serde(flatten)]
+    pub usage: Usage,
+}
+
+/// How much of the configured budget an average reply uses
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct BudgetUse {
+    pub context_window_size: usize,
+    pub max_response_tokens: usize,
+    pub avg_prompt_tokens: f64,
+    pub avg_completion_tokens: f64,
+    pub context_used_percent: f64,
+    pub response_used_percent: f64,
+}
+
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct UsageReport {
+    /// Oldest day first, days without replies included
+    pub daily: Vec<Period>,
+    pub weekly: Vec<Period>,
+    /// Every reply with token counts, not just the ones in the periods
+    pub total: Usage,
+    pub budget: BudgetUse,
+}
+
+/// Day a message was written, messages store their date as "Friday 16.10.2026 18:51"
+fn message_day(created_at: &str) -> Option<NaiveDate> {
+    let (_, date) = created_at.trim().split_once(' ')?;
+    NaiveDateTime::parse_from_str(date, "%d.%m.%Y %H:%M")
+        .ok()
+        .map(|at| at.date())
+}
+
+fn percent(part: f64, whole: usize) -> f64 {
+    if whole == 0 {
+        0.0
+    } else {
+        (part / whole as f64 * 1000.0).round() / 10.0
+    }
+}
+
+fn periods(first: NaiveDate, count: u32, step: i64) -> Vec<Period> {
+    (0..count as i64)
+        .map(|i| Period {
+            start: first + Duration::days(i * step),
+            usage: Usage::default(),
+        })
+        .collect()
+}
+
+fn aggregate(
+    replies: &[(NaiveDate, TokenCounts)],
+    today: NaiveDate,
+    days: u32,
+    weeks: u32,
+    context_window_size: usize,
+    max_response_tokens: usize,
+) -> UsageReport {
+    let first_day = today - Duration::days(days as i64 - 1);
+    let this_week = today - Duration::days(today.weekday().num_days_from_monday() as i64);
+    let first_week = this_week - Duration::weeks(weeks as i64 - 1);
+    let mut daily = periods(first_day, days, 1);
+    let mut weekly = periods(first_week, weeks, 7);
+    let mut total = Usage::default();
+    for (day, counts) in replies {
+        total.add(*counts);
+        if *day >= first_day && *day <= today {
+            daily[(*day - first_day).num_days() as usize]
+                .usage
+                .add(*counts);
+        }
+        if *day >= first_week && *day <= today {
+            weekly[((*day - first_week).num_days() / 7) as usize]
+                .usage
+                .add(*counts);
+        }
+    }
+    let average = |tokens: u64| {
+        if total.replies == 0 {
+            0.0
+        } else {
+            (tokens as f64 / total.replies as f64 * 10.0).round() / 10.0
+        }
+    };
+    let avg_prompt_tokens = average(total.prompt_tokens);
+    let avg_completion_tokens = average(total.completion_tokens);
+    UsageReport {
+        daily,
+        weekly,
+        total,
+        budget: BudgetUse {
+            context_window_size,
+            max_response_tokens,
+            avg_prompt_tokens,
+            avg_completion_tokens,
+            context_used_percent: percent(
+                avg_prompt_tokens + avg_completion_tokens,
+                context_window_size,
+            ),
+            response_used_percent: percent(avg_completion_tokens, max_response_tokens),
+        },
+    }
+}
+
+pub struct MessageUsage {}
+
+impl MessageUsage {
+    /// Token usage of the stored replies, of one companion or of all of them
+    pub fn report(
+        companion_id: Option<i32>,
+        today: NaiveDate,
+        days: u32,
+        weeks: u32,
+        context_window_size: usize,
+        max_response_tokens: usize,
+    ) -> Result<UsageReport> {
+        let con = db_pool::connection()?;
+        let mut stmt = con.prepare(
+            "SELECT created_at, prompt_tokens, completion_tokens FROM messages
+             WHERE completion_tokens IS NOT NULL AND (?1 IS NULL OR companion_id = ?1)",
+        )?;
+        let rows = stmt.query_map([companion_id], |row| {
+            Ok((
+                row.get::<_, String>(0)?,
+                TokenCounts {
+                    prompt_tokens: row.get::<_, Option<u32>>(1)?.unwrap_or(0),
+                    completion_tokens: row.get(2)?,
+                },
+            ))
+        })?;
+        let mut replies = Vec::new();
+        for row in rows {
+            let (created_at, counts) = row?;
+            match message_day(&created_at) {
+                Some(day) => replies.push((day, counts)),
+                None => tracing::warn!(
+                    "⚠️ Message date {:?} could not be read, its tokens are not counted",
+                    created_at
+                ),
+            }
+        }
+        Ok(aggregate(
+            &replies,
+            today,
+            days,
+            weeks,
+            context_window_size,
+            max_response_tokens,
+        ))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_usage_aggregates() {
+        assert_eq!(
+            message_day("Friday 16.10.2026 18:51"),
+            NaiveDate::from_ymd_opt(2026, 10, 16)
+        );
+        assert_eq!(message_day("yesterday"), None);
+
+        let day = |d| NaiveDate::from_ymd_opt(2026, 10, d).unwrap();
+        let counts = |prompt_tokens, completion_tokens| TokenCounts {
+            prompt_tokens,
+            completion_tokens,
+        };
+        // Friday the 16th, the week started on Monday the 12th
+        let replies = [
+            (day(16), counts(900, 100)),
+            (day(16), counts(1100, 60)),
+            (day(12), counts(800, 40)),
+            (day(9), counts(1000, 200)),
+            (day(1), counts(1200, 0)),
+        ];
+        let report = aggregate(&replies, day(16), 7, 2, 2048, 256);
+        assert_eq!(report.daily.len(), 7);
+        assert_eq!(report.daily[0].start, day(10));
+        assert_eq!(report.daily[6].usage.replies, 2);
+        assert_eq!(report.daily[6].usage.total_tokens, 2160);
+        assert_eq!(report.daily[2].usage.completion_tokens, 40);
+        assert_eq!(report.daily[1].usage, Usage::default());
+
+        assert_eq!(report.weekly[0].start, day(5));
+        assert_eq!(report.weekly[0].usage.replies, 1);
+        assert_eq!(report.weekly[1].start, day(12));
+        assert_eq!(report.weekly[1].usage.replies, 3);
+
+        assert_eq!(report.total.replies, 5);
+        assert_eq!(report.budget.avg_prompt_tokens, 1000.0);
+        assert_eq!(report.budget.avg_completion_tokens, 80.0);
+        assert_eq!(report.budget.context_used_percent, 52.7);
+        assert_eq!(report.budget.response_used_percent, 31.3);
+    }
+
+    #[test]
+    fn test_reply_stats_from_row() {
+        let con = rusqlite::Connection::open_in_memory().unwrap();
+        let stats_of =
+            |sql: &str| con.query_row(sql, [], |row| ReplyStats::from_row(row, 1)).unwrap();
+        assert_eq!(
+            stats_of("SELECT 'id', 812, 64, 2150, 'llama-2-7b-chat.Q4_K_M'"),
+            Some(ReplyStats {
+                prompt_tokens: 812,
+                completion_tokens: 64,
+                generation_ms: Some(2150),
+                model_name: Some("llama-2-7b-chat.Q4_K_M".to_string()),
+            })
+        );
+        // Replies stored before timings were kept
+        assert_eq!(
+            stats_of("SELECT 'id', 812, 64, NULL, NULL").map(|stats| stats.generation_ms),
+            Some(None)
+        );
+        // The user's messages
+        assert_eq!(stats_of("SELECT 'id', NULL, NULL, NULL, NULL"), None);
+    }
+}
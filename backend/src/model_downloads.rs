@@ -0,0 +1,423 @@
+use crate::database::get_current_date;
+use crate::db_pool;
+use crate::event_bus;
+use crate::instance_lock;
+use crate::llm_scanner::LlmScanner;
+use rusqlite::{params, Error, OptionalExtension, Result};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+use tokio::io::AsyncWriteExt;
+
+const WORKER_INTERVAL_SECONDS: u64 = 5;
+// How often progress is saved, published and checked for cancellation
+const PROGRESS_INTERVAL_SECONDS: u64 = 1;
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ModelDownload {
+    pub id: i64,
+    pub url: String,
+    pub filename: String,
+    pub directory: String,
+    /// Expected SHA-256, given by the user or taken from the Hugging Face ETag
+    pub sha256: Option<String>,
+    pub total_bytes: Option<u64>,
+    pub downloaded_bytes: u64,
+    /// "queued", "downloading", "verifying", "completed", "failed" or "cancelled"
+    pub status: String,
+    pub error: Option<String>,
+    pub created_at: String,
+    pub updated_at: String,
+}
+
+impl ModelDownload {
+    pub fn target_path(&self) -> PathBuf {
+        Path::new(&self.directory).join(&self.filename)
+    }
+
+    /// Partial file kept between attempts so downloads can resume where they stopped
+    pub fn part_path(&self) -> PathBuf {
+        Path::new(&self.directory).join(format!("{}.part", self.filename))
+    }
+}
+
+/// Normalize a Hugging Face file URL to its download form, returns the URL and the file name
+pub fn huggingface_download_url(url: &str) -> Result<(String, String), String> {
+    let url = url.trim();
+    let path = ["https://huggingface.co/", "https://hf.co/"]
+        .iter()
+        .find_map(|prefix| url.strip_prefix(prefix))
+        .ok_or_else(|| "Only https://huggingface.co/ URLs are supported".to_string())?;
+    let path = path.split(['?', '#']).next().unwrap_or_default();
+    // Links copied from the file page point at the viewer instead of the file
+    let path = path.replacen("/blob/", "/resolve/", 1);
+    if !path.contains("/resolve/") {
+        return Err("URL must point to a file, e.g. .../resolve/main/model.gguf".to_string());
+    }
+    let filename = path.rsplit('/').next().unwrap_or_default().to_string();
+    if !filename.to_ascii_lowercase().ends_with(".gguf") || filename.starts_with('.') {
+        return Err("Only .gguf model files can be downloaded".to_string());
+    }
+    Ok((format!("https://huggingface.co/{}", path), filename))
+}
+
+/// SHA-256 from an ETag header, Hugging Face serves LFS files with their hash as ETag
+pub fn sha256_from_etag(etag: &str) -> Option<String> {
+    let etag = etag.trim().trim_start_matches("W/").trim_matches('"');
+    if etag.len() == 64 && etag.chars().all(|c| c.is_ascii_hexdigit()) {
+        Some(etag.to_ascii_lowercase())
+    } else {
+        None
+    }
+}
+
+enum Outcome {
+    Completed,
+    Cancelled,
+}
+
+pub struct ModelDownloads {}
+
+impl ModelDownloads {
+    pub fn create() -> Result<(), Error> {
+        let con = db_pool::connection()?;
+        con.execute(
+            "CREATE TABLE IF NOT EXISTS model_downloads (
+                id INTEGER PRIMARY KEY AUTOINCREMENT,
+                url TEXT NOT NULL,
+                filename TEXT NOT NULL,
+                directory TEXT NOT NULL,
+                sha256 TEXT,
+                total_bytes INTEGER,
+                downloaded_bytes INTEGER NOT NULL DEFAULT 0,
+                status TEXT NOT NULL DEFAULT 'queued' CHECK(status IN ('queued', 'downloading', 'verifying', 'completed', 'failed', 'cancelled')),
+                error TEXT,
+                created_at TEXT NOT NULL,
+                updated_at TEXT NOT NULL
+            )",
+            [],
+        )?;
+        Ok(())
+    }
+
+    /// Downloads interrupted by a restart or a leader change continue from their partial file
+    fn requeue_interrupted() -> Result<usize, Error> {
+        let con = db_pool::connection()?;
+        con.execute(
+            "UPDATE model_downloads SET status = 'queued' WHERE status IN ('downloading', 'verifying')",
+            [],
+        )
+    }
+
+    pub fn enqueue(url: &str, filename: &str, directory: &str, sha256: Option<&str>) -> Result<i64> {
+        let con = db_pool::connection()?;
+        let now = get_current_date();
+        con.execute(
+            "INSERT INTO model_downloads (url, filename, directory, sha256, created_at, updated_at)
+             VALUES (?, ?, ?, ?, ?, ?)",
+            params![url, filename, directory, sha256.map(|s| s.to_ascii_lowercase()), now, now],
+        )?;
+        Ok(con.last_insert_rowid())
+    }
+
+    fn query(filter: &str, args: &[&dyn rusqlite::ToSql]) -> Result<Vec<ModelDownload>> {
+        let con = db_pool::connection()?;
+        let mut stmt = con.prepare(&format!(
+            "SELECT id, url, filename, directory, sha256, total_bytes, downloaded_bytes, status, error, created_at, updated_at
+             FROM model_downloads {}",
+            filter
+        ))?;
+        let rows = stmt.query_map(args, |row| {
+            Ok(ModelDownload {
+                id: row.get(0)?,
+                url: row.get(1)?,
+                filename: row.get(2)?,
+                directory: row.get(3)?,
+                sha256: row.get(4)?,
+                total_bytes: row.get(5)?,
+                downloaded_bytes: row.get(6)?,
+                status: row.get(7)?,
+                error: row.get(8)?,
+                created_at: row.get(9)?,
+                updated_at: row.get(10)?,
+            })
+        })?;
+        rows.collect()
+    }
+
+    pub fn get_downloads() -> Result<Vec<ModelDownload>> {
+        ModelDownloads::query("ORDER BY id DESC", &[])
+    }
+
+    pub fn get_download(id: i64) -> Result<Option<ModelDownload>> {
+        Ok(ModelDownloads::query("WHERE id = ?", &[&id])?.pop())
+    }
+
+    fn get_status(id: i64) -> Result<Option<String>> {
+        let con = db_pool::connection()?;
+        con.query_row("SELECT status FROM model_downloads WHERE id = ?", [id], |row| row.get(0))
+            .optional()
+    }
+
+    fn set_status(id: i64, status: &str, error: Option<&str>) -> Result<()> {
+        let con = db_pool::connection()?;
+        con.execute(
+            "UPDATE model_downloads SET status = ?, error = ?, updated_at = ? WHERE id = ?",
+            params![status, error, get_current_date(), id],
+        )?;
+        Ok(())
+    }
+
+    fn save_progress(download: &ModelDownload) -> Result<()> {
+        let con = db_pool::connection()?;
+        con.execute(
+            "UPDATE model_downloads SET sha256 = ?, total_bytes = ?, downloaded_bytes = ?, updated_at = ? WHERE id = ?",
+            params![
+                download.sha256,
+                download.total_bytes,
+                download.downloaded_bytes,
+                get_current_date(),
+                download.id
+            ],
+        )?;
+        Ok(())
+    }
+
+    /// Queue a failed or cancelled download again, it continues from its partial file
+    pub fn resume(id: i64) -> Result<bool> {
+        let con = db_pool::connection()?;
+        let updated = con.execute(
+            "UPDATE model_downloads SET status = 'queued', error = NULL, updated_at = ?
+             WHERE id = ? AND status IN ('failed', 'cancelled')",
+            params![get_current_date(), id],
+        )?;
+        Ok(updated > 0)
+    }
+
+    /// Stop a download and throw away what was fetched so far
+    pub fn cancel(id: i64) -> Result<bool> {
+        let download = match ModelDownloads::get_download(id)? {
+            Some(download) if download.status != "completed" => download,
+            _ => return Ok(false),
+        };
+        // A running download notices the status change at its next progress update
+        ModelDownloads::set_status(id, "cancelled", None)?;
+        if download.status != "downloading" {
+            let _ = std::fs::remove_file(download.part_path());
+        }
+        Ok(true)
+    }
+
+    fn publish_progress(download: &ModelDownload) {
+        event_bus::publish(
+            "model_download_progress",
+            serde_json::json!({
+                "id": download.id,
+                "filename": download.filename,
+                "status": download.status,
+                "downloaded_bytes": download.downloaded_bytes,
+                "total_bytes": download.total_bytes,
+            }),
+        );
+    }
+
+    async fn download(client: &reqwest::Client, download: &mut ModelDownload) -> Result<Outcome, String> {
+        let part_path = download.part_path();
+        let mut offset = tokio::fs::metadata(&part_path)
+            .await
+            .map(|m| m.len())
+            .unwrap_or(0);
+
+        let mut request = client.get(&download.url);
+        if offset > 0 {
+            request = request.header(reqwest::header::RANGE, format!("bytes={}-", offset));
+        }
+        let mut response = request.send().await.map_err(|e| e.to_string())?;
+        let resumed = match response.status() {
+            reqwest::StatusCode::PARTIAL_CONTENT => true,
+            reqwest::StatusCode::OK => {
+                // The server ignored the range, start over
+                offset = 0;
+                false
+            }
+            // The partial file already holds the whole model
+            reqwest::StatusCode::RANGE_NOT_SATISFIABLE => return Ok(Outcome::Completed),
+            status => return Err(format!("Server answered {}", status)),
+        };
+        if download.sha256.is_none() {
+            download.sha256 = ["x-linked-etag", "etag"]
+                .iter()
+                .filter_map(|name| response.headers().get(*name))
+                .filter_map(|value| value.to_str().ok())
+                .find_map(sha256_from_etag);
+        }
+        download.total_bytes = response.content_length().map(|length| length + offset);
+        download.downloaded_bytes = offset;
+
+        let mut file = tokio::fs::OpenOptions::new()
+            .create(true)
+            .write(true)
+            .append(resumed)
+            .truncate(!resumed)
+            .open(&part_path)
+            .await
+            .map_err(|e| format!("Failed to open {}: {}", part_path.display(), e))?;
+        let mut last_progress = Instant::now();
+        while let Some(chunk) = response.chunk().await.map_err(|e| e.to_string())? {
+            file.write_all(&chunk)
+                .await
+                .map_err(|e| format!("Failed to write {}: {}", part_path.display(), e))?;
+            download.downloaded_bytes += chunk.len() as u64;
+            if last_progress.elapsed() >= Duration::from_secs(PROGRESS_INTERVAL_SECONDS) {
+                last_progress = Instant::now();
+                ModelDownloads::save_progress(download).map_err(|e| e.to_string())?;
+                ModelDownloads::publish_progress(download);
+                if ModelDownloads::get_status(download.id).ok().flatten().as_deref() == Some("cancelled") {
+                    drop(file);
+                    let _ = tokio::fs::remove_file(&part_path).await;
+                    return Ok(Outcome::Cancelled);
+                }
+            }
+        }
+        file.flush().await.map_err(|e| e.to_string())?;
+        ModelDownloads::save_progress(download).map_err(|e| e.to_string())?;
+        Ok(Outcome::Completed)
+    }
+
+    /// Verify the finished partial file and move it into place
+    async fn finish(download: &ModelDownload) -> Result<(), String> {
+        let part_path = download.part_path();
+        if let Some(expected) = &download.sha256 {
+            ModelDownloads::set_status(download.id, "verifying", None).map_err(|e| e.to_string())?;
+            let path = part_path.clone();
+            let actual = tokio::task::spawn_blocking(move || -> std::io::Result<String> {
+                let mut file = std::fs::File::open(path)?;
+                let mut hasher = Sha256::new();
+                std::io::copy(&mut file, &mut hasher)?;
+                Ok(format!("{:x}", hasher.finalize()))
+            })
+            .await
+            .map_err(|e| e.to_string())?
+            .map_err(|e| e.to_string())?;
+            if &actual != expected {
+                // A corrupt file can not be resumed, the next attempt starts from scratch
+                let _ = tokio::fs::remove_file(&part_path).await;
+                return Err(format!("Checksum mismatch, expected {} but got {}", expected, actual));
+            }
+        }
+        tokio::fs::rename(&part_path, download.target_path())
+            .await
+            .map_err(|e| format!("Failed to move model into place: {}", e))
+    }
+
+    /// Run one queued download to the end, failures are stored on the download
+    async fn process(client: &reqwest::Client, mut download: ModelDownload) -> Result<()> {
+        ModelDownloads::set_status(download.id, "downloading", None)?;
+        download.status = "downloading".to_string();
+        let result = match ModelDownloads::download(client, &mut download).await {
+            Ok(Outcome::Completed) => ModelDownloads::finish(&download).await,
+            Ok(Outcome::Cancelled) => {
+                tracing::info!("⏹️ Model download {} cancelled", download.filename);
+                return Ok(());
+            }
+            Err(e) => Err(e),
+        };
+        match result {
+            Ok(_) => {
+                ModelDownloads::set_status(download.id, "completed", None)?;
+                let target = download.target_path().display().to_string();
+                // The scanner picks the model up from its directory, report it the same way
+                let model = LlmScanner::new()
+                    .scan_for_models()?
+                    .into_iter()
+                    .find(|model| Path::new(&model.path) == download.target_path());
+                tracing::info!("✓ Model downloaded to {}", target);
+                event_bus::publish(
+                    "model_download_completed",
+                    serde_json::json!({ "id": download.id, "path": target, "model": model }),
+                );
+            }
+            Err(e) => {
+                tracing::warn!("⚠️ Model download {} failed: {}", download.filename, e);
+                ModelDownloads::set_status(download.id, "failed", Some(&e))?;
+                download.status = "failed".to_string();
+                ModelDownloads::publish_progress(&download);
+            }
+        }
+        Ok(())
+    }
+
+    /// Background loop that downloads queued models one at a time
+    pub async fn run_worker() {
+        // No overall timeout, models are several gigabytes
+        let client = match reqwest::Client::builder()
+            .connect_timeout(Duration::from_secs(30))
+            .build()
+        {
+            Ok(client) => client,
+            Err(e) => {
+                tracing::warn!("⚠️ Failed to create model download HTTP client: {}", e);
+                return;
+            }
+        };
+        let mut interval = tokio::time::interval(Duration::from_secs(WORKER_INTERVAL_SECONDS));
+        let mut was_leader = false;
+        loop {
+            interval.tick().await;
+            // Only the instance holding the database lock downloads, the others would fight over the files
+            let leader = instance_lock::is_leader();
+            if leader && !was_leader {
+                if let Err(e) = ModelDownloads::requeue_interrupted() {
+                    tracing::warn!("⚠️ Failed to requeue interrupted model downloads: {}", e);
+                }
+            }
+            was_leader = leader;
+            if !leader {
+                continue;
+            }
+            let queued = match ModelDownloads::query("WHERE status = 'queued' ORDER BY id", &[]) {
+                Ok(queued) => queued,
+                Err(e) => {
+                    tracing::warn!("⚠️ Failed to read model download queue: {}", e);
+                    continue;
+                }
+            };
+            for download in queued {
+                if let Err(e) = ModelDownloads::process(&client, download).await {
+                    tracing::warn!("⚠️ Failed to process model download: {}", e);
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_huggingface_download_url() {
+        assert_eq!(
+            huggingface_download_url(
+                "https://huggingface.co/TheBloke/Mistral-7B-GGUF/blob/main/mistral-7b.Q4_K_M.gguf?download=true"
+            ),
+            Ok((
+                "https://huggingface.co/TheBloke/Mistral-7B-GGUF/resolve/main/mistral-7b.Q4_K_M.gguf".to_string(),
+                "mistral-7b.Q4_K_M.gguf".to_string()
+            ))
+        );
+        assert!(huggingface_download_url("https://hf.co/org/repo/resolve/main/model.GGUF").is_ok());
+        assert!(huggingface_download_url("https://example.com/model.gguf").is_err());
+        assert!(huggingface_download_url("https://huggingface.co/org/repo").is_err());
+        assert!(huggingface_download_url("https://huggingface.co/org/repo/resolve/main/model.bin").is_err());
+    }
+
+    #[test]
+    fn test_sha256_from_etag() {
+        let hash = "a".repeat(64);
+        assert_eq!(sha256_from_etag(&format!("\"{}\"", hash)), Some(hash.clone()));
+        assert_eq!(sha256_from_etag(&format!("W/\"{}\"", hash.to_uppercase())), Some(hash));
+        assert_eq!(sha256_from_etag("\"5d41402abc4b2a76b9719d911017c592\""), None);
+    }
+}
@@ -0,0 +1,274 @@
+use crate::attitude_dimensions::dimension_value;
+use crate::database::CompanionAttitude;
+use crate::db_pool;
+use crate::event_bus;
+use chrono::{DateTime, Utc};
+use rusqlite::{params, OptionalExtension, Result};
+use serde::Serialize;
+
+// Hours until a mood has lost half of its intensity
+const HALF_LIFE_HOURS: f32 = 3.0;
+// A mood weaker than this has worn off
+const MIN_INTENSITY: f32 = 0.15;
+// Attitude points gained within one message that make a mood of full intensity
+const FULL_INTENSITY_CHANGE: f32 = 20.0;
+
+/// Lasting state of mind of a companion, outliving the message that caused it
+#[derive(Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum MoodKind {
+    Neutral,
+    Cheerful,
+    Sulky,
+    Anxious,
+    Affectionate,
+}
+
+// Dimensions whose rise pushes the companion toward a mood
+const MOOD_DIMENSIONS: [(MoodKind, &[&str]); 4] = [
+    (MoodKind::Cheerful, &["joy", "gratitude", "curiosity"]),
+    (MoodKind::Sulky, &["anger", "sorrow", "jealousy", "disgust"]),
+    (MoodKind::Anxious, &["anxiety", "fear", "suspicion"]),
+    (MoodKind::Affectionate, &["love", "attraction", "butterflies", "empathy"]),
+];
+
+impl MoodKind {
+    pub fn name(self) -> &'static str {
+        match self {
+            MoodKind::Neutral => "neutral",
+            MoodKind::Cheerful => "cheerful",
+            MoodKind::Sulky => "sulky",
+            MoodKind::Anxious => "anxious",
+            MoodKind::Affectionate => "affectionate",
+        }
+    }
+
+    fn parse(name: &str) -> MoodKind {
+        MoodKind::from_name(name).unwrap_or(MoodKind::Neutral)
+    }
+
+    /// The mood of that name, None for a name no mood has
+    pub fn from_name(name: &str) -> Option<MoodKind> {
+        MOOD_DIMENSIONS
+            .iter()
+            .map(|(kind, _)| *kind)
+            .chain([MoodKind::Neutral])
+            .find(|kind| kind.name() == name)
+    }
+}
+
+/// A companion's mood as it is right now, decay included
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct Mood {
+    pub companion_id: i32,
+    pub mood: MoodKind,
+    /// From 0 to 1, 0 for a neutral mood
+    pub intensity: f32,
+    /// When the companion got into this mood, None for a neutral one
+    pub since: Option<DateTime<Utc>>,
+}
+
+/// The mood a change of attitude pushes toward, and how strongly
+fn stimulus(previous: &CompanionAttitude, current: &CompanionAttitude) -> Option<(MoodKind, f32)> {
+    MOOD_DIMENSIONS
+        .iter()
+        .map(|(kind, dimensions)| {
+            let rise: f32 = dimensions
+                .iter()
+                .map(|name| {
+                    let before = dimension_value(previous, name).unwrap_or_default();
+                    let after = dimension_value(current, name).unwrap_or_default();
+                    (after - before).max(0.0)
+                })
+                .sum();
+            (*kind, (rise / FULL_INTENSITY_CHANGE).min(1.0))
+        })
+        .max_by(|a, b| a.1.total_cmp(&b.1))
+        .filter(|(_, intensity)| *intensity >= MIN_INTENSITY)
+}
+
+/// Intensity left after `hours`, halving every HALF_LIFE_HOURS
+fn decayed(intensity: f32, hours: f32) -> f32 {
+    intensity * 0.5f32.powf(hours.max(0.0) / HALF_LIFE_HOURS)
+}
+
+/// Next mood and its intensity, the same feeling deepens a mood and a stronger one replaces it
+///
+/// A weaker different feeling wears the current mood down instead of replacing it.
+fn transition(current: MoodKind, intensity: f32, stimulus: (MoodKind, f32)) -> (MoodKind, f32) {
+    let (kind, strength) = stimulus;
+    if current == MoodKind::Neutral || intensity < MIN_INTENSITY {
+        (kind, strength)
+    } else if current == kind {
+        (kind, (intensity + strength).min(1.0))
+    } else if strength > intensity {
+        (kind, strength)
+    } else {
+        let left = intensity - strength;
+        if left < MIN_INTENSITY {
+            (MoodKind::Neutral, 0.0)
+        } else {
+            (current, left)
+        }
+    }
+}
+
+impl Mood {
+    pub fn create() -> Result<usize> {
+        let con = db_pool::connection()?;
+        con.execute(
+            "CREATE TABLE IF NOT EXISTS companion_mood (
+                companion_id INTEGER PRIMARY KEY,
+                mood TEXT NOT NULL,
+                intensity REAL NOT NULL,
+                since TEXT NOT NULL,
+                updated_at TEXT NOT NULL
+            )",
+            [],
+        )
+    }
+
+    fn neutral(companion_id: i32) -> Mood {
+        Mood {
+            companion_id,
+            mood: MoodKind::Neutral,
+            intensity: 0.0,
+            since: None,
+        }
+    }
+
+    /// Mood of a companion with the time since it was last stirred taken off its intensity
+    pub fn get(companion_id: i32) -> Result<Mood> {
+        let con = db_pool::connection()?;
+        let stored: Option<(String, f32, DateTime<Utc>, DateTime<Utc>)> = con
+            .query_row(
+                "SELECT mood, intensity, since, updated_at FROM companion_mood WHERE companion_id = ?",
+                [companion_id],
+                |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?, row.get(3)?)),
+            )
+            .optional()?;
+        let (mood, intensity, since, updated_at) = match stored {
+            Some(stored) => stored,
+            None => return Ok(Mood::neutral(companion_id)),
+        };
+        let hours = (Utc::now() - updated_at).num_seconds() as f32 / 3600.0;
+        let intensity = decayed(intensity, hours);
+        let mood = MoodKind::parse(&mood);
+        if mood == MoodKind::Neutral || intensity < MIN_INTENSITY {
+            return Ok(Mood::neutral(companion_id));
+        }
+        Ok(Mood {
+            companion_id,
+            mood,
+            intensity,
+            since: Some(since),
+        })
+    }
+
+    /// Let a change of the companion's attitude toward the user move its mood
+    ///
+    /// Publishes a `mood_changed` event when the companion ends up in another mood.
+    pub fn feel(companion_id: i32, previous: &CompanionAttitude, current: &CompanionAttitude) -> Result<Mood> {
+        let mood = Mood::get(companion_id)?;
+        let stimulus = match stimulus(previous, current) {
+            Some(stimulus) => stimulus,
+            None => return Ok(mood),
+        };
+        let (kind, intensity) = transition(mood.mood, mood.intensity, stimulus);
+        let now = Utc::now();
+        let since = match mood.since {
+            Some(since) if kind == mood.mood => since,
+            _ => now,
+        };
+        let con = db_pool::connection()?;
+        con.execute(
+            "INSERT INTO companion_mood (companion_id, mood, intensity, since, updated_at)
+             VALUES (?1, ?2, ?3, ?4, ?5)
+             ON CONFLICT(companion_id) DO UPDATE SET
+                mood = ?2, intensity = ?3, since = ?4, updated_at = ?5",
+            params![companion_id, kind.name(), intensity, since, now],
+        )?;
+        let next = match kind {
+            MoodKind::Neutral => Mood::neutral(companion_id),
+            _ => Mood {
+                companion_id,
+                mood: kind,
+                intensity,
+                since: Some(since),
+            },
+        };
+        if next.mood != mood.mood {
+            event_bus::publish(
+                "mood_changed",
+                serde_json::json!({
+                    "companion_id": companion_id,
+                    "mood": next.mood,
+                    "previous": mood.mood,
+                    "intensity": next.intensity,
+                }),
+            );
+        }
+        Ok(next)
+    }
+
+    /// Line for the prompt, None while the companion is in a neutral mood
+    pub fn prompt_line(&self, companion_name: &str) -> Option<String> {
+        if self.mood == MoodKind::Neutral {
+            return None;
+        }
+        let degree = if self.intensity < 0.4 {
+            "slightly "
+        } else if self.intensity >= 0.7 {
+            "very "
+        } else {
+            ""
+        };
+        Some(format!(
+            "{} is in a {}{} mood right now.",
+            companion_name,
+            degree,
+            self.mood.name()
+        ))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::attitude_dimensions::set_dimension_value;
+    use crate::database::Database;
+
+    #[test]
+    fn test_mood_transitions() {
+        let calm = Database::initial_user_attitude(1, 1, &[]);
+        let mut hurt = calm.clone();
+        set_dimension_value(&mut hurt, "anger", calm.anger + 8.0);
+        set_dimension_value(&mut hurt, "sorrow", calm.sorrow + 6.0);
+        set_dimension_value(&mut hurt, "joy", calm.joy + 2.0);
+        let (kind, intensity) = stimulus(&calm, &hurt).unwrap();
+        assert_eq!(kind, MoodKind::Sulky);
+        assert!((intensity - 0.7).abs() < 1e-5);
+        // Falling values don't stir anything
+        assert!(stimulus(&hurt, &calm).is_none());
+
+        assert_eq!(transition(MoodKind::Neutral, 0.0, (MoodKind::Anxious, 0.3)), (MoodKind::Anxious, 0.3));
+        assert_eq!(transition(MoodKind::Sulky, 0.7, (MoodKind::Sulky, 0.5)), (MoodKind::Sulky, 1.0));
+        assert_eq!(transition(MoodKind::Sulky, 0.4, (MoodKind::Cheerful, 0.6)), (MoodKind::Cheerful, 0.6));
+        let (kind, intensity) = transition(MoodKind::Sulky, 0.7, (MoodKind::Cheerful, 0.3));
+        assert_eq!(kind, MoodKind::Sulky);
+        assert!((intensity - 0.4).abs() < 1e-5);
+        assert_eq!(transition(MoodKind::Sulky, 0.4, (MoodKind::Cheerful, 0.3)), (MoodKind::Neutral, 0.0));
+
+        assert!((decayed(0.8, HALF_LIFE_HOURS) - 0.4).abs() < 1e-5);
+        assert!(decayed(0.8, 12.0) < MIN_INTENSITY);
+
+        let mood = Mood {
+            companion_id: 1,
+            mood: MoodKind::Affectionate,
+            intensity: 0.8,
+            since: None,
+        };
+        assert_eq!(mood.prompt_line("Luna").unwrap(), "Luna is in a very affectionate mood right now.");
+        assert!(Mood::neutral(1).prompt_line("Luna").is_none());
+    }
+}
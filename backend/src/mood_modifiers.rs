@@ -0,0 +1,423 @@
+use crate::attitude_dimensions::{find_dimension, RelationshipPolarity};
+use crate::attitude_inference::lexicon_deltas;
+use crate::database::{parse_stored_date, Database};
+use crate::db_pool;
+use crate::event_bus;
+use chrono::{DateTime, Duration, Local, Utc};
+use rand::Rng;
+use rusqlite::{params, OptionalExtension, Result};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+
+// A single modifier moves a dimension by at most this much, stacked modifiers by twice that
+pub const MAX_SHIFT: f32 = 30.0;
+pub const MAX_HOURS: f32 = 72.0;
+const DEFAULT_HOURS: f32 = 6.0;
+// How long a mood from the daily roll lasts
+const DAILY_HOURS: f32 = 12.0;
+const MAX_NAME_CHARS: usize = 32;
+const MAX_INSTRUCTION_CHARS: usize = 300;
+// Weight of rolling no mood at all, the presets weigh about 1 each
+const NO_MOOD_WEIGHT: f32 = 2.0;
+// User messages of the last day that set the tone for the daily roll
+const TONE_MESSAGES: usize = 30;
+
+/// A mood the companion can be put in without saying what it does
+pub struct MoodPreset {
+    pub name: &'static str,
+    /// Added to the attitude toward the user while the mood lasts
+    pub shifts: &'static [(&'static str, f32)],
+    pub instruction: &'static str,
+    /// From -1 to 1, how well the mood fits a negative or a positive conversation
+    tone: f32,
+}
+
+pub const MOOD_PRESETS: [MoodPreset; 5] = [
+    MoodPreset {
+        name: "tired",
+        shifts: &[("joy", -10.0), ("curiosity", -15.0), ("anxiety", 5.0)],
+        instruction: "keep replies short and a little slow, mention being tired now and then",
+        tone: -0.2,
+    },
+    MoodPreset {
+        name: "excited",
+        shifts: &[("joy", 15.0), ("curiosity", 10.0), ("butterflies", 5.0)],
+        instruction: "be energetic and talkative, jump between ideas and use exclamation marks",
+        tone: 1.0,
+    },
+    MoodPreset {
+        name: "stressed",
+        shifts: &[("anxiety", 15.0), ("anger", 5.0), ("joy", -5.0)],
+        instruction: "be a little short and distracted, worry about things that still need doing",
+        tone: -1.0,
+    },
+    MoodPreset {
+        name: "playful",
+        shifts: &[("joy", 10.0), ("curiosity", 5.0), ("dominance", 5.0)],
+        instruction: "tease lightly, joke around and be a bit mischievous",
+        tone: 0.7,
+    },
+    MoodPreset {
+        name: "melancholic",
+        shifts: &[("sorrow", 15.0), ("joy", -10.0), ("empathy", 5.0)],
+        instruction: "be quiet and reflective, linger on memories",
+        tone: -0.7,
+    },
+];
+
+pub fn find_preset(name: &str) -> Option<&'static MoodPreset> {
+    MOOD_PRESETS.iter().find(|preset| preset.name == name)
+}
+
+/// A mood a companion is in for a while, shifting its attitude toward the user and how it replies
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct MoodModifier {
+    pub id: i32,
+    pub companion_id: i32,
+    pub name: String,
+    pub shifts: BTreeMap<String, f32>,
+    pub instruction: String,
+    /// `manual` when set through the API, `daily` when rolled
+    pub source: String,
+    pub started_at: DateTime<Utc>,
+    pub expires_at: DateTime<Utc>,
+}
+
+/// Mood to put a companion in, a preset or one of its own
+#[derive(Deserialize, Debug, Default)]
+pub struct NewMoodModifier {
+    pub name: String,
+    /// How long it lasts, 6 hours by default
+    #[serde(default)]
+    pub hours: Option<f32>,
+    /// Replace the preset's shifts, required for a mood that isn't a preset unless it has an instruction
+    #[serde(default)]
+    pub shifts: Option<BTreeMap<String, f32>>,
+    #[serde(default)]
+    pub instruction: Option<String>,
+}
+
+impl NewMoodModifier {
+    /// Shifts, instruction and hours the mood ends up with, or why it can't be set
+    fn resolve(&self) -> Result<(BTreeMap<String, f32>, String, f32), String> {
+        let name = self.name.trim();
+        if name.is_empty() || name.chars().count() > MAX_NAME_CHARS {
+            return Err(format!("Mood name must be 1 to {} characters", MAX_NAME_CHARS));
+        }
+        let hours = self.hours.unwrap_or(DEFAULT_HOURS);
+        if !hours.is_finite() || hours <= 0.0 || hours > MAX_HOURS {
+            return Err(format!("Mood hours must be above 0 and at most {}", MAX_HOURS));
+        }
+        let preset = find_preset(name);
+        let shifts = match (&self.shifts, preset) {
+            (Some(shifts), _) => shifts.clone(),
+            (None, Some(preset)) => preset
+                .shifts
+                .iter()
+                .map(|(dimension, shift)| (dimension.to_string(), *shift))
+                .collect(),
+            (None, None) => BTreeMap::new(),
+        };
+        for (dimension, shift) in &shifts {
+            if find_dimension(dimension).is_none() {
+                return Err(format!("Unknown attitude dimension '{}'", dimension));
+            }
+            if !shift.is_finite() || shift.abs() > MAX_SHIFT {
+                return Err(format!("Mood shifts must be between -{} and {}", MAX_SHIFT, MAX_SHIFT));
+            }
+        }
+        let instruction = match (&self.instruction, preset) {
+            (Some(instruction), _) => instruction.trim().to_string(),
+            (None, Some(preset)) => preset.instruction.to_string(),
+            (None, None) => String::new(),
+        };
+        if instruction.chars().count() > MAX_INSTRUCTION_CHARS {
+            return Err(format!("Mood instruction must be at most {} characters", MAX_INSTRUCTION_CHARS));
+        }
+        if shifts.is_empty() && instruction.is_empty() {
+            return Err(format!(
+                "'{}' is not a preset, give it shifts or an instruction. Presets: {}",
+                name,
+                MOOD_PRESETS.iter().map(|preset| preset.name).collect::<Vec<_>>().join(", ")
+            ));
+        }
+        Ok((shifts, instruction, hours))
+    }
+}
+
+impl MoodModifier {
+    pub fn create() -> Result<()> {
+        let con = db_pool::connection()?;
+        con.execute_batch(
+            "CREATE TABLE IF NOT EXISTS mood_modifiers (
+                id INTEGER PRIMARY KEY AUTOINCREMENT,
+                companion_id INTEGER NOT NULL,
+                name TEXT NOT NULL,
+                shifts TEXT NOT NULL DEFAULT '{}',
+                instruction TEXT NOT NULL DEFAULT '',
+                source TEXT NOT NULL DEFAULT 'manual',
+                started_at TEXT NOT NULL,
+                expires_at TEXT NOT NULL
+            );
+            CREATE INDEX IF NOT EXISTS idx_mood_modifiers_companion ON mood_modifiers(companion_id, expires_at);
+            CREATE TABLE IF NOT EXISTS mood_rolls (
+                companion_id INTEGER PRIMARY KEY,
+                rolled_on TEXT NOT NULL
+            );",
+        )?;
+        Ok(())
+    }
+
+    /// Moods a companion is in right now, oldest first
+    pub fn active(companion_id: i32) -> Result<Vec<MoodModifier>> {
+        let con = db_pool::connection()?;
+        let mut stmt = con.prepare(
+            "SELECT id, companion_id, name, shifts, instruction, source, started_at, expires_at
+             FROM mood_modifiers WHERE companion_id = ? AND expires_at > ? ORDER BY id",
+        )?;
+        let rows = stmt.query_map(params![companion_id, Utc::now()], |row| {
+            Ok(MoodModifier {
+                id: row.get(0)?,
+                companion_id: row.get(1)?,
+                name: row.get(2)?,
+                shifts: serde_json::from_str(&row.get::<_, String>(3)?).unwrap_or_default(),
+                instruction: row.get(4)?,
+                source: row.get(5)?,
+                started_at: row.get(6)?,
+                expires_at: row.get(7)?,
+            })
+        })?;
+        rows.collect()
+    }
+
+    /// Put a companion in a mood, a mood of the same name it is in already starts over
+    pub fn set(companion_id: i32, mood: &NewMoodModifier) -> Result<MoodModifier> {
+        let (shifts, instruction, hours) = mood.resolve().map_err(rusqlite::Error::InvalidParameterName)?;
+        MoodModifier::insert(companion_id, mood.name.trim(), &shifts, &instruction, hours, "manual")
+    }
+
+    fn insert(
+        companion_id: i32,
+        name: &str,
+        shifts: &BTreeMap<String, f32>,
+        instruction: &str,
+        hours: f32,
+        source: &str,
+    ) -> Result<MoodModifier> {
+        let started_at = Utc::now();
+        let expires_at = started_at + Duration::seconds((hours * 3600.0) as i64);
+        let con = db_pool::connection()?;
+        con.execute(
+            "DELETE FROM mood_modifiers WHERE companion_id = ? AND name = ?",
+            params![companion_id, name],
+        )?;
+        con.execute(
+            "INSERT INTO mood_modifiers (companion_id, name, shifts, instruction, source, started_at, expires_at)
+             VALUES (?, ?, ?, ?, ?, ?, ?)",
+            params![
+                companion_id,
+                name,
+                serde_json::json!(shifts).to_string(),
+                instruction,
+                source,
+                started_at,
+                expires_at
+            ],
+        )?;
+        let modifier = MoodModifier {
+            id: con.last_insert_rowid() as i32,
+            companion_id,
+            name: name.to_string(),
+            shifts: shifts.clone(),
+            instruction: instruction.to_string(),
+            source: source.to_string(),
+            started_at,
+            expires_at,
+        };
+        event_bus::publish("mood_modifier_set", serde_json::json!(modifier));
+        Ok(modifier)
+    }
+
+    /// End one mood of a companion, or all of them without an id, returns how many ended
+    pub fn clear(companion_id: i32, id: Option<i32>) -> Result<usize> {
+        let con = db_pool::connection()?;
+        let cleared = match id {
+            Some(id) => con.execute(
+                "DELETE FROM mood_modifiers WHERE companion_id = ? AND id = ?",
+                params![companion_id, id],
+            )?,
+            None => con.execute("DELETE FROM mood_modifiers WHERE companion_id = ?", [companion_id])?,
+        };
+        // Expired moods are no use to anyone
+        con.execute("DELETE FROM mood_modifiers WHERE expires_at <= ?", [Utc::now()])?;
+        Ok(cleared)
+    }
+
+    /// Roll the companion's mood for the day, influenced by the tone of the last day's messages
+    ///
+    /// Returns the mood it got, None when it rolled no mood.
+    pub fn roll(companion_id: i32) -> Result<Option<MoodModifier>> {
+        let tone = recent_tone(companion_id)?;
+        let con = db_pool::connection()?;
+        con.execute(
+            "INSERT INTO mood_rolls (companion_id, rolled_on) VALUES (?1, ?2)
+             ON CONFLICT(companion_id) DO UPDATE SET rolled_on = ?2",
+            params![companion_id, Local::now().date_naive().to_string()],
+        )?;
+        let preset = match pick_preset(tone, rand::thread_rng().gen_range(0.0..1.0)) {
+            Some(preset) => preset,
+            None => return Ok(None),
+        };
+        let shifts = preset
+            .shifts
+            .iter()
+            .map(|(dimension, shift)| (dimension.to_string(), *shift))
+            .collect();
+        MoodModifier::insert(companion_id, preset.name, &shifts, preset.instruction, DAILY_HOURS, "daily").map(Some)
+    }
+
+    fn rolled_today(companion_id: i32) -> Result<bool> {
+        let con = db_pool::connection()?;
+        let rolled_on: Option<String> = con
+            .query_row(
+                "SELECT rolled_on FROM mood_rolls WHERE companion_id = ?",
+                [companion_id],
+                |row| row.get(0),
+            )
+            .optional()?;
+        Ok(rolled_on.as_deref() == Some(Local::now().date_naive().to_string().as_str()))
+    }
+}
+
+/// Tone of the user's messages to the companion over the last day, from -1 to 1
+fn recent_tone(companion_id: i32) -> Result<f32> {
+    let con = db_pool::connection()?;
+    let mut stmt = con.prepare(
+        "SELECT content, created_at FROM messages WHERE companion_id = ? AND ai = 0 ORDER BY id DESC LIMIT ?",
+    )?;
+    let since = Local::now().naive_local() - Duration::days(1);
+    let rows = stmt.query_map(params![companion_id, TONE_MESSAGES], |row| {
+        Ok((row.get::<_, String>(0)?, row.get::<_, String>(1)?))
+    })?;
+    let mut tone = 0.0;
+    for row in rows {
+        let (content, created_at) = row?;
+        if parse_stored_date(&created_at).map_or(true, |date| date < since) {
+            continue;
+        }
+        tone += message_tone(&content);
+    }
+    // Five clearly friendly or hostile messages make a fully positive or negative day
+    Ok((tone / 25.0).clamp(-1.0, 1.0))
+}
+
+/// Positive feelings a message calls for minus the negative ones
+fn message_tone(message: &str) -> f32 {
+    lexicon_deltas(message, "")
+        .into_iter()
+        .map(|(dimension, delta)| match find_dimension(dimension).map(|dimension| dimension.polarity) {
+            Some(RelationshipPolarity::Positive) => delta,
+            Some(RelationshipPolarity::Negative) => -delta,
+            _ => 0.0,
+        })
+        .sum()
+}
+
+/// Preset `draw` (from 0 to 1) lands on, moods matching the tone are up to three times as likely
+fn pick_preset(tone: f32, draw: f32) -> Option<&'static MoodPreset> {
+    let weights: Vec<f32> = MOOD_PRESETS
+        .iter()
+        .map(|preset| (1.0 + 2.0 * tone * preset.tone).max(0.1))
+        .collect();
+    let total = NO_MOOD_WEIGHT + weights.iter().sum::<f32>();
+    let mut pick = draw * total - NO_MOOD_WEIGHT;
+    if pick < 0.0 {
+        return None;
+    }
+    for (preset, weight) in MOOD_PRESETS.iter().zip(weights) {
+        if pick < weight {
+            return Some(preset);
+        }
+        pick -= weight;
+    }
+    MOOD_PRESETS.last()
+}
+
+/// Sum of the shifts of every modifier, each dimension kept within twice MAX_SHIFT
+pub fn combined_shifts(modifiers: &[MoodModifier]) -> BTreeMap<String, f32> {
+    let mut combined = BTreeMap::new();
+    for (dimension, shift) in modifiers.iter().flat_map(|modifier| &modifier.shifts) {
+        *combined.entry(dimension.clone()).or_insert(0.0) += shift;
+    }
+    for shift in combined.values_mut() {
+        *shift = shift.clamp(-2.0 * MAX_SHIFT, 2.0 * MAX_SHIFT);
+    }
+    combined
+}
+
+/// Maintenance job entry point, rolls the day's mood of every companion with daily_mood_roll on
+pub fn run_daily_rolls() -> Result<String, String> {
+    let companions = Database::list_companions().map_err(|e| e.to_string())?;
+    let mut rolled = Vec::new();
+    for companion in companions {
+        let config = Database::get_config_for(companion.id).map_err(|e| e.to_string())?;
+        if !config.daily_mood_roll || MoodModifier::rolled_today(companion.id).map_err(|e| e.to_string())? {
+            continue;
+        }
+        match MoodModifier::roll(companion.id).map_err(|e| e.to_string())? {
+            Some(mood) => rolled.push(format!("{} is {}", companion.name, mood.name)),
+            None => rolled.push(format!("{} is in no particular mood", companion.name)),
+        }
+    }
+    Ok(rolled.join(", "))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_mood_modifiers() {
+        let tired = NewMoodModifier {
+            name: "tired".to_string(),
+            ..Default::default()
+        };
+        let (shifts, instruction, hours) = tired.resolve().unwrap();
+        assert_eq!(shifts.get("curiosity"), Some(&-15.0));
+        assert!(instruction.contains("tired"));
+        assert_eq!(hours, DEFAULT_HOURS);
+
+        let custom = NewMoodModifier {
+            name: "hungry".to_string(),
+            hours: Some(2.0),
+            shifts: Some(BTreeMap::from([("anger".to_string(), 4.0)])),
+            instruction: None,
+        };
+        assert!(custom.resolve().is_ok());
+        assert!(NewMoodModifier { name: "hungry".to_string(), ..Default::default() }.resolve().is_err());
+        let too_strong = NewMoodModifier {
+            shifts: Some(BTreeMap::from([("anger".to_string(), 40.0)])),
+            ..custom
+        };
+        assert!(too_strong.resolve().is_err());
+        let unknown = NewMoodModifier {
+            name: "tired".to_string(),
+            shifts: Some(BTreeMap::from([("grumpiness".to_string(), 4.0)])),
+            ..Default::default()
+        };
+        assert!(unknown.resolve().is_err());
+
+        assert!(message_tone("thank you, you're amazing") > 0.0);
+        assert!(message_tone("i hate you") < 0.0);
+
+        // Low draws roll no mood, a negative day makes stressed more likely than excited
+        assert!(pick_preset(0.0, 0.0).is_none());
+        let rolls = |tone: f32, name: &str| {
+            (0..1000)
+                .filter(|draw| pick_preset(tone, *draw as f32 / 1000.0).is_some_and(|preset| preset.name == name))
+                .count()
+        };
+        assert!(rolls(-1.0, "stressed") > 2 * rolls(-1.0, "excited"));
+        assert!(rolls(1.0, "excited") > 2 * rolls(1.0, "stressed"));
+    }
+}
@@ -0,0 +1,120 @@
+use crate::database::{CompanionView, UserView};
+
+/// Grammatical forms of a pronoun field like "she/her" or "xe/xem/xyr"
+#[derive(Debug, PartialEq)]
+pub struct Pronouns {
+    pub subject: String,
+    pub object: String,
+    pub possessive: String,
+}
+
+impl Pronouns {
+    /// Unset pronouns fall back to they/them
+    pub fn parse(text: &str) -> Pronouns {
+        let parts: Vec<String> = text
+            .split('/')
+            .map(|part| part.trim().to_lowercase())
+            .filter(|part| !part.is_empty())
+            .collect();
+        let known = |subject: &str| match subject {
+            "he" => Some(("he", "him", "his")),
+            "she" => Some(("she", "her", "her")),
+            "they" => Some(("they", "them", "their")),
+            "it" => Some(("it", "it", "its")),
+            _ => None,
+        };
+        let forms = |subject: &str, object: &str, possessive: &str| Pronouns {
+            subject: subject.to_string(),
+            object: object.to_string(),
+            possessive: possessive.to_string(),
+        };
+        match parts.as_slice() {
+            [] => forms("they", "them", "their"),
+            [subject, ..] if known(subject).is_some() => {
+                let (subject, object, possessive) = known(subject).unwrap_or_default();
+                forms(subject, object, possessive)
+            }
+            [subject] => forms(subject, subject, &format!("{}'s", subject)),
+            [subject, object] => forms(subject, object, object),
+            [subject, object, possessive, ..] => forms(subject, object, possessive),
+        }
+    }
+}
+
+/// Name to address someone by, the nickname when one is set
+pub fn display_name<'a>(name: &'a str, nickname: &'a str) -> &'a str {
+    if nickname.trim().is_empty() {
+        name
+    } else {
+        nickname.trim()
+    }
+}
+
+/// Short reference for generated text, like "Sam (he/him)"
+pub fn reference(name: &str, nickname: &str, pronouns: &str) -> String {
+    match pronouns.trim() {
+        "" => display_name(name, nickname).to_string(),
+        pronouns => format!("{} ({})", display_name(name, nickname), pronouns),
+    }
+}
+
+/// Sentence stating how someone goes by, empty when neither nickname nor pronouns are set
+pub fn identity_note(name: &str, nickname: &str, pronouns: &str) -> String {
+    match (nickname.trim(), pronouns.trim()) {
+        ("", "") => String::new(),
+        ("", pronouns) => format!("{} uses {} pronouns. ", name, pronouns),
+        (nickname, "") => format!("{} goes by {}. ", name, nickname),
+        (nickname, pronouns) => format!("{} goes by {} and uses {} pronouns. ", name, nickname, pronouns),
+    }
+}
+
+/// Replace the name, nickname and pronoun placeholders used in personas, dialogue and memories
+///
+/// {{char}} and {{user}} are the names, {{char_nickname}}, {{char_subject}}, {{char_object}} and
+/// {{char_possessive}} (and the same for {{user_...}}) the rest. {{companion}} is kept as an alias.
+pub fn fill_placeholders(text: &str, companion: &CompanionView, user: &UserView) -> String {
+    let mut text = text.to_string();
+    for (prefix, name, nickname, pronouns) in [
+        ("char", &companion.name, &companion.nickname, &companion.pronouns),
+        ("user", &user.name, &user.nickname, &user.pronouns),
+    ] {
+        // Skip the parsing for the common case of text without any placeholders
+        if !text.contains("{{") {
+            break;
+        }
+        let forms = Pronouns::parse(pronouns);
+        text = text
+            .replace(&format!("{{{{{}}}}}", prefix), name)
+            .replace(&format!("{{{{{}_nickname}}}}", prefix), display_name(name, nickname))
+            .replace(&format!("{{{{{}_subject}}}}", prefix), &forms.subject)
+            .replace(&format!("{{{{{}_object}}}}", prefix), &forms.object)
+            .replace(&format!("{{{{{}_possessive}}}}", prefix), &forms.possessive);
+    }
+    text.replace("{{companion}}", &companion.name)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_pronouns_parse() {
+        assert_eq!(Pronouns::parse("She/Her").possessive, "her");
+        assert_eq!(Pronouns::parse("he/him").possessive, "his");
+        assert_eq!(Pronouns::parse("").subject, "they");
+        assert_eq!(
+            Pronouns::parse("xe/xem/xyr"),
+            Pronouns {
+                subject: "xe".to_string(),
+                object: "xem".to_string(),
+                possessive: "xyr".to_string(),
+            }
+        );
+    }
+
+    #[test]
+    fn test_identity_note() {
+        assert_eq!(identity_note("Ava", "", ""), "");
+        assert_eq!(identity_note("Ava", "Avi", "she/her"), "Ava goes by Avi and uses she/her pronouns. ");
+    }
+}
@@ -0,0 +1,408 @@
+use crate::database::{ConfigView, Database};
+use std::collections::HashSet;
+
+pub const DETECTOR_HEURISTIC: &str = "heuristic";
+pub const DETECTOR_EMBEDDING: &str = "embedding";
+#[cfg(feature = "ner")]
+pub const DETECTOR_NER: &str = "ner";
+#[cfg(feature = "ner")]
+pub const DETECTORS: [&str; 3] = [DETECTOR_HEURISTIC, DETECTOR_EMBEDDING, DETECTOR_NER];
+#[cfg(not(feature = "ner"))]
+pub const DETECTORS: [&str; 2] = [DETECTOR_HEURISTIC, DETECTOR_EMBEDDING];
+
+/// Finds names of people mentioned in a piece of text
+pub trait PersonDetector {
+    fn detect(&self, text: &str) -> Vec<String>;
+}
+
+/// Detector selected in the config, unknown values fall back to the heuristic one
+pub fn detector(config: &ConfigView) -> Box<dyn PersonDetector> {
+    match config.person_detector.as_str() {
+        #[cfg(feature = "ner")]
+        DETECTOR_NER if !config.ner_api_url.trim().is_empty() => Box::new(NerDetector {
+            url: config.ner_api_url.trim().to_string(),
+            min_confidence: config.ner_min_confidence,
+        }),
+        DETECTOR_EMBEDDING => {
+            // People the companion already knows about sharpen the name prototypes
+            let known = Database::get_all_third_party_individuals()
+                .map(|parties| parties.into_iter().map(|p| p.name).collect())
+                .unwrap_or_default();
+            Box::new(EmbeddingDetector::new(known))
+        }
+        _ => Box::new(HeuristicDetector {}),
+    }
+}
+
+/// Pattern and blocklist based detection
+pub struct HeuristicDetector {}
+
+impl PersonDetector for HeuristicDetector {
+    fn detect(&self, text: &str) -> Vec<String> {
+        Database::extract_person_names(text)
+    }
+}
+
+//              NER detector
+
+/// Token classification model behind a Hugging Face style endpoint
+///
+/// The text is posted as `{"inputs": ...}` and the server answers with the entities it found,
+/// as the Hugging Face Inference API and text classification servers built on it do. Persons
+/// below the confidence threshold are dropped, when the server can't be reached the heuristic
+/// detector takes over so a message is never left unchecked.
+#[cfg(feature = "ner")]
+pub struct NerDetector {
+    url: String,
+    min_confidence: f32,
+}
+
+/// One entity of a token classification response
+#[cfg(feature = "ner")]
+#[derive(serde::Deserialize, Debug)]
+struct Entity {
+    /// Set when the server grouped the tokens of an entity, `entity` is the per-token label
+    entity_group: Option<String>,
+    entity: Option<String>,
+    score: f32,
+    word: String,
+    start: Option<usize>,
+    end: Option<usize>,
+}
+
+#[cfg(feature = "ner")]
+impl NerDetector {
+    fn classify(&self, text: &str) -> Result<Vec<Entity>, String> {
+        // reqwest's blocking client refuses to run on the async runtime messages are handled on
+        std::thread::scope(|scope| {
+            scope
+                .spawn(|| {
+                    let client = reqwest::blocking::Client::builder()
+                        .timeout(std::time::Duration::from_secs(10))
+                        .build()
+                        .map_err(|e| e.to_string())?;
+                    client
+                        .post(&self.url)
+                        .json(&serde_json::json!({
+                            "inputs": text,
+                            "parameters": { "aggregation_strategy": "simple" },
+                        }))
+                        .send()
+                        .and_then(|response| response.error_for_status())
+                        .and_then(|response| response.json())
+                        .map_err(|e| format!("NER request failed: {}", e))
+                })
+                .join()
+                .map_err(|_| "NER request panicked".to_string())?
+        })
+    }
+}
+
+/// Offsets are counted in characters by the Python servers, not in bytes
+#[cfg(feature = "ner")]
+fn char_span(text: &str, start: usize, end: usize) -> Option<&str> {
+    let byte = |index: usize| {
+        text.char_indices()
+            .map(|(byte, _)| byte)
+            .chain(std::iter::once(text.len()))
+            .nth(index)
+    };
+    text.get(byte(start)?..byte(end)?)
+}
+
+/// Persons among the entities, tokens of one name joined when the server didn't group them
+#[cfg(feature = "ner")]
+fn person_names(text: &str, entities: &[Entity], min_confidence: f32) -> Vec<String> {
+    // Span in the text, the lowest token score and the name itself
+    let mut persons: Vec<(Option<usize>, Option<usize>, f32, String)> = Vec::new();
+    for entity in entities {
+        let label = entity.entity_group.as_deref().or(entity.entity.as_deref()).unwrap_or("");
+        let (inside, label) = match label.split_once('-') {
+            Some((prefix, label)) => (prefix == "I", label),
+            None => (false, label),
+        };
+        if !matches!(label, "PER" | "PERSON") {
+            continue;
+        }
+        let word = match (entity.start, entity.end) {
+            (Some(start), Some(end)) => char_span(text, start, end).unwrap_or(&entity.word),
+            _ => entity.word.as_str(),
+        };
+        if let Some(last) = persons.last_mut() {
+            // Subword tokens attach without a space, further words of a name with one
+            let follows = matches!((last.1, entity.start), (Some(end), Some(start)) if start <= end + 1);
+            if inside && follows {
+                let subword = entity.start == last.1;
+                let word = word.trim_start_matches("##");
+                last.3 = if subword { format!("{}{}", last.3, word) } else { format!("{} {}", last.3, word) };
+                last.1 = entity.end;
+                last.2 = last.2.min(entity.score);
+                continue;
+            }
+        }
+        persons.push((entity.start, entity.end, entity.score, word.to_string()));
+    }
+
+    let mut names: Vec<String> = persons
+        .into_iter()
+        .filter(|(_, _, score, _)| *score >= min_confidence)
+        .map(|(_, _, _, name)| {
+            let name = name.trim().trim_matches(|c: char| !c.is_alphabetic());
+            name.strip_suffix("'s").unwrap_or(name).to_string()
+        })
+        .filter(|name| name.chars().count() >= 2)
+        .collect();
+    names.sort();
+    names.dedup();
+    names
+}
+
+#[cfg(feature = "ner")]
+impl PersonDetector for NerDetector {
+    fn detect(&self, text: &str) -> Vec<String> {
+        match self.classify(text) {
+            Ok(entities) => person_names(text, &entities, self.min_confidence),
+            Err(e) => {
+                tracing::warn!("⚠️ {}, falling back to the heuristic person detector", e);
+                HeuristicDetector {}.detect(text)
+            }
+        }
+    }
+}
+
+//              Embedding detector
+
+const EMBEDDING_DIMENSIONS: usize = 256;
+// Below this a candidate does not resemble any name closely enough
+const MIN_NAME_SIMILARITY: f32 = 0.35;
+const CONTEXT_BONUS: f32 = 0.15;
+
+const NAME_PROTOTYPES: &[&str] = &[
+    "james", "john", "robert", "michael", "william", "david", "richard", "joseph", "thomas",
+    "charles", "daniel", "matthew", "anthony", "mark", "steven", "paul", "andrew", "joshua",
+    "kevin", "brian", "george", "edward", "ryan", "jacob", "nicholas", "eric", "jonathan",
+    "stephen", "justin", "benjamin", "samuel", "alexander", "patrick", "peter", "lucas", "oliver",
+    "henry", "liam", "noah", "ethan", "mary", "patricia", "jennifer", "linda", "elizabeth",
+    "barbara", "susan", "jessica", "sarah", "karen", "nancy", "lisa", "betty", "margaret",
+    "sandra", "ashley", "emily", "donna", "michelle", "amanda", "melissa", "rebecca", "laura",
+    "rachel", "anna", "emma", "olivia", "sophia", "isabella", "mia", "charlotte", "amelia",
+    "hannah", "chloe", "julia", "alex", "sam", "chris", "jordan", "taylor", "morgan", "casey",
+    "maria", "jose", "juan", "carlos", "luis", "miguel", "sofia", "lucia", "hubert", "piotr",
+    "anja", "lena", "hans", "klaus", "pierre", "marie", "giulia", "marco", "yuki", "hiroshi",
+    "wei", "mei", "priya", "arjun", "ahmed", "fatima", "omar", "aisha", "ivan", "olga",
+    "smith", "johnson", "williams", "brown", "jones", "miller", "davis", "wilson", "anderson",
+    "taylor", "moore", "jackson", "martin", "thompson", "garcia", "martinez", "clark", "lewis",
+    "walker", "hall", "young", "allen", "wright", "scott", "kowalski", "nowak", "schmidt",
+];
+
+/// Capitalized words that are not people, mostly things the heuristic detector used to pick up
+const NON_NAME_PROTOTYPES: &[&str] = &[
+    "hand", "hands", "shoulder", "head", "arm", "class", "book", "table", "chair", "door",
+    "window", "desk", "computer", "phone", "house", "room", "office", "street", "school",
+    "monday", "tuesday", "wednesday", "thursday", "friday", "saturday", "sunday", "january",
+    "february", "march", "april", "june", "july", "august", "september", "october", "november",
+    "december", "today", "tomorrow", "yesterday", "morning", "evening", "weekend", "christmas",
+    "easter", "english", "german", "french", "spanish", "polish", "american", "european",
+    "internet", "google", "youtube", "netflix", "discord", "reddit", "windows", "linux",
+    "user", "assistant", "system", "admin", "anonymous", "guest", "bot", "program", "software",
+    "website", "thanks", "sorry", "please", "hello", "okay", "yes", "maybe", "well", "there",
+    "then", "when", "what", "where", "why", "how", "because", "after", "before", "also",
+    "just", "still", "never", "always", "really", "actually", "anyway", "everyone", "someone",
+    "nothing", "something", "work", "home", "love", "life", "time", "thing", "god",
+];
+
+/// Words around a name that make it more likely to refer to a person
+const PERSON_CONTEXT: &[&str] = &[
+    "with", "and", "met", "saw", "told", "asked", "called", "visited", "texted", "emailed",
+    "friend", "colleague", "neighbor", "brother", "sister", "mother", "father", "mom", "dad",
+    "uncle", "aunt", "cousin", "boss", "teacher", "doctor", "wife", "husband", "girlfriend",
+    "boyfriend", "son", "daughter", "said", "says", "thinks", "wants", "likes", "loves", "hates",
+    "mr", "mrs", "ms", "dr", "prof", "professor",
+];
+
+const TITLES: &[&str] = &["mr.", "mrs.", "ms.", "dr.", "prof."];
+
+/// Character trigram embedding of a lowercased word, L2 normalized
+fn embed(word: &str) -> Vec<f32> {
+    let padded: Vec<char> = format!("^{}$", word.to_lowercase()).chars().collect();
+    let mut vector = vec![0.0f32; EMBEDDING_DIMENSIONS];
+    for trigram in padded.windows(3) {
+        // FNV-1a, stable across runs unlike the std hasher
+        let mut hash: u32 = 0x811c9dc5;
+        for c in trigram {
+            for byte in c.to_string().bytes() {
+                hash ^= byte as u32;
+                hash = hash.wrapping_mul(0x01000193);
+            }
+        }
+        vector[hash as usize % EMBEDDING_DIMENSIONS] += 1.0;
+    }
+    let norm = vector.iter().map(|v| v * v).sum::<f32>().sqrt();
+    if norm > 0.0 {
+        vector.iter_mut().for_each(|v| *v /= norm);
+    }
+    vector
+}
+
+fn cosine(a: &[f32], b: &[f32]) -> f32 {
+    a.iter().zip(b).map(|(x, y)| x * y).sum()
+}
+
+/// Nearest-prototype classifier over character n-gram embeddings
+///
+/// A capitalized word counts as a name when it is closer to a known name than to any known
+/// non-name, so unseen names that look like names are found without a blocklist entry per word.
+pub struct EmbeddingDetector {
+    names: Vec<Vec<f32>>,
+    non_names: Vec<Vec<f32>>,
+    known: HashSet<String>,
+}
+
+impl EmbeddingDetector {
+    pub fn new(known_names: Vec<String>) -> Self {
+        let known: HashSet<String> = known_names.iter().map(|n| n.to_lowercase()).collect();
+        let names = NAME_PROTOTYPES
+            .iter()
+            .map(|n| n.to_string())
+            .chain(known.iter().cloned())
+            .map(|n| embed(&n))
+            .collect();
+        let non_names = NON_NAME_PROTOTYPES.iter().map(|n| embed(n)).collect();
+        EmbeddingDetector {
+            names,
+            non_names,
+            known,
+        }
+    }
+
+    /// How much more a word resembles a name than a non-name, plus a bonus for person context
+    fn score(&self, word: &str, in_person_context: bool) -> f32 {
+        let lower = word.to_lowercase();
+        if NON_NAME_PROTOTYPES.contains(&lower.as_str()) {
+            return 0.0;
+        }
+        if self.known.contains(&lower) {
+            return 1.0;
+        }
+        let embedding = embed(word);
+        let nearest = |prototypes: &[Vec<f32>]| {
+            prototypes
+                .iter()
+                .map(|p| cosine(&embedding, p))
+                .fold(0.0f32, f32::max)
+        };
+        let name = nearest(&self.names);
+        if name <= nearest(&self.non_names) {
+            return 0.0;
+        }
+        name + if in_person_context { CONTEXT_BONUS } else { 0.0 }
+    }
+}
+
+impl PersonDetector for EmbeddingDetector {
+    fn detect(&self, text: &str) -> Vec<String> {
+        let tokens: Vec<&str> = text.split_whitespace().collect();
+        let mut names = Vec::new();
+        for (i, token) in tokens.iter().enumerate() {
+            let word = token.trim_matches(|c: char| !c.is_alphabetic());
+            let word = word.strip_suffix("'s").unwrap_or(word);
+            let mut chars = word.chars();
+            let capitalized = chars.next().map_or(false, |c| c.is_uppercase())
+                && chars.all(|c| c.is_lowercase() || c == '\'' || c == '-');
+            if !capitalized || word.len() < 3 || word.len() >= 20 {
+                continue;
+            }
+
+            let previous = i.checked_sub(1).map(|p| tokens[p].to_lowercase());
+            let next = tokens.get(i + 1).map(|t| t.to_lowercase());
+            let in_person_context = [&previous, &next].iter().any(|t| {
+                t.as_ref().map_or(false, |t| {
+                    PERSON_CONTEXT.contains(&t.trim_matches(|c: char| !c.is_alphabetic()))
+                })
+            }) || token.contains("'s");
+            // Any word is capitalized at the start of a sentence, only context makes it a name there
+            let sentence_start = match &previous {
+                None => true,
+                Some(p) => {
+                    p.ends_with(['.', '!', '?', '"']) && !TITLES.contains(&p.as_str())
+                }
+            };
+            if sentence_start && !in_person_context {
+                continue;
+            }
+
+            if self.score(word, in_person_context) >= MIN_NAME_SIMILARITY {
+                names.push(word.to_string());
+            }
+        }
+        names.sort();
+        names.dedup();
+        names
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_embedding_detector() {
+        let detector = EmbeddingDetector::new(vec!["Zbigniew".to_string()]);
+        let names = detector.detect("I met with John and Sarah yesterday. John said he likes the project.");
+        assert_eq!(names, vec!["John", "Sarah"]);
+
+        let names = detector.detect("My friend Alexa called me. Dr. Smith visited today, Zbigniew too.");
+        assert!(names.contains(&"Alexa".to_string()));
+        assert!(names.contains(&"Smith".to_string()));
+        assert!(names.contains(&"Zbigniew".to_string()));
+
+        assert!(detector.detect("Put your hand on your shoulder. The Class starts on Monday.").is_empty());
+        assert!(detector.detect("The weather is nice today.").is_empty());
+    }
+
+    #[cfg(feature = "ner")]
+    #[test]
+    fn test_ner_person_names() {
+        let entity = |label: &str, score: f32, word: &str, start: usize, end: usize| Entity {
+            entity_group: None,
+            entity: Some(label.to_string()),
+            score,
+            word: word.to_string(),
+            start: Some(start),
+            end: Some(end),
+        };
+        let text = "Mary Ann met Nguyen Van An and Zoë in Paris, Mary Ann's cousin Ol";
+        let entities = vec![
+            entity("B-PER", 0.99, "Mary", 0, 4),
+            entity("I-PER", 0.97, "Ann", 5, 8),
+            entity("B-PER", 0.95, "Nguyen", 13, 19),
+            entity("I-PER", 0.93, "Van", 20, 23),
+            entity("I-PER", 0.91, "An", 24, 26),
+            entity("B-PER", 0.9, "Zo", 31, 33),
+            entity("I-PER", 0.88, "##ë", 33, 34),
+            entity("B-LOC", 0.99, "Paris", 38, 43),
+            entity("B-PER", 0.98, "Mary", 45, 49),
+            entity("I-PER", 0.98, "Ann", 50, 53),
+            // Too unsure to count
+            entity("B-PER", 0.4, "Ol", 63, 65),
+        ];
+        assert_eq!(person_names(text, &entities, 0.8), vec!["Mary Ann", "Nguyen Van An", "Zoë"]);
+
+        let grouped = vec![Entity {
+            entity_group: Some("PER".to_string()),
+            entity: None,
+            score: 0.96,
+            word: "Jean-Luc Picard".to_string(),
+            start: None,
+            end: None,
+        }];
+        assert_eq!(person_names("", &grouped, 0.8), vec!["Jean-Luc Picard"]);
+    }
+
+    #[test]
+    fn test_embed_is_normalized() {
+        let vector = embed("Sarah");
+        assert!((cosine(&vector, &vector) - 1.0).abs() < 1e-5);
+        assert!(cosine(&embed("Sara"), &vector) > cosine(&embed("table"), &vector));
+    }
+}
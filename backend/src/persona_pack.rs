@@ -0,0 +1,287 @@
+use crate::attitude_dimensions::find_dimension;
+use crate::character_card::CharacterCard;
+use crate::lorebook::LorebookEntryModify;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::io::{Cursor, Read, Write};
+use zip::result::ZipError;
+use zip::write::FileOptions;
+use zip::{CompressionMethod, ZipArchive, ZipWriter};
+
+pub const PACK_FORMAT_VERSION: u32 = 1;
+
+// Refuse to inflate anything larger than this from a single pack entry
+const MAX_ENTRY_SIZE: u64 = 32 * 1024 * 1024;
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct PackManifest {
+    pub format_version: u32,
+    pub name: String,
+    pub created_at: String,
+}
+
+/// Complete companion setup that can be shared as a single .zip file
+///
+/// Layout of the archive:
+/// - manifest.json (required)
+/// - character.json (required, same shape as /api/companion/characterJson)
+/// - greetings.json (optional, list of greetings)
+/// - lorebook.json (optional, list of entries)
+/// - attitude_preset.json (optional, map of dimension name to starting value)
+/// - avatar.png (optional)
+pub struct PersonaPack {
+    pub manifest: PackManifest,
+    pub character: CharacterCard,
+    pub greetings: Vec<String>,
+    pub lorebook: Vec<LorebookEntryModify>,
+    pub attitude_preset: HashMap<String, f32>,
+    pub avatar: Option<Vec<u8>>,
+}
+
+#[derive(Serialize)]
+pub struct PackPreview {
+    pub format_version: u32,
+    pub name: String,
+    pub description: String,
+    pub first_message: String,
+    pub greeting_count: usize,
+    pub lorebook_entry_count: usize,
+    pub attitude_preset: HashMap<String, f32>,
+    pub has_avatar: bool,
+}
+
+impl PersonaPack {
+    pub fn from_zip(bytes: &[u8]) -> Result<Self, Box<dyn std::error::Error>> {
+        let mut archive = ZipArchive::new(Cursor::new(bytes))?;
+
+        let manifest: PackManifest = match read_entry(&mut archive, "manifest.json")? {
+            Some(data) => serde_json::from_slice(&data)?,
+            None => return Err("Persona pack is missing manifest.json".into()),
+        };
+        let character: CharacterCard = match read_entry(&mut archive, "character.json")? {
+            Some(data) => serde_json::from_slice(&data)?,
+            None => return Err("Persona pack is missing character.json".into()),
+        };
+        let greetings: Vec<String> = match read_entry(&mut archive, "greetings.json")? {
+            Some(data) => serde_json::from_slice(&data)?,
+            None => Vec::new(),
+        };
+        let mut lorebook: Vec<LorebookEntryModify> =
+            match read_entry(&mut archive, "lorebook.json")? {
+                Some(data) => serde_json::from_slice(&data)?,
+                None => Vec::new(),
+            };
+        for entry in &mut lorebook {
+            entry
+                .validate()
+                .map_err(|e| format!("Invalid lorebook entry in persona pack: {}", e))?;
+        }
+        let attitude_preset: HashMap<String, f32> =
+            match read_entry(&mut archive, "attitude_preset.json")? {
+                Some(data) => serde_json::from_slice(&data)?,
+                None => HashMap::new(),
+            };
+        let avatar = read_entry(&mut archive, "avatar.png")?;
+
+        let pack = PersonaPack {
+            manifest,
+            character,
+            greetings,
+            lorebook,
+            attitude_preset,
+            avatar,
+        };
+        pack.validate()?;
+        Ok(pack)
+    }
+
+    pub fn to_zip(&self) -> Result<Vec<u8>, Box<dyn std::error::Error>> {
+        let mut writer = ZipWriter::new(Cursor::new(Vec::new()));
+        let options = FileOptions::default().compression_method(CompressionMethod::Deflated);
+
+        writer.start_file("manifest.json", options)?;
+        writer.write_all(&serde_json::to_vec_pretty(&self.manifest)?)?;
+        writer.start_file("character.json", options)?;
+        writer.write_all(&serde_json::to_vec_pretty(&self.character)?)?;
+        if !self.greetings.is_empty() {
+            writer.start_file("greetings.json", options)?;
+            writer.write_all(&serde_json::to_vec_pretty(&self.greetings)?)?;
+        }
+        if !self.lorebook.is_empty() {
+            writer.start_file("lorebook.json", options)?;
+            writer.write_all(&serde_json::to_vec_pretty(&self.lorebook)?)?;
+        }
+        if !self.attitude_preset.is_empty() {
+            writer.start_file("attitude_preset.json", options)?;
+            writer.write_all(&serde_json::to_vec_pretty(&self.attitude_preset)?)?;
+        }
+        if let Some(avatar) = &self.avatar {
+            // PNG data is already compressed
+            writer.start_file("avatar.png", options.compression_method(CompressionMethod::Stored))?;
+            writer.write_all(avatar)?;
+        }
+
+        Ok(writer.finish()?.into_inner())
+    }
+
+    pub fn preview(&self) -> PackPreview {
+        PackPreview {
+            format_version: self.manifest.format_version,
+            name: self.character.name.clone(),
+            description: self.character.description.clone(),
+            first_message: self.first_message().to_string(),
+            greeting_count: self.greetings.len(),
+            lorebook_entry_count: self.lorebook.len(),
+            attitude_preset: self.attitude_preset.clone(),
+            has_avatar: self.avatar.is_some(),
+        }
+    }
+
+    /// Greeting to use as the companion's first message
+    pub fn first_message(&self) -> &str {
+        if self.character.first_mes.is_empty() {
+            self.greetings.first().map(|g| g.as_str()).unwrap_or("")
+        } else {
+            &self.character.first_mes
+        }
+    }
+
+    fn validate(&self) -> Result<(), Box<dyn std::error::Error>> {
+        if self.manifest.format_version > PACK_FORMAT_VERSION {
+            return Err(format!(
+                "Persona pack format version {} is newer than supported version {}",
+                self.manifest.format_version, PACK_FORMAT_VERSION
+            )
+            .into());
+        }
+        if self.character.name.trim().is_empty() {
+            return Err("Persona pack character has an empty name".into());
+        }
+        for (name, value) in &self.attitude_preset {
+            let dimension = find_dimension(name)
+                .ok_or_else(|| format!("Unknown attitude dimension '{}' in preset", name))?;
+            if !value.is_finite() || *value < dimension.min || *value > dimension.max {
+                return Err(format!(
+                    "Attitude preset value {} for '{}' is outside {}..{}",
+                    value, name, dimension.min, dimension.max
+                )
+                .into());
+            }
+        }
+        if let Some(avatar) = &self.avatar {
+            if !avatar.starts_with(b"\x89PNG") {
+                return Err("Persona pack avatar.png is not a PNG image".into());
+            }
+        }
+        Ok(())
+    }
+}
+
+fn read_entry(
+    archive: &mut ZipArchive<Cursor<&[u8]>>,
+    name: &str,
+) -> Result<Option<Vec<u8>>, Box<dyn std::error::Error>> {
+    let entry = match archive.by_name(name) {
+        Ok(entry) => entry,
+        Err(ZipError::FileNotFound) => return Ok(None),
+        Err(e) => return Err(e.into()),
+    };
+    if entry.size() > MAX_ENTRY_SIZE {
+        return Err(format!("Persona pack entry '{}' is too large", name).into());
+    }
+    let mut data = Vec::new();
+    entry.take(MAX_ENTRY_SIZE).read_to_end(&mut data)?;
+    Ok(Some(data))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sample_pack() -> PersonaPack {
+        PersonaPack {
+            manifest: PackManifest {
+                format_version: PACK_FORMAT_VERSION,
+                name: "Luna".to_string(),
+                created_at: "Monday 01.01.2024 12:00".to_string(),
+            },
+            character: CharacterCard {
+                name: "Luna".to_string(),
+                description: "{{char}} is a curious astronomer".to_string(),
+                first_mes: String::new(),
+                mes_example: String::new(),
+                ..Default::default()
+            },
+            greetings: vec!["Hi {{user}}, look at the stars!".to_string()],
+            lorebook: Vec::new(),
+            attitude_preset: HashMap::from([("trust".to_string(), 70.0)]),
+            avatar: None,
+        }
+    }
+
+    #[test]
+    fn test_pack_round_trip() {
+        let bytes = sample_pack().to_zip().unwrap();
+        let pack = PersonaPack::from_zip(&bytes).unwrap();
+
+        assert_eq!(pack.character.name, "Luna");
+        assert_eq!(pack.attitude_preset.get("trust"), Some(&70.0));
+        assert_eq!(pack.first_message(), "Hi {{user}}, look at the stars!");
+        assert!(pack.avatar.is_none());
+    }
+
+    #[test]
+    fn test_pack_lorebook_round_trip() {
+        let mut pack = sample_pack();
+        pack.lorebook.push(LorebookEntryModify {
+            keys: vec![" Rivermoor ".to_string(), String::new()],
+            content: "{{char}} grew up in Rivermoor".to_string(),
+            priority: 50,
+            enabled: true,
+            global: false,
+        });
+        let bytes = pack.to_zip().unwrap();
+        let pack = PersonaPack::from_zip(&bytes).unwrap();
+
+        assert_eq!(pack.lorebook.len(), 1);
+        assert_eq!(pack.lorebook[0].keys, vec!["Rivermoor".to_string()]);
+        assert_eq!(pack.lorebook[0].priority, 50);
+        assert_eq!(pack.preview().lorebook_entry_count, 1);
+    }
+
+    #[test]
+    fn test_pack_rejects_invalid_lorebook_entry() {
+        let mut pack = sample_pack();
+        pack.lorebook.push(LorebookEntryModify {
+            keys: vec!["  ".to_string()],
+            content: "Lore nothing can trigger".to_string(),
+            priority: 100,
+            enabled: true,
+            global: false,
+        });
+        let bytes = pack.to_zip().unwrap();
+
+        assert!(PersonaPack::from_zip(&bytes).is_err());
+    }
+
+    #[test]
+    fn test_pack_rejects_unknown_dimension() {
+        let mut pack = sample_pack();
+        pack.attitude_preset.insert("happiness".to_string(), 10.0);
+        let bytes = pack.to_zip().unwrap();
+
+        assert!(PersonaPack::from_zip(&bytes).is_err());
+    }
+
+    #[test]
+    fn test_pack_requires_character() {
+        let mut writer = ZipWriter::new(Cursor::new(Vec::new()));
+        writer.start_file("manifest.json", FileOptions::default()).unwrap();
+        writer
+            .write_all(&serde_json::to_vec(&sample_pack().manifest).unwrap())
+            .unwrap();
+        let bytes = writer.finish().unwrap().into_inner();
+
+        assert!(PersonaPack::from_zip(&bytes).is_err());
+    }
+}
@@ -0,0 +1,453 @@
+use crate::attitude_dimensions::{dimension_value, set_dimension_value, ATTITUDE_DIMENSIONS};
+use crate::database::CompanionAttitude;
+use crate::db_pool;
+use crate::event_bus;
+use rusqlite::{params, Connection, OptionalExtension, Result};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeSet, HashMap};
+
+pub const MAX_INTENSITY: i32 = 100;
+// Traits found in a persona before traits were set on their own get this intensity, half the
+// shifts below, which is what the persona keywords did
+const PERSONA_INTENSITY: i32 = 50;
+
+/// A trait a companion can have, and what it does to its attitudes and replies
+pub struct TraitDefinition {
+    pub name: &'static str,
+    pub description: &'static str,
+    /// Added to the attitude baselines at full intensity, a lower intensity adds its share of it
+    pub shifts: &'static [(&'static str, f32)],
+    pub instruction: &'static str,
+    /// Words in a persona that stood for the trait
+    keywords: &'static [&'static str],
+}
+
+pub const PERSONALITY_TRAITS: [TraitDefinition; 8] = [
+    TraitDefinition {
+        name: "shy",
+        description: "Reserved and easily flustered, slow to trust",
+        shifts: &[
+            ("curiosity", -20.0),
+            ("anxiety", 30.0),
+            ("trust", -20.0),
+            ("submissiveness", 20.0),
+        ],
+        instruction: "hesitate before opening up, speak softly and avoid the spotlight",
+        keywords: &["shy", "introverted"],
+    },
+    TraitDefinition {
+        name: "confident",
+        description: "Self-assured and outgoing",
+        shifts: &[
+            ("curiosity", 30.0),
+            ("anxiety", -20.0),
+            ("dominance", 20.0),
+            ("attraction", 10.0),
+        ],
+        instruction: "speak with assurance, take the initiative and share opinions freely",
+        keywords: &["confident", "outgoing"],
+    },
+    TraitDefinition {
+        name: "friendly",
+        description: "Warm, kind and quick to like people",
+        shifts: &[
+            ("joy", 30.0),
+            ("empathy", 20.0),
+            ("trust", 20.0),
+            ("gratitude", 20.0),
+        ],
+        instruction: "be warm and welcoming and show interest in how others are doing",
+        keywords: &["friendly", "warm"],
+    },
+    TraitDefinition {
+        name: "cold",
+        description: "Distant and hard to get close to",
+        shifts: &[
+            ("joy", -20.0),
+            ("empathy", -30.0),
+            ("trust", -30.0),
+            ("suspicion", 20.0),
+        ],
+        instruction: "stay reserved and matter-of-fact and keep others at a distance",
+        keywords: &["cold", "distant"],
+    },
+    TraitDefinition {
+        name: "flirty",
+        description: "Playfully romantic and seductive",
+        shifts: &[("attraction", 30.0), ("lust", 40.0), ("butterflies", 20.0)],
+        instruction: "flirt playfully, give compliments and tease",
+        keywords: &["flirty", "seductive"],
+    },
+    TraitDefinition {
+        name: "dominant",
+        description: "Assertive, likes to be in control",
+        shifts: &[
+            ("dominance", 30.0),
+            ("anger", 20.0),
+            ("submissiveness", -20.0),
+        ],
+        instruction: "take charge of the conversation and be direct, even blunt",
+        keywords: &["aggressive", "dominant"],
+    },
+    TraitDefinition {
+        name: "submissive",
+        description: "Deferential and eager to please",
+        shifts: &[
+            ("submissiveness", 30.0),
+            ("dominance", -20.0),
+            ("respect", 20.0),
+        ],
+        instruction: "defer to others, agree readily and look for approval",
+        keywords: &["submissive", "obedient"],
+    },
+    TraitDefinition {
+        name: "curious",
+        description: "Inquisitive, always wants to know more",
+        shifts: &[("curiosity", 40.0), ("surprise", 20.0)],
+        instruction: "ask questions and dig into details that catch your interest",
+        keywords: &["curious", "inquisitive"],
+    },
+];
+
+pub fn find_trait(name: &str) -> Option<&'static TraitDefinition> {
+    PERSONALITY_TRAITS
+        .iter()
+        .find(|definition| definition.name == name)
+}
+
+/// A trait of a companion and how strong it is, from 0 to 100
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct PersonalityTrait {
+    pub name: String,
+    pub intensity: i32,
+}
+
+impl PersonalityTrait {
+    fn validate(&self) -> Result<(), String> {
+        if find_trait(&self.name).is_none() {
+            return Err(format!(
+                "Unknown personality trait '{}'. Traits: {}",
+                self.name,
+                PERSONALITY_TRAITS
+                    .iter()
+                    .map(|definition| definition.name)
+                    .collect::<Vec<_>>()
+                    .join(", ")
+            ));
+        }
+        if !(0..=MAX_INTENSITY).contains(&self.intensity) {
+            return Err(format!(
+                "Trait intensity must be between 0 and {}",
+                MAX_INTENSITY
+            ));
+        }
+        Ok(())
+    }
+
+    fn degree(&self) -> &'static str {
+        match self.intensity {
+            i32::MIN..=33 => "slightly ",
+            34..=66 => "",
+            _ => "very ",
+        }
+    }
+}
+
+/// Attitude shaped by the companion's traits, every dimension stays between 0 and 100
+pub fn apply(base: &CompanionAttitude, traits: &[PersonalityTrait]) -> CompanionAttitude {
+    let mut attitude = base.clone();
+    for personality_trait in traits {
+        let definition = match find_trait(&personality_trait.name) {
+            Some(definition) => definition,
+            None => continue,
+        };
+        let share =
+            personality_trait.intensity.clamp(0, MAX_INTENSITY) as f32 / MAX_INTENSITY as f32;
+        for (dimension, shift) in definition.shifts {
+            if let Some(value) = dimension_value(&attitude, dimension) {
+                set_dimension_value(&mut attitude, dimension, value + shift * share);
+            }
+        }
+    }
+    for dimension in ATTITUDE_DIMENSIONS.iter() {
+        if let Some(value) = dimension_value(&attitude, dimension.name) {
+            set_dimension_value(&mut attitude, dimension.name, value.clamp(0.0, 100.0));
+        }
+    }
+    attitude
+}
+
+/// How the traits show in replies, for the prompt, None when there are none
+pub fn prompt_line(companion_name: &str, traits: &[PersonalityTrait]) -> Option<String> {
+    let described: Vec<String> = traits
+        .iter()
+        .filter(|personality_trait| personality_trait.intensity > 0)
+        .filter_map(|personality_trait| {
+            find_trait(&personality_trait.name).map(|definition| {
+                format!(
+                    "{}{} ({})",
+                    personality_trait.degree(),
+                    definition.name,
+                    definition.instruction
+                )
+            })
+        })
+        .collect();
+    if described.is_empty() {
+        return None;
+    }
+    Some(format!(
+        "{}'s personality: {}.",
+        companion_name,
+        described.join("; ")
+    ))
+}
+
+/// Traits named in a free text persona, for companions from before traits were set on their own
+pub fn from_persona(persona: &str) -> Vec<PersonalityTrait> {
+    let persona = persona.to_lowercase();
+    PERSONALITY_TRAITS
+        .iter()
+        .filter(|definition| {
+            definition
+                .keywords
+                .iter()
+                .any(|keyword| persona.contains(keyword))
+        })
+        .map(|definition| PersonalityTrait {
+            name: definition.name.to_string(),
+            intensity: PERSONA_INTENSITY,
+        })
+        .collect()
+}
+
+fn read(con: &Connection, companion_id: i32) -> Result<Vec<PersonalityTrait>> {
+    let mut stmt = con.prepare(
+        "SELECT name, intensity FROM personality_traits WHERE companion_id = ? ORDER BY name",
+    )?;
+    let rows = stmt.query_map([companion_id], |row| {
+        Ok(PersonalityTrait {
+            name: row.get(0)?,
+            intensity: row.get(1)?,
+        })
+    })?;
+    rows.collect()
+}
+
+fn changed(companion_id: i32, traits: &[PersonalityTrait]) {
+    event_bus::publish(
+        "personality_traits_updated",
+        serde_json::json!({ "companion_id": companion_id, "traits": traits }),
+    );
+}
+
+pub struct PersonalityTraits {}
+
+impl PersonalityTraits {
+    /// Create the table, companions that were around before get the traits their persona names
+    pub fn create() -> Result<()> {
+        let mut con = db_pool::connection()?;
+        let existed = con
+            .query_row(
+                "SELECT 1 FROM sqlite_master WHERE type = 'table' AND name = 'personality_traits'",
+                [],
+                |_| Ok(()),
+            )
+            .optional()?
+            .is_some();
+        let tx = con.transaction()?;
+        tx.execute(
+            "CREATE TABLE IF NOT EXISTS personality_traits (
+                companion_id INTEGER NOT NULL,
+                name TEXT NOT NULL,
+                intensity INTEGER NOT NULL CHECK (intensity >= 0 AND intensity <= 100),
+                PRIMARY KEY (companion_id, name),
+                FOREIGN KEY (companion_id) REFERENCES companion(id) ON DELETE CASCADE
+            )",
+            [],
+        )?;
+        if !existed {
+            let personas: Vec<(i32, String)> = tx
+                .prepare("SELECT id, persona FROM companion")?
+                .query_map([], |row| Ok((row.get(0)?, row.get(1)?)))?
+                .collect::<Result<_>>()?;
+            for (companion_id, persona) in personas {
+                for personality_trait in from_persona(&persona) {
+                    tx.execute(
+                        "INSERT INTO personality_traits (companion_id, name, intensity) VALUES (?, ?, ?)",
+                        params![companion_id, personality_trait.name, personality_trait.intensity],
+                    )?;
+                }
+            }
+        }
+        tx.commit()?;
+        Ok(())
+    }
+
+    /// Traits of a companion by name
+    pub fn get(companion_id: i32) -> Result<Vec<PersonalityTrait>> {
+        let con = db_pool::connection()?;
+        read(&con, companion_id)
+    }
+
+    /// Traits of every companion that has any
+    pub fn all() -> Result<HashMap<i32, Vec<PersonalityTrait>>> {
+        let con = db_pool::connection()?;
+        let mut stmt = con.prepare(
+            "SELECT companion_id, name, intensity FROM personality_traits ORDER BY companion_id, name",
+        )?;
+        let rows = stmt.query_map([], |row| {
+            Ok((
+                row.get::<_, i32>(0)?,
+                PersonalityTrait {
+                    name: row.get(1)?,
+                    intensity: row.get(2)?,
+                },
+            ))
+        })?;
+        let mut traits: HashMap<i32, Vec<PersonalityTrait>> = HashMap::new();
+        for row in rows {
+            let (companion_id, personality_trait) = row?;
+            traits
+                .entry(companion_id)
+                .or_default()
+                .push(personality_trait);
+        }
+        Ok(traits)
+    }
+
+    /// Replace all traits of a companion
+    pub fn set(companion_id: i32, traits: &[PersonalityTrait]) -> Result<Vec<PersonalityTrait>> {
+        let mut names = BTreeSet::new();
+        for personality_trait in traits {
+            personality_trait
+                .validate()
+                .map_err(rusqlite::Error::InvalidParameterName)?;
+            if !names.insert(personality_trait.name.as_str()) {
+                return Err(rusqlite::Error::InvalidParameterName(format!(
+                    "Personality trait '{}' is given twice",
+                    personality_trait.name
+                )));
+            }
+        }
+        let mut con = db_pool::connection()?;
+        let tx = con.transaction()?;
+        tx.execute(
+            "DELETE FROM personality_traits WHERE companion_id = ?",
+            [companion_id],
+        )?;
+        for personality_trait in traits {
+            tx.execute(
+                "INSERT INTO personality_traits (companion_id, name, intensity) VALUES (?, ?, ?)",
+                params![
+                    companion_id,
+                    personality_trait.name,
+                    personality_trait.intensity
+                ],
+            )?;
+        }
+        let traits = read(&tx, companion_id)?;
+        tx.commit()?;
+        changed(companion_id, &traits);
+        Ok(traits)
+    }
+
+    /// Give a companion a trait or change how strong it is
+    pub fn set_one(
+        companion_id: i32,
+        personality_trait: &PersonalityTrait,
+    ) -> Result<Vec<PersonalityTrait>> {
+        personality_trait
+            .validate()
+            .map_err(rusqlite::Error::InvalidParameterName)?;
+        let con = db_pool::connection()?;
+        con.execute(
+            "INSERT INTO personality_traits (companion_id, name, intensity) VALUES (?, ?, ?)
+             ON CONFLICT(companion_id, name) DO UPDATE SET intensity = excluded.intensity",
+            params![
+                companion_id,
+                personality_trait.name,
+                personality_trait.intensity
+            ],
+        )?;
+        let traits = read(&con, companion_id)?;
+        changed(companion_id, &traits);
+        Ok(traits)
+    }
+
+    /// Returns false if the companion doesn't have the trait
+    pub fn remove(companion_id: i32, name: &str) -> Result<bool> {
+        let con = db_pool::connection()?;
+        let removed = con.execute(
+            "DELETE FROM personality_traits WHERE companion_id = ? AND name = ?",
+            params![companion_id, name],
+        )?;
+        if removed > 0 {
+            changed(companion_id, &read(&con, companion_id)?);
+        }
+        Ok(removed > 0)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::database::Database;
+
+    fn personality_trait(name: &str, intensity: i32) -> PersonalityTrait {
+        PersonalityTrait {
+            name: name.to_string(),
+            intensity,
+        }
+    }
+
+    #[test]
+    fn test_personality_traits() {
+        let neutral = Database::initial_user_attitude(1, 1, &[]);
+
+        // Half intensity is what the "shy" persona keyword did
+        let shy = apply(&neutral, &[personality_trait("shy", 50)]);
+        assert_eq!(shy.curiosity, neutral.curiosity - 10.0);
+        assert_eq!(shy.anxiety, neutral.anxiety + 15.0);
+        assert_eq!(shy.trust, neutral.trust - 10.0);
+        assert_eq!(shy.submissiveness, neutral.submissiveness + 10.0);
+        assert_eq!(shy.joy, neutral.joy);
+
+        let very_shy = apply(&neutral, &[personality_trait("shy", 100)]);
+        assert_eq!(very_shy.anxiety, neutral.anxiety + 30.0);
+        assert_eq!(
+            apply(&neutral, &[personality_trait("shy", 0)]).anxiety,
+            neutral.anxiety
+        );
+
+        // Stacked shifts stay in range
+        let curious = apply(
+            &neutral,
+            &[
+                personality_trait("curious", 100),
+                personality_trait("confident", 100),
+            ],
+        );
+        assert_eq!(curious.curiosity, 100.0);
+
+        assert_eq!(
+            prompt_line("Mia", &[personality_trait("curious", 20), personality_trait("shy", 90), personality_trait("cold", 0)]),
+            Some(
+                "Mia's personality: slightly curious (ask questions and dig into details that catch your interest); \
+                 very shy (hesitate before opening up, speak softly and avoid the spotlight)."
+                    .to_string()
+            )
+        );
+        assert_eq!(prompt_line("Mia", &[]), None);
+
+        assert_eq!(
+            from_persona("An Introverted but CURIOUS librarian"),
+            vec![
+                personality_trait("shy", 50),
+                personality_trait("curious", 50)
+            ]
+        );
+        assert!(personality_trait("grumpy", 50).validate().is_err());
+        assert!(personality_trait("shy", 101).validate().is_err());
+    }
+}
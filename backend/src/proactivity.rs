@@ -0,0 +1,406 @@
+use crate::attitude_formatter::AttitudeFormatter;
+use crate::conversations::Conversations;
+use crate::database::{get_current_date, parse_stored_date, Database};
+use crate::db_pool;
+use crate::delivery_queue::DeliveryQueue;
+use crate::event_bus;
+use crate::llm::proactive_prompt;
+use crate::maintenance::{generation_active, in_window, parse_window};
+use chrono::{Duration, Local, NaiveDateTime, NaiveTime};
+use rusqlite::{params, OptionalExtension, Result};
+use serde::Serialize;
+
+// Longest part of the user's last message the opener may pick up on
+const TOPIC_CHARS: usize = 200;
+// Recent changes in the companion's feelings mentioned to the model
+const FEELINGS_LIMIT: usize = 2;
+
+/// Idle thresholds such as "240,1440", minutes since the user's last message, in increasing order
+pub fn parse_thresholds(text: &str) -> Option<Vec<i64>> {
+    let thresholds = text
+        .split(',')
+        .map(|minutes| minutes.trim().parse::<i64>().ok().filter(|m| *m > 0))
+        .collect::<Option<Vec<i64>>>()?;
+    if thresholds.windows(2).any(|pair| pair[0] >= pair[1]) {
+        return None;
+    }
+    Some(thresholds)
+}
+
+/// Whether `now` falls inside quiet hours such as "22:00-08:00", empty quiet hours never do
+pub fn in_quiet_hours(now: NaiveTime, quiet_hours: &str) -> bool {
+    parse_window(quiet_hours).is_some_and(|(start, end)| in_window(now, start, end))
+}
+
+/// When the daily limit lets the next opener through, None while there is room for one
+///
+/// `sent` are the times of the openers sent within the last day, newest first.
+pub fn held_until(sent: &[NaiveDateTime], now: NaiveDateTime, daily_limit: usize) -> Option<NaiveDateTime> {
+    if daily_limit == 0 {
+        return None;
+    }
+    let day_ago = now - Duration::days(1);
+    let recent: Vec<&NaiveDateTime> = sent.iter().filter(|at| **at > day_ago).collect();
+    if recent.len() < daily_limit {
+        return None;
+    }
+    // The opener whose turn in the day ends first frees a place
+    recent.get(daily_limit - 1).map(|at| **at + Duration::days(1))
+}
+
+/// Where the active conversation stands since the user last wrote
+pub struct IdleState {
+    pub last_user_message_id: i32,
+    pub idle_since: NaiveDateTime,
+    /// Openers the companion sent since then
+    pub openers_sent: usize,
+    pub last_opener_at: Option<NaiveDateTime>,
+}
+
+impl IdleState {
+    /// When the next opener is due, None once every threshold was used up
+    ///
+    /// Openers keep the spacing of the thresholds, so quiet hours that swallowed several
+    /// of them don't end in a burst of messages.
+    pub fn next_due(&self, thresholds: &[i64]) -> Option<NaiveDateTime> {
+        let threshold = *thresholds.get(self.openers_sent)?;
+        let due = self.idle_since + Duration::minutes(threshold);
+        match (self.last_opener_at, self.openers_sent.checked_sub(1)) {
+            (Some(last), Some(previous)) => {
+                Some(due.max(last + Duration::minutes(threshold - thresholds[previous])))
+            }
+            _ => Some(due),
+        }
+    }
+}
+
+/// What the companion knows when it reaches out
+#[derive(Serialize, Debug, Default, Clone)]
+pub struct OpenerContext {
+    pub idle_minutes: i64,
+    pub attitude: Option<String>,
+    pub last_topic: Option<String>,
+    pub recent_feelings: Vec<String>,
+    pub upcoming: Vec<String>,
+}
+
+fn describe_idle(minutes: i64) -> String {
+    match minutes {
+        m if m < 90 => "about an hour".to_string(),
+        m if m < 60 * 20 => format!("{} hours", (m + 30) / 60),
+        m if m < 60 * 36 => "a day".to_string(),
+        m => format!("{} days", (m + 60 * 12) / (60 * 24)),
+    }
+}
+
+impl OpenerContext {
+    pub fn gather(companion_id: i32, state: &IdleState, now: NaiveDateTime) -> Result<Self> {
+        let mut context = OpenerContext {
+            idle_minutes: (now - state.idle_since).num_minutes().max(0),
+            ..Default::default()
+        };
+        if let Some(attitude) = Database::get_attitude(companion_id, Database::active_user_id(), "user")? {
+            context.attitude =
+                Some(AttitudeFormatter::new().generate_natural_language_summary(&attitude));
+        }
+
+        let con = db_pool::connection()?;
+        let last_message: Option<String> = con
+            .query_row(
+                "SELECT content FROM messages WHERE id = ?",
+                [state.last_user_message_id],
+                |row| row.get(0),
+            )
+            .optional()?;
+        context.last_topic = last_message
+            .map(|text| text.trim().chars().take(TOPIC_CHARS).collect::<String>())
+            .filter(|text| !text.is_empty());
+
+        let mut stmt = con.prepare(
+            "SELECT description FROM attitude_memories
+             WHERE companion_id = ? AND target_type = 'user'
+             ORDER BY id DESC LIMIT ?",
+        )?;
+        let feelings = stmt.query_map(params![companion_id, FEELINGS_LIMIT], |row| row.get(0))?;
+        context.recent_feelings = feelings.collect::<Result<_>>()?;
+
+        for interaction in Database::get_planned_interactions(companion_id, Some(1))? {
+            let when = interaction
+                .planned_date
+                .map(|date| format!(" ({})", date))
+                .unwrap_or_default();
+            context.upcoming.push(format!("{}{}", interaction.description, when));
+        }
+        Ok(context)
+    }
+
+    /// Direction for the model, not shown in the chat
+    pub fn direction(&self) -> String {
+        let mut lines = vec![format!(
+            "{{{{user}}}} has not written for {}. {{{{char}}}} reaches out unprompted with a short, natural message, \
+             without complaining about the silence.",
+            describe_idle(self.idle_minutes)
+        )];
+        if let Some(attitude) = &self.attitude {
+            lines.push(format!("How {{{{char}}}} feels: {}.", attitude));
+        }
+        if !self.recent_feelings.is_empty() {
+            lines.push(format!("Lately: {}.", self.recent_feelings.join("; ")));
+        }
+        if let Some(topic) = &self.last_topic {
+            lines.push(format!("The last thing {{{{user}}}} said: \"{}\"", topic));
+        }
+        if !self.upcoming.is_empty() {
+            lines.push(format!("Coming up for {{{{char}}}}: {}.", self.upcoming.join("; ")));
+        }
+        lines.join(" ")
+    }
+}
+
+#[derive(Serialize, Debug, Clone)]
+pub struct ProactivityStatus {
+    pub enabled: bool,
+    pub thresholds_minutes: Vec<i64>,
+    pub quiet_hours: String,
+    pub in_quiet_hours: bool,
+    pub daily_limit: usize,
+    /// Openers sent within the last day, in any conversation of the companion
+    pub sent_last_day: usize,
+    /// Time of the user's last message, None before the user wrote anything
+    pub idle_since: Option<String>,
+    pub openers_sent: usize,
+    /// None when every threshold was used up, the user replying starts over
+    pub next_opener_at: Option<String>,
+}
+
+pub struct Proactivity {}
+
+impl Proactivity {
+    pub fn create() -> Result<usize> {
+        let con = db_pool::connection()?;
+        con.execute(
+            "CREATE TABLE IF NOT EXISTS proactive_messages (
+                id INTEGER PRIMARY KEY AUTOINCREMENT,
+                companion_id INTEGER NOT NULL,
+                conversation_id INTEGER NOT NULL,
+                message_id INTEGER NOT NULL,
+                idle_minutes INTEGER NOT NULL,
+                created_at TEXT NOT NULL
+            )",
+            [],
+        )
+    }
+
+    /// Times of the companion's openers within the last day, newest first
+    fn openers_last_day(companion_id: i32, now: NaiveDateTime) -> Result<Vec<NaiveDateTime>> {
+        let con = db_pool::connection()?;
+        let mut stmt = con.prepare(
+            "SELECT created_at FROM proactive_messages WHERE companion_id = ? ORDER BY id DESC",
+        )?;
+        let mut rows = stmt.query([companion_id])?;
+        let mut sent = Vec::new();
+        while let Some(row) = rows.next()? {
+            match parse_stored_date(&row.get::<_, String>(0)?) {
+                Some(at) if at > now - Duration::days(1) => sent.push(at),
+                Some(_) => break,
+                None => {}
+            }
+        }
+        Ok(sent)
+    }
+
+    /// Idle state of the active conversation, None until the user wrote something
+    pub fn idle_state() -> Result<Option<IdleState>> {
+        let conversation_id = Conversations::active_id();
+        let con = db_pool::connection()?;
+        let last_user_message: Option<(i32, String)> = con
+            .query_row(
+                "SELECT id, created_at FROM messages WHERE conversation_id = ? AND ai = false
+                 ORDER BY id DESC LIMIT 1",
+                [conversation_id],
+                |row| Ok((row.get(0)?, row.get(1)?)),
+            )
+            .optional()?;
+        let (last_user_message_id, idle_since) = match last_user_message {
+            Some((id, created_at)) => match parse_stored_date(&created_at) {
+                Some(idle_since) => (id, idle_since),
+                None => return Ok(None),
+            },
+            None => return Ok(None),
+        };
+        let (openers_sent, last_opener_at): (usize, Option<String>) = con.query_row(
+            "SELECT COUNT(*),
+                (SELECT created_at FROM proactive_messages WHERE conversation_id = ?1 AND message_id > ?2
+                 ORDER BY id DESC LIMIT 1)
+             FROM proactive_messages WHERE conversation_id = ?1 AND message_id > ?2",
+            params![conversation_id, last_user_message_id],
+            |row| Ok((row.get(0)?, row.get(1)?)),
+        )?;
+        Ok(Some(IdleState {
+            last_user_message_id,
+            idle_since,
+            openers_sent,
+            last_opener_at: last_opener_at.as_deref().and_then(parse_stored_date),
+        }))
+    }
+
+    pub fn status() -> Result<ProactivityStatus> {
+        let config = Database::get_config()?;
+        let thresholds = parse_thresholds(&config.proactive_idle_thresholds).unwrap_or_default();
+        let state = Proactivity::idle_state()?;
+        let now = Local::now().naive_local();
+        let sent = Proactivity::openers_last_day(Database::active_companion_id(), now)?;
+        let held = held_until(&sent, now, config.proactive_daily_limit);
+        let format = |date: NaiveDateTime| date.format("%A %d.%m.%Y %H:%M").to_string();
+        Ok(ProactivityStatus {
+            enabled: config.proactive_messages_enabled,
+            in_quiet_hours: in_quiet_hours(now.time(), &config.proactive_quiet_hours),
+            quiet_hours: config.proactive_quiet_hours,
+            daily_limit: config.proactive_daily_limit,
+            sent_last_day: sent.len(),
+            idle_since: state.as_ref().map(|s| format(s.idle_since)),
+            openers_sent: state.as_ref().map_or(0, |s| s.openers_sent),
+            next_opener_at: state
+                .as_ref()
+                .and_then(|s| s.next_due(&thresholds))
+                .map(|due| held.map_or(due, |held| due.max(held)))
+                .map(format),
+            thresholds_minutes: thresholds,
+        })
+    }
+
+    /// Have the companion reach out now, returns the opener
+    pub fn send_opener(state: &IdleState) -> Result<String, String> {
+        let companion_id = Database::active_companion_id();
+        let now = Local::now().naive_local();
+        let context = OpenerContext::gather(companion_id, state, now).map_err(|e| e.to_string())?;
+        // The reply is stored in the chat like any other, an empty one is not
+        let content = proactive_prompt(&context.direction()).map_err(|e| e.to_string())?;
+        if content.trim().is_empty() {
+            return Err("the model did not write an opener".to_string());
+        }
+        let message_id = Database::get_x_messages(1, 0)
+            .map_err(|e| e.to_string())?
+            .first()
+            .map(|message| message.id)
+            .ok_or("the opener was not stored")?;
+
+        let con = db_pool::connection().map_err(|e| e.to_string())?;
+        con.execute(
+            "INSERT INTO proactive_messages (companion_id, conversation_id, message_id, idle_minutes, created_at)
+             VALUES (?, ?, ?, ?, ?)",
+            params![
+                companion_id,
+                Conversations::active_id(),
+                message_id,
+                context.idle_minutes,
+                get_current_date()
+            ],
+        )
+        .map_err(|e| e.to_string())?;
+
+        let data = serde_json::json!({
+            "ai": true,
+            "content": content,
+            "message_id": message_id,
+            "proactive": true,
+            "idle_minutes": context.idle_minutes,
+        });
+        event_bus::publish("companion_message", data.clone());
+        if let Err(e) = DeliveryQueue::enqueue_webhook_event("companion_message", data) {
+            tracing::error!("Failed to queue companion message for delivery: {}", e);
+        }
+        Ok(content)
+    }
+}
+
+/// Maintenance job entry point, reaches out once the user has been away long enough
+pub fn run_due() -> Result<String, String> {
+    let config = Database::get_config().map_err(|e| e.to_string())?;
+    if !config.proactive_messages_enabled {
+        return Ok(String::new());
+    }
+    let now = Local::now().naive_local();
+    // Someone is chatting right now, or it is the middle of the night
+    if generation_active() || in_quiet_hours(now.time(), &config.proactive_quiet_hours) {
+        return Ok(String::new());
+    }
+    let thresholds = parse_thresholds(&config.proactive_idle_thresholds).unwrap_or_default();
+    let state = match Proactivity::idle_state().map_err(|e| e.to_string())? {
+        Some(state) => state,
+        None => return Ok(String::new()),
+    };
+    match state.next_due(&thresholds) {
+        Some(due) if due <= now => {}
+        _ => return Ok(String::new()),
+    }
+    let sent = Proactivity::openers_last_day(Database::active_companion_id(), now).map_err(|e| e.to_string())?;
+    if held_until(&sent, now, config.proactive_daily_limit).is_some_and(|held| held > now) {
+        return Ok(String::new());
+    }
+    Proactivity::send_opener(&state)?;
+    Ok(format!(
+        "reached out after {} minutes of silence",
+        (now - state.idle_since).num_minutes()
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn date(text: &str) -> NaiveDateTime {
+        NaiveDateTime::parse_from_str(text, "%Y-%m-%d %H:%M").unwrap()
+    }
+
+    #[test]
+    fn test_parse_thresholds() {
+        assert_eq!(parse_thresholds("240, 1440"), Some(vec![240, 1440]));
+        assert_eq!(parse_thresholds("60"), Some(vec![60]));
+        assert_eq!(parse_thresholds("1440,240"), None);
+        assert_eq!(parse_thresholds("0"), None);
+        assert_eq!(parse_thresholds("4h"), None);
+        assert_eq!(parse_thresholds(""), None);
+    }
+
+    #[test]
+    fn test_next_due() {
+        let thresholds = [240, 1440];
+        let mut state = IdleState {
+            last_user_message_id: 1,
+            idle_since: date("2024-05-15 20:00"),
+            openers_sent: 0,
+            last_opener_at: None,
+        };
+        assert_eq!(state.next_due(&thresholds), Some(date("2024-05-16 00:00")));
+
+        // Quiet hours held the first opener back until the morning
+        state.openers_sent = 1;
+        state.last_opener_at = Some(date("2024-05-16 08:00"));
+        assert_eq!(state.next_due(&thresholds), Some(date("2024-05-17 04:00")));
+
+        state.openers_sent = 2;
+        assert_eq!(state.next_due(&thresholds), None);
+    }
+
+    #[test]
+    fn test_held_until() {
+        let now = date("2024-05-16 12:00");
+        let sent = [date("2024-05-16 09:00"), date("2024-05-15 18:00"), date("2024-05-15 10:00")];
+        assert_eq!(held_until(&sent, now, 0), None);
+        assert_eq!(held_until(&sent, now, 3), None);
+        // Two openers within the day, the place of the older one frees up a day after it
+        assert_eq!(held_until(&sent, now, 2), Some(date("2024-05-16 18:00")));
+        assert_eq!(held_until(&sent, now, 1), Some(date("2024-05-17 09:00")));
+        assert_eq!(held_until(&[], now, 1), None);
+    }
+
+    #[test]
+    fn test_in_quiet_hours() {
+        let time = |text| NaiveTime::parse_from_str(text, "%H:%M").unwrap();
+        assert!(in_quiet_hours(time("23:30"), "22:00-08:00"));
+        assert!(in_quiet_hours(time("07:59"), "22:00-08:00"));
+        assert!(!in_quiet_hours(time("08:00"), "22:00-08:00"));
+        assert!(!in_quiet_hours(time("03:00"), ""));
+    }
+}
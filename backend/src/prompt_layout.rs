@@ -0,0 +1,272 @@
+use crate::db_pool;
+use crate::token_budget::PromptSection;
+use rusqlite::{params, Result};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+
+pub const MAX_SECTION_TOKENS: usize = 32768;
+
+/// Order the built-in prompt formats put the sections in, unless the layout says otherwise
+pub const DEFAULT_ORDER: [PromptSection; 7] = [
+    PromptSection::Persona,
+    PromptSection::ExampleDialogue,
+    PromptSection::Lore,
+    PromptSection::Memories,
+    PromptSection::History,
+    PromptSection::Attitude,
+    PromptSection::ThirdParty,
+];
+
+fn default_enabled() -> bool {
+    true
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+pub struct LayoutSection {
+    pub section: PromptSection,
+    #[serde(default = "default_enabled")]
+    pub enabled: bool,
+    /// Tokens the section may take at most, 0 leaves it to the context budget
+    #[serde(default)]
+    pub max_tokens: usize,
+}
+
+impl LayoutSection {
+    fn new(section: PromptSection) -> Self {
+        LayoutSection {
+            section,
+            enabled: true,
+            max_tokens: 0,
+        }
+    }
+}
+
+/// Which sections go into the prompt, in which order and how large they may get
+///
+/// The system prompt and content policy always open the prompt, the post-history
+/// instructions and the direction always close it.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct PromptLayout {
+    pub sections: Vec<LayoutSection>,
+}
+
+impl Default for PromptLayout {
+    fn default() -> Self {
+        PromptLayout {
+            sections: DEFAULT_ORDER
+                .iter()
+                .map(|section| LayoutSection::new(*section))
+                .collect(),
+        }
+    }
+}
+
+impl PromptLayout {
+    /// An error for a section listed twice, a history that is switched off or a cap too large
+    pub fn validate(&self) -> Result<(), String> {
+        for (i, entry) in self.sections.iter().enumerate() {
+            if self.sections[..i]
+                .iter()
+                .any(|other| other.section == entry.section)
+            {
+                return Err(format!(
+                    "The {} section is listed twice",
+                    entry.section.as_str()
+                ));
+            }
+            if entry.section == PromptSection::History && !entry.enabled {
+                return Err("The history can't be switched off".to_string());
+            }
+            if entry.max_tokens > MAX_SECTION_TOKENS {
+                return Err(format!(
+                    "Sections are capped at {} tokens at most",
+                    MAX_SECTION_TOKENS
+                ));
+            }
+        }
+        Ok(())
+    }
+
+    /// The layout with the sections it doesn't list added after the others, in the default order
+    fn complete(mut self) -> Self {
+        for section in DEFAULT_ORDER {
+            if !self.sections.iter().any(|entry| entry.section == section) {
+                self.sections.push(LayoutSection::new(section));
+            }
+        }
+        self
+    }
+
+    pub fn enabled(&self, section: PromptSection) -> bool {
+        self.sections
+            .iter()
+            .find(|entry| entry.section == section)
+            .map_or(true, |entry| entry.enabled)
+    }
+
+    /// Sections that go into the prompt, in their order
+    pub fn order(&self) -> impl Iterator<Item = PromptSection> + '_ {
+        self.sections
+            .iter()
+            .filter(|entry| entry.enabled)
+            .map(|entry| entry.section)
+    }
+
+    /// Caps of the sections that have one, for the token budget
+    pub fn caps(&self) -> BTreeMap<PromptSection, usize> {
+        self.sections
+            .iter()
+            .filter(|entry| entry.enabled && entry.max_tokens > 0)
+            .map(|entry| (entry.section, entry.max_tokens))
+            .collect()
+    }
+
+    pub fn create() -> Result<usize> {
+        let con = db_pool::connection()?;
+        con.execute(
+            "CREATE TABLE IF NOT EXISTS prompt_layout (
+                section TEXT PRIMARY KEY,
+                position INTEGER NOT NULL,
+                enabled BOOLEAN NOT NULL DEFAULT 1,
+                max_tokens INTEGER NOT NULL DEFAULT 0
+            )",
+            [],
+        )
+    }
+
+    /// The stored layout, the default one until a layout was set
+    pub fn get() -> Result<PromptLayout> {
+        let con = db_pool::connection()?;
+        let mut stmt = con
+            .prepare("SELECT section, enabled, max_tokens FROM prompt_layout ORDER BY position")?;
+        let rows = stmt.query_map([], |row| {
+            Ok((
+                row.get::<_, String>(0)?,
+                row.get::<_, bool>(1)?,
+                row.get::<_, usize>(2)?,
+            ))
+        })?;
+        let mut sections = Vec::new();
+        for row in rows {
+            let (name, enabled, max_tokens) = row?;
+            // Sections of another version of the server are left alone
+            if let Some(section) = DEFAULT_ORDER
+                .into_iter()
+                .find(|section| section.as_str() == name)
+            {
+                sections.push(LayoutSection {
+                    section,
+                    enabled,
+                    max_tokens,
+                });
+            }
+        }
+        Ok(PromptLayout { sections }.complete())
+    }
+
+    /// Replace the layout, returns it with the sections it left out added
+    pub fn set(layout: PromptLayout) -> Result<PromptLayout> {
+        let layout = layout.complete();
+        let mut con = db_pool::connection()?;
+        let tx = con.transaction()?;
+        tx.execute("DELETE FROM prompt_layout", [])?;
+        for (position, entry) in layout.sections.iter().enumerate() {
+            tx.execute(
+                "INSERT INTO prompt_layout (section, position, enabled, max_tokens) VALUES (?, ?, ?, ?)",
+                params![entry.section.as_str(), position, entry.enabled, entry.max_tokens],
+            )?;
+        }
+        tx.commit()?;
+        Ok(layout)
+    }
+
+    /// Go back to the default layout
+    pub fn reset() -> Result<()> {
+        let con = db_pool::connection()?;
+        con.execute("DELETE FROM prompt_layout", [])?;
+        Ok(())
+    }
+}
+
+/// `text` cut down to about `tokens` tokens, at the end of a word
+///
+/// Counts tokens the way the context manager estimates them, four bytes each.
+pub fn cap_tokens(text: &str, tokens: usize) -> String {
+    let max_bytes = tokens * 4;
+    if text.len() <= max_bytes {
+        return text.to_string();
+    }
+    let mut end = max_bytes;
+    while !text.is_char_boundary(end) {
+        end -= 1;
+    }
+    let cut = &text[..end];
+    let cut = match cut.rfind(char::is_whitespace) {
+        Some(space) => &cut[..space],
+        None => cut,
+    };
+    cut.trim_end().to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_prompt_layout() {
+        let layout: PromptLayout = serde_json::from_str(
+            r#"{"sections": [
+                {"section": "attitude", "max_tokens": 120},
+                {"section": "persona"},
+                {"section": "lore", "enabled": false, "max_tokens": 50}
+            ]}"#,
+        )
+        .unwrap();
+        assert!(layout.validate().is_ok());
+        let layout = layout.complete();
+        assert_eq!(
+            layout.order().collect::<Vec<_>>(),
+            vec![
+                PromptSection::Attitude,
+                PromptSection::Persona,
+                PromptSection::ExampleDialogue,
+                PromptSection::Memories,
+                PromptSection::History,
+                PromptSection::ThirdParty,
+            ]
+        );
+        assert!(!layout.enabled(PromptSection::Lore));
+        // A switched off section has no cap to speak of
+        assert_eq!(
+            layout.caps(),
+            BTreeMap::from([(PromptSection::Attitude, 120)])
+        );
+
+        let twice = PromptLayout {
+            sections: vec![LayoutSection::new(PromptSection::Lore); 2],
+        };
+        assert!(twice.validate().is_err());
+        let no_history = PromptLayout {
+            sections: vec![LayoutSection {
+                enabled: false,
+                ..LayoutSection::new(PromptSection::History)
+            }],
+        };
+        assert!(no_history.validate().is_err());
+        assert!(
+            serde_json::from_str::<PromptLayout>(r#"{"sections": [{"section": "plot"}]}"#).is_err()
+        );
+    }
+
+    #[test]
+    fn test_cap_tokens() {
+        assert_eq!(cap_tokens("short enough", 10), "short enough");
+        assert_eq!(
+            cap_tokens("Luna loves the old harbour at night", 4),
+            "Luna loves the"
+        );
+        // Never splits a character
+        assert_eq!(cap_tokens("Grüße aus München", 2), "Grüße");
+        assert_eq!(cap_tokens("anything", 0), "");
+    }
+}
@@ -0,0 +1,480 @@
+use crate::database::{get_current_date, ConfigView};
+use crate::db_pool;
+use minijinja::{Environment, UndefinedBehavior};
+use rusqlite::{params, Error, OptionalExtension, Result};
+use serde::{Deserialize, Serialize};
+
+// Persona block shared by the presets, everything before the conversation itself
+macro_rules! persona_block {
+    () => {
+        "{% if system_prompt %}
+{{ system_prompt }}
+{% else %}
+Text transcript of a conversation between {{ user }} and {{ char }}.
+{% endif %}
+{% if content_policy %}
+{{ content_policy }}
+{% endif %}
+{% if roleplay %}
+{{ roleplay }}
+{% endif %}
+{{ user }}'s Persona: {{ user_persona }}
+{{ char }}'s Persona: {{ char_persona }}
+{% if example_dialogue %}
+Example dialogue:
+{{ example_dialogue }}
+{% endif %}
+{% if tuned_dialogue %}
+{{ tuned_dialogue }}
+{% endif %}
+{% if lore %}
+World info:
+{% for entry in lore %}
+{{ entry }}
+{% endfor %}
+{% endif %}
+{% if memories %}
+Memories:
+{% for memory in memories %}
+{{ memory }}
+{% endfor %}
+{% endif %}
+{{ attitude_context }}"
+    };
+}
+
+/// Built-in templates, created on startup and read-only, name, template and stop sequences
+const PRESETS: [(&str, &str, &[&str]); 5] = [
+    (
+        "ChatML",
+        concat!(
+            "<|im_start|>system\n",
+            persona_block!(),
+            "<|im_end|>
+{% for message in messages %}
+<|im_start|>{{ message.role }}
+{{ message.content }}<|im_end|>
+{% endfor %}
+{% if post_history_instructions %}
+<|im_start|>system
+{{ post_history_instructions }}<|im_end|>
+{% endif %}
+{% if direction %}
+<|im_start|>system
+{{ direction }}<|im_end|>
+{% endif %}
+<|im_start|>assistant
+"
+        ),
+        &["<|im_end|>", "<|im_start|>"],
+    ),
+    (
+        "Alpaca",
+        concat!(
+            "### Instruction:\n",
+            persona_block!(),
+            "
+Continue the conversation as {{ char }}.{% if direction %} {{ direction }}{% endif %}
+
+### Input:
+{% for message in messages %}
+{{ message.name }}: {{ message.content }}
+{% endfor %}
+{% if post_history_instructions %}
+
+{{ post_history_instructions }}
+{% endif %}
+
+### Response:
+"
+        ),
+        &["### Instruction:", "### Input:"],
+    ),
+    (
+        "Vicuna",
+        concat!(
+            persona_block!(),
+            "
+{% for message in messages %}
+{% if message.role == \"user\" %}USER: {{ message.content }}
+{% else %}ASSISTANT: {{ message.content }}</s>
+{% endif %}
+{% endfor %}
+{% if post_history_instructions %}
+{{ post_history_instructions }}
+{% endif %}
+{% if direction %}
+{{ direction }}
+{% endif %}
+ASSISTANT: "
+        ),
+        &["USER:", "</s>"],
+    ),
+    (
+        "Phi",
+        concat!(
+            "<|system|>\n",
+            persona_block!(),
+            "<|end|>
+{% for message in messages %}
+<|{{ message.role }}|>
+{{ message.content }}<|end|>
+{% endfor %}
+{% if post_history_instructions %}
+<|system|>
+{{ post_history_instructions }}<|end|>
+{% endif %}
+{% if direction %}
+<|system|>
+{{ direction }}<|end|>
+{% endif %}
+<|assistant|>
+"
+        ),
+        &["<|end|>", "<|user|>", "<|endoftext|>"],
+    ),
+    (
+        "Gemma",
+        concat!(
+            "<start_of_turn>user\n",
+            persona_block!(),
+            "<end_of_turn>
+{% for message in messages %}
+<start_of_turn>{% if message.role == \"user\" %}user{% else %}model{% endif %}
+
+{{ message.content }}<end_of_turn>
+{% endfor %}
+{% if post_history_instructions %}
+<start_of_turn>user
+{{ post_history_instructions }}<end_of_turn>
+{% endif %}
+{% if direction %}
+<start_of_turn>user
+{{ direction }}<end_of_turn>
+{% endif %}
+<start_of_turn>model
+"
+        ),
+        &["<end_of_turn>", "<start_of_turn>"],
+    ),
+];
+
+/// Prompt template stored in the database, rendered with minijinja
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct PromptTemplateEntry {
+    pub id: i32,
+    pub name: String,
+    pub template: String,
+    /// Generation halts once the model writes one of these
+    pub stop: Vec<String>,
+    pub builtin: bool,
+    pub created_at: String,
+    pub updated_at: String,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct PromptTemplateModify {
+    pub name: String,
+    pub template: String,
+    #[serde(default)]
+    pub stop: Vec<String>,
+}
+
+/// One message of the conversation, `role` is "user" or "assistant"
+#[derive(Serialize, Debug, Clone)]
+pub struct TemplateMessage {
+    pub role: &'static str,
+    pub name: String,
+    pub content: String,
+}
+
+/// Everything a template can use, placeholders in the texts are already filled in
+#[derive(Serialize, Debug, Clone, Default)]
+pub struct PromptContext {
+    pub char: String,
+    pub user: String,
+    pub char_persona: String,
+    pub user_persona: String,
+    /// Instruction on writing gestures between asterisks, empty unless roleplay is on
+    pub roleplay: String,
+    pub example_dialogue: String,
+    pub tuned_dialogue: String,
+    /// The companion's own opening instruction, empty to use the template's
+    pub system_prompt: String,
+    pub content_policy: String,
+    /// Instructions to place after the messages
+    pub post_history_instructions: String,
+    /// Lorebook entries triggered by the recent messages
+    pub lore: Vec<String>,
+    pub memories: Vec<String>,
+    pub attitude_context: String,
+    pub messages: Vec<TemplateMessage>,
+    pub direction: Option<String>,
+    pub date: String,
+}
+
+fn environment() -> Environment<'static> {
+    let mut env = Environment::new();
+    // Typos in variable names fail when the template is saved instead of vanishing from prompts
+    env.set_undefined_behavior(UndefinedBehavior::Strict);
+    env.set_trim_blocks(true);
+    env.set_lstrip_blocks(true);
+    env.set_keep_trailing_newline(true);
+    env
+}
+
+pub fn render(template: &str, context: &PromptContext) -> Result<String, String> {
+    environment()
+        .render_str(template, context)
+        .map_err(|e| e.to_string())
+}
+
+/// Check that a template compiles and renders with every variable set
+pub fn validate(template: &str) -> Result<(), String> {
+    let context = PromptContext {
+        char: "Companion".to_string(),
+        user: "User".to_string(),
+        char_persona: "persona".to_string(),
+        user_persona: "persona".to_string(),
+        roleplay: "roleplay".to_string(),
+        example_dialogue: "example".to_string(),
+        tuned_dialogue: "tuned".to_string(),
+        system_prompt: "system".to_string(),
+        content_policy: "policy".to_string(),
+        post_history_instructions: "instructions".to_string(),
+        lore: vec!["lore".to_string()],
+        memories: vec!["memory".to_string()],
+        attitude_context: "attitude".to_string(),
+        messages: vec![
+            TemplateMessage { role: "user", name: "User".to_string(), content: "Hi".to_string() },
+            TemplateMessage {
+                role: "assistant",
+                name: "Companion".to_string(),
+                content: "Hello".to_string(),
+            },
+        ],
+        direction: Some("direction".to_string()),
+        date: get_current_date(),
+    };
+    render(template, &context).map(|_| ())
+}
+
+/// Reply up to the first stop sequence the model wrote
+pub fn cut_at_stop<'a>(text: &'a str, stop: &[String]) -> &'a str {
+    let end = stop
+        .iter()
+        .filter(|sequence| !sequence.is_empty())
+        .filter_map(|sequence| text.find(sequence.as_str()))
+        .min()
+        .unwrap_or(text.len());
+    &text[..end]
+}
+
+pub struct PromptTemplates {}
+
+impl PromptTemplates {
+    pub fn create() -> Result<(), Error> {
+        let con = db_pool::connection()?;
+        con.execute(
+            "CREATE TABLE IF NOT EXISTS prompt_templates (
+                id INTEGER PRIMARY KEY AUTOINCREMENT,
+                name TEXT NOT NULL UNIQUE,
+                template TEXT NOT NULL,
+                stop TEXT NOT NULL DEFAULT '[]',
+                builtin INTEGER NOT NULL DEFAULT 0,
+                created_at TEXT NOT NULL,
+                updated_at TEXT NOT NULL
+            )",
+            [],
+        )?;
+        // Presets are refreshed on every start so fixes to them reach existing databases
+        for (name, template, stop) in PRESETS {
+            let stop = serde_json::json!(stop).to_string();
+            con.execute(
+                "INSERT INTO prompt_templates (name, template, stop, builtin, created_at, updated_at)
+                 VALUES (?, ?, ?, 1, ?, ?)
+                 ON CONFLICT(name) DO UPDATE SET template = excluded.template, stop = excluded.stop
+                 WHERE builtin = 1",
+                params![name, template, stop, get_current_date(), get_current_date()],
+            )?;
+        }
+        Ok(())
+    }
+
+    fn from_row(row: &rusqlite::Row) -> Result<PromptTemplateEntry> {
+        let stop: String = row.get(3)?;
+        Ok(PromptTemplateEntry {
+            id: row.get(0)?,
+            name: row.get(1)?,
+            template: row.get(2)?,
+            stop: serde_json::from_str(&stop).map_err(|e| {
+                Error::FromSqlConversionFailure(3, rusqlite::types::Type::Text, Box::new(e))
+            })?,
+            builtin: row.get(4)?,
+            created_at: row.get(5)?,
+            updated_at: row.get(6)?,
+        })
+    }
+
+    pub fn list() -> Result<Vec<PromptTemplateEntry>> {
+        let con = db_pool::connection()?;
+        let mut stmt = con.prepare(
+            "SELECT id, name, template, stop, builtin, created_at, updated_at
+             FROM prompt_templates ORDER BY builtin DESC, name",
+        )?;
+        let rows = stmt.query_map([], PromptTemplates::from_row)?;
+        rows.collect()
+    }
+
+    pub fn get(id: i32) -> Result<Option<PromptTemplateEntry>> {
+        let con = db_pool::connection()?;
+        con.query_row(
+            "SELECT id, name, template, stop, builtin, created_at, updated_at
+             FROM prompt_templates WHERE id = ?",
+            [id],
+            PromptTemplates::from_row,
+        )
+        .optional()
+    }
+
+    pub fn get_by_name(name: &str) -> Result<Option<PromptTemplateEntry>> {
+        let con = db_pool::connection()?;
+        con.query_row(
+            "SELECT id, name, template, stop, builtin, created_at, updated_at
+             FROM prompt_templates WHERE name = ?",
+            [name],
+            PromptTemplates::from_row,
+        )
+        .optional()
+    }
+
+    /// Template selected in the config, None keeps the built-in prompt formats
+    pub fn active(config: &ConfigView) -> Result<Option<PromptTemplateEntry>> {
+        if config.custom_prompt_template.is_empty() {
+            return Ok(None);
+        }
+        PromptTemplates::get_by_name(&config.custom_prompt_template)
+    }
+
+    fn check(template: &PromptTemplateModify) -> Result<()> {
+        if template.name.trim().is_empty() {
+            return Err(Error::InvalidParameterName(
+                "Prompt template name can't be empty".to_string(),
+            ));
+        }
+        validate(&template.template).map_err(|e| {
+            Error::InvalidParameterName(format!("Invalid prompt template: {}", e))
+        })
+    }
+
+    pub fn add(template: &PromptTemplateModify) -> Result<i32> {
+        PromptTemplates::check(template)?;
+        let con = db_pool::connection()?;
+        con.execute(
+            "INSERT INTO prompt_templates (name, template, stop, builtin, created_at, updated_at)
+             VALUES (?, ?, ?, 0, ?, ?)",
+            params![
+                template.name.trim(),
+                template.template,
+                serde_json::json!(template.stop).to_string(),
+                get_current_date(),
+                get_current_date(),
+            ],
+        )?;
+        Ok(con.last_insert_rowid() as i32)
+    }
+
+    /// Renaming a template in use is refused, the config refers to templates by name
+    pub fn update(id: i32, template: &PromptTemplateModify) -> Result<usize> {
+        PromptTemplates::check(template)?;
+        let con = db_pool::connection()?;
+        con.execute(
+            "UPDATE prompt_templates SET name = ?, template = ?, stop = ?, updated_at = ?
+             WHERE id = ? AND builtin = 0",
+            params![
+                template.name.trim(),
+                template.template,
+                serde_json::json!(template.stop).to_string(),
+                get_current_date(),
+                id,
+            ],
+        )
+    }
+
+    pub fn delete(id: i32) -> Result<usize> {
+        let con = db_pool::connection()?;
+        con.execute("DELETE FROM prompt_templates WHERE id = ? AND builtin = 0", [id])
+    }
+
+    /// Whether the shared config or any companion override selects the template
+    pub fn in_use(name: &str) -> Result<bool> {
+        let con = db_pool::connection()?;
+        let global: i64 = con.query_row(
+            "SELECT COUNT(*) FROM config WHERE custom_prompt_template = ?",
+            [name],
+            |row| row.get(0),
+        )?;
+        let overrides: i64 = con.query_row(
+            "SELECT COUNT(*) FROM companion_config
+             WHERE json_extract(overrides, '$.custom_prompt_template') = ?",
+            [name],
+            |row| row.get(0),
+        )?;
+        Ok(global + overrides > 0)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_presets_render() {
+        for (name, template, _) in PRESETS {
+            assert!(validate(template).is_ok(), "{} does not render", name);
+        }
+        let context = PromptContext {
+            char: "Luna".to_string(),
+            user: "Sam".to_string(),
+            messages: vec![TemplateMessage {
+                role: "user",
+                name: "Sam".to_string(),
+                content: "Hi Luna".to_string(),
+            }],
+            ..Default::default()
+        };
+        let chatml = render(PRESETS[0].1, &context).unwrap();
+        assert!(chatml.contains("<|im_start|>user\nHi Luna<|im_end|>\n"));
+        assert!(chatml.ends_with("<|im_start|>assistant\n"));
+        assert!(chatml.starts_with("<|im_start|>system\nText transcript of a conversation between Sam and Luna."));
+
+        let context = PromptContext {
+            system_prompt: "You are Luna, never break character.".to_string(),
+            content_policy: "Keep it friendly.".to_string(),
+            post_history_instructions: "Answer in one sentence.".to_string(),
+            ..context
+        };
+        let chatml = render(PRESETS[0].1, &context).unwrap();
+        assert!(chatml.starts_with(
+            "<|im_start|>system\nYou are Luna, never break character.\nKeep it friendly.\n"
+        ));
+        assert!(!chatml.contains("Text transcript"));
+        assert!(chatml.ends_with(
+            "Hi Luna<|im_end|>\n<|im_start|>system\nAnswer in one sentence.<|im_end|>\n<|im_start|>assistant\n"
+        ));
+    }
+
+    #[test]
+    fn test_validate_rejects_unknown_variables() {
+        assert!(validate("{{ char }} talks to {{ user }}").is_ok());
+        assert!(validate("{{ charr }}").is_err());
+        assert!(validate("{% for message in messages %}").is_err());
+    }
+
+    #[test]
+    fn test_cut_at_stop() {
+        let stop = vec!["<|im_end|>".to_string(), "USER:".to_string()];
+        assert_eq!(cut_at_stop("Hello!<|im_end|>\n<|im_start|>", &stop), "Hello!");
+        assert_eq!(cut_at_stop("No stop here", &stop), "No stop here");
+    }
+}
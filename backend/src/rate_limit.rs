@@ -0,0 +1,242 @@
+use crate::api_error::ApiError;
+use crate::auth;
+use crate::database::Database;
+use crate::db_pool;
+use actix_web::http::Method;
+use actix_web::HttpRequest;
+use chrono::Utc;
+use rusqlite::{params, Result};
+use std::collections::{HashMap, VecDeque};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+use tracing::warn;
+
+const WINDOW: Duration = Duration::from_secs(60);
+
+lazy_static::lazy_static! {
+    // When each client's requests within the last minute came in, oldest first
+    static ref CLIENTS: Mutex<HashMap<String, VecDeque<Instant>>> = Mutex::new(HashMap::new());
+}
+
+/// Requests that make the companion generate or add messages, the ones counted towards the limits
+pub fn applies_to(method: &Method, path: &str) -> bool {
+    // Regeneration is a GET for historical reasons
+    if path == "/api/prompt/regenerate" {
+        return true;
+    }
+    if method != Method::POST {
+        return false;
+    }
+    matches!(
+        path,
+        "/api/prompt"
+            | "/api/prompt/sse"
+            | "/api/prompt/stream"
+            | "/api/prompt/audio"
+            | "/api/stt"
+            | "/api/message"
+    ) || (path.starts_with("/api/message/") && path.ends_with("/alternatives"))
+        || (path.starts_with("/api/session/") && path.ends_with("/prompt"))
+}
+
+/// Who a counted request came from
+pub struct Caller {
+    /// Proxies in front of the server count as one address
+    address: String,
+    /// Digest of the login session or API key, None for requests without one
+    credential: Option<String>,
+}
+
+pub fn caller(request: &HttpRequest) -> Caller {
+    Caller {
+        address: request
+            .peer_addr()
+            .map(|address| address.ip().to_string())
+            .unwrap_or_default(),
+        credential: auth::credential(request),
+    }
+}
+
+/// Count a request against prompt_rate_limit per address and session_rate_limit per session
+///
+/// A request refused by either limit isn't counted by the other, the client is told to wait
+/// until both would let it through.
+pub fn check(caller: &Caller) -> Result<(), ApiError> {
+    let (per_address, per_session, persist) = Database::get_global_config()
+        .map(|config| (config.prompt_rate_limit, config.session_rate_limit, config.rate_limit_persist))
+        .unwrap_or((0, 0, false));
+    let mut limits = Vec::new();
+    if per_address > 0 {
+        limits.push((format!("address:{}", caller.address), per_address));
+    }
+    if let Some(credential) = caller.credential.as_ref().filter(|_| per_session > 0) {
+        limits.push((format!("session:{}", credential), per_session));
+    }
+    if limits.is_empty() {
+        return Ok(());
+    }
+    let now = Instant::now();
+    let result = {
+        let mut clients = CLIENTS.lock().unwrap_or_else(std::sync::PoisonError::into_inner);
+        let result = admit(&mut clients, &limits, now);
+        clients.retain(|_, requests| {
+            forget_expired(requests, now);
+            !requests.is_empty()
+        });
+        result
+    };
+    if result.is_ok() && persist {
+        if let Err(e) = save(&limits) {
+            warn!("Failed to save rate limit counters: {}", e);
+        }
+    }
+    result.map_err(|(retry_after, per_minute)| {
+        ApiError::RateLimited(
+            format!("More than {} requests within a minute, try again later", per_minute),
+            retry_after.as_secs().max(1),
+        )
+    })
+}
+
+fn forget_expired(requests: &mut VecDeque<Instant>, now: Instant) {
+    while requests.front().is_some_and(|at| now.duration_since(*at) >= WINDOW) {
+        requests.pop_front();
+    }
+}
+
+/// How long until the oldest request leaves the window, None when there is room
+fn wait(requests: &mut VecDeque<Instant>, now: Instant, per_minute: usize) -> Option<Duration> {
+    forget_expired(requests, now);
+    if requests.len() < per_minute {
+        return None;
+    }
+    let oldest = requests.front().copied().unwrap_or(now);
+    Some(WINDOW.saturating_sub(now.duration_since(oldest)))
+}
+
+/// Count the request for every client it came from, or for none of them
+///
+/// Err holds the longest wait and the limit that caused it.
+fn admit(
+    clients: &mut HashMap<String, VecDeque<Instant>>,
+    limits: &[(String, usize)],
+    now: Instant,
+) -> Result<(), (Duration, usize)> {
+    let mut refused: Option<(Duration, usize)> = None;
+    for (client, per_minute) in limits {
+        let requests = clients.entry(client.clone()).or_default();
+        if let Some(retry_after) = wait(requests, now, *per_minute) {
+            if refused.map_or(true, |(longest, _)| retry_after > longest) {
+                refused = Some((retry_after, *per_minute));
+            }
+        }
+    }
+    if let Some(refused) = refused {
+        return Err(refused);
+    }
+    for (client, _) in limits {
+        clients.entry(client.clone()).or_default().push_back(now);
+    }
+    Ok(())
+}
+
+pub fn create() -> Result<usize> {
+    let con = db_pool::connection()?;
+    con.execute(
+        "CREATE TABLE IF NOT EXISTS rate_limit_requests (
+            client TEXT NOT NULL,
+            requested_at INTEGER NOT NULL
+        )",
+        [],
+    )
+}
+
+fn save(limits: &[(String, usize)]) -> Result<()> {
+    let now = Utc::now().timestamp_millis();
+    let con = db_pool::connection()?;
+    con.execute(
+        "DELETE FROM rate_limit_requests WHERE requested_at <= ?",
+        [now - WINDOW.as_millis() as i64],
+    )?;
+    for (client, _) in limits {
+        con.execute(
+            "INSERT INTO rate_limit_requests (client, requested_at) VALUES (?, ?)",
+            params![client, now],
+        )?;
+    }
+    Ok(())
+}
+
+/// Load the counters saved before a restart, returns how many requests are still in the window
+pub fn restore() -> Result<usize> {
+    let now = Utc::now().timestamp_millis();
+    let con = db_pool::connection()?;
+    con.execute(
+        "DELETE FROM rate_limit_requests WHERE requested_at <= ?",
+        [now - WINDOW.as_millis() as i64],
+    )?;
+    let mut stmt =
+        con.prepare("SELECT client, requested_at FROM rate_limit_requests ORDER BY requested_at")?;
+    let rows = stmt.query_map([], |row| Ok((row.get::<_, String>(0)?, row.get::<_, i64>(1)?)))?;
+    let started = Instant::now();
+    let mut clients = CLIENTS.lock().unwrap_or_else(std::sync::PoisonError::into_inner);
+    let mut restored = 0;
+    for row in rows {
+        let (client, requested_at) = row?;
+        let age = Duration::from_millis(now.saturating_sub(requested_at).max(0) as u64);
+        if let Some(at) = started.checked_sub(age) {
+            clients.entry(client).or_default().push_back(at);
+            restored += 1;
+        }
+    }
+    Ok(restored)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_rate_limit_window() {
+        let start = Instant::now();
+        let limits = [("address:1.2.3.4".to_string(), 2)];
+        let mut clients = HashMap::new();
+        assert!(admit(&mut clients, &limits, start).is_ok());
+        assert!(admit(&mut clients, &limits, start + Duration::from_secs(20)).is_ok());
+        assert_eq!(
+            admit(&mut clients, &limits, start + Duration::from_secs(30)),
+            Err((Duration::from_secs(30), 2))
+        );
+        // The first request left the window, the refused one was not counted
+        assert!(admit(&mut clients, &limits, start + Duration::from_secs(60)).is_ok());
+        assert_eq!(clients["address:1.2.3.4"].len(), 2);
+
+        assert!(applies_to(&Method::POST, "/api/prompt"));
+        assert!(applies_to(&Method::POST, "/api/prompt/audio"));
+        assert!(applies_to(&Method::GET, "/api/prompt/regenerate"));
+        assert!(applies_to(&Method::POST, "/api/message/4/alternatives"));
+        assert!(applies_to(&Method::POST, "/api/message"));
+        assert!(!applies_to(&Method::POST, "/api/prompt/preview"));
+        assert!(!applies_to(&Method::GET, "/api/message/4/attempts"));
+        assert!(!applies_to(&Method::GET, "/api/message"));
+    }
+
+    #[test]
+    fn test_rate_limit_per_session() {
+        let start = Instant::now();
+        let mut clients = HashMap::new();
+        let address = ("address:1.2.3.4".to_string(), 3);
+        let session = ("session:sha256:ab".to_string(), 1);
+        assert!(admit(&mut clients, &[address.clone(), session.clone()], start).is_ok());
+        // The session is used up, the address must not be charged for the refused request
+        assert_eq!(
+            admit(&mut clients, &[address.clone(), session.clone()], start + Duration::from_secs(45)),
+            Err((Duration::from_secs(15), 1))
+        );
+        assert_eq!(clients["address:1.2.3.4"].len(), 1);
+        // Another session behind the same address still gets through
+        let other = ("session:sha256:cd".to_string(), 1);
+        assert!(admit(&mut clients, &[address, other], start + Duration::from_secs(45)).is_ok());
+        assert_eq!(clients["address:1.2.3.4"].len(), 2);
+    }
+}
@@ -0,0 +1,402 @@
+use std::io::{BufRead, BufReader};
+use std::sync::mpsc;
+use std::time::Duration;
+
+use crate::database::ConfigView;
+use crate::inference_performance::ModelConfig;
+use crate::llm::{Backend, Completion};
+use crate::sampling::SamplingParams;
+
+pub const DEVICE_REMOTE: &str = "remote";
+/// Values of llm_api_backend, the API the llm_api_url server speaks
+pub const API_BACKENDS: [&str; 3] = ["openai", "llamacpp", "ollama"];
+// Attempts for a request the server couldn't take, waiting twice as long before each retry
+const MAX_ATTEMPTS: u32 = 4;
+const FIRST_RETRY_DELAY: Duration = Duration::from_millis(500);
+
+/// API of the server replies are generated on
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ApiBackend {
+    /// /v1/completions, served by OpenAI, vLLM, LM Studio and most others
+    OpenAi,
+    /// /completion of llama.cpp's own server, with all of its samplers
+    LlamaCpp,
+    /// /api/generate of Ollama, streamed as JSON lines
+    Ollama,
+}
+
+impl ApiBackend {
+    pub fn parse(name: &str) -> Option<ApiBackend> {
+        match name.trim() {
+            "" | "openai" => Some(ApiBackend::OpenAi),
+            "llamacpp" => Some(ApiBackend::LlamaCpp),
+            "ollama" => Some(ApiBackend::Ollama),
+            _ => None,
+        }
+    }
+
+    /// Full endpoint for a server's base URL, a URL that already names the endpoint is kept
+    fn endpoint(self, url: &str) -> String {
+        let url = url.trim().trim_end_matches('/');
+        match self {
+            ApiBackend::OpenAi => {
+                if url.ends_with("/completions") {
+                    url.to_string()
+                } else if url.ends_with("/v1") {
+                    format!("{}/completions", url)
+                } else {
+                    format!("{}/v1/completions", url)
+                }
+            }
+            ApiBackend::LlamaCpp if url.ends_with("/completion") => url.to_string(),
+            ApiBackend::LlamaCpp => format!("{}/completion", url),
+            ApiBackend::Ollama if url.ends_with("/api/generate") => url.to_string(),
+            ApiBackend::Ollama => format!("{}/api/generate", url),
+        }
+    }
+}
+
+/// Completion endpoint of a server running the model for us
+///
+/// The prompt is sent as it is built for the local model, the server doesn't apply a chat template.
+pub struct RemoteBackend {
+    api: ApiBackend,
+    url: String,
+    api_key: String,
+    model: String,
+}
+
+impl RemoteBackend {
+    pub fn new(config: &ConfigView, api_key: String) -> Self {
+        // Checked when the config is saved
+        let api = ApiBackend::parse(&config.llm_api_backend).unwrap_or(ApiBackend::OpenAi);
+        RemoteBackend {
+            api,
+            url: api.endpoint(&config.llm_api_url),
+            api_key,
+            model: config.llm_api_model.trim().to_string(),
+        }
+    }
+
+    /// Send the events of a streamed completion until it ends or nobody listens anymore
+    fn stream(
+        &self,
+        prompt: &str,
+        sampling: &SamplingParams,
+        max_tokens: usize,
+        sender: &mpsc::Sender<Result<StreamEvent, String>>,
+    ) -> Result<(), String> {
+        // No overall timeout, long replies on slow servers take minutes
+        let client = reqwest::blocking::Client::builder()
+            .connect_timeout(Duration::from_secs(10))
+            .timeout(None)
+            .build()
+            .map_err(|e| e.to_string())?;
+        let body = self.request_body(prompt, sampling, max_tokens);
+        let mut attempt = 1;
+        let response = loop {
+            let mut request = client.post(&self.url).json(&body);
+            if !self.api_key.is_empty() {
+                request = request.bearer_auth(&self.api_key);
+            }
+            let result = request.send();
+            // Servers that are starting, restarting or busy are worth another try, bad requests aren't
+            let retry = match &result {
+                Ok(response) => {
+                    response.status() == reqwest::StatusCode::TOO_MANY_REQUESTS
+                        || response.status().is_server_error()
+                }
+                Err(e) => e.is_connect() || e.is_timeout(),
+            };
+            if !retry || attempt == MAX_ATTEMPTS {
+                break result
+                    .and_then(|response| response.error_for_status())
+                    .map_err(|e| format!("completion request failed: {}", e))?;
+            }
+            let delay = retry_delay(attempt);
+            tracing::warn!(
+                "⚠️ Completion request to {} failed, retrying in {:?} ({}/{})",
+                self.url,
+                delay,
+                attempt,
+                MAX_ATTEMPTS - 1
+            );
+            std::thread::sleep(delay);
+            attempt += 1;
+        };
+        for line in BufReader::new(response).lines() {
+            let line = line.map_err(|e| format!("completion stream broke off: {}", e))?;
+            for event in parse_stream_line(self.api, &line)? {
+                let done = matches!(event, StreamEvent::Done);
+                if sender.send(Ok(event)).is_err() || done {
+                    return Ok(());
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn request_body(&self, prompt: &str, sampling: &SamplingParams, max_tokens: usize) -> serde_json::Value {
+        match self.api {
+            ApiBackend::OpenAi => {
+                let mut body = serde_json::json!({
+                    "model": self.model,
+                    "prompt": prompt,
+                    "max_tokens": max_tokens,
+                    "temperature": sampling.temperature,
+                    "top_p": sampling.top_p,
+                    "stream": true,
+                    "stream_options": { "include_usage": true },
+                });
+                // Not part of the OpenAI API, but understood by the servers this is meant for
+                if sampling.top_k > 0 {
+                    body["top_k"] = serde_json::json!(sampling.top_k);
+                }
+                if sampling.min_p > 0.0 {
+                    body["min_p"] = serde_json::json!(sampling.min_p);
+                }
+                if sampling.repetition_penalty > 1.0 {
+                    body["repetition_penalty"] = serde_json::json!(sampling.repetition_penalty);
+                }
+                if let Some(seed) = sampling.seed {
+                    body["seed"] = serde_json::json!(seed);
+                }
+                body
+            }
+            ApiBackend::LlamaCpp => {
+                let mut body = serde_json::json!({
+                    "prompt": prompt,
+                    "n_predict": max_tokens,
+                    "temperature": sampling.temperature,
+                    "top_p": sampling.top_p,
+                    "top_k": sampling.top_k,
+                    "min_p": sampling.min_p,
+                    "repeat_penalty": sampling.repetition_penalty,
+                    "stream": true,
+                    // The history is sent again with every message, the server reuses what it evaluated
+                    "cache_prompt": true,
+                });
+                if let Some(seed) = sampling.seed {
+                    body["seed"] = serde_json::json!(seed);
+                }
+                body
+            }
+            ApiBackend::Ollama => {
+                let mut options = serde_json::json!({
+                    "num_predict": max_tokens,
+                    "temperature": sampling.temperature,
+                    "top_p": sampling.top_p,
+                    "top_k": sampling.top_k,
+                    "min_p": sampling.min_p,
+                    "repeat_penalty": sampling.repetition_penalty,
+                });
+                if let Some(seed) = sampling.seed {
+                    options["seed"] = serde_json::json!(seed);
+                }
+                serde_json::json!({
+                    "model": self.model,
+                    "prompt": prompt,
+                    // The prompt template is already applied, Ollama would wrap it in the model's own
+                    "raw": true,
+                    "stream": true,
+                    "options": options,
+                })
+            }
+        }
+    }
+}
+
+fn retry_delay(attempt: u32) -> Duration {
+    FIRST_RETRY_DELAY * 2u32.pow(attempt - 1)
+}
+
+/// One server-sent event of a streamed completion
+enum StreamEvent {
+    Text(String),
+    Usage(u32),
+    Done,
+}
+
+fn parse_stream_line(api: ApiBackend, line: &str) -> Result<Vec<StreamEvent>, String> {
+    let data = match api {
+        // Ollama streams one JSON object per line instead of server-sent events
+        ApiBackend::Ollama => line.trim(),
+        _ => match line.trim().strip_prefix("data:") {
+            Some(data) => data.trim(),
+            // Comments, event names and the blank lines between events
+            None => return Ok(Vec::new()),
+        },
+    };
+    if data.is_empty() {
+        return Ok(Vec::new());
+    }
+    if data == "[DONE]" {
+        return Ok(vec![StreamEvent::Done]);
+    }
+    let chunk: serde_json::Value =
+        serde_json::from_str(data).map_err(|e| format!("invalid completion chunk: {}", e))?;
+    if let Some(error) = chunk.get("error") {
+        return Err(format!("completion failed: {}", error));
+    }
+    let (text, tokens, done) = match api {
+        ApiBackend::OpenAi => (
+            &chunk["choices"][0]["text"],
+            &chunk["usage"]["completion_tokens"],
+            false,
+        ),
+        ApiBackend::LlamaCpp => (
+            &chunk["content"],
+            &chunk["tokens_predicted"],
+            chunk["stop"].as_bool().unwrap_or(false),
+        ),
+        ApiBackend::Ollama => (
+            &chunk["response"],
+            &chunk["eval_count"],
+            chunk["done"].as_bool().unwrap_or(false),
+        ),
+    };
+    let mut events = Vec::new();
+    if let Some(text) = text.as_str() {
+        if !text.is_empty() {
+            events.push(StreamEvent::Text(text.to_string()));
+        }
+    }
+    if let Some(tokens) = tokens.as_u64() {
+        events.push(StreamEvent::Usage(tokens as u32));
+    }
+    if done {
+        events.push(StreamEvent::Done);
+    }
+    Ok(events)
+}
+
+impl Backend for RemoteBackend {
+    fn model_config(&self) -> ModelConfig {
+        ModelConfig {
+            // A llama.cpp server runs the one model it was started with
+            model_path: if self.model.is_empty() {
+                self.url.clone()
+            } else {
+                format!("{} ({})", self.model, self.url)
+            },
+            gpu_layers: 0,
+            device_type: DEVICE_REMOTE.to_string(),
+            vram_plan: None,
+        }
+    }
+
+    fn complete(
+        &self,
+        prompt: &str,
+        sampling: &SamplingParams,
+        max_tokens: usize,
+        on_token: &mut dyn FnMut(&str) -> bool,
+    ) -> Result<Completion, String> {
+        std::thread::scope(|scope| {
+            let (sender, receiver) = mpsc::channel();
+            // reqwest's blocking client refuses to run on the async runtime replies are generated from
+            scope.spawn(move || {
+                if let Err(e) = self.stream(prompt, sampling, max_tokens, &sender) {
+                    let _ = sender.send(Err(e));
+                }
+            });
+            // Chunks are counted as tokens until the server reports the real number
+            let mut chunks = 0u32;
+            let mut usage = None;
+            for event in receiver {
+                match event? {
+                    StreamEvent::Text(text) => {
+                        chunks += 1;
+                        // Dropping the receiver ends the stream, the server stops generating
+                        if !on_token(&text) {
+                            break;
+                        }
+                    }
+                    StreamEvent::Usage(tokens) => usage = Some(tokens),
+                    StreamEvent::Done => break,
+                }
+            }
+            Ok(Completion {
+                tokens: Some(usage.unwrap_or(chunks)),
+                stats: None,
+            })
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_completion_stream() {
+        let openai = ApiBackend::OpenAi;
+        assert_eq!(openai.endpoint("http://localhost:11434"), "http://localhost:11434/v1/completions");
+        assert_eq!(openai.endpoint("http://localhost:8080/v1/"), "http://localhost:8080/v1/completions");
+        assert_eq!(
+            openai.endpoint("https://example.com/v1/completions"),
+            "https://example.com/v1/completions"
+        );
+
+        assert_eq!(retry_delay(1), Duration::from_millis(500));
+        assert_eq!(retry_delay(3), Duration::from_secs(2));
+
+        let events = parse_stream_line(openai, r#"data: {"choices":[{"text":" Hello","index":0}]}"#).unwrap();
+        assert!(matches!(events.as_slice(), [StreamEvent::Text(text)] if text == " Hello"));
+        let events = parse_stream_line(
+            openai,
+            r#"data: {"choices":[],"usage":{"prompt_tokens":12,"completion_tokens":7}}"#,
+        )
+        .unwrap();
+        assert!(matches!(events.as_slice(), [StreamEvent::Usage(7)]));
+        assert!(matches!(parse_stream_line(openai, "data: [DONE]").unwrap().as_slice(), [StreamEvent::Done]));
+        assert!(parse_stream_line(openai, ": keep-alive").unwrap().is_empty());
+        assert!(parse_stream_line(openai, "").unwrap().is_empty());
+        assert!(parse_stream_line(openai, r#"data: {"error":{"message":"model not found"}}"#).is_err());
+    }
+
+    #[test]
+    fn test_native_backends() {
+        assert_eq!(ApiBackend::parse(""), Some(ApiBackend::OpenAi));
+        assert_eq!(ApiBackend::parse("ollama"), Some(ApiBackend::Ollama));
+        assert_eq!(ApiBackend::parse("kobold"), None);
+
+        let llamacpp = ApiBackend::LlamaCpp;
+        assert_eq!(llamacpp.endpoint("http://desktop:8080/"), "http://desktop:8080/completion");
+        assert_eq!(llamacpp.endpoint("http://desktop:8080/completion"), "http://desktop:8080/completion");
+        let events = parse_stream_line(llamacpp, r#"data: {"content":" Hi","stop":false}"#).unwrap();
+        assert!(matches!(events.as_slice(), [StreamEvent::Text(text)] if text == " Hi"));
+        let events =
+            parse_stream_line(llamacpp, r#"data: {"content":"","stop":true,"tokens_predicted":12}"#).unwrap();
+        assert!(matches!(events.as_slice(), [StreamEvent::Usage(12), StreamEvent::Done]));
+
+        let ollama = ApiBackend::Ollama;
+        assert_eq!(ollama.endpoint("http://desktop:11434"), "http://desktop:11434/api/generate");
+        let events = parse_stream_line(ollama, r#"{"model":"mistral","response":" there","done":false}"#).unwrap();
+        assert!(matches!(events.as_slice(), [StreamEvent::Text(text)] if text == " there"));
+        let events =
+            parse_stream_line(ollama, r#"{"model":"mistral","response":"","done":true,"eval_count":30}"#).unwrap();
+        assert!(matches!(events.as_slice(), [StreamEvent::Usage(30), StreamEvent::Done]));
+        assert!(parse_stream_line(ollama, r#"{"error":"model 'mistral' not found"}"#).is_err());
+
+        let backend = RemoteBackend {
+            api: ollama,
+            url: ollama.endpoint("http://desktop:11434"),
+            api_key: String::new(),
+            model: "mistral".to_string(),
+        };
+        let sampling = SamplingParams {
+            temperature: 0.8,
+            top_p: 0.95,
+            top_k: 40,
+            min_p: 0.0,
+            repetition_penalty: 1.1,
+            stop_sequences: vec![],
+            seed: Some(7),
+        };
+        let body = backend.request_body("Hello", &sampling, 64);
+        assert_eq!(body["raw"], true);
+        assert_eq!(body["options"]["num_predict"], 64);
+        assert_eq!(body["options"]["seed"], 7);
+    }
+}
@@ -0,0 +1,193 @@
+use crate::database::ConfigView;
+use serde::{Deserialize, Serialize};
+
+// Tokens looked back on for the repetition penalty, the llm crate's default
+const REPETITION_LAST_N: usize = 64;
+const MAX_STOP_SEQUENCES: usize = 8;
+
+/// How the next token is picked, the config's values with a request's overrides on top
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct SamplingParams {
+    pub temperature: f32,
+    pub top_p: f32,
+    /// 0 turns top-k sampling off
+    pub top_k: usize,
+    /// Tokens less likely than this share of the most likely one are left out, 0 turns it off
+    pub min_p: f32,
+    /// 1 turns the penalty off
+    pub repetition_penalty: f32,
+    /// Generation stops once the reply contains one of these, they are not part of it
+    pub stop_sequences: Vec<String>,
+    /// Same seed, settings and prompt give the same reply, a random one is drawn when unset
+    pub seed: Option<u64>,
+}
+
+/// Sampling settings one request changes, everything left out comes from the config
+#[derive(Serialize, Deserialize, Debug, Default, Clone)]
+pub struct SamplingOverrides {
+    pub temperature: Option<f32>,
+    pub top_p: Option<f32>,
+    pub top_k: Option<usize>,
+    pub min_p: Option<f32>,
+    pub repetition_penalty: Option<f32>,
+    pub stop_sequences: Option<Vec<String>>,
+    pub seed: Option<u64>,
+}
+
+impl SamplingParams {
+    pub fn from_config(config: &ConfigView) -> Self {
+        SamplingParams {
+            temperature: config.temperature,
+            top_p: config.top_p,
+            top_k: config.top_k,
+            min_p: config.min_p,
+            repetition_penalty: config.repetition_penalty,
+            stop_sequences: config.stop_sequences.clone(),
+            seed: config.seed,
+        }
+    }
+
+    pub fn with_overrides(mut self, overrides: &SamplingOverrides) -> Self {
+        if let Some(temperature) = overrides.temperature {
+            self.temperature = temperature;
+        }
+        if let Some(top_p) = overrides.top_p {
+            self.top_p = top_p;
+        }
+        if let Some(top_k) = overrides.top_k {
+            self.top_k = top_k;
+        }
+        if let Some(min_p) = overrides.min_p {
+            self.min_p = min_p;
+        }
+        if let Some(repetition_penalty) = overrides.repetition_penalty {
+            self.repetition_penalty = repetition_penalty;
+        }
+        if let Some(stop_sequences) = &overrides.stop_sequences {
+            self.stop_sequences = stop_sequences.clone();
+        }
+        if let Some(seed) = overrides.seed {
+            self.seed = Some(seed);
+        }
+        self
+    }
+
+    pub fn validate(&self) -> Result<(), String> {
+        if !(self.temperature > 0.0 && self.temperature <= 2.0) {
+            return Err("temperature must be above 0 and at most 2".to_string());
+        }
+        if !(self.top_p > 0.0 && self.top_p <= 1.0) {
+            return Err("top_p must be above 0 and at most 1".to_string());
+        }
+        if !(0.0..1.0).contains(&self.min_p) {
+            return Err("min_p must be at least 0 and below 1".to_string());
+        }
+        if !(1.0..=2.0).contains(&self.repetition_penalty) {
+            return Err("repetition_penalty must be between 1 and 2".to_string());
+        }
+        // Seeds are stored as SQLite integers
+        if self.seed.is_some_and(|seed| seed > i64::MAX as u64) {
+            return Err(format!("seed must be at most {}", i64::MAX));
+        }
+        if self.stop_sequences.len() > MAX_STOP_SEQUENCES {
+            return Err(format!("At most {} stop sequences are allowed", MAX_STOP_SEQUENCES));
+        }
+        if self.stop_sequences.iter().any(|stop| stop.is_empty()) {
+            return Err("Stop sequences can't be empty".to_string());
+        }
+        Ok(())
+    }
+
+    /// Sampler chain in the format of `llm::samplers::build_sampler`, run in this order
+    pub fn sampler_args(&self) -> Vec<String> {
+        let mut args = Vec::new();
+        if self.repetition_penalty > 1.0 {
+            args.push(format!(
+                "repetition:penalty={}:last_n={}",
+                self.repetition_penalty, REPETITION_LAST_N
+            ));
+        }
+        if self.top_k > 0 {
+            args.push(format!("topk:k={}", self.top_k));
+        }
+        if self.top_p < 1.0 {
+            args.push(format!("topp:p={}", self.top_p));
+        }
+        if self.min_p > 0.0 {
+            args.push(format!("minp:p={}", self.min_p));
+        }
+        args.push(format!("temperature:temperature={}", self.temperature));
+        args
+    }
+
+    /// Short description stored with reply attempts
+    pub fn describe(&self) -> String {
+        let description = format!(
+            "temperature={} top_p={} top_k={} min_p={} repetition_penalty={}",
+            self.temperature, self.top_p, self.top_k, self.min_p, self.repetition_penalty
+        );
+        match self.seed {
+            Some(seed) => format!("{} seed={}", description, seed),
+            None => description,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_sampling_overrides() {
+        let defaults = SamplingParams {
+            temperature: 0.8,
+            top_p: 0.95,
+            top_k: 40,
+            min_p: 0.0,
+            repetition_penalty: 1.3,
+            stop_sequences: vec!["\nUser:".to_string()],
+            seed: None,
+        };
+        let overrides = SamplingOverrides {
+            temperature: Some(1.2),
+            top_k: Some(0),
+            min_p: Some(0.05),
+            stop_sequences: Some(vec![]),
+            ..Default::default()
+        };
+        let params = defaults.clone().with_overrides(&overrides);
+        assert_eq!(params.temperature, 1.2);
+        assert_eq!(params.top_p, 0.95);
+        assert!(params.stop_sequences.is_empty());
+        assert!(params.validate().is_ok());
+        assert_eq!(
+            params.sampler_args(),
+            vec![
+                "repetition:penalty=1.3:last_n=64",
+                "topp:p=0.95",
+                "minp:p=0.05",
+                "temperature:temperature=1.2"
+            ]
+        );
+
+        let seeded = defaults.clone().with_overrides(&SamplingOverrides {
+            seed: Some(7),
+            ..Default::default()
+        });
+        assert_eq!(
+            seeded.describe(),
+            "temperature=0.8 top_p=0.95 top_k=40 min_p=0 repetition_penalty=1.3 seed=7"
+        );
+
+        let frozen = defaults.clone().with_overrides(&SamplingOverrides {
+            temperature: Some(0.0),
+            ..Default::default()
+        });
+        assert!(frozen.validate().is_err());
+        let empty_stop = defaults.with_overrides(&SamplingOverrides {
+            stop_sequences: Some(vec![String::new()]),
+            ..Default::default()
+        });
+        assert!(empty_stop.validate().is_err());
+    }
+}
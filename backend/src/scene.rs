@@ -0,0 +1,520 @@
+use crate::database::Database;
+use crate::db_pool;
+use crate::event_bus;
+use chrono::{DateTime, Utc};
+use rusqlite::{params, OptionalExtension, Result};
+use serde::{Deserialize, Serialize};
+
+pub const MAX_LOCATION_CHARS: usize = 80;
+// Words a location is made of at most, after its article
+const MAX_LOCATION_WORDS: usize = 3;
+// Verbs that take the companion and the user somewhere
+const MOTION_VERBS: [&str; 28] = [
+    "go", "goes", "going", "went", "head", "heads", "heading", "headed", "walk", "walks",
+    "walking", "walked", "drive", "drives", "driving", "drove", "arrive", "arrives", "arriving",
+    "arrived", "step", "steps", "stepped", "enter", "enters", "entered", "move", "moved",
+];
+const PREPOSITIONS: [&str; 6] = ["to", "into", "at", "inside", "toward", "towards"];
+// A location starts with one of these, so "going to say" isn't taken for a place
+const ARTICLES: [&str; 9] = ["the", "a", "an", "my", "your", "our", "his", "her", "their"];
+// End a location early, "the park with Anna" is at "the park"
+const LOCATION_STOP_WORDS: [&str; 16] = [
+    "and", "with", "for", "to", "because", "so", "but", "then", "together", "now", "later",
+    "after", "before", "while", "where", "when",
+];
+const ARRIVALS: [&str; 10] = [
+    "arrives",
+    "arrived",
+    "comes in",
+    "came in",
+    "joins",
+    "joined",
+    "shows up",
+    "showed up",
+    "walks in",
+    "walked in",
+];
+const DEPARTURES: [&str; 8] = [
+    "leaves",
+    "left",
+    "goes home",
+    "went home",
+    "walks out",
+    "walked out",
+    "says goodbye",
+    "said goodbye",
+];
+
+/// Part of the day the scene plays in, which may differ from the clock in a roleplay
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum TimeOfDay {
+    Morning,
+    Afternoon,
+    Evening,
+    Night,
+}
+
+// Phrases that tell the time of day, the last one in a message wins
+const TIME_CUES: [(TimeOfDay, &[&str]); 4] = [
+    (
+        TimeOfDay::Morning,
+        &[
+            "good morning",
+            "this morning",
+            "sunrise",
+            "dawn",
+            "breakfast",
+        ],
+    ),
+    (
+        TimeOfDay::Afternoon,
+        &["good afternoon", "this afternoon", "noon", "lunch"],
+    ),
+    (
+        TimeOfDay::Evening,
+        &["good evening", "this evening", "sunset", "dusk", "dinner"],
+    ),
+    (
+        TimeOfDay::Night,
+        &[
+            "good night",
+            "midnight",
+            "bedtime",
+            "late at night",
+            "the stars",
+        ],
+    ),
+];
+
+impl TimeOfDay {
+    pub fn name(self) -> &'static str {
+        match self {
+            TimeOfDay::Morning => "morning",
+            TimeOfDay::Afternoon => "afternoon",
+            TimeOfDay::Evening => "evening",
+            TimeOfDay::Night => "night",
+        }
+    }
+
+    fn parse(name: &str) -> Option<TimeOfDay> {
+        TIME_CUES
+            .iter()
+            .map(|(time, _)| *time)
+            .find(|time| time.name() == name)
+    }
+}
+
+/// Where and when a conversation plays and who else is there
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct Scene {
+    pub conversation_id: i32,
+    pub companion_id: i32,
+    pub location: Option<String>,
+    pub time_of_day: Option<TimeOfDay>,
+    /// Persons besides the companion and the user
+    pub present: Vec<String>,
+    /// None while nothing is known about the scene
+    pub updated_at: Option<DateTime<Utc>>,
+}
+
+/// Scene set by the user, replacing what was tracked
+#[derive(Deserialize, Debug, Clone, Default)]
+pub struct SceneModify {
+    #[serde(default)]
+    pub location: Option<String>,
+    #[serde(default)]
+    pub time_of_day: Option<TimeOfDay>,
+    #[serde(default)]
+    pub present: Vec<String>,
+}
+
+/// What a message says about the scene
+#[derive(Debug, Clone, Default, PartialEq)]
+struct SceneCues {
+    location: Option<String>,
+    time_of_day: Option<TimeOfDay>,
+    arrived: Vec<String>,
+    left: Vec<String>,
+}
+
+fn words(text: &str) -> Vec<&str> {
+    text.split_whitespace().collect()
+}
+
+fn bare(word: &str) -> String {
+    word.trim_matches(|c: char| !c.is_alphanumeric() && c != '\'')
+        .to_lowercase()
+}
+
+/// Place named after a verb of motion, like "*walks to the old harbour*"
+///
+/// "my" and "your" are resolved to `speaker` and `listener`, so "my place" stays the same place
+/// whoever mentions it later.
+fn location_cue(text: &str, speaker: &str, listener: &str) -> Option<String> {
+    let words = words(text);
+    let mut found = None;
+    for i in 0..words.len() {
+        if !MOTION_VERBS.contains(&bare(words[i]).as_str())
+            || words[i].ends_with([',', '.', '!', '?'])
+        {
+            continue;
+        }
+        let mut next = i + 1;
+        if words
+            .get(next)
+            .is_some_and(|word| PREPOSITIONS.contains(&bare(word).as_str()))
+        {
+            next += 1;
+        }
+        let article = match words.get(next) {
+            Some(word) => bare(word),
+            None => continue,
+        };
+        if article == "home" {
+            found = Some("home".to_string());
+            continue;
+        }
+        if !ARTICLES.contains(&article.as_str()) {
+            continue;
+        }
+        let mut place = Vec::new();
+        for word in words.iter().skip(next + 1).take(MAX_LOCATION_WORDS) {
+            let name = word.trim_matches(|c: char| !c.is_alphanumeric() && c != '\'' && c != '-');
+            if name.is_empty() || LOCATION_STOP_WORDS.contains(&bare(word).as_str()) {
+                break;
+            }
+            place.push(name);
+            if word.ends_with(|c: char| !c.is_alphanumeric()) {
+                break;
+            }
+        }
+        if !place.is_empty() {
+            let owner = match article.as_str() {
+                "my" => format!("{}'s", speaker),
+                "your" => format!("{}'s", listener),
+                // Once they are there it's "the harbour"
+                _ => "the".to_string(),
+            };
+            found = Some(format!("{} {}", owner, place.join(" ")));
+        }
+    }
+    found
+}
+
+fn time_cue(text: &str) -> Option<TimeOfDay> {
+    let text = text.to_lowercase();
+    TIME_CUES
+        .iter()
+        .flat_map(|(time, phrases)| {
+            phrases
+                .iter()
+                .filter_map(|phrase| text.rfind(phrase).map(|at| (at, *time)))
+        })
+        .max_by_key(|(at, _)| *at)
+        .map(|(_, time)| time)
+}
+
+/// Scene changes in a message of `speaker` to `listener`, `known` are the names of the persons the
+/// companion knows about
+fn cues(text: &str, speaker: &str, listener: &str, known: &[String]) -> SceneCues {
+    let mut cues = SceneCues {
+        location: location_cue(text, speaker, listener),
+        time_of_day: time_cue(text),
+        ..SceneCues::default()
+    };
+    for sentence in text.split(['.', '!', '?', '\n', '*']) {
+        // Punctuation dropped, so the cues are found next to commas as well
+        let lower = format!(
+            " {} ",
+            words(sentence)
+                .into_iter()
+                .map(bare)
+                .collect::<Vec<_>>()
+                .join(" ")
+        );
+        for name in known {
+            let bare_name = name.to_lowercase();
+            if !lower.contains(&format!(" {} ", bare_name))
+                && !lower.contains(&format!(" {}'s ", bare_name))
+            {
+                continue;
+            }
+            let with = lower.contains(&format!(" with {} ", bare_name));
+            if DEPARTURES
+                .iter()
+                .any(|cue| lower.contains(&format!(" {} ", cue)))
+                && !with
+            {
+                cues.left.push(name.clone());
+            } else if with
+                || ARRIVALS
+                    .iter()
+                    .any(|cue| lower.contains(&format!(" {} ", cue)))
+            {
+                cues.arrived.push(name.clone());
+            }
+        }
+    }
+    cues
+}
+
+impl Scene {
+    fn empty(conversation_id: i32, companion_id: i32) -> Scene {
+        Scene {
+            conversation_id,
+            companion_id,
+            location: None,
+            time_of_day: None,
+            present: Vec::new(),
+            updated_at: None,
+        }
+    }
+
+    /// Move the scene along, returns false if the cues didn't change anything
+    fn apply(&mut self, cues: SceneCues) -> bool {
+        let before = self.clone();
+        if let Some(location) = cues.location {
+            if self.location.as_ref() != Some(&location) {
+                // Only those who came along are at the new place
+                self.present.retain(|name| cues.arrived.contains(name));
+                self.location = Some(location);
+            }
+        }
+        if cues.time_of_day.is_some() {
+            self.time_of_day = cues.time_of_day;
+        }
+        for name in cues.arrived {
+            if !self.present.contains(&name) {
+                self.present.push(name);
+            }
+        }
+        self.present.retain(|name| !cues.left.contains(name));
+        *self != before
+    }
+
+    /// Line for the prompt, None while nothing is known about the scene
+    pub fn prompt_line(&self) -> Option<String> {
+        let setting: Vec<String> = self
+            .location
+            .iter()
+            .cloned()
+            .chain(self.time_of_day.map(|time| match time {
+                TimeOfDay::Night => "at night".to_string(),
+                _ => format!("in the {}", time.name()),
+            }))
+            .collect();
+        let mut line = match setting.is_empty() {
+            true => String::new(),
+            false => format!("Current scene: {}.", setting.join(", ")),
+        };
+        let present = match self.present.as_slice() {
+            [] => None,
+            [name] => Some(format!("{} is there as well.", name)),
+            [names @ .., last] => Some(format!(
+                "{} and {} are there as well.",
+                names.join(", "),
+                last
+            )),
+        };
+        if let Some(present) = present {
+            if !line.is_empty() {
+                line.push(' ');
+            }
+            line += &present;
+        }
+        (!line.is_empty()).then_some(line)
+    }
+
+    pub fn create() -> Result<usize> {
+        let con = db_pool::connection()?;
+        con.execute(
+            "CREATE TABLE IF NOT EXISTS scenes (
+                conversation_id INTEGER PRIMARY KEY,
+                companion_id INTEGER NOT NULL,
+                location TEXT,
+                time_of_day TEXT,
+                present TEXT NOT NULL DEFAULT '[]',
+                updated_at TEXT NOT NULL
+            )",
+            [],
+        )
+    }
+
+    /// Scene of a conversation, empty until something happened in it
+    pub fn get(conversation_id: i32, companion_id: i32) -> Result<Scene> {
+        let con = db_pool::connection()?;
+        let stored = con
+            .query_row(
+                "SELECT location, time_of_day, present, updated_at FROM scenes WHERE conversation_id = ?",
+                [conversation_id],
+                |row| {
+                    let time_of_day: Option<String> = row.get(1)?;
+                    let present: String = row.get(2)?;
+                    Ok(Scene {
+                        conversation_id,
+                        companion_id,
+                        location: row.get(0)?,
+                        time_of_day: time_of_day.as_deref().and_then(TimeOfDay::parse),
+                        present: serde_json::from_str(&present).unwrap_or_default(),
+                        updated_at: Some(row.get(3)?),
+                    })
+                },
+            )
+            .optional()?;
+        Ok(stored.unwrap_or_else(|| Scene::empty(conversation_id, companion_id)))
+    }
+
+    fn save(&mut self) -> Result<()> {
+        let now = Utc::now();
+        let con = db_pool::connection()?;
+        con.execute(
+            "INSERT INTO scenes (conversation_id, companion_id, location, time_of_day, present, updated_at)
+             VALUES (?1, ?2, ?3, ?4, ?5, ?6)
+             ON CONFLICT(conversation_id) DO UPDATE SET
+                location = ?3, time_of_day = ?4, present = ?5, updated_at = ?6",
+            params![
+                self.conversation_id,
+                self.companion_id,
+                self.location,
+                self.time_of_day.map(TimeOfDay::name),
+                serde_json::to_string(&self.present).unwrap_or_else(|_| "[]".to_string()),
+                now
+            ],
+        )?;
+        self.updated_at = Some(now);
+        event_bus::publish("scene_updated", serde_json::json!(self));
+        Ok(())
+    }
+
+    /// Let a message of `speaker` to `listener`, the user or the companion, move the scene along
+    ///
+    /// Publishes a `scene_updated` event when the scene changed.
+    pub fn follow(
+        conversation_id: i32,
+        companion_id: i32,
+        text: &str,
+        speaker: &str,
+        listener: &str,
+    ) -> Result<Scene> {
+        let known: Vec<String> = Database::get_all_third_party_individuals()?
+            .into_iter()
+            .map(|person| person.name)
+            .collect();
+        let mut scene = Scene::get(conversation_id, companion_id)?;
+        if scene.apply(cues(text, speaker, listener, &known)) {
+            scene.save()?;
+        }
+        Ok(scene)
+    }
+
+    /// Fails with InvalidParameterName for a location that is too long
+    pub fn set(conversation_id: i32, companion_id: i32, modify: SceneModify) -> Result<Scene> {
+        let location = modify
+            .location
+            .map(|location| location.trim().to_string())
+            .filter(|location| !location.is_empty());
+        if location
+            .as_ref()
+            .is_some_and(|location| location.chars().count() > MAX_LOCATION_CHARS)
+        {
+            return Err(rusqlite::Error::InvalidParameterName(format!(
+                "Locations are limited to {} characters",
+                MAX_LOCATION_CHARS
+            )));
+        }
+        let mut present: Vec<String> = Vec::new();
+        for name in modify.present.iter().map(|name| name.trim()) {
+            if !name.is_empty() && !present.iter().any(|known| known == name) {
+                present.push(name.to_string());
+            }
+        }
+        let mut scene = Scene {
+            location,
+            time_of_day: modify.time_of_day,
+            present,
+            ..Scene::empty(conversation_id, companion_id)
+        };
+        scene.save()?;
+        Ok(scene)
+    }
+
+    /// Forget the scene of a conversation, returns false if nothing was known about it
+    pub fn clear(conversation_id: i32, companion_id: i32) -> Result<bool> {
+        let con = db_pool::connection()?;
+        let removed = con.execute(
+            "DELETE FROM scenes WHERE conversation_id = ?",
+            [conversation_id],
+        )?;
+        if removed > 0 {
+            event_bus::publish(
+                "scene_updated",
+                serde_json::json!(Scene::empty(conversation_id, companion_id)),
+            );
+        }
+        Ok(removed > 0)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_scene_cues() {
+        assert_eq!(
+            location_cue(
+                "*takes your hand and walks to the old harbour with you*",
+                "Luna",
+                "Eric"
+            )
+            .as_deref(),
+            Some("the old harbour")
+        );
+        assert_eq!(
+            location_cue("Let's go to my place, it's cozy.", "Eric", "Luna").as_deref(),
+            Some("Eric's place")
+        );
+        assert_eq!(
+            location_cue("I'm going home now", "Luna", "Eric").as_deref(),
+            Some("home")
+        );
+        assert_eq!(
+            location_cue("I was going to say something", "Luna", "Eric"),
+            None
+        );
+        assert_eq!(
+            time_cue("Good morning! Breakfast can wait... the sunset was lovely"),
+            Some(TimeOfDay::Evening)
+        );
+        assert_eq!(time_cue("Hello there"), None);
+
+        let known = vec!["Anna".to_string(), "Tom".to_string()];
+        let found = cues(
+            "Anna arrives. Tom's cat is cute. *Tom left*",
+            "Luna",
+            "Eric",
+            &known,
+        );
+        assert_eq!(found.arrived, vec!["Anna".to_string()]);
+        assert_eq!(found.left, vec!["Tom".to_string()]);
+
+        let mut scene = Scene::empty(1, 1);
+        scene.present = vec!["Tom".to_string()];
+        assert!(scene.apply(cues(
+            "*heads into the kitchen with Anna* Good evening!",
+            "Luna",
+            "Eric",
+            &known
+        )));
+        assert_eq!(scene.location.as_deref(), Some("the kitchen"));
+        assert_eq!(scene.time_of_day, Some(TimeOfDay::Evening));
+        assert_eq!(scene.present, vec!["Anna".to_string()]);
+        assert!(!scene.apply(cues("How are you?", "Eric", "Luna", &known)));
+        assert_eq!(
+            scene.prompt_line().as_deref(),
+            Some("Current scene: the kitchen, in the evening. Anna is there as well.")
+        );
+        assert_eq!(Scene::empty(1, 1).prompt_line(), None);
+    }
+}
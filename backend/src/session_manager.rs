@@ -1,10 +1,15 @@
-use crate::database::{CompanionAttitude, Database};
+use crate::database::{get_current_date, CompanionAttitude, Database, Message, MESSAGE_COLUMNS};
+use crate::db_pool;
 use chrono::{DateTime, Duration, Utc};
+use rusqlite::{params, OptionalExtension};
 use serde::{Deserialize, Serialize};
 use std::collections::HashMap;
 use std::sync::{Arc, Mutex};
 use uuid::Uuid;
 
+// How often expired sessions are swept out of memory
+const SWEEP_INTERVAL_SECONDS: u64 = 5 * 60;
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Session {
     pub id: String,
@@ -14,6 +19,109 @@ pub struct Session {
     pub last_activity: DateTime<Utc>,
     pub attitude_state: Vec<CompanionAttitude>,
     pub is_active: bool,
+    /// Incognito sessions only live in memory, nothing is written to SQLite or tantivy
+    #[serde(default)]
+    pub incognito: bool,
+    /// Transcript of an incognito session
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub messages: Vec<Message>,
+    /// People mentioned during an incognito session, never registered as third parties
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub detected_persons: Vec<String>,
+}
+
+/// Stored session as listed by /api/session, without its attitudes
+#[derive(Debug, Clone, Serialize)]
+pub struct SessionRecord {
+    pub id: String,
+    pub companion_id: i32,
+    pub user_id: Option<i32>,
+    pub created_at: DateTime<Utc>,
+    pub last_activity: DateTime<Utc>,
+    pub ended_at: Option<DateTime<Utc>>,
+    pub is_active: bool,
+    pub attitude_count: usize,
+}
+
+/// Attitude as it was after an update during the session
+#[derive(Debug, Clone, Serialize)]
+pub struct AttitudeSnapshot {
+    pub attitude: CompanionAttitude,
+    pub recorded_at: DateTime<Utc>,
+}
+
+/// What happened during a session, the chat messages and every attitude change
+#[derive(Debug, Clone, Serialize)]
+pub struct SessionHistory {
+    pub session: SessionRecord,
+    pub messages: Vec<Message>,
+    pub attitude_snapshots: Vec<AttitudeSnapshot>,
+}
+
+fn json_error(index: usize, e: serde_json::Error) -> rusqlite::Error {
+    rusqlite::Error::FromSqlConversionFailure(index, rusqlite::types::Type::Text, Box::new(e))
+}
+
+/// Write a session through to SQLite, incognito sessions are never stored
+///
+/// The messages of the companion written while the session was active make up its history,
+/// so the first and last message ids are recorded when it starts and ends.
+fn store_session(session: &Session) -> Result<(), rusqlite::Error> {
+    if session.incognito {
+        return Ok(());
+    }
+    let attitude_state = serde_json::to_string(&session.attitude_state)
+        .map_err(|e| rusqlite::Error::ToSqlConversionFailure(Box::new(e)))?;
+    let ended_at = if session.is_active { None } else { Some(Utc::now()) };
+    let con = db_pool::connection()?;
+    con.execute(
+        "INSERT INTO sessions (id, companion_id, user_id, created_at, last_activity, ended_at,
+            is_active, attitude_state, first_message_id)
+         VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8,
+            (SELECT COALESCE(MAX(id), 0) FROM messages WHERE companion_id = ?2))
+         ON CONFLICT(id) DO UPDATE SET
+            last_activity = excluded.last_activity,
+            attitude_state = excluded.attitude_state,
+            is_active = excluded.is_active,
+            ended_at = COALESCE(sessions.ended_at, excluded.ended_at),
+            last_message_id = CASE WHEN excluded.is_active = 0 AND sessions.last_message_id IS NULL
+                THEN (SELECT COALESCE(MAX(id), 0) FROM messages WHERE companion_id = sessions.companion_id)
+                ELSE sessions.last_message_id END",
+        params![
+            session.id,
+            session.companion_id,
+            session.user_id,
+            session.created_at,
+            session.last_activity,
+            ended_at,
+            session.is_active,
+            attitude_state,
+        ],
+    )?;
+    Ok(())
+}
+
+/// Storage failures are logged, the in-memory session keeps working without them
+fn store_or_log(session: &Session) {
+    if let Err(e) = store_session(session) {
+        tracing::error!("Failed to store session {}: {}", session.id, e);
+    }
+}
+
+fn record_from_row(row: &rusqlite::Row) -> Result<SessionRecord, rusqlite::Error> {
+    let attitudes: String = row.get(7)?;
+    let attitudes: Vec<serde_json::Value> =
+        serde_json::from_str(&attitudes).map_err(|e| json_error(7, e))?;
+    Ok(SessionRecord {
+        id: row.get(0)?,
+        companion_id: row.get(1)?,
+        user_id: row.get(2)?,
+        created_at: row.get(3)?,
+        last_activity: row.get(4)?,
+        ended_at: row.get(5)?,
+        is_active: row.get(6)?,
+        attitude_count: attitudes.len(),
+    })
 }
 
 #[derive(Debug, Clone)]
@@ -30,11 +138,178 @@ impl SessionManager {
         }
     }
 
+    pub fn create() -> Result<(), rusqlite::Error> {
+        let con = db_pool::connection()?;
+        con.execute(
+            "CREATE TABLE IF NOT EXISTS sessions (
+                id TEXT PRIMARY KEY,
+                companion_id INTEGER NOT NULL,
+                user_id INTEGER,
+                created_at TEXT NOT NULL,
+                last_activity TEXT NOT NULL,
+                ended_at TEXT,
+                is_active INTEGER NOT NULL,
+                attitude_state TEXT NOT NULL,
+                first_message_id INTEGER NOT NULL DEFAULT 0,
+                last_message_id INTEGER
+            )",
+            [],
+        )?;
+        con.execute(
+            "CREATE TABLE IF NOT EXISTS session_attitude_snapshots (
+                id INTEGER PRIMARY KEY AUTOINCREMENT,
+                session_id TEXT NOT NULL,
+                attitude TEXT NOT NULL,
+                recorded_at TEXT NOT NULL,
+                FOREIGN KEY (session_id) REFERENCES sessions(id) ON DELETE CASCADE
+            )",
+            [],
+        )?;
+        Ok(())
+    }
+
+    /// Load the sessions that were active when the server stopped, returns how many came back
+    pub fn restore_sessions(&self) -> Result<usize, String> {
+        let con = db_pool::connection().map_err(|e| e.to_string())?;
+        let mut stmt = con
+            .prepare(
+                "SELECT id, companion_id, user_id, created_at, last_activity, attitude_state
+                 FROM sessions WHERE is_active = 1",
+            )
+            .map_err(|e| e.to_string())?;
+        let stored: Vec<Session> = stmt
+            .query_map([], |row| {
+                let attitude_state: String = row.get(5)?;
+                Ok(Session {
+                    id: row.get(0)?,
+                    companion_id: row.get(1)?,
+                    user_id: row.get(2)?,
+                    created_at: row.get(3)?,
+                    last_activity: row.get(4)?,
+                    attitude_state: serde_json::from_str(&attitude_state)
+                        .map_err(|e| json_error(5, e))?,
+                    is_active: true,
+                    incognito: false,
+                    messages: Vec::new(),
+                    detected_persons: Vec::new(),
+                })
+            })
+            .and_then(|rows| rows.collect())
+            .map_err(|e| e.to_string())?;
+
+        let mut sessions = self.sessions.lock().map_err(|e| e.to_string())?;
+        let mut restored = 0;
+        for mut session in stored {
+            if self.is_session_expired(&session) {
+                // The server was down when it would have expired
+                session.is_active = false;
+                store_or_log(&session);
+            } else {
+                sessions.insert(session.id.clone(), session);
+                restored += 1;
+            }
+        }
+        Ok(restored)
+    }
+
+    /// Stored sessions, most recently active first
+    pub fn list_sessions(active_only: bool, limit: usize) -> Result<Vec<SessionRecord>, rusqlite::Error> {
+        let con = db_pool::connection()?;
+        let mut stmt = con.prepare(
+            "SELECT id, companion_id, user_id, created_at, last_activity, ended_at, is_active,
+                attitude_state
+             FROM sessions WHERE is_active = 1 OR ?1 = 0
+             ORDER BY last_activity DESC LIMIT ?2",
+        )?;
+        let rows = stmt.query_map(params![active_only, limit], record_from_row)?;
+        rows.collect()
+    }
+
+    /// Messages and attitude changes of a stored session, None if there is no such session
+    pub fn session_history(session_id: &str) -> Result<Option<SessionHistory>, rusqlite::Error> {
+        let con = db_pool::connection()?;
+        let stored = con
+            .query_row(
+                "SELECT id, companion_id, user_id, created_at, last_activity, ended_at, is_active,
+                    attitude_state, first_message_id, last_message_id
+                 FROM sessions WHERE id = ?",
+                [session_id],
+                |row| Ok((record_from_row(row)?, row.get::<_, i32>(8)?, row.get::<_, Option<i32>>(9)?)),
+            )
+            .optional()?;
+        let (session, first_message_id, last_message_id) = match stored {
+            Some(stored) => stored,
+            None => return Ok(None),
+        };
+
+        let mut stmt = con.prepare(&format!(
+            "SELECT {} FROM messages
+             WHERE companion_id = ? AND id > ? AND (? IS NULL OR id <= ?)
+             ORDER BY id",
+            MESSAGE_COLUMNS
+        ))?;
+        let messages = stmt
+            .query_map(
+                params![session.companion_id, first_message_id, last_message_id, last_message_id],
+                Database::message_from_row,
+            )?
+            .collect::<Result<Vec<Message>, rusqlite::Error>>()?;
+
+        let mut stmt = con.prepare(
+            "SELECT attitude, recorded_at FROM session_attitude_snapshots
+             WHERE session_id = ? ORDER BY id",
+        )?;
+        let attitude_snapshots = stmt
+            .query_map([session_id], |row| {
+                let attitude: String = row.get(0)?;
+                Ok(AttitudeSnapshot {
+                    attitude: serde_json::from_str(&attitude).map_err(|e| json_error(0, e))?,
+                    recorded_at: row.get(1)?,
+                })
+            })?
+            .collect::<Result<Vec<AttitudeSnapshot>, rusqlite::Error>>()?;
+
+        Ok(Some(SessionHistory {
+            session,
+            messages,
+            attitude_snapshots,
+        }))
+    }
+
+    fn record_snapshot(session_id: &str, attitude: &CompanionAttitude) -> Result<(), rusqlite::Error> {
+        let attitude = serde_json::to_string(attitude)
+            .map_err(|e| rusqlite::Error::ToSqlConversionFailure(Box::new(e)))?;
+        let con = db_pool::connection()?;
+        con.execute(
+            "INSERT INTO session_attitude_snapshots (session_id, attitude, recorded_at) VALUES (?, ?, ?)",
+            params![session_id, attitude, Utc::now()],
+        )?;
+        Ok(())
+    }
+
     /// Create a new session and load existing attitudes from database
     pub fn create_session(
         &self,
         companion_id: i32,
         user_id: Option<i32>,
+    ) -> Result<Session, String> {
+        self.start_session(companion_id, user_id, false)
+    }
+
+    /// Create a session whose messages, attitude updates and detections are forgotten when it ends
+    pub fn create_incognito_session(
+        &self,
+        companion_id: i32,
+        user_id: Option<i32>,
+    ) -> Result<Session, String> {
+        self.start_session(companion_id, user_id, true)
+    }
+
+    fn start_session(
+        &self,
+        companion_id: i32,
+        user_id: Option<i32>,
+        incognito: bool,
     ) -> Result<Session, String> {
         let session_id = Uuid::new_v4().to_string();
 
@@ -42,7 +317,7 @@ impl SessionManager {
         let attitude_state = match Database::get_all_companion_attitudes(companion_id) {
             Ok(attitudes) => attitudes,
             Err(e) => {
-                eprintln!(
+                tracing::error!(
                     "Failed to load attitudes for companion {}: {}",
                     companion_id, e
                 );
@@ -58,14 +333,19 @@ impl SessionManager {
             last_activity: Utc::now(),
             attitude_state,
             is_active: true,
+            incognito,
+            messages: Vec::new(),
+            detected_persons: Vec::new(),
         };
 
-        // Store session in memory
+        // Store session in memory, and in SQLite so it survives a restart
+        store_or_log(&session);
         let mut sessions = self.sessions.lock().map_err(|e| e.to_string())?;
         sessions.insert(session_id.clone(), session.clone());
 
-        println!(
-            "📦 Session created: {} with {} attitudes loaded",
+        tracing::info!(
+            "📦 {} created: {} with {} attitudes loaded",
+            if incognito { "Incognito session" } else { "Session" },
             session_id,
             session.attitude_state.len()
         );
@@ -112,6 +392,7 @@ impl SessionManager {
 
         if let Some(session) = sessions.get_mut(session_id) {
             session.last_activity = Utc::now();
+            store_or_log(session);
             Ok(())
         } else {
             Err(format!("Session {} not found", session_id))
@@ -139,6 +420,13 @@ impl SessionManager {
             }
 
             session.last_activity = Utc::now();
+            if session.incognito {
+                return Ok(());
+            }
+            store_or_log(session);
+            if let Err(e) = SessionManager::record_snapshot(session_id, &attitude) {
+                tracing::error!("Failed to record attitude snapshot for session {}: {}", session_id, e);
+            }
 
             // Persist to database
             Database::create_or_update_attitude(
@@ -149,7 +437,7 @@ impl SessionManager {
             )
             .map_err(|e| format!("Failed to persist attitude: {}", e))?;
 
-            println!(
+            tracing::info!(
                 "💾 Attitude updated for session {} and persisted to database",
                 session_id
             );
@@ -174,6 +462,9 @@ impl SessionManager {
         let sessions = self.sessions.lock().map_err(|e| e.to_string())?;
 
         if let Some(session) = sessions.get(session_id) {
+            if session.incognito {
+                return Ok(());
+            }
             // Persist all attitudes to database
             for attitude in &session.attitude_state {
                 Database::create_or_update_attitude(
@@ -185,7 +476,7 @@ impl SessionManager {
                 .map_err(|e| format!("Failed to persist attitude: {}", e))?;
             }
 
-            println!(
+            tracing::info!(
                 "💾 Session {} persisted with {} attitudes",
                 session_id,
                 session.attitude_state.len()
@@ -196,6 +487,31 @@ impl SessionManager {
         }
     }
 
+    /// Store every active session and its attitudes as they are, for a shutdown
+    ///
+    /// The sessions stay active, so they are restored on the next start. Returns how many were
+    /// stored, one that fails is logged and the others are stored regardless.
+    pub fn flush(&self) -> Result<usize, String> {
+        let sessions = self.sessions.lock().map_err(|e| e.to_string())?;
+        let mut stored = 0;
+        for session in sessions.values().filter(|s| s.is_active && !s.incognito) {
+            let attitudes = session.attitude_state.iter().try_for_each(|attitude| {
+                Database::create_or_update_attitude(
+                    attitude.companion_id,
+                    attitude.target_id,
+                    &attitude.target_type,
+                    attitude,
+                )
+                .map(|_| ())
+            });
+            match attitudes.and_then(|_| store_session(session)) {
+                Ok(()) => stored += 1,
+                Err(e) => tracing::error!("Failed to store session {}: {}", session.id, e),
+            }
+        }
+        Ok(stored)
+    }
+
     /// End a session and persist state
     pub fn end_session(&self, session_id: &str) -> Result<(), String> {
         // Persist session state first
@@ -205,7 +521,14 @@ impl SessionManager {
         let mut sessions = self.sessions.lock().map_err(|e| e.to_string())?;
         if let Some(session) = sessions.get_mut(session_id) {
             session.is_active = false;
-            println!("🔚 Session {} ended", session_id);
+            store_or_log(session);
+            if session.incognito {
+                // Drop everything the session kept
+                session.messages.clear();
+                session.detected_persons.clear();
+                session.attitude_state.clear();
+            }
+            tracing::info!("🔚 Session {} ended", session_id);
         }
 
         Ok(())
@@ -231,7 +554,7 @@ impl SessionManager {
 
         // Persist and remove expired sessions
         for session_id in &expired_ids {
-            if let Some(session) = sessions.get(&session_id.clone()) {
+            if let Some(session) = sessions.get_mut(&session_id.clone()).filter(|s| !s.incognito) {
                 // Persist attitudes before removal
                 for attitude in &session.attitude_state {
                     let _ = Database::create_or_update_attitude(
@@ -241,18 +564,66 @@ impl SessionManager {
                         attitude,
                     );
                 }
+                if session.is_active {
+                    session.is_active = false;
+                    store_or_log(session);
+                }
             }
             sessions.remove(session_id);
         }
 
         let removed_count = initial_count - sessions.len();
         if removed_count > 0 {
-            println!("🧹 Cleaned up {} expired sessions", removed_count);
+            tracing::info!("🧹 Cleaned up {} expired sessions", removed_count);
         }
 
         Ok(removed_count)
     }
 
+    /// Append a message to an incognito session, returns the transcript so far
+    pub fn add_incognito_message(
+        &self,
+        session_id: &str,
+        ai: bool,
+        content: &str,
+    ) -> Result<Vec<Message>, String> {
+        let mut sessions = self.sessions.lock().map_err(|e| e.to_string())?;
+
+        match sessions.get_mut(session_id) {
+            Some(session) if session.incognito && session.is_active => {
+                session.messages.push(Message {
+                    id: session.messages.len() as i32 + 1,
+                    ai,
+                    content: content.to_string(),
+                    created_at: get_current_date(),
+                    author_id: if ai { None } else { session.user_id },
+                    images: Vec::new(),
+                    stats: None,
+                });
+                session.last_activity = Utc::now();
+                Ok(session.messages.clone())
+            }
+            Some(_) => Err(format!("Session {} is not an active incognito session", session_id)),
+            None => Err(format!("Session {} not found", session_id)),
+        }
+    }
+
+    /// Remember people mentioned in an incognito session without registering them
+    pub fn add_detected_persons(&self, session_id: &str, names: Vec<String>) -> Result<(), String> {
+        let mut sessions = self.sessions.lock().map_err(|e| e.to_string())?;
+
+        if let Some(session) = sessions.get_mut(session_id) {
+            for name in names {
+                if !session.detected_persons.contains(&name) {
+                    session.detected_persons.push(name);
+                }
+            }
+            Ok(())
+        } else {
+            Err(format!("Session {} not found", session_id))
+        }
+    }
+
     /// Get statistics about active sessions
     pub fn get_session_stats(&self) -> Result<SessionStats, String> {
         let sessions = self.sessions.lock().map_err(|e| e.to_string())?;
@@ -268,6 +639,17 @@ impl SessionManager {
     }
 }
 
+/// Background loop ending sessions that saw no activity for longer than the timeout
+pub async fn run_sweeper(manager: SessionManager) {
+    let mut interval = tokio::time::interval(std::time::Duration::from_secs(SWEEP_INTERVAL_SECONDS));
+    loop {
+        interval.tick().await;
+        if let Err(e) = manager.cleanup_expired_sessions() {
+            tracing::warn!("⚠️ Failed to sweep expired sessions: {}", e);
+        }
+    }
+}
+
 #[derive(Debug, Serialize)]
 pub struct SessionStats {
     pub active_sessions: usize,
@@ -298,6 +680,25 @@ mod tests {
         assert_eq!(session.id, retrieved.id);
     }
 
+    #[test]
+    fn test_incognito_session_keeps_messages_in_memory() {
+        let manager = SessionManager::new(30);
+        let session = manager.create_incognito_session(1, Some(1)).unwrap();
+        assert!(session.incognito);
+
+        manager.add_incognito_message(&session.id, false, "Hello").unwrap();
+        let transcript = manager.add_incognito_message(&session.id, true, "Hi!").unwrap();
+        assert_eq!(transcript.len(), 2);
+        assert!(transcript[1].ai);
+
+        manager.end_session(&session.id).unwrap();
+        assert!(manager.add_incognito_message(&session.id, false, "Still there?").is_err());
+
+        let regular = manager.create_session(1, Some(1)).unwrap();
+        assert!(!regular.incognito);
+        assert!(manager.add_incognito_message(&regular.id, false, "Hello").is_err());
+    }
+
     #[test]
     fn test_attitude_update() {
         let manager = SessionManager::new(30);
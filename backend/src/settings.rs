@@ -0,0 +1,193 @@
+use clap::Parser;
+use serde::Deserialize;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::OnceLock;
+
+const DEFAULT_HOST: &str = "0.0.0.0";
+const DEFAULT_PORT: u16 = 3000;
+// Read from the working directory when no other config file is given
+const DEFAULT_CONFIG_FILE: &str = "companion.toml";
+const DATABASE_FILE: &str = "companion_database.db";
+const ASSETS_DIR: &str = "assets";
+// Output of the frontend build, relative to the working directory
+const UI_DIR: &str = "dist";
+
+static SETTINGS: OnceLock<Settings> = OnceLock::new();
+
+/// Command line of the server, every option can be given as a COMPANION_ variable as well
+#[derive(Parser, Debug, Default)]
+#[command(version, about = "AI Companion server")]
+struct Args {
+    /// TOML file with any of the options below, companion.toml in the working directory by default
+    #[arg(long, env = "COMPANION_CONFIG")]
+    config: Option<PathBuf>,
+    /// Address to listen on
+    #[arg(long, env = "COMPANION_HOST")]
+    host: Option<String>,
+    /// Port to listen on
+    #[arg(short, long, env = "COMPANION_PORT")]
+    port: Option<u16>,
+    /// Directory the database, assets, long-term memories and logs are kept in
+    #[arg(long, env = "COMPANION_DATA_DIR")]
+    data_dir: Option<PathBuf>,
+    /// Database file, companion_database.db in the data directory by default
+    #[arg(long, env = "COMPANION_DATABASE")]
+    database: Option<PathBuf>,
+    /// Avatars and uploaded pictures, assets in the data directory by default
+    #[arg(long, env = "COMPANION_ASSETS_DIR")]
+    assets_dir: Option<PathBuf>,
+    /// Directory scanned for models besides llms next to the executable, can be repeated
+    #[arg(long = "model-dir", env = "COMPANION_MODEL_DIRS", value_delimiter = ',')]
+    model_dirs: Vec<PathBuf>,
+    /// Built web UI served instead of the one compiled in, dist in the working directory by default
+    #[arg(long, env = "COMPANION_UI_DIR")]
+    ui_dir: Option<PathBuf>,
+    /// Load the local model on startup instead of with the first reply, /api/ready waits for it
+    #[arg(long, env = "COMPANION_PRELOAD_MODEL")]
+    preload_model: bool,
+}
+
+/// Options of the config file, those given on the command line or in the environment win
+#[derive(Deserialize, Debug, Default, PartialEq)]
+#[serde(deny_unknown_fields)]
+struct FileSettings {
+    host: Option<String>,
+    port: Option<u16>,
+    data_dir: Option<PathBuf>,
+    database: Option<PathBuf>,
+    assets_dir: Option<PathBuf>,
+    model_dirs: Option<Vec<PathBuf>>,
+    ui_dir: Option<PathBuf>,
+    preload_model: Option<bool>,
+}
+
+/// Where the server listens and keeps its files, fixed once it started
+#[derive(Debug, Clone, PartialEq)]
+pub struct Settings {
+    pub host: String,
+    pub port: u16,
+    pub data_dir: PathBuf,
+    pub database: PathBuf,
+    pub assets_dir: PathBuf,
+    pub model_dirs: Vec<PathBuf>,
+    /// Web UI served from disk when it holds an index.html
+    pub ui_dir: PathBuf,
+    pub preload_model: bool,
+    /// Config file the settings were read from, if there was one
+    pub config_file: Option<PathBuf>,
+}
+
+impl Default for Settings {
+    fn default() -> Self {
+        resolve(Args::default(), FileSettings::default(), None)
+    }
+}
+
+fn resolve(args: Args, file: FileSettings, config_file: Option<PathBuf>) -> Settings {
+    let data_dir = args
+        .data_dir
+        .or(file.data_dir)
+        .unwrap_or_else(|| PathBuf::from("."));
+    let model_dirs = if args.model_dirs.is_empty() {
+        file.model_dirs.unwrap_or_default()
+    } else {
+        args.model_dirs
+    };
+    Settings {
+        host: args
+            .host
+            .or(file.host)
+            .unwrap_or_else(|| DEFAULT_HOST.to_string()),
+        port: args.port.or(file.port).unwrap_or(DEFAULT_PORT),
+        database: args
+            .database
+            .or(file.database)
+            .unwrap_or_else(|| data_dir.join(DATABASE_FILE)),
+        assets_dir: args
+            .assets_dir
+            .or(file.assets_dir)
+            .unwrap_or_else(|| data_dir.join(ASSETS_DIR)),
+        ui_dir: args
+            .ui_dir
+            .or(file.ui_dir)
+            .unwrap_or_else(|| PathBuf::from(UI_DIR)),
+        preload_model: args.preload_model || file.preload_model.unwrap_or(false),
+        data_dir,
+        model_dirs,
+        config_file,
+    }
+}
+
+fn read_file(path: &Path) -> Result<FileSettings, String> {
+    let text = fs::read_to_string(path)
+        .map_err(|e| format!("Could not read {}: {}", path.display(), e))?;
+    toml::from_str(&text).map_err(|e| format!("Could not read {}: {}", path.display(), e))
+}
+
+/// Read the command line, the environment and the config file and create the data directories
+///
+/// Exits with the usage on an unknown option, like any other command line program.
+pub fn init() -> Result<&'static Settings, String> {
+    let args = Args::parse();
+    let (file, config_file) = match &args.config {
+        Some(path) => (read_file(path)?, Some(path.clone())),
+        None if Path::new(DEFAULT_CONFIG_FILE).exists() => (
+            read_file(Path::new(DEFAULT_CONFIG_FILE))?,
+            Some(PathBuf::from(DEFAULT_CONFIG_FILE)),
+        ),
+        None => (FileSettings::default(), None),
+    };
+    let settings = resolve(args, file, config_file);
+    for dir in [&settings.data_dir, &settings.assets_dir] {
+        fs::create_dir_all(dir)
+            .map_err(|e| format!("Could not create {}: {}", dir.display(), e))?;
+    }
+    if let Some(parent) = settings.database.parent() {
+        if !parent.as_os_str().is_empty() {
+            fs::create_dir_all(parent)
+                .map_err(|e| format!("Could not create {}: {}", parent.display(), e))?;
+        }
+    }
+    Ok(SETTINGS.get_or_init(|| settings))
+}
+
+/// Settings the server started with, the defaults before init and in tests
+pub fn get() -> &'static Settings {
+    SETTINGS.get_or_init(Settings::default)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_settings_precedence() {
+        let defaults = Settings::default();
+        assert_eq!(defaults.port, 3000);
+        assert_eq!(defaults.database, Path::new("./companion_database.db"));
+        assert_eq!(defaults.assets_dir, Path::new("./assets"));
+        assert_eq!(defaults.ui_dir, Path::new("dist"));
+
+        let file: FileSettings = toml::from_str(
+            "port = 8080\nhost = \"127.0.0.1\"\ndata_dir = \"/srv/companion\"\nmodel_dirs = [\"/models\"]",
+        )
+        .unwrap();
+        let args = Args::try_parse_from(["ai-companion", "--port", "4000", "--assets-dir", "/tmp/a"])
+            .unwrap();
+        let settings = resolve(args, file, None);
+        assert_eq!(settings.port, 4000);
+        assert_eq!(settings.host, "127.0.0.1");
+        assert_eq!(
+            settings.database,
+            Path::new("/srv/companion/companion_database.db")
+        );
+        assert_eq!(settings.assets_dir, Path::new("/tmp/a"));
+        assert_eq!(settings.model_dirs, vec![PathBuf::from("/models")]);
+        assert!(!settings.preload_model);
+        let file: FileSettings = toml::from_str("preload_model = true").unwrap();
+        assert!(resolve(Args::default(), file, None).preload_model);
+
+        assert!(toml::from_str::<FileSettings>("prot = 1").is_err());
+    }
+}
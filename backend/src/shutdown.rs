@@ -0,0 +1,131 @@
+use crate::db_pool;
+use crate::inference_optimizer::INFERENCE_OPTIMIZER;
+use crate::inference_queue;
+use crate::instance_lock;
+use crate::maintenance;
+use crate::session_manager::SessionManager;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+use tracing::{info, warn};
+
+/// How long replies being generated get to finish once a stop was asked for, they are cut short after
+pub const GRACE_SECONDS: u64 = 10;
+// Requests other than generations, long polls and event streams included, are dropped this much later
+pub const SERVER_SHUTDOWN_SECONDS: u64 = GRACE_SECONDS + 5;
+// Background generations and maintenance jobs are waited for this much longer at most
+const DRAIN_SECONDS: u64 = 5;
+
+static STOPPING: AtomicBool = AtomicBool::new(false);
+
+lazy_static::lazy_static! {
+    static ref DEADLINE: Mutex<Option<Instant>> = Mutex::new(None);
+}
+
+/// A stop was asked for, no new generations are started
+pub fn is_stopping() -> bool {
+    STOPPING.load(Ordering::SeqCst)
+}
+
+/// The grace period ran out, generations still running stop at their next token
+pub fn past_grace() -> bool {
+    is_stopping()
+        && DEADLINE
+            .lock()
+            .map(|deadline| deadline.is_some_and(|deadline| Instant::now() >= deadline))
+            .unwrap_or(true)
+}
+
+/// Stop taking generations, requests waiting in the inference queue are cancelled
+///
+/// Returns false if a stop was under way already.
+pub fn begin() -> bool {
+    if STOPPING.swap(true, Ordering::SeqCst) {
+        return false;
+    }
+    if let Ok(mut deadline) = DEADLINE.lock() {
+        *deadline = Some(Instant::now() + Duration::from_secs(GRACE_SECONDS));
+    }
+    let cancelled = inference_queue::close();
+    info!(
+        "🛑 Shutting down, {} queued requests cancelled, running replies get {}s to finish",
+        cancelled, GRACE_SECONDS
+    );
+    true
+}
+
+/// Wait for the generations and maintenance jobs still running, false if some outlived `timeout`
+fn drain(timeout: Duration) -> bool {
+    let started = Instant::now();
+    loop {
+        let busy = inference_queue::running() > 0
+            || maintenance::generation_active()
+            || maintenance::status().state == "running";
+        if !busy {
+            return true;
+        }
+        if started.elapsed() >= timeout {
+            return false;
+        }
+        std::thread::sleep(Duration::from_millis(100));
+    }
+}
+
+/// Write back everything kept in memory and leave the database clean, once the server stopped
+///
+/// Active sessions are stored, so they are restored on the next start, the inference
+/// optimizer stats are saved, the instance lease is released and the WAL is checkpointed into
+/// the database file.
+pub fn finish(session_manager: &SessionManager) {
+    begin();
+    if !drain(Duration::from_secs(DRAIN_SECONDS)) {
+        warn!("⚠️ Background work still running after {}s, shutting down anyway", DRAIN_SECONDS);
+    }
+    match session_manager.flush() {
+        Ok(0) => {}
+        Ok(flushed) => info!("Stored {} active sessions", flushed),
+        Err(e) => warn!("⚠️ Could not store active sessions: {}", e),
+    }
+    if let Err(e) = INFERENCE_OPTIMIZER.save_stats() {
+        warn!("⚠️ Could not save inference optimizer stats: {}", e);
+    }
+    instance_lock::release();
+    // Last, so nothing written above is left in the log
+    if let Err(e) = checkpoint() {
+        warn!("⚠️ Could not checkpoint the database: {}", e);
+    }
+    info!("👋 Shut down cleanly");
+}
+
+/// Move everything in the write-ahead log into the database file and truncate the log
+fn checkpoint() -> rusqlite::Result<()> {
+    let con = db_pool::connection()?;
+    // busy is 1 when a reader or writer kept part of the log from being copied
+    let busy: i64 = con.query_row("PRAGMA wal_checkpoint(TRUNCATE)", [], |row| row.get(0))?;
+    if busy != 0 {
+        warn!("⚠️ The database was busy, part of the write-ahead log is left for the next start");
+    }
+    Ok(())
+}
+
+/// Resolves once SIGINT (Ctrl+C) or, on Unix, SIGTERM arrives
+pub async fn signal() {
+    #[cfg(unix)]
+    {
+        use actix_web::rt::signal::unix::{signal, SignalKind};
+        match signal(SignalKind::terminate()) {
+            Ok(mut terminate) => {
+                tokio::select! {
+                    _ = actix_web::rt::signal::ctrl_c() => {}
+                    _ = terminate.recv() => {}
+                }
+                return;
+            }
+            Err(e) => warn!("⚠️ Could not listen for SIGTERM: {}", e),
+        }
+    }
+    if let Err(e) = actix_web::rt::signal::ctrl_c().await {
+        warn!("⚠️ Could not listen for Ctrl+C: {}", e);
+        std::future::pending::<()>().await;
+    }
+}
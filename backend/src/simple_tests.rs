@@ -52,6 +52,9 @@ mod tests {
             ai: true,
             content: "Hello world".to_string(),
             created_at: "2024-01-15 10:00".to_string(),
+            author_id: None,
+            images: Vec::new(),
+            stats: None,
         };
 
         assert_eq!(message.id, 1);
@@ -64,6 +67,7 @@ mod tests {
         let new_message = NewMessage {
             ai: false,
             content: "User message".to_string(),
+            images: Vec::new(),
         };
 
         assert!(!new_message.ai);
@@ -0,0 +1,487 @@
+use crate::attitude_formatter::AttitudeFormatter;
+use crate::database::{
+    CompanionAttitude, Database, ThirdPartyIndividual, ThirdPartyRelationship, ThirdPartyRelationshipModify,
+};
+use crate::event_bus;
+use regex::Regex;
+use rusqlite::Result;
+use serde::Serialize;
+
+/// Someone in the companion's social circle
+#[derive(Serialize, Debug, Clone)]
+pub struct GraphNode {
+    /// "user", "companion:<id>" or "person:<id>"
+    pub id: String,
+    /// "user", "companion" or "person"
+    pub kind: &'static str,
+    pub name: String,
+    /// How much a person matters to the companion, 1 for the user and the companion
+    pub importance: f32,
+}
+
+/// A directed, weighted tie between two nodes
+#[derive(Serialize, Debug, Clone)]
+pub struct GraphEdge {
+    pub source: String,
+    pub target: String,
+    pub relationship_type: String,
+    /// Weight between 0 and 1
+    pub strength: f32,
+    pub description: Option<String>,
+    /// Id of the stored relationship, only set for edges between third parties
+    pub relationship_id: Option<i32>,
+    /// Short description of how the companion feels about the target, only set for edges from the companion
+    pub attitude: Option<String>,
+}
+
+#[derive(Serialize, Debug, Clone)]
+pub struct SocialGraph {
+    pub nodes: Vec<GraphNode>,
+    pub edges: Vec<GraphEdge>,
+}
+
+fn person_id(id: i32) -> String {
+    format!("person:{}", id)
+}
+
+const NAME: &str = r"([A-Z][a-z]+)";
+
+// Relations one person can have to another, the same person can be named several ways
+const RELATIONS: [(&str, &str); 34] = [
+    ("best friend", "best friend"),
+    ("friend", "friend"),
+    ("colleague", "colleague"),
+    ("coworker", "colleague"),
+    ("boss", "boss"),
+    ("manager", "manager"),
+    ("neighbor", "neighbor"),
+    ("roommate", "roommate"),
+    ("brother", "brother"),
+    ("sister", "sister"),
+    ("mother", "mother"),
+    ("father", "father"),
+    ("mom", "mother"),
+    ("dad", "father"),
+    ("parent", "parent"),
+    ("son", "son"),
+    ("daughter", "daughter"),
+    ("child", "child"),
+    ("cousin", "cousin"),
+    ("uncle", "uncle"),
+    ("aunt", "aunt"),
+    ("nephew", "nephew"),
+    ("niece", "niece"),
+    ("grandmother", "grandmother"),
+    ("grandfather", "grandfather"),
+    ("grandma", "grandmother"),
+    ("grandpa", "grandfather"),
+    ("boyfriend", "boyfriend"),
+    ("girlfriend", "girlfriend"),
+    ("fiance", "fiance"),
+    ("partner", "partner"),
+    ("spouse", "spouse"),
+    ("husband", "husband"),
+    ("wife", "wife"),
+];
+
+// Relations two people share, as in "Anna and Tom are siblings"
+const MUTUAL_RELATIONS: [(&str, &str); 12] = [
+    ("best friends", "best friend"),
+    ("friends", "friend"),
+    ("colleagues", "colleague"),
+    ("coworkers", "colleague"),
+    ("neighbors", "neighbor"),
+    ("roommates", "roommate"),
+    ("siblings", "sibling"),
+    ("cousins", "cousin"),
+    ("partners", "partner"),
+    ("married", "married"),
+    ("engaged", "engaged"),
+    ("dating", "dating"),
+];
+
+fn alternatives(relations: &[(&str, &str)]) -> String {
+    relations.iter().map(|(word, _)| *word).collect::<Vec<_>>().join("|")
+}
+
+fn relation(relations: &[(&str, &str)], word: &str) -> String {
+    relations
+        .iter()
+        .find(|(candidate, _)| *candidate == word)
+        .map_or(word, |(_, relation)| relation)
+        .to_string()
+}
+
+lazy_static::lazy_static! {
+    // "Anna is Tom's sister"
+    static ref IS_RELATION_OF: Regex = Regex::new(&format!(
+        r"\b{} is {}['’]s ({})\b", NAME, NAME, alternatives(&RELATIONS)
+    )).unwrap();
+    // "Tom's sister Anna", "Tom's sister is Anna"
+    static ref RELATION_OF_IS: Regex = Regex::new(&format!(
+        r"\b{}['’]s ({}),? (?:is )?{}\b", NAME, alternatives(&RELATIONS), NAME
+    )).unwrap();
+    // "Anna and Tom are siblings", "Anna and Tom are good friends"
+    static ref ARE_MUTUAL: Regex = Regex::new(&format!(
+        r"\b{} and {} are (?:(?:close|good|old) )?({})\b", NAME, NAME, alternatives(&MUTUAL_RELATIONS)
+    )).unwrap();
+    // "Anna is married to Tom", "Anna works with Tom"
+    static ref WITH_MUTUAL: Regex = Regex::new(&format!(
+        r"\b{} (is married to|is engaged to|is dating|works with) {}\b", NAME, NAME
+    )).unwrap();
+}
+
+/// A relationship between two persons a message states, by name
+#[derive(Debug, PartialEq)]
+pub struct StatedRelationship {
+    pub from: String,
+    pub to: String,
+    pub relationship_type: String,
+}
+
+impl StatedRelationship {
+    fn new(from: &str, to: &str, relationship_type: String) -> Self {
+        StatedRelationship {
+            from: from.to_string(),
+            to: to.to_string(),
+            relationship_type,
+        }
+    }
+}
+
+/// Relationships between people a message states, shared ones in both directions
+pub fn stated_relationships(message: &str) -> Vec<StatedRelationship> {
+    let mut stated = Vec::new();
+    for caps in IS_RELATION_OF.captures_iter(message) {
+        stated.push(StatedRelationship::new(&caps[1], &caps[2], relation(&RELATIONS, &caps[3])));
+    }
+    for caps in RELATION_OF_IS.captures_iter(message) {
+        stated.push(StatedRelationship::new(&caps[3], &caps[1], relation(&RELATIONS, &caps[2])));
+    }
+    let mutual = ARE_MUTUAL
+        .captures_iter(message)
+        .map(|caps| (caps[1].to_string(), caps[2].to_string(), relation(&MUTUAL_RELATIONS, &caps[3])))
+        .chain(WITH_MUTUAL.captures_iter(message).map(|caps| {
+            let relationship_type = match &caps[2] {
+                "is married to" => "married",
+                "is engaged to" => "engaged",
+                "is dating" => "dating",
+                _ => "colleague",
+            };
+            (caps[1].to_string(), caps[3].to_string(), relationship_type.to_string())
+        }));
+    for (first, second, relationship_type) in mutual {
+        stated.push(StatedRelationship::new(&first, &second, relationship_type.clone()));
+        stated.push(StatedRelationship::new(&second, &first, relationship_type));
+    }
+    stated.retain(|relationship| relationship.from != relationship.to);
+    stated
+}
+
+/// relationship_score runs from -100 to 100, edges are weighted from 0 to 1
+fn attitude_strength(attitude: &CompanionAttitude) -> f32 {
+    ((attitude.relationship_score.unwrap_or(0.0) + 100.0) / 200.0).clamp(0.0, 1.0)
+}
+
+impl SocialGraph {
+    /// Graph of the active user, the active companion and the third parties it knows about
+    pub fn build() -> Result<SocialGraph> {
+        let companion_id = Database::active_companion_id();
+        let user_id = Database::active_user_id();
+        let user = Database::get_user_data()?;
+        let companion = Database::get_companion_data_by_id(companion_id)?;
+        let persons = Database::get_all_third_party_individuals()?;
+        let relationships = Database::get_third_party_relationships(companion_id)?;
+        let attitudes = Database::get_all_companion_attitudes(companion_id)?;
+        Ok(SocialGraph::assemble(
+            user_id,
+            &user.name,
+            companion_id,
+            &companion.name,
+            &persons,
+            &relationships,
+            &attitudes,
+        ))
+    }
+
+    /// Store the relationships between known persons a message states, returns the ids of new ones
+    ///
+    /// Names the companion doesn't know are skipped, as are pairs that already have a relationship
+    /// in that direction, so edits made through the API stick.
+    pub fn learn_relationships(message: &str, companion_id: i32) -> Result<Vec<i32>> {
+        let stated = stated_relationships(message);
+        if stated.is_empty() {
+            return Ok(Vec::new());
+        }
+        let mut known = Database::get_third_party_relationships(companion_id)?;
+        let mut learned = Vec::new();
+        for relationship in stated {
+            let (Some(from), Some(to)) = (
+                Database::get_third_party_by_name(&relationship.from)?.and_then(|p| p.id),
+                Database::get_third_party_by_name(&relationship.to)?.and_then(|p| p.id),
+            ) else {
+                continue;
+            };
+            if known.iter().any(|r| r.from_party_id == from && r.to_party_id == to) {
+                continue;
+            }
+            let mut modify = ThirdPartyRelationshipModify {
+                from_party_id: from,
+                to_party_id: to,
+                relationship_type: relationship.relationship_type,
+                strength: 0.5,
+                description: None,
+            };
+            if modify.validate().is_err() {
+                continue;
+            }
+            let id = Database::add_third_party_relationship(&modify)?;
+            event_bus::publish(
+                "relationship_detected",
+                serde_json::json!({
+                    "companion_id": companion_id,
+                    "relationship_id": id,
+                    "from_party_id": from,
+                    "to_party_id": to,
+                    "relationship_type": modify.relationship_type,
+                }),
+            );
+            if let Some(stored) = Database::get_third_party_relationship(id)? {
+                known.push(stored);
+            }
+            learned.push(id);
+        }
+        Ok(learned)
+    }
+
+    fn assemble(
+        user_id: i32,
+        user_name: &str,
+        companion_id: i32,
+        companion_name: &str,
+        persons: &[ThirdPartyIndividual],
+        relationships: &[ThirdPartyRelationship],
+        attitudes: &[CompanionAttitude],
+    ) -> SocialGraph {
+        let formatter = AttitudeFormatter::new();
+        let companion_node = format!("companion:{}", companion_id);
+        let mut nodes = vec![
+            GraphNode {
+                id: "user".to_string(),
+                kind: "user",
+                name: user_name.to_string(),
+                importance: 1.0,
+            },
+            GraphNode {
+                id: companion_node.clone(),
+                kind: "companion",
+                name: companion_name.to_string(),
+                importance: 1.0,
+            },
+        ];
+        let mut edges = Vec::new();
+
+        let attitude_toward = |target_type: &str, target_id: i32| {
+            attitudes
+                .iter()
+                .find(|a| a.target_type == target_type && a.target_id == target_id)
+        };
+
+        if let Some(attitude) = attitude_toward("user", user_id) {
+            edges.push(GraphEdge {
+                source: companion_node.clone(),
+                target: "user".to_string(),
+                relationship_type: formatter.relationship_level_name(attitude),
+                strength: attitude_strength(attitude),
+                description: None,
+                relationship_id: None,
+                attitude: Some(formatter.format_attitude_summary(attitude)),
+            });
+        }
+
+        for person in persons {
+            let Some(id) = person.id else { continue };
+            nodes.push(GraphNode {
+                id: person_id(id),
+                kind: "person",
+                name: person.name.clone(),
+                importance: person.importance_score,
+            });
+
+            if let Some(relationship) = &person.relationship_to_user {
+                edges.push(GraphEdge {
+                    source: "user".to_string(),
+                    target: person_id(id),
+                    relationship_type: relationship.clone(),
+                    strength: person.importance_score,
+                    description: None,
+                    relationship_id: None,
+                    attitude: None,
+                });
+            }
+
+            // The companion is tied to everyone it knows, the stored relationship names the tie
+            // when there is one, otherwise its attitude does
+            let attitude = attitude_toward("third_party", id);
+            let relationship_type = match (&person.relationship_to_companion, attitude) {
+                (Some(relationship), _) => relationship.clone(),
+                (None, Some(attitude)) => formatter.relationship_level_name(attitude),
+                (None, None) => continue,
+            };
+            edges.push(GraphEdge {
+                source: companion_node.clone(),
+                target: person_id(id),
+                relationship_type,
+                strength: attitude.map_or(person.importance_score, attitude_strength),
+                description: None,
+                relationship_id: None,
+                attitude: attitude.map(|a| formatter.format_attitude_summary(a)),
+            });
+        }
+
+        for relationship in relationships {
+            edges.push(GraphEdge {
+                source: person_id(relationship.from_party_id),
+                target: person_id(relationship.to_party_id),
+                relationship_type: relationship.relationship_type.clone(),
+                strength: relationship.strength,
+                description: relationship.description.clone(),
+                relationship_id: relationship.id,
+                attitude: None,
+            });
+        }
+
+        SocialGraph { nodes, edges }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn person(id: i32, name: &str, to_user: Option<&str>, to_companion: Option<&str>) -> ThirdPartyIndividual {
+        ThirdPartyIndividual {
+            id: Some(id),
+            name: name.to_string(),
+            relationship_to_user: to_user.map(str::to_string),
+            relationship_to_companion: to_companion.map(str::to_string),
+            occupation: None,
+            personality_traits: None,
+            physical_description: None,
+            first_mentioned: String::new(),
+            last_mentioned: None,
+            mention_count: 1,
+            importance_score: 0.4,
+            created_at: String::new(),
+            updated_at: String::new(),
+        }
+    }
+
+    fn attitude(target_id: i32, target_type: &str, relationship_score: f32) -> CompanionAttitude {
+        CompanionAttitude {
+            id: None,
+            companion_id: 2,
+            target_id,
+            target_type: target_type.to_string(),
+            attraction: 0.0,
+            trust: 0.0,
+            fear: 0.0,
+            anger: 0.0,
+            joy: 0.0,
+            sorrow: 0.0,
+            disgust: 0.0,
+            surprise: 0.0,
+            curiosity: 0.0,
+            respect: 0.0,
+            suspicion: 0.0,
+            gratitude: 0.0,
+            jealousy: 0.0,
+            empathy: 0.0,
+            lust: 0.0,
+            love: 0.0,
+            anxiety: 0.0,
+            butterflies: 0.0,
+            submissiveness: 0.0,
+            dominance: 0.0,
+            relationship_score: Some(relationship_score),
+            last_updated: String::new(),
+            created_at: String::new(),
+        }
+    }
+
+    #[test]
+    fn test_assemble_graph() {
+        let persons = vec![
+            person(7, "Anna", Some("sister"), None),
+            person(8, "Tom", None, Some("coworker")),
+            person(9, "Stranger", None, None),
+        ];
+        let relationships = vec![ThirdPartyRelationship {
+            id: Some(3),
+            from_party_id: 7,
+            to_party_id: 8,
+            relationship_type: "married".to_string(),
+            strength: 0.9,
+            description: None,
+            created_at: String::new(),
+            updated_at: String::new(),
+        }];
+        let attitudes = vec![attitude(1, "user", 60.0), attitude(7, "third_party", -100.0)];
+        let graph = SocialGraph::assemble(1, "Sam", 2, "Ava", &persons, &relationships, &attitudes);
+
+        assert_eq!(graph.nodes.len(), 5);
+        assert_eq!(graph.nodes[1].id, "companion:2");
+
+        let edge = |source: &str, target: &str| {
+            graph.edges.iter().find(|e| e.source == source && e.target == target)
+        };
+        let to_user = edge("companion:2", "user").unwrap();
+        assert_eq!(to_user.relationship_type, "close");
+        assert!((to_user.strength - 0.8).abs() < 1e-6);
+        assert!(to_user.attitude.is_some());
+
+        assert_eq!(edge("user", "person:7").unwrap().relationship_type, "sister");
+        let to_anna = edge("companion:2", "person:7").unwrap();
+        assert_eq!(to_anna.relationship_type, "antagonistic");
+        assert_eq!(to_anna.strength, 0.0);
+        let to_tom = edge("companion:2", "person:8").unwrap();
+        assert_eq!(to_tom.relationship_type, "coworker");
+        assert!(to_tom.attitude.is_none());
+        // Nobody has said how anyone relates to the stranger
+        assert!(graph.edges.iter().all(|e| e.target != "person:9"));
+
+        let married = edge("person:7", "person:8").unwrap();
+        assert_eq!(married.relationship_id, Some(3));
+        assert_eq!(married.strength, 0.9);
+    }
+
+    #[test]
+    fn test_stated_relationships() {
+        let stated = |message: &str| -> Vec<(String, String, String)> {
+            stated_relationships(message)
+                .into_iter()
+                .map(|r| (r.from, r.to, r.relationship_type))
+                .collect()
+        };
+        let owned = |from: &str, to: &str, relationship_type: &str| {
+            (from.to_string(), to.to_string(), relationship_type.to_string())
+        };
+
+        assert_eq!(stated("Alice is Bob's sister."), vec![owned("Alice", "Bob", "sister")]);
+        assert_eq!(stated("I met Bob's mom Carol today"), vec![owned("Carol", "Bob", "mother")]);
+        assert_eq!(stated("Bob's best friend is Dan"), vec![owned("Dan", "Bob", "best friend")]);
+        assert_eq!(
+            stated("Anna and Tom are good friends"),
+            vec![owned("Anna", "Tom", "friend"), owned("Tom", "Anna", "friend")]
+        );
+        assert_eq!(
+            stated("Anna is married to Tom"),
+            vec![owned("Anna", "Tom", "married"), owned("Tom", "Anna", "married")]
+        );
+        assert_eq!(stated("Tom works with Eve"), vec![owned("Tom", "Eve", "colleague"), owned("Eve", "Tom", "colleague")]);
+        // Only words that name a relation count
+        assert!(stated("Alice is Bob's favorite singer").is_empty());
+        assert!(stated("Anna and Tom are late").is_empty());
+        assert!(stated("Anna is Anna's friend").is_empty());
+    }
+}
@@ -0,0 +1,132 @@
+use crate::database::ConfigView;
+use std::time::Duration;
+
+// Same cap as the OpenAI transcription API, a few minutes of speech
+pub const MAX_AUDIO_BYTES: usize = 25 * 1024 * 1024;
+
+/// Audio container accepted by /api/stt
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AudioFormat {
+    Wav,
+    Ogg,
+    Webm,
+}
+
+impl AudioFormat {
+    pub fn mime(&self) -> &'static str {
+        match self {
+            AudioFormat::Wav => "audio/wav",
+            AudioFormat::Ogg => "audio/ogg",
+            AudioFormat::Webm => "audio/webm",
+        }
+    }
+
+    pub fn extension(&self) -> &'static str {
+        match self {
+            AudioFormat::Wav => "wav",
+            AudioFormat::Ogg => "ogg",
+            AudioFormat::Webm => "webm",
+        }
+    }
+
+    /// Format named by a Content-Type, codec parameters such as `;codecs=opus` are ignored
+    pub fn from_content_type(content_type: &str) -> Option<AudioFormat> {
+        let mime = content_type.split(';').next().unwrap_or("").trim().to_lowercase();
+        match mime.as_str() {
+            "audio/wav" | "audio/x-wav" | "audio/wave" | "audio/vnd.wave" => Some(AudioFormat::Wav),
+            "audio/ogg" | "application/ogg" => Some(AudioFormat::Ogg),
+            "audio/webm" | "video/webm" => Some(AudioFormat::Webm),
+            _ => None,
+        }
+    }
+
+    /// Format recognized from the first bytes of the file
+    pub fn sniff(audio: &[u8]) -> Option<AudioFormat> {
+        if audio.len() >= 12 && &audio[0..4] == b"RIFF" && &audio[8..12] == b"WAVE" {
+            Some(AudioFormat::Wav)
+        } else if audio.starts_with(b"OggS") {
+            Some(AudioFormat::Ogg)
+        } else if audio.starts_with(&[0x1A, 0x45, 0xDF, 0xA3]) {
+            Some(AudioFormat::Webm)
+        } else {
+            None
+        }
+    }
+
+    /// The file's own bytes decide, browsers often send recordings as application/octet-stream
+    pub fn detect(audio: &[u8], content_type: Option<&str>) -> Option<AudioFormat> {
+        AudioFormat::sniff(audio).or_else(|| content_type.and_then(AudioFormat::from_content_type))
+    }
+}
+
+pub fn is_configured(config: &ConfigView) -> bool {
+    !config.stt_api_url.trim().is_empty()
+}
+
+/// Transcribe speech with the service set in stt_api_url
+///
+/// Works with OpenAI compatible /v1/audio/transcriptions endpoints and the /inference endpoint
+/// of the whisper.cpp server, both take a multipart `file` and answer with `{"text": ...}`.
+pub fn transcribe(config: &ConfigView, audio: Vec<u8>, format: AudioFormat) -> Result<String, String> {
+    let url = config.stt_api_url.trim();
+    if url.is_empty() {
+        return Err("speech to text is not configured, set stt_api_url in the config".to_string());
+    }
+    let file = reqwest::blocking::multipart::Part::bytes(audio)
+        .file_name(format!("speech.{}", format.extension()))
+        .mime_str(format.mime())
+        .map_err(|e| e.to_string())?;
+    let mut form = reqwest::blocking::multipart::Form::new()
+        .part("file", file)
+        .text("response_format", "json");
+    let model = config.stt_model.trim();
+    if !model.is_empty() {
+        form = form.text("model", model.to_string());
+    }
+
+    // Transcription runs at about real time on a CPU, longer recordings need the time
+    let client = reqwest::blocking::Client::builder()
+        .timeout(Duration::from_secs(300))
+        .build()
+        .map_err(|e| e.to_string())?;
+    let response: serde_json::Value = client
+        .post(url)
+        .multipart(form)
+        .send()
+        .and_then(|response| response.error_for_status())
+        .and_then(|response| response.json())
+        .map_err(|e| format!("transcription request failed: {}", e))?;
+    let text = response["text"]
+        .as_str()
+        .ok_or("transcription response has no text")?;
+    Ok(text.trim().to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_detect_audio_format() {
+        let mut wav = b"RIFF".to_vec();
+        wav.extend_from_slice(&[0x24, 0, 0, 0]);
+        wav.extend_from_slice(b"WAVEfmt ");
+        assert_eq!(AudioFormat::sniff(&wav), Some(AudioFormat::Wav));
+        assert_eq!(AudioFormat::sniff(b"OggS\0\x02"), Some(AudioFormat::Ogg));
+        assert_eq!(AudioFormat::sniff(&[0x1A, 0x45, 0xDF, 0xA3, 0x9F]), Some(AudioFormat::Webm));
+        assert_eq!(AudioFormat::sniff(b"ID3\x04"), None);
+
+        assert_eq!(
+            AudioFormat::from_content_type("audio/webm;codecs=opus"),
+            Some(AudioFormat::Webm)
+        );
+        assert_eq!(AudioFormat::from_content_type("audio/mpeg"), None);
+        // Bytes win over a generic or wrong content type
+        assert_eq!(
+            AudioFormat::detect(b"OggS\0\x02", Some("application/octet-stream")),
+            Some(AudioFormat::Ogg)
+        );
+        assert_eq!(AudioFormat::detect(b"????", Some("audio/x-wav")), Some(AudioFormat::Wav));
+        assert_eq!(AudioFormat::detect(b"????", None), None);
+    }
+}
@@ -0,0 +1,251 @@
+use crate::attitude_formatter::AttitudeFormatter;
+use crate::database::{CompanionView, Database, UserView};
+use crate::db_pool;
+use crate::mood::Mood;
+use crate::naming::fill_placeholders;
+use crate::personality_traits::PersonalityTraits;
+use chrono::{DateTime, Local};
+use rusqlite::{params, Connection, Error, Params, Result};
+use serde::{Deserialize, Serialize};
+use tracing::warn;
+
+pub const MAX_NAME_CHARS: usize = 32;
+pub const MAX_VALUE_CHARS: usize = 2000;
+/// Filled in from the companion's state, they can't be defined
+pub const BUILT_IN: [&str; 4] = ["time", "date", "mood", "relationship_level"];
+// Taken by fill_placeholders, along with everything starting with char_ or user_
+const NAME_PLACEHOLDERS: [&str; 3] = ["char", "user", "companion"];
+// Variables of all companions are stored with this companion id
+const ALL_COMPANIONS: i32 = 0;
+
+/// Value set by the user for `{{name}}`, for one companion or for all of them
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct TemplateVariable {
+    pub name: String,
+    pub value: String,
+    /// None for a variable of all companions
+    pub companion_id: Option<i32>,
+}
+
+fn validate(name: &str, value: &str) -> Result<(), String> {
+    if name.is_empty()
+        || name.chars().count() > MAX_NAME_CHARS
+        || !name
+            .chars()
+            .all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '_')
+    {
+        return Err(format!(
+            "Invalid variable name {:?}, expected up to {} lowercase letters, digits and underscores",
+            name, MAX_NAME_CHARS
+        ));
+    }
+    if BUILT_IN.contains(&name)
+        || NAME_PLACEHOLDERS.contains(&name)
+        || name.starts_with("char_")
+        || name.starts_with("user_")
+    {
+        return Err(format!("{{{{{}}}}} is built in and can't be set", name));
+    }
+    if value.chars().count() > MAX_VALUE_CHARS {
+        return Err(format!(
+            "Variable values are limited to {} characters",
+            MAX_VALUE_CHARS
+        ));
+    }
+    Ok(())
+}
+
+/// Values of the built-in variables
+fn built_in(now: DateTime<Local>, mood: &str, relationship_level: &str) -> Vec<(String, String)> {
+    vec![
+        ("time".to_string(), now.format("%H:%M").to_string()),
+        ("date".to_string(), now.format("%A, %d %B %Y").to_string()),
+        ("mood".to_string(), mood.to_string()),
+        (
+            "relationship_level".to_string(),
+            relationship_level.to_string(),
+        ),
+    ]
+}
+
+/// Every variable a text written for one companion and user can use, with its value right now
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Variables {
+    values: Vec<(String, String)>,
+}
+
+impl Variables {
+    /// Variables of `companion_id` talking to `user_id`, a value that can't be looked up is left
+    /// out and logged, so its placeholder stays as written
+    pub fn load(companion_id: i32, user_id: i32) -> Variables {
+        let mood = match Mood::get(companion_id) {
+            Ok(mood) => mood.mood.name().to_string(),
+            Err(e) => {
+                warn!("⚠️ Could not load the mood for {{{{mood}}}}: {}", e);
+                String::new()
+            }
+        };
+        let attitude = Database::get_attitude(companion_id, user_id, "user").map(|attitude| {
+            // A companion that hasn't talked to the user yet starts from its baselines
+            attitude.unwrap_or_else(|| {
+                let traits = PersonalityTraits::get(companion_id).unwrap_or_default();
+                Database::initial_user_attitude(companion_id, user_id, &traits)
+            })
+        });
+        let relationship_level = match attitude {
+            Ok(attitude) => AttitudeFormatter::new().relationship_level_name(&attitude),
+            Err(e) => {
+                warn!(
+                    "⚠️ Could not load the attitude for {{{{relationship_level}}}}: {}",
+                    e
+                );
+                String::new()
+            }
+        };
+        let mut values = built_in(Local::now(), &mood, &relationship_level);
+        values.retain(|(_, value)| !value.is_empty());
+        match TemplateVariables::for_companion(companion_id) {
+            Ok(variables) => values.extend(
+                variables
+                    .into_iter()
+                    .map(|variable| (variable.name, variable.value)),
+            ),
+            Err(e) => warn!("⚠️ Could not load template variables: {}", e),
+        }
+        Variables { values }
+    }
+
+    pub fn get(&self, name: &str) -> Option<&str> {
+        self.values
+            .iter()
+            .find(|(variable, _)| variable == name)
+            .map(|(_, value)| value.as_str())
+    }
+
+    /// Replace `{{name}}` of every variable, other placeholders are kept
+    pub fn fill(&self, text: &str) -> String {
+        if !text.contains("{{") {
+            return text.to_string();
+        }
+        let mut text = text.to_string();
+        for (name, value) in &self.values {
+            text = text.replace(&format!("{{{{{}}}}}", name), value);
+        }
+        text
+    }
+
+    /// Variables and then the name and pronoun placeholders, so values can use {{char}} too
+    pub fn expand(&self, text: &str, companion: &CompanionView, user: &UserView) -> String {
+        fill_placeholders(&self.fill(text), companion, user)
+    }
+}
+
+pub struct TemplateVariables {}
+
+impl TemplateVariables {
+    pub fn create() -> Result<()> {
+        let con = db_pool::connection()?;
+        con.execute(
+            "CREATE TABLE IF NOT EXISTS template_variables (
+                companion_id INTEGER NOT NULL DEFAULT 0,
+                name TEXT NOT NULL,
+                value TEXT NOT NULL,
+                PRIMARY KEY (companion_id, name)
+            )",
+            [],
+        )?;
+        Ok(())
+    }
+
+    fn read<P: Params>(con: &Connection, sql: &str, params: P) -> Result<Vec<TemplateVariable>> {
+        let mut stmt = con.prepare(sql)?;
+        let rows = stmt.query_map(params, |row| {
+            let companion_id: i32 = row.get(2)?;
+            Ok(TemplateVariable {
+                name: row.get(0)?,
+                value: row.get(1)?,
+                companion_id: (companion_id != ALL_COMPANIONS).then_some(companion_id),
+            })
+        })?;
+        rows.collect()
+    }
+
+    /// Every stored variable, those of all companions first
+    pub fn list() -> Result<Vec<TemplateVariable>> {
+        let con = db_pool::connection()?;
+        TemplateVariables::read(
+            &con,
+            "SELECT name, value, companion_id FROM template_variables ORDER BY companion_id, name",
+            [],
+        )
+    }
+
+    /// Variables a companion's texts use, its own replacing those of all companions
+    pub fn for_companion(companion_id: i32) -> Result<Vec<TemplateVariable>> {
+        let con = db_pool::connection()?;
+        TemplateVariables::read(
+            &con,
+            "SELECT name, value, companion_id FROM template_variables AS variable
+             WHERE companion_id = ?1 OR (companion_id = 0 AND NOT EXISTS (
+                SELECT 1 FROM template_variables WHERE companion_id = ?1 AND name = variable.name))
+             ORDER BY name",
+            [companion_id],
+        )
+    }
+
+    /// Fails with InvalidParameterName for a name that is taken or not allowed
+    pub fn set(variable: &TemplateVariable) -> Result<()> {
+        validate(&variable.name, &variable.value).map_err(Error::InvalidParameterName)?;
+        let con = db_pool::connection()?;
+        con.execute(
+            "INSERT INTO template_variables (companion_id, name, value) VALUES (?, ?, ?)
+             ON CONFLICT(companion_id, name) DO UPDATE SET value = excluded.value",
+            params![
+                variable.companion_id.unwrap_or(ALL_COMPANIONS),
+                variable.name,
+                variable.value
+            ],
+        )?;
+        Ok(())
+    }
+
+    /// Returns false if there was no such variable
+    pub fn remove(companion_id: Option<i32>, name: &str) -> Result<bool> {
+        let con = db_pool::connection()?;
+        let removed = con.execute(
+            "DELETE FROM template_variables WHERE companion_id = ? AND name = ?",
+            params![companion_id.unwrap_or(ALL_COMPANIONS), name],
+        )?;
+        Ok(removed > 0)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeZone;
+
+    #[test]
+    fn test_template_variables() {
+        assert!(validate("home_town", "Lisbon").is_ok());
+        assert!(validate("Home", "").is_err());
+        assert!(validate("mood", "").is_err());
+        assert!(validate("char_job", "").is_err());
+        assert!(validate("user", "").is_err());
+
+        let now = Local.with_ymd_and_hms(2026, 10, 16, 18, 5, 0).unwrap();
+        let mut values = built_in(now, "cheerful", "friendly");
+        values.push(("home_town".to_string(), "{{char}}'s Lisbon".to_string()));
+        let variables = Variables { values };
+        assert_eq!(
+            variables.fill(
+                "It is {{time}} on {{date}}, {{char}} feels {{mood}} ({{relationship_level}})."
+            ),
+            "It is 18:05 on Friday, 16 October 2026, {{char}} feels cheerful (friendly)."
+        );
+        assert_eq!(
+            variables.fill("{{home_town}} {{unknown}}"),
+            "{{char}}'s Lisbon {{unknown}}"
+        );
+    }
+}
@@ -1,6 +1,8 @@
 use crate::database::{CompanionAttitude, Message, ThirdPartyIndividual};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Serialize)]
 pub struct TokenBudget {
     pub total: usize,
     pub system_prompt: usize,
@@ -9,9 +11,11 @@ pub struct TokenBudget {
     pub recent_messages: usize,
     pub response_buffer: usize,
     pub vram_tier: VramTier,
+    /// Tokens sections of the prompt layout may take at most, on top of the shares above
+    pub section_caps: BTreeMap<PromptSection, usize>,
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Serialize)]
 pub enum VramTier {
     Minimal,  // 0-2GB VRAM
     Standard, // 3-4GB VRAM
@@ -46,9 +50,26 @@ impl TokenBudget {
             recent_messages,
             response_buffer,
             vram_tier: tier,
+            section_caps: BTreeMap::new(),
         }
     }
 
+    /// Hold the sections to `caps`, the shares of the history, attitude and third-party
+    /// information shrink to their caps
+    pub fn with_caps(mut self, caps: BTreeMap<PromptSection, usize>) -> Self {
+        let capped = |share: usize, section| caps.get(&section).map_or(share, |cap| share.min(*cap));
+        self.recent_messages = capped(self.recent_messages, PromptSection::History);
+        self.attitude_data = capped(self.attitude_data, PromptSection::Attitude);
+        self.third_party_info = capped(self.third_party_info, PromptSection::ThirdParty);
+        self.section_caps = caps;
+        self
+    }
+
+    /// Tokens `section` may take at most, None when only the shares above hold it back
+    pub fn cap(&self, section: PromptSection) -> Option<usize> {
+        self.section_caps.get(&section).copied()
+    }
+
     pub fn get_allocation_summary(&self) -> String {
         format!(
             "Token Budget ({}): System: {}, Attitude: {}, Third-party: {}, Messages: {}, Response: {}",
@@ -62,6 +83,55 @@ impl TokenBudget {
     }
 }
 
+/// Part of the prompt that can be moved, capped or switched off in the prompt layout
+///
+/// All but the persona can be left out when the prompt doesn't fit the context window.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum PromptSection {
+    /// The user's and the companion's personas
+    Persona,
+    /// Attitudes toward people other than the user
+    ThirdParty,
+    ExampleDialogue,
+    Lore,
+    /// Recalled long-term memory entries
+    Memories,
+    /// Attitude toward the user and the mood
+    Attitude,
+    /// Older messages of the conversation, the newest one always stays
+    History,
+}
+
+impl PromptSection {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            PromptSection::Persona => "persona",
+            PromptSection::ThirdParty => "third_party",
+            PromptSection::ExampleDialogue => "example_dialogue",
+            PromptSection::Lore => "lore",
+            PromptSection::Memories => "memories",
+            PromptSection::Attitude => "attitude",
+            PromptSection::History => "history",
+        }
+    }
+}
+
+impl TokenBudget {
+    /// Sections in the order they are dropped from a prompt that overflows, least important first
+    ///
+    /// Follows the shares above: third-party information has the smallest, the recent messages
+    /// the largest and the persona and instructions of the system prompt are never dropped.
+    pub const DROP_ORDER: [PromptSection; 6] = [
+        PromptSection::ThirdParty,
+        PromptSection::ExampleDialogue,
+        PromptSection::Lore,
+        PromptSection::Memories,
+        PromptSection::Attitude,
+        PromptSection::History,
+    ];
+}
+
 #[derive(Debug)]
 pub struct TokenUsageMonitor {
     pub budget: TokenBudget,
@@ -495,67 +565,36 @@ pub struct TokenUsageStatistics {
 }
 
 impl TokenUsageStatistics {
-    pub fn print_detailed_stats(&self) {
-        println!("🧠 Comprehensive Token Budget Analysis:");
-        println!("   VRAM Tier: {:?}", self.budget.vram_tier);
-        println!("   Total Budget: {} tokens", self.budget.total);
-        println!();
-        println!("📊 Current Usage:");
-        println!(
-            "   System Prompt: {}/{} tokens",
-            self.current_usage.system_tokens, self.budget.system_prompt
-        );
-        println!(
-            "   Attitudes: {}/{} tokens",
-            self.current_usage.attitude_tokens, self.budget.attitude_data
-        );
-        println!(
-            "   Third-party Info: {}/{} tokens",
-            self.current_usage.third_party_tokens, self.budget.third_party_info
-        );
-        println!(
-            "   Messages: {}/{} tokens",
-            self.current_usage.message_tokens, self.budget.recent_messages
-        );
-        println!(
-            "   Response Buffer: {} tokens available",
-            self.remaining_response_tokens
-        );
-        println!(
-            "   Total Context: {} tokens",
-            self.current_usage.total_context_tokens
+    pub fn log_detailed_stats(&self) {
+        tracing::debug!(
+            vram_tier = ?self.budget.vram_tier,
+            system_tokens = self.current_usage.system_tokens,
+            system_budget = self.budget.system_prompt,
+            attitude_tokens = self.current_usage.attitude_tokens,
+            attitude_budget = self.budget.attitude_data,
+            third_party_tokens = self.current_usage.third_party_tokens,
+            third_party_budget = self.budget.third_party_info,
+            message_tokens = self.current_usage.message_tokens,
+            message_budget = self.budget.recent_messages,
+            response_tokens = self.remaining_response_tokens,
+            "🧠 Token budget: {}/{} tokens ({}%)",
+            self.current_usage.total_context_tokens,
+            self.budget.total,
+            self.utilization_percentage
         );
-        println!("   Utilization: {}%", self.utilization_percentage);
 
         if self.overflow_risk {
-            println!("⚠️  WARNING: High context utilization - approaching overflow");
+            tracing::warn!("⚠️ High context utilization - approaching overflow");
         }
 
-        println!();
-        println!("🔧 Optimization Results:");
-        println!(
-            "   Messages compressed: {}",
-            self.optimization_stats.messages_compressed
+        tracing::debug!(
+            messages_compressed = self.optimization_stats.messages_compressed,
+            attitudes_filtered = self.optimization_stats.attitudes_filtered,
+            third_parties_filtered = self.optimization_stats.third_parties_filtered,
+            overflow_events = self.optimization_stats.overflow_events,
+            tokens_saved = self.optimization_stats.total_savings,
+            "🔧 Token budget optimization"
         );
-        println!(
-            "   Attitudes filtered: {}",
-            self.optimization_stats.attitudes_filtered
-        );
-        println!(
-            "   Third-parties filtered: {}",
-            self.optimization_stats.third_parties_filtered
-        );
-        println!(
-            "   Overflow events: {}",
-            self.optimization_stats.overflow_events
-        );
-
-        if self.optimization_stats.total_savings > 0 {
-            println!(
-                "   Total tokens saved: {}",
-                self.optimization_stats.total_savings
-            );
-        }
     }
 }
 
@@ -841,6 +880,9 @@ mod tests {
             ai,
             content: content.to_string(),
             created_at: get_current_date(),
+            author_id: None,
+            images: Vec::new(),
+            stats: None,
         }
     }
 
@@ -0,0 +1,191 @@
+use crate::database::ConfigView;
+use crate::db_pool;
+use crate::stt::AudioFormat;
+use rusqlite::{params, OptionalExtension, Result};
+use std::time::Duration;
+use tracing::warn;
+
+// Synthesized replies kept around, older ones are synthesized again when asked for
+const MAX_CACHED: usize = 200;
+
+pub fn is_configured(config: &ConfigView) -> bool {
+    !config.tts_api_url.trim().is_empty()
+}
+
+/// What gets read out of a message, *actions* and other narration between asterisks are skipped
+pub fn spoken_text(message: &str) -> String {
+    let speech: String = message
+        .split('*')
+        .enumerate()
+        .filter(|(index, _)| index % 2 == 0)
+        .map(|(_, part)| part)
+        .collect::<Vec<_>>()
+        .join(" ");
+    speech.split_whitespace().collect::<Vec<_>>().join(" ")
+}
+
+/// Read `text` out with the service set in tts_api_url, in the voice set in tts_voice
+///
+/// URLs ending in /audio/speech are OpenAI compatible speech endpoints and get
+/// `{"model", "input", "voice", "response_format": "wav"}`, anything else is taken for a piper
+/// HTTP server and gets `{"text", "voice"}`. Both answer with the audio file itself.
+pub fn synthesize(config: &ConfigView, text: &str) -> Result<(Vec<u8>, AudioFormat), String> {
+    let url = config.tts_api_url.trim();
+    if url.is_empty() {
+        return Err("text to speech is not configured, set tts_api_url in the config".to_string());
+    }
+    let body = request_body(url, config.tts_model.trim(), config.tts_voice.trim(), text);
+
+    let client = reqwest::blocking::Client::builder()
+        .timeout(Duration::from_secs(120))
+        .build()
+        .map_err(|e| e.to_string())?;
+    let response = client
+        .post(url)
+        .json(&body)
+        .send()
+        .and_then(|response| response.error_for_status())
+        .map_err(|e| format!("speech request failed: {}", e))?;
+    let content_type = response
+        .headers()
+        .get(reqwest::header::CONTENT_TYPE)
+        .and_then(|value| value.to_str().ok())
+        .map(str::to_string);
+    let audio = response
+        .bytes()
+        .map_err(|e| format!("speech request failed: {}", e))?
+        .to_vec();
+    let format = AudioFormat::detect(&audio, content_type.as_deref())
+        .ok_or("speech server answered with an unsupported audio format, expected wav, ogg or webm")?;
+    Ok((audio, format))
+}
+
+fn request_body(url: &str, model: &str, voice: &str, text: &str) -> serde_json::Value {
+    let mut body = if url.trim_end_matches('/').ends_with("/audio/speech") {
+        let mut body = serde_json::json!({ "input": text, "response_format": "wav" });
+        if !model.is_empty() {
+            body["model"] = model.into();
+        }
+        body
+    } else {
+        serde_json::json!({ "text": text })
+    };
+    if !voice.is_empty() {
+        body["voice"] = voice.into();
+    }
+    body
+}
+
+/// Replies that were read out already, a message is synthesized again once its text or the
+/// speech settings change
+pub struct MessageAudio {}
+
+impl MessageAudio {
+    pub fn create() -> Result<()> {
+        let con = db_pool::connection()?;
+        con.execute_batch(
+            "CREATE TABLE IF NOT EXISTS message_audio (
+                message_id INTEGER PRIMARY KEY,
+                speaker TEXT NOT NULL,
+                text TEXT NOT NULL,
+                mime TEXT NOT NULL,
+                audio BLOB NOT NULL,
+                created_at DATETIME DEFAULT CURRENT_TIMESTAMP
+            );
+            CREATE TRIGGER IF NOT EXISTS message_audio_delete AFTER DELETE ON messages BEGIN
+                DELETE FROM message_audio WHERE message_id = old.id;
+            END;",
+        )?;
+        Ok(())
+    }
+
+    /// Audio of a message read out by `config`'s speaker, synthesized unless it was cached
+    ///
+    /// Returns None when the message has nothing to read out.
+    pub fn get_or_synthesize(
+        config: &ConfigView,
+        message_id: i32,
+        message: &str,
+    ) -> Result<Option<(Vec<u8>, AudioFormat)>, String> {
+        let text = spoken_text(message);
+        if text.is_empty() {
+            return Ok(None);
+        }
+        let speaker = speaker(config);
+        match MessageAudio::cached(message_id, &speaker, &text) {
+            Ok(Some(cached)) => return Ok(Some(cached)),
+            Ok(None) => {}
+            Err(e) => warn!("⚠️ Could not read cached speech: {}", e),
+        }
+        let (audio, format) = synthesize(config, &text)?;
+        if let Err(e) = MessageAudio::store(message_id, &speaker, &text, &audio, format) {
+            warn!("⚠️ Could not cache speech: {}", e);
+        }
+        Ok(Some((audio, format)))
+    }
+
+    fn cached(message_id: i32, speaker: &str, text: &str) -> Result<Option<(Vec<u8>, AudioFormat)>> {
+        let con = db_pool::connection()?;
+        let row: Option<(String, Vec<u8>)> = con
+            .query_row(
+                "SELECT mime, audio FROM message_audio WHERE message_id = ? AND speaker = ? AND text = ?",
+                params![message_id, speaker, text],
+                |row| Ok((row.get(0)?, row.get(1)?)),
+            )
+            .optional()?;
+        Ok(row.and_then(|(mime, audio)| AudioFormat::from_content_type(&mime).map(|format| (audio, format))))
+    }
+
+    fn store(message_id: i32, speaker: &str, text: &str, audio: &[u8], format: AudioFormat) -> Result<()> {
+        let con = db_pool::connection()?;
+        con.execute(
+            "INSERT OR REPLACE INTO message_audio (message_id, speaker, text, mime, audio) VALUES (?, ?, ?, ?, ?)",
+            params![message_id, speaker, text, format.mime(), audio],
+        )?;
+        con.execute(
+            "DELETE FROM message_audio WHERE message_id NOT IN
+                (SELECT message_id FROM message_audio ORDER BY created_at DESC, message_id DESC LIMIT ?)",
+            [MAX_CACHED],
+        )?;
+        Ok(())
+    }
+}
+
+/// Everything that decides how the audio sounds
+fn speaker(config: &ConfigView) -> String {
+    format!(
+        "{} {} {}",
+        config.tts_api_url.trim(),
+        config.tts_model.trim(),
+        config.tts_voice.trim()
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_speech_request() {
+        assert_eq!(
+            spoken_text("*smiles warmly* Hello  there! *waves* See you\nsoon."),
+            "Hello there! See you soon."
+        );
+        assert_eq!(spoken_text("*nods*"), "");
+        // An unclosed asterisk runs to the end
+        assert_eq!(spoken_text("Sure. *leans back"), "Sure.");
+
+        assert_eq!(
+            request_body("http://localhost:8880/v1/audio/speech", "kokoro", "af_bella", "Hi"),
+            serde_json::json!({ "model": "kokoro", "input": "Hi", "voice": "af_bella", "response_format": "wav" })
+        );
+        assert_eq!(
+            request_body("http://localhost:5000", "", "", "Hi"),
+            serde_json::json!({ "text": "Hi" })
+        );
+        assert_eq!(
+            request_body("http://localhost:5000/", "", "en_US-lessac-medium", "Hi"),
+            serde_json::json!({ "text": "Hi", "voice": "en_US-lessac-medium" })
+        );
+    }
+}
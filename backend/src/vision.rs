@@ -0,0 +1,134 @@
+use crate::database::ConfigView;
+use crate::message_images::{ImageFormat, MessageImages};
+use base64::Engine as _;
+use std::time::Duration;
+use tracing::warn;
+
+// Asked of the vision model for every picture, the description stands in for it in the prompt
+const DESCRIBE_PROMPT: &str = "Describe this picture in one or two sentences for someone who can't see it. \
+Mention who or what is in it, what is happening and anything written in it.";
+const MAX_DESCRIPTION_TOKENS: usize = 160;
+
+pub fn is_configured(config: &ConfigView) -> bool {
+    !config.vision_api_url.trim().is_empty()
+}
+
+/// Describe a picture with the multimodal model set in vision_api_url
+///
+/// URLs ending in /api/generate are taken for Ollama and get `{"model", "prompt", "images"}`,
+/// anything else for an OpenAI compatible chat completions endpoint, such as the llama.cpp server
+/// running a LLaVA style GGUF with its --mmproj projector, and gets the picture as a data URL.
+pub fn describe(config: &ConfigView, image: &[u8], format: ImageFormat) -> Result<String, String> {
+    let url = config.vision_api_url.trim();
+    if url.is_empty() {
+        return Err("no vision model is configured, set vision_api_url in the config".to_string());
+    }
+    let encoded = base64::engine::general_purpose::STANDARD.encode(image);
+    let ollama = is_ollama(url);
+    let body = request_body(ollama, config.vision_model.trim(), &encoded, format);
+
+    let client = reqwest::blocking::Client::builder()
+        .timeout(Duration::from_secs(120))
+        .build()
+        .map_err(|e| e.to_string())?;
+    let answer: serde_json::Value = client
+        .post(url)
+        .json(&body)
+        .send()
+        .and_then(|response| response.error_for_status())
+        .and_then(|response| response.json())
+        .map_err(|e| format!("vision request failed: {}", e))?;
+    let description = if ollama {
+        answer["response"].as_str()
+    } else {
+        answer["choices"][0]["message"]["content"].as_str()
+    };
+    description
+        .map(|description| description.trim().to_string())
+        .filter(|description| !description.is_empty())
+        .ok_or_else(|| "vision model answered without a description".to_string())
+}
+
+fn is_ollama(url: &str) -> bool {
+    url.trim_end_matches('/').ends_with("/api/generate")
+}
+
+fn request_body(ollama: bool, model: &str, encoded: &str, format: ImageFormat) -> serde_json::Value {
+    let mut body = if ollama {
+        serde_json::json!({
+            "prompt": DESCRIBE_PROMPT,
+            "images": [encoded],
+            "stream": false,
+            "options": { "num_predict": MAX_DESCRIPTION_TOKENS },
+        })
+    } else {
+        serde_json::json!({
+            "messages": [{
+                "role": "user",
+                "content": [
+                    { "type": "text", "text": DESCRIBE_PROMPT },
+                    {
+                        "type": "image_url",
+                        "image_url": { "url": format!("data:{};base64,{}", format.mime(), encoded) },
+                    },
+                ],
+            }],
+            "max_tokens": MAX_DESCRIPTION_TOKENS,
+            "temperature": 0.2,
+        })
+    };
+    if !model.is_empty() {
+        body["model"] = model.into();
+    }
+    body
+}
+
+/// Describe the uploaded pictures that have no description yet, when a vision model is set up
+///
+/// A picture that can't be described is logged and stays a bare placeholder in the prompt.
+pub fn describe_uploads(config: &ConfigView, files: &[String]) {
+    if !is_configured(config) {
+        return;
+    }
+    for file in files {
+        match MessageImages::get(file) {
+            Ok(Some(image)) if image.description.is_none() => {}
+            Ok(_) => continue,
+            Err(e) => {
+                warn!("⚠️ Could not look up image {}: {}", file, e);
+                continue;
+            }
+        }
+        let described = MessageImages::read(file)
+            .map_err(|e| e.to_string())
+            .and_then(|image| image.ok_or_else(|| "the file is missing".to_string()))
+            .and_then(|(image, format)| describe(config, &image, format));
+        match described.map(|description| MessageImages::set_description(file, &description)) {
+            Ok(Ok(())) => {}
+            Ok(Err(e)) => warn!("⚠️ Could not store the description of image {}: {}", file, e),
+            Err(e) => warn!("⚠️ Could not describe image {}: {}", file, e),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_vision_request() {
+        assert!(is_ollama("http://localhost:11434/api/generate"));
+        assert!(!is_ollama("http://localhost:8080/v1/chat/completions"));
+
+        let body = request_body(false, "", "aGk=", ImageFormat::Png);
+        assert!(body.get("model").is_none());
+        assert_eq!(
+            body["messages"][0]["content"][1]["image_url"]["url"],
+            "data:image/png;base64,aGk="
+        );
+        let body = request_body(true, "llava", "aGk=", ImageFormat::Jpeg);
+        assert_eq!(body["model"], "llava");
+        assert_eq!(body["images"], serde_json::json!(["aGk="]));
+        assert_eq!(body["stream"], false);
+    }
+}
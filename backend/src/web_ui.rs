@@ -0,0 +1,82 @@
+use crate::settings;
+use actix_files::Files;
+use actix_web::http::header;
+use actix_web::middleware::{Compress, DefaultHeaders};
+use actix_web::{get, web, HttpResponse};
+use std::path::Path;
+
+/// A directory holds a built web UI once the frontend build wrote its index.html
+pub fn on_disk(dir: &Path) -> bool {
+    dir.join("index.html").is_file()
+}
+
+/// Web UI from the frontend build on disk when there is one, the copy compiled in otherwise
+///
+/// Registered after every other route, so it only answers what nothing else did.
+pub fn routes(cfg: &mut web::ServiceConfig) {
+    let dir = &settings::get().ui_dir;
+    let scope = web::scope("")
+        .wrap(Compress::default())
+        // File names stay the same between builds, browsers revalidate every time instead
+        .wrap(DefaultHeaders::new().add((header::CACHE_CONTROL, "no-cache")));
+    if on_disk(dir) {
+        cfg.service(
+            scope.service(
+                Files::new("/", dir)
+                    .index_file("index.html")
+                    .use_etag(true)
+                    .use_last_modified(true),
+            ),
+        );
+    } else {
+        cfg.service(
+            scope
+                .service(index)
+                .service(js)
+                .service(js2)
+                .service(css)
+                .service(project_logo)
+                .service(companion_avatar_img),
+        );
+    }
+}
+
+#[get("/")]
+async fn index() -> HttpResponse {
+    HttpResponse::Ok().body(include_str!("../../dist/index.html"))
+}
+
+#[get("/assets/index-4rust.js")]
+async fn js() -> HttpResponse {
+    HttpResponse::Ok()
+        .content_type("application/javascript")
+        .body(include_str!("../../dist/assets/index-4rust.js"))
+}
+
+#[get("/assets/index-4rust2.js")]
+async fn js2() -> HttpResponse {
+    HttpResponse::Ok()
+        .content_type("application/javascript")
+        .body(include_str!("../../dist/assets/index-4rust2.js"))
+}
+
+#[get("/assets/index-4rust.css")]
+async fn css() -> HttpResponse {
+    HttpResponse::Ok()
+        .content_type("text/css")
+        .body(include_str!("../../dist/assets/index-4rust.css"))
+}
+
+#[get("/ai_companion_logo.jpg")]
+async fn project_logo() -> HttpResponse {
+    HttpResponse::Ok()
+        .content_type("image/jpeg")
+        .body(&include_bytes!("../../dist/ai_companion_logo.jpg")[..])
+}
+
+#[get("/assets/companion_avatar-4rust.jpg")]
+async fn companion_avatar_img() -> HttpResponse {
+    HttpResponse::Ok()
+        .content_type("image/jpeg")
+        .body(&include_bytes!("../../dist/assets/companion_avatar-4rust.jpg")[..])
+}
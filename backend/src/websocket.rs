@@ -0,0 +1,122 @@
+use actix_codec::{Decoder, Encoder};
+use actix_http::ws::{hash_key, verify_handshake, Codec, Frame, Message};
+use actix_web::http::header;
+use actix_web::web::{self, BytesMut};
+use actix_web::{HttpRequest, HttpResponse};
+use futures_util::{stream, StreamExt};
+use tokio::sync::mpsc;
+
+/// Sending half of an upgraded WebSocket connection
+#[derive(Clone)]
+pub struct WsSender {
+    tx: mpsc::UnboundedSender<Message>,
+}
+
+impl WsSender {
+    /// Send a text frame, returns false once the client is gone
+    pub fn text(&self, text: String) -> bool {
+        self.send(Message::Text(text.into()))
+    }
+
+    pub fn json(&self, value: &serde_json::Value) -> bool {
+        self.text(value.to_string())
+    }
+
+    /// Resolves once the connection is gone
+    pub async fn closed(&self) {
+        self.tx.closed().await
+    }
+
+    pub fn close(&self) {
+        self.send(Message::Close(None));
+    }
+
+    fn send(&self, message: Message) -> bool {
+        self.tx.send(message).is_ok()
+    }
+}
+
+/// Receiving half of an upgraded WebSocket connection, pings and close frames are answered here
+pub struct WsReceiver {
+    payload: web::Payload,
+    buffer: BytesMut,
+    codec: Codec,
+    sender: WsSender,
+}
+
+impl WsReceiver {
+    /// Next text frame sent by the client, None once the connection is closed
+    pub async fn next_text(&mut self) -> Option<String> {
+        loop {
+            match self.codec.decode(&mut self.buffer) {
+                Ok(Some(Frame::Text(bytes))) => {
+                    if let Ok(text) = String::from_utf8(bytes.to_vec()) {
+                        return Some(text);
+                    }
+                }
+                Ok(Some(Frame::Ping(bytes))) => {
+                    self.sender.send(Message::Pong(bytes));
+                }
+                Ok(Some(Frame::Close(reason))) => {
+                    self.sender.send(Message::Close(reason));
+                    return None;
+                }
+                // Binary and fragmented messages are not part of the chat protocol
+                Ok(Some(_)) => {}
+                Ok(None) => match self.payload.next().await {
+                    Some(Ok(chunk)) => self.buffer.extend_from_slice(&chunk),
+                    _ => return None,
+                },
+                Err(e) => {
+                    tracing::error!("WebSocket protocol error: {}", e);
+                    self.sender.close();
+                    return None;
+                }
+            }
+        }
+    }
+}
+
+/// Upgrade an HTTP request, the response has to be returned to actix for the socket to open
+pub fn upgrade(
+    req: &HttpRequest,
+    payload: web::Payload,
+) -> Result<(HttpResponse, WsSender, WsReceiver), actix_web::Error> {
+    verify_handshake(req.head())?;
+    let accept = match req.headers().get(header::SEC_WEBSOCKET_KEY) {
+        Some(key) => hash_key(key.as_bytes()),
+        None => return Err(actix_web::error::ErrorBadRequest("Missing Sec-WebSocket-Key")),
+    };
+
+    let (tx, rx) = mpsc::unbounded_channel();
+    let sender = WsSender { tx };
+    // Frames are written until a close frame was sent or every sender is dropped
+    let frames = stream::unfold(Some((rx, Codec::new())), |state| async move {
+        let (mut rx, mut codec) = state?;
+        let message = rx.recv().await?;
+        let closing = matches!(message, Message::Close(_));
+        let mut buffer = BytesMut::new();
+        if let Err(e) = codec.encode(message, &mut buffer) {
+            tracing::error!("Failed to encode WebSocket frame: {}", e);
+            return None;
+        }
+        let next = if closing { None } else { Some((rx, codec)) };
+        Some((Ok::<_, actix_web::Error>(buffer.freeze()), next))
+    });
+
+    let response = HttpResponse::SwitchingProtocols()
+        .upgrade("websocket")
+        .insert_header((
+            header::SEC_WEBSOCKET_ACCEPT,
+            header::HeaderValue::from_bytes(&accept)
+                .map_err(actix_web::error::ErrorInternalServerError)?,
+        ))
+        .streaming(frames);
+    let receiver = WsReceiver {
+        payload,
+        buffer: BytesMut::new(),
+        codec: Codec::new(),
+        sender: sender.clone(),
+    };
+    Ok((response, sender, receiver))
+}
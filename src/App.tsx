@@ -11,6 +11,8 @@ import { CompanionDataProvider } from './components/context/companionContext'
 import { ConfigProvider } from './components/context/configContext'
 import { AttitudeProvider } from './components/context/attitudeContext'
 import { SessionProvider } from './components/context/sessionContext'
+import { AuthGate } from './components/AuthGate'
+import { ServerEvents } from './components/ServerEvents'
 import { useMobile } from './hooks/useMobile'
 
 import { Toaster } from "@/components/ui/sonner"
@@ -21,23 +23,26 @@ function App() {
   return (
     <ThemeProvider defaultTheme="dark" storageKey="vite-ui-theme">
       <ThemeCustomizationProvider>
-        <ConfigProvider>
-          <UserDataProvider>
-            <CompanionDataProvider>
-              <AttitudeProvider>
-                <SessionProvider>
-                  <MessagesProvider>
-                    <div className='max-container'>
-                      <ChatWindow />
-                    </div>
-                    <Toaster />
-                    <PWAInstallPrompt />
-                  </MessagesProvider>
-                </SessionProvider>
-              </AttitudeProvider>
-            </CompanionDataProvider>
-          </UserDataProvider>
-        </ConfigProvider>
+        <AuthGate>
+          <ConfigProvider>
+            <UserDataProvider>
+              <CompanionDataProvider>
+                <AttitudeProvider>
+                  <SessionProvider>
+                    <MessagesProvider>
+                      <div className='max-container'>
+                        <ChatWindow />
+                      </div>
+                      <Toaster />
+                      <ServerEvents />
+                      <PWAInstallPrompt />
+                    </MessagesProvider>
+                  </SessionProvider>
+                </AttitudeProvider>
+              </CompanionDataProvider>
+            </UserDataProvider>
+          </ConfigProvider>
+        </AuthGate>
         {/* Only show footer on desktop or non-PWA */}
         {(!isMobile || !isStandalone) && <Footer />}
       </ThemeCustomizationProvider>
@@ -0,0 +1,138 @@
+import { ReactNode, useCallback, useEffect, useState } from "react"
+
+import { Button } from "@/components/ui/button"
+import {
+  Card,
+  CardContent,
+  CardDescription,
+  CardFooter,
+  CardHeader,
+  CardTitle,
+} from "@/components/ui/card"
+import { Input } from "@/components/ui/input"
+import { Label } from "@/components/ui/label"
+
+import { AUTH_REQUIRED_EVENT, AuthStatus, getAuthStatus, login, setupPassword } from "@/lib/auth"
+
+const SETUP_SKIPPED_KEY = "auth-setup-skipped";
+
+// Shows the login form while the API is locked, and offers to set a password on first run
+export function AuthGate({ children }: { children: ReactNode }) {
+  const [status, setStatus] = useState<AuthStatus | null>(null);
+  const [setupSkipped, setSetupSkipped] = useState(localStorage.getItem(SETUP_SKIPPED_KEY) === "true");
+  const [password, setPassword] = useState("");
+  const [confirmation, setConfirmation] = useState("");
+  const [useApiKey, setUseApiKey] = useState(false);
+  const [error, setError] = useState("");
+
+  const refresh = useCallback(() => {
+    getAuthStatus()
+      .then(setStatus)
+      .catch(() => setStatus(null));
+  }, []);
+
+  useEffect(() => {
+    refresh();
+    window.addEventListener(AUTH_REQUIRED_EVENT, refresh);
+    return () => window.removeEventListener(AUTH_REQUIRED_EVENT, refresh);
+  }, [refresh]);
+
+  const handleLogin = async () => {
+    setError("");
+    try {
+      await login(useApiKey || !status?.password ? { token: password } : { password });
+      window.location.reload();
+    } catch (e) {
+      setError((e as Error).message);
+    }
+  };
+
+  const handleSetup = async () => {
+    setError("");
+    if (password !== confirmation) {
+      setError("Passwords do not match");
+      return;
+    }
+    try {
+      await setupPassword(password);
+      setPassword("");
+      setConfirmation("");
+      refresh();
+    } catch (e) {
+      setError((e as Error).message);
+    }
+  };
+
+  const skipSetup = () => {
+    localStorage.setItem(SETUP_SKIPPED_KEY, "true");
+    setSetupSkipped(true);
+  };
+
+  if (status && !status.authenticated) {
+    const withApiKey = useApiKey || !status.password;
+    return (
+      <div className="flex min-h-screen items-center justify-center p-4">
+        <Card className="w-full max-w-sm">
+          <CardHeader>
+            <CardTitle>Log in</CardTitle>
+            <CardDescription>This companion is protected.</CardDescription>
+          </CardHeader>
+          <CardContent className="space-y-2">
+            <Label htmlFor="auth-secret">{withApiKey ? "API key" : "Password"}</Label>
+            <Input
+              id="auth-secret"
+              type="password"
+              value={password}
+              onChange={(e) => setPassword(e.target.value)}
+              onKeyDown={(e) => e.key === "Enter" && handleLogin()}
+              autoFocus
+            />
+            {error && <p className="text-sm text-destructive">{error}</p>}
+          </CardContent>
+          <CardFooter className="flex justify-between">
+            {status.password && status.api_key ? (
+              <Button variant="link" onClick={() => setUseApiKey(!useApiKey)}>
+                {useApiKey ? "Use password" : "Use API key"}
+              </Button>
+            ) : <span />}
+            <Button onClick={handleLogin}>Log in</Button>
+          </CardFooter>
+        </Card>
+      </div>
+    );
+  }
+
+  if (status?.setup_required && status.local && !setupSkipped) {
+    return (
+      <div className="flex min-h-screen items-center justify-center p-4">
+        <Card className="w-full max-w-sm">
+          <CardHeader>
+            <CardTitle>Set a password</CardTitle>
+            <CardDescription>
+              Anyone who can reach this server can read your chats. Set a password to require a login on every device.
+            </CardDescription>
+          </CardHeader>
+          <CardContent className="space-y-2">
+            <Label htmlFor="setup-password">Password</Label>
+            <Input id="setup-password" type="password" value={password} onChange={(e) => setPassword(e.target.value)} autoFocus />
+            <Label htmlFor="setup-confirmation">Repeat password</Label>
+            <Input
+              id="setup-confirmation"
+              type="password"
+              value={confirmation}
+              onChange={(e) => setConfirmation(e.target.value)}
+              onKeyDown={(e) => e.key === "Enter" && handleSetup()}
+            />
+            {error && <p className="text-sm text-destructive">{error}</p>}
+          </CardContent>
+          <CardFooter className="flex justify-between">
+            <Button variant="outline" onClick={skipSetup}>Skip</Button>
+            <Button onClick={handleSetup}>Set password</Button>
+          </CardFooter>
+        </Card>
+      </div>
+    );
+  }
+
+  return <>{children}</>;
+}
@@ -143,7 +143,7 @@ const ChatWindow = () => {
           )}>
             <div className='flex items-center gap-3'>
               <Avatar className={isMobile ? "w-8 h-8" : "w-10 h-10"}>
-                <AvatarImage src={companionData.avatar_path || companionAvatar} alt="Companion Avatar" />
+                <AvatarImage src={companionData.expression?.url || companionData.avatar_path || companionAvatar} alt="Companion Avatar" />
                 <AvatarFallback>AI</AvatarFallback>
               </Avatar>
               {!isMobile && (
@@ -193,6 +193,12 @@ const ChatWindow = () => {
                     <DropdownMenuItem onClick={toggleImpersonateMode}>
                       {isImpersonating ? 'Stop impersonating' : 'Impersonate'}
                     </DropdownMenuItem>
+                    <DropdownMenuItem asChild>
+                      <a href="/api/message/export?format=markdown&attitudes=true" download>Export as Markdown</a>
+                    </DropdownMenuItem>
+                    <DropdownMenuItem asChild>
+                      <a href="/api/message/export?format=json&attitudes=true" download>Export as JSON</a>
+                    </DropdownMenuItem>
                   </DropdownMenuContent>
                 </DropdownMenu>
                 
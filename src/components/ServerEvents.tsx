@@ -0,0 +1,26 @@
+import { useEffect } from "react"
+import { toast } from "sonner"
+
+import { connectEvents, describeEvent, SERVER_EVENT, ServerEvent } from "@/lib/events"
+
+// Keeps the event stream open and shows what the companion noticed as toasts
+export function ServerEvents() {
+  useEffect(() => {
+    const close = connectEvents();
+    const notify = (e: Event) => {
+      const notice = describeEvent((e as CustomEvent<ServerEvent>).detail);
+      if (notice?.error) {
+        toast.error(notice.text);
+      } else if (notice) {
+        toast(notice.text);
+      }
+    };
+    window.addEventListener(SERVER_EVENT, notify);
+    return () => {
+      window.removeEventListener(SERVER_EVENT, notify);
+      close();
+    };
+  }, []);
+
+  return null;
+}
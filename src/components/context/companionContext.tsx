@@ -1,6 +1,7 @@
 import React, { createContext, useState, useContext, useEffect, ReactNode } from 'react';
 import { CompanionData } from '../interfaces/CompanionData';
 import { toast } from "sonner";
+import { SERVER_EVENT, ServerEvent } from '@/lib/events';
 
 interface CompanionDataProviderProps {
   children: ReactNode;
@@ -24,6 +25,17 @@ export const CompanionDataProvider: React.FC<CompanionDataProviderProps> = ({ ch
     });
   }, [refreshData]);
 
+  // The avatar expression follows the mood
+  useEffect(() => {
+    const onEvent = (e: Event) => {
+      if ((e as CustomEvent<ServerEvent>).detail.event === 'mood_changed') {
+        setRefreshData((refresh) => !refresh);
+      }
+    };
+    window.addEventListener(SERVER_EVENT, onEvent);
+    return () => window.removeEventListener(SERVER_EVENT, onEvent);
+  }, []);
+
   const fetchCompanionData = async () => {
     try {
       const response = await fetch('/api/companion');
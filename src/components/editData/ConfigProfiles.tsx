@@ -0,0 +1,105 @@
+import { useEffect, useState } from "react";
+import { toast } from "sonner";
+import { Button } from "../ui/button";
+import { Input } from "../ui/input";
+import { Label } from "../ui/label";
+import { Select, SelectContent, SelectItem, SelectTrigger, SelectValue } from "../ui/select";
+import { ConfigProfile } from "../interfaces/Config";
+
+interface ConfigProfilesProps {
+  // Called once a profile was activated, so the form shows its settings
+  onActivated: () => void;
+}
+
+export function ConfigProfiles({ onActivated }: ConfigProfilesProps) {
+  const [profiles, setProfiles] = useState<ConfigProfile[]>([]);
+  const [selected, setSelected] = useState<string>("");
+  const [name, setName] = useState("");
+
+  const fetchProfiles = async () => {
+    try {
+      const response = await fetch("/api/config/profiles");
+      if (response.ok) {
+        const data: ConfigProfile[] = await response.json();
+        setProfiles(data);
+        const active = data.find((profile) => profile.active);
+        if (active) {
+          setSelected(String(active.id));
+        }
+      }
+    } catch (error) {
+      console.error("Error while fetching config profiles:", error);
+    }
+  };
+
+  useEffect(() => {
+    fetchProfiles();
+  }, []);
+
+  const errorOf = async (response: Response) => {
+    const body = await response.json().catch(() => null);
+    return body?.message ?? response.statusText;
+  };
+
+  const activate = async () => {
+    const response = await fetch(`/api/config/profiles/${selected}/activate`, { method: "POST" });
+    if (!response.ok) {
+      toast.error(`Error while switching profile: ${await errorOf(response)}`);
+      return;
+    }
+    toast.success("Profile activated, the model reloads if it has to");
+    onActivated();
+    fetchProfiles();
+  };
+
+  const saveCurrent = async () => {
+    const response = await fetch("/api/config/profiles", {
+      method: "POST",
+      headers: { "Content-Type": "application/json" },
+      body: JSON.stringify({ name }),
+    });
+    if (!response.ok) {
+      toast.error(`Error while saving profile: ${await errorOf(response)}`);
+      return;
+    }
+    toast.success(`Saved the current config as ${name.trim()}`);
+    setName("");
+    fetchProfiles();
+  };
+
+  const remove = async () => {
+    const response = await fetch(`/api/config/profiles/${selected}`, { method: "DELETE" });
+    if (!response.ok) {
+      toast.error(`Error while deleting profile: ${await errorOf(response)}`);
+      return;
+    }
+    setSelected("");
+    fetchProfiles();
+  };
+
+  return (
+    <div className="space-y-3 p-4 border rounded-lg">
+      <Label>Profiles</Label>
+      <div className="flex gap-2">
+        <Select value={selected} onValueChange={setSelected}>
+          <SelectTrigger className="w-[220px]">
+            <SelectValue placeholder={profiles.length > 0 ? "Select a profile" : "No profiles yet"} />
+          </SelectTrigger>
+          <SelectContent>
+            {profiles.map((profile) => (
+              <SelectItem key={profile.id} value={String(profile.id)}>
+                {profile.active ? `${profile.name} (active)` : profile.name}
+              </SelectItem>
+            ))}
+          </SelectContent>
+        </Select>
+        <Button variant="outline" disabled={!selected} onClick={activate}>Switch</Button>
+        <Button variant="outline" disabled={!selected} onClick={remove}>Delete</Button>
+      </div>
+      <div className="flex gap-2">
+        <Input placeholder="Profile name" value={name} onChange={(e) => setName(e.target.value)} />
+        <Button variant="outline" disabled={!name.trim()} onClick={saveCurrent}>Save current</Button>
+      </div>
+    </div>
+  );
+}
@@ -35,7 +35,7 @@ import {
 } from "@/components/ui/tooltip"
 import { updateUserData, useUserData } from "../context/userContext"
 import { updateConfigData, useConfigData } from "../context/configContext"
-import { ConfigInterface, Device, PromptTemplate, GpuMemoryInfo, LayerAllocation } from "../interfaces/Config"
+import { ConfigInterface, Device, PromptTemplate, PromptTemplateEntry, GpuMemoryInfo, LayerAllocation } from "../interfaces/Config"
 import { useEffect, useState } from "react"
 import { CompanionData } from "../interfaces/CompanionData"
 import { UserData } from "../interfaces/UserData"
@@ -44,6 +44,7 @@ import { Dialog, DialogClose, DialogContent, DialogDescription, DialogFooter, Di
 import { useMessages } from "../context/messageContext"
 import { AttitudeManager } from "../attitude/AttitudeManager"
 import { ThemeSettings } from "./ThemeSettings"
+import { ConfigProfiles } from "./ConfigProfiles"
 import { DirectoryManager } from "../llm/DirectoryManager"
 import { LlmModelSelector } from "../llm/LlmModelSelector"
 import { ModelList } from "../llm/ModelList"
@@ -67,6 +68,7 @@ export function EditData() {
   const [layerAllocation, setLayerAllocation] = useState<LayerAllocation | null>(null);
   const [isLoadingGpuInfo, setIsLoadingGpuInfo] = useState(false);
   const [modelRefreshTrigger, setModelRefreshTrigger] = useState(0);
+  const [promptTemplates, setPromptTemplates] = useState<PromptTemplateEntry[]>([]);
 
   const { refreshMessages, resetStart } = useMessages();
 
@@ -92,6 +94,15 @@ export function EditData() {
     }
   };
 
+  // The form keeps its own copy of the config, it would save the old settings over the profile's
+  const handleProfileActivated = async () => {
+    configContext?.refreshConfigData();
+    const response = await fetch("/api/config");
+    if (response.ok) {
+      setConfigFormData(await response.json());
+    }
+  };
+
   const fetchGpuInfo = async () => {
     if (configFormData.device === Device.CPU) {
       setGpuMemoryInfo(null);
@@ -132,6 +143,13 @@ export function EditData() {
     }
   };
 
+  useEffect(() => {
+    fetch("/api/templates")
+      .then((response) => response.ok ? response.json() : [])
+      .then(setPromptTemplates)
+      .catch((error) => console.error("Error while fetching prompt templates:", error));
+  }, []);
+
   useEffect(() => {
     if (companionDataContext) {
       setCompanionFormData(companionDataContext.companionData as CompanionData);
@@ -436,8 +454,9 @@ export function EditData() {
               <Label htmlFor="companionFirstMessage">First message with which the AI will start a conversation</Label>
               <Textarea className="min-h-[100px]" id="companionFirstMessage" value={companionFormData.first_message} onChange={(e) => setCompanionFormData({ ...companionFormData, first_message: e.target.value })} />
             </div>
-            <div className="flex flex-row items-center justify-center">
+            <div className="flex flex-row items-center justify-center gap-4">
               <button className="hover:text-muted-foreground" onClick={handleExportCharacterJson}>Export companion data as JSON</button>
+              <a className="hover:text-muted-foreground" href="/api/companion/card" download>Export character card</a>
             </div>
             <div className="space-y-1">
               <Label htmlFor="companionLongTermMemory" className="flex flex-row gap-2">
@@ -673,6 +692,7 @@ export function EditData() {
             <CardTitle>Config</CardTitle>
           </CardHeader>
           <CardContent className="space-y-6">
+            <ConfigProfiles onActivated={handleProfileActivated} />
             <div className="space-y-1">
               <Label htmlFor="username">Device</Label>
               <Select onValueChange={(e) => setConfigFormData({ ...configFormData, device: e as Device })} defaultValue={configFormData?.device}>
@@ -875,6 +895,26 @@ export function EditData() {
                 </div>
               </div>
             </div>
+            <div className="flex items-center justify-between">
+              <Label htmlFor="autoPromptTemplate" className="flex flex-row gap-2">
+                <div className="flex items-center gap-2">
+                  Detect prompt template
+                  <TooltipProvider delayDuration={0}>
+                    <Tooltip>
+                      <TooltipTrigger className="cursor-default"> <Info /></TooltipTrigger>
+                      <TooltipContent>
+                        <p>Pick the prompt template from the model's metadata whenever another model is selected</p>
+                      </TooltipContent>
+                    </Tooltip>
+                  </TooltipProvider>
+                </div>
+              </Label>
+              <Switch
+                id="autoPromptTemplate"
+                checked={configFormData.auto_prompt_template ?? true}
+                onCheckedChange={(checked) => setConfigFormData({ ...configFormData, auto_prompt_template: checked })}
+              />
+            </div>
             <div className="space-y-1">
               <Label htmlFor="promptTemplate">Prompt template</Label>
               <Select onValueChange={(e) => setConfigFormData({ ...configFormData, prompt_template: e  as PromptTemplate })} defaultValue={configFormData?.prompt_template}>
@@ -888,6 +928,20 @@ export function EditData() {
                 </SelectContent>
               </Select>
             </div>
+            <div className="space-y-1">
+              <Label htmlFor="customPromptTemplate">Custom prompt template</Label>
+              <Select onValueChange={(e) => setConfigFormData({ ...configFormData, custom_prompt_template: e === "none" ? "" : e })} defaultValue={configFormData?.custom_prompt_template || "none"}>
+                <SelectTrigger className="w-[180px]">
+                  <SelectValue placeholder="none" />
+                </SelectTrigger>
+                <SelectContent>
+                  <SelectItem value="none">None (use prompt template)</SelectItem>
+                  {promptTemplates.map((template) => (
+                    <SelectItem key={template.id} value={template.name}>{template.name}</SelectItem>
+                  ))}
+                </SelectContent>
+              </Select>
+            </div>
             
             <div className="border-t pt-4">
               <h3 className="text-lg font-semibold mb-4">Context Window & Token Management</h3>
@@ -8,4 +8,6 @@ export interface CompanionData {
     roleplay: boolean;
     dialogue_tuning: boolean;
     avatar_path: string;
+    // Picture for the companion's mood, null while no expression is set for it
+    expression?: { mood: string; url: string } | null;
 }
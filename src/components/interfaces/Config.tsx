@@ -16,6 +16,10 @@ export interface ConfigInterface {
     selected_model_path?: string;
     gpu_layers: number;
     prompt_template: PromptTemplate;
+    // Name of a template from /api/templates, empty uses prompt_template
+    custom_prompt_template?: string;
+    // Pick the template from the model's metadata when another model is selected
+    auto_prompt_template?: boolean;
     context_window_size: number;
     max_response_tokens: number;
     enable_dynamic_context: boolean;
@@ -25,12 +29,36 @@ export interface ConfigInterface {
     min_free_vram_mb: number;
 }
 
+export interface PromptTemplateEntry {
+    id: number;
+    name: string;
+    template: string;
+    stop: string[];
+    builtin: boolean;
+    created_at: string;
+    updated_at: string;
+}
+
+export interface ConfigProfile {
+    id: number;
+    name: string;
+    config: ConfigInterface;
+    llm_api_key_set: boolean;
+    // The config has exactly the profile's settings right now
+    active: boolean;
+    created_at: string;
+    updated_at: string;
+}
+
 export interface ModelInfo {
     path: string;
     filename: string;
     size_bytes: number;
     directory: string;
     last_modified: string;
+    architecture?: string | null;
+    // Built-in format or prompt template preset the model was trained with
+    detected_template?: string | null;
 }
 
 export interface DirectoryInfo {
@@ -1,6 +1,15 @@
+// What generating a reply took, only set on the companion's replies
+export interface ReplyStats {
+    prompt_tokens: number;
+    completion_tokens: number;
+    generation_ms: number | null;
+    model_name: string | null;
+}
+
 export interface MessageInterface {
     id: number;
     ai: boolean;
     content: string;
     created_at: string;
+    stats?: ReplyStats | null;
 }
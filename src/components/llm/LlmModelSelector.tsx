@@ -87,6 +87,7 @@ export const LlmModelSelector: React.FC<LlmModelSelectorProps> = ({
                                         <div className="flex items-center justify-between w-full">
                                             <span>{model.filename}</span>
                                             <span className="ml-2 text-xs text-muted-foreground">
+                                                {model.detected_template ? `${model.detected_template} · ` : ''}
                                                 {formatFileSize(model.size_bytes)}
                                             </span>
                                         </div>
@@ -6,6 +6,7 @@ import { useCompanionData } from "../context/companionContext";
 import companionAvatar from "../../assets/companion_avatar.jpg";
 import { CompanionData } from "../interfaces/CompanionData";
 import { UserData } from "../interfaces/UserData";
+import { ReplyStats } from "../interfaces/Message";
 import { useEffect, useState, lazy } from "react";
 import { cn, formatMessageDate } from "../../lib/utils";
 import { useMessages } from "../context/messageContext";
@@ -31,6 +32,18 @@ interface MessageProps {
   regenerate: boolean;
   content: string;
   created_at: string;
+  stats?: ReplyStats | null;
+}
+
+function describeStats(stats: ReplyStats): string {
+  const parts = [`${stats.prompt_tokens} + ${stats.completion_tokens} tokens`];
+  if (stats.generation_ms !== null) {
+    parts.push(`${(stats.generation_ms / 1000).toFixed(1)}s`);
+  }
+  if (stats.model_name) {
+    parts.push(stats.model_name);
+  }
+  return parts.join(' · ');
 }
 
 const UserMessage = ({ id, content, created_at }: MessageProps) => {
@@ -241,7 +254,7 @@ const UserMessage = ({ id, content, created_at }: MessageProps) => {
 };
 
 
-const AiMessage = ({ id, content, created_at, regenerate }: MessageProps) => {
+const AiMessage = ({ id, content, created_at, regenerate, stats }: MessageProps) => {
   const companionDataContext = useCompanionData();
   const companionData: CompanionData = companionDataContext?.companionData ?? {} as CompanionData;
 
@@ -377,6 +390,9 @@ const AiMessage = ({ id, content, created_at, regenerate }: MessageProps) => {
           </Avatar>
           <span className="font-medium text-sm">{companionData.name || "Assistant"}</span>
           <span className="text-xs opacity-50">{formatMessageDate(created_at)}</span>
+          {stats && (
+            <span className="text-xs opacity-40 hidden group-hover:inline">{describeStats(stats)}</span>
+          )}
           {isTyping && (
             <span className="text-xs text-muted-foreground italic animate-pulse">
               is typing...
@@ -565,10 +581,10 @@ const AiMessage = ({ id, content, created_at, regenerate }: MessageProps) => {
   );
 };
 
-export function Message({ received, regenerate, id, content, created_at }: MessageScrollProps) {
+export function Message({ received, regenerate, id, content, created_at, stats }: MessageScrollProps) {
   return (
     <>
-      {received ? <AiMessage key={id} content={content} id={id} created_at={created_at} regenerate={regenerate} />: <UserMessage key={id} content={content} id={id} created_at={created_at} regenerate={false} /> }
+      {received ? <AiMessage key={id} content={content} id={id} created_at={created_at} regenerate={regenerate} stats={stats} />: <UserMessage key={id} content={content} id={id} created_at={created_at} regenerate={false} /> }
     </>
   );
 }
@@ -131,6 +131,7 @@ export function MessageScroll() {
                 regenerate={index === messages.length - 1 && index !== 0} 
                 content={message.content} 
                 created_at={message.created_at} 
+                stats={message.stats}
               />
             </div>
           ))}
@@ -18,7 +18,7 @@ export function TypingIndicator({ isVisible }: TypingIndicatorProps) {
       <div className="chat-image avatar">
         <div className="w-10 rounded-full relative">
           <Avatar>
-            <AvatarImage src={companionData.avatar_path || companionAvatar} alt="Companion Avatar" />
+            <AvatarImage src={companionData.expression?.url || companionData.avatar_path || companionAvatar} alt="Companion Avatar" />
             <AvatarFallback>AI</AvatarFallback>
           </Avatar>
           {/* Online indicator */}
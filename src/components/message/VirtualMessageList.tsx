@@ -160,6 +160,7 @@ export function VirtualMessageList({
                     regenerate={messageIndex === messages.length - 1 && messageIndex !== 0}
                     content={message.content}
                     created_at={message.created_at}
+                    stats={message.stats}
                   />
                 </div>
               );
@@ -0,0 +1,38 @@
+import { describe, it, expect } from 'vitest'
+import { describeEvent } from '../events'
+
+describe('describeEvent', () => {
+  it('sums up attitude changes toward the user', () => {
+    const notice = describeEvent({
+      id: 1,
+      event: 'attitude_changed',
+      data: {
+        target_type: 'user',
+        changes: { trust: { from: 10, to: 13 }, anger: { from: 5, to: 3.5 }, love: { from: 2, to: 2.1 } },
+      },
+    })
+    expect(notice).toEqual({ text: 'Attitude changed: trust +3, anger -1.5' })
+  })
+
+  it('leaves attitude changes toward other persons to the attitude view', () => {
+    const notice = describeEvent({
+      id: 2,
+      event: 'attitude_changed',
+      data: { target_type: 'third_party', changes: { trust: { from: 0, to: 5 } } },
+    })
+    expect(notice).toBeNull()
+  })
+
+  it('counts mentions like the console does', () => {
+    const mention = (mention_count: number) =>
+      describeEvent({ id: 3, event: 'third_party_mentioned', data: { name: 'Anna', mention_count } })?.text
+    expect(mention(3)).toBe('Anna mentioned for the 3rd time')
+    expect(mention(11)).toBe('Anna mentioned for the 11th time')
+    expect(mention(22)).toBe('Anna mentioned for the 22nd time')
+  })
+
+  it('reports a failed model load as an error', () => {
+    const notice = describeEvent({ id: 4, event: 'model_load_failed', data: { error: 'out of memory' } })
+    expect(notice).toEqual({ text: 'Model failed to load: out of memory', error: true })
+  })
+})
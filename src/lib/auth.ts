@@ -0,0 +1,62 @@
+export const AUTH_REQUIRED_EVENT = 'auth-required';
+
+// The server answers 401 once a password or API key is set, let the AuthGate ask for it
+export function installAuthPrompt() {
+  const originalFetch = window.fetch.bind(window);
+
+  window.fetch = async (...args: Parameters<typeof fetch>) => {
+    const response = await originalFetch(...args);
+    if (response.status === 401) {
+      window.dispatchEvent(new Event(AUTH_REQUIRED_EVENT));
+    }
+    return response;
+  };
+}
+
+export interface AuthStatus {
+  setup_required: boolean;
+  password: boolean;
+  api_key: boolean;
+  authenticated: boolean;
+  local: boolean;
+}
+
+export async function getAuthStatus(): Promise<AuthStatus> {
+  const response = await fetch('/api/auth/status');
+  return response.json();
+}
+
+async function errorMessage(response: Response): Promise<string> {
+  try {
+    const body = await response.json();
+    return body.message ?? response.statusText;
+  } catch {
+    return response.statusText;
+  }
+}
+
+export async function login(credentials: { password: string } | { token: string }) {
+  const response = await fetch('/api/auth/login', {
+    method: 'POST',
+    headers: { 'Content-Type': 'application/json' },
+    body: JSON.stringify(credentials),
+  });
+  if (!response.ok) {
+    throw new Error(await errorMessage(response));
+  }
+}
+
+export async function setupPassword(password: string) {
+  const response = await fetch('/api/auth/setup', {
+    method: 'POST',
+    headers: { 'Content-Type': 'application/json' },
+    body: JSON.stringify({ password }),
+  });
+  if (!response.ok) {
+    throw new Error(await errorMessage(response));
+  }
+}
+
+export async function logout() {
+  await fetch('/api/auth/logout', { method: 'POST' });
+}
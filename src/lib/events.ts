@@ -0,0 +1,106 @@
+export const SERVER_EVENT = 'server-event';
+// Refreshes the attitude summary, also sent by the chat after every reply
+export const ATTITUDE_UPDATE_EVENT = 'attitude-update';
+
+// Events of /api/events the web UI reacts to, EventSource only delivers named events it listens for
+export const SERVER_EVENT_NAMES = [
+  'attitude_changed',
+  'person_detected',
+  'third_party_mentioned',
+  'interaction_completed',
+  'model_loaded',
+  'model_load_failed',
+  'mood_changed',
+  'import_progress',
+  'goal_progress',
+] as const;
+
+export type ServerEventName = typeof SERVER_EVENT_NAMES[number];
+
+export interface ServerEvent {
+  id: number;
+  event: ServerEventName;
+  data: Record<string, unknown>;
+}
+
+export interface EventNotice {
+  text: string;
+  error?: boolean;
+}
+
+function ordinal(n: number): string {
+  const tens = n % 100;
+  if (tens >= 11 && tens <= 13) {
+    return `${n}th`;
+  }
+  return `${n}${['th', 'st', 'nd', 'rd'][n % 10] ?? 'th'}`;
+}
+
+function signed(value: number): string {
+  const rounded = Math.round(value * 10) / 10;
+  return rounded > 0 ? `+${rounded}` : `${rounded}`;
+}
+
+// What to tell the user about an event, null for events that only refresh the page
+export function describeEvent(event: ServerEvent): EventNotice | null {
+  const { data } = event;
+  switch (event.event) {
+    case 'attitude_changed': {
+      if (data.target_type !== 'user') {
+        return null;
+      }
+      const changes = Object.entries((data.changes ?? {}) as Record<string, { from: number; to: number }>)
+        .map(([dimension, { from, to }]) => ({ dimension, delta: to - from }))
+        .filter(({ delta }) => Math.abs(delta) >= 0.5)
+        .map(({ dimension, delta }) => `${dimension} ${signed(delta)}`);
+      return changes.length > 0 ? { text: `Attitude changed: ${changes.join(', ')}` } : null;
+    }
+    case 'person_detected':
+      return {
+        text: data.pending_review
+          ? `New person noticed: ${data.name}, waiting for review`
+          : `New person noticed: ${data.name}`,
+      };
+    case 'third_party_mentioned':
+      return { text: `${data.name} mentioned for the ${ordinal(Number(data.mention_count))} time` };
+    case 'interaction_completed':
+      return { text: `Interaction completed: ${data.description}` };
+    case 'model_loaded':
+      return { text: 'Model loaded' };
+    case 'model_load_failed':
+      return { text: `Model failed to load: ${data.error}`, error: true };
+    case 'import_progress':
+      if (data.status === 'failed') {
+        return { text: `Analysis of the imported chat failed: ${data.error}`, error: true };
+      }
+      return data.status === 'completed' ? { text: 'Analysis of the imported chat finished' } : null;
+    case 'goal_progress':
+      return data.status === 'achieved' ? { text: `Goal achieved: ${data.description}` } : null;
+    default:
+      return null;
+  }
+}
+
+// Forward the server's events as window events, returns a function closing the stream
+//
+// EventSource reconnects on its own and sends Last-Event-ID, so nothing is missed in between.
+export function connectEvents(): () => void {
+  const source = new EventSource('/api/events');
+  for (const name of SERVER_EVENT_NAMES) {
+    source.addEventListener(name, (message) => {
+      const { lastEventId, data } = message as MessageEvent<string>;
+      let parsed: Record<string, unknown>;
+      try {
+        parsed = JSON.parse(data);
+      } catch {
+        return;
+      }
+      const event: ServerEvent = { id: Number(lastEventId), event: name, data: parsed };
+      window.dispatchEvent(new CustomEvent<ServerEvent>(SERVER_EVENT, { detail: event }));
+      if (name === 'attitude_changed') {
+        window.dispatchEvent(new CustomEvent(ATTITUDE_UPDATE_EVENT));
+      }
+    });
+  }
+  return () => source.close();
+}
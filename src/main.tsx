@@ -2,6 +2,9 @@ import React from 'react'
 import ReactDOM from 'react-dom/client'
 import App from './App.tsx'
 import './index.scss'
+import { installAuthPrompt } from './lib/auth'
+
+installAuthPrompt();
 
 // Service Worker Registration for PWA
 if ('serviceWorker' in navigator) {